@@ -0,0 +1,118 @@
+//! Measures the memory used by data sets with and without interning.
+//!
+//! The benchmark simulates three units that each fetch the same data set
+//! of one million VRPs from a different source and then fetch it again
+//! ten times with some of the VRPs replaced each time. For each unit, the
+//! current set and the diffs of the last ten updates are kept, similar to
+//! what an RTR target does.
+//!
+//! Since memory once allocated is rarely returned to the operating system,
+//! each variant runs in its own process. Run the benchmark via
+//! `cargo bench --bench intern`. Memory use is only available on Linux.
+
+use std::{env, fs, process};
+use std::net::Ipv4Addr;
+use rpki::resources::addr::{MaxLenPrefix, Prefix};
+use rpki::rtr::payload::Payload;
+use rtrtr::payload::{Diff, PackBuilder, Set};
+
+
+/// The number of VRPs in each data set.
+const SET_LEN: u32 = 1_000_000;
+
+/// The number of VRPs replaced in each update.
+const CHURN: u32 = 1_000;
+
+/// The number of units.
+const UNITS: usize = 3;
+
+/// The number of updates after the initial data set.
+const UPDATES: u32 = 10;
+
+/// The environment variable selecting the variant in the child process.
+const VARIANT_VAR: &str = "RTRTR_BENCH_INTERN_VARIANT";
+
+fn main() {
+    match env::var(VARIANT_VAR) {
+        Ok(variant) => run(variant == "interned"),
+        Err(_) => {
+            for variant in ["plain", "interned"] {
+                let status = process::Command::new(
+                    env::current_exe().unwrap()
+                ).env(VARIANT_VAR, variant).status().unwrap();
+                if !status.success() {
+                    eprintln!("Variant {} failed.", variant);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+/// Runs the simulation and prints the memory used.
+fn run(intern: bool) {
+    let base = rss_kib();
+    let mut units: Vec<(Set, Vec<Diff>)> = Vec::new();
+    for _ in 0..UNITS {
+        units.push((make_set(0, intern), Vec::new()));
+    }
+    for round in 1..=UPDATES {
+        for (current, diffs) in units.iter_mut() {
+            let new = make_set(round, intern);
+            diffs.insert(0, new.diff_from(current));
+            diffs.truncate(UPDATES as usize);
+            *current = new;
+        }
+    }
+    let used = rss_kib().zip(base).map(|(rss, base)| {
+        rss.saturating_sub(base)
+    });
+    match used {
+        Some(used) => {
+            println!(
+                "{:>8}: {} MiB for {} units with {} VRPs each",
+                if intern { "interned" } else { "plain" },
+                used / 1024, units.len(), units[0].0.len()
+            );
+        }
+        None => println!("memory use not available on this system"),
+    }
+}
+
+/// Creates the data set fetched in the given round.
+///
+/// In each round, the first `CHURN` VRPs of the previous round are replaced
+/// by the same number of new VRPs.
+fn make_set(round: u32, intern: bool) -> Set {
+    let mut builder = PackBuilder::empty();
+    for idx in round * CHURN..round * CHURN + SET_LEN {
+        builder.insert_unchecked(vrp(idx));
+    }
+    let set = Set::from(builder.finalize());
+    if intern {
+        set.intern()
+    }
+    else {
+        set
+    }
+}
+
+/// Creates a VRP from a number.
+fn vrp(idx: u32) -> Payload {
+    Payload::origin(
+        MaxLenPrefix::new(
+            Prefix::new_v4(Ipv4Addr::from(idx << 8), 24).unwrap(),
+            Some(24)
+        ).unwrap(),
+        (idx % 65_000).into()
+    )
+}
+
+/// Returns the resident set size of the process in KiB.
+fn rss_kib() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    status.lines().find_map(|line| {
+        line.strip_prefix("VmRSS:")?.trim().strip_suffix("kB")?.trim()
+            .parse().ok()
+    })
+}
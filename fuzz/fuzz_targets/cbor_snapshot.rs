@@ -0,0 +1,27 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rpki::rtr::Payload;
+use rtrtr::formats::cbor::Snapshot;
+use rtrtr::formats::output::Format;
+use rtrtr::payload::{PackBuilder, Set};
+
+fuzz_target!(|data: (Vec<Payload>, Vec<u8>)| {
+    // Arbitrary input must never panic.
+    let _ = Snapshot::from_slice(&data.1);
+
+    // Encoding a set and reading it back must result in the same set.
+    // Router keys are left out since arbitrary keys aren’t valid.
+    let mut pack = PackBuilder::empty();
+    for item in data.0 {
+        if !matches!(item, Payload::RouterKey(_)) {
+            let _ = pack.insert(item);
+        }
+    }
+    let set = Set::from(pack.finalize());
+    let encoded: Vec<u8> = Format::Cbor.stream(
+        set.clone(), Default::default(), None
+    ).flatten().collect();
+    let decoded = Snapshot::from_slice(&encoded).unwrap();
+    assert_eq!(decoded.set(), &set);
+});
@@ -0,0 +1,353 @@
+//! Keeping the last known good data across restarts.
+//!
+//! If a state directory is configured via the `state-dir` option, units
+//! that support it keep a snapshot of their last data set in a file in this
+//! directory. The file is named after the unit with all characters other
+//! than ASCII letters, digits, `-`, `_`, and `.` percent-encoded.
+//!
+//! When starting, such a unit loads its snapshot and offers it as its data
+//! set right away. This way, targets can serve the stale data to their
+//! clients while the unit is still waiting for the first fresh data from
+//! its upstream server.
+//!
+//! Snapshots are written in the background whenever the unit’s data set
+//! changes. If the data set changes again while a snapshot is still being
+//! written, only the latest data set is written afterwards.
+//!
+//! RTR targets without an explicitly configured state file keep their RTR
+//! state in a file of the same name in the `targets` subdirectory.
+
+use std::{fs, io};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use chrono::{DateTime, TimeZone, Utc};
+use daemonbase::config::ConfigPath;
+use daemonbase::error::Failed;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio::task::spawn_blocking;
+use crate::payload;
+use crate::comms::{Gate, UnitUpdate};
+use crate::handoff::{write_file, PayloadList};
+
+
+//------------ CacheConfig ---------------------------------------------------
+
+/// The configuration of the data cache.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct CacheConfig {
+    /// The directory to keep the snapshots in.
+    ///
+    /// If this is `None`, no snapshots are kept.
+    #[serde(rename = "state-dir")]
+    dir: Option<ConfigPath>,
+}
+
+impl CacheConfig {
+    /// Opens the cache.
+    ///
+    /// Creates the state directory if it doesn’t exist yet.
+    pub fn open(&self) -> Result<Cache, Failed> {
+        let dir = match self.dir.as_ref() {
+            Some(dir) => {
+                let dir: &Path = dir.as_ref();
+                dir.to_path_buf()
+            }
+            None => return Ok(Cache::default())
+        };
+        if let Err(err) = fs::create_dir_all(dir.join(TARGETS_DIR)) {
+            error!(
+                "Failed to create state directory {}: {}",
+                dir.display(), err
+            );
+            return Err(Failed)
+        }
+        Ok(Cache { dir: Some(dir.into()) })
+    }
+}
+
+
+//------------ Cache ---------------------------------------------------------
+
+/// The cache of the data sets of all units.
+#[derive(Clone, Debug, Default)]
+pub struct Cache {
+    /// The state directory if there is one.
+    dir: Option<Arc<Path>>,
+}
+
+impl Cache {
+    /// Returns the cache for the unit with the given name.
+    ///
+    /// Returns `None` if no state directory has been configured. This
+    /// needs to be called from within the runtime.
+    pub fn unit(&self, name: &str) -> Option<UnitCache> {
+        let dir = self.dir.as_ref()?;
+        Some(UnitCache::new(name.into(), dir.join(file_name(name))))
+    }
+
+    /// Returns the path of the state file for the target with the name.
+    ///
+    /// Returns `None` if no state directory has been configured.
+    pub fn target_state(&self, name: &str) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        Some(dir.join(TARGETS_DIR).join(file_name(name)))
+    }
+}
+
+/// The name of the subdirectory for the state files of targets.
+const TARGETS_DIR: &str = "targets";
+
+
+//------------ UnitCache -----------------------------------------------------
+
+/// The cache of the data set of a single unit.
+#[derive(Debug)]
+pub struct UnitCache {
+    /// The path of the snapshot file.
+    path: Arc<Path>,
+
+    /// The sender for new data sets to the writer task.
+    writer: watch::Sender<Option<payload::Set>>,
+}
+
+impl UnitCache {
+    /// Creates the cache and spawns its writer task.
+    fn new(unit: Arc<str>, path: PathBuf) -> Self {
+        let path: Arc<Path> = path.into();
+        let (writer, mut rx) = watch::channel(None::<payload::Set>);
+        let task_path = path.clone();
+        tokio::spawn(async move {
+            while rx.changed().await.is_ok() {
+                let set = match rx.borrow_and_update().clone() {
+                    Some(set) => set,
+                    None => continue,
+                };
+                let path = task_path.clone();
+                let res = spawn_blocking(move || {
+                    Snapshot::write(&path, &set)
+                }).await;
+                match res {
+                    Ok(Ok(())) => {
+                        debug!(
+                            "Unit {}: wrote snapshot to {}.",
+                            unit, task_path.display()
+                        );
+                    }
+                    Ok(Err(err)) => {
+                        warn!(
+                            "Unit {}: failed to write snapshot to {}: {}",
+                            unit, task_path.display(), err
+                        );
+                    }
+                    Err(_) => { }
+                }
+            }
+        });
+        UnitCache { path, writer }
+    }
+
+    /// Loads the snapshot of the unit’s data set.
+    ///
+    /// Returns `None` if there is no snapshot or it can’t be used. The
+    /// latter case is logged.
+    pub fn load(&self, unit: &str) -> Option<Snapshot> {
+        match Snapshot::read(&self.path) {
+            Ok(res) => res,
+            Err(err) => {
+                warn!(
+                    "Unit {}: ignoring snapshot {}: {}",
+                    unit, self.path.display(), err
+                );
+                None
+            }
+        }
+    }
+
+    /// Offers the snapshot of the unit’s data set via the gate.
+    ///
+    /// Does nothing if there is no usable snapshot.
+    pub async fn restore(&self, unit: &str, gate: &mut Gate) {
+        let snapshot = match self.load(unit) {
+            Some(snapshot) => snapshot,
+            None => return
+        };
+        info!(
+            "Unit {}: serving {} items from snapshot written at {} until \
+             fresh data arrives.",
+            unit, snapshot.set.len(), snapshot.created
+        );
+        gate.update(
+            UnitUpdate::Payload(payload::Update::new(snapshot.set))
+        ).await;
+    }
+
+    /// Stores a new data set.
+    ///
+    /// The snapshot is written in the background.
+    pub fn store(&self, set: &payload::Set) {
+        self.writer.send_replace(Some(set.clone()));
+    }
+}
+
+
+//------------ Snapshot ------------------------------------------------------
+
+/// A data set loaded from the cache.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    /// The time the snapshot was written.
+    pub created: DateTime<Utc>,
+
+    /// The data set.
+    pub set: payload::Set,
+}
+
+impl Snapshot {
+    /// Reads a snapshot from a file.
+    ///
+    /// Returns `Ok(None)` if the file doesn’t exist.
+    fn read(path: &Path) -> Result<Option<Self>, String> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err.to_string())
+        };
+        let file = serde_json::from_slice::<SnapshotFile>(
+            &data
+        ).map_err(|err| err.to_string())?;
+        if file.version != SnapshotFile::VERSION {
+            return Err(format!("unsupported version {}", file.version))
+        }
+        let created = Utc.timestamp_opt(file.created, 0).single().ok_or(
+            "invalid creation time"
+        )?;
+        Ok(Some(Snapshot {
+            created,
+            set: file.payload.decode()?.into(),
+        }))
+    }
+
+    /// Atomically writes a snapshot of a data set to a file.
+    fn write(path: &Path, set: &payload::Set) -> Result<(), io::Error> {
+        let file = SnapshotFile {
+            version: SnapshotFile::VERSION,
+            created: Utc::now().timestamp(),
+            payload: PayloadList::encode(set.iter()),
+        };
+        write_file(path, &serde_json::to_vec(&file)?)
+    }
+}
+
+
+//------------ SnapshotFile --------------------------------------------------
+
+/// The content of a snapshot file.
+#[derive(Debug, Deserialize, Serialize)]
+struct SnapshotFile {
+    /// The version of the file format.
+    version: u8,
+
+    /// The time the snapshot was written as a Unix timestamp.
+    created: i64,
+
+    /// The data set.
+    payload: PayloadList,
+}
+
+impl SnapshotFile {
+    /// The current version of the file format.
+    const VERSION: u8 = 1;
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Returns the name of the snapshot file for a unit.
+fn file_name(unit: &str) -> String {
+    let mut res = String::with_capacity(unit.len() + 5);
+    for ch in unit.bytes() {
+        if ch.is_ascii_alphanumeric() || matches!(ch, b'-' | b'_' | b'.') {
+            res.push(char::from(ch))
+        }
+        else {
+            // Writing into a string can’t fail.
+            let _ = write!(res, "%{:02X}", ch);
+        }
+    }
+    res.push_str(".json");
+    res
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::payload::testrig;
+
+    #[test]
+    fn file_names() {
+        assert_eq!(file_name("json-unit_1.a"), "json-unit_1.a.json");
+        assert_eq!(file_name("a/b c"), "a%2Fb%20c.json");
+        assert_eq!(file_name(".."), "...json");
+    }
+
+    #[test]
+    fn snapshot() {
+        let dir = std::env::temp_dir().join(format!(
+            "rtrtr-cache-{}", std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("unit.json");
+
+        assert!(Snapshot::read(&path).unwrap().is_none());
+
+        let set = payload::Set::from(testrig::slurm_pack(
+            include_bytes!("../test-data/router-keys.slurm.json")
+        ));
+        Snapshot::write(&path, &set).unwrap();
+        let snapshot = Snapshot::read(&path).unwrap().unwrap();
+        assert_eq!(snapshot.set, set);
+        assert!(snapshot.created <= Utc::now());
+
+        fs::write(&path, b"{ \"version\": 1 }").unwrap();
+        assert!(Snapshot::read(&path).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unit_cache() {
+        let dir = std::env::temp_dir().join(format!(
+            "rtrtr-unit-cache-{}", std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let cache = Cache { dir: Some(dir.as_path().into()) };
+        let unit = cache.unit("json").unwrap();
+        assert!(unit.load("json").is_none());
+        assert_eq!(
+            cache.target_state("rtr"),
+            Some(dir.join("targets").join("rtr.json"))
+        );
+
+        let set = payload::Set::from(testrig::pack([1, 2, 3]));
+        unit.store(&set);
+        let mut snapshot = None;
+        for _ in 0..100 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            snapshot = unit.load("json");
+            if snapshot.is_some() {
+                break
+            }
+        }
+        assert_eq!(snapshot.unwrap().set, set);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
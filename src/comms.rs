@@ -11,21 +11,28 @@
 //!
 //! The type [`GateMetrics`] can be used by units to provide some obvious
 //! metrics such as the number of payload units in the data set or the time
-//! of last update based on the updates sent to the gate.
+//! of last update based on the updates sent to the gate. It also keeps the
+//! [`DiffHistory`] of the most recent changes to the data set if enabled.
 
-use std::fmt;
+use std::{fmt, panic};
+use std::collections::VecDeque;
 use std::sync::atomic;
-use std::sync::Arc;
-use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
+use std::time::Duration;
 use chrono::{DateTime, Utc};
 use crossbeam_utils::atomic::AtomicCell;
 use futures_util::pin_mut;
 use futures_util::future::{pending, select, Either, Future};
+use log::warn;
 use slab::Slab;
 use serde::Deserialize;
 use tokio::sync::{mpsc, oneshot};
+use tokio::task::spawn_blocking;
+use tokio::time::{timeout_at, Instant};
 use crate::{manager, metrics, payload};
 use crate::config::Marked;
+use crate::events::{Event, Notifier};
 use crate::metrics::{Metric, MetricType, MetricUnit};
 
 
@@ -67,14 +74,41 @@ pub struct Gate {
     /// Senders to all links.
     updates: Slab<UpdateSender>,
 
-    /// Number of suspended senders.
-    suspended: usize,
-
     /// The current unit status.
     unit_status: UnitStatus,
 
+    /// A reference to the last set provided by the unit before interning.
+    ///
+    /// Units that attach a diff to their updates make it from their own
+    /// previous set rather than the interned set kept in the unit status.
+    /// Since both have the same content, the diff applies to the latter,
+    /// too, and we can skip calculating it.
+    origin: Option<payload::SetRef>,
+
     /// The gate metrics.
     metrics: Arc<GateMetrics>,
+
+    /// The name of the unit owning the gate.
+    ///
+    /// This is used to record the provenance of payload updates.
+    name: Option<Arc<str>>,
+
+    /// The notifier for reporting changes in unit health.
+    notifier: Notifier,
+
+    /// How long the unit may be stalled before its data is withdrawn.
+    ///
+    /// If this is `None`, the data is kept forever.
+    expire: Option<Duration>,
+
+    /// The time the data of the stalled unit will be withdrawn.
+    expiry: Option<Instant>,
+
+    /// Has the unit been suspended by an operator?
+    ///
+    /// While suspended, the unit appears stalled to all links and its
+    /// updates are not passed on.
+    suspended: bool,
 }
 
 
@@ -89,9 +123,14 @@ impl Gate {
         let gate = Gate {
             commands: rx,
             updates: Slab::new(),
-            suspended: 0,
             unit_status: Default::default(),
+            origin: None,
             metrics: Default::default(),
+            name: None,
+            notifier: Default::default(),
+            expire: None,
+            expiry: None,
+            suspended: false,
         };
         let agent = GateAgent { commands: tx };
         (gate, agent)
@@ -102,6 +141,36 @@ impl Gate {
         self.metrics.clone()
     }
 
+    /// Sets the name of the unit owning the gate.
+    ///
+    /// Once set, the name is added to the provenance of all payload updates
+    /// sent through the gate.
+    pub fn set_name(&mut self, name: Arc<str>) {
+        self.name = Some(name)
+    }
+
+    /// Sets the notifier for reporting changes in unit health.
+    ///
+    /// Events are only reported if the gate also has a name.
+    pub fn set_notifier(&mut self, notifier: Notifier) {
+        self.notifier = notifier
+    }
+
+    /// Sets how long the unit may be stalled before its data is withdrawn.
+    ///
+    /// Once the unit has been stalled for longer than `expire`, the gate
+    /// replaces its data with an empty set while keeping the unit stalled.
+    /// If `expire` is `None`, the data is never withdrawn.
+    ///
+    /// The data is only withdrawn while the unit runs
+    /// [`process`](Self::process) which units do when waiting anyway.
+    pub fn set_expire(&mut self, expire: Option<Duration>) {
+        self.expire = expire;
+        if expire.is_none() {
+            self.expiry = None
+        }
+    }
+
     /// Runs the gate’s internal machine.
     ///
     /// This method returns a future that runs the gate’s internal machine.
@@ -115,7 +184,19 @@ impl Gate {
     pub async fn process(&mut self) -> Result<GateStatus, Terminated> {
         let status = self.gate_status();
         loop {
-            let command = match self.commands.recv().await {
+            let command = match self.expiry {
+                Some(expiry) => {
+                    match timeout_at(expiry, self.commands.recv()).await {
+                        Ok(command) => command,
+                        Err(_) => {
+                            self.expire().await;
+                            continue
+                        }
+                    }
+                }
+                None => self.commands.recv().await
+            };
+            let command = match command {
                 Some(command) => command,
                 None => return Err(Terminated)
             };
@@ -127,7 +208,11 @@ impl Gate {
                 GateCommand::Subscribe { suspended, response } => {
                     self.subscribe(suspended, response)
                 }
+                GateCommand::Suspend { suspend } => {
+                    self.suspend(suspend).await
+                }
             }
+            self.update_link_metrics();
 
             let new_status = self.gate_status();
             if new_status != status {
@@ -161,13 +246,190 @@ impl Gate {
     /// Updates the unit.
     ///
     /// This method will send out the update to all active links. It will
-    /// also update the gate metrics based on the update.
+    /// also update the gate metrics based on the update. If the gate has
+    /// a name, it is added to the provenance of a payload update.
     ///
     /// Returns whether the update changed the unit’s status.
-    pub async fn update(&mut self, update: UnitUpdate) -> bool {
+    pub async fn update(&mut self, mut update: UnitUpdate) -> bool {
+        if let (UnitUpdate::Payload(payload), Some(name)) = (
+            &mut update, self.name.as_ref()
+        ) {
+            payload.record_step(name.clone());
+        }
+        let health = self.unit_status.health;
+        let previous = self.unit_status.payload.as_ref().map(|payload| {
+            payload.set().clone()
+        });
+
+        let mut diff = None;
+        if let UnitUpdate::Payload(payload) = &mut update {
+            let started = Instant::now();
+            let origin = payload.set().downgrade();
+            let (interned, change) = match self.intern(
+                payload.clone(), previous
+            ).await {
+                Ok(res) => res,
+                Err(Terminated) => return false,
+            };
+            *payload = interned;
+            if change.is_some() {
+                self.metrics.diff_duration.store(
+                    started.elapsed().as_micros().try_into().unwrap_or(
+                        u64::MAX
+                    ),
+                    atomic::Ordering::Relaxed
+                );
+            }
+            self.origin = Some(origin);
+            diff = change;
+        }
+
         if !self.unit_status.apply(&update) {
             return false
         }
+        if let UnitUpdate::Payload(payload) = &update {
+            match diff {
+                Some(diff) => {
+                    self.metrics.record_change(
+                        diff.announced().len(), diff.withdrawn().len()
+                    );
+                    if !diff.is_empty() {
+                        self.metrics.diffs.push(diff)
+                    }
+                }
+                None => self.metrics.record_change(payload.set().len(), 0)
+            }
+        }
+        self.expiry = match update {
+            UnitUpdate::Stalled => {
+                self.expire.map(|expire| Instant::now() + expire)
+            }
+            _ => None
+        };
+        self.notify_health(health);
+        if !self.suspended {
+            self.distribute(&update).await;
+        }
+        self.metrics.update(&self.unit_status);
+        true
+    }
+
+    /// Interns the set of a payload update.
+    ///
+    /// Returns the interned update and, if there is a previous set, the
+    /// diff from that set. If the set hasn’t changed, the previous set is
+    /// used instead of interning the new one. For large sets this takes a
+    /// while, so the work is done on the blocking thread pool.
+    ///
+    /// Returns an error if the blocking task was cancelled which only
+    /// happens when the runtime shuts down.
+    async fn intern(
+        &self,
+        mut update: payload::Update,
+        previous: Option<payload::Set>,
+    ) -> Result<(payload::Update, Option<payload::Diff>), Terminated> {
+        let previous_ref = previous.as_ref().map(payload::Set::downgrade);
+        let carried = self.origin.iter().chain(
+            previous_ref.iter()
+        ).find_map(|set| update.diff_from_ref(set)).cloned();
+        let res = spawn_blocking(move || {
+            let previous = match previous {
+                Some(previous) => previous,
+                None => {
+                    update.intern();
+                    return (update, None)
+                }
+            };
+            let change = match carried {
+                Some(change) => change,
+                None => update.set().diff_from_parallel(&previous),
+            };
+            if change.is_empty() {
+                update = update.derive(previous.clone());
+            }
+            else {
+                update.intern();
+            }
+            (update.with_diff(&previous, change.clone()), Some(change))
+        }).await;
+        match res {
+            Ok(res) => Ok(res),
+            Err(err) if err.is_panic() => {
+                panic::resume_unwind(err.into_panic())
+            }
+            Err(_) => Err(Terminated),
+        }
+    }
+
+    /// Suspends or resumes the unit on behalf of an operator.
+    ///
+    /// When suspending, all links are told that the unit is stalled. When
+    /// resuming, they receive the unit’s current status.
+    async fn suspend(&mut self, suspend: bool) {
+        if suspend == self.suspended {
+            return
+        }
+        self.suspended = suspend;
+        self.metrics.suspended_unit.store(
+            suspend, atomic::Ordering::Relaxed
+        );
+        if let Some(name) = self.name.as_ref() {
+            if suspend {
+                warn!("Unit {}: suspended by operator.", name);
+            }
+            else {
+                warn!("Unit {}: resumed by operator.", name);
+            }
+        }
+        if suspend {
+            if self.unit_status.health != UnitHealth::Gone {
+                self.distribute(&UnitUpdate::Stalled).await;
+            }
+        }
+        else if let Some(update) = self.unit_status.to_update() {
+            self.distribute(&update).await;
+        }
+    }
+
+    /// Withdraws the data of a unit that has been stalled for too long.
+    ///
+    /// Sends an update with an empty set to all links, immediately
+    /// followed by an update returning the unit to stalled.
+    async fn expire(&mut self) {
+        self.expiry = None;
+        let previous = match self.unit_status.payload.as_ref() {
+            Some(payload) if !payload.set().is_empty() => {
+                payload.set().clone()
+            }
+            _ => return
+        };
+        if let (Some(name), Some(expire)) = (
+            self.name.as_ref(), self.expire
+        ) {
+            warn!(
+                "Unit {}: stalled for more than {}s, \
+                 withdrawing {} items.",
+                name, expire.as_secs(), previous.len()
+            );
+        }
+        let mut update = payload::Update::new(payload::Set::default());
+        if let Some(name) = self.name.as_ref() {
+            update.record_step(name.clone());
+        }
+        if self.metrics.diffs.is_enabled() {
+            self.metrics.diffs.push(update.diff_since(&previous))
+        }
+        self.unit_status.payload = Some(update.clone());
+        self.origin = None;
+        if !self.suspended {
+            self.distribute(&UnitUpdate::Payload(update)).await;
+            self.distribute(&UnitUpdate::Stalled).await;
+        }
+        self.metrics.count.store(0, atomic::Ordering::Relaxed);
+    }
+
+    /// Sends an update to all active links.
+    async fn distribute(&mut self, update: &UnitUpdate) {
         for (_, item) in &mut self.updates {
             if item.suspended {
                 continue
@@ -183,13 +445,35 @@ impl Gate {
             item.sender = None
         }
         self.updates.retain(|_, item| item.sender.is_some());
-        self.metrics.update(&self.unit_status);
-        true
+        self.update_link_metrics();
+    }
+
+    /// Reports a change of unit health to the notifier.
+    fn notify_health(&self, old: UnitHealth) {
+        let component = match self.name.as_ref() {
+            Some(name) => name.to_string(),
+            None => return,
+        };
+        let event = match (old, self.unit_status.health) {
+            (UnitHealth::Healthy, UnitHealth::Stalled) => {
+                Event::UnitStalled { component }
+            }
+            (UnitHealth::Stalled, UnitHealth::Healthy) => {
+                Event::UnitRecovered { component }
+            }
+            (UnitHealth::Gone, _) => return,
+            (_, UnitHealth::Gone) => Event::UnitGone { component },
+            _ => return
+        };
+        self.notifier.notify(event)
     }
 
     /// Returns the current gate status.
+    ///
+    /// The gate is dormant if all its links are suspended or the unit has
+    /// been suspended by an operator.
     pub fn gate_status(&self) -> GateStatus {
-        if self.suspended == self.updates.len() {
+        if self.suspended || self.suspended_links() == self.updates.len() {
             GateStatus::Dormant
         }
         else {
@@ -197,6 +481,18 @@ impl Gate {
         }
     }
 
+    /// Returns the number of links that are currently suspended.
+    fn suspended_links(&self) -> usize {
+        self.updates.iter().filter(|(_, item)| item.suspended).count()
+    }
+
+    /// Updates the link counts and gate status in the metrics.
+    fn update_link_metrics(&self) {
+        self.metrics.update_links(
+            self.updates.len(), self.suspended_links()
+        );
+    }
+
     /// Processes a suspension command.
     fn suspension(&mut self, slot: usize, suspend: bool) {
         if let Some(item) = self.updates.get_mut(slot) {
@@ -215,10 +511,14 @@ impl Gate {
             sender: Some(tx),
             suspended,
         });
+        let mut unit_status = self.unit_status.clone();
+        if self.suspended && unit_status.health != UnitHealth::Gone {
+            unit_status.health = UnitHealth::Stalled
+        }
         let subscription = SubscribeResponse {
             slot,
             receiver,
-            unit_status: self.unit_status.clone(),
+            unit_status,
         };
         if let Err(subscription) = response.send(subscription) {
             self.updates.remove(subscription.slot);
@@ -245,6 +545,15 @@ impl GateAgent {
     pub fn create_link(&mut self) -> Link {
         Link::new(self.commands.clone())
     }
+
+    /// Suspends or resumes the unit on behalf of an operator.
+    ///
+    /// While suspended, the unit appears stalled to all links and its gate
+    /// is dormant. Returns whether the request could be queued with the
+    /// gate.
+    pub fn suspend(&self, suspend: bool) -> bool {
+        self.commands.try_send(GateCommand::Suspend { suspend }).is_ok()
+    }
 }
 
 
@@ -266,19 +575,186 @@ pub struct GateMetrics {
     /// The number of payload items in the last update.
     count: AtomicUsize,
 
+    /// Whether the unit has ever produced a payload update.
+    payload: AtomicBool,
+
     /// The date and time of the last update.
     ///
     /// If there has never been an update, this will be `None`.
     update: AtomicCell<Option<DateTime<Utc>>>,
+
+    /// The service level objective for the age of the data.
+    ///
+    /// If this is `None`, the unit doesn’t have an objective.
+    slo: AtomicCell<Option<DataSlo>>,
+
+    /// The most recent changes to the data set.
+    diffs: DiffHistory,
+
+    /// The number of links connected to the gate.
+    links: AtomicUsize,
+
+    /// The number of links that are currently suspended.
+    suspended: AtomicUsize,
+
+    /// Whether the unit has been suspended by an operator.
+    suspended_unit: AtomicBool,
+
+    /// The total number of items added by updates.
+    added: AtomicU64,
+
+    /// The total number of items removed by updates.
+    removed: AtomicU64,
+
+    /// The number of items added or removed by the last update.
+    last_change: AtomicUsize,
+
+    /// The time it took to process the last update in microseconds.
+    ///
+    /// This is the time spent calculating the diff to the previous set
+    /// and interning the new set.
+    diff_duration: AtomicU64,
 }
 
 impl GateMetrics {
+    /// Returns the current health of the unit.
+    pub fn health(&self) -> UnitHealth {
+        self.health.load()
+    }
+
+    /// Returns the number of payload items in the last update.
+    pub fn count(&self) -> usize {
+        self.count.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Returns whether the unit has ever produced a payload update.
+    pub fn has_payload(&self) -> bool {
+        self.payload.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Returns the date and time of the last update if there was one.
+    pub fn updated(&self) -> Option<DateTime<Utc>> {
+        self.update.load()
+    }
+
+    /// Sets the maximum age of the unit’s data.
+    ///
+    /// The age of the data is measured from the last update or, if there
+    /// hasn’t been one yet, from the time this method was called.
+    pub fn set_max_age(&self, max_age: Option<Duration>) {
+        self.slo.store(max_age.map(|max_age| {
+            DataSlo {
+                max_age: i64::try_from(max_age.as_secs()).unwrap_or(i64::MAX),
+                since: Utc::now(),
+            }
+        }))
+    }
+
+    /// Returns how many seconds the data exceeds the allowed maximum age.
+    ///
+    /// Returns `None` if there is no maximum age. Otherwise returns the
+    /// number of seconds by which the allowed age is exceeded. This will be
+    /// negative if the data is within its objective.
+    pub fn over_max_age(&self) -> Option<i64> {
+        let slo = self.slo.load()?;
+        let since = self.update.load().unwrap_or(slo.since);
+        Some(
+            Utc::now().signed_duration_since(since).num_seconds()
+            .saturating_sub(slo.max_age)
+        )
+    }
+
+    /// Returns the history of the most recent changes to the data set.
+    pub fn diffs(&self) -> &DiffHistory {
+        &self.diffs
+    }
+
+    /// Returns whether the unit’s data is within its service level objective.
+    ///
+    /// Returns `None` if there is no objective.
+    pub fn within_slo(&self) -> Option<bool> {
+        self.over_max_age().map(|over| over <= 0)
+    }
+
+    /// Returns the number of links connected to the gate.
+    pub fn links(&self) -> usize {
+        self.links.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Returns the number of links that are currently suspended.
+    pub fn suspended_links(&self) -> usize {
+        self.suspended.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Returns whether the unit has been suspended by an operator.
+    pub fn is_suspended(&self) -> bool {
+        self.suspended_unit.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Returns the status of the gate.
+    ///
+    /// The gate is dormant if all its links are suspended, including when
+    /// there are no links at all, or the unit has been suspended by an
+    /// operator.
+    pub fn gate_status(&self) -> GateStatus {
+        if self.is_suspended() || self.suspended_links() == self.links() {
+            GateStatus::Dormant
+        }
+        else {
+            GateStatus::Active
+        }
+    }
+
+    /// Updates the number of links and suspended links.
+    fn update_links(&self, links: usize, suspended: usize) {
+        self.links.store(links, atomic::Ordering::Relaxed);
+        self.suspended.store(suspended, atomic::Ordering::Relaxed);
+    }
+
+    /// Returns the total number of items added and removed by updates.
+    pub fn changes(&self) -> (u64, u64) {
+        (
+            self.added.load(atomic::Ordering::Relaxed),
+            self.removed.load(atomic::Ordering::Relaxed),
+        )
+    }
+
+    /// Returns the number of items added or removed by the last update.
+    pub fn last_change(&self) -> usize {
+        self.last_change.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Returns the time it took to calculate the changes of the last update.
+    pub fn diff_duration(&self) -> Duration {
+        Duration::from_micros(
+            self.diff_duration.load(atomic::Ordering::Relaxed)
+        )
+    }
+
+    /// Records the number of items added and removed by an update.
+    ///
+    /// The first update of a unit adds all its items.
+    fn record_change(&self, added: usize, removed: usize) {
+        self.added.fetch_add(
+            u64::try_from(added).unwrap_or(u64::MAX),
+            atomic::Ordering::Relaxed
+        );
+        self.removed.fetch_add(
+            u64::try_from(removed).unwrap_or(u64::MAX),
+            atomic::Ordering::Relaxed
+        );
+        self.last_change.store(
+            added.saturating_add(removed), atomic::Ordering::Relaxed
+        );
+    }
+
     /// Updates the metrics to match the given update.
     fn update(&self, status: &UnitStatus) {
         if let Some(payload) = status.payload.as_ref() {
             self.count.store(
                 payload.set().len(), atomic::Ordering::Relaxed
             );
+            self.payload.store(true, atomic::Ordering::Relaxed);
         }
         self.update.store(Some(Utc::now()));
         self.health.store(status.health)
@@ -294,6 +770,24 @@ impl GateMetrics {
         "vrps", "the number of VRPs in the last update",
         MetricType::Gauge, MetricUnit::Total
     );
+    const ADDED_METRIC: Metric = Metric::new(
+        "vrps_added", "the number of items added by updates",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const REMOVED_METRIC: Metric = Metric::new(
+        "vrps_removed", "the number of items removed by updates",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const LAST_CHANGE_METRIC: Metric = Metric::new(
+        "last_update_change_size",
+        "the number of items added or removed by the last update",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const DIFF_DURATION_METRIC: Metric = Metric::new(
+        "last_update_diff_duration",
+        "the time it took to calculate the changes of the last update",
+        MetricType::Gauge, MetricUnit::Second
+    );
     const UPDATE_METRIC: Metric = Metric::new(
         "last_update", "the date and time of the last update",
         MetricType::Text, MetricUnit::Info
@@ -302,6 +796,35 @@ impl GateMetrics {
         "since_last_update", "the number of seconds since the last update",
         MetricType::Gauge, MetricUnit::Second
     );
+    const WITHIN_SLO_METRIC: Metric = Metric::new(
+        "data_within_slo",
+        "whether the age of the data is within the configured maximum",
+        MetricType::Gauge, MetricUnit::Info
+    );
+    const OVER_SLO_METRIC: Metric = Metric::new(
+        "data_over_slo",
+        "the number of seconds the data is older than the configured maximum",
+        MetricType::Gauge, MetricUnit::Second
+    );
+    const GATE_STATUS_METRIC: Metric = Metric::new(
+        "gate_status",
+        "whether any link currently wants updates from the unit",
+        MetricType::Text, MetricUnit::Info
+    );
+    const LINKS_METRIC: Metric = Metric::new(
+        "links", "the number of links connected to the unit",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const SUSPENDED_LINKS_METRIC: Metric = Metric::new(
+        "suspended_links",
+        "the number of links to the unit that are currently suspended",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const SUSPENDED_METRIC: Metric = Metric::new(
+        "unit_suspended",
+        "whether the unit has been suspended by an operator",
+        MetricType::Gauge, MetricUnit::Info
+    );
 }
 
 impl metrics::Source for GateMetrics {
@@ -317,6 +840,16 @@ impl metrics::Source for GateMetrics {
             &Self::COUNT_METRIC, Some(unit_name),
             self.count.load(atomic::Ordering::Relaxed)
         );
+        let (added, removed) = self.changes();
+        target.append_simple(&Self::ADDED_METRIC, Some(unit_name), added);
+        target.append_simple(&Self::REMOVED_METRIC, Some(unit_name), removed);
+        target.append_simple(
+            &Self::LAST_CHANGE_METRIC, Some(unit_name), self.last_change()
+        );
+        target.append_simple(
+            &Self::DIFF_DURATION_METRIC, Some(unit_name),
+            self.diff_duration().as_secs_f64()
+        );
         match self.update.load() {
             Some(update) => {
                 target.append_simple(
@@ -339,10 +872,104 @@ impl metrics::Source for GateMetrics {
                 );
             }
         }
+        if let Some(over) = self.over_max_age() {
+            target.append_simple(
+                &Self::WITHIN_SLO_METRIC, Some(unit_name),
+                if over <= 0 { 1 } else { 0 }
+            );
+            target.append_simple(
+                &Self::OVER_SLO_METRIC, Some(unit_name), over.max(0)
+            );
+        }
+        target.append_simple(
+            &Self::GATE_STATUS_METRIC, Some(unit_name), self.gate_status()
+        );
+        target.append_simple(
+            &Self::LINKS_METRIC, Some(unit_name), self.links()
+        );
+        target.append_simple(
+            &Self::SUSPENDED_LINKS_METRIC, Some(unit_name),
+            self.suspended_links()
+        );
+        target.append_simple(
+            &Self::SUSPENDED_METRIC, Some(unit_name),
+            u8::from(self.is_suspended())
+        );
+    }
+}
+
+
+//------------ DiffHistory ---------------------------------------------------
+
+/// The most recent changes to a unit’s data set.
+///
+/// The history keeps the diffs of up to a configured number of updates
+/// together with the time they happened. It starts out disabled, i.e.,
+/// with a size of zero, since calculating the diffs isn’t free.
+#[derive(Debug, Default)]
+pub struct DiffHistory {
+    /// The maximum number of diffs to keep.
+    size: AtomicUsize,
+
+    /// The diffs, oldest first.
+    diffs: Mutex<VecDeque<(DateTime<Utc>, payload::Diff)>>,
+}
+
+impl DiffHistory {
+    /// Sets the maximum number of diffs to keep.
+    ///
+    /// A size of zero disables the history.
+    pub fn set_size(&self, size: usize) {
+        self.size.store(size, atomic::Ordering::Relaxed);
+        let mut diffs = self.diffs.lock().unwrap();
+        while diffs.len() > size {
+            diffs.pop_front();
+        }
+    }
+
+    /// Returns the maximum number of diffs to keep.
+    pub fn size(&self) -> usize {
+        self.size.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Returns whether the history is enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.size() > 0
+    }
+
+    /// Adds a new diff, dropping the oldest one if necessary.
+    pub fn push(&self, diff: payload::Diff) {
+        let size = self.size();
+        if size == 0 {
+            return
+        }
+        let mut diffs = self.diffs.lock().unwrap();
+        while diffs.len() >= size {
+            diffs.pop_front();
+        }
+        diffs.push_back((Utc::now(), diff));
+    }
+
+    /// Returns a copy of the diffs currently kept, oldest first.
+    pub fn to_vec(&self) -> Vec<(DateTime<Utc>, payload::Diff)> {
+        self.diffs.lock().unwrap().iter().cloned().collect()
     }
 }
 
 
+//------------ DataSlo -------------------------------------------------------
+
+/// The service level objective for the age of a unit’s data.
+#[derive(Clone, Copy, Debug)]
+struct DataSlo {
+    /// The maximum age of the data in seconds.
+    max_age: i64,
+
+    /// The time to measure the age from if there hasn’t been an update.
+    since: DateTime<Utc>,
+}
+
+
 //------------ Link ----------------------------------------------------------
 
 /// A link to another unit.
@@ -419,7 +1046,14 @@ impl Link {
     /// Query for the next update.
     ///
     /// The method returns a future that resolves into the next update. The
-    /// future can be dropped safely at any time.
+    /// future can be dropped safely at any time, i.e., the method is cancel
+    /// safe: no update is lost if the future is dropped before it resolves.
+    /// Updates are only taken from the cancel safe update queue once they
+    /// are returned. If the future is dropped while subscribing to the
+    /// gate, the gate drops the subscription once it finds nobody waiting
+    /// for it and the next call subscribes again. Callers can therefore
+    /// query several links via `select!` or `select_all` in a loop and
+    /// re-create the futures every time.
     ///
     /// If this method is called when the unit status is “gone,” the future
     /// will never resolve.
@@ -551,11 +1185,21 @@ pub enum GateStatus {
     Dormant,
 }
 
+impl fmt::Display for GateStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            GateStatus::Active => "active",
+            GateStatus::Dormant => "dormant",
+        })
+    }
+}
+
 
 //------------ UnitHealth ----------------------------------------------------
 
 /// A unit’s self-perceived ability to produce updates.
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum UnitHealth {
     /// The unit is ready to produce data updates.
     ///
@@ -607,6 +1251,7 @@ impl fmt::Display for UnitHealth {
 ///
 /// This is a helper type that makes it easier to apply updates.
 #[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 struct UnitStatus {
     /// The current health of the unit.
     health: UnitHealth,
@@ -676,6 +1321,7 @@ impl UnitStatus {
 
 /// An update to the unit.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum UnitUpdate {
     /// A new payload set has become available.
     ///
@@ -714,6 +1360,12 @@ enum GateCommand {
         suspend: bool,
     },
 
+    /// Suspend or resume the unit on behalf of an operator.
+    Suspend {
+        /// Suspend the unit?
+        suspend: bool,
+    },
+
     /// Subscribe to the gate.
     Subscribe {
         /// Should the subscription start in suspended state?
@@ -766,3 +1418,335 @@ struct SubscribeResponse {
     unit_status: UnitStatus,
 }
 
+
+
+//============ Tests =========================================================
+
+#[cfg(all(test, feature = "arbitrary"))]
+mod test {
+    use super::*;
+    use arbitrary::{Arbitrary, Unstructured};
+    use rand::RngCore;
+    use tokio::time::timeout;
+
+    /// Runs `op` with a number of random unstructured data sources.
+    fn with_random_data(mut op: impl FnMut(&mut Unstructured)) {
+        let mut rng = rand_pcg::Pcg32::new(
+            0xcafef00dd15ea5e5, 0xa02bdbf7bb3c0a7
+        );
+        let mut buf = vec![0u8; 4096];
+        for _ in 0..200 {
+            rng.fill_bytes(&mut buf);
+            op(&mut Unstructured::new(&buf))
+        }
+    }
+
+    /// Creates a random sequence of updates.
+    ///
+    /// In order to exercise the deduplication of updates, an update is
+    /// sometimes repeated.
+    fn random_updates(u: &mut Unstructured) -> Vec<UnitUpdate> {
+        let mut res = Vec::<UnitUpdate>::new();
+        while !u.is_empty() {
+            let repeat = u.ratio(1u8, 4u8).unwrap();
+            let update = match (repeat, res.last()) {
+                (true, Some(last)) => last.clone(),
+                _ => match UnitUpdate::arbitrary(u) {
+                    Ok(update) => update,
+                    Err(_) => break,
+                }
+            };
+            res.push(update);
+        }
+        res
+    }
+
+    /// Asserts that two unit statuses are the same.
+    fn assert_status_eq(left: &UnitStatus, right: &UnitStatus) {
+        assert_eq!(left.health, right.health);
+        assert_eq!(left.payload, right.payload);
+    }
+
+    #[test]
+    fn apply_status() {
+        with_random_data(|u| {
+            let status = UnitStatus::arbitrary(u).unwrap();
+            let update = UnitUpdate::arbitrary(u).unwrap();
+            let mut applied = status.clone();
+            let changed = applied.apply(&update);
+
+            // The new health is always that of the update.
+            assert_eq!(applied.health, UnitHealth::from(&update));
+
+            // If there was no change, the status must already have
+            // represented the update.
+            if !changed {
+                assert_status_eq(&applied, &status);
+                match (status.to_update(), &update) {
+                    (
+                        Some(UnitUpdate::Payload(left)),
+                        UnitUpdate::Payload(right)
+                    ) => {
+                        assert_eq!(&left, right)
+                    }
+                    (Some(UnitUpdate::Stalled), UnitUpdate::Stalled) => { }
+                    (Some(UnitUpdate::Gone), UnitUpdate::Gone) => { }
+                    (left, right) => {
+                        panic!(
+                            "unchanged status {:?} for update {:?}",
+                            left, right
+                        )
+                    }
+                }
+            }
+
+            // Applying the same update again never changes anything.
+            let mut again = applied.clone();
+            assert!(!again.apply(&update));
+            assert_status_eq(&again, &applied);
+
+            // The status converts into an update that leads to the same
+            // health and, if healthy, the same payload.
+            if let Some(update) = applied.to_update() {
+                let mut fresh = UnitStatus::default();
+                fresh.apply(&update);
+                assert_eq!(fresh.health, applied.health);
+                if fresh.health == UnitHealth::Healthy {
+                    assert_eq!(fresh.payload, applied.payload);
+                }
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn gate_and_link_agree() {
+        let mut sequences = Vec::new();
+        with_random_data(|u| sequences.push(random_updates(u)));
+
+        for updates in sequences {
+            let mut updates = updates.into_iter();
+            let first = match updates.next() {
+                Some(first) => first,
+                None => continue,
+            };
+
+            let (mut gate, mut agent) = Gate::new();
+            let mut link = agent.create_link();
+
+            // The link connects upon the first query and receives the
+            // current status of the gate as its initial update.
+            gate.update(first).await;
+            gate.process_until(link.query()).await.unwrap();
+            assert_status_eq(&link.unit_status, &gate.unit_status);
+
+            for update in updates {
+                let mut expected = gate.unit_status.clone();
+                let expected_change = expected.apply(&update);
+                let changed = gate.update(update).await;
+                assert_eq!(changed, expected_change);
+                assert_status_eq(&gate.unit_status, &expected);
+
+                // Only changes are forwarded to the link.
+                if changed {
+                    link.query().await;
+                }
+                assert_status_eq(&link.unit_status, &gate.unit_status);
+                assert_eq!(link.health(), gate.unit_status.health);
+            }
+
+            // A link connecting late also sees the current status.
+            let mut late = agent.create_link();
+            gate.process_until(late.query()).await.unwrap();
+            assert_status_eq(&late.unit_status, &gate.unit_status);
+
+            // Once the gate is gone, so are the links.
+            drop(gate);
+            assert!(matches!(link.query().await, UnitUpdate::Gone));
+            assert_eq!(link.health(), UnitHealth::Gone);
+        }
+    }
+
+    #[tokio::test]
+    async fn query_cancel_safe() {
+        let (mut gate, mut agent) = Gate::new();
+        let mut link = agent.create_link();
+
+        // Dropping the query while subscribing doesn’t leave a
+        // subscription behind.
+        assert!(
+            timeout(Duration::from_millis(10), link.query()).await.is_err()
+        );
+        let set = payload::Set::from(payload::testrig::pack([1, 2, 3]));
+        gate.update(
+            UnitUpdate::Payload(payload::Update::new(set.clone()))
+        ).await;
+        gate.process_until(link.query()).await.unwrap();
+        assert_eq!(link.payload().unwrap().set(), &set);
+        assert_eq!(gate.updates.len(), 1);
+
+        // Dropping the query while waiting for an update doesn’t lose the
+        // update.
+        assert!(
+            timeout(Duration::from_millis(10), link.query()).await.is_err()
+        );
+        let set = payload::Set::from(payload::testrig::pack([4, 5]));
+        gate.update(
+            UnitUpdate::Payload(payload::Update::new(set.clone()))
+        ).await;
+        match link.query().await {
+            UnitUpdate::Payload(update) => assert_eq!(update.set(), &set),
+            update => panic!("unexpected update {:?}", update),
+        }
+    }
+
+    #[tokio::test]
+    async fn expire_stalled() {
+        let (mut gate, mut agent) = Gate::new();
+        gate.set_expire(Some(Duration::from_millis(50)));
+        let mut link = agent.create_link();
+        let set = payload::Set::from(payload::testrig::pack([1, 2, 3]));
+        gate.update(
+            UnitUpdate::Payload(payload::Update::new(set.clone()))
+        ).await;
+        gate.process_until(link.query()).await.unwrap();
+        assert_eq!(link.payload().unwrap().set(), &set);
+
+        // While stalled, the data is kept until it expires.
+        gate.update(UnitUpdate::Stalled).await;
+        assert!(matches!(
+            gate.process_until(link.query()).await.unwrap(),
+            UnitUpdate::Stalled
+        ));
+        match gate.process_until(link.query()).await.unwrap() {
+            UnitUpdate::Payload(update) => {
+                assert!(update.set().is_empty())
+            }
+            update => panic!("unexpected update {:?}", update),
+        }
+        assert!(matches!(
+            gate.process_until(link.query()).await.unwrap(),
+            UnitUpdate::Stalled
+        ));
+        assert_eq!(link.health(), UnitHealth::Stalled);
+        assert!(link.payload().unwrap().set().is_empty());
+        assert_eq!(gate.metrics().count(), 0);
+
+        // New data is passed on as usual.
+        gate.update(
+            UnitUpdate::Payload(payload::Update::new(set.clone()))
+        ).await;
+        assert!(matches!(
+            gate.process_until(link.query()).await.unwrap(),
+            UnitUpdate::Payload(_)
+        ));
+        assert_eq!(link.payload().unwrap().set(), &set);
+    }
+
+    #[tokio::test]
+    async fn carried_diffs() {
+        use payload::testrig::pack;
+
+        fn update(
+            set: &payload::Set, previous: Option<&payload::Set>
+        ) -> UnitUpdate {
+            let update = payload::Update::new(set.clone());
+            UnitUpdate::Payload(match previous {
+                Some(previous) => {
+                    update.with_diff(previous, set.diff_from(previous))
+                }
+                None => update
+            })
+        }
+
+        let (mut gate, mut agent) = Gate::new();
+        let mut link = agent.create_link();
+        let first = payload::Set::from(pack([1, 2, 3, 4, 5, 6]));
+        gate.update(update(&first, None)).await;
+        gate.process_until(link.query()).await.unwrap();
+        assert_eq!(link.payload().unwrap().set(), &first);
+
+        // The unit makes its diffs from its own sets while the gate keeps
+        // the interned sets. The diffs are passed on nonetheless.
+        let mut unit_previous = first;
+        for values in [[1, 2, 3, 5, 6, 7], [2, 3, 5, 6, 7, 8]] {
+            let gate_previous = link.payload().unwrap().set().clone();
+            let set = payload::Set::from(pack(values));
+            let unit_update = update(&set, Some(&unit_previous));
+            let carried = match &unit_update {
+                UnitUpdate::Payload(payload) => {
+                    payload.diff_since(&unit_previous)
+                }
+                _ => unreachable!()
+            };
+            assert!(gate.update(unit_update).await);
+            gate.process_until(link.query()).await.unwrap();
+            let received = link.payload().unwrap();
+            assert_eq!(received.set(), &set);
+            assert!(!received.set().downgrade().is(&set));
+            let diff = received.diff_from_ref(
+                &gate_previous.downgrade()
+            ).unwrap();
+            assert_eq!(
+                diff.announced().as_slice().as_ptr(),
+                carried.announced().as_slice().as_ptr()
+            );
+            unit_previous = set;
+        }
+    }
+
+    #[tokio::test]
+    async fn change_metrics() {
+        let (mut gate, _agent) = Gate::new();
+        gate.update(UnitUpdate::Payload(payload::Update::new(
+            payload::testrig::pack([1, 2, 3]).into()
+        ))).await;
+        assert_eq!(gate.metrics().changes(), (3, 0));
+        assert_eq!(gate.metrics().last_change(), 3);
+
+        gate.update(UnitUpdate::Payload(payload::Update::new(
+            payload::testrig::pack([2, 3, 4, 5]).into()
+        ))).await;
+        assert_eq!(gate.metrics().changes(), (5, 1));
+        assert_eq!(gate.metrics().last_change(), 3);
+
+        // Updates that don’t change anything aren’t counted.
+        gate.update(UnitUpdate::Payload(payload::Update::new(
+            payload::testrig::pack([2, 3, 4, 5]).into()
+        ))).await;
+        assert_eq!(gate.metrics().changes(), (5, 1));
+        assert_eq!(gate.metrics().last_change(), 3);
+    }
+
+    #[tokio::test]
+    async fn suspend_unit() {
+        let (mut gate, mut agent) = Gate::new();
+        let mut link = agent.create_link();
+        let set = payload::Set::from(payload::testrig::pack([1, 2, 3]));
+        gate.update(
+            UnitUpdate::Payload(payload::Update::new(set.clone()))
+        ).await;
+        gate.process_until(link.query()).await.unwrap();
+
+        // A suspended unit appears stalled and its gate is dormant.
+        assert!(agent.suspend(true));
+        assert!(matches!(
+            gate.process_until(link.query()).await.unwrap(),
+            UnitUpdate::Stalled
+        ));
+        assert_eq!(gate.gate_status(), GateStatus::Dormant);
+        assert!(gate.metrics().is_suspended());
+
+        // Updates are not passed on but kept for resuming.
+        let set = payload::Set::from(payload::testrig::pack([4, 5]));
+        assert!(gate.update(
+            UnitUpdate::Payload(payload::Update::new(set.clone()))
+        ).await);
+        assert!(agent.suspend(false));
+        match gate.process_until(link.query()).await.unwrap() {
+            UnitUpdate::Payload(update) => assert_eq!(update.set(), &set),
+            update => panic!("unexpected update {:?}", update),
+        }
+        assert_eq!(link.health(), UnitHealth::Healthy);
+        assert!(!gate.metrics().is_suspended());
+    }
+}
@@ -5,16 +5,22 @@
 //! this module. This struct also provides the facilities to load the config
 //! file referred to in command line options.
 
-use std::{borrow, error, fmt, fs, io, ops};
+use std::{borrow, env, error, fmt, fs, hash, io, ops};
+use std::cell::Cell;
 use std::path::Path;
 use std::sync::Arc;
 use clap::{Args as _, FromArgMatches};
 use daemonbase::logging;
 use daemonbase::config::ConfigPath;
 use daemonbase::error::Failed;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::{Error as _, IntoDeserializer};
 use toml::Spanned;
 use crate::http;
+use crate::cache::CacheConfig;
+use crate::events::EventsConfig;
+use crate::handoff::HandoffConfig;
+use crate::log::AuditConfig;
 use crate::manager::{HttpClientConfig, Manager, TargetSet, UnitSet};
 
 
@@ -42,6 +48,10 @@ pub struct Config {
     #[serde(flatten)]
     pub log: logging::Config,
 
+    /// The audit log configuration.
+    #[serde(flatten)]
+    pub audit: AuditConfig,
+
     /// The HTTP server configuration.
     #[serde(flatten)]
     pub http: http::Server,
@@ -49,6 +59,24 @@ pub struct Config {
     /// The HTTP client configuration.
     #[serde(flatten)]
     pub http_client: HttpClientConfig,
+
+    /// The event notification configuration.
+    #[serde(flatten)]
+    pub events: EventsConfig,
+
+    /// The state handoff configuration.
+    #[serde(flatten)]
+    pub handoff: HandoffConfig,
+
+    /// The data cache configuration.
+    #[serde(flatten)]
+    pub cache: CacheConfig,
+
+    /// The units that need to have data for the instance to be ready.
+    ///
+    /// If this is `None`, all units feeding a target need to have data.
+    #[serde(rename = "ready-units")]
+    pub ready_units: Option<Vec<String>>,
 }
 
 impl Config {
@@ -59,18 +87,58 @@ impl Config {
         Args::augment_args(app)
     }
 
+    /// Checks the parts of the configuration only used at run time.
+    ///
+    /// Loading the configuration already checks its syntax and resolves
+    /// all links between components. This method additionally loads all
+    /// files referenced by units, targets, and HTTP servers, such as TLS
+    /// certificates and SLURM files. All problems are logged.
+    pub fn check(&self) -> Result<(), Failed> {
+        let units = self.units.check();
+        let targets = self.targets.check();
+        let http = self.http.check();
+        units.and(targets).and(http)
+    }
+
     /// Creates a configuration from a bytes slice with TOML data.
+    ///
+    /// If `profile` is given, the profile of this name is applied to the
+    /// data. It is an error if the data doesn’t define this profile.
     pub fn from_toml(
         slice: &str, base_dir: Option<impl AsRef<Path>>,
+        profile: Option<&str>,
     ) -> Result<Self, toml::de::Error> {
         if let Some(ref base_dir) = base_dir {
             ConfigPath::set_base_path(base_dir.as_ref().into())
         }
-        let res = toml::de::from_str(slice);
+        let res = Self::deserialize_toml(slice, profile);
         ConfigPath::clear_base_path();
         res
     }
 
+    /// Deserializes the configuration from TOML data.
+    ///
+    /// If a profile is selected, it is applied first. If the data contains
+    /// templates, these are then instantiated into units and targets and
+    /// target groups are expanded into the targets. Finally, environment
+    /// variables given as `${env:NAME}` are expanded in all string values.
+    /// Because this happens on the parsed TOML data, source positions are
+    /// not available for the values in all these cases.
+    fn deserialize_toml(
+        slice: &str, profile: Option<&str>,
+    ) -> Result<Self, toml::de::Error> {
+        let mut table: toml::Table = toml::de::from_str(slice)?;
+        if profile.is_none()
+            && !table.contains_key("templates")
+            && !table.contains_key("target-groups")
+            && !slice.contains("${env:")
+        {
+            return toml::de::from_str(slice)
+        }
+        expand_table(&mut table, profile)?;
+        without_spans(|| table.try_into())
+    }
+
     /// Loads the configuration based on command line options provided.
     ///
     /// The `matches` must be the result of getting argument matches from a
@@ -88,7 +156,7 @@ impl Config {
         let args = Args::from_arg_matches(
             matches
         ).expect("bug in command line arguments parser");
-        let conf = match ConfigFile::load(&args.config) {
+        let mut conf = match ConfigFile::load(&args.config) {
             Ok(conf) => conf,
             Err(err) => {
                 eprintln!(
@@ -99,6 +167,7 @@ impl Config {
                 return Err(Failed)
             }
         };
+        conf.set_profile(args.profile);
         let (manager, mut config) = Manager::load(conf)?;
         config.log.apply_args(&args.log);
         Ok((manager, config))
@@ -113,11 +182,553 @@ pub struct Args {
     #[arg(short, long)]
     pub config: ConfigPath,
 
+    /// The name of the configuration profile to activate.
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+
     #[command(flatten)]
     pub log: logging::Args,
 }
 
 
+//------------ Expansion -----------------------------------------------------
+
+/// Expands the TOML data into the plain configuration.
+///
+/// This applies the selected profile, instantiates templates, expands
+/// target groups, and finally expands environment variables.
+fn expand_table(
+    table: &mut toml::Table, profile: Option<&str>,
+) -> Result<(), toml::de::Error> {
+    apply_profile(table, profile)?;
+    expand_templates(table)?;
+    expand_target_groups(table)?;
+    expand_env(table)
+}
+
+
+//------------ Profiles ------------------------------------------------------
+
+/// Applies the selected profile to the TOML data.
+///
+/// Profiles are defined in the `profile` table. Each profile is a table
+/// with the same structure as the configuration itself. The values of the
+/// selected profile are merged into the configuration: tables are merged
+/// recursively while all other values replace those present in the
+/// configuration.
+///
+/// The `profile` table is always removed.
+fn apply_profile(
+    table: &mut toml::Table, profile: Option<&str>,
+) -> Result<(), toml::de::Error> {
+    let profiles = match table.remove("profile") {
+        Some(toml::Value::Table(profiles)) => Some(profiles),
+        Some(_) => {
+            return Err(toml::de::Error::custom(
+                "profile must be a table"
+            ))
+        }
+        None => None
+    };
+    let name = match profile {
+        Some(name) => name,
+        None => return Ok(())
+    };
+    match profiles.and_then(|mut profiles| profiles.remove(name)) {
+        Some(toml::Value::Table(profile)) => {
+            merge_tables(table, profile);
+            Ok(())
+        }
+        Some(_) => {
+            Err(toml::de::Error::custom(format!(
+                "profile '{}' must be a table", name
+            )))
+        }
+        None => {
+            Err(toml::de::Error::custom(format!(
+                "unknown profile '{}'", name
+            )))
+        }
+    }
+}
+
+/// Merges the `overlay` table into the `base` table.
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        if let toml::Value::Table(overlay) = value {
+            if let Some(toml::Value::Table(base)) = base.get_mut(&key) {
+                merge_tables(base, overlay);
+                continue
+            }
+            base.insert(key, toml::Value::Table(overlay));
+        }
+        else {
+            base.insert(key, value);
+        }
+    }
+}
+
+
+//------------ Templates -----------------------------------------------------
+
+/// Instantiates the templates in the TOML data.
+///
+/// Templates are defined in the `templates` table. Each template is a table
+/// with optional `units` and `targets` tables defining components just like
+/// the configuration itself and an `instances` array of parameter tables.
+/// For each instance, the template’s components are added to the
+/// configuration with all placeholders of the form `${param}` in their
+/// names and string values replaced with the value of the parameter. A
+/// string consisting of only a placeholder is replaced with the parameter
+/// value itself, keeping its type. A literal `$` is given as `$$`.
+///
+/// Placeholders for unknown parameters in string values are left alone so
+/// that values can contain placeholders of their own, such as those of the
+/// json unit. In names, they are an error.
+///
+/// The `templates` table is removed.
+fn expand_templates(table: &mut toml::Table) -> Result<(), toml::de::Error> {
+    let templates = match table.remove("templates") {
+        Some(toml::Value::Table(templates)) => templates,
+        Some(_) => {
+            return Err(toml::de::Error::custom(
+                "templates must be a table"
+            ))
+        }
+        None => return Ok(())
+    };
+    for (name, template) in templates {
+        let err = |msg: String| {
+            toml::de::Error::custom(format!("template '{}': {}", name, msg))
+        };
+        let mut template = match template {
+            toml::Value::Table(template) => template,
+            _ => return Err(err("must be a table".into()))
+        };
+        let instances = match template.remove("instances") {
+            Some(toml::Value::Array(instances)) => instances,
+            Some(_) => return Err(err("instances must be an array".into())),
+            None => Vec::new(),
+        };
+        for key in template.keys() {
+            if key != "units" && key != "targets" {
+                return Err(err(format!("unexpected key '{}'", key)))
+            }
+        }
+        for (idx, instance) in instances.iter().enumerate() {
+            let params = instance.as_table().ok_or_else(|| {
+                err(format!("instance {} must be a table", idx))
+            })?;
+            for (class, components) in &template {
+                let components = components.as_table().ok_or_else(|| {
+                    err(format!("{} must be a table", class))
+                })?;
+                let target = table.entry(class.as_str()).or_insert_with(|| {
+                    toml::Value::Table(Default::default())
+                }).as_table_mut().ok_or_else(|| {
+                    toml::de::Error::custom(
+                        format!("{} must be a table", class)
+                    )
+                })?;
+                for (key, value) in components {
+                    let key = expand_name(key, params).map_err(|msg| {
+                        err(format!("instance {}: {}", idx, msg))
+                    })?;
+                    let value = expand_value(value, params).map_err(|msg| {
+                        err(format!("instance {}: {}", idx, msg))
+                    })?;
+                    if target.contains_key(&key) {
+                        return Err(err(format!(
+                            "instance {}: duplicate name '{}' in {}",
+                            idx, key, class
+                        )))
+                    }
+                    target.insert(key, value);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the placeholders in a TOML value.
+fn expand_value(
+    value: &toml::Value, params: &toml::Table
+) -> Result<toml::Value, String> {
+    match value {
+        toml::Value::String(value) => {
+            let param = value.strip_prefix("${").and_then(|value| {
+                value.strip_suffix('}')
+            }).and_then(|name| params.get(name));
+            match param {
+                Some(param) => Ok(param.clone()),
+                None => expand_str(value, params).map(toml::Value::String)
+            }
+        }
+        toml::Value::Array(values) => {
+            values.iter().map(|value| {
+                expand_value(value, params)
+            }).collect::<Result<_, _>>().map(toml::Value::Array)
+        }
+        toml::Value::Table(values) => {
+            values.iter().map(|(key, value)| {
+                Ok::<_, String>((
+                    expand_name(key, params)?,
+                    expand_value(value, params)?
+                ))
+            }).collect::<Result<_, _>>().map(toml::Value::Table)
+        }
+        value => Ok(value.clone())
+    }
+}
+
+/// Replaces the placeholders in a string value.
+///
+/// Placeholders for unknown parameters are kept.
+fn expand_str(value: &str, params: &toml::Table) -> Result<String, String> {
+    substitute(value, |name| param_str(params, name))
+}
+
+/// Replaces the placeholders in the name of a component or key.
+///
+/// Unlike in values, placeholders for unknown parameters are an error.
+fn expand_name(
+    value: &str, params: &toml::Table
+) -> Result<String, String> {
+    substitute(value, |name| {
+        param_str(params, name)?.map(Some).ok_or_else(|| {
+            format!("unknown parameter '{}'", name)
+        })
+    })
+}
+
+/// Returns the value of a parameter for use within a string.
+///
+/// Parameters used within a string must be strings, integers, floats, or
+/// booleans. Returns `Ok(None)` if there is no such parameter.
+fn param_str(
+    params: &toml::Table, name: &str
+) -> Result<Option<String>, String> {
+    match params.get(name) {
+        Some(toml::Value::String(param)) => Ok(Some(param.clone())),
+        Some(
+            param @ (toml::Value::Integer(_) | toml::Value::Float(_)
+            | toml::Value::Boolean(_))
+        ) => {
+            Ok(Some(param.to_string()))
+        }
+        Some(_) => {
+            Err(format!(
+                "parameter '{}' can’t be used within a string", name
+            ))
+        }
+        None => Ok(None)
+    }
+}
+
+/// Replaces all placeholders of the form `${name}` in a string.
+///
+/// The replacement for each placeholder is determined by `lookup`. If it
+/// returns `None`, the placeholder is kept as is. A literal dollar sign
+/// can be escaped as `$$`. A dollar sign not followed by an opening brace
+/// is kept as is.
+fn substitute(
+    value: &str,
+    mut lookup: impl FnMut(&str) -> Result<Option<String>, String>,
+) -> Result<String, String> {
+    let mut res = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(pos) = rest.find('$') {
+        res.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        if let Some(tail) = rest.strip_prefix('$') {
+            res.push('$');
+            rest = tail;
+            continue
+        }
+        let tail = match rest.strip_prefix('{') {
+            Some(tail) => tail,
+            None => {
+                res.push('$');
+                continue
+            }
+        };
+        let end = tail.find('}').ok_or_else(|| {
+            format!("unterminated placeholder in '{}'", value)
+        })?;
+        let name = &tail[..end];
+        match lookup(name)? {
+            Some(value) => res.push_str(&value),
+            None => {
+                res.push_str("${");
+                res.push_str(name);
+                res.push('}');
+            }
+        }
+        rest = &tail[end + 1..];
+    }
+    res.push_str(rest);
+    Ok(res)
+}
+
+
+//------------ Environment Variables -----------------------------------------
+
+/// Expands environment variables in the TOML data.
+///
+/// All placeholders of the form `${env:NAME}` in string values are
+/// replaced with the value of the environment variable `NAME`. Everything
+/// else is left alone, including other placeholders and escaped dollar
+/// signs, since these may have a meaning for the option the string is used
+/// with. An escaped placeholder `$${env:NAME}` is not expanded. Keys are
+/// left alone, too.
+fn expand_env(table: &mut toml::Table) -> Result<(), toml::de::Error> {
+    expand_env_table(table, &mut |name| env::var(name).ok())
+}
+
+/// Expands placeholders in all string values of a table.
+///
+/// The values for the placeholders are determined via `lookup`. This is
+/// separate from [`expand_env`] so it can be tested without touching the
+/// environment.
+fn expand_env_table(
+    table: &mut toml::Table,
+    lookup: &mut impl FnMut(&str) -> Option<String>,
+) -> Result<(), toml::de::Error> {
+    for (key, value) in table.iter_mut() {
+        expand_env_value(value, lookup).map_err(|err| {
+            toml::de::Error::custom(format!("{}: {}", key, err))
+        })?;
+    }
+    Ok(())
+}
+
+/// Expands placeholders in a TOML value.
+fn expand_env_value(
+    value: &mut toml::Value,
+    lookup: &mut impl FnMut(&str) -> Option<String>,
+) -> Result<(), String> {
+    match value {
+        toml::Value::String(value) => {
+            *value = expand_env_str(value, lookup)?;
+        }
+        toml::Value::Array(values) => {
+            for value in values {
+                expand_env_value(value, lookup)?;
+            }
+        }
+        toml::Value::Table(values) => {
+            for (key, value) in values.iter_mut() {
+                expand_env_value(value, lookup).map_err(|err| {
+                    format!("{}.{}", key, err)
+                })?;
+            }
+        }
+        _ => { }
+    }
+    Ok(())
+}
+
+/// Expands the environment variable placeholders in a string.
+fn expand_env_str(
+    value: &str,
+    lookup: &mut impl FnMut(&str) -> Option<String>,
+) -> Result<String, String> {
+    let mut res = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(pos) = rest.find('$') {
+        res.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if let Some(tail) = rest.strip_prefix("$$") {
+            res.push_str("$$");
+            rest = tail;
+        }
+        else if let Some(tail) = rest.strip_prefix("${env:") {
+            let (name, tail) = tail.split_once('}').ok_or_else(|| {
+                format!("unterminated placeholder in '{}'", value)
+            })?;
+            res.push_str(&lookup(name).ok_or_else(|| {
+                format!("environment variable '{}' not set", name)
+            })?);
+            rest = tail;
+        }
+        else {
+            res.push('$');
+            rest = &rest[1..];
+        }
+    }
+    res.push_str(rest);
+    Ok(res)
+}
+
+
+//------------ Target Groups -------------------------------------------------
+
+/// Expands the target groups in the TOML data.
+///
+/// Target groups are defined in the `target-groups` table. Each group is a
+/// table of target options. A target refers to a group via its `group`
+/// option and receives all options of the group it doesn’t define itself.
+///
+/// The `target-groups` table and the `group` options are removed.
+fn expand_target_groups(
+    table: &mut toml::Table
+) -> Result<(), toml::de::Error> {
+    let groups = match table.remove("target-groups") {
+        Some(toml::Value::Table(groups)) => groups,
+        Some(_) => {
+            return Err(toml::de::Error::custom(
+                "target-groups must be a table"
+            ))
+        }
+        None => return Ok(())
+    };
+    let targets = match table.get_mut("targets") {
+        Some(toml::Value::Table(targets)) => targets,
+        _ => return Ok(())
+    };
+    for (name, target) in targets.iter_mut() {
+        // Let deserialization complain about broken targets.
+        let target = match target.as_table_mut() {
+            Some(target) => target,
+            None => continue,
+        };
+        let group = match target.remove("group") {
+            Some(toml::Value::String(group)) => group,
+            Some(_) => {
+                return Err(toml::de::Error::custom(format!(
+                    "target '{}': group must be a string", name
+                )))
+            }
+            None => continue
+        };
+        let group = match groups.get(&group) {
+            Some(toml::Value::Table(group)) => group,
+            Some(_) => {
+                return Err(toml::de::Error::custom(format!(
+                    "target group '{}' must be a table", group
+                )))
+            }
+            None => {
+                return Err(toml::de::Error::custom(format!(
+                    "target '{}': unknown target group '{}'", name, group
+                )))
+            }
+        };
+        for (key, value) in group {
+            if !target.contains_key(key) {
+                target.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+
+//------------ Disabled ------------------------------------------------------
+
+/// A component type that has been disabled at compile time.
+///
+/// This type is used in place of the configuration of units and targets
+/// that are not available because the cargo feature providing them has not
+/// been enabled. Trying to deserialize a value always fails with an error
+/// explaining the situation. Because of this, values of this type can never
+/// exist.
+#[derive(Debug)]
+pub enum Disabled { }
+
+impl<'de> Deserialize<'de> for Disabled {
+    fn deserialize<D: Deserializer<'de>>(
+        _deserializer: D
+    ) -> Result<Self, D::Error> {
+        Err(serde::de::Error::custom(
+            "this component type is not supported by this build of RTRTR"
+        ))
+    }
+}
+
+
+//------------ Secret --------------------------------------------------------
+
+/// A secret value such as a password or token.
+///
+/// In the configuration, the secret can be given directly as a string.
+/// Alternatively, a string of the form `env:VAR` takes the secret from the
+/// environment variable `VAR` and a string of the form `file:PATH` reads it
+/// from the file at `PATH`, relative to the directory of the config file,
+/// with trailing line breaks removed. These indirections are resolved when
+/// the configuration is loaded so that an error is reported right away if
+/// they cannot be resolved.
+///
+/// The `Debug` implementation doesn’t reveal the secret.
+#[derive(Clone, Eq, PartialEq)]
+pub struct Secret(Arc<str>);
+
+impl Secret {
+    /// Returns the secret value.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Resolves the secret from a configuration value.
+    fn resolve<E: serde::de::Error>(value: &str) -> Result<Self, E> {
+        if let Some(var) = value.strip_prefix("env:") {
+            std::env::var(var).map(Into::into).map_err(|err| {
+                E::custom(format!(
+                    "cannot read secret from environment variable {}: {}",
+                    var, err
+                ))
+            })
+        }
+        else if let Some(path) = value.strip_prefix("file:") {
+            let path = ConfigPath::deserialize(
+                IntoDeserializer::<E>::into_deserializer(path.to_string())
+            )?;
+            match fs::read_to_string(&path) {
+                Ok(secret) => {
+                    Ok(secret.trim_end_matches(['\r', '\n']).into())
+                }
+                Err(err) => {
+                    Err(E::custom(format!(
+                        "cannot read secret from file {}: {}",
+                        path.display(), err
+                    )))
+                }
+            }
+        }
+        else {
+            Ok(value.into())
+        }
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(src: &str) -> Self {
+        Secret(src.into())
+    }
+}
+
+impl From<String> for Secret {
+    fn from(src: String) -> Self {
+        Secret(src.into())
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D
+    ) -> Result<Self, D::Error> {
+        Self::resolve(&String::deserialize(deserializer)?)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+
 //------------ Source --------------------------------------------------------
 
 /// Description of the source of configuration.
@@ -163,20 +774,34 @@ struct LineCol {
 /// This wrapper is used when data needs to be resolved after parsing has
 /// finished. In this case, we need information about the source location
 /// to be able to produce meaningful error messages.
-#[derive(Clone, Debug, Deserialize)]
-#[serde(from = "Spanned<T>")]
+#[derive(Clone, Debug)]
 pub struct Marked<T> {
     value: T,
-    index: usize,
+    index: Option<usize>,
     source: Option<Source>,
     pos: Option<LineCol>,
 }
 
+thread_local!(
+    /// Whether the current deserializer provides source positions.
+    static SPANNED: Cell<bool> = const { Cell::new(true) }
+);
+
+/// Runs a closure with a deserializer that doesn’t provide positions.
+///
+/// Values deserialized within the closure are not marked.
+fn without_spans<R>(op: impl FnOnce() -> R) -> R {
+    SPANNED.with(|spanned| spanned.set(false));
+    let res = op();
+    SPANNED.with(|spanned| spanned.set(true));
+    res
+}
+
 impl<T> Marked<T> {
     /// Resolves the position for the given config file.
     pub fn resolve_config(&mut self, config: &ConfigFile) {
         self.source = Some(config.source.clone());
-        self.pos = Some(config.resolve_pos(self.index));
+        self.pos = self.index.map(|index| config.resolve_pos(index));
     }
 
     /// Returns a reference to the value.
@@ -222,7 +847,7 @@ impl<T> From<T> for Marked<T> {
     fn from(src: T) -> Marked<T> {
         Marked {
             value: src,
-            index: 0,
+            index: None,
             source: None, pos: None,
         }
     }
@@ -231,7 +856,7 @@ impl<T> From<T> for Marked<T> {
 impl<T> From<Spanned<T>> for Marked<T> {
     fn from(src: Spanned<T>) -> Marked<T> {
         Marked {
-            index: src.span().start,
+            index: Some(src.span().start),
             value: src.into_inner(),
             source: None, pos: None,
         }
@@ -239,6 +864,22 @@ impl<T> From<Spanned<T>> for Marked<T> {
 }
 
 
+//--- Deserialize
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Marked<T> {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D
+    ) -> Result<Self, D::Error> {
+        if SPANNED.with(|spanned| spanned.get()) {
+            Spanned::<T>::deserialize(deserializer).map(Into::into)
+        }
+        else {
+            T::deserialize(deserializer).map(Into::into)
+        }
+    }
+}
+
+
 //--- Deref, AsRef, Borrow
 
 impl<T> ops::Deref for Marked<T> {
@@ -262,6 +903,26 @@ impl<T> borrow::Borrow<T> for Marked<T> {
 }
 
 
+//--- PartialEq, Eq, and Hash
+//
+// These only consider the value so that marked values can be used as keys
+// that are looked up via their unmarked value.
+
+impl<T: PartialEq> PartialEq for Marked<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.value.eq(&other.value)
+    }
+}
+
+impl<T: Eq> Eq for Marked<T> { }
+
+impl<T: hash::Hash> hash::Hash for Marked<T> {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.value.hash(state)
+    }
+}
+
+
 //--- Display and Error
 
 impl<T: fmt::Display> fmt::Display for Marked<T> {
@@ -285,6 +946,9 @@ pub struct ConfigFile {
     /// The data of this file.
     bytes: String,
 
+    /// The name of the profile to apply to the data.
+    profile: Option<String>,
+
     /// The start indexes of lines.
     ///
     /// The start index of the first line is in `line_start[0]` and so on.
@@ -307,10 +971,21 @@ impl ConfigFile {
                     }
                 ),
                 bytes,
+                profile: None,
             }
         })
     }
 
+    /// Selects the profile to apply when loading the configuration.
+    pub fn set_profile(&mut self, profile: Option<String>) {
+        self.profile = profile
+    }
+
+    /// Returns the name of the selected profile if there is one.
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
     pub fn path(&self) -> Option<&Path> {
         self.source.path.as_ref().map(|path| path.as_ref())
     }
@@ -323,6 +998,32 @@ impl ConfigFile {
         &self.bytes
     }
 
+    /// Loads the current content of the file from disk again.
+    ///
+    /// The selected profile is kept. Fails if the configuration wasn’t
+    /// loaded from a file in the first place.
+    pub fn reread(&self) -> Result<Self, io::Error> {
+        let path = self.path().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported, "not loaded from a file"
+            )
+        })?;
+        let mut res = Self::load(&path)?;
+        res.profile = self.profile.clone();
+        Ok(res)
+    }
+
+    /// Returns the expanded configuration as a TOML table.
+    ///
+    /// This is the data the configuration is deserialized from, i.e., with
+    /// the profile applied and templates, target groups, and environment
+    /// variables expanded.
+    pub fn expanded(&self) -> Result<toml::Table, toml::de::Error> {
+        let mut table: toml::Table = toml::de::from_str(&self.bytes)?;
+        expand_table(&mut table, self.profile())?;
+        Ok(table)
+    }
+
     fn resolve_pos(&self, pos: usize) -> LineCol {
         let line = self.line_starts.iter().enumerate().find_map(|(i, start)|
             if *start > pos {
@@ -339,6 +1040,99 @@ impl ConfigFile {
 }
 
 
+//------------ ConfigDiff ----------------------------------------------------
+
+/// The differences between two configurations.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct ConfigDiff {
+    /// The differences in the units.
+    pub units: ComponentDiff,
+
+    /// The differences in the targets.
+    pub targets: ComponentDiff,
+
+    /// Have any values outside of units and targets changed?
+    #[serde(rename = "settings-changed")]
+    pub settings_changed: bool,
+}
+
+impl ConfigDiff {
+    /// Determines the differences between two expanded configurations.
+    pub fn new(old: &toml::Table, new: &toml::Table) -> Self {
+        let settings = |table: &toml::Table| {
+            let mut table = table.clone();
+            table.remove("units");
+            table.remove("targets");
+            table
+        };
+        ConfigDiff {
+            units: ComponentDiff::new(old.get("units"), new.get("units")),
+            targets: ComponentDiff::new(
+                old.get("targets"), new.get("targets")
+            ),
+            settings_changed: settings(old) != settings(new),
+        }
+    }
+
+    /// Returns whether the two configurations are the same.
+    pub fn is_empty(&self) -> bool {
+        self.units.is_empty() && self.targets.is_empty()
+            && !self.settings_changed
+    }
+}
+
+
+//------------ ComponentDiff -------------------------------------------------
+
+/// The differences between two sets of components.
+///
+/// Each list contains the names of the components in alphabetical order.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct ComponentDiff {
+    /// The components only present in the new configuration.
+    pub added: Vec<String>,
+
+    /// The components only present in the old configuration.
+    pub removed: Vec<String>,
+
+    /// The components present in both but configured differently.
+    pub changed: Vec<String>,
+}
+
+impl ComponentDiff {
+    /// Determines the differences between two component tables.
+    ///
+    /// A missing table or a value that isn’t a table is treated as an
+    /// empty table.
+    fn new(old: Option<&toml::Value>, new: Option<&toml::Value>) -> Self {
+        let empty = toml::Table::new();
+        let old = old.and_then(toml::Value::as_table).unwrap_or(&empty);
+        let new = new.and_then(toml::Value::as_table).unwrap_or(&empty);
+        let mut res = ComponentDiff::default();
+        for (name, value) in new {
+            match old.get(name) {
+                Some(old) if old == value => { }
+                Some(_) => res.changed.push(name.clone()),
+                None => res.added.push(name.clone()),
+            }
+        }
+        res.removed = old.keys().filter(|name| {
+            !new.contains_key(*name)
+        }).cloned().collect();
+        res.added.sort();
+        res.removed.sort();
+        res.changed.sort();
+        res
+    }
+
+    /// Returns whether there are no differences.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+            && self.changed.is_empty()
+    }
+}
+
+
 //------------ ConfigError --------------------------------------------------
 
 /// An error occurred during parsing of a configuration file.
@@ -353,7 +1147,7 @@ impl ConfigError {
         ConfigError {
             pos: Marked {
                 value: (),
-                index: 0,
+                index: None,
                 source: Some(file.source.clone()),
                 pos: err.span().map(|range| {
                     file.resolve_pos(range.start)
@@ -373,3 +1167,344 @@ impl fmt::Display for ConfigError {
 
 impl error::Error for ConfigError { }
 
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn config_diff() {
+        let old: toml::Table = toml::from_str(r#"
+            log-level = "info"
+
+            [units.vrps]
+            type = "json"
+            refresh = 60
+
+            [units.backup]
+            type = "json"
+            refresh = 600
+
+            [targets.rtr]
+            type = "rtr"
+            unit = "vrps"
+        "#).unwrap();
+        let new: toml::Table = toml::from_str(r#"
+            log-level = "info"
+
+            [units.vrps]
+            type = "json"
+            refresh = 30
+
+            [units.local]
+            type = "slurm"
+
+            [targets.rtr]
+            type = "rtr"
+            unit = "vrps"
+        "#).unwrap();
+        let diff = ConfigDiff::new(&old, &new);
+        assert_eq!(diff.units.added, ["local"]);
+        assert_eq!(diff.units.removed, ["backup"]);
+        assert_eq!(diff.units.changed, ["vrps"]);
+        assert!(diff.targets.is_empty());
+        assert!(!diff.settings_changed);
+        assert!(!diff.is_empty());
+
+        assert!(ConfigDiff::new(&old, &old).is_empty());
+        let mut debug = old.clone();
+        debug.insert("log-level".into(), "debug".into());
+        let diff = ConfigDiff::new(&old, &debug);
+        assert!(diff.settings_changed);
+        assert!(diff.units.is_empty());
+    }
+
+    #[test]
+    fn target_groups() {
+        let mut table: toml::Table = toml::from_str(r#"
+            [target-groups.edge]
+            type = "rtr"
+            unit = "vrps"
+            refresh = 600
+
+            [targets.edge-1]
+            group = "edge"
+            listen = [ "192.0.2.1:323" ]
+
+            [targets.edge-2]
+            group = "edge"
+            listen = [ "192.0.2.2:323" ]
+            refresh = 300
+
+            [targets.other]
+            type = "http"
+            unit = "vrps"
+        "#).unwrap();
+        expand_target_groups(&mut table).unwrap();
+        assert!(!table.contains_key("target-groups"));
+        let targets = table["targets"].as_table().unwrap();
+        let edge1 = targets["edge-1"].as_table().unwrap();
+        assert!(!edge1.contains_key("group"));
+        assert_eq!(edge1["type"].as_str(), Some("rtr"));
+        assert_eq!(edge1["unit"].as_str(), Some("vrps"));
+        assert_eq!(edge1["refresh"].as_integer(), Some(600));
+        let edge2 = targets["edge-2"].as_table().unwrap();
+        assert_eq!(edge2["refresh"].as_integer(), Some(300));
+        let other = targets["other"].as_table().unwrap();
+        assert!(!other.contains_key("refresh"));
+
+        let mut table: toml::Table = toml::from_str(r#"
+            [target-groups.edge]
+            type = "rtr"
+
+            [targets.edge-1]
+            group = "core"
+        "#).unwrap();
+        assert!(expand_target_groups(&mut table).is_err());
+    }
+
+    #[test]
+    fn templates() {
+        let mut table: toml::Table = toml::from_str(r#"
+            [units.vrps]
+            type = "json"
+            uri = "https://rp.example.net/json"
+            refresh = 60
+
+            [templates.customer.units."slurm-${name}"]
+            type = "slurm"
+            source = "vrps"
+            files = [ "/etc/rtrtr/${name}.json" ]
+
+            [templates.customer.targets."rtr-${name}"]
+            type = "rtr"
+            listen = [ "${listen}" ]
+            unit = "slurm-${name}"
+            refresh = "${refresh}"
+            comment = "costs $$5"
+
+            [[templates.customer.instances]]
+            name = "acme"
+            listen = "192.0.2.1:323"
+            refresh = 600
+
+            [[templates.customer.instances]]
+            name = "example"
+            listen = "192.0.2.2:323"
+            refresh = 300
+        "#).unwrap();
+        expand_templates(&mut table).unwrap();
+        assert!(!table.contains_key("templates"));
+        let units = table["units"].as_table().unwrap();
+        assert_eq!(units.len(), 3);
+        assert_eq!(
+            units["slurm-acme"]["files"][0].as_str(),
+            Some("/etc/rtrtr/acme.json")
+        );
+        let targets = table["targets"].as_table().unwrap();
+        let acme = targets["rtr-acme"].as_table().unwrap();
+        assert_eq!(acme["listen"][0].as_str(), Some("192.0.2.1:323"));
+        assert_eq!(acme["unit"].as_str(), Some("slurm-acme"));
+        assert_eq!(acme["refresh"].as_integer(), Some(600));
+        assert_eq!(acme["comment"].as_str(), Some("costs $5"));
+        let example = targets["rtr-example"].as_table().unwrap();
+        assert_eq!(example["unit"].as_str(), Some("slurm-example"));
+        assert_eq!(example["refresh"].as_integer(), Some(300));
+
+        let mut table: toml::Table = toml::from_str(r#"
+            [templates.customer.units."slurm-${nmae}"]
+            type = "slurm"
+
+            [[templates.customer.instances]]
+            name = "acme"
+        "#).unwrap();
+        assert!(expand_templates(&mut table).is_err());
+
+        let mut table: toml::Table = toml::from_str(r#"
+            [templates.customer.units."vrps-${name}"]
+            type = "json"
+            uri = "https://${name}.example.net/json"
+            refresh = 60
+            query = { since = "${timestamp}" }
+            headers = { x-request-time = "${datetime}" }
+
+            [[templates.customer.instances]]
+            name = "acme"
+        "#).unwrap();
+        expand_templates(&mut table).unwrap();
+        let vrps = table["units"]["vrps-acme"].as_table().unwrap();
+        assert_eq!(
+            vrps["uri"].as_str(), Some("https://acme.example.net/json")
+        );
+        assert_eq!(vrps["query"]["since"].as_str(), Some("${timestamp}"));
+        assert_eq!(
+            vrps["headers"]["x-request-time"].as_str(), Some("${datetime}")
+        );
+
+        let mut table: toml::Table = toml::from_str(r#"
+            [templates.customer.units.slurm]
+            type = "slurm"
+
+            [[templates.customer.instances]]
+            name = "acme"
+        "#).unwrap();
+        expand_templates(&mut table).unwrap();
+
+        let mut table: toml::Table = toml::from_str(r#"
+            [templates.customer.units.slurm]
+            type = "slurm"
+
+            [[templates.customer.instances]]
+            name = "acme"
+
+            [[templates.customer.instances]]
+            name = "example"
+        "#).unwrap();
+        assert!(expand_templates(&mut table).is_err());
+    }
+
+    #[test]
+    fn env() {
+        let mut table: toml::Table = toml::from_str(r#"
+            http-listen = [ "${env:ADDR}:8080" ]
+
+            [units.vrps]
+            type = "json"
+            uri = "https://${env:HOST}/json"
+            comment = "costs $$5, not $${env:HOST} but ${env:HOST}$"
+            refresh = 60
+        "#).unwrap();
+        expand_env_table(&mut table, &mut |name| {
+            match name {
+                "ADDR" => Some("192.0.2.1".into()),
+                "HOST" => Some("rp.example.net".into()),
+                _ => None
+            }
+        }).unwrap();
+        assert_eq!(table["http-listen"][0].as_str(), Some("192.0.2.1:8080"));
+        let vrps = table["units"]["vrps"].as_table().unwrap();
+        assert_eq!(vrps["uri"].as_str(), Some("https://rp.example.net/json"));
+        assert_eq!(
+            vrps["comment"].as_str(),
+            Some("costs $$5, not $${env:HOST} but rp.example.net$")
+        );
+        assert_eq!(vrps["refresh"].as_integer(), Some(60));
+
+        let mut table: toml::Table = toml::from_str(r#"
+            [units.vrps]
+            uri = "https://${env:HOTS}/json"
+        "#).unwrap();
+        let err = expand_env_table(&mut table, &mut |_| None).unwrap_err();
+        assert!(err.to_string().contains("HOTS"));
+
+        let mut table: toml::Table = toml::from_str(r#"
+            [units.vrps]
+            uri = "https://${env:HOST/json"
+        "#).unwrap();
+        assert!(expand_env_table(&mut table, &mut |_| None).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "unit-json")]
+    fn env_with_json_placeholders() {
+        let mut table: toml::Table = toml::from_str(r#"
+            [units.vrps]
+            type = "json"
+            uri = "https://${env:HOST}/json"
+            refresh = 60
+            method = "post"
+            body = '{ "since": ${timestamp}, "price": "$$5" }'
+            query = { at = "${datetime}" }
+        "#).unwrap();
+        expand_env_table(&mut table, &mut |name| {
+            (name == "HOST").then(|| "rp.example.net".into())
+        }).unwrap();
+        let vrps = table["units"]["vrps"].clone();
+        assert_eq!(vrps["uri"].as_str(), Some("https://rp.example.net/json"));
+        assert_eq!(
+            vrps["body"].as_str(),
+            Some(r#"{ "since": ${timestamp}, "price": "$$5" }"#)
+        );
+        assert_eq!(vrps["query"]["at"].as_str(), Some("${datetime}"));
+        vrps.try_into::<crate::units::Unit>().unwrap();
+    }
+
+    #[test]
+    fn profiles() {
+        let data = r#"
+            log_level = "info"
+
+            [units.vrps]
+            type = "json"
+            uri = "https://rp.example.net/json"
+            refresh = 60
+
+            [profile.lab]
+            log_level = "debug"
+
+            [profile.lab.units.vrps]
+            uri = "http://localhost:8323/json"
+
+            [profile.prod.targets.edge]
+            listen = [ "192.0.2.1:323" ]
+        "#;
+
+        let mut table: toml::Table = toml::from_str(data).unwrap();
+        apply_profile(&mut table, Some("lab")).unwrap();
+        assert!(!table.contains_key("profile"));
+        assert_eq!(table["log_level"].as_str(), Some("debug"));
+        let vrps = table["units"]["vrps"].as_table().unwrap();
+        assert_eq!(vrps["type"].as_str(), Some("json"));
+        assert_eq!(
+            vrps["uri"].as_str(), Some("http://localhost:8323/json")
+        );
+        assert_eq!(vrps["refresh"].as_integer(), Some(60));
+        assert!(!table.contains_key("targets"));
+
+        let mut table: toml::Table = toml::from_str(data).unwrap();
+        apply_profile(&mut table, None).unwrap();
+        assert!(!table.contains_key("profile"));
+        assert_eq!(table["log_level"].as_str(), Some("info"));
+
+        let mut table: toml::Table = toml::from_str(data).unwrap();
+        apply_profile(&mut table, Some("prod")).unwrap();
+        assert!(table["targets"]["edge"].as_table().is_some());
+
+        let mut table: toml::Table = toml::from_str(data).unwrap();
+        assert!(apply_profile(&mut table, Some("staging")).is_err());
+    }
+
+    #[test]
+    fn secret() {
+        #[derive(Deserialize)]
+        struct Config {
+            secret: Secret,
+        }
+
+        fn load(value: &str) -> Result<Secret, toml::de::Error> {
+            toml::from_str::<Config>(
+                &format!("secret = \"{}\"", value)
+            ).map(|config| config.secret)
+        }
+
+        assert_eq!(load("plain").unwrap().expose(), "plain");
+        assert_eq!(format!("{:?}", load("plain").unwrap()), "Secret(..)");
+
+        std::env::set_var("RTRTR_TEST_SECRET", "from-env");
+        assert_eq!(
+            load("env:RTRTR_TEST_SECRET").unwrap().expose(), "from-env"
+        );
+        assert!(load("env:RTRTR_TEST_SECRET_MISSING").is_err());
+
+        let path = std::env::temp_dir().join(
+            format!("rtrtr-test-secret-{}", std::process::id())
+        );
+        fs::write(&path, "from-file\n").unwrap();
+        let secret = load(&format!("file:{}", path.display()));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(secret.unwrap().expose(), "from-file");
+        assert!(load(&format!("file:{}", path.display())).is_err());
+    }
+}
@@ -0,0 +1,250 @@
+//! Evaluating the configured pipelines offline.
+//!
+//! An evaluation runs the units of a configuration once without starting
+//! any targets or servers. Selected units are replaced with input units
+//! that provide a data set loaded from a file in the JSON format produced
+//! by validators. Once all units feeding a target have produced their data
+//! set, the data set each target would serve is written in JSON format
+//! either into a file per target or to standard output.
+//!
+//! This allows testing local exception and filter configurations against
+//! known data sets without access to the actual sources.
+
+use std::{fs, io};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use daemonbase::error::Failed;
+use log::error;
+use tokio::runtime;
+use tokio::time::timeout;
+use crate::formats::{json, output};
+use crate::config::Config;
+use crate::comms::{Gate, Link, Terminated, UnitUpdate};
+use crate::manager::{Component, Manager, TargetSet};
+use crate::payload;
+use crate::units::Unit;
+
+
+//------------ Args ----------------------------------------------------------
+
+/// The command line arguments of the `eval` command.
+#[derive(clap::Parser)]
+pub struct Args {
+    /// Replace a unit with a data set read from a JSON file.
+    #[arg(
+        long = "input", value_name = "UNIT=PATH",
+        value_parser = Args::parse_input,
+    )]
+    inputs: Vec<(String, PathBuf)>,
+
+    /// Write the output of each target into this directory.
+    #[arg(long, value_name = "DIR")]
+    output: Option<PathBuf>,
+
+    /// Seconds to wait for the data set of each target.
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    timeout: u64,
+}
+
+impl Args {
+    /// Parses the value of an `--input` argument.
+    fn parse_input(value: &str) -> Result<(String, PathBuf), String> {
+        match value.split_once('=') {
+            Some((unit, path)) if !unit.is_empty() && !path.is_empty() => {
+                Ok((unit.into(), path.into()))
+            }
+            _ => Err("expected UNIT=PATH".into())
+        }
+    }
+}
+
+
+//------------ run -----------------------------------------------------------
+
+/// Runs an evaluation of the given configuration.
+///
+/// The manager and configuration must have been loaded but nothing must
+/// have been spawned yet.
+pub fn run(
+    args: Args, mut manager: Manager, mut config: Config
+) -> Result<(), Failed> {
+    let mut failed = false;
+    for (name, path) in args.inputs {
+        let set = match load_input(&path) {
+            Ok(set) => set,
+            Err(err) => {
+                error!("Failed to load input {}: {}", path.display(), err);
+                failed = true;
+                continue
+            }
+        };
+        if !config.units.replace(&name, Unit::Input(Input { set })) {
+            error!("Unknown unit '{}' in input.", name);
+            failed = true;
+        }
+    }
+    if failed {
+        return Err(Failed)
+    }
+
+    let links = std::mem::take(&mut config.targets).into_links();
+    let runtime = runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    manager.spawn(
+        &mut config.units, &mut TargetSet::new(), runtime.handle()
+    );
+    let wait = Duration::from_secs(args.timeout);
+    let results = runtime.block_on(async {
+        let mut res = Vec::new();
+        for (name, links) in links {
+            let link = match links.into_iter().next() {
+                Some(link) => link,
+                None => {
+                    error!("Target {}: no unit to evaluate.", name);
+                    return Err(Failed)
+                }
+            };
+            match timeout(wait, first_payload(link)).await {
+                Ok(Some(set)) => res.push((name, set)),
+                Ok(None) => {
+                    error!("Target {}: unit terminated without data.", name);
+                    return Err(Failed)
+                }
+                Err(_) => {
+                    error!(
+                        "Target {}: no data within {} seconds.",
+                        name, args.timeout
+                    );
+                    return Err(Failed)
+                }
+            }
+        }
+        Ok(res)
+    })?;
+
+    let res = match args.output {
+        Some(dir) => write_files(&dir, results),
+        None => write_stdout(results),
+    };
+    res.map_err(|err| {
+        error!("Failed to write output: {}", err);
+        Failed
+    })
+}
+
+/// Loads an input data set from a JSON file.
+fn load_input(path: &Path) -> Result<payload::Set, String> {
+    let data = fs::read(path).map_err(|err| err.to_string())?;
+    let set: json::Set = serde_json::from_slice(
+        &data
+    ).map_err(|err| err.to_string())?;
+    Ok(set.into_payload())
+}
+
+/// Waits for the first data set of the unit behind a link.
+///
+/// Returns `None` if the unit goes away before producing a data set.
+async fn first_payload(mut link: Link) -> Option<payload::Set> {
+    loop {
+        match link.query().await {
+            UnitUpdate::Payload(update) => return Some(update.into_set()),
+            UnitUpdate::Stalled => { }
+            UnitUpdate::Gone => return None,
+        }
+    }
+}
+
+/// Writes the output of each target into a file in `dir`.
+///
+/// The files are named after the target with a `.json` extension.
+fn write_files(
+    dir: &Path, results: Vec<(String, payload::Set)>
+) -> Result<(), io::Error> {
+    fs::create_dir_all(dir)?;
+    for (name, set) in results {
+        let mut file = io::BufWriter::new(
+            fs::File::create(dir.join(format!("{}.json", name)))?
+        );
+        for chunk in stream(set) {
+            file.write_all(&chunk)?;
+        }
+        file.flush()?;
+    }
+    Ok(())
+}
+
+/// Writes the output of all targets to stdout.
+///
+/// The output is a JSON object with the target names as keys and each
+/// target’s output as the value.
+fn write_stdout(
+    results: Vec<(String, payload::Set)>
+) -> Result<(), io::Error> {
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    out.write_all(b"{")?;
+    for (idx, (name, set)) in results.into_iter().enumerate() {
+        if idx > 0 {
+            out.write_all(b",")?;
+        }
+        serde_json::to_writer(&mut out, &name)?;
+        out.write_all(b":")?;
+        for chunk in stream(set) {
+            out.write_all(&chunk)?;
+        }
+    }
+    out.write_all(b"}\n")?;
+    out.flush()
+}
+
+/// Returns the output stream for a data set.
+fn stream(set: payload::Set) -> output::Stream {
+    output::Format::Json.stream(set, Default::default(), None)
+}
+
+
+//------------ Input ---------------------------------------------------------
+
+/// A unit providing a fixed data set.
+///
+/// This replaces units for an evaluation. It can’t be configured directly.
+#[derive(Debug)]
+pub struct Input {
+    /// The data set to provide.
+    set: payload::Set,
+}
+
+impl Input {
+    pub async fn run(
+        self, _component: Component, mut gate: Gate
+    ) -> Result<(), Terminated> {
+        gate.update(
+            UnitUpdate::Payload(payload::Update::new(self.set))
+        ).await;
+        loop {
+            gate.process().await?;
+        }
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_input() {
+        assert_eq!(
+            Args::parse_input("json=data/vrps.json").unwrap(),
+            (String::from("json"), PathBuf::from("data/vrps.json"))
+        );
+        assert!(Args::parse_input("json").is_err());
+        assert!(Args::parse_input("=vrps.json").is_err());
+        assert!(Args::parse_input("json=").is_err());
+    }
+}
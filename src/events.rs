@@ -0,0 +1,496 @@
+//! Notifying external parties of events.
+//!
+//! RTRTR can tell external systems about noteworthy events such as units
+//! becoming stalled or recovering, RTR targets increasing their serial
+//! number, or guards refusing updates. For each event, a small JSON object
+//! is POSTed to all configured webhooks. Delivery is attempted a number of
+//! times with increasing delays between attempts.
+//!
+//! Components report events via the [`Notifier`] available from their
+//! [`Component`](crate::manager::Component). The actual delivery is done
+//! by a [`Dispatcher`] running in the background.
+
+use std::sync::Arc;
+#[cfg(feature = "webhooks")]
+use std::time::Duration;
+use chrono::{SecondsFormat, Utc};
+use daemonbase::error::Failed;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use url::Url;
+use crate::config::Secret;
+use crate::manager::HttpClientConfig;
+
+
+//------------ Configuration -------------------------------------------------
+
+/// The length of the queue of events waiting for delivery.
+const QUEUE_LEN: usize = 256;
+
+/// The delay before the first retry of a failed delivery.
+#[cfg(feature = "webhooks")]
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The maximum delay between two retries of a failed delivery.
+#[cfg(feature = "webhooks")]
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+
+//------------ EventsConfig --------------------------------------------------
+
+/// The configuration for event notification.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct EventsConfig {
+    /// The webhooks to deliver events to.
+    #[serde(default)]
+    webhooks: Vec<WebhookConfig>,
+}
+
+impl EventsConfig {
+    /// Creates a notifier and the dispatcher to deliver its events.
+    ///
+    /// If no webhooks are configured, the notifier silently drops all
+    /// events and no dispatcher is returned.
+    pub fn start(
+        &self, http_config: &HttpClientConfig,
+    ) -> Result<(Notifier, Option<Dispatcher>), Failed> {
+        if self.webhooks.is_empty() {
+            return Ok((Notifier::default(), None))
+        }
+        let (tx, rx) = mpsc::channel(QUEUE_LEN);
+        let dispatcher = Dispatcher::new(&self.webhooks, http_config, rx)?;
+        Ok((Notifier { queue: Some(tx) }, Some(dispatcher)))
+    }
+}
+
+
+//------------ WebhookConfig -------------------------------------------------
+
+/// The configuration of a single webhook.
+#[derive(Clone, Debug, Deserialize)]
+#[cfg_attr(not(feature = "webhooks"), allow(dead_code))]
+pub struct WebhookConfig {
+    /// The URL to POST events to.
+    url: Url,
+
+    /// The kinds of events to deliver.
+    ///
+    /// If this is `None`, all events are delivered.
+    events: Option<Vec<EventKind>>,
+
+    /// How often to retry a failed delivery.
+    #[serde(default = "WebhookConfig::default_retries")]
+    retries: u32,
+
+    /// The timeout for a single delivery attempt in seconds.
+    #[serde(default = "WebhookConfig::default_timeout")]
+    timeout: u64,
+
+    /// The token for HTTP bearer authentication.
+    #[serde(rename = "bearer-token")]
+    bearer_token: Option<Secret>,
+}
+
+impl WebhookConfig {
+    /// The default for the `retries` value.
+    fn default_retries() -> u32 {
+        5
+    }
+
+    /// The default for the `timeout` value.
+    fn default_timeout() -> u64 {
+        10
+    }
+
+    /// Returns whether the webhook wants events of the given kind.
+    #[cfg_attr(not(feature = "webhooks"), allow(dead_code))]
+    fn wants(&self, kind: EventKind) -> bool {
+        match self.events.as_ref() {
+            Some(events) => events.contains(&kind),
+            None => true,
+        }
+    }
+}
+
+
+//------------ Notifier ------------------------------------------------------
+
+/// A means to report events.
+///
+/// Values of this type can be cloned cheaply and all clones feed into the
+/// same dispatcher. If no webhooks are configured, events are silently
+/// dropped.
+#[derive(Clone, Debug, Default)]
+pub struct Notifier {
+    /// The queue of events to be delivered.
+    queue: Option<mpsc::Sender<Arc<EventMessage>>>,
+}
+
+impl Notifier {
+    /// Reports an event.
+    ///
+    /// This never blocks. If too many events are waiting for delivery, the
+    /// event is dropped with a warning.
+    pub fn notify(&self, event: Event) {
+        let queue = match self.queue.as_ref() {
+            Some(queue) => queue,
+            None => return,
+        };
+        let message = match EventMessage::new(&event) {
+            Ok(message) => message,
+            Err(err) => {
+                error!("Failed to encode event: {}", err);
+                return
+            }
+        };
+        if queue.try_send(Arc::new(message)).is_err() {
+            warn!("Event queue full. Dropping '{}' event.", event.kind());
+        }
+    }
+}
+
+
+//------------ Dispatcher ----------------------------------------------------
+
+/// The background task delivering events to webhooks.
+pub struct Dispatcher {
+    /// The webhooks to deliver to.
+    #[cfg_attr(not(feature = "webhooks"), allow(dead_code))]
+    webhooks: Vec<Webhook>,
+
+    /// The queue of events to be delivered.
+    #[cfg_attr(not(feature = "webhooks"), allow(dead_code))]
+    queue: mpsc::Receiver<Arc<EventMessage>>,
+}
+
+impl Dispatcher {
+    /// Creates a new dispatcher.
+    #[cfg(feature = "webhooks")]
+    fn new(
+        config: &[WebhookConfig],
+        http_config: &HttpClientConfig,
+        queue: mpsc::Receiver<Arc<EventMessage>>,
+    ) -> Result<Self, Failed> {
+        let mut webhooks = Vec::with_capacity(config.len());
+        for config in config {
+            let client = http_config.client_builder().and_then(|builder| {
+                builder.timeout(
+                    Duration::from_secs(config.timeout)
+                ).build().map_err(|err| err.to_string())
+            });
+            match client {
+                Ok(client) => {
+                    webhooks.push(Webhook { config: config.clone(), client })
+                }
+                Err(err) => {
+                    error!(
+                        "Failed to create HTTP client for webhook {}: {}",
+                        Webhook::host(&config.url), err
+                    );
+                    return Err(Failed)
+                }
+            }
+        }
+        Ok(Dispatcher { webhooks, queue })
+    }
+
+    /// Creates a new dispatcher.
+    ///
+    /// This is the version used when webhooks have been disabled at compile
+    /// time. It always fails.
+    #[cfg(not(feature = "webhooks"))]
+    fn new(
+        _config: &[WebhookConfig],
+        _http_config: &HttpClientConfig,
+        _queue: mpsc::Receiver<Arc<EventMessage>>,
+    ) -> Result<Self, Failed> {
+        error!("Webhooks are not supported by this build of RTRTR.");
+        Err(Failed)
+    }
+
+    /// Runs the dispatcher.
+    ///
+    /// This needs to be spawned onto a Tokio runtime. Each webhook gets
+    /// its own task so that a slow webhook doesn’t hold up the others.
+    #[cfg(feature = "webhooks")]
+    pub async fn run(mut self) {
+        let mut queues = Vec::with_capacity(self.webhooks.len());
+        for webhook in self.webhooks {
+            let (tx, rx) = mpsc::channel(QUEUE_LEN);
+            queues.push((webhook.config.clone(), tx));
+            tokio::spawn(webhook.run(rx));
+        }
+        while let Some(message) = self.queue.recv().await {
+            for (config, queue) in &queues {
+                if !config.wants(message.kind) {
+                    continue
+                }
+                if queue.try_send(message.clone()).is_err() {
+                    warn!(
+                        "Webhook {}: queue full. Dropping '{}' event.",
+                        Webhook::host(&config.url), message.kind
+                    );
+                }
+            }
+        }
+    }
+
+    /// Runs the dispatcher.
+    ///
+    /// This is the version used when webhooks have been disabled at compile
+    /// time. Since such a dispatcher can never be created, it does nothing.
+    #[cfg(not(feature = "webhooks"))]
+    pub async fn run(self) {
+    }
+}
+
+
+//------------ Webhook -------------------------------------------------------
+
+/// A webhook ready for delivering events.
+#[cfg(feature = "webhooks")]
+struct Webhook {
+    /// The configuration of the webhook.
+    config: WebhookConfig,
+
+    /// The HTTP client to use.
+    client: reqwest::Client,
+}
+
+/// A placeholder for webhooks if they are disabled at compile time.
+#[cfg(not(feature = "webhooks"))]
+enum Webhook { }
+
+#[cfg(feature = "webhooks")]
+impl Webhook {
+    /// Delivers all events arriving on the queue.
+    async fn run(self, mut queue: mpsc::Receiver<Arc<EventMessage>>) {
+        while let Some(message) = queue.recv().await {
+            self.deliver(&message).await
+        }
+    }
+
+    /// Delivers a single event, retrying if necessary.
+    async fn deliver(&self, message: &EventMessage) {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            let mut request = self.client.post(self.config.url.clone())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(message.body.clone());
+            if let Some(token) = self.config.bearer_token.as_ref() {
+                request = request.bearer_auth(token.expose());
+            }
+            let res = request.send().await
+                .and_then(|response| response.error_for_status());
+            let err = match res {
+                Ok(_) => return,
+                Err(err) => err,
+            };
+            if attempt >= self.config.retries {
+                error!(
+                    "Webhook {}: failed to deliver '{}' event: {}. \
+                     Giving up.",
+                    Self::host(&self.config.url), message.kind, err
+                );
+                return
+            }
+            warn!(
+                "Webhook {}: failed to deliver '{}' event: {}. \
+                 Retrying in {}s.",
+                Self::host(&self.config.url), message.kind, err,
+                backoff.as_secs()
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            attempt += 1;
+        }
+    }
+
+    /// Returns the host portion of a URL for logging.
+    ///
+    /// Webhook URLs often contain secrets in their path or query, so we
+    /// only ever log the host.
+    fn host(url: &Url) -> &str {
+        url.host_str().unwrap_or("<unknown>")
+    }
+}
+
+
+//------------ Event ---------------------------------------------------------
+
+/// An event to be reported.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    /// A unit has become stalled.
+    UnitStalled {
+        /// The name of the unit.
+        component: String,
+    },
+
+    /// A stalled unit has become healthy again.
+    UnitRecovered {
+        /// The name of the unit.
+        component: String,
+    },
+
+    /// A unit has terminated.
+    UnitGone {
+        /// The name of the unit.
+        component: String,
+    },
+
+    /// An RTR target has increased its serial number.
+    TargetSerial {
+        /// The name of the target.
+        component: String,
+
+        /// The new serial number.
+        serial: u32,
+    },
+
+    /// A guard has refused or altered an update.
+    GuardTriggered {
+        /// The name of the component with the guard.
+        component: String,
+
+        /// The name of the guard.
+        guard: &'static str,
+
+        /// A human readable description of what happened.
+        detail: String,
+    },
+
+    /// The configuration has been reloaded.
+    ConfigReloaded,
+}
+
+impl Event {
+    /// Returns the kind of the event.
+    pub fn kind(&self) -> EventKind {
+        match *self {
+            Event::UnitStalled { .. } => EventKind::UnitStalled,
+            Event::UnitRecovered { .. } => EventKind::UnitRecovered,
+            Event::UnitGone { .. } => EventKind::UnitGone,
+            Event::TargetSerial { .. } => EventKind::TargetSerial,
+            Event::GuardTriggered { .. } => EventKind::GuardTriggered,
+            Event::ConfigReloaded => EventKind::ConfigReloaded,
+        }
+    }
+}
+
+
+//------------ EventKind -----------------------------------------------------
+
+/// The kind of an event.
+///
+/// This is used to select the events a webhook is interested in.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EventKind {
+    UnitStalled,
+    UnitRecovered,
+    UnitGone,
+    TargetSerial,
+    GuardTriggered,
+    ConfigReloaded,
+}
+
+impl std::fmt::Display for EventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match *self {
+            EventKind::UnitStalled => "unit-stalled",
+            EventKind::UnitRecovered => "unit-recovered",
+            EventKind::UnitGone => "unit-gone",
+            EventKind::TargetSerial => "target-serial",
+            EventKind::GuardTriggered => "guard-triggered",
+            EventKind::ConfigReloaded => "config-reloaded",
+        })
+    }
+}
+
+
+//------------ EventMessage --------------------------------------------------
+
+/// An event encoded for delivery.
+#[derive(Debug)]
+struct EventMessage {
+    /// The kind of the event.
+    kind: EventKind,
+
+    /// The JSON encoded event.
+    #[cfg_attr(not(feature = "webhooks"), allow(dead_code))]
+    body: Vec<u8>,
+}
+
+impl EventMessage {
+    /// Encodes an event.
+    ///
+    /// The current time is added to the event as member `"timestamp"`.
+    fn new(event: &Event) -> Result<Self, serde_json::Error> {
+        #[derive(Serialize)]
+        struct Message<'a> {
+            timestamp: String,
+
+            #[serde(flatten)]
+            event: &'a Event,
+        }
+
+        Ok(EventMessage {
+            kind: event.kind(),
+            body: serde_json::to_vec(&Message {
+                timestamp: Utc::now().to_rfc3339_opts(
+                    SecondsFormat::Millis, true
+                ),
+                event
+            })?,
+        })
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_event() {
+        let message = EventMessage::new(&Event::TargetSerial {
+            component: "rtr".into(), serial: 12
+        }).unwrap();
+        assert_eq!(message.kind, EventKind::TargetSerial);
+        let value: serde_json::Value = serde_json::from_slice(
+            &message.body
+        ).unwrap();
+        assert_eq!(value["event"], "target-serial");
+        assert_eq!(value["component"], "rtr");
+        assert_eq!(value["serial"], 12);
+        assert!(value["timestamp"].is_string());
+
+        let message = EventMessage::new(&Event::ConfigReloaded).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(
+            &message.body
+        ).unwrap();
+        assert_eq!(value["event"], "config-reloaded");
+    }
+
+    #[test]
+    fn webhook_events() {
+        let config: WebhookConfig = toml::from_str(
+            "url = \"https://example.net/hook\"\n\
+             events = [ \"unit-stalled\", \"unit-recovered\" ]\n"
+        ).unwrap();
+        assert!(config.wants(EventKind::UnitStalled));
+        assert!(!config.wants(EventKind::TargetSerial));
+        assert_eq!(config.retries, 5);
+
+        let config: WebhookConfig = toml::from_str(
+            "url = \"https://example.net/hook\"\n"
+        ).unwrap();
+        assert!(config.wants(EventKind::TargetSerial));
+    }
+}
@@ -0,0 +1,526 @@
+//! The OpenBGPD configuration format for validated RPKI data.
+//!
+//! rpki-client can write its output as a fragment of an OpenBGPD
+//! configuration file. The route origins are given in a `roa-set` block
+//! with one entry per origin:
+//!
+//! ```text
+//! roa-set {
+//!     192.0.2.0/24 maxlen 24 source-as 64496 expires 1700000000
+//!     2001:db8::/32 source-as 64497
+//! }
+//! ```
+//!
+//! ASPA records are given in an `aspa-set` block:
+//!
+//! ```text
+//! aspa-set {
+//!     customer-as 64496 expires 1700000000 provider-as { 64497, 64498 }
+//! }
+//! ```
+//!
+//! Entries can be separated by line breaks or commas. Comments start with
+//! `#` and continue until the end of the line. The `expires` attribute and
+//! the `allow` attribute of ASPA providers are ignored. Other blocks, such
+//! as those with rpki-client’s statistics, are skipped.
+//!
+//! When creating this format, the same syntax is used without the
+//! `expires` attributes. The max length of a route origin is only given if
+//! it differs from the prefix length. The `aspa-set` block is left out if
+//! there are no ASPA records. The format doesn’t contain any router keys.
+
+use std::{fmt, str};
+use rpki::resources::addr::{MaxLenPrefix, Prefix};
+use rpki::resources::asn::Asn;
+use rpki::rtr::payload::{Aspa, Payload, RouteOrigin};
+use rpki::rtr::pdu::ProviderAsns;
+use crate::payload;
+use super::output::{Aspas, Origins};
+
+
+//============ Input =========================================================
+
+//------------ Set -----------------------------------------------------------
+
+/// The content of an OpenBGPD formatted data set.
+#[derive(Clone, Debug, Default)]
+pub struct Set {
+    /// The route origins.
+    origins: Vec<RouteOrigin>,
+
+    /// The ASPA records.
+    aspas: Vec<Aspa>,
+}
+
+impl Set {
+    /// Parses a data set from its raw content.
+    pub fn from_slice(data: &[u8]) -> Result<Self, ParseError> {
+        let data = str::from_utf8(data).map_err(|_| {
+            ParseError::new(1, "invalid UTF-8")
+        })?;
+        let mut tokens = Tokens::new(data);
+        let mut res = Set::default();
+        while let Some(token) = tokens.next() {
+            match token {
+                "roa-set" => {
+                    tokens.expect("{")?;
+                    res.roa_set(&mut tokens)?;
+                }
+                "aspa-set" => {
+                    tokens.expect("{")?;
+                    res.aspa_set(&mut tokens)?;
+                }
+                "," => { }
+                "}" => return Err(tokens.error("unexpected '}'")),
+                _ => tokens.skip_block()?,
+            }
+        }
+        Ok(res)
+    }
+
+    /// Returns the number of route origins in the data set.
+    ///
+    /// This includes duplicates.
+    pub fn len(&self) -> usize {
+        self.origins.len()
+    }
+
+    /// Returns whether the data set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.origins.is_empty() && self.aspas.is_empty()
+    }
+
+    /// Converts the data set into a payload set.
+    pub fn into_payload(self) -> payload::Set {
+        let mut res = payload::PackBuilder::empty();
+        for item in self.origins {
+            let _ = res.insert(Payload::Origin(item));
+        }
+        for item in self.aspas {
+            let _ = res.insert(Payload::Aspa(item));
+        }
+        res.finalize().into()
+    }
+
+    /// Parses the content of a `roa-set` block.
+    ///
+    /// The opening brace has already been consumed.
+    fn roa_set(&mut self, tokens: &mut Tokens) -> Result<(), ParseError> {
+        loop {
+            let prefix = match tokens.required()? {
+                "}" => return Ok(()),
+                "," => continue,
+                prefix => prefix,
+            };
+            let line = tokens.line;
+            let prefix = prefix.parse::<Prefix>().map_err(|_| {
+                tokens.error(format!("invalid prefix '{}'", prefix))
+            })?;
+            let mut max_len = None;
+            let mut asn = None;
+            loop {
+                match tokens.peek() {
+                    Some("maxlen") => {
+                        tokens.next();
+                        max_len = Some(tokens.number::<u8>()?);
+                    }
+                    Some("source-as") => {
+                        tokens.next();
+                        asn = Some(Asn::from(tokens.number::<u32>()?));
+                    }
+                    Some("expires") => {
+                        tokens.next();
+                        tokens.number::<i64>()?;
+                    }
+                    _ => break
+                }
+            }
+            let asn = asn.ok_or_else(|| {
+                ParseError::new(line, "missing source-as")
+            })?;
+            let prefix = MaxLenPrefix::new(prefix, max_len).map_err(|_| {
+                ParseError::new(line, "invalid maxlen")
+            })?;
+            self.origins.push(RouteOrigin::new(prefix, asn));
+        }
+    }
+
+    /// Parses the content of an `aspa-set` block.
+    ///
+    /// The opening brace has already been consumed.
+    fn aspa_set(&mut self, tokens: &mut Tokens) -> Result<(), ParseError> {
+        loop {
+            match tokens.required()? {
+                "}" => return Ok(()),
+                "," => continue,
+                "customer-as" => { }
+                token => {
+                    return Err(tokens.error(
+                        format!("unexpected '{}'", token)
+                    ))
+                }
+            }
+            let customer = Asn::from(tokens.number::<u32>()?);
+            if tokens.peek() == Some("expires") {
+                tokens.next();
+                tokens.number::<i64>()?;
+            }
+            tokens.expect("provider-as")?;
+            tokens.expect("{")?;
+            let mut providers = Vec::new();
+            loop {
+                match tokens.required()? {
+                    "}" => break,
+                    "," => { }
+                    "allow" => {
+                        tokens.required()?;
+                    }
+                    provider => {
+                        providers.push(Asn::from(
+                            provider.parse::<u32>().map_err(|_| {
+                                tokens.error(format!(
+                                    "invalid AS number '{}'", provider
+                                ))
+                            })?
+                        ));
+                    }
+                }
+            }
+            let providers = ProviderAsns::try_from_iter(
+                providers
+            ).map_err(|_| tokens.error("too many ASPA providers"))?;
+            self.aspas.push(Aspa::new(customer, providers));
+        }
+    }
+}
+
+
+//------------ Tokens --------------------------------------------------------
+
+/// An iterator over the tokens of the configuration.
+///
+/// Tokens are separated by white space. Braces and commas are tokens by
+/// themselves.
+struct Tokens<'a> {
+    /// The remaining data.
+    data: &'a str,
+
+    /// The line number of the start of the remaining data.
+    line: usize,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(data: &'a str) -> Self {
+        Tokens { data, line: 1 }
+    }
+
+    /// Skips over white space and comments.
+    fn skip_space(&mut self) {
+        loop {
+            let trimmed = self.data.trim_start();
+            self.line += self.data[..self.data.len() - trimmed.len()]
+                .matches('\n').count();
+            self.data = trimmed;
+            if !self.data.starts_with('#') {
+                return
+            }
+            self.data = match self.data.find('\n') {
+                Some(pos) => &self.data[pos..],
+                None => "",
+            };
+        }
+    }
+
+    /// Returns the next token without consuming it.
+    fn peek(&mut self) -> Option<&'a str> {
+        self.skip_space();
+        let data = self.data;
+        let first = data.chars().next()?;
+        if matches!(first, '{' | '}' | ',') {
+            return Some(&data[..1])
+        }
+        let end = data.find(|ch: char| {
+            ch.is_whitespace() || matches!(ch, '{' | '}' | ',' | '#')
+        }).unwrap_or(data.len());
+        Some(&data[..end])
+    }
+
+    /// Returns the next token or an error if there is none.
+    fn required(&mut self) -> Result<&'a str, ParseError> {
+        self.next().ok_or_else(|| self.error("unexpected end of data"))
+    }
+
+    /// Consumes the next token which must be `expected`.
+    fn expect(&mut self, expected: &str) -> Result<(), ParseError> {
+        match self.required()? {
+            token if token == expected => Ok(()),
+            token => {
+                Err(self.error(format!(
+                    "expected '{}', found '{}'", expected, token
+                )))
+            }
+        }
+    }
+
+    /// Consumes the next token and parses it as a number.
+    fn number<T: str::FromStr>(&mut self) -> Result<T, ParseError> {
+        let token = self.required()?;
+        token.parse().map_err(|_| {
+            self.error(format!("invalid number '{}'", token))
+        })
+    }
+
+    /// Skips over tokens until the end of the next block.
+    fn skip_block(&mut self) -> Result<(), ParseError> {
+        let mut depth = 0usize;
+        loop {
+            match self.required()? {
+                "{" => depth += 1,
+                "}" => {
+                    depth = depth.checked_sub(1).ok_or_else(|| {
+                        self.error("unexpected '}'")
+                    })?;
+                    if depth == 0 {
+                        return Ok(())
+                    }
+                }
+                _ => { }
+            }
+        }
+    }
+
+    /// Creates an error for the current line.
+    fn error(&self, msg: impl Into<String>) -> ParseError {
+        ParseError::new(self.line, msg)
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let res = self.peek()?;
+        self.data = &self.data[res.len()..];
+        Some(res)
+    }
+}
+
+
+//------------ ParseError ----------------------------------------------------
+
+/// An error happened while parsing the data set.
+#[derive(Clone, Debug)]
+pub struct ParseError {
+    /// The line the error happened in.
+    line: usize,
+
+    /// A description of the error.
+    msg: String,
+}
+
+impl ParseError {
+    fn new(line: usize, msg: impl Into<String>) -> Self {
+        ParseError { line, msg: msg.into() }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+
+//============ Output ========================================================
+
+//------------ OutputStream --------------------------------------------------
+
+/// A stream of OpenBGPD formatted output.
+pub struct OutputStream {
+    /// The iterator over the route origins.
+    origins: Origins,
+
+    /// The iterator over the ASPA records.
+    aspas: Aspas,
+
+    /// The current stream state.
+    state: StreamState,
+}
+
+/// The state of the stream.
+#[derive(Clone, Copy, Debug)]
+enum StreamState {
+    /// We need to write the header next.
+    Header,
+
+    /// We are writing route origins.
+    Origins,
+
+    /// We are writing ASPA records.
+    Aspas,
+
+    /// We are done!
+    Done
+}
+
+impl OutputStream {
+    /// Creates a new output stream for the given payload.
+    pub fn new(origins: Origins, aspas: Aspas) -> Self {
+        OutputStream {
+            origins,
+            aspas,
+            state: StreamState::Header,
+        }
+    }
+
+    /// Returns the line for a route origin.
+    fn origin(origin: &RouteOrigin) -> Vec<u8> {
+        let max_len = origin.prefix.resolved_max_len();
+        if max_len > origin.prefix.prefix_len() {
+            format!(
+                "\t{} maxlen {} source-as {}\n",
+                origin.prefix.prefix(), max_len, origin.asn.into_u32()
+            ).into_bytes()
+        }
+        else {
+            format!(
+                "\t{} source-as {}\n",
+                origin.prefix.prefix(), origin.asn.into_u32()
+            ).into_bytes()
+        }
+    }
+
+    /// Returns the line for an ASPA record.
+    fn aspa(aspa: &Aspa) -> Vec<u8> {
+        let providers = aspa.providers.iter().map(|asn| {
+            asn.into_u32().to_string()
+        }).collect::<Vec<_>>();
+        format!(
+            "\tcustomer-as {} provider-as {{ {} }}\n",
+            aspa.customer.into_u32(), providers.join(", ")
+        ).into_bytes()
+    }
+}
+
+impl Iterator for OutputStream {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.state {
+            StreamState::Header => {
+                self.state = StreamState::Origins;
+                Some(b"roa-set {\n".to_vec())
+            }
+            StreamState::Origins => {
+                if let Some(origin) = self.origins.next() {
+                    return Some(Self::origin(&origin))
+                }
+                if self.aspas.is_empty() {
+                    self.state = StreamState::Done;
+                    Some(b"}\n".to_vec())
+                }
+                else {
+                    self.state = StreamState::Aspas;
+                    Some(b"}\n\naspa-set {\n".to_vec())
+                }
+            }
+            StreamState::Aspas => {
+                if let Some(aspa) = self.aspas.next() {
+                    return Some(Self::aspa(&aspa))
+                }
+                self.state = StreamState::Done;
+                Some(b"}\n".to_vec())
+            }
+            StreamState::Done => None
+        }
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let set = Set::from_slice(b"
+            # Generated by rpki-client.
+
+            roa-set {
+                192.0.2.0/24 maxlen 24 source-as 64496 expires 1700000000
+                198.51.100.0/24 source-as 64497,
+                2001:db8::/32 maxlen 48 source-as 64498
+            }
+
+            aspa-set {
+                customer-as 64496 expires 1700000000 provider-as {
+                    64497, 64498 allow inet6
+                }
+            }
+
+            rpki-client-stats {
+                roas 3 # statistics are ignored
+            }
+        ").unwrap();
+        assert_eq!(set.len(), 3);
+        assert_eq!(set.origins[1].asn, 64497.into());
+        assert_eq!(set.origins[1].prefix.max_len(), None);
+        assert_eq!(set.origins[2].prefix.max_len(), Some(48));
+        assert_eq!(set.aspas.len(), 1);
+        assert_eq!(
+            set.aspas[0].providers.iter().collect::<Vec<_>>(),
+            [Asn::from(64497), Asn::from(64498)]
+        );
+        assert_eq!(set.into_payload().len(), 4);
+    }
+
+    #[test]
+    fn errors() {
+        let err = Set::from_slice(
+            b"roa-set {\n  192.0.2.0/24 maxlen 24\n}\n"
+        ).unwrap_err();
+        assert_eq!(err.to_string(), "line 2: missing source-as");
+        assert!(Set::from_slice(b"roa-set {\n 192.0.2.0/24").is_err());
+        assert!(Set::from_slice(
+            b"roa-set { 192.0.2.0/24 maxlen 16 source-as 64496 }"
+        ).is_err());
+        assert!(Set::from_slice(b"roa-set { bogus source-as 1 }").is_err());
+        assert!(Set::from_slice(b"}").is_err());
+    }
+
+    #[test]
+    fn output() {
+        let set = Set::from_slice(b"
+            roa-set {
+                192.0.2.0/24 maxlen 26 source-as 64496
+                2001:db8::/32 source-as 64497
+            }
+            aspa-set {
+                customer-as 64496 provider-as { 64497, 64498 }
+            }
+        ").unwrap().into_payload();
+        let output = OutputStream::new(
+            Origins::new(set.clone(), Default::default()), Aspas::new(&set)
+        ).flatten().collect::<Vec<_>>();
+        assert_eq!(
+            String::from_utf8(output.clone()).unwrap(),
+            "roa-set {\n\
+             \t192.0.2.0/24 maxlen 26 source-as 64496\n\
+             \t2001:db8::/32 source-as 64497\n\
+             }\n\
+             \n\
+             aspa-set {\n\
+             \tcustomer-as 64496 provider-as { 64497, 64498 }\n\
+             }\n"
+        );
+        assert_eq!(Set::from_slice(&output).unwrap().into_payload(), set);
+
+        let output = OutputStream::new(
+            Origins::new(payload::Set::default(), Default::default()),
+            Aspas::new(&payload::Set::default())
+        ).flatten().collect::<Vec<_>>();
+        assert_eq!(output, b"roa-set {\n}\n");
+    }
+}
@@ -0,0 +1,154 @@
+//! The BIRD configuration format for validated RPKI data.
+//!
+//! This produces a `roa table` statement as understood by BIRD 1.x with a
+//! static entry for each route origin:
+//!
+//! ```text
+//! roa table rtrtr {
+//!     roa 192.0.2.0/24 max 24 as 64496;
+//! }
+//! ```
+//!
+//! The max length is always given. Since the IPv4 and IPv6 daemons of
+//! BIRD 1.x only accept prefixes of their own address family, the data set
+//! may need to be split by address family before being written.
+//!
+//! Router keys and ASPA records cannot be represented in this format and
+//! are left out.
+
+use super::output::Origins;
+
+
+//------------ OutputStream --------------------------------------------------
+
+/// A stream of BIRD formatted output.
+pub struct OutputStream {
+    /// The iterator over the route origins.
+    iter: Origins,
+
+    /// The name of the ROA table.
+    table: String,
+
+    /// The current stream state.
+    state: StreamState,
+}
+
+/// The state of the stream.
+#[derive(Clone, Copy, Debug)]
+enum StreamState {
+    /// We need to write the header next.
+    Header,
+
+    /// We are writing route origins.
+    Body,
+
+    /// We are done!
+    Done
+}
+
+impl OutputStream {
+    /// Creates a new output stream for the given route origins.
+    ///
+    /// The table name needs to be a valid BIRD symbol. This can be checked
+    /// via [`is_valid_table_name`].
+    pub fn new(iter: Origins, table: String) -> Self {
+        OutputStream {
+            iter,
+            table,
+            state: StreamState::Header,
+        }
+    }
+}
+
+impl Iterator for OutputStream {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.state {
+            StreamState::Header => {
+                self.state = StreamState::Body;
+                Some(format!("roa table {} {{\n", self.table).into_bytes())
+            }
+            StreamState::Body => {
+                match self.iter.next() {
+                    Some(origin) => {
+                        Some(format!(
+                            "\troa {} max {} as {};\n",
+                            origin.prefix.prefix(),
+                            origin.prefix.resolved_max_len(),
+                            origin.asn.into_u32(),
+                        ).into_bytes())
+                    }
+                    None => {
+                        self.state = StreamState::Done;
+                        Some(b"}\n".to_vec())
+                    }
+                }
+            }
+            StreamState::Done => None
+        }
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Returns whether a string can be used as the name of a ROA table.
+///
+/// BIRD symbols start with a letter or underscore followed by letters,
+/// digits, and underscores.
+pub fn is_valid_table_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(ch) if ch.is_ascii_alphabetic() || ch == '_' => { }
+        _ => return false
+    }
+    chars.all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::payload;
+    use crate::formats::json::Set;
+
+    #[test]
+    fn output() {
+        let set = serde_json::from_slice::<Set>(
+            include_bytes!("../../test-data/vrps.json")
+        ).unwrap().into_payload();
+        let output = OutputStream::new(
+            Origins::new(set, Default::default()), "rtrtr".into()
+        ).flatten().collect::<Vec<_>>();
+        let output = String::from_utf8(output).unwrap();
+        let mut lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.first(), Some(&"roa table rtrtr {"));
+        assert_eq!(lines.last(), Some(&"}"));
+        lines.sort();
+        assert_eq!(
+            lines[..2],
+            [
+                "\troa 192.0.2.0/24 max 24 as 64512;",
+                "\troa 2001:db8::/32 max 32 as 4200000000;",
+            ]
+        );
+
+        let output = OutputStream::new(
+            Origins::new(payload::Set::default(), Default::default()),
+            "rpki".into()
+        ).flatten().collect::<Vec<_>>();
+        assert_eq!(output, b"roa table rpki {\n}\n");
+    }
+
+    #[test]
+    fn table_names() {
+        assert!(is_valid_table_name("rtrtr"));
+        assert!(is_valid_table_name("_roa4"));
+        assert!(!is_valid_table_name(""));
+        assert!(!is_valid_table_name("4roa"));
+        assert!(!is_valid_table_name("roa-table"));
+    }
+}
@@ -0,0 +1,883 @@
+//! A compact binary format for payload sets based on CBOR.
+//!
+//! The format is meant for passing complete data sets between RTRTR
+//! instances. It is considerably smaller than JSON and cheaper to produce
+//! and parse.
+//!
+//! A data set is a CBOR map as defined in [RFC 8949] with text strings as
+//! keys. The keys `"session"` and `"serial"` contain the session ID and
+//! serial number of the data set as unsigned integers and the key
+//! `"generated"` the time the data set was produced in seconds since the
+//! Unix epoch. All three are optional. The keys `"roas"`, `"routerKeys"`,
+//! and `"aspas"` contain arrays of the payload items:
+//!
+//! * a route origin is an array of the ASN, the address as a byte string of
+//!   four or sixteen octets, the prefix length, and, if present, the max
+//!   length,
+//! * a router key is an array of the ASN, the subject key identifier, and
+//!   the DER encoded subject public key info, the latter two as byte
+//!   strings, and
+//! * an ASPA record is an array of the customer ASN and an array with the
+//!   provider ASNs.
+//!
+//! When creating output, the top-level map and the item arrays are encoded
+//! with indefinite length so that the output can be produced on the fly.
+//! When reading, both definite and indefinite lengths are accepted and keys
+//! that aren’t known are ignored.
+//!
+//! [RFC 8949]: https://tools.ietf.org/html/rfc8949
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, TimeZone, Utc};
+use rpki::resources::addr::{MaxLenPrefix, Prefix};
+use rpki::resources::asn::Asn;
+use rpki::rtr::payload::{Aspa, Payload, RouteOrigin, RouterKey};
+use rpki::rtr::pdu::ProviderAsns;
+use rpki::slurm::SlurmFile;
+use crate::payload;
+use super::output::{Aspas, Metadata as OutputMetadata, Origins, RouterKeys};
+
+
+//------------ Constants -----------------------------------------------------
+
+/// The major type of an unsigned integer.
+const UINT: u8 = 0;
+
+/// The major type of a negative integer.
+const NEGATIVE: u8 = 1;
+
+/// The major type of a byte string.
+const BYTES: u8 = 2;
+
+/// The major type of a text string.
+const TEXT: u8 = 3;
+
+/// The major type of an array.
+const ARRAY: u8 = 4;
+
+/// The major type of a map.
+const MAP: u8 = 5;
+
+/// The major type of a tagged item.
+const TAG: u8 = 6;
+
+/// The major type of simple values and floats.
+const SIMPLE: u8 = 7;
+
+/// The additional information indicating an indefinite length.
+const INDEFINITE: u8 = 31;
+
+/// The octet ending an item of indefinite length.
+const BREAK: u8 = 0xff;
+
+/// The maximum nesting depth of items skipped when reading.
+const MAX_DEPTH: usize = 16;
+
+
+//------------ Snapshot ------------------------------------------------------
+
+/// A data set read from CBOR.
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    /// The session ID of the data set if present.
+    session: Option<u16>,
+
+    /// The serial number of the data set if present.
+    serial: Option<u32>,
+
+    /// The time the data set was generated if present.
+    generated: Option<DateTime<Utc>>,
+
+    /// The data set.
+    set: payload::Set,
+}
+
+impl Snapshot {
+    /// Reads a snapshot from its encoded form.
+    pub fn from_slice(data: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader { data };
+        let mut res = Snapshot::default();
+        let mut items = payload::PackBuilder::empty();
+        let mut router_keys = Vec::new();
+        let len = reader.container(MAP)?;
+        reader.items(len, |reader| {
+            match reader.text()? {
+                "session" => {
+                    res.session = Some(
+                        u16::try_from(reader.uint()?).map_err(|_| {
+                            String::from("session out of range")
+                        })?
+                    );
+                }
+                "serial" => {
+                    res.serial = Some(
+                        u32::try_from(reader.uint()?).map_err(|_| {
+                            String::from("serial out of range")
+                        })?
+                    );
+                }
+                "generated" => {
+                    res.generated = i64::try_from(
+                        reader.uint()?
+                    ).ok().and_then(|secs| {
+                        Utc.timestamp_opt(secs, 0).single()
+                    });
+                }
+                "roas" => {
+                    let len = reader.container(ARRAY)?;
+                    reader.items(len, |reader| {
+                        items.insert_unchecked(reader.origin()?);
+                        Ok(())
+                    })?;
+                }
+                "routerKeys" => {
+                    let len = reader.container(ARRAY)?;
+                    reader.items(len, |reader| {
+                        router_keys.push(reader.router_key()?);
+                        Ok(())
+                    })?;
+                }
+                "aspas" => {
+                    let len = reader.container(ARRAY)?;
+                    reader.items(len, |reader| {
+                        items.insert_unchecked(reader.aspa()?);
+                        Ok(())
+                    })?;
+                }
+                _ => reader.skip(0)?
+            }
+            Ok(())
+        })?;
+        if !reader.data.is_empty() {
+            return Err("trailing data".into())
+        }
+        for key in router_keys_from_slurm(&router_keys)? {
+            items.insert_unchecked(key)
+        }
+        res.set = items.finalize().into();
+        Ok(res)
+    }
+
+    /// Returns the session ID of the data set if present.
+    pub fn session(&self) -> Option<u16> {
+        self.session
+    }
+
+    /// Returns the serial number of the data set if present.
+    pub fn serial(&self) -> Option<u32> {
+        self.serial
+    }
+
+    /// Returns the time the data set was generated if present.
+    pub fn generated(&self) -> Option<DateTime<Utc>> {
+        self.generated
+    }
+
+    /// Returns the data set.
+    pub fn set(&self) -> &payload::Set {
+        &self.set
+    }
+
+    /// Converts the snapshot into the data set.
+    pub fn into_set(self) -> payload::Set {
+        self.set
+    }
+}
+
+
+//------------ Reader --------------------------------------------------------
+
+/// Reading CBOR items from a slice.
+struct Reader<'a> {
+    /// The remaining data.
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    /// Takes the given number of octets from the data.
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < len {
+            return Err("unexpected end of data".into())
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    /// Takes the head of an item.
+    ///
+    /// Returns the major type and the argument. The argument is `None` for
+    /// items of indefinite length.
+    fn head(&mut self) -> Result<(u8, Option<u64>), String> {
+        let initial = self.take(1)?[0];
+        let info = initial & 0x1f;
+        let arg = match info {
+            0..=23 => u64::from(info),
+            24 => u64::from(self.take(1)?[0]),
+            25 => {
+                u64::from(u16::from_be_bytes(
+                    self.take(2)?.try_into().unwrap()
+                ))
+            }
+            26 => {
+                u64::from(u32::from_be_bytes(
+                    self.take(4)?.try_into().unwrap()
+                ))
+            }
+            27 => u64::from_be_bytes(self.take(8)?.try_into().unwrap()),
+            INDEFINITE => return Ok((initial >> 5, None)),
+            _ => return Err("invalid item header".into())
+        };
+        Ok((initial >> 5, Some(arg)))
+    }
+
+    /// Takes the head of a definite length item of the given major type.
+    fn definite(&mut self, major: u8) -> Result<u64, String> {
+        match self.head()? {
+            (found, Some(arg)) if found == major => Ok(arg),
+            _ => Err("unexpected item".into())
+        }
+    }
+
+    /// Takes an unsigned integer.
+    fn uint(&mut self) -> Result<u64, String> {
+        self.definite(UINT)
+    }
+
+    /// Takes an unsigned integer that must fit into 32 bits.
+    fn u32(&mut self) -> Result<u32, String> {
+        u32::try_from(self.uint()?).map_err(|_| {
+            String::from("integer out of range")
+        })
+    }
+
+    /// Takes the content of a definite length string of the given type.
+    fn string(&mut self, major: u8) -> Result<&'a [u8], String> {
+        let len = self.definite(major)?;
+        self.take(usize::try_from(len).map_err(|_| {
+            String::from("unexpected end of data")
+        })?)
+    }
+
+    /// Takes a byte string.
+    fn bytes(&mut self) -> Result<&'a [u8], String> {
+        self.string(BYTES)
+    }
+
+    /// Takes a text string.
+    fn text(&mut self) -> Result<&'a str, String> {
+        std::str::from_utf8(self.string(TEXT)?).map_err(|_| {
+            String::from("invalid text string")
+        })
+    }
+
+    /// Takes the head of an array or map.
+    ///
+    /// Returns the number of elements or `None` for indefinite length.
+    fn container(&mut self, major: u8) -> Result<Option<u64>, String> {
+        match self.head()? {
+            (found, len) if found == major => Ok(len),
+            _ => Err("unexpected item".into())
+        }
+    }
+
+    /// Processes the elements of an array or map.
+    ///
+    /// For a map, `op` needs to take both the key and the value.
+    ///
+    /// Since every element takes at least one octet, a definite length
+    /// larger than the remaining data is rejected right away rather than
+    /// only once the data runs out.
+    fn items(
+        &mut self,
+        len: Option<u64>,
+        mut op: impl FnMut(&mut Self) -> Result<(), String>
+    ) -> Result<(), String> {
+        match len {
+            Some(len) => {
+                if len > self.data.len() as u64 {
+                    return Err("unexpected end of data".into())
+                }
+                for _ in 0..len {
+                    op(self)?
+                }
+            }
+            None => {
+                while self.data.first() != Some(&BREAK) {
+                    op(self)?
+                }
+                self.data = &self.data[1..];
+            }
+        }
+        Ok(())
+    }
+
+    /// Skips over an item of any type.
+    fn skip(&mut self, depth: usize) -> Result<(), String> {
+        if depth > MAX_DEPTH {
+            return Err("items nested too deeply".into())
+        }
+        match self.head()? {
+            (UINT | NEGATIVE | SIMPLE, Some(_)) => Ok(()),
+            (BYTES | TEXT, Some(len)) => {
+                self.take(usize::try_from(len).map_err(|_| {
+                    String::from("unexpected end of data")
+                })?).map(|_| ())
+            }
+            (major @ (BYTES | TEXT), None) => {
+                self.items(None, |reader| {
+                    reader.string(major).map(|_| ())
+                })
+            }
+            (ARRAY, len) => {
+                self.items(len, |reader| reader.skip(depth + 1))
+            }
+            (MAP, len) => {
+                self.items(len, |reader| {
+                    reader.skip(depth + 1)?;
+                    reader.skip(depth + 1)
+                })
+            }
+            (TAG, Some(_)) => self.skip(depth + 1),
+            _ => Err("invalid item".into())
+        }
+    }
+
+    /// Takes a route origin.
+    fn origin(&mut self) -> Result<Payload, String> {
+        let len = self.definite(ARRAY)?;
+        if len != 3 && len != 4 {
+            return Err("invalid route origin".into())
+        }
+        let asn = self.u32()?;
+        let addr = match self.bytes()? {
+            addr if addr.len() == 4 => {
+                let addr: [u8; 4] = addr.try_into().unwrap();
+                IpAddr::V4(Ipv4Addr::from(addr))
+            }
+            addr if addr.len() == 16 => {
+                let addr: [u8; 16] = addr.try_into().unwrap();
+                IpAddr::V6(Ipv6Addr::from(addr))
+            }
+            _ => return Err("invalid address in route origin".into())
+        };
+        let prefix_len = u8::try_from(self.uint()?).map_err(|_| {
+            String::from("invalid prefix length in route origin")
+        })?;
+        let max_len = if len == 4 {
+            Some(u8::try_from(self.uint()?).map_err(|_| {
+                String::from("invalid max length in route origin")
+            })?)
+        }
+        else {
+            None
+        };
+        let prefix = Prefix::new(addr, prefix_len).map_err(|_| {
+            String::from("invalid prefix in route origin")
+        })?;
+        let prefix = MaxLenPrefix::new(prefix, max_len).map_err(|_| {
+            String::from("invalid max length in route origin")
+        })?;
+        Ok(Payload::Origin(RouteOrigin::new(prefix, asn.into())))
+    }
+
+    /// Takes a router key.
+    ///
+    /// Because router keys are constructed via SLURM, this only returns
+    /// the raw values.
+    fn router_key(&mut self) -> Result<(u32, &'a [u8], &'a [u8]), String> {
+        if self.definite(ARRAY)? != 3 {
+            return Err("invalid router key".into())
+        }
+        Ok((self.u32()?, self.bytes()?, self.bytes()?))
+    }
+
+    /// Takes an ASPA record.
+    fn aspa(&mut self) -> Result<Payload, String> {
+        if self.definite(ARRAY)? != 2 {
+            return Err("invalid ASPA record".into())
+        }
+        let customer = self.u32()?;
+        let mut providers = Vec::new();
+        let len = self.container(ARRAY)?;
+        self.items(len, |reader| {
+            providers.push(Asn::from(reader.u32()?));
+            Ok(())
+        })?;
+        let providers = ProviderAsns::try_from_iter(
+            providers
+        ).map_err(|_| String::from("too many ASPA providers"))?;
+        Ok(Payload::Aspa(Aspa::new(customer.into(), providers)))
+    }
+}
+
+/// Converts raw router keys into payload.
+///
+/// Router keys are easiest to construct from SLURM.
+fn router_keys_from_slurm(
+    keys: &[(u32, &[u8], &[u8])]
+) -> Result<Vec<Payload>, String> {
+    if keys.is_empty() {
+        return Ok(Vec::new())
+    }
+    let slurm = serde_json::to_vec(&serde_json::json!({
+        "slurmVersion": 1,
+        "validationOutputFilters": {
+            "prefixFilters": [],
+            "bgpsecFilters": [],
+        },
+        "locallyAddedAssertions": {
+            "prefixAssertions": [],
+            "bgpsecAssertions": keys.iter().map(|(asn, ski, key)| {
+                serde_json::json!({
+                    "asn": asn,
+                    "SKI": URL_SAFE_NO_PAD.encode(ski),
+                    "routerPublicKey": URL_SAFE_NO_PAD.encode(key),
+                })
+            }).collect::<Vec<_>>(),
+        },
+    })).map_err(|err| err.to_string())?;
+    let slurm = SlurmFile::from_reader(
+        slurm.as_slice()
+    ).map_err(|err| format!("invalid router key: {}", err))?;
+    Ok(slurm.assertions.iter_payload().collect())
+}
+
+
+//------------ OutputStream --------------------------------------------------
+
+/// A stream of CBOR encoded output.
+pub struct OutputStream {
+    /// The iterator over the route origins.
+    iter: Origins,
+
+    /// The iterator over the router keys.
+    router_keys: RouterKeys,
+
+    /// The iterator over the ASPA records.
+    aspas: Aspas,
+
+    /// The metadata to include with the output if any.
+    metadata: Option<OutputMetadata>,
+
+    /// The current stream state.
+    state: StreamState,
+}
+
+/// The state of the stream.
+#[derive(Clone, Copy, Debug)]
+enum StreamState {
+    /// We need to write the header next.
+    Header,
+
+    /// We are writing route origins.
+    Origins,
+
+    /// We are writing router keys.
+    Keys,
+
+    /// We are writing ASPA records.
+    Aspas,
+
+    /// We are done!
+    Done
+}
+
+impl OutputStream {
+    /// Creates a new output stream for the given payload.
+    pub fn new(
+        iter: Origins,
+        router_keys: RouterKeys,
+        aspas: Aspas,
+        metadata: Option<OutputMetadata>,
+    ) -> Self {
+        OutputStream {
+            iter,
+            router_keys,
+            aspas,
+            metadata,
+            state: StreamState::Header,
+        }
+    }
+
+    /// Returns the header of the output.
+    ///
+    /// This starts the map, adds the metadata, and starts the array of
+    /// route origins.
+    fn header(&self) -> Vec<u8> {
+        let mut res = vec![(MAP << 5) | INDEFINITE];
+        if let Some(metadata) = self.metadata.as_ref() {
+            if let Some(session) = metadata.session {
+                push_text(&mut res, "session");
+                push_head(&mut res, UINT, session.into());
+            }
+            push_text(&mut res, "serial");
+            push_head(&mut res, UINT, metadata.serial.into());
+            push_text(&mut res, "generated");
+            push_head(
+                &mut res, UINT,
+                u64::try_from(metadata.generated.timestamp()).unwrap_or(0)
+            );
+        }
+        push_text(&mut res, "roas");
+        res.push((ARRAY << 5) | INDEFINITE);
+        res
+    }
+
+    /// Returns the encoded route origin.
+    fn origin(origin: &RouteOrigin) -> Vec<u8> {
+        let mut res = Vec::with_capacity(24);
+        let max_len = origin.prefix.max_len();
+        push_head(
+            &mut res, ARRAY, if max_len.is_some() { 4 } else { 3 }
+        );
+        push_head(&mut res, UINT, origin.asn.into_u32().into());
+        match origin.prefix.addr() {
+            IpAddr::V4(addr) => push_string(&mut res, BYTES, &addr.octets()),
+            IpAddr::V6(addr) => push_string(&mut res, BYTES, &addr.octets()),
+        }
+        push_head(&mut res, UINT, origin.prefix.prefix_len().into());
+        if let Some(max_len) = max_len {
+            push_head(&mut res, UINT, max_len.into());
+        }
+        res
+    }
+
+    /// Returns the encoded router key.
+    fn router_key(key: &RouterKey) -> Vec<u8> {
+        let mut res = Vec::new();
+        push_head(&mut res, ARRAY, 3);
+        push_head(&mut res, UINT, key.asn.into_u32().into());
+        push_string(&mut res, BYTES, key.key_identifier.as_slice());
+        push_string(&mut res, BYTES, key.key_info.as_slice());
+        res
+    }
+
+    /// Returns the encoded ASPA record.
+    fn aspa(aspa: &Aspa) -> Vec<u8> {
+        let mut res = Vec::new();
+        push_head(&mut res, ARRAY, 2);
+        push_head(&mut res, UINT, aspa.customer.into_u32().into());
+        let providers: Vec<_> = aspa.providers.iter().collect();
+        push_head(
+            &mut res, ARRAY,
+            u64::try_from(providers.len()).unwrap_or(u64::MAX)
+        );
+        for provider in providers {
+            push_head(&mut res, UINT, provider.into_u32().into());
+        }
+        res
+    }
+
+    /// Ends the current array and starts the next one if there is one.
+    fn end_list(&mut self, next: StreamState) -> Vec<u8> {
+        let mut res = vec![BREAK];
+        match next {
+            StreamState::Keys => {
+                push_text(&mut res, "routerKeys");
+                res.push((ARRAY << 5) | INDEFINITE);
+            }
+            StreamState::Aspas => {
+                push_text(&mut res, "aspas");
+                res.push((ARRAY << 5) | INDEFINITE);
+            }
+            _ => res.push(BREAK),
+        }
+        self.state = next;
+        res
+    }
+}
+
+impl Iterator for OutputStream {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.state {
+            StreamState::Header => {
+                self.state = StreamState::Origins;
+                Some(self.header())
+            }
+            StreamState::Origins => {
+                match self.iter.next() {
+                    Some(origin) => Some(Self::origin(&origin)),
+                    None => Some(self.end_list(StreamState::Keys))
+                }
+            }
+            StreamState::Keys => {
+                match self.router_keys.next() {
+                    Some(key) => Some(Self::router_key(&key)),
+                    None => Some(self.end_list(StreamState::Aspas))
+                }
+            }
+            StreamState::Aspas => {
+                match self.aspas.next() {
+                    Some(aspa) => Some(Self::aspa(&aspa)),
+                    None => Some(self.end_list(StreamState::Done))
+                }
+            }
+            StreamState::Done => None
+        }
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Appends the head of an item with the given major type and argument.
+fn push_head(target: &mut Vec<u8>, major: u8, arg: u64) {
+    let major = major << 5;
+    if arg < 24 {
+        target.push(major | arg as u8)
+    }
+    else if let Ok(arg) = u8::try_from(arg) {
+        target.push(major | 24);
+        target.push(arg);
+    }
+    else if let Ok(arg) = u16::try_from(arg) {
+        target.push(major | 25);
+        target.extend_from_slice(&arg.to_be_bytes());
+    }
+    else if let Ok(arg) = u32::try_from(arg) {
+        target.push(major | 26);
+        target.extend_from_slice(&arg.to_be_bytes());
+    }
+    else {
+        target.push(major | 27);
+        target.extend_from_slice(&arg.to_be_bytes());
+    }
+}
+
+/// Appends a byte or text string.
+fn push_string(target: &mut Vec<u8>, major: u8, value: &[u8]) {
+    push_head(target, major, u64::try_from(value.len()).unwrap_or(u64::MAX));
+    target.extend_from_slice(value);
+}
+
+/// Appends a text string.
+fn push_text(target: &mut Vec<u8>, value: &str) {
+    push_string(target, TEXT, value.as_bytes())
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::payload::testrig;
+
+    fn encode(
+        set: &payload::Set, metadata: Option<OutputMetadata>
+    ) -> Vec<u8> {
+        OutputStream::new(
+            Origins::new(set.clone(), Default::default()),
+            RouterKeys::new(set), Aspas::new(set), metadata
+        ).flatten().collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut builder = payload::PackBuilder::empty();
+        for item in testrig::pack([1, 2, 3]).iter() {
+            builder.insert(item.clone()).unwrap();
+        }
+        builder.insert(Payload::Origin(RouteOrigin::new(
+            MaxLenPrefix::new(
+                Prefix::new_v6("2001:db8::".parse().unwrap(), 32).unwrap(),
+                Some(48)
+            ).unwrap(),
+            64496.into()
+        ))).unwrap();
+        builder.insert(Payload::Aspa(Aspa::new(
+            64496.into(),
+            ProviderAsns::try_from_iter(
+                [64498, 64499].into_iter().map(Asn::from)
+            ).unwrap()
+        ))).unwrap();
+        for item in testrig::slurm_pack(
+            include_bytes!("../../test-data/router-keys.slurm.json")
+        ).iter() {
+            builder.insert(item.clone()).unwrap();
+        }
+        let set = payload::Set::from(builder.finalize());
+
+        let snapshot = Snapshot::from_slice(&encode(&set, None)).unwrap();
+        assert_eq!(snapshot.session(), None);
+        assert_eq!(snapshot.serial(), None);
+        assert_eq!(snapshot.set(), &set);
+
+        let update = payload::Update::new(set.clone());
+        let metadata = OutputMetadata::new(&update, 12).with_session(7);
+        let snapshot = Snapshot::from_slice(
+            &encode(&set, Some(metadata.clone()))
+        ).unwrap();
+        assert_eq!(snapshot.session(), Some(7));
+        assert_eq!(snapshot.serial(), Some(12));
+        assert_eq!(
+            snapshot.generated().map(|time| time.timestamp()),
+            Some(metadata.generated.timestamp())
+        );
+        assert_eq!(snapshot.into_set(), set);
+    }
+
+    #[test]
+    fn definite_lengths() {
+        // {"serial": 1, "extra": [1, {"a": h''}],
+        //  "roas": [[64496, h'c0000200', 24]]}
+        let data = [
+            0xa3,
+            0x66, b's', b'e', b'r', b'i', b'a', b'l', 0x01,
+            0x65, b'e', b'x', b't', b'r', b'a',
+            0x82, 0x01, 0xa1, 0x61, b'a', 0x40,
+            0x64, b'r', b'o', b'a', b's',
+            0x81, 0x83, 0x19, 0xfb, 0xf0, 0x44, 192, 0, 2, 0, 0x18, 24,
+        ];
+        let snapshot = Snapshot::from_slice(&data).unwrap();
+        assert_eq!(snapshot.serial(), Some(1));
+        assert_eq!(snapshot.set().len(), 1);
+
+        assert!(Snapshot::from_slice(&data[..data.len() - 1]).is_err());
+        assert!(Snapshot::from_slice(b"").is_err());
+        assert!(Snapshot::from_slice(&[0x80]).is_err());
+        let mut trailing = data.to_vec();
+        trailing.push(0);
+        assert!(Snapshot::from_slice(&trailing).is_err());
+    }
+
+    #[test]
+    fn indefinite_lengths() {
+        // {_ "extra": [_ {_ "a": (_ h'01', h'02')}], "serial": 1,
+        //  "roas": [_ [64496, h'c0000200', 24]]}
+        let data = [
+            0xbf,
+            0x65, b'e', b'x', b't', b'r', b'a',
+            0x9f, 0xbf, 0x61, b'a', 0x5f, 0x41, 1, 0x41, 2, 0xff, 0xff, 0xff,
+            0x66, b's', b'e', b'r', b'i', b'a', b'l', 0x01,
+            0x64, b'r', b'o', b'a', b's',
+            0x9f, 0x83, 0x19, 0xfb, 0xf0, 0x44, 192, 0, 2, 0, 0x18, 24, 0xff,
+            0xff,
+        ];
+        let snapshot = Snapshot::from_slice(&data).unwrap();
+        assert_eq!(snapshot.serial(), Some(1));
+        assert_eq!(snapshot.set().len(), 1);
+
+        // Every prefix lacks at least the final break.
+        for len in 0..data.len() {
+            assert!(Snapshot::from_slice(&data[..len]).is_err());
+        }
+
+        // Chunks of indefinite strings must be definite strings of the
+        // same type.
+        assert!(Snapshot::from_slice(&[
+            0xbf, 0x61, b'a', 0x5f, 0x61, b'x', 0xff, 0xff
+        ]).is_err());
+        assert!(Snapshot::from_slice(&[
+            0xbf, 0x61, b'a', 0x5f, 0x5f, 0xff, 0xff, 0xff
+        ]).is_err());
+    }
+
+    #[test]
+    fn truncated_output() {
+        let set = payload::Set::from(testrig::pack([1, 2, 3]));
+        let update = payload::Update::new(set.clone());
+        let data = encode(
+            &set, Some(OutputMetadata::new(&update, 12).with_session(7))
+        );
+        assert_eq!(Snapshot::from_slice(&data).unwrap().into_set(), set);
+        for len in 0..data.len() {
+            assert!(Snapshot::from_slice(&data[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn truncated_heads() {
+        // Each argument size with one octet missing.
+        for data in [
+            &[0x18][..],
+            &[0x19, 0x01],
+            &[0x1a, 0x01, 0x02, 0x03],
+            &[0x1b, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07],
+        ] {
+            assert!(Reader { data }.uint().is_err());
+        }
+
+        // The reserved additional information values.
+        for info in 28..31 {
+            assert!(Reader { data: &[info, 0, 0, 0, 0] }.head().is_err());
+        }
+
+        // A string longer than the data.
+        assert!(Reader { data: &[0x42, 0x01] }.bytes().is_err());
+        assert!(Reader {
+            data: &[0x5b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]
+        }.bytes().is_err());
+    }
+
+    #[test]
+    fn definite_length_limit() {
+        // {"roas": [ ... 2^64 - 1 elements ... ]}
+        let data = [
+            0xa1, 0x64, b'r', b'o', b'a', b's',
+            0x9b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0x83, 0x19, 0xfb, 0xf0, 0x44, 192, 0, 2, 0, 0x18, 24,
+        ];
+        assert!(Snapshot::from_slice(&data).is_err());
+
+        // The same for a skipped map with 2^64 - 1 entries.
+        let data = [
+            0xbf, 0x61, b'a',
+            0xbb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0x00, 0x00, 0xff,
+        ];
+        assert!(Snapshot::from_slice(&data).is_err());
+
+        let mut reader = Reader { data: &[0x00, 0x00] };
+        assert!(reader.items(Some(3), |reader| {
+            reader.uint().map(|_| ())
+        }).is_err());
+        let mut reader = Reader { data: &[0x00, 0x00] };
+        assert!(reader.items(Some(2), |reader| {
+            reader.uint().map(|_| ())
+        }).is_ok());
+    }
+
+    #[test]
+    fn nesting_limit() {
+        // {"extra": [[[ ... 0 ... ]]]} with the given number of arrays.
+        fn nested(depth: usize) -> Vec<u8> {
+            let mut res = vec![0xa1, 0x65, b'e', b'x', b't', b'r', b'a'];
+            res.extend(std::iter::repeat(0x81).take(depth));
+            res.push(0x00);
+            res
+        }
+
+        assert!(Snapshot::from_slice(&nested(MAX_DEPTH)).is_ok());
+        assert!(Snapshot::from_slice(&nested(MAX_DEPTH + 1)).is_err());
+
+        // Tags and maps count, too.
+        let mut data = vec![0xa1, 0x65, b'e', b'x', b't', b'r', b'a'];
+        for _ in 0..MAX_DEPTH + 1 {
+            data.extend_from_slice(&[0xc0, 0xa1, 0x00]);
+        }
+        data.push(0x00);
+        assert!(Snapshot::from_slice(&data).is_err());
+
+        // Deep nesting must fail without exhausting the stack.
+        let mut data = vec![0xa1, 0x65, b'e', b'x', b't', b'r', b'a'];
+        data.extend(std::iter::repeat(0x9f).take(1_000_000));
+        assert!(Snapshot::from_slice(&data).is_err());
+    }
+
+    #[test]
+    fn heads() {
+        for value in [0, 23, 24, 255, 256, 65535, 65536, u64::MAX] {
+            let mut data = Vec::new();
+            push_head(&mut data, UINT, value);
+            let mut reader = Reader { data: &data };
+            assert_eq!(reader.uint().unwrap(), value);
+            assert!(reader.data.is_empty());
+        }
+    }
+}
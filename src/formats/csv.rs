@@ -0,0 +1,93 @@
+//! The CSV format for validated RPKI data.
+//!
+//! This produces the CSV flavour used by Routinator. The output starts with
+//! a header line naming the columns. It is followed by one line for each
+//! route origin with the AS number including the `AS` prefix, the prefix in
+//! slash notation, the max length, and the trust anchor. Since the trust
+//! anchor isn’t known to RTRTR, it is always given as `N/A`.
+//!
+//! Router keys and ASPA records cannot be represented in this format and
+//! are left out.
+
+use super::output::Origins;
+
+
+//------------ OutputStream --------------------------------------------------
+
+/// A stream of CSV formatted output.
+pub struct OutputStream {
+    /// The iterator over the route origins.
+    iter: Origins,
+
+    /// Do we still need to write the header?
+    header: bool,
+}
+
+impl OutputStream {
+    /// Creates a new output stream for the given route origins.
+    pub fn new(iter: Origins) -> Self {
+        OutputStream {
+            iter,
+            header: true,
+        }
+    }
+}
+
+impl Iterator for OutputStream {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.header {
+            self.header = false;
+            return Some(b"ASN,IP Prefix,Max Length,Trust Anchor\n".to_vec())
+        }
+        self.iter.next().map(|origin| {
+            format!(
+                "{},{},{},N/A\n",
+                origin.asn,
+                origin.prefix.prefix(),
+                origin.prefix.resolved_max_len(),
+            ).into_bytes()
+        })
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::payload;
+    use crate::formats::json::Set;
+
+    #[test]
+    fn output() {
+        let set = serde_json::from_slice::<Set>(
+            include_bytes!("../../test-data/vrps.json")
+        ).unwrap().into_payload();
+        let output = OutputStream::new(
+            Origins::new(set, Default::default())
+        ).flatten().collect::<Vec<_>>();
+        let output = String::from_utf8(output).unwrap();
+        let mut lines = output.lines();
+        assert_eq!(
+            lines.next(), Some("ASN,IP Prefix,Max Length,Trust Anchor")
+        );
+        let mut lines = lines.collect::<Vec<_>>();
+        lines.sort();
+        assert_eq!(
+            lines,
+            [
+                "AS4200000000,2001:db8::/32,32,N/A",
+                "AS64512,192.0.2.0/24,24,N/A",
+            ]
+        );
+        assert!(output.ends_with('\n'));
+
+        let output = OutputStream::new(
+            Origins::new(payload::Set::default(), Default::default())
+        ).flatten().collect::<Vec<_>>();
+        assert_eq!(output, b"ASN,IP Prefix,Max Length,Trust Anchor\n");
+    }
+}
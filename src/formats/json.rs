@@ -12,17 +12,63 @@
 //! and one member called `maxLength` with the max length as an integer.
 //!
 //! Additional members are allowed both in the top-level object and the VRP
-//! objects. They are simply ignored.
+//! objects. They are simply ignored. The only exception is the member
+//! `"ta"` of a VRP which may contain the name of the trust anchor the VRP
+//! was derived from. It is available via [`Set::trust_anchors`].
+//!
+//! As an exception, the top-level object may contain a member called
+//! `"metadata"` as produced by GoRTR, StayRTR, and rpki-client. It is an
+//! object with information about the data set. Those of its members we
+//! understand are made available via [`Metadata`]. Members with unexpected
+//! types are ignored rather than causing the data set to be rejected. A
+//! stricter set of rules can be checked via [`Set::check_schema`].
+//!
+//! Incremental updates as provided by Routinator’s `/json-delta` endpoint
+//! can be read via [`Delta`].
 //!
 //! When creating a JSON file, this minimal format will be used. The ASN will
-//! be represented as a string with the `AS` prefix.
+//! be represented as a string with the `AS` prefix. Optionally, a
+//! `"metadata"` member understood by all the above producers can be added.
+//!
+//! If the data set contains router keys, they are given in a member called
+//! `"routerKeys"`, both when reading and creating JSON. It contains a list
+//! of objects with the members `"asn"`, `"SKI"`, and `"routerPublicKey"`
+//! using the same representation as the BGPsec assertions of SLURM (RFC
+//! 8416). I.e., both the key identifier and the DER encoded subject public
+//! key info are given in unpadded Base64 with the URL-safe alphabet.
+//!
+//! Similarly, ASPA records are given in a member called `"aspas"` when
+//! creating JSON if the data set contains any. It contains a list of
+//! objects with a member `"customer"` with the customer ASN and a member
+//! `"providers"` with a list of provider ASNs. ASPA records in this form
+//! are read back as well.
+//!
+//! The full output of rpki-client uses different members for router keys
+//! and ASPA records which are read but never created. Router keys are
+//! given in a member called `"bgpsec_keys"` as a list of objects with the
+//! members `"asn"`, `"ski"` with the key identifier in hex, and `"pubkey"`
+//! with the subject public key info in regular Base64. ASPA records are
+//! given in the `"aspas"` member as objects with a member `"customer_asid"`
+//! and a member `"providers"` with a list of provider AS numbers. Objects
+//! with neither a `"customer_asid"` nor a `"customer"` member are skipped.
+//!
+//! Individual payload items such as those of a diff can be converted into a
+//! JSON value via [`items_value`] and read back via [`Items`].
 
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use bytes::Bytes;
+use chrono::{DateTime, TimeZone, Utc};
+use rpki::crypto::KeyIdentifier;
 use rpki::resources::asn::Asn;
 use rpki::resources::addr::{MaxLenError, MaxLenPrefix, Prefix};
-use rpki::rtr::payload::{RouteOrigin, Payload, PayloadRef};
-use rpki::rtr::server::PayloadSet;
-use serde::{Deserialize, Serialize};
+use rpki::rtr::client::PayloadError;
+use rpki::rtr::payload::{Action, Aspa, RouteOrigin, RouterKey, Payload};
+use rpki::rtr::pdu::{ProviderAsns, RouterKeyInfo};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::IgnoredAny;
 use crate::payload;
+use super::output::{Aspas, Metadata as OutputMetadata, Origins, RouterKeys};
 
 
 //============ Input =========================================================
@@ -32,19 +78,400 @@ use crate::payload;
 /// The content of a JSON formatted data set.
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Set {
+    /// The metadata of the data set if present.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<Metadata>,
+
     /// The list of VRPs.
     roas: Vec<Vrp>,
+
+    /// The list of router keys.
+    #[serde(
+        default, rename = "routerKeys", skip_serializing_if = "Vec::is_empty"
+    )]
+    router_keys: Vec<RouterKeyItem>,
+
+    /// The list of router keys in rpki-client’s format.
+    #[serde(default, skip_serializing)]
+    bgpsec_keys: Vec<BgpsecKeyItem>,
+
+    /// The list of ASPA records in rpki-client’s format.
+    #[serde(default, skip_serializing)]
+    aspas: Vec<AspaItem>,
 }
 
 impl Set {
+    /// Returns the metadata of the data set if present.
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Returns the number of VRPs in the data set.
+    ///
+    /// This includes duplicates.
+    pub fn len(&self) -> usize {
+        self.roas.len()
+    }
+
+    /// Returns whether the data set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.roas.is_empty()
+    }
+
+    /// Checks the data set against the strict schema.
+    ///
+    /// Parsing a data set is lenient and ignores members of the metadata
+    /// with unexpected types. The strict schema requires all known metadata
+    /// members to have the correct type and the number of VRPs reported in
+    /// the metadata to match the actual number.
+    ///
+    /// Returns a description of the first violation found.
+    pub fn check_schema(&self) -> Result<(), String> {
+        let metadata = match self.metadata.as_ref() {
+            Some(metadata) => metadata,
+            None => return Ok(())
+        };
+        if let Some(name) = metadata.invalid_member() {
+            return Err(format!(
+                "metadata member '{}' has an unexpected type", name
+            ))
+        }
+        if let Some(count) = metadata.count() {
+            if count != self.len() as u64 {
+                return Err(format!(
+                    "metadata reports {} VRPs but data set contains {}",
+                    count, self.len()
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the trust anchors of the VRPs.
+    ///
+    /// Returns `None` if none of the VRPs has a trust anchor. The value
+    /// `"N/A"` is used by RTRTR itself for an unknown trust anchor and is
+    /// therefore ignored.
+    pub fn trust_anchors(&self) -> Option<payload::TrustAnchors> {
+        let mut res = payload::TrustAnchorsBuilder::empty();
+        let mut empty = true;
+        for item in &self.roas {
+            if let Some(ta) = item.ta.as_ref() {
+                if ta != "N/A" {
+                    res.insert(Payload::Origin(item.payload), ta);
+                    empty = false;
+                }
+            }
+        }
+        (!empty).then(|| res.finalize())
+    }
+
     /// Converts the JSON formatted data set into a payload set.
     pub fn into_payload(self) -> payload::Set {
         let mut res = payload::PackBuilder::empty();
         for item in self.roas {
             let _ = res.insert(item.into_payload());
         }
+        for item in self.router_keys {
+            let _ = res.insert(item.into_payload());
+        }
+        for item in self.bgpsec_keys {
+            let _ = res.insert(item.0.into_payload());
+        }
+        for item in self.aspas.into_iter().filter_map(|item| item.0) {
+            let _ = res.insert(Payload::Aspa(item));
+        }
+        res.finalize().into()
+    }
+}
+
+
+//------------ Delta ---------------------------------------------------------
+
+/// The content of a JSON formatted delta.
+///
+/// This is the format of the `/json-delta` endpoint of Routinator. It is an
+/// object with the members `"session"` and `"serial"` identifying the
+/// version of the data set, `"announced"` and `"withdrawn"` containing the
+/// VRPs added and removed since the version given in the request, and
+/// `"reset"` which is true if the delta contains the complete data set
+/// instead.
+///
+/// The two lists of VRPs are either given directly or as the member
+/// `"routeOrigins"` of an object. Any other members of such an object are
+/// ignored.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Delta {
+    /// Whether the delta contains the complete data set.
+    #[serde(default)]
+    reset: bool,
+
+    /// The session of the data set.
+    session: u64,
+
+    /// The serial number of the data set.
+    serial: u64,
+
+    /// The VRPs that have been announced.
+    #[serde(default)]
+    announced: DeltaVrps,
+
+    /// The VRPs that have been withdrawn.
+    #[serde(default)]
+    withdrawn: DeltaVrps,
+}
+
+impl Delta {
+    /// Returns whether the delta contains the complete data set.
+    pub fn is_reset(&self) -> bool {
+        self.reset
+    }
+
+    /// Returns the session of the data set.
+    pub fn session(&self) -> u64 {
+        self.session
+    }
+
+    /// Returns the serial number of the data set.
+    pub fn serial(&self) -> u64 {
+        self.serial
+    }
+
+    /// Converts the announced VRPs of the delta into a payload set.
+    ///
+    /// This is what should be done with a reset delta.
+    pub fn into_set(self) -> payload::Set {
+        let mut res = payload::PackBuilder::empty();
+        for item in self.announced.into_vec() {
+            let _ = res.insert(item.into_payload());
+        }
         res.finalize().into()
     }
+
+    /// Converts the delta into a payload diff.
+    ///
+    /// Returns an error if the delta announces or withdraws the same VRP
+    /// more than once or both announces and withdraws it.
+    pub fn into_diff(self) -> Result<payload::Diff, PayloadError> {
+        let mut res = payload::DiffBuilder::empty();
+        for item in self.announced.into_vec() {
+            res.push(item.into_payload(), Action::Announce)?;
+        }
+        for item in self.withdrawn.into_vec() {
+            res.push(item.into_payload(), Action::Withdraw)?;
+        }
+        Ok(res.finalize())
+    }
+}
+
+
+//------------ DeltaVrps -----------------------------------------------------
+
+/// The list of VRPs in a delta.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum DeltaVrps {
+    /// The VRPs given directly as a list.
+    List(Vec<Vrp>),
+
+    /// The VRPs given as a member of an object.
+    Object {
+        #[serde(default, rename = "routeOrigins")]
+        route_origins: Vec<Vrp>,
+    }
+}
+
+impl DeltaVrps {
+    /// Converts the value into the list of VRPs.
+    fn into_vec(self) -> Vec<Vrp> {
+        match self {
+            DeltaVrps::List(list) => list,
+            DeltaVrps::Object { route_origins } => route_origins,
+        }
+    }
+}
+
+impl Default for DeltaVrps {
+    fn default() -> Self {
+        DeltaVrps::List(Vec::new())
+    }
+}
+
+
+//------------ Items ---------------------------------------------------------
+
+/// A JSON formatted list of payload items of all types.
+///
+/// This is the format produced by [`items_value`]. Since ASPA records
+/// cannot currently be converted into payload, they are only counted.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Items {
+    /// The route origins.
+    #[serde(default, rename = "routeOrigins")]
+    route_origins: Vec<Vrp>,
+
+    /// The router keys.
+    #[serde(default, rename = "routerKeys")]
+    router_keys: Vec<RouterKeyItem>,
+
+    /// The ASPA records.
+    #[serde(default)]
+    aspas: Vec<IgnoredAny>,
+}
+
+impl Items {
+    /// Returns the number of ASPA records that will be ignored.
+    pub fn aspa_count(&self) -> usize {
+        self.aspas.len()
+    }
+
+    /// Converts the items into a list of payload.
+    pub fn into_payload(self) -> Vec<Payload> {
+        self.route_origins.into_iter().map(Vrp::into_payload).chain(
+            self.router_keys.into_iter().map(RouterKeyItem::into_payload)
+        ).collect()
+    }
+}
+
+
+//------------ Metadata ------------------------------------------------------
+
+/// The metadata of a JSON formatted data set.
+///
+/// The different producers use different members. We try to understand as
+/// many of them as possible.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Metadata {
+    /// The number of VRPs in the data set as reported by the producer.
+    ///
+    /// GoRTR and StayRTR call this `"counts"`, rpki-client `"vrps"`.
+    #[serde(
+        default, alias = "vrps", skip_serializing_if = "Member::is_unset"
+    )]
+    counts: Member<u64>,
+
+    /// The time the data set was generated as a Unix timestamp.
+    #[serde(default, skip_serializing_if = "Member::is_unset")]
+    generated: Member<i64>,
+
+    /// The time the data set was built in RFC 3339 format.
+    #[serde(default, skip_serializing_if = "Member::is_unset")]
+    buildtime: Member<String>,
+
+    /// The serial number of the data set.
+    #[serde(default, skip_serializing_if = "Member::is_unset")]
+    serial: Member<u32>,
+}
+
+impl Metadata {
+    /// Returns the number of VRPs reported by the producer.
+    pub fn count(&self) -> Option<u64> {
+        self.counts.get().copied()
+    }
+
+    /// Returns the time the data set was generated.
+    ///
+    /// Uses the Unix timestamp if present and the build time otherwise.
+    pub fn generated(&self) -> Option<DateTime<Utc>> {
+        if let Some(generated) = self.generated.get() {
+            return Utc.timestamp_opt(*generated, 0).single()
+        }
+        DateTime::parse_from_rfc3339(
+            self.buildtime.get()?
+        ).ok().map(|time| time.with_timezone(&Utc))
+    }
+
+    /// Returns the serial number of the data set.
+    pub fn serial(&self) -> Option<u32> {
+        self.serial.get().copied()
+    }
+
+    /// Returns the name of the first member with an unexpected type.
+    fn invalid_member(&self) -> Option<&'static str> {
+        if self.counts.is_invalid() {
+            Some("counts")
+        }
+        else if self.generated.is_invalid() {
+            Some("generated")
+        }
+        else if self.buildtime.is_invalid() {
+            Some("buildtime")
+        }
+        else if self.serial.is_invalid() {
+            Some("serial")
+        }
+        else {
+            None
+        }
+    }
+}
+
+
+//------------ Member --------------------------------------------------------
+
+/// A leniently parsed member of the metadata.
+///
+/// Values of the wrong type are not rejected but remembered as invalid.
+#[derive(Clone, Debug, Default)]
+enum Member<T> {
+    /// The member was not present.
+    #[default]
+    Missing,
+
+    /// The member was present with the expected type.
+    Valid(T),
+
+    /// The member was present with an unexpected type.
+    Invalid,
+}
+
+impl<T> Member<T> {
+    /// Returns the value if it was present with the expected type.
+    fn get(&self) -> Option<&T> {
+        match self {
+            Member::Valid(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the member had an unexpected type.
+    fn is_invalid(&self) -> bool {
+        matches!(self, Member::Invalid)
+    }
+
+    /// Returns whether there is no valid value.
+    fn is_unset(&self) -> bool {
+        !matches!(self, Member::Valid(_))
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Member<T> {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D
+    ) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Lenient<T> {
+            Value(T),
+            Other(IgnoredAny),
+        }
+
+        match Lenient::<T>::deserialize(deserializer)? {
+            Lenient::Value(value) => Ok(Member::Valid(value)),
+            Lenient::Other(_) => Ok(Member::Invalid),
+        }
+    }
+}
+
+impl<T: Serialize> Serialize for Member<T> {
+    fn serialize<S: Serializer>(
+        &self, serializer: S
+    ) -> Result<S::Ok, S::Error> {
+        match self {
+            Member::Valid(value) => value.serialize(serializer),
+            _ => serializer.serialize_none(),
+        }
+    }
 }
 
 
@@ -56,6 +483,9 @@ impl Set {
 struct Vrp {
     /// The payload of the VRP.
     payload: RouteOrigin,
+
+    /// The name of the trust anchor if given.
+    ta: Option<String>,
 }
 
 impl Vrp {
@@ -72,12 +502,118 @@ impl TryFrom<JsonVrp> for Vrp {
         MaxLenPrefix::new(json.prefix, Some(json.max_length)).map(|prefix| {
             Vrp {
                 payload: RouteOrigin::new(prefix, json.asn),
+                ta: json.ta,
             }
         })
     }
 }
 
 
+//------------ RouterKeyItem -------------------------------------------------
+
+/// The content of a JSON formatted router key.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "JsonRouterKey", into = "JsonRouterKey")]
+struct RouterKeyItem {
+    /// The payload of the router key.
+    payload: RouterKey,
+}
+
+impl RouterKeyItem {
+    /// Converts the JSON router key into regular payload.
+    fn into_payload(self) -> Payload {
+        Payload::RouterKey(self.payload)
+    }
+}
+
+impl TryFrom<JsonRouterKey> for RouterKeyItem {
+    type Error = String;
+
+    fn try_from(json: JsonRouterKey) -> Result<Self, Self::Error> {
+        let ski = decode_base64url(&json.ski).ok_or_else(|| {
+            String::from("invalid router key identifier")
+        })?;
+        let key_identifier = KeyIdentifier::try_from(
+            ski.as_slice()
+        ).map_err(|_| String::from("invalid router key identifier"))?;
+        let key = decode_base64url(&json.key).ok_or_else(|| {
+            String::from("invalid router public key")
+        })?;
+        let key_info = RouterKeyInfo::new(Bytes::from(key)).map_err(|_| {
+            String::from("invalid router public key")
+        })?;
+        Ok(RouterKeyItem {
+            payload: RouterKey::new(key_identifier, json.asn, key_info)
+        })
+    }
+}
+
+/// Decodes URL-safe Base64 with or without trailing padding.
+fn decode_base64url(s: &str) -> Option<Vec<u8>> {
+    URL_SAFE_NO_PAD.decode(s.trim_end_matches('=')).ok()
+}
+
+
+//------------ BgpsecKeyItem -------------------------------------------------
+
+/// A router key in rpki-client’s JSON format.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "JsonBgpsecKey")]
+struct BgpsecKeyItem(RouterKeyItem);
+
+impl TryFrom<JsonBgpsecKey> for BgpsecKeyItem {
+    type Error = String;
+
+    fn try_from(json: JsonBgpsecKey) -> Result<Self, Self::Error> {
+        let ski = json.ski.replace(':', "");
+        if ski.len() % 2 != 0 {
+            return Err("invalid router key identifier".into())
+        }
+        let ski = (0..ski.len()).step_by(2).map(|idx| {
+            ski.get(idx..idx + 2).and_then(|octet| {
+                u8::from_str_radix(octet, 16).ok()
+            })
+        }).collect::<Option<Vec<_>>>().ok_or_else(|| {
+            String::from("invalid router key identifier")
+        })?;
+        let key = STANDARD.decode(json.pubkey.as_bytes()).map_err(|_| {
+            String::from("invalid router public key")
+        })?;
+        RouterKeyItem::try_from(JsonRouterKey {
+            asn: json.asn,
+            ski: URL_SAFE_NO_PAD.encode(ski),
+            key: URL_SAFE_NO_PAD.encode(key),
+        }).map(BgpsecKeyItem)
+    }
+}
+
+
+//------------ AspaItem ------------------------------------------------------
+
+/// An ASPA record in rpki-client’s JSON format.
+///
+/// Contains `None` if the record was in neither of the known formats.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "JsonAspa")]
+struct AspaItem(Option<Aspa>);
+
+impl TryFrom<JsonAspa> for AspaItem {
+    type Error = String;
+
+    fn try_from(json: JsonAspa) -> Result<Self, Self::Error> {
+        let customer = match (json.customer_asid, json.customer) {
+            (Some(customer), _) => customer.into(),
+            (None, Some(customer)) => customer.0,
+            (None, None) => return Ok(AspaItem(None))
+        };
+        let providers = ProviderAsns::try_from_iter(
+            json.providers.into_iter().map(|item| item.0)
+        ).map_err(|_| String::from("too many ASPA providers"))?;
+        Ok(AspaItem(Some(Aspa::new(customer, providers))))
+    }
+}
+
+
 //============ Serialization =================================================
 
 
@@ -101,6 +637,10 @@ struct JsonVrp {
     /// The max-length member.
     #[serde(rename = "maxLength")]
     max_length: u8,
+
+    /// The trust anchor member.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ta: Option<String>,
 }
 
 impl From<Vrp> for JsonVrp {
@@ -109,19 +649,115 @@ impl From<Vrp> for JsonVrp {
             prefix: vrp.payload.prefix.prefix(),
             asn: vrp.payload.asn,
             max_length: vrp.payload.prefix.resolved_max_len(),
+            ta: vrp.ta,
+        }
+    }
+}
+
+
+//------------ JsonRouterKey -------------------------------------------------
+
+/// A JSON formatted router key.
+///
+/// This is a private helper type making the Serde impls easier.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct JsonRouterKey {
+    /// The ASN member.
+    #[serde(
+        serialize_with = "Asn::serialize_as_str",
+        deserialize_with = "Asn::deserialize_from_any",
+    )]
+    asn: Asn,
+
+    /// The subject key identifier in Base64.
+    #[serde(rename = "SKI")]
+    ski: String,
+
+    /// The subject public key info in Base64.
+    #[serde(rename = "routerPublicKey")]
+    key: String,
+}
+
+impl From<RouterKeyItem> for JsonRouterKey {
+    fn from(key: RouterKeyItem) -> Self {
+        JsonRouterKey {
+            asn: key.payload.asn,
+            ski: URL_SAFE_NO_PAD.encode(
+                key.payload.key_identifier.as_slice()
+            ),
+            key: URL_SAFE_NO_PAD.encode(key.payload.key_info.as_slice()),
         }
     }
 }
 
 
+//------------ JsonBgpsecKey -------------------------------------------------
+
+/// A router key in rpki-client’s JSON format.
+///
+/// This is a private helper type making the Serde impls easier.
+#[derive(Clone, Debug, Deserialize)]
+struct JsonBgpsecKey {
+    /// The ASN member.
+    #[serde(deserialize_with = "Asn::deserialize_from_any")]
+    asn: Asn,
+
+    /// The subject key identifier in hex.
+    ski: String,
+
+    /// The subject public key info in Base64.
+    pubkey: String,
+}
+
+
+//------------ JsonAspa ------------------------------------------------------
+
+/// An ASPA record in either rpki-client’s or our own JSON format.
+///
+/// This is a private helper type making the Serde impls easier.
+#[derive(Clone, Debug, Deserialize)]
+struct JsonAspa {
+    /// The customer ASN member used by rpki-client.
+    customer_asid: Option<u32>,
+
+    /// The customer ASN member used by our own output.
+    customer: Option<JsonAsn>,
+
+    /// The provider ASNs member.
+    #[serde(default)]
+    providers: Vec<JsonAsn>,
+}
+
+
+//------------ JsonAsn -------------------------------------------------------
+
+/// An AS number given either as an integer or a string.
+///
+/// This is a private helper type making the Serde impls easier.
+#[derive(Clone, Copy, Debug, Deserialize)]
+struct JsonAsn(
+    #[serde(deserialize_with = "Asn::deserialize_from_any")]
+    Asn
+);
+
+
 //============ Output ========================================================
 
 //------------ OutputStream --------------------------------------------------
 
 /// A stream of JSON formatted output.
 pub struct OutputStream {
-    /// The iterator over the payload set.
-    iter: payload::OwnedSetIter,
+    /// The iterator over the route origins.
+    iter: Origins,
+
+    /// The iterator over the router keys.
+    router_keys: RouterKeys,
+
+    /// The iterator over the ASPA records.
+    aspas: Aspas,
+
+    /// The metadata to include with the output if any.
+    metadata: Option<OutputMetadata>,
 
     /// The current stream state.
     state: StreamState,
@@ -139,28 +775,119 @@ enum StreamState {
     /// We need to write more elements.
     Body,
 
+    /// We need to write the first router key next.
+    FirstKey,
+
+    /// We need to write more router keys.
+    KeyBody,
+
+    /// We need to write the first ASPA record next.
+    FirstAspa,
+
+    /// We need to write more ASPA records.
+    AspaBody,
+
     /// We are done!
     Done
 }
 
 impl OutputStream {
-    /// Creates a new output stream for the given payload set.
-    pub fn new(set: payload::Set) -> Self {
+    /// Creates a new output stream for the given payload.
+    pub fn new(
+        iter: Origins,
+        router_keys: RouterKeys,
+        aspas: Aspas,
+        metadata: Option<OutputMetadata>,
+    ) -> Self {
         OutputStream {
-            iter: set.into_owned_iter(),
+            iter,
+            router_keys,
+            aspas,
+            metadata,
             state: StreamState::Header,
         }
     }
 
-    /// Returns the next route origin in the payload set.
+    /// Returns the header of the output.
+    fn header(&self) -> Vec<u8> {
+        let metadata = match self.metadata.as_ref() {
+            Some(metadata) => metadata,
+            None => return b"{\n  \"roas\": [\n".to_vec()
+        };
+        let mut res = format!(
+            "{{\n  \"metadata\": {{\n    \
+            \"generated\": {},\n    \
+            \"buildtime\": \"{}\",\n    \
+            \"serial\": {},\n    \
+            \"counts\": {},\n    \
+            \"routerKeys\": {},\n    \
+            \"aspas\": {},\n    \
+            \"generator\": \"RTRTR {}\"",
+            metadata.generated.timestamp(),
+            metadata.generated.format("%Y-%m-%dT%H:%M:%SZ"),
+            metadata.serial,
+            metadata.origins,
+            metadata.router_keys,
+            metadata.aspas,
+            clap::crate_version!(),
+        );
+        if let Some(unit) = metadata.unit.as_ref() {
+            res.push_str(",\n    \"unit\": ");
+            // Serializing a string into a string can’t fail.
+            res.push_str(&serde_json::to_string(unit).unwrap());
+        }
+        res.push_str("\n  },\n  \"roas\": [\n");
+        res.into_bytes()
+    }
+
+    /// Returns the next route origin.
     pub fn next_origin(&mut self) -> Option<RouteOrigin> {
-        loop {
-            match self.iter.next() {
-                Some(PayloadRef::Origin(value)) => return Some(value),
-                None => return None,
-                _ => {}
+        self.iter.next()
+    }
+
+    /// Returns the output after the last item of a list.
+    ///
+    /// This either starts the next non-empty list or ends the output.
+    /// Lists already written are empty at this point.
+    fn end_list(&mut self) -> Vec<u8> {
+        if !self.router_keys.is_empty() {
+            self.state = StreamState::FirstKey;
+            b"\n  ],\n  \"routerKeys\": [\n".to_vec()
+        }
+        else if !self.aspas.is_empty() {
+            self.state = StreamState::FirstAspa;
+            b"\n  ],\n  \"aspas\": [\n".to_vec()
+        }
+        else {
+            self.state = StreamState::Done;
+            b"\n  ]\n}".to_vec()
+        }
+    }
+
+    /// Returns the JSON object for a router key.
+    fn router_key(key: &RouterKey) -> String {
+        format!(
+            "{{ \"asn\": \"{}\", \"SKI\": \"{}\", \
+            \"routerPublicKey\": \"{}\" }}",
+            key.asn,
+            URL_SAFE_NO_PAD.encode(key.key_identifier.as_slice()),
+            URL_SAFE_NO_PAD.encode(key.key_info.as_slice()),
+        )
+    }
+
+    /// Returns the JSON object for an ASPA record.
+    fn aspa(aspa: &Aspa) -> String {
+        let mut res = format!(
+            "{{ \"customer\": \"{}\", \"providers\": [", aspa.customer
+        );
+        for (idx, provider) in aspa.providers.iter().enumerate() {
+            if idx > 0 {
+                res.push_str(", ");
             }
+            res.push_str(&format!("\"{}\"", provider));
         }
+        res.push_str("] }");
+        res
     }
 }
 
@@ -171,7 +898,7 @@ impl Iterator for OutputStream {
         match self.state {
             StreamState::Header => {
                 self.state = StreamState::First;
-                Some(b"{\n  \"roas\": [\n".to_vec())
+                Some(self.header())
             }
             StreamState::First => {
                 match self.next_origin() {
@@ -185,10 +912,7 @@ impl Iterator for OutputStream {
                             payload.prefix.resolved_max_len(),
                         ).into_bytes())
                     }
-                    None => {
-                        self.state = StreamState::Done;
-                        Some(b"\n  ]\n}".to_vec())
-                    }
+                    None => Some(self.end_list())
                 }
             }
             StreamState::Body => {
@@ -203,10 +927,52 @@ impl Iterator for OutputStream {
                             payload.prefix.resolved_max_len(),
                         ).into_bytes())
                     }
-                    None => {
-                        self.state = StreamState::Done;
-                        Some(b"\n  ]\n}".to_vec())
+                    None => Some(self.end_list())
+                }
+            }
+            StreamState::FirstKey => {
+                match self.router_keys.next() {
+                    Some(key) => {
+                        self.state = StreamState::KeyBody;
+                        Some(
+                            format!("    {}", Self::router_key(&key))
+                            .into_bytes()
+                        )
+                    }
+                    None => Some(self.end_list())
+                }
+            }
+            StreamState::KeyBody => {
+                match self.router_keys.next() {
+                    Some(key) => {
+                        Some(
+                            format!(",\n    {}", Self::router_key(&key))
+                            .into_bytes()
+                        )
+                    }
+                    None => Some(self.end_list())
+                }
+            }
+            StreamState::FirstAspa => {
+                match self.aspas.next() {
+                    Some(aspa) => {
+                        self.state = StreamState::AspaBody;
+                        Some(
+                            format!("    {}", Self::aspa(&aspa)).into_bytes()
+                        )
+                    }
+                    None => Some(self.end_list())
+                }
+            }
+            StreamState::AspaBody => {
+                match self.aspas.next() {
+                    Some(aspa) => {
+                        Some(
+                            format!(",\n    {}", Self::aspa(&aspa))
+                            .into_bytes()
+                        )
                     }
+                    None => Some(self.end_list())
                 }
             }
             StreamState::Done => {
@@ -217,6 +983,60 @@ impl Iterator for OutputStream {
 }
 
 
+//------------ items_value ---------------------------------------------------
+
+/// Returns a JSON object containing the given payload items.
+///
+/// The object has the members `"routeOrigins"`, `"routerKeys"`, and
+/// `"aspas"` with lists of the respective items. Route origins and router
+/// keys are represented the same way as in the complete output, albeit
+/// without the trust anchor. ASPA records are objects with a member
+/// `"customer"` with the customer ASN and a member `"providers"` with a
+/// list of provider ASNs.
+pub fn items_value<'a>(
+    items: impl Iterator<Item = &'a Payload>
+) -> serde_json::Value {
+    let mut origins = Vec::new();
+    let mut keys = Vec::new();
+    let mut aspas = Vec::new();
+    for item in items {
+        match item {
+            Payload::Origin(origin) => {
+                origins.push(serde_json::json!({
+                    "asn": origin.asn.to_string(),
+                    "prefix": origin.prefix.prefix().to_string(),
+                    "maxLength": origin.prefix.resolved_max_len(),
+                }))
+            }
+            Payload::RouterKey(key) => {
+                keys.push(serde_json::json!({
+                    "asn": key.asn.to_string(),
+                    "SKI": URL_SAFE_NO_PAD.encode(
+                        key.key_identifier.as_slice()
+                    ),
+                    "routerPublicKey": URL_SAFE_NO_PAD.encode(
+                        key.key_info.as_slice()
+                    ),
+                }))
+            }
+            Payload::Aspa(aspa) => {
+                aspas.push(serde_json::json!({
+                    "customer": aspa.customer.to_string(),
+                    "providers": aspa.providers.iter().map(|asn| {
+                        asn.to_string()
+                    }).collect::<Vec<_>>(),
+                }))
+            }
+        }
+    }
+    serde_json::json!({
+        "routeOrigins": origins,
+        "routerKeys": keys,
+        "aspas": aspas,
+    })
+}
+
+
 //============ Testing =======================================================
 
 #[cfg(test)]
@@ -257,5 +1077,358 @@ mod test {
             include_bytes!("../../test-data/vrps.rpki-client.json")
         ).unwrap());
     }
-}
 
+    #[test]
+    fn rpki_client() {
+        let set = serde_json::from_slice::<Set>(
+            include_bytes!("../../test-data/vrps.rpki-client.json")
+        ).unwrap();
+        assert_eq!(set.bgpsec_keys.len(), 1);
+        let set = set.into_payload();
+        assert_eq!(set.len(), 3);
+        let key = set.iter().find_map(|item| match item {
+            Payload::RouterKey(key) => Some(key.clone()),
+            _ => None
+        }).unwrap();
+        assert_eq!(key.asn, 64512.into());
+        assert_eq!(
+            key.key_identifier.as_slice()[..4], [0xBE, 0x88, 0x9B, 0x55]
+        );
+
+        let set = serde_json::from_str::<Set>(r#"{
+            "roas": [],
+            "aspas": [
+                { "customer_asid": 64496, "expires": 1700000000,
+                  "providers": [64498, 64499] },
+                { "customer": "AS64497", "providers": ["AS64498"] },
+                { "providers": [64498] }
+            ]
+        }"#).unwrap().into_payload();
+        assert_eq!(set.len(), 2);
+        let mut aspas = set.iter().filter_map(|item| match item {
+            Payload::Aspa(aspa) => Some(aspa.clone()),
+            _ => None
+        }).collect::<Vec<_>>();
+        aspas.sort_by_key(|aspa| aspa.customer);
+        assert_eq!(aspas[0].customer, 64496.into());
+        assert_eq!(
+            aspas[0].providers.iter().collect::<Vec<_>>(),
+            [Asn::from(64498), Asn::from(64499)]
+        );
+        assert_eq!(aspas[1].customer, 64497.into());
+        assert_eq!(
+            aspas[1].providers.iter().collect::<Vec<_>>(),
+            [Asn::from(64498)]
+        );
+
+        assert!(serde_json::from_str::<Set>(r#"{
+            "roas": [],
+            "bgpsec_keys": [
+                { "asn": 64496, "ski": "BE8", "pubkey": "MFkw" }
+            ]
+        }"#).is_err());
+    }
+
+    #[test]
+    fn metadata() {
+        let set = serde_json::from_slice::<Set>(
+            include_bytes!("../../test-data/vrps.json")
+        ).unwrap();
+        assert!(set.metadata().is_none());
+
+        let set = serde_json::from_slice::<Set>(
+            include_bytes!("../../test-data/vrps-metadata.json")
+        ).unwrap();
+        let metadata = set.metadata().unwrap();
+        assert_eq!(metadata.count(), Some(2));
+        assert_eq!(
+            metadata.generated(),
+            Utc.timestamp_opt(1606315808, 0).single()
+        );
+        assert_eq!(metadata.serial(), None);
+
+        let set = serde_json::from_slice::<Set>(
+            include_bytes!("../../test-data/vrps.rpki-client.json")
+        ).unwrap();
+        let metadata = set.metadata().unwrap();
+        assert_eq!(metadata.count(), Some(297379));
+        assert_eq!(
+            metadata.generated(),
+            Utc.with_ymd_and_hms(2021, 11, 18, 12, 56, 53).single()
+        );
+
+        let set = serde_json::from_str::<Set>(
+            r#"{"metadata": {"counts": "many", "serial": 12}, "roas": []}"#
+        ).unwrap();
+        let metadata = set.metadata().unwrap();
+        assert_eq!(metadata.count(), None);
+        assert_eq!(metadata.serial(), Some(12));
+    }
+
+    #[test]
+    fn delta() {
+        let delta = serde_json::from_str::<Delta>(r#"{
+            "reset": true, "session": 12, "serial": 4,
+            "announced": [
+                { "asn": "AS64496", "prefix": "192.0.2.0/24",
+                  "maxLength": 24 },
+                { "asn": "AS64497", "prefix": "198.51.100.0/24",
+                  "maxLength": 24 }
+            ]
+        }"#).unwrap();
+        assert!(delta.is_reset());
+        assert_eq!(delta.session(), 12);
+        assert_eq!(delta.serial(), 4);
+        let set = delta.into_set();
+        assert_eq!(set.len(), 2);
+
+        let delta = serde_json::from_str::<Delta>(r#"{
+            "session": 12, "serial": 5,
+            "announced": { "routeOrigins": [
+                { "asn": "AS64498", "prefix": "203.0.113.0/24",
+                  "maxLength": 24 }
+            ], "routerKeys": [] },
+            "withdrawn": { "routeOrigins": [
+                { "asn": "AS64496", "prefix": "192.0.2.0/24",
+                  "maxLength": 24 }
+            ] }
+        }"#).unwrap();
+        assert!(!delta.is_reset());
+        let diff = delta.into_diff().unwrap();
+        assert_eq!(diff.len(), 2);
+        let set = diff.apply(&set).unwrap();
+        assert_eq!(set.len(), 2);
+
+        let delta = serde_json::from_str::<Delta>(r#"{
+            "session": 12, "serial": 6,
+            "announced": [
+                { "asn": "AS64496", "prefix": "192.0.2.0/24",
+                  "maxLength": 24 }
+            ],
+            "withdrawn": [
+                { "asn": "AS64496", "prefix": "192.0.2.0/24",
+                  "maxLength": 24 }
+            ]
+        }"#).unwrap();
+        assert!(delta.into_diff().is_err());
+    }
+
+    #[test]
+    fn trust_anchors() {
+        let set = serde_json::from_slice::<Set>(
+            include_bytes!("../../test-data/vrps.json")
+        ).unwrap();
+        let tas = set.trust_anchors().unwrap();
+        assert_eq!(tas.counts(&set.into_payload()), [("ta", 2)]);
+
+        let set = serde_json::from_str::<Set>(
+            r#"{"roas": []}"#
+        ).unwrap();
+        assert!(set.trust_anchors().is_none());
+
+        let set = serde_json::from_str::<Set>(r#"{"roas": [
+            { "asn": "AS64496", "prefix": "192.0.2.0/24", "maxLength": 24,
+              "ta": "ripe" },
+            { "asn": "AS64497", "prefix": "198.51.100.0/24",
+              "maxLength": 24, "ta": "N/A" },
+            { "asn": "AS64498", "prefix": "203.0.113.0/24",
+              "maxLength": 24 }
+        ]}"#).unwrap();
+        let tas = set.trust_anchors().unwrap();
+        let set = set.into_payload();
+        assert_eq!(tas.counts(&set), [("ripe", 1)]);
+    }
+
+    #[test]
+    fn check_schema() {
+        let set = serde_json::from_slice::<Set>(
+            include_bytes!("../../test-data/vrps-metadata.json")
+        ).unwrap();
+        assert!(set.check_schema().is_ok());
+
+        let set = serde_json::from_slice::<Set>(
+            include_bytes!("../../test-data/vrps.json")
+        ).unwrap();
+        assert!(set.check_schema().is_ok());
+
+        let set = serde_json::from_str::<Set>(
+            r#"{"metadata": {"counts": "many"}, "roas": []}"#
+        ).unwrap();
+        assert_eq!(
+            set.check_schema().unwrap_err(),
+            "metadata member 'counts' has an unexpected type"
+        );
+
+        let set = serde_json::from_str::<Set>(
+            r#"{"metadata": {"vrps": 3}, "roas": []}"#
+        ).unwrap();
+        assert_eq!(
+            set.check_schema().unwrap_err(),
+            "metadata reports 3 VRPs but data set contains 0"
+        );
+    }
+
+    #[test]
+    fn output_metadata() {
+        let set = serde_json::from_slice::<Set>(
+            include_bytes!("../../test-data/vrps.json")
+        ).unwrap().into_payload();
+        let mut update = payload::Update::new(set.clone());
+        update.record_step("json-unit".into());
+        let generated = Utc.timestamp_opt(1606315808, 0).unwrap();
+        let metadata = OutputMetadata {
+            generated, ..OutputMetadata::new(&update, 12)
+        };
+        let output = OutputStream::new(
+            Origins::new(set.clone(), Default::default()),
+            RouterKeys::new(&set), Aspas::new(&set), Some(metadata)
+        ).flatten().collect::<Vec<_>>();
+
+        let parsed = serde_json::from_slice::<Set>(&output).unwrap();
+        assert_eq!(parsed.len(), 2);
+        let metadata = parsed.metadata().unwrap();
+        assert_eq!(metadata.count(), Some(2));
+        assert_eq!(metadata.serial(), Some(12));
+        assert_eq!(metadata.generated(), Some(generated));
+        assert_eq!(parsed.into_payload(), set);
+
+        let value = serde_json::from_slice::<serde_json::Value>(
+            &output
+        ).unwrap();
+        assert_eq!(value["metadata"]["unit"], "json-unit");
+        assert_eq!(
+            value["metadata"]["buildtime"], "2020-11-25T14:50:08Z"
+        );
+
+        let output = OutputStream::new(
+            Origins::new(set.clone(), Default::default()),
+            RouterKeys::new(&set), Aspas::new(&set), None
+        ).flatten().collect::<Vec<_>>();
+        let parsed = serde_json::from_slice::<Set>(&output).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert!(parsed.metadata().is_none());
+        assert_eq!(parsed.into_payload(), set);
+    }
+
+    #[test]
+    fn output_router_keys() {
+        let set = payload::Set::from(payload::testrig::slurm_pack(
+            include_bytes!("../../test-data/router-keys.slurm.json")
+        ));
+        let output = OutputStream::new(
+            Origins::new(set.clone(), Default::default()),
+            RouterKeys::new(&set), Aspas::new(&set), None
+        ).flatten().collect::<Vec<_>>();
+
+        let parsed = serde_json::from_slice::<Set>(&output).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed.into_payload(), set);
+
+        let value = serde_json::from_slice::<serde_json::Value>(
+            &output
+        ).unwrap();
+        let keys = value["routerKeys"].as_array().unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0]["asn"], "AS64496");
+        assert_eq!(keys[0]["SKI"], "cDqQSTxtNciD6jhyofuAaaO7HkM");
+        assert_eq!(
+            keys[0]["routerPublicKey"],
+            "MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEmowgZo47IqDJWso5xRLIhl5K4rj\
+             frtujXNxB2BXhrqDyFVXN7Oxyx5tGZRMqlVVdzlMvhq-XLv0TYHU4WB3x0A"
+        );
+        assert_eq!(keys[1]["asn"], "AS64497");
+        assert_eq!(keys[1]["SKI"], "0aIpsAt4lSx3nfZTY0OBREGDkK4");
+
+        // Without router keys, there is no member for them.
+        let set = set.filter(|item| matches!(item, Payload::Origin(_)));
+        let output = OutputStream::new(
+            Origins::new(set.clone(), Default::default()),
+            RouterKeys::new(&set), Aspas::new(&set), None
+        ).flatten().collect::<Vec<_>>();
+        let value = serde_json::from_slice::<serde_json::Value>(
+            &output
+        ).unwrap();
+        assert!(value.get("routerKeys").is_none());
+        assert_eq!(value["roas"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn output_aspas() {
+        use rpki::rtr::pdu::ProviderAsns;
+
+        let mut builder = payload::PackBuilder::empty();
+        builder.insert(Payload::Aspa(Aspa::new(
+            64496.into(),
+            ProviderAsns::try_from_iter(
+                [64498, 64499].into_iter().map(Asn::from)
+            ).unwrap()
+        ))).unwrap();
+        let set = payload::Set::from(builder.finalize());
+        let output = OutputStream::new(
+            Origins::new(set.clone(), Default::default()),
+            RouterKeys::new(&set), Aspas::new(&set), None
+        ).flatten().collect::<Vec<_>>();
+
+        let value = serde_json::from_slice::<serde_json::Value>(
+            &output
+        ).unwrap();
+        assert!(value.get("routerKeys").is_none());
+        assert_eq!(value["roas"].as_array().unwrap().len(), 0);
+        assert_eq!(
+            value["aspas"],
+            serde_json::json!([
+                { "customer": "AS64496", "providers": ["AS64498", "AS64499"] }
+            ])
+        );
+
+        // The ASPA record survives the round trip.
+        let parsed = serde_json::from_slice::<Set>(&output).unwrap();
+        assert_eq!(parsed.into_payload(), set);
+    }
+
+    #[test]
+    fn items() {
+        let set = payload::Set::from(payload::testrig::slurm_pack(
+            include_bytes!("../../test-data/router-keys.slurm.json")
+        ));
+        let value = items_value(set.iter());
+        assert_eq!(value["routeOrigins"].as_array().unwrap().len(), 2);
+        assert!(value["aspas"].as_array().unwrap().is_empty());
+        let keys = value["routerKeys"].as_array().unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0]["asn"], "AS64496");
+        assert_eq!(keys[0]["SKI"], "cDqQSTxtNciD6jhyofuAaaO7HkM");
+
+        let value = items_value(std::iter::empty());
+        assert!(value["routeOrigins"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn items_round_trip() {
+        let set = payload::Set::from(payload::testrig::slurm_pack(
+            include_bytes!("../../test-data/router-keys.slurm.json")
+        ));
+        let items: Items = serde_json::from_value(
+            items_value(set.iter())
+        ).unwrap();
+        assert_eq!(items.aspa_count(), 0);
+        let mut res = payload::PackBuilder::empty();
+        for item in items.into_payload() {
+            res.insert(item).unwrap();
+        }
+        assert_eq!(payload::Set::from(res.finalize()), set);
+
+        let items: Items = serde_json::from_str(r#"{
+            "aspas": [ { "customer": "AS64496", "providers": [] } ]
+        }"#).unwrap();
+        assert_eq!(items.aspa_count(), 1);
+        assert!(items.into_payload().is_empty());
+
+        assert!(serde_json::from_str::<Items>(r#"{
+            "routerKeys": [
+                { "asn": "AS64496", "SKI": "invalid",
+                  "routerPublicKey": "invalid" }
+            ]
+        }"#).is_err());
+    }
+}
@@ -1,6 +1,11 @@
 //! Serialization formats for payload data.
 
 pub mod output;
+pub mod bgpd;
+pub mod bird;
+pub mod cbor;
+pub mod csv;
 pub mod json;
+pub mod slurm;
 
 
@@ -1,10 +1,16 @@
 //! All supported output formats.
 
 
+use std::vec;
+use chrono::{DateTime, Utc};
+use rpki::rtr::payload::{
+    Aspa, Payload, PayloadRef, RouteOrigin, RouterKey
+};
+use rpki::rtr::server::PayloadSet;
 use serde::Deserialize;
 use crate::payload;
 use crate::http::ContentType;
-use super::json;
+use super::{cbor, csv, json, slurm};
 
 //------------ Format --------------------------------------------------------
 
@@ -14,17 +20,246 @@ use super::json;
 pub enum Format {
     #[serde(rename = "json")]
     Json,
+
+    #[serde(rename = "csv")]
+    Csv,
+
+    #[serde(rename = "slurm")]
+    Slurm,
+
+    #[serde(rename = "cbor")]
+    Cbor,
 }
 
 impl Format {
     pub fn content_type(self) -> ContentType {
         match self {
             Format::Json => ContentType::JSON,
+            Format::Csv => ContentType::CSV,
+            Format::Slurm => ContentType::JSON,
+            Format::Cbor => ContentType::CBOR,
         }
     }
 
-    pub fn stream(self, set: payload::Set) -> Stream {
-        Stream::new(self, set)
+    /// Returns whether the format always includes metadata.
+    ///
+    /// For these formats, metadata should be provided to
+    /// [`stream`](Self::stream) even if it wasn’t requested.
+    pub fn has_metadata(self) -> bool {
+        matches!(self, Format::Cbor)
+    }
+
+    /// Returns a stream of the data set in this format.
+    ///
+    /// If `metadata` is given, the output is wrapped with it. Formats that
+    /// cannot represent metadata ignore it.
+    pub fn stream(
+        self, set: payload::Set, order: Order, metadata: Option<Metadata>,
+    ) -> Stream {
+        Stream::new(self, set, order, metadata)
+    }
+}
+
+
+//------------ Order ---------------------------------------------------------
+
+/// The order in which items appear in the output.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+pub enum Order {
+    /// Order by prefix.
+    ///
+    /// This is the order the items are kept in a payload set, so no extra
+    /// work is necessary.
+    #[default]
+    #[serde(rename = "prefix")]
+    Prefix,
+
+    /// Order by AS number and then by prefix.
+    #[serde(rename = "asn")]
+    Asn,
+}
+
+
+//------------ Include -------------------------------------------------------
+
+/// The types of payload to include in the output.
+///
+/// This is given as a list of the type names `"roas"`, `"router-keys"`,
+/// and `"aspas"`. By default, all types are included.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(try_from = "Vec<String>")]
+pub struct Include {
+    /// Include route origins?
+    pub origins: bool,
+
+    /// Include router keys?
+    pub router_keys: bool,
+
+    /// Include ASPA records?
+    pub aspas: bool,
+}
+
+impl Include {
+    /// Creates a value that includes nothing.
+    fn none() -> Self {
+        Include { origins: false, router_keys: false, aspas: false }
+    }
+
+    /// Returns whether all types are included.
+    pub fn is_all(self) -> bool {
+        self.origins && self.router_keys && self.aspas
+    }
+
+    /// Adds a type given by its name.
+    ///
+    /// Both `"router-keys"` and `"routerkeys"` are accepted for router keys.
+    fn add(&mut self, name: &str) -> Result<(), String> {
+        match name {
+            "roas" => self.origins = true,
+            "router-keys" | "routerkeys" => self.router_keys = true,
+            "aspas" => self.aspas = true,
+            _ => return Err(format!("unknown payload type '{}'", name))
+        }
+        Ok(())
+    }
+
+    /// Creates a value from the query string of a URI.
+    ///
+    /// The types are given via the `include` parameter as a comma
+    /// separated list. The parameter may appear multiple times. Other
+    /// parameters are ignored. Returns `Ok(None)` if there is no `include`
+    /// parameter and an error message if it contains an unknown type.
+    pub fn from_query(query: Option<&str>) -> Result<Option<Self>, String> {
+        let query = match query {
+            Some(query) => query,
+            None => return Ok(None)
+        };
+        let mut res = None;
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            if key != "include" {
+                continue
+            }
+            let include = res.get_or_insert_with(Include::none);
+            for name in value.split(',') {
+                let name = name.trim();
+                if !name.is_empty() {
+                    include.add(name)?;
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Returns whether the given item is included.
+    pub fn contains(self, payload: &Payload) -> bool {
+        match payload {
+            Payload::Origin(_) => self.origins,
+            Payload::RouterKey(_) => self.router_keys,
+            Payload::Aspa(_) => self.aspas,
+        }
+    }
+
+    /// Returns a set with only the included items.
+    pub fn apply(self, set: &payload::Set) -> payload::Set {
+        if self.is_all() {
+            set.clone()
+        }
+        else {
+            set.filter(|payload| self.contains(payload))
+        }
+    }
+}
+
+impl Default for Include {
+    fn default() -> Self {
+        Include { origins: true, router_keys: true, aspas: true }
+    }
+}
+
+impl TryFrom<Vec<String>> for Include {
+    type Error = String;
+
+    fn try_from(names: Vec<String>) -> Result<Self, Self::Error> {
+        let mut res = Include::none();
+        for name in names {
+            res.add(&name)?;
+        }
+        Ok(res)
+    }
+}
+
+
+//------------ Metadata ------------------------------------------------------
+
+/// Information about a data set to be included in the output.
+///
+/// This mimics the metadata produced by other relying party software and
+/// RTR caches so that tooling relying on it keeps working.
+#[derive(Clone, Debug)]
+pub struct Metadata {
+    /// The time the data set was generated.
+    pub generated: DateTime<Utc>,
+
+    /// The session ID of the data set if known.
+    pub session: Option<u16>,
+
+    /// The serial number of the data set.
+    pub serial: u32,
+
+    /// The name of the unit the data set was received from.
+    pub unit: Option<String>,
+
+    /// The number of route origins in the data set.
+    pub origins: usize,
+
+    /// The number of router keys in the data set.
+    pub router_keys: usize,
+
+    /// The number of ASPA records in the data set.
+    pub aspas: usize,
+}
+
+impl Metadata {
+    /// Creates the metadata for an update and a serial number.
+    pub fn new(update: &payload::Update, serial: u32) -> Self {
+        let mut res = Metadata {
+            generated: Utc::now(),
+            session: None,
+            serial,
+            unit: update.provenance().steps().first().map(|step| {
+                step.component().into()
+            }),
+            origins: 0,
+            router_keys: 0,
+            aspas: 0,
+        };
+        for payload in update.set().iter() {
+            match payload {
+                Payload::Origin(_) => res.origins += 1,
+                Payload::RouterKey(_) => res.router_keys += 1,
+                Payload::Aspa(_) => res.aspas += 1,
+            }
+        }
+        res
+    }
+
+    /// Adds the session ID of the data set.
+    pub fn with_session(mut self, session: u16) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// Returns the metadata for only the included types of payload.
+    pub fn restrict(&self, include: Include) -> Self {
+        let count = |included: bool, count: usize| {
+            if included { count } else { 0 }
+        };
+        Metadata {
+            origins: count(include.origins, self.origins),
+            router_keys: count(include.router_keys, self.router_keys),
+            aspas: count(include.aspas, self.aspas),
+            ..self.clone()
+        }
     }
 }
 
@@ -36,15 +271,50 @@ pub struct Stream(StreamInner);
 
 enum StreamInner {
     Json(json::OutputStream),
+    Csv(csv::OutputStream),
+    Slurm(slurm::OutputStream),
+    Cbor(cbor::OutputStream),
 }
 
 impl Stream {
     /// Creates a new output stream from a format and a data set.
-    fn new(format: Format, set: payload::Set) -> Self {
+    fn new(
+        format: Format, set: payload::Set, order: Order,
+        metadata: Option<Metadata>,
+    ) -> Self {
         Stream(match format {
-            Format::Json => StreamInner::Json(json::OutputStream::new(set)),
+            Format::Json => {
+                let router_keys = RouterKeys::new(&set);
+                let aspas = Aspas::new(&set);
+                StreamInner::Json(json::OutputStream::new(
+                    Origins::new(set, order), router_keys, aspas, metadata
+                ))
+            }
+            Format::Csv => {
+                StreamInner::Csv(csv::OutputStream::new(
+                    Origins::new(set, order)
+                ))
+            }
+            Format::Slurm => {
+                let router_keys = RouterKeys::new(&set);
+                StreamInner::Slurm(slurm::OutputStream::new(
+                    Origins::new(set, order), router_keys
+                ))
+            }
+            Format::Cbor => {
+                let router_keys = RouterKeys::new(&set);
+                let aspas = Aspas::new(&set);
+                StreamInner::Cbor(cbor::OutputStream::new(
+                    Origins::new(set, order), router_keys, aspas, metadata
+                ))
+            }
         })
     }
+
+    /// Combines the output into chunks of about the given size.
+    pub fn chunks(self, size: usize) -> Chunks {
+        Chunks { stream: self, size }
+    }
 }
 
 impl Iterator for Stream {
@@ -52,8 +322,269 @@ impl Iterator for Stream {
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.0 {
-            StreamInner::Json(ref mut inner) => inner.next()
+            StreamInner::Json(ref mut inner) => inner.next(),
+            StreamInner::Csv(ref mut inner) => inner.next(),
+            StreamInner::Slurm(ref mut inner) => inner.next(),
+            StreamInner::Cbor(ref mut inner) => inner.next(),
+        }
+    }
+}
+
+
+//------------ Chunks --------------------------------------------------------
+
+/// The output of a stream combined into chunks.
+///
+/// Each chunk but the last one is at least the given size and exceeds it
+/// by less than the size of a single item of the underlying stream. This
+/// keeps the number of chunks low without having to assemble the entire
+/// output in memory.
+pub struct Chunks {
+    /// The underlying stream.
+    stream: Stream,
+
+    /// The minimum size of a chunk.
+    size: usize,
+}
+
+impl Iterator for Chunks {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut res = self.stream.next()?;
+        res.reserve(self.size.saturating_sub(res.len()));
+        while res.len() < self.size {
+            match self.stream.next() {
+                Some(item) => res.extend_from_slice(&item),
+                None => break
+            }
+        }
+        Some(res)
+    }
+}
+
+
+//------------ Origins -------------------------------------------------------
+
+/// An iterator over the route origins of a data set in output order.
+pub struct Origins(OriginsInner);
+
+enum OriginsInner {
+    /// Iterate over the set directly.
+    Set(payload::OwnedSetIter),
+
+    /// Iterate over a sorted copy of the route origins.
+    Sorted(vec::IntoIter<RouteOrigin>),
+}
+
+impl Origins {
+    /// Creates a new iterator over the route origins in the given order.
+    ///
+    /// Unless the requested order is that of the set, this builds a
+    /// temporary index of all route origins.
+    pub fn new(set: payload::Set, order: Order) -> Self {
+        Origins(match order {
+            Order::Prefix => OriginsInner::Set(set.into_owned_iter()),
+            Order::Asn => {
+                let mut index = set.iter().filter_map(|payload| {
+                    match payload {
+                        Payload::Origin(origin) => Some(*origin),
+                        _ => None,
+                    }
+                }).collect::<Vec<_>>();
+                index.sort_unstable_by_key(|origin| {
+                    (origin.asn, origin.prefix)
+                });
+                OriginsInner::Sorted(index.into_iter())
+            }
+        })
+    }
+}
+
+impl Iterator for Origins {
+    type Item = RouteOrigin;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.0 {
+            OriginsInner::Set(ref mut iter) => {
+                loop {
+                    if let PayloadRef::Origin(origin) = iter.next()? {
+                        return Some(origin)
+                    }
+                }
+            }
+            OriginsInner::Sorted(ref mut iter) => iter.next(),
         }
     }
 }
 
+
+//------------ RouterKeys ----------------------------------------------------
+
+/// An iterator over the router keys of a data set.
+///
+/// Because there usually are only a few router keys, the iterator simply
+/// keeps a copy of all of them.
+pub struct RouterKeys(vec::IntoIter<RouterKey>);
+
+impl RouterKeys {
+    /// Creates a new iterator over the router keys in the given set.
+    pub fn new(set: &payload::Set) -> Self {
+        RouterKeys(
+            set.iter().filter_map(|payload| {
+                match payload {
+                    Payload::RouterKey(key) => Some(key.clone()),
+                    _ => None,
+                }
+            }).collect::<Vec<_>>().into_iter()
+        )
+    }
+
+    /// Returns whether there are no more router keys.
+    pub fn is_empty(&self) -> bool {
+        self.0.len() == 0
+    }
+}
+
+impl Iterator for RouterKeys {
+    type Item = RouterKey;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
+
+//------------ Aspas ---------------------------------------------------------
+
+/// An iterator over the ASPA records of a data set.
+///
+/// As with router keys, the iterator simply keeps a copy of all of them.
+pub struct Aspas(vec::IntoIter<Aspa>);
+
+impl Aspas {
+    /// Creates a new iterator over the ASPA records in the given set.
+    pub fn new(set: &payload::Set) -> Self {
+        Aspas(
+            set.iter().filter_map(|payload| {
+                match payload {
+                    Payload::Aspa(aspa) => Some(aspa.clone()),
+                    _ => None,
+                }
+            }).collect::<Vec<_>>().into_iter()
+        )
+    }
+
+    /// Returns whether there are no more ASPA records.
+    pub fn is_empty(&self) -> bool {
+        self.0.len() == 0
+    }
+}
+
+impl Iterator for Aspas {
+    type Item = Aspa;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use rpki::resources::addr::{MaxLenPrefix, Prefix};
+
+    fn origin(addr: [u8; 4], asn: u32) -> Payload {
+        Payload::origin(
+            MaxLenPrefix::new(
+                Prefix::new_v4(Ipv4Addr::from(addr), 24).unwrap(), None
+            ).unwrap(),
+            asn.into()
+        )
+    }
+
+    fn include(origins: bool, router_keys: bool, aspas: bool) -> Include {
+        Include { origins, router_keys, aspas }
+    }
+
+    #[test]
+    fn include_types() {
+        assert_eq!(Include::from_query(None), Ok(None));
+        assert_eq!(Include::from_query(Some("order=asn")), Ok(None));
+        assert_eq!(
+            Include::from_query(Some("include=roas,routerkeys")),
+            Ok(Some(include(true, true, false)))
+        );
+        assert_eq!(
+            Include::from_query(Some("include=aspas&include=roas")),
+            Ok(Some(include(true, false, true)))
+        );
+        assert_eq!(
+            Include::from_query(Some("include=")),
+            Ok(Some(Include::none()))
+        );
+        assert!(Include::from_query(Some("include=vrps")).is_err());
+
+        assert_eq!(
+            Include::try_from(vec!["router-keys".to_string()]),
+            Ok(include(false, true, false))
+        );
+
+        let mut builder = payload::PackBuilder::empty();
+        builder.insert(origin([192, 0, 2, 0], 64497)).unwrap();
+        builder.insert(origin([198, 51, 100, 0], 64496)).unwrap();
+        let set = payload::Set::from(builder.finalize());
+        assert_eq!(Include::default().apply(&set).len(), 2);
+        assert!(include(false, true, true).apply(&set).is_empty());
+    }
+
+    #[test]
+    fn chunks() {
+        let mut builder = payload::PackBuilder::empty();
+        for i in 0..200 {
+            builder.insert(origin([10, 0, i, 0], 64496)).unwrap();
+        }
+        let set = payload::Set::from(builder.finalize());
+        let stream = || {
+            Format::Json.stream(set.clone(), Order::Prefix, None)
+        };
+
+        let chunks = stream().chunks(1024).collect::<Vec<_>>();
+        assert!(chunks.len() > 1);
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!(chunk.len() >= 1024);
+            assert!(chunk.len() < 1200);
+        }
+        assert_eq!(chunks.concat(), stream().flatten().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn origins_order() {
+        let mut builder = payload::PackBuilder::empty();
+        builder.insert(origin([192, 0, 2, 0], 64497)).unwrap();
+        builder.insert(origin([198, 51, 100, 0], 64496)).unwrap();
+        builder.insert(origin([203, 0, 113, 0], 64497)).unwrap();
+        let set = payload::Set::from(builder.finalize());
+
+        let by_prefix = Origins::new(set.clone(), Order::Prefix).map(|o| {
+            o.asn.into_u32()
+        }).collect::<Vec<_>>();
+        assert_eq!(by_prefix, [64497, 64496, 64497]);
+
+        let by_asn = Origins::new(set, Order::Asn).map(|o| {
+            o.prefix.addr()
+        }).collect::<Vec<_>>();
+        assert_eq!(
+            by_asn,
+            [
+                Ipv4Addr::from([198, 51, 100, 0]),
+                Ipv4Addr::from([192, 0, 2, 0]),
+                Ipv4Addr::from([203, 0, 113, 0]),
+            ]
+        );
+    }
+}
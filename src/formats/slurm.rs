@@ -0,0 +1,1017 @@
+//! SLURM files for validated RPKI data.
+//!
+//! When reading SLURM files, their structure can be checked via
+//! [`validate`] which reports all problems with the JSON path of the
+//! offending value. Besides version 1 as defined in RFC 8416, version 2
+//! with the additional members `"aspaFilters"` and `"aspaAssertions"` is
+//! understood. Since the SLURM parser of the `rpki` crate only knows
+//! version 1, the ASPA rules are taken out of a file via [`AspaRules`]
+//! before handing it over.
+//!
+//! When creating SLURM, a version 1 file is produced that adds the data set
+//! as local assertions. Route origins are given as prefix assertions and
+//! router keys as BGPsec assertions. The filters are always empty. ASPA
+//! records are left out.
+
+use std::{error, fmt};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::DateTime;
+use rpki::resources::addr::Prefix;
+use rpki::resources::asn::Asn;
+use rpki::rtr::payload::{Aspa, RouteOrigin, RouterKey};
+use rpki::rtr::pdu::ProviderAsns;
+use serde::Deserialize;
+use super::output::{Origins, RouterKeys};
+
+
+//============ Input =========================================================
+
+//------------ Version -------------------------------------------------------
+
+/// The version of a SLURM file.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(try_from = "u8")]
+pub enum Version {
+    /// Version 1 as defined in RFC 8416.
+    #[default]
+    V1,
+
+    /// Version 2 which adds ASPA filters and assertions.
+    V2,
+}
+
+impl Version {
+    /// Returns the version number.
+    pub fn number(self) -> u8 {
+        match self {
+            Version::V1 => 1,
+            Version::V2 => 2,
+        }
+    }
+}
+
+impl TryFrom<u8> for Version {
+    type Error = String;
+
+    fn try_from(version: u8) -> Result<Self, Self::Error> {
+        match version {
+            1 => Ok(Version::V1),
+            2 => Ok(Version::V2),
+            _ => Err(format!("unsupported SLURM version {}", version))
+        }
+    }
+}
+
+
+//------------ validate ------------------------------------------------------
+
+/// Checks the structure of a SLURM file.
+///
+/// If `expected` is given, the file has to be of this version. Otherwise
+/// the version is taken from the file’s `"slurmVersion"` member.
+///
+/// Returns the version of the file or all the problems found.
+pub fn validate(
+    slurm: &serde_json::Value, expected: Option<Version>
+) -> Result<Version, ValidationErrors> {
+    let mut validator = Validator::default();
+    let version = validator.root(slurm, expected);
+    if validator.errors.is_empty() {
+        Ok(version)
+    }
+    else {
+        Err(ValidationErrors(validator.errors))
+    }
+}
+
+
+//------------ Validator -----------------------------------------------------
+
+/// The kind of value expected for a member of a SLURM item.
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    /// An AS number as an integer.
+    Asn,
+
+    /// An IP prefix in slash notation.
+    Prefix,
+
+    /// A prefix length.
+    MaxLength,
+
+    /// A subject key identifier in Base64.
+    Ski,
+
+    /// Some other data in Base64.
+    Base64,
+
+    /// A time in RFC 3339 format.
+    Time,
+
+    /// A list of AS numbers.
+    Providers,
+
+    /// Any string.
+    Text,
+}
+
+/// Collects the problems found in a SLURM file.
+#[derive(Debug, Default)]
+struct Validator {
+    /// The problems found so far.
+    errors: Vec<ValidationError>,
+}
+
+impl Validator {
+    /// Records a problem.
+    fn error(&mut self, path: &str, message: impl Into<String>) {
+        self.errors.push(ValidationError {
+            path: path.into(),
+            message: message.into(),
+        })
+    }
+
+    /// Checks the top-level object and returns the version to assume.
+    fn root(
+        &mut self, slurm: &serde_json::Value, expected: Option<Version>
+    ) -> Version {
+        let fallback = expected.unwrap_or(Version::V1);
+        let obj = match slurm.as_object() {
+            Some(obj) => obj,
+            None => {
+                self.error("$", "expected an object");
+                return fallback
+            }
+        };
+        let version = match obj.get("slurmVersion") {
+            Some(version) => match version.as_u64() {
+                Some(number) => {
+                    match u8::try_from(number).ok().and_then(|number| {
+                        Version::try_from(number).ok()
+                    }) {
+                        Some(version) => version,
+                        None => {
+                            self.error(
+                                "$.slurmVersion",
+                                format!(
+                                    "unsupported SLURM version {}", number
+                                )
+                            );
+                            fallback
+                        }
+                    }
+                }
+                None => {
+                    self.error("$.slurmVersion", "expected an integer");
+                    fallback
+                }
+            },
+            None => {
+                self.error("$", "missing member 'slurmVersion'");
+                fallback
+            }
+        };
+        if let Some(expected) = expected {
+            if version != expected {
+                self.error(
+                    "$.slurmVersion",
+                    format!(
+                        "expected SLURM version {}", expected.number()
+                    )
+                );
+            }
+        }
+        let v2 = version == Version::V2;
+        self.members(
+            "$", obj,
+            &["slurmVersion", "validationOutputFilters",
+              "locallyAddedAssertions"]
+        );
+        self.section(
+            "$", obj, "validationOutputFilters",
+            &[
+                ("prefixFilters", true), ("bgpsecFilters", true),
+                ("aspaFilters", v2),
+            ],
+            version,
+        );
+        self.section(
+            "$", obj, "locallyAddedAssertions",
+            &[
+                ("prefixAssertions", true), ("bgpsecAssertions", true),
+                ("aspaAssertions", v2),
+            ],
+            version,
+        );
+        version
+    }
+
+    /// Checks that an object only has the given members.
+    fn members(
+        &mut self,
+        path: &str,
+        obj: &serde_json::Map<String, serde_json::Value>,
+        allowed: &[&str],
+    ) {
+        for key in obj.keys() {
+            if !allowed.contains(&key.as_str()) {
+                self.error(
+                    &format!("{}.{}", path, key), "unexpected member"
+                );
+            }
+        }
+    }
+
+    /// Checks a section containing lists of items.
+    ///
+    /// The lists are given with whether they are allowed in this version.
+    /// Allowed lists are also required.
+    fn section(
+        &mut self,
+        path: &str,
+        obj: &serde_json::Map<String, serde_json::Value>,
+        key: &str,
+        lists: &[(&str, bool)],
+        version: Version,
+    ) {
+        let path = format!("{}.{}", path, key);
+        let obj = match obj.get(key) {
+            Some(value) => match value.as_object() {
+                Some(obj) => obj,
+                None => {
+                    self.error(&path, "expected an object");
+                    return
+                }
+            },
+            None => {
+                self.error(&path, "missing member");
+                return
+            }
+        };
+        for (key, value) in obj {
+            let item_path = format!("{}.{}", path, key);
+            match lists.iter().find(|(list, _)| *list == key.as_str()) {
+                Some((_, true)) => { }
+                Some((_, false)) => {
+                    self.error(
+                        &item_path,
+                        format!(
+                            "not allowed in SLURM version {}",
+                            version.number()
+                        )
+                    );
+                    continue
+                }
+                None => {
+                    self.error(&item_path, "unexpected member");
+                    continue
+                }
+            }
+            let list = match value.as_array() {
+                Some(list) => list,
+                None => {
+                    self.error(&item_path, "expected a list");
+                    continue
+                }
+            };
+            for (idx, item) in list.iter().enumerate() {
+                self.item(&format!("{}[{}]", item_path, idx), key, item);
+            }
+        }
+        for (key, allowed) in lists {
+            if *allowed && !obj.contains_key(*key) {
+                self.error(&format!("{}.{}", path, key), "missing member");
+            }
+        }
+    }
+
+    /// Checks an item of the given list.
+    fn item(&mut self, path: &str, list: &str, item: &serde_json::Value) {
+        let obj = match item.as_object() {
+            Some(obj) => obj,
+            None => {
+                self.error(path, "expected an object");
+                return
+            }
+        };
+        match list {
+            "prefixFilters" => {
+                self.item_members(
+                    path, obj, &[],
+                    &[
+                        ("prefix", Kind::Prefix), ("asn", Kind::Asn),
+                        ("comment", Kind::Text),
+                    ]
+                );
+                self.one_of(path, obj, &["prefix", "asn"]);
+            }
+            "bgpsecFilters" => {
+                self.item_members(
+                    path, obj, &[],
+                    &[
+                        ("asn", Kind::Asn), ("SKI", Kind::Ski),
+                        ("comment", Kind::Text),
+                    ]
+                );
+                self.one_of(path, obj, &["asn", "SKI"]);
+            }
+            "aspaFilters" => {
+                self.item_members(
+                    path, obj, &[("customerAsid", Kind::Asn)],
+                    &[("comment", Kind::Text)]
+                );
+            }
+            "prefixAssertions" => {
+                self.item_members(
+                    path, obj,
+                    &[("prefix", Kind::Prefix), ("asn", Kind::Asn)],
+                    &[
+                        ("maxPrefixLength", Kind::MaxLength),
+                        ("comment", Kind::Text),
+                        ("notBefore", Kind::Time), ("notAfter", Kind::Time),
+                    ]
+                );
+                self.max_length(path, obj);
+            }
+            "bgpsecAssertions" => {
+                self.item_members(
+                    path, obj,
+                    &[
+                        ("asn", Kind::Asn), ("SKI", Kind::Ski),
+                        ("routerPublicKey", Kind::Base64),
+                    ],
+                    &[
+                        ("comment", Kind::Text),
+                        ("notBefore", Kind::Time), ("notAfter", Kind::Time),
+                    ]
+                );
+            }
+            "aspaAssertions" => {
+                self.item_members(
+                    path, obj,
+                    &[
+                        ("customerAsid", Kind::Asn),
+                        ("providerSet", Kind::Providers),
+                    ],
+                    &[("comment", Kind::Text)]
+                );
+                self.providers(path, obj);
+            }
+            _ => { }
+        }
+    }
+
+    /// Checks the members of an item.
+    fn item_members(
+        &mut self,
+        path: &str,
+        obj: &serde_json::Map<String, serde_json::Value>,
+        required: &[(&str, Kind)],
+        optional: &[(&str, Kind)],
+    ) {
+        for (key, value) in obj {
+            let member_path = format!("{}.{}", path, key);
+            match required.iter().chain(optional).find(|(name, _)| {
+                *name == key.as_str()
+            }) {
+                Some((_, kind)) => {
+                    if let Err(err) = Self::value(*kind, value) {
+                        self.error(&member_path, err);
+                    }
+                }
+                None => self.error(&member_path, "unexpected member"),
+            }
+        }
+        for (key, _) in required {
+            if !obj.contains_key(*key) {
+                self.error(path, format!("missing member '{}'", key));
+            }
+        }
+    }
+
+    /// Checks that at least one of the given members is present.
+    fn one_of(
+        &mut self,
+        path: &str,
+        obj: &serde_json::Map<String, serde_json::Value>,
+        keys: &[&str],
+    ) {
+        if !keys.iter().any(|key| obj.contains_key(*key)) {
+            self.error(
+                path,
+                format!("requires at least one of '{}'", keys.join("', '"))
+            );
+        }
+    }
+
+    /// Checks the max length of a prefix assertion against its prefix.
+    fn max_length(
+        &mut self,
+        path: &str,
+        obj: &serde_json::Map<String, serde_json::Value>,
+    ) {
+        let prefix = obj.get("prefix").and_then(|prefix| {
+            prefix.as_str()
+        }).and_then(|prefix| prefix.parse::<Prefix>().ok());
+        let max_len = obj.get("maxPrefixLength").and_then(|max_len| {
+            max_len.as_u64()
+        });
+        if let (Some(prefix), Some(max_len)) = (prefix, max_len) {
+            let limit = if prefix.addr().is_ipv4() { 32 } else { 128 };
+            if max_len < u64::from(prefix.len()) || max_len > limit {
+                self.error(
+                    &format!("{}.maxPrefixLength", path),
+                    format!(
+                        "must be between {} and {}", prefix.len(), limit
+                    )
+                );
+            }
+        }
+    }
+
+    /// Checks the providers of an ASPA assertion.
+    fn providers(
+        &mut self,
+        path: &str,
+        obj: &serde_json::Map<String, serde_json::Value>,
+    ) {
+        let providers = match obj.get("providerSet").and_then(|providers| {
+            providers.as_array()
+        }) {
+            Some(providers) => providers,
+            None => return
+        };
+        let customer = obj.get("customerAsid").and_then(|customer| {
+            customer.as_u64()
+        });
+        let mut seen = Vec::new();
+        for (idx, provider) in providers.iter().enumerate() {
+            let provider = match provider.as_u64() {
+                Some(provider) => provider,
+                None => continue,
+            };
+            if Some(provider) == customer {
+                self.error(
+                    &format!("{}.providerSet[{}]", path, idx),
+                    "customer cannot be its own provider"
+                );
+            }
+            else if seen.contains(&provider) {
+                self.error(
+                    &format!("{}.providerSet[{}]", path, idx),
+                    "duplicate provider"
+                );
+            }
+            seen.push(provider);
+        }
+    }
+
+    /// Checks a single value.
+    fn value(kind: Kind, value: &serde_json::Value) -> Result<(), String> {
+        match kind {
+            Kind::Asn => {
+                Self::asn(value)
+            }
+            Kind::Prefix => {
+                match value.as_str() {
+                    Some(prefix) => {
+                        prefix.parse::<Prefix>().map(|_| ()).map_err(|err| {
+                            format!("invalid prefix: {}", err)
+                        })
+                    }
+                    None => Err("expected a string".into())
+                }
+            }
+            Kind::MaxLength => {
+                match value.as_u64() {
+                    Some(len) if len <= 128 => Ok(()),
+                    _ => Err("expected an integer up to 128".into())
+                }
+            }
+            Kind::Ski | Kind::Base64 => {
+                let data = value.as_str().ok_or(
+                    "expected a string"
+                )?;
+                let data = URL_SAFE_NO_PAD.decode(data).map_err(|_| {
+                    "invalid unpadded URL-safe Base64"
+                })?;
+                if matches!(kind, Kind::Ski) && data.len() != 20 {
+                    return Err("key identifier must be 20 bytes".into())
+                }
+                Ok(())
+            }
+            Kind::Time => {
+                match value.as_str().map(DateTime::parse_from_rfc3339) {
+                    Some(Ok(_)) => Ok(()),
+                    _ => Err("expected a time in RFC 3339 format".into())
+                }
+            }
+            Kind::Providers => {
+                let list = value.as_array().ok_or("expected a list")?;
+                for (idx, item) in list.iter().enumerate() {
+                    Self::asn(item).map_err(|err| {
+                        format!("item {}: {}", idx, err)
+                    })?;
+                }
+                Ok(())
+            }
+            Kind::Text => {
+                match value.as_str() {
+                    Some(_) => Ok(()),
+                    None => Err("expected a string".into())
+                }
+            }
+        }
+    }
+
+    /// Checks that a value is an AS number.
+    fn asn(value: &serde_json::Value) -> Result<(), String> {
+        match value.as_u64() {
+            Some(asn) if asn <= u64::from(u32::MAX) => Ok(()),
+            _ => Err("expected an AS number as an integer".into())
+        }
+    }
+}
+
+
+//------------ ValidationErrors ----------------------------------------------
+
+/// The problems found in a SLURM file.
+#[derive(Debug)]
+pub struct ValidationErrors(Vec<ValidationError>);
+
+impl ValidationErrors {
+    /// The maximum number of problems included in the display output.
+    const MAX_DISPLAYED: usize = 10;
+
+    /// Returns the number of problems.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns whether there are no problems at all.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns an iterator over the problems.
+    pub fn iter(&self) -> impl Iterator<Item = &ValidationError> {
+        self.0.iter()
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (idx, err) in self.0.iter().take(
+            Self::MAX_DISPLAYED
+        ).enumerate() {
+            if idx > 0 {
+                f.write_str("; ")?;
+            }
+            err.fmt(f)?;
+        }
+        if self.0.len() > Self::MAX_DISPLAYED {
+            write!(
+                f, " (and {} more)", self.0.len() - Self::MAX_DISPLAYED
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for ValidationErrors { }
+
+
+//------------ ValidationError -----------------------------------------------
+
+/// A problem found in a SLURM file.
+#[derive(Clone, Debug)]
+pub struct ValidationError {
+    /// The JSON path of the offending value.
+    path: String,
+
+    /// A description of the problem.
+    message: String,
+}
+
+impl ValidationError {
+    /// Returns the JSON path of the offending value.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Returns a description of the problem.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+
+//------------ AspaRules -----------------------------------------------------
+
+/// The ASPA filters and assertions of a SLURM version 2 file.
+#[derive(Clone, Debug, Default)]
+pub struct AspaRules {
+    /// The customer ASNs whose ASPA records are to be dropped.
+    pub filters: Vec<Asn>,
+
+    /// The ASPA records to be added.
+    pub assertions: Vec<Aspa>,
+}
+
+impl AspaRules {
+    /// Removes the ASPA filters and assertions from a SLURM file.
+    ///
+    /// The file should have been validated before. What remains is a
+    /// regular version 1 file.
+    pub fn take(slurm: &mut serde_json::Value) -> Result<Self, String> {
+        #[derive(Deserialize)]
+        struct Filter {
+            #[serde(rename = "customerAsid")]
+            customer: u32,
+        }
+
+        #[derive(Deserialize)]
+        struct Assertion {
+            #[serde(rename = "customerAsid")]
+            customer: u32,
+
+            #[serde(rename = "providerSet")]
+            providers: Vec<u32>,
+        }
+
+        fn take_list<T: for<'de> Deserialize<'de>>(
+            slurm: &mut serde_json::Value, section: &str, key: &str,
+        ) -> Result<Vec<T>, String> {
+            match slurm.get_mut(section).and_then(|section| {
+                section.as_object_mut()
+            }).and_then(|section| section.remove(key)) {
+                Some(list) => {
+                    serde_json::from_value(list).map_err(|err| {
+                        format!("$.{}.{}: {}", section, key, err)
+                    })
+                }
+                None => Ok(Vec::new())
+            }
+        }
+
+        let filters = take_list::<Filter>(
+            slurm, "validationOutputFilters", "aspaFilters"
+        )?;
+        let assertions = take_list::<Assertion>(
+            slurm, "locallyAddedAssertions", "aspaAssertions"
+        )?;
+        if let Some(version) = slurm.get_mut("slurmVersion") {
+            *version = 1.into();
+        }
+        Ok(AspaRules {
+            filters: filters.into_iter().map(|item| {
+                Asn::from(item.customer)
+            }).collect(),
+            assertions: assertions.into_iter().enumerate().map(
+                |(idx, item)| {
+                    let mut providers = item.providers;
+                    providers.sort_unstable();
+                    providers.dedup();
+                    ProviderAsns::try_from_iter(
+                        providers.into_iter().map(Asn::from)
+                    ).map(|providers| {
+                        Aspa::new(item.customer.into(), providers)
+                    }).map_err(|_| {
+                        format!(
+                            "$.locallyAddedAssertions.aspaAssertions[{}]\
+                             .providerSet: too many providers",
+                            idx
+                        )
+                    })
+                }
+            ).collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Returns whether the rules are empty.
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty() && self.assertions.is_empty()
+    }
+
+    /// Returns whether ASPA records for the given customer are replaced.
+    ///
+    /// This is the case if the customer is filtered or if there is an
+    /// assertion for it.
+    pub fn replaces(&self, customer: Asn) -> bool {
+        self.filters.contains(&customer)
+        || self.assertions.iter().any(|item| item.customer == customer)
+    }
+}
+
+
+//============ Output ========================================================
+
+//------------ OutputStream --------------------------------------------------
+
+/// A stream of SLURM formatted output.
+pub struct OutputStream {
+    /// The iterator over the route origins.
+    origins: Origins,
+
+    /// The iterator over the router keys.
+    router_keys: RouterKeys,
+
+    /// Where in the output are we?
+    state: StreamState,
+}
+
+/// The part of the output produced next.
+#[derive(Clone, Copy, Debug)]
+enum StreamState {
+    Header,
+    FirstOrigin,
+    Origin,
+    FirstKey,
+    Key,
+    Done,
+}
+
+impl OutputStream {
+    /// Creates a new output stream for the given payload.
+    pub fn new(origins: Origins, router_keys: RouterKeys) -> Self {
+        OutputStream {
+            origins,
+            router_keys,
+            state: StreamState::Header,
+        }
+    }
+
+    /// Returns the JSON object for a route origin.
+    fn origin(origin: &RouteOrigin) -> String {
+        match origin.prefix.max_len() {
+            Some(max_len) => {
+                format!(
+                    "{{ \"asn\": {}, \"prefix\": \"{}\", \
+                    \"maxPrefixLength\": {} }}",
+                    origin.asn.into_u32(), origin.prefix.prefix(), max_len,
+                )
+            }
+            None => {
+                format!(
+                    "{{ \"asn\": {}, \"prefix\": \"{}\" }}",
+                    origin.asn.into_u32(), origin.prefix.prefix(),
+                )
+            }
+        }
+    }
+
+    /// Returns the JSON object for a router key.
+    fn router_key(key: &RouterKey) -> String {
+        format!(
+            "{{ \"asn\": {}, \"SKI\": \"{}\", \
+            \"routerPublicKey\": \"{}\" }}",
+            key.asn.into_u32(),
+            URL_SAFE_NO_PAD.encode(key.key_identifier.as_slice()),
+            URL_SAFE_NO_PAD.encode(key.key_info.as_slice()),
+        )
+    }
+}
+
+impl Iterator for OutputStream {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.state {
+            StreamState::Header => {
+                self.state = StreamState::FirstOrigin;
+                Some(
+                    b"{\n  \"slurmVersion\": 1,\n  \
+                    \"validationOutputFilters\": {\n    \
+                    \"prefixFilters\": [],\n    \
+                    \"bgpsecFilters\": []\n  },\n  \
+                    \"locallyAddedAssertions\": {\n    \
+                    \"prefixAssertions\": [".to_vec()
+                )
+            }
+            StreamState::FirstOrigin | StreamState::Origin => {
+                let first = matches!(self.state, StreamState::FirstOrigin);
+                match self.origins.next() {
+                    Some(origin) => {
+                        self.state = StreamState::Origin;
+                        Some(format!(
+                            "{}\n      {}",
+                            if first { "" } else { "," },
+                            Self::origin(&origin)
+                        ).into_bytes())
+                    }
+                    None => {
+                        self.state = StreamState::FirstKey;
+                        Some(format!(
+                            "{}],\n    \"bgpsecAssertions\": [",
+                            if first { "" } else { "\n    " },
+                        ).into_bytes())
+                    }
+                }
+            }
+            StreamState::FirstKey | StreamState::Key => {
+                let first = matches!(self.state, StreamState::FirstKey);
+                match self.router_keys.next() {
+                    Some(key) => {
+                        self.state = StreamState::Key;
+                        Some(format!(
+                            "{}\n      {}",
+                            if first { "" } else { "," },
+                            Self::router_key(&key)
+                        ).into_bytes())
+                    }
+                    None => {
+                        self.state = StreamState::Done;
+                        Some(format!(
+                            "{}]\n  }}\n}}\n",
+                            if first { "" } else { "\n    " },
+                        ).into_bytes())
+                    }
+                }
+            }
+            StreamState::Done => None
+        }
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::payload;
+
+    fn output(set: &payload::Set) -> Vec<u8> {
+        OutputStream::new(
+            Origins::new(set.clone(), Default::default()),
+            RouterKeys::new(set),
+        ).flatten().collect()
+    }
+
+    #[test]
+    fn output_round_trip() {
+        let set = payload::Set::from(payload::testrig::slurm_pack(
+            include_bytes!("../../test-data/router-keys.slurm.json")
+        ));
+        let output = output(&set);
+        assert!(output.ends_with(b"\n"));
+        assert_eq!(
+            payload::Set::from(payload::testrig::slurm_pack(&output)),
+            set
+        );
+    }
+
+    #[test]
+    fn output_empty() {
+        let output = output(&payload::Set::default());
+        let json: serde_json::Value = serde_json::from_slice(
+            &output
+        ).unwrap();
+        assert_eq!(
+            json["locallyAddedAssertions"]["prefixAssertions"],
+            serde_json::json!([])
+        );
+        assert_eq!(
+            json["locallyAddedAssertions"]["bgpsecAssertions"],
+            serde_json::json!([])
+        );
+        assert!(payload::testrig::slurm_pack(&output).is_empty());
+    }
+
+    #[test]
+    fn validate_versions() {
+        let v1: serde_json::Value = serde_json::from_slice(include_bytes!(
+            "../../test-data/router-keys-exceptions.slurm.json"
+        )).unwrap();
+        assert_eq!(validate(&v1, None).unwrap(), Version::V1);
+        assert_eq!(validate(&v1, Some(Version::V1)).unwrap(), Version::V1);
+        let err = validate(&v1, Some(Version::V2)).unwrap_err();
+        assert_eq!(err.len(), 1);
+        assert_eq!(err.iter().next().unwrap().path(), "$.slurmVersion");
+
+        let v2 = serde_json::json!({
+            "slurmVersion": 2,
+            "validationOutputFilters": {
+                "prefixFilters": [],
+                "bgpsecFilters": [],
+                "aspaFilters": [ { "customerAsid": 64496 } ]
+            },
+            "locallyAddedAssertions": {
+                "prefixAssertions": [],
+                "bgpsecAssertions": [],
+                "aspaAssertions": [
+                    {
+                        "customerAsid": 64497,
+                        "providerSet": [64498, 64499],
+                        "comment": "Two providers"
+                    }
+                ]
+            }
+        });
+        assert_eq!(validate(&v2, None).unwrap(), Version::V2);
+
+        // Version 2 requires the ASPA lists.
+        let mut v2_missing = v2.clone();
+        v2_missing["validationOutputFilters"].as_object_mut().unwrap()
+            .remove("aspaFilters");
+        let err = validate(&v2_missing, None).unwrap_err();
+        assert_eq!(
+            err.iter().map(ValidationError::path).collect::<Vec<_>>(),
+            ["$.validationOutputFilters.aspaFilters"]
+        );
+
+        assert!(
+            validate(&serde_json::json!({"slurmVersion": 3}), None).is_err()
+        );
+    }
+
+    #[test]
+    fn validate_paths() {
+        let slurm = serde_json::json!({
+            "slurmVersion": 1,
+            "validationOutputFilters": {
+                "prefixFilters": [
+                    { "prefix": "192.0.2.0/24" },
+                    { "prefix": "192.0.2.0/33" },
+                    { "comment": "Matches nothing" }
+                ],
+                "bgpsecFilters": [
+                    { "SKI": "Zm9v" }
+                ],
+                "aspaFilters": []
+            },
+            "locallyAddedAssertions": {
+                "prefixAssertions": [
+                    {
+                        "asn": 64496, "prefix": "192.0.2.0/24",
+                        "maxPrefixLength": 16
+                    },
+                    { "asn": "AS64496", "prefix": "192.0.2.0/24" }
+                ],
+                "bgpsecAssertions": [],
+                "extra": []
+            }
+        });
+        let err = validate(&slurm, None).unwrap_err();
+        let mut paths = err.iter().map(|err| {
+            err.path().to_string()
+        }).collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                "$.locallyAddedAssertions.extra",
+                "$.locallyAddedAssertions.prefixAssertions[0]\
+                 .maxPrefixLength",
+                "$.locallyAddedAssertions.prefixAssertions[1].asn",
+                "$.validationOutputFilters.aspaFilters",
+                "$.validationOutputFilters.bgpsecFilters[0].SKI",
+                "$.validationOutputFilters.prefixFilters[1].prefix",
+                "$.validationOutputFilters.prefixFilters[2]",
+            ]
+        );
+        assert!(err.to_string().contains(
+            "$.validationOutputFilters.prefixFilters[2]: requires at least \
+             one of 'prefix', 'asn'"
+        ));
+    }
+
+    #[test]
+    fn take_aspa_rules() {
+        let mut slurm = serde_json::json!({
+            "slurmVersion": 2,
+            "validationOutputFilters": {
+                "prefixFilters": [],
+                "bgpsecFilters": [],
+                "aspaFilters": [ { "customerAsid": 64496 } ]
+            },
+            "locallyAddedAssertions": {
+                "prefixAssertions": [],
+                "bgpsecAssertions": [],
+                "aspaAssertions": [
+                    { "customerAsid": 64497, "providerSet": [64499, 64498] }
+                ]
+            }
+        });
+        let rules = AspaRules::take(&mut slurm).unwrap();
+        assert_eq!(rules.filters, [Asn::from(64496)]);
+        assert_eq!(rules.assertions.len(), 1);
+        assert_eq!(rules.assertions[0].customer, Asn::from(64497));
+        assert_eq!(
+            rules.assertions[0].providers.iter().collect::<Vec<_>>(),
+            [Asn::from(64498), Asn::from(64499)]
+        );
+        assert!(rules.replaces(64496.into()));
+        assert!(rules.replaces(64497.into()));
+        assert!(!rules.replaces(64498.into()));
+        assert_eq!(validate(&slurm, None).unwrap(), Version::V1);
+    }
+}
@@ -0,0 +1,448 @@
+//! Handing over state to a new process.
+//!
+//! When upgrading RTRTR, the old process can write the state of its
+//! components to a _handoff file_ when it is shut down. The new process
+//! reads this file upon startup and the components pick up where the old
+//! process stopped. For RTR targets, this means that they keep their
+//! session, serial number, data set, and diff history, so that clients can
+//! continue with serial queries against the new process.
+//!
+//! Components that want to hand off state register an implementation of
+//! [`Export`] via [`Component::register_handoff`] and retrieve the state
+//! handed to them via [`Component::take_handoff`].
+//!
+//! [`Component::register_handoff`]:
+//!     crate::manager::Component::register_handoff
+//! [`Component::take_handoff`]: crate::manager::Component::take_handoff
+
+use std::{fs, io};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, Weak};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use daemonbase::config::ConfigPath;
+use daemonbase::error::Failed;
+use log::{error, info, warn};
+use rpki::resources::asn::Asn;
+use rpki::rtr::payload::{Aspa, Payload};
+use rpki::rtr::pdu::ProviderAsns;
+use rpki::slurm::SlurmFile;
+use serde::{Deserialize, Serialize};
+use crate::payload::{Pack, PackBuilder};
+
+
+//------------ HandoffConfig -------------------------------------------------
+
+/// The configuration of the state handoff.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct HandoffConfig {
+    /// The path to the handoff file.
+    ///
+    /// If this is `None`, no state is handed off.
+    #[serde(rename = "handoff-file")]
+    file: Option<ConfigPath>,
+}
+
+impl HandoffConfig {
+    /// Loads the state handed off by a previous process.
+    ///
+    /// If there is a handoff file, it is removed after reading so that its
+    /// state is only ever used once. A handoff file that can’t be read is
+    /// logged and otherwise ignored.
+    pub fn load(&self) -> Handoff {
+        let path = match self.file.as_ref() {
+            Some(path) => {
+                let path: &Path = path.as_ref();
+                path.to_path_buf()
+            }
+            None => return Handoff::default(),
+        };
+        Handoff::load(path)
+    }
+}
+
+
+//------------ Handoff -------------------------------------------------------
+
+/// The state handed off between processes.
+///
+/// This keeps both the state imported from a previous process that hasn’t
+/// been taken by its component yet and the components that will provide
+/// their state to the next process.
+#[derive(Debug, Default)]
+pub struct Handoff {
+    /// The path to the handoff file.
+    path: Option<PathBuf>,
+
+    /// The imported state by component name.
+    imported: Mutex<HashMap<String, serde_json::Value>>,
+
+    /// The components providing state to export.
+    exporters: Mutex<Vec<(String, Weak<dyn Export>)>>,
+}
+
+impl Handoff {
+    /// Creates the handoff for the given handoff file.
+    ///
+    /// Imports the state from the file if it exists.
+    fn load(path: PathBuf) -> Self {
+        let imported = HandoffFile::load(&path).map(|file| {
+            file.components
+        }).unwrap_or_default();
+        Handoff {
+            path: Some(path),
+            imported: Mutex::new(imported),
+            exporters: Default::default(),
+        }
+    }
+
+    /// Takes the imported state of the given component.
+    pub fn take(&self, name: &str) -> Option<serde_json::Value> {
+        self.imported.lock().unwrap().remove(name)
+    }
+
+    /// Registers a component’s state for export.
+    pub fn register(&self, name: String, source: Weak<dyn Export>) {
+        if self.path.is_some() {
+            self.exporters.lock().unwrap().push((name, source))
+        }
+    }
+
+    /// Writes the state of all registered components to the handoff file.
+    ///
+    /// Does nothing if no handoff file has been configured.
+    pub fn write(&self) -> Result<(), Failed> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return Ok(())
+        };
+        let mut components = HashMap::new();
+        for (name, source) in self.exporters.lock().unwrap().iter() {
+            let state = source.upgrade().and_then(|source| source.export());
+            if let Some(state) = state {
+                components.insert(name.clone(), state);
+            }
+        }
+        let count = components.len();
+        let file = HandoffFile { version: HandoffFile::VERSION, components };
+        let res = serde_json::to_vec(&file).map_err(io::Error::from).and_then(
+            |data| write_file(path, &data)
+        );
+        match res {
+            Ok(()) => {
+                info!(
+                    "Handed off state of {} components to {}.",
+                    count, path.display()
+                );
+                Ok(())
+            }
+            Err(err) => {
+                error!(
+                    "Failed to write handoff file {}: {}",
+                    path.display(), err
+                );
+                Err(Failed)
+            }
+        }
+    }
+}
+
+
+//------------ Export --------------------------------------------------------
+
+/// A type providing state to hand off to a new process.
+pub trait Export: Send + Sync {
+    /// Returns the current state.
+    ///
+    /// Returns `None` if there currently is no state worth handing off.
+    fn export(&self) -> Option<serde_json::Value>;
+}
+
+
+//------------ HandoffFile ---------------------------------------------------
+
+/// The content of the handoff file.
+#[derive(Debug, Deserialize, Serialize)]
+struct HandoffFile {
+    /// The version of the file format.
+    version: u8,
+
+    /// The state of the components by component name.
+    components: HashMap<String, serde_json::Value>,
+}
+
+impl HandoffFile {
+    /// The current version of the file format.
+    const VERSION: u8 = 1;
+
+    /// Loads and removes the handoff file.
+    ///
+    /// Returns `None` if there is no file or it can’t be used.
+    fn load(path: &Path) -> Option<Self> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return None
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to read handoff file {}: {}. Starting afresh.",
+                    path.display(), err
+                );
+                return None
+            }
+        };
+        if let Err(err) = fs::remove_file(path) {
+            warn!(
+                "Failed to remove handoff file {}: {}",
+                path.display(), err
+            );
+        }
+        let file = match serde_json::from_slice::<Self>(&data) {
+            Ok(file) => file,
+            Err(err) => {
+                warn!(
+                    "Failed to parse handoff file {}: {}. Starting afresh.",
+                    path.display(), err
+                );
+                return None
+            }
+        };
+        if file.version != Self::VERSION {
+            warn!(
+                "Handoff file {} has unsupported version {}. \
+                 Starting afresh.",
+                path.display(), file.version
+            );
+            return None
+        }
+        info!(
+            "Resuming state of {} components from handoff file {}.",
+            file.components.len(), path.display()
+        );
+        Some(file)
+    }
+}
+
+
+//------------ PayloadList ---------------------------------------------------
+
+/// A list of payload items in handoff state.
+///
+/// The items are encoded as the local assertions of a SLURM version 2 file.
+/// The list of ASPA assertions is left out if there are none, so lists
+/// written before ASPA records were supported can still be read.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PayloadList {
+    /// The route origins.
+    #[serde(rename = "prefixAssertions")]
+    origins: Vec<OriginItem>,
+
+    /// The router keys.
+    #[serde(rename = "bgpsecAssertions")]
+    router_keys: Vec<RouterKeyItem>,
+
+    /// The ASPA records.
+    #[serde(
+        rename = "aspaAssertions",
+        default, skip_serializing_if = "Vec::is_empty"
+    )]
+    aspas: Vec<AspaItem>,
+}
+
+impl PayloadList {
+    /// Encodes the payload items.
+    pub fn encode<'a>(
+        items: impl IntoIterator<Item = &'a Payload>
+    ) -> Self {
+        let mut res = Self::default();
+        for item in items {
+            match item {
+                Payload::Origin(origin) => {
+                    res.origins.push(OriginItem {
+                        asn: origin.asn.into_u32(),
+                        prefix: origin.prefix.prefix().to_string(),
+                        max_len: origin.prefix.max_len(),
+                    })
+                }
+                Payload::RouterKey(key) => {
+                    res.router_keys.push(RouterKeyItem {
+                        asn: key.asn.into_u32(),
+                        ski: URL_SAFE_NO_PAD.encode(
+                            key.key_identifier.as_slice()
+                        ),
+                        key: URL_SAFE_NO_PAD.encode(key.key_info.as_slice()),
+                    })
+                }
+                Payload::Aspa(aspa) => {
+                    res.aspas.push(AspaItem {
+                        customer: aspa.customer.into_u32(),
+                        providers: aspa.providers.iter().map(|asn| {
+                            asn.into_u32()
+                        }).collect(),
+                    })
+                }
+            }
+        }
+        res
+    }
+
+    /// Decodes the list into a pack.
+    pub fn decode(&self) -> Result<Pack, String> {
+        let slurm = serde_json::to_vec(&serde_json::json!({
+            "slurmVersion": 1,
+            "validationOutputFilters": {
+                "prefixFilters": [],
+                "bgpsecFilters": [],
+            },
+            "locallyAddedAssertions": {
+                "prefixAssertions": self.origins,
+                "bgpsecAssertions": self.router_keys,
+            },
+        })).map_err(|err| err.to_string())?;
+        let slurm = SlurmFile::from_reader(
+            slurm.as_slice()
+        ).map_err(|err| err.to_string())?;
+        let mut res = PackBuilder::empty();
+        for payload in slurm.assertions.iter_payload() {
+            res.insert_unchecked(payload)
+        }
+        for item in &self.aspas {
+            let providers = ProviderAsns::try_from_iter(
+                item.providers.iter().copied().map(Asn::from)
+            ).map_err(|_| String::from("too many ASPA providers"))?;
+            res.insert_unchecked(
+                Payload::Aspa(Aspa::new(item.customer.into(), providers))
+            )
+        }
+        Ok(res.finalize())
+    }
+}
+
+
+//------------ OriginItem ----------------------------------------------------
+
+/// A route origin in a payload list.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct OriginItem {
+    asn: u32,
+    prefix: String,
+    #[serde(
+        rename = "maxPrefixLength",
+        default, skip_serializing_if = "Option::is_none"
+    )]
+    max_len: Option<u8>,
+}
+
+
+//------------ RouterKeyItem -------------------------------------------------
+
+/// A router key in a payload list.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct RouterKeyItem {
+    asn: u32,
+    #[serde(rename = "SKI")]
+    ski: String,
+    #[serde(rename = "routerPublicKey")]
+    key: String,
+}
+
+
+//------------ AspaItem ------------------------------------------------------
+
+/// An ASPA record in a payload list.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct AspaItem {
+    #[serde(rename = "customerAsid")]
+    customer: u32,
+    #[serde(rename = "providerSet")]
+    providers: Vec<u32>,
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Atomically replaces the content of a file.
+///
+/// The data is first written to a temporary file next to the file which
+/// then replaces it.
+pub fn write_file(path: &Path, data: &[u8]) -> Result<(), io::Error> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, data)?;
+    fs::rename(&tmp_path, path)
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::payload::testrig;
+
+    #[test]
+    fn payload_list() {
+        let pack = testrig::slurm_pack(
+            include_bytes!("../test-data/router-keys.slurm.json")
+        );
+        let list = PayloadList::encode(pack.iter());
+        let list: PayloadList = serde_json::from_slice(
+            &serde_json::to_vec(&list).unwrap()
+        ).unwrap();
+        assert_eq!(list.decode().unwrap(), pack);
+
+        let mut builder = PackBuilder::empty();
+        for payload in pack.iter() {
+            builder.insert_unchecked(payload.clone())
+        }
+        builder.insert_unchecked(Payload::Aspa(Aspa::new(
+            64496.into(),
+            ProviderAsns::try_from_iter(
+                [64497, 64498].into_iter().map(Asn::from)
+            ).unwrap()
+        )));
+        let pack = builder.finalize();
+        let list: PayloadList = serde_json::from_slice(
+            &serde_json::to_vec(&PayloadList::encode(pack.iter())).unwrap()
+        ).unwrap();
+        assert_eq!(list.decode().unwrap(), pack);
+    }
+
+    #[test]
+    fn handoff_file() {
+        let dir = std::env::temp_dir().join(format!(
+            "rtrtr-handoff-{}", std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("handoff.json");
+
+        struct State;
+
+        impl Export for State {
+            fn export(&self) -> Option<serde_json::Value> {
+                Some(serde_json::json!({ "serial": 12 }))
+            }
+        }
+
+        let state = std::sync::Arc::new(State);
+        let old = Handoff {
+            path: Some(path.clone()),
+            .. Default::default()
+        };
+        old.register(
+            "rtr".into(),
+            std::sync::Arc::downgrade(&state) as Weak<dyn Export>
+        );
+        old.write().unwrap();
+
+        let new = Handoff::load(path.clone());
+        assert!(!path.exists());
+        assert_eq!(
+            new.take("rtr"), Some(serde_json::json!({ "serial": 12 }))
+        );
+        assert_eq!(new.take("rtr"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
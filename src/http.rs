@@ -1,39 +1,81 @@
 //! The HTTP server.
 //!
 //! Because with HTTP you can select what information you want per request,
-//! we only have one HTTP server for the entire instance. HTTP targets will
-//! provide their data via a specific base path within that server.
+//! there normally is only one HTTP server for the entire instance. HTTP
+//! targets will provide their data via a specific base path within that
+//! server.
+//!
+//! Additional named servers with their own listen addresses and TLS
+//! configuration can be defined, too. Targets can pick the server they want
+//! to be available on by its name. This makes it possible to, say, provide
+//! the management endpoints on an internal network only.
 //!
 //! Server configuration happens via the [`Server`] struct that normally is
 //! part of the [`Config`](crate::config::Config).
+//!
+//! The server itself is only available if the `http-server` feature is
+//! enabled. Otherwise, only the types necessary for components to register
+//! their resources are provided.
+
+#![cfg_attr(not(feature = "http-server"), allow(dead_code, unused_imports))]
 
-use std::{fmt, io};
+use std::{fmt, fs, io};
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::net::TcpListener as StdListener;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::task::{Context, Poll};
+use std::time::Duration;
 use arc_swap::ArcSwap;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 use bytes::Bytes;
 use chrono::{DateTime, Utc};
-use daemonbase::error::ExitError;
+use crossbeam_utils::atomic::AtomicCell;
+use daemonbase::config::ConfigPath;
+use daemonbase::error::{ExitError, Failed};
 use futures_util::pin_mut;
 use futures_util::stream::{Stream, StreamExt};
-use http_body_util::{BodyExt, Empty, Full, StreamBody};
+use http_body_util::{BodyExt, Empty, Full, Limited, StreamBody};
 use http_body_util::combinators::BoxBody;
 use hyper::{Method, StatusCode};
 use hyper::body::{Body, Frame};
+use hyper::header::{AUTHORIZATION, HeaderName, REFERER, USER_AGENT};
 use hyper::http::response::Builder;
 use hyper::service::service_fn;
 use hyper_util::rt::{TokioExecutor, TokioIo};
 use log::{debug, error};
+#[cfg(not(feature = "http-server"))]
+use log::warn;
+use rpki::resources::addr::Prefix;
 use serde::Deserialize;
+use slab::Slab;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-use tokio::net::{TcpListener, TcpStream};
 use tokio::runtime::Runtime;
+use tokio::time::{Instant, sleep_until};
+use crate::config::Secret;
 use crate::metrics;
+use crate::log::{AccessEntry, AccessLog, AccessLogConfig};
 use crate::utils::http::format_http_date;
+use crate::utils::listener::{
+    bind, AcceptMetrics, Listener, SocketOptions
+};
+use crate::utils::net::AccessList;
+use crate::utils::tls::{MaybeTlsTcpStream, TlsAcceptor};
+#[cfg(feature = "tls")]
+use crate::utils::tls::create_server_config;
+
+
+//------------ Configuration -------------------------------------------------
+
+/// The maximum size of the body of a POST request in octets.
+///
+/// This is large enough for a complete JSON-encoded VRP set.
+const MAX_BODY_LEN: usize = 256 * 1024 * 1024;
 
 
 //------------ Server --------------------------------------------------------
@@ -44,128 +86,527 @@ pub struct Server {
     /// The socket addresses to listen on.
     #[serde(rename = "http-listen")]
     listen: Vec<SocketAddr>,
+
+    /// The number of seconds an idle connection is kept open.
+    ///
+    /// A value of zero means that idle connections are never closed.
+    #[serde(
+        default = "Server::default_idle_timeout",
+        rename = "http-idle-timeout"
+    )]
+    idle_timeout: u64,
+
+    /// Additional named servers.
+    #[serde(default, rename = "http-servers")]
+    servers: HashMap<String, NamedServer>,
+
+    /// The prefixes clients are allowed to connect from.
+    #[serde(default, rename = "http-allow")]
+    allow: Vec<Prefix>,
+
+    /// The prefixes clients are not allowed to connect from.
+    #[serde(default, rename = "http-deny")]
+    deny: Vec<Prefix>,
+
+    /// The access log configuration.
+    #[serde(flatten)]
+    access_log: AccessLogConfig,
+
+    /// The bearer token required for the admin endpoints.
+    ///
+    /// If this is `None`, the admin endpoints are disabled.
+    #[serde(rename = "http-admin-token")]
+    admin_token: Option<Secret>,
+
+    /// The authentication required for the metrics endpoints.
+    #[serde(default, rename = "http-metrics-auth")]
+    metrics_auth: HttpAuth,
 }
 
 impl Server {
+    /// The default idle timeout in seconds.
+    fn default_idle_timeout() -> u64 {
+        60
+    }
+
+    /// Returns whether a named server of the given name has been defined.
+    pub fn has_server(&self, name: &str) -> bool {
+        self.servers.contains_key(name)
+    }
+
+    /// Checks the parts of the configuration only used at run time.
+    ///
+    /// This loads the TLS certificates and keys of all named servers and
+    /// logs any problems found.
+    pub fn check(&self) -> Result<(), Failed> {
+        let mut res = Ok(());
+        for (name, server) in &self.servers {
+            if server.tls_acceptor(name).is_err() {
+                res = Err(Failed)
+            }
+        }
+        res
+    }
+
+    /// Returns the socket addresses of all servers.
+    ///
+    /// This includes the addresses of the named servers. If HTTP server
+    /// support is disabled in this build, the list is empty.
+    pub fn listen_addrs(&self) -> Vec<SocketAddr> {
+        if !cfg!(feature = "http-server") {
+            return Vec::new()
+        }
+        let mut servers: Vec<_> = self.servers.iter().collect();
+        servers.sort_by(|left, right| left.0.cmp(right.0));
+        let mut res = self.listen.clone();
+        for (_, server) in servers {
+            res.extend_from_slice(&server.listen);
+        }
+        res
+    }
+
     /// Runs the server.
     ///
     /// The method will start a new server listening on the sockets provided
     /// via the configuration and spawns it onto the given `runtime`. The
     /// method should be run before `runtime` is started. It will
     /// synchronously create and bind all required sockets before returning.
+    /// This includes the sockets of all named servers.
     ///
     /// The server will use `metrics` to produce information on its metrics
-    /// related endpoints.
+    /// related endpoints. If configured, all served requests are recorded
+    /// in the access log which is opened here, too.
+    #[cfg(feature = "http-server")]
     pub fn run(
         &self,
         metrics: metrics::Collection,
         resources: Resources,
         runtime: &Runtime,
     ) -> Result<(), ExitError> {
+        let idle_timeout = match self.idle_timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs))
+        };
+        let access_log = self.access_log.open()?;
+        let access = Arc::new(AccessList::new(&self.allow, &self.deny));
+
+        let mut configs = vec![(
+            &self.listen,
+            Arc::new(ListenerConfig {
+                name: None, management: true, tls: None, idle_timeout,
+                admin_token: self.admin_token.clone(),
+                metrics_auth: self.metrics_auth.clone(),
+            })
+        )];
+        for (name, server) in &self.servers {
+            configs.push((
+                &server.listen,
+                Arc::new(ListenerConfig {
+                    name: Some(name.as_str().into()),
+                    management: server.management,
+                    tls: server.tls_acceptor(name)?,
+                    idle_timeout,
+                    admin_token: self.admin_token.clone(),
+                    metrics_auth: self.metrics_auth.clone(),
+                })
+            ));
+        }
+
         // Bind and collect all listeners first so we can error out
         // if any of them fails.
         let mut listeners = Vec::new();
-        for addr in &self.listen {
-            // Binding needs to have happened before dropping privileges
-            // during detach. So we do this here synchronously.
-            let listener = match StdListener::bind(addr) {
-                Ok(listener) => listener,
-                Err(err) => {
-                    error!("Fatal: error listening on {}: {}", addr, err);
-                    return Err(ExitError::default());
+        for (listen, config) in configs {
+            for addr in listen {
+                // Binding needs to have happened before dropping privileges
+                // during detach. So we do this here synchronously.
+                let listener = bind(*addr)?;
+                match config.name.as_ref() {
+                    Some(name) => {
+                        debug!(
+                            "HTTP server '{}' listening on {}", name, addr
+                        );
+                    }
+                    None => debug!("HTTP server listening on {}", addr),
                 }
-            };
-            if let Err(err) = listener.set_nonblocking(true) {
-                error!(
-                    "Fatal: failed to set listener {} to non-blocking: {}.",
-                    addr, err
-                );
-                return Err(ExitError::default());
+                listeners.push((listener, *addr, config.clone()));
             }
-            debug!("HTTP server listening on {}", addr);
-            listeners.push((listener, addr));
         }
 
+        let http_metrics = Arc::new(HttpMetrics::default());
+        metrics.register(
+            "http-server".into(),
+            Arc::downgrade(&http_metrics) as Weak<dyn metrics::Source>
+        );
+
         // Now spawn the listeners onto the runtime. This way, they will start
         // doing their thing as soon as the runtime is started.
-        for (listener, addr) in listeners {
+        for (listener, addr, config) in listeners {
             runtime.spawn(
                 Self::single_listener(
-                    listener, *addr, metrics.clone(), resources.clone()
+                    listener, addr, config, access.clone(), metrics.clone(),
+                    resources.clone(), http_metrics.clone(),
+                    access_log.clone(),
                 )
             );
         }
         Ok(())
     }
  
+    /// Runs the server.
+    ///
+    /// Because HTTP server support is disabled in this build, this only
+    /// warns if servers have been configured.
+    #[cfg(not(feature = "http-server"))]
+    pub fn run(
+        &self,
+        _metrics: metrics::Collection,
+        _resources: Resources,
+        _runtime: &Runtime,
+    ) -> Result<(), ExitError> {
+        if !self.listen.is_empty() || !self.servers.is_empty() {
+            warn!(
+                "HTTP server support is not available in this build. \
+                 Ignoring HTTP server configuration."
+            );
+        }
+        Ok(())
+    }
+
     /// Runs a single HTTP listener.
     ///
     /// Currently, this async function only resolves if the underlying
     /// listener encounters an error.
+    #[cfg(feature = "http-server")]
+    #[allow(clippy::too_many_arguments)]
     async fn single_listener(
         listener: StdListener,
         addr: SocketAddr,
+        config: Arc<ListenerConfig>,
+        access: Arc<AccessList>,
         metrics: metrics::Collection,
         resources: Resources,
+        http_metrics: Arc<HttpMetrics>,
+        access_log: AccessLog,
     ) {
-        let listener = match TcpListener::from_std(listener) {
-            Ok(listener) => listener,
+        let mut listener = match Listener::new(
+            listener, addr, config.tls.clone(), SocketOptions::default(),
+            http_metrics.accept.clone()
+        ) {
+            Ok(listener) => listener.with_access(access),
             Err(err) => {
                 error!("Error on HTTP listener: {}", err);
                 return
             }
         };
         loop {
-            let stream = match listener.accept().await {
-                Ok((stream, _addr)) => stream,
-                Err(err) => {
-                    error!("Fatal error in HTTP server {}: {}", addr, err);
+            let (stream, client) = match listener.accept().await {
+                Ok(res) => res,
+                Err(_) => {
+                    // The listener has already logged the error.
+                    error!("Fatal error in HTTP server {}.", addr);
                     break;
                 }
             };
             let metrics = metrics.clone();
             let resources = resources.clone();
+            let config = config.clone();
+            let access_log = access_log.clone();
+            let stream = HttpStream::new(stream, http_metrics.clone());
+            let conn = stream.conn.clone();
             tokio::task::spawn(async move {
-                let _ = hyper_util::server::conn::auto::Builder::new(
-                    TokioExecutor::new()
-                ).serve_connection(
-                    TokioIo::new(stream),
-                    service_fn(move |req| {
-                        let metrics = metrics.clone();
-                        let resources = resources.clone();
-                        async move {
-                            Self::handle_request(
-                                req, &metrics, &resources
-                            ).await
-                        }
-                    })
-                ).await;
+                Self::serve_connection(
+                    stream, client, conn, config, metrics, resources,
+                    access_log,
+                ).await
             });
         }
     }
 
+    /// Serves a single HTTP connection.
+    ///
+    /// If the listener has an idle timeout, the connection is gracefully
+    /// shut down once there hasn’t been any activity on it for that long.
+    #[cfg(feature = "http-server")]
+    #[allow(clippy::too_many_arguments)]
+    async fn serve_connection(
+        stream: HttpStream,
+        client: SocketAddr,
+        conn: Arc<ConnectionState>,
+        config: Arc<ListenerConfig>,
+        metrics: metrics::Collection,
+        resources: Resources,
+        access_log: AccessLog,
+    ) {
+        let idle_timeout = config.idle_timeout;
+        let builder = hyper_util::server::conn::auto::Builder::new(
+            TokioExecutor::new()
+        );
+        let service_conn = conn.clone();
+        let serve = builder.serve_connection(
+            TokioIo::new(stream),
+            service_fn(move |req| {
+                let metrics = metrics.clone();
+                let resources = resources.clone();
+                let config = config.clone();
+                let access_log = access_log.clone();
+                service_conn.request();
+                async move {
+                    Self::handle_request(
+                        req, client, &config, &metrics, &resources,
+                        &access_log,
+                    ).await
+                }
+            })
+        );
+        pin_mut!(serve);
+
+        let idle_timeout = match idle_timeout {
+            Some(timeout) => timeout,
+            None => {
+                let _ = serve.await;
+                return
+            }
+        };
+        loop {
+            tokio::select! {
+                _ = serve.as_mut() => return,
+                _ = sleep_until(conn.last_active() + idle_timeout) => {
+                    if conn.last_active() + idle_timeout <= Instant::now() {
+                        break
+                    }
+                }
+            }
+        }
+        debug!("Closing idle HTTP connection.");
+        conn.reaped();
+        serve.as_mut().graceful_shutdown();
+        let _ = serve.await;
+    }
+
     /// Handles a single HTTP request.
+    ///
+    /// The request is recorded in the access log if that wants it.
+    ///
+    /// Admin requests are processed via `spawn_blocking` since they may do
+    /// blocking I/O such as reading the config file or writing the audit
+    /// log.
+    #[cfg(feature = "http-server")]
     async fn handle_request(
-        req: Request,
+        mut req: Request,
+        client: SocketAddr,
+        config: &Arc<ListenerConfig>,
         metrics: &metrics::Collection,
         resources: &Resources,
+        access_log: &AccessLog,
     ) -> Result<Response, Infallible> {
-        if *req.method() != Method::GET {
-            return Ok(Self::method_not_allowed())
+        req.extensions_mut().insert(ClientAddr(client));
+        let (response, target) = match Self::read_body(&mut req).await {
+            Ok(()) if Self::is_admin_request(&req) => {
+                let config = config.clone();
+                let metrics = metrics.clone();
+                let resources = resources.clone();
+                let res = tokio::task::spawn_blocking(move || {
+                    let res = Self::process_request(
+                        &req, &config, &metrics, &resources
+                    );
+                    (res, req)
+                }).await;
+                match res {
+                    Ok((res, processed)) => {
+                        req = processed;
+                        res
+                    }
+                    Err(err) => {
+                        error!("Processing admin request failed: {}", err);
+                        return Ok(
+                            ResponseBuilder::new(
+                                StatusCode::INTERNAL_SERVER_ERROR
+                            )
+                            .content_type(ContentType::TEXT)
+                            .body("Internal Server Error")
+                        )
+                    }
+                }
+            }
+            Ok(()) => {
+                Self::process_request(&req, config, metrics, resources)
+            }
+            Err(response) => (response, None)
+        };
+        if access_log.includes(target.as_deref()) {
+            access_log.record(
+                &Self::access_entry(&req, client, &response, target)
+            );
+        }
+        Ok(response)
+    }
+
+    /// Returns whether a request is for the admin endpoints.
+    #[cfg(feature = "http-server")]
+    fn is_admin_request(req: &Request) -> bool {
+        *req.method() == Method::POST && req.uri().path().starts_with("/api/")
+    }
+
+    /// Reads the body of a POST request.
+    ///
+    /// The body is stored in the request’s extensions where it can be
+    /// retrieved via [`request_body`]. Bodies of other requests are
+    /// ignored. If the body can’t be read or is too large, returns the
+    /// error response.
+    #[cfg(feature = "http-server")]
+    async fn read_body(req: &mut Request) -> Result<(), Response> {
+        if *req.method() != Method::POST {
+            return Ok(())
+        }
+        match Limited::new(req.body_mut(), MAX_BODY_LEN).collect().await {
+            Ok(body) => {
+                let body = body.to_bytes();
+                req.extensions_mut().insert(RequestBody(body));
+                Ok(())
+            }
+            Err(err) => {
+                if err.is::<http_body_util::LengthLimitError>() {
+                    Err(
+                        ResponseBuilder::new(StatusCode::PAYLOAD_TOO_LARGE)
+                        .content_type(ContentType::TEXT)
+                        .body("Payload Too Large")
+                    )
+                }
+                else {
+                    debug!("Failed to read HTTP request body: {}", err);
+                    Err(
+                        ResponseBuilder::bad_request()
+                        .content_type(ContentType::TEXT)
+                        .body("Bad Request")
+                    )
+                }
+            }
+        }
+    }
+
+    /// Produces the response for a request.
+    ///
+    /// POST requests for paths under `/api/` are admin requests and only
+    /// available on servers providing the management endpoints. All other
+    /// POST requests are passed to the registered resources which have to
+    /// take care of authorization themselves.
+    ///
+    /// GET requests for the metrics, the status, and everything under
+    /// `/api/` need to pass the metrics authentication.
+    ///
+    /// Returns the response and the name of the target that produced it,
+    /// if any.
+    #[cfg(feature = "http-server")]
+    fn process_request(
+        req: &Request,
+        config: &ListenerConfig,
+        metrics: &metrics::Collection,
+        resources: &Resources,
+    ) -> (Response, Option<Arc<str>>) {
+        match *req.method() {
+            Method::GET => { }
+            Method::POST if req.uri().path().starts_with("/api/") => {
+                if !config.management {
+                    return (Self::method_not_allowed(), None)
+                }
+                return Self::process_admin_request(req, config, resources)
+            }
+            Method::POST => {
+                return match resources.process_named_request(
+                    req, config.name.as_deref()
+                ) {
+                    Some(res) => res,
+                    None => (Self::method_not_allowed(), None)
+                }
+            }
+            _ => return (Self::method_not_allowed(), None)
+        }
+        if is_protected_path(req.uri().path()) {
+            if let Err(response) = config.metrics_auth.check(req) {
+                return (*response, None)
+            }
         }
-        Ok(match req.uri().path() {
-            "/metrics" => Self::metrics(metrics),
-            "/status" => Self::status(metrics),
+        match req.uri().path() {
+            "/healthz" if config.management => (Self::healthz(), None),
+            "/metrics" if config.management => {
+                (Self::metrics(metrics), None)
+            }
+            "/status" if config.management => {
+                (Self::status(req, metrics), None)
+            }
             _ => {
-                match resources.process_request(&req) {
-                    Some(response) => response,
-                    None => Self::not_found()
+                match resources.process_named_request(
+                    req, config.name.as_deref()
+                ) {
+                    Some(res) => res,
+                    None => (Self::not_found(), None)
                 }
             }
-        })
+        }
+    }
+
+    /// Produces the response for a request to the admin endpoints.
+    ///
+    /// Admin requests are only processed if an admin token has been
+    /// configured and the request carries it as a bearer token.
+    #[cfg(feature = "http-server")]
+    fn process_admin_request(
+        req: &Request,
+        config: &ListenerConfig,
+        resources: &Resources,
+    ) -> (Response, Option<Arc<str>>) {
+        let token = match config.admin_token.as_ref() {
+            Some(token) => token,
+            None => return (Self::method_not_allowed(), None)
+        };
+        if !has_bearer_token(req, token) {
+            return (Self::unauthorized(), None)
+        }
+        match resources.process_named_request(req, config.name.as_deref()) {
+            Some(res) => res,
+            None => (Self::not_found(), None)
+        }
+    }
+
+    /// Creates the access log entry for a request.
+    #[cfg(feature = "http-server")]
+    fn access_entry(
+        req: &Request,
+        client: SocketAddr,
+        response: &Response,
+        target: Option<Arc<str>>,
+    ) -> AccessEntry {
+        let header = |name: HeaderName| {
+            req.headers().get(name).and_then(|value| value.to_str().ok())
+        };
+        AccessEntry::new(
+            client,
+            req.method().as_str(),
+            req.uri().path_and_query().map(|path| {
+                path.as_str()
+            }).unwrap_or("/"),
+            format!("{:?}", req.version()),
+        ).response(
+            response.status().as_u16(),
+            response.body().size_hint().exact(),
+        ).headers(
+            header(REFERER), header(USER_AGENT)
+        ).target(target.as_deref())
+    }
+
+    /// Produces the response for a call to the `/healthz` endpoint.
+    ///
+    /// Since we are obviously running if we can answer, this always
+    /// succeeds.
+    #[cfg(feature = "http-server")]
+    fn healthz() -> Response {
+        ResponseBuilder::ok()
+        .content_type(ContentType::TEXT)
+        .body("ok")
     }
 
     /// Produces the response for a call to the `/metrics` endpoint.
+    #[cfg(feature = "http-server")]
     fn metrics(metrics: &metrics::Collection) -> Response {
         ResponseBuilder::ok()
         .content_type(ContentType::PROMETHEUS)
@@ -173,15 +614,30 @@ impl Server {
     }
 
     /// Produces the response for a call to the `/status` endpoint.
-    fn status(metrics: &metrics::Collection) -> Response {
+    ///
+    /// The output can be limited via query parameters as described for
+    /// [`metrics::Selection::from_query`].
+    #[cfg(feature = "http-server")]
+    fn status(req: &Request, metrics: &metrics::Collection) -> Response {
+        let selection = match metrics::Selection::from_query(
+            req.uri().query()
+        ) {
+            Ok(selection) => selection,
+            Err(err) => {
+                return ResponseBuilder::bad_request()
+                .content_type(ContentType::TEXT)
+                .body(err)
+            }
+        };
         ResponseBuilder::ok()
         .content_type(ContentType::TEXT)
         .body(
-            metrics.assemble(metrics::OutputFormat::Plain)
+            metrics.assemble_selected(metrics::OutputFormat::Plain, selection)
         )
     }
 
     /// Produces the response for a Method Not Allowed error.
+    #[cfg(feature = "http-server")]
     fn method_not_allowed() -> Response {
         ResponseBuilder::method_not_allowed()
         .content_type(ContentType::TEXT)
@@ -189,11 +645,109 @@ impl Server {
     }
 
     /// Produces the response for a Not Found error.
+    #[cfg(feature = "http-server")]
     fn not_found() -> Response {
         ResponseBuilder::not_found()
         .content_type(ContentType::TEXT)
         .body("Not Found")
     }
+
+    /// Produces the response for an Unauthorized error.
+    #[cfg(feature = "http-server")]
+    fn unauthorized() -> Response {
+        ResponseBuilder::unauthorized()
+        .header("WWW-Authenticate", "Bearer")
+        .content_type(ContentType::TEXT)
+        .body("Unauthorized")
+    }
+}
+
+
+//------------ NamedServer ---------------------------------------------------
+
+/// The configuration of an additional named HTTP server.
+#[derive(Clone, Deserialize)]
+pub struct NamedServer {
+    /// The socket addresses to listen on.
+    listen: Vec<SocketAddr>,
+
+    /// Whether to provide the management endpoints.
+    ///
+    /// These are the `/healthz`, `/metrics`, and `/status` endpoints.
+    #[serde(default = "NamedServer::default_management")]
+    management: bool,
+
+    /// The path to the server certificate to use for TLS.
+    certificate: Option<PathBuf>,
+
+    /// The path to the private key to use for TLS.
+    key: Option<PathBuf>,
+}
+
+impl NamedServer {
+    /// The default for the management option.
+    fn default_management() -> bool {
+        true
+    }
+
+    /// Creates the TLS acceptor for the server if TLS is configured.
+    fn tls_acceptor(
+        &self, name: &str
+    ) -> Result<Option<TlsAcceptor>, ExitError> {
+        match (self.certificate.as_ref(), self.key.as_ref()) {
+            #[cfg(feature = "tls")]
+            (Some(cert), Some(key)) => {
+                Ok(Some(TlsAcceptor::from(Arc::new(
+                    create_server_config(
+                        &format!("HTTP server {}", name), key, cert
+                    )?
+                ))))
+            }
+            #[cfg(not(feature = "tls"))]
+            (Some(_), Some(_)) => {
+                error!(
+                    "Fatal: HTTP server '{}' uses TLS which is not \
+                     supported by this build.",
+                    name
+                );
+                Err(ExitError::default())
+            }
+            (None, None) => Ok(None),
+            _ => {
+                error!(
+                    "Fatal: HTTP server '{}' needs both 'certificate' \
+                     and 'key' for TLS.",
+                    name
+                );
+                Err(ExitError::default())
+            }
+        }
+    }
+}
+
+
+//------------ ListenerConfig ------------------------------------------------
+
+/// The configuration shared by all listeners of a server.
+#[cfg(feature = "http-server")]
+struct ListenerConfig {
+    /// The name of the server or `None` for the default server.
+    name: Option<Arc<str>>,
+
+    /// Whether to provide the management endpoints.
+    management: bool,
+
+    /// The TLS acceptor if the server uses TLS.
+    tls: Option<TlsAcceptor>,
+
+    /// The time after which idle connections are closed.
+    idle_timeout: Option<Duration>,
+
+    /// The bearer token required for the admin endpoints if enabled.
+    admin_token: Option<Secret>,
+
+    /// The authentication required for the metrics endpoints.
+    metrics_auth: HttpAuth,
 }
 
 
@@ -208,6 +762,9 @@ impl Server {
 /// Such new resources can be registered with the [`register`][Self::register]
 /// method. An HTTP request can be processed using the
 /// [`process_request`][Self::process_request] method.
+///
+/// Each resource is registered for a particular server, given by its name
+/// or `None` for the default server, and is only available there.
 #[derive(Clone, Default)]
 pub struct Resources {
     /// The currently registered sources.
@@ -226,8 +783,17 @@ impl Resources {
     /// Registers a new processor with the collection.
     ///
     /// The processor is given as a weak pointer so that it gets dropped
-    /// when the owning component terminates.
-    pub fn register(&self, process: Weak<dyn ProcessRequest>) {
+    /// when the owning component terminates. The name of the server the
+    /// resource should be available on is given via `server`. If this is
+    /// `None`, the default server is used. The name of the component
+    /// providing the resource, if any, is given via `name`. It is used for
+    /// the access log.
+    pub fn register(
+        &self,
+        process: Weak<dyn ProcessRequest>,
+        server: Option<Arc<str>>,
+        name: Option<Arc<str>>,
+    ) {
         let lock = self.register.lock().unwrap();
         let old_sources = self.sources.load();
         let mut new_sources = Vec::new();
@@ -237,7 +803,7 @@ impl Resources {
             }
         }
         new_sources.push(
-            RegisteredResource { process }
+            RegisteredResource { server, name, process }
         );
         self.sources.store(new_sources.into());
         drop(lock);
@@ -245,16 +811,34 @@ impl Resources {
 
     /// Processes an HTTP request.
     ///
+    /// Only resources registered for the server given via `server` are
+    /// considered.
+    ///
     /// Returns some response if any of the registered processors actually
     /// processed the particular request or `None` otherwise.
     pub fn process_request(
-        &self, request: &Request,
+        &self, request: &Request, server: Option<&str>,
     ) -> Option<Response> {
+        self.process_named_request(
+            request, server
+        ).map(|(response, _)| response)
+    }
+
+    /// Processes an HTTP request and returns who processed it.
+    ///
+    /// This is like [`process_request`][Self::process_request] but also
+    /// returns the name the processing resource was registered with.
+    pub fn process_named_request(
+        &self, request: &Request, server: Option<&str>,
+    ) -> Option<(Response, Option<Arc<str>>)> {
         let sources = self.sources.load();
         for item in sources.iter() {
+            if item.server.as_deref() != server {
+                continue
+            }
             if let Some(process) = item.process.upgrade() {
                 if let Some(response) = process.process_request(request) {
-                    return Some(response)
+                    return Some((response, item.name.clone()))
                 }
             }
         }
@@ -276,6 +860,12 @@ impl fmt::Debug for Resources {
 /// All information on a resource registered with a collection.
 #[derive(Clone)]
 struct RegisteredResource {
+    /// The name of the server the resource is available on.
+    server: Option<Arc<str>>,
+
+    /// The name of the component providing the resource.
+    name: Option<Arc<str>>,
+
     /// A weak pointer to the resource’s processor.
     process: Weak<dyn ProcessRequest>,
 }
@@ -317,6 +907,332 @@ where F: Fn(&Request) -> Option<Response> + Sync + Send {
 
 pub type Request = hyper::Request<hyper::body::Incoming>;
 
+/// Returns the address of the client that sent a request.
+///
+/// This is available for all requests received by the HTTP server.
+pub fn client_addr(request: &Request) -> Option<SocketAddr> {
+    request.extensions().get::<ClientAddr>().map(|addr| addr.0)
+}
+
+/// Returns whether a GET request for a path needs the metrics auth.
+///
+/// This is the case for the metrics, the status, and all API endpoints.
+fn is_protected_path(path: &str) -> bool {
+    matches!(path, "/metrics" | "/status") || path.starts_with("/api/")
+}
+
+/// Returns whether a request carries the given bearer token.
+pub fn has_bearer_token<B>(
+    request: &hyper::Request<B>, token: &Secret
+) -> bool {
+    let value = match request.headers().get(AUTHORIZATION).and_then(|value| {
+        value.to_str().ok()
+    }) {
+        Some(value) => value,
+        None => return false,
+    };
+    let value = match value.strip_prefix("Bearer ") {
+        Some(value) => value.trim(),
+        None => return false,
+    };
+
+    secret_eq(value.as_bytes(), token.expose().as_bytes())
+}
+
+/// Compares a value provided by a client with a secret.
+///
+/// All bytes are compared so the time taken doesn’t reveal the secret.
+fn secret_eq(value: &[u8], secret: &[u8]) -> bool {
+    value.len() == secret.len() && value.iter().zip(secret).fold(
+        0, |res, (left, right)| res | (left ^ right)
+    ) == 0
+}
+
+/// The address of the client stored in the extensions of a request.
+#[derive(Clone, Copy, Debug)]
+struct ClientAddr(SocketAddr);
+
+/// Returns the body of a request.
+///
+/// This is available for all POST requests received by the HTTP server.
+pub fn request_body(request: &Request) -> Option<&Bytes> {
+    request.extensions().get::<RequestBody>().map(|body| &body.0)
+}
+
+/// The body of a request stored in the extensions of a request.
+#[derive(Clone, Debug)]
+struct RequestBody(Bytes);
+
+
+//------------ HttpAuth ------------------------------------------------------
+
+/// The authentication required for accessing a resource.
+///
+/// A request is permitted if it carries the bearer token or the credentials
+/// of one of the users via basic authentication. Users can be given
+/// directly or via an htpasswd file. If none of these are configured, all
+/// requests are permitted.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpAuth {
+    /// The bearer token permitting access.
+    token: Option<Secret>,
+
+    /// The users permitted access via basic authentication.
+    #[serde(default)]
+    users: Vec<HttpUser>,
+
+    /// The users from an htpasswd file.
+    htpasswd: Option<Htpasswd>,
+}
+
+impl HttpAuth {
+    /// Returns whether authentication is required at all.
+    pub fn is_required(&self) -> bool {
+        self.token.is_some() || self.all_users().next().is_some()
+    }
+
+    /// Checks whether a request is permitted.
+    ///
+    /// If it isn’t, returns the Unauthorized response to send instead. It
+    /// is boxed to keep the result small.
+    pub fn check<B>(
+        &self, request: &hyper::Request<B>
+    ) -> Result<(), Box<Response>> {
+        if self.permits(request) {
+            return Ok(())
+        }
+        let mut response = ResponseBuilder::unauthorized();
+        if self.token.is_some() {
+            response = response.header("WWW-Authenticate", "Bearer");
+        }
+        if self.all_users().next().is_some() {
+            response = response.header(
+                "WWW-Authenticate",
+                "Basic realm=\"rtrtr\", charset=\"UTF-8\""
+            );
+        }
+        Err(Box::new(
+            response.content_type(ContentType::TEXT).body("Unauthorized")
+        ))
+    }
+
+    /// Returns whether a request is permitted.
+    fn permits<B>(&self, request: &hyper::Request<B>) -> bool {
+        if !self.is_required() {
+            return true
+        }
+        if let Some(token) = self.token.as_ref() {
+            if has_bearer_token(request, token) {
+                return true
+            }
+        }
+        let (name, password) = match basic_credentials(request) {
+            Some(credentials) => credentials,
+            None => return false
+        };
+
+        // Check all users so that the time taken doesn’t reveal which
+        // names exist.
+        let mut res = false;
+        for user in self.all_users() {
+            if
+                secret_eq(name.as_bytes(), user.name.as_bytes())
+                && user.password.verify(&password)
+            {
+                res = true
+            }
+        }
+        res
+    }
+
+    /// Returns an iterator over all configured users.
+    fn all_users(&self) -> impl Iterator<Item = &HttpUser> + '_ {
+        self.users.iter().chain(
+            self.htpasswd.iter().flat_map(|file| file.0.iter())
+        )
+    }
+}
+
+
+//------------ HttpUser ------------------------------------------------------
+
+/// A user permitted access via basic authentication.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "HttpUserConfig")]
+struct HttpUser {
+    /// The user name.
+    name: String,
+
+    /// The password.
+    password: Password,
+}
+
+impl TryFrom<HttpUserConfig> for HttpUser {
+    type Error = String;
+
+    fn try_from(config: HttpUserConfig) -> Result<Self, Self::Error> {
+        let password = match (config.password, config.password_hash) {
+            (Some(password), None) => Password::Plain(password),
+            (None, Some(hash)) => {
+                Password::from_hash(hash.expose()).map_err(|err| {
+                    format!("user '{}': {}", config.name, err)
+                })?
+            }
+            _ => {
+                return Err(format!(
+                    "user '{}': exactly one of 'password' and \
+                     'password-hash' is required",
+                    config.name
+                ))
+            }
+        };
+        Ok(HttpUser { name: config.name, password })
+    }
+}
+
+/// The configuration of a user.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HttpUserConfig {
+    /// The user name.
+    name: String,
+
+    /// The password in plain text.
+    password: Option<Secret>,
+
+    /// The password as a bcrypt or Argon2 hash.
+    #[serde(rename = "password-hash")]
+    password_hash: Option<Secret>,
+}
+
+/// Returns the user name and password of basic authentication.
+fn basic_credentials<B>(
+    request: &hyper::Request<B>
+) -> Option<(String, String)> {
+    let value = request.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let value = value.strip_prefix("Basic ")?.trim();
+    let value = String::from_utf8(BASE64.decode(value).ok()?).ok()?;
+    let (name, password) = value.split_once(':')?;
+    Some((name.into(), password.into()))
+}
+
+
+//------------ Password ------------------------------------------------------
+
+/// The password of a user.
+///
+/// All variants keep the value in a [`Secret`] so it doesn’t end up in
+/// debug output.
+#[derive(Clone, Debug)]
+enum Password {
+    /// The password in plain text.
+    Plain(Secret),
+
+    /// A bcrypt hash of the password.
+    Bcrypt(Secret),
+
+    /// An Argon2 hash of the password in PHC string format.
+    Argon2(Secret),
+}
+
+impl Password {
+    /// Creates a password from a hash.
+    ///
+    /// The hash can be a bcrypt hash in the format used by htpasswd, i.e.,
+    /// starting with `$2a$`, `$2b$`, or `$2y$`, or an Argon2 hash in PHC
+    /// string format.
+    fn from_hash(hash: &str) -> Result<Self, String> {
+        if ["$2a$", "$2b$", "$2y$"].iter().any(|tag| hash.starts_with(tag)) {
+            hash.parse::<bcrypt::HashParts>().map_err(|err| {
+                format!("invalid bcrypt hash: {}", err)
+            })?;
+            Ok(Password::Bcrypt(hash.into()))
+        }
+        else if hash.starts_with("$argon2") {
+            argon2::PasswordHash::new(hash).map_err(|err| {
+                format!("invalid Argon2 hash: {}", err)
+            })?;
+            Ok(Password::Argon2(hash.into()))
+        }
+        else {
+            Err("unsupported password hash, use bcrypt or Argon2".into())
+        }
+    }
+
+    /// Returns whether the given password matches.
+    ///
+    /// All comparisons take the same time for passwords of the same
+    /// length no matter how much of them matches.
+    fn verify(&self, password: &str) -> bool {
+        match *self {
+            Password::Plain(ref secret) => {
+                secret_eq(password.as_bytes(), secret.expose().as_bytes())
+            }
+            Password::Bcrypt(ref hash) => {
+                bcrypt::verify(password, hash.expose()).unwrap_or(false)
+            }
+            Password::Argon2(ref hash) => {
+                use argon2::PasswordVerifier;
+
+                let hash = match argon2::PasswordHash::new(hash.expose()) {
+                    Ok(hash) => hash,
+                    Err(_) => return false
+                };
+                argon2::Argon2::default().verify_password(
+                    password.as_bytes(), &hash
+                ).is_ok()
+            }
+        }
+    }
+}
+
+
+//------------ Htpasswd ------------------------------------------------------
+
+/// The users listed in an htpasswd file.
+///
+/// Each non-empty line of the file that doesn’t start with `#` contains a
+/// user name and a bcrypt or Argon2 password hash separated by a colon.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "ConfigPath")]
+struct Htpasswd(Vec<HttpUser>);
+
+impl Htpasswd {
+    /// Parses the content of an htpasswd file.
+    fn parse(data: &str) -> Result<Self, String> {
+        let mut res = Vec::new();
+        for (idx, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+            let (name, hash) = line.split_once(':').ok_or_else(|| {
+                format!("line {}: expected 'name:hash'", idx + 1)
+            })?;
+            let password = Password::from_hash(hash).map_err(|err| {
+                format!("line {}: {}", idx + 1, err)
+            })?;
+            res.push(HttpUser { name: name.into(), password });
+        }
+        Ok(Htpasswd(res))
+    }
+}
+
+impl TryFrom<ConfigPath> for Htpasswd {
+    type Error = String;
+
+    fn try_from(path: ConfigPath) -> Result<Self, Self::Error> {
+        let path: &Path = path.as_ref();
+        let data = fs::read_to_string(path).map_err(|err| {
+            format!("cannot read htpasswd file {}: {}", path.display(), err)
+        })?;
+        Self::parse(&data).map_err(|err| {
+            format!("htpasswd file {}: {}", path.display(), err)
+        })
+    }
+}
+
 
 //------------ Response ------------------------------------------------------
 
@@ -361,6 +1277,11 @@ impl ResponseBuilder {
         Self::new(StatusCode::NOT_MODIFIED)
     }
 
+    /// Creates a new builder for an Unauthorized response.
+    pub fn unauthorized() -> Self {
+        Self::new(StatusCode::UNAUTHORIZED)
+    }
+
     /// Creates a new builder for a Method Not Allowed response.
     pub fn method_not_allowed() -> Self {
         Self::new(StatusCode::METHOD_NOT_ALLOWED)
@@ -378,6 +1299,13 @@ impl ResponseBuilder {
         }
     }
 
+    /// Adds a header.
+    pub fn header(self, name: &str, value: &str) -> Self {
+        ResponseBuilder {
+            builder: self.builder.header(name, value)
+        }
+    }
+
     /// Adds the ETag header.
     pub fn etag(self, etag: &str) -> Self {
         ResponseBuilder {
@@ -445,6 +1373,7 @@ impl ResponseBuilder {
 pub struct ContentType(&'static [u8]);
 
 impl ContentType {
+    pub const CBOR: ContentType = ContentType(b"application/cbor");
     pub const CSV: ContentType = ContentType(
         b"text/csv;charset=utf-8;header=present"
     );
@@ -457,33 +1386,242 @@ impl ContentType {
     pub fn external(value: &'static [u8]) -> Self {
         ContentType(value)
     }
+
+    pub fn as_bytes(&self) -> &'static [u8] {
+        self.0
+    }
+}
+
+
+//------------ HttpMetrics ---------------------------------------------------
+
+/// The metrics of the HTTP server.
+#[cfg(feature = "http-server")]
+#[derive(Debug, Default)]
+struct HttpMetrics {
+    /// The currently open connections.
+    connections: Mutex<Slab<Arc<ConnectionState>>>,
+
+    /// The metrics of the accept loops of all listeners.
+    accept: Arc<AcceptMetrics>,
+
+    /// The number of requests received since startup.
+    requests: AtomicU64,
+
+    /// The number of connections closed because they were idle.
+    reaped: AtomicU64,
+}
+
+#[cfg(feature = "http-server")]
+impl HttpMetrics {
+    /// Registers a newly accepted connection.
+    fn open(self: &Arc<Self>) -> (usize, Arc<ConnectionState>) {
+        let conn = Arc::new(ConnectionState {
+            metrics: self.clone(),
+            last_active: AtomicCell::new(Instant::now()),
+            requests: AtomicU64::new(0),
+        });
+        let key = self.connections.lock().unwrap().insert(conn.clone());
+        (key, conn)
+    }
+
+    /// Removes a closed connection.
+    fn close(&self, key: usize) {
+        self.connections.lock().unwrap().remove(key);
+    }
+}
+
+#[cfg(feature = "http-server")]
+impl HttpMetrics {
+    const OPEN_METRIC: metrics::Metric = metrics::Metric::new(
+        "http_connections", "number of currently open HTTP connections",
+        metrics::MetricType::Gauge, metrics::MetricUnit::Total,
+    );
+    const MAX_IDLE_METRIC: metrics::Metric = metrics::Metric::new(
+        "http_max_idle",
+        "longest time a currently open HTTP connection has been idle",
+        metrics::MetricType::Gauge, metrics::MetricUnit::Second,
+    );
+    const MAX_REQUESTS_METRIC: metrics::Metric = metrics::Metric::new(
+        "http_max_connection_requests",
+        "largest number of requests served by a currently open connection",
+        metrics::MetricType::Gauge, metrics::MetricUnit::Total,
+    );
+    const ACCEPTED_METRIC: metrics::Metric = metrics::Metric::new(
+        "http_accepted_connections",
+        "number of HTTP connections accepted since startup",
+        metrics::MetricType::Counter, metrics::MetricUnit::Total,
+    );
+    const ACCEPT_ERRORS_METRIC: metrics::Metric = metrics::Metric::new(
+        "http_accept_errors",
+        "number of failed attempts to accept an HTTP connection",
+        metrics::MetricType::Counter, metrics::MetricUnit::Total,
+    );
+    const ACCEPT_PAUSES_METRIC: metrics::Metric = metrics::Metric::new(
+        "http_accept_pauses",
+        "number of times accepting was paused for lack of resources",
+        metrics::MetricType::Counter, metrics::MetricUnit::Total,
+    );
+    const DENIED_METRIC: metrics::Metric = metrics::Metric::new(
+        "http_denied_connections",
+        "number of HTTP connections denied because of their address",
+        metrics::MetricType::Counter, metrics::MetricUnit::Total,
+    );
+    const REQUESTS_METRIC: metrics::Metric = metrics::Metric::new(
+        "http_requests", "number of HTTP requests received since startup",
+        metrics::MetricType::Counter, metrics::MetricUnit::Total,
+    );
+    const REAPED_METRIC: metrics::Metric = metrics::Metric::new(
+        "http_idle_closed",
+        "number of HTTP connections closed because they were idle",
+        metrics::MetricType::Counter, metrics::MetricUnit::Total,
+    );
+}
+
+#[cfg(feature = "http-server")]
+impl metrics::Source for HttpMetrics {
+    fn append(&self, _unit_name: &str, target: &mut metrics::Target)  {
+        let now = Instant::now();
+        let (open, max_idle, max_requests) = {
+            let connections = self.connections.lock().unwrap();
+            let mut max_idle = Duration::ZERO;
+            let mut max_requests = 0;
+            for (_, conn) in connections.iter() {
+                max_idle = max_idle.max(
+                    now.saturating_duration_since(conn.last_active())
+                );
+                max_requests = max_requests.max(
+                    conn.requests.load(Ordering::Relaxed)
+                );
+            }
+            (connections.len(), max_idle, max_requests)
+        };
+        target.append_simple(&Self::OPEN_METRIC, None, open);
+        target.append_simple(
+            &Self::MAX_IDLE_METRIC, None, max_idle.as_secs()
+        );
+        target.append_simple(&Self::MAX_REQUESTS_METRIC, None, max_requests);
+        target.append_simple(
+            &Self::ACCEPTED_METRIC, None, self.accept.accepted()
+        );
+        target.append_simple(
+            &Self::ACCEPT_ERRORS_METRIC, None, self.accept.errors()
+        );
+        target.append_simple(
+            &Self::ACCEPT_PAUSES_METRIC, None, self.accept.pauses()
+        );
+        target.append_simple(
+            &Self::DENIED_METRIC, None, self.accept.denied()
+        );
+        target.append_simple(
+            &Self::REQUESTS_METRIC, None,
+            self.requests.load(Ordering::Relaxed)
+        );
+        target.append_simple(
+            &Self::REAPED_METRIC, None,
+            self.reaped.load(Ordering::Relaxed)
+        );
+    }
+}
+
+
+//------------ ConnectionState -----------------------------------------------
+
+/// The state of a single open HTTP connection.
+#[cfg(feature = "http-server")]
+#[derive(Debug)]
+struct ConnectionState {
+    /// The metrics of the server the connection belongs to.
+    metrics: Arc<HttpMetrics>,
+
+    /// The time of the last read or write on the connection.
+    last_active: AtomicCell<Instant>,
+
+    /// The number of requests received on the connection.
+    requests: AtomicU64,
+}
+
+#[cfg(feature = "http-server")]
+impl ConnectionState {
+    /// Returns the time of the last activity on the connection.
+    fn last_active(&self) -> Instant {
+        self.last_active.load()
+    }
+
+    /// Marks the connection as active right now.
+    fn touch(&self) {
+        self.last_active.store(Instant::now())
+    }
+
+    /// Records that a request has been received on the connection.
+    fn request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.metrics.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that the connection has been closed for being idle.
+    fn reaped(&self) {
+        self.metrics.reaped.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 
 //------------ Wrapped sockets -----------------------------------------------
 
 /// A TCP stream wrapped for use with Hyper.
+///
+/// The wrapper keeps track of the activity on the connection and removes
+/// the connection from the server’s metrics when dropped.
+#[cfg(feature = "http-server")]
 struct HttpStream {
-    sock: TcpStream,
+    sock: MaybeTlsTcpStream,
+    key: usize,
+    conn: Arc<ConnectionState>,
+}
+
+#[cfg(feature = "http-server")]
+impl HttpStream {
+    /// Creates a new stream, registering it with the metrics.
+    fn new(sock: MaybeTlsTcpStream, metrics: Arc<HttpMetrics>) -> Self {
+        let (key, conn) = metrics.open();
+        HttpStream { sock, key, conn }
+    }
+}
+
+#[cfg(feature = "http-server")]
+impl Drop for HttpStream {
+    fn drop(&mut self) {
+        self.conn.metrics.close(self.key)
+    }
 }
 
+#[cfg(feature = "http-server")]
 impl AsyncRead for HttpStream {
     fn poll_read(
         mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf
     ) -> Poll<Result<(), io::Error>> {
         let sock = &mut self.sock;
         pin_mut!(sock);
-        sock.poll_read(cx, buf)
+        let res = sock.poll_read(cx, buf);
+        if res.is_ready() {
+            self.conn.touch();
+        }
+        res
     }
 }
 
+#[cfg(feature = "http-server")]
 impl AsyncWrite for HttpStream {
     fn poll_write(
         mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]
     ) -> Poll<Result<usize, io::Error>> {
         let sock = &mut self.sock;
         pin_mut!(sock);
-        sock.poll_write(cx, buf)
+        let res = sock.poll_write(cx, buf);
+        if res.is_ready() {
+            self.conn.touch();
+        }
+        res
     }
 
     fn poll_flush(
@@ -503,3 +1641,141 @@ impl AsyncWrite for HttpStream {
     }
 }
 
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(path: &str, auth: Option<&str>) -> hyper::Request<()> {
+        let mut request = hyper::Request::builder().uri(path);
+        if let Some(auth) = auth {
+            request = request.header(AUTHORIZATION, auth);
+        }
+        request.body(()).unwrap()
+    }
+
+    fn basic(name: &str, password: &str) -> String {
+        format!("Basic {}", BASE64.encode(format!("{}:{}", name, password)))
+    }
+
+    #[test]
+    fn protected_paths() {
+        assert!(is_protected_path("/metrics"));
+        assert!(is_protected_path("/status"));
+        assert!(is_protected_path("/api/v1/status"));
+        assert!(is_protected_path("/api/v1/units/vrps/diffs"));
+        assert!(!is_protected_path("/healthz"));
+        assert!(!is_protected_path("/json"));
+    }
+
+    #[test]
+    fn auth_none() {
+        let auth = HttpAuth::default();
+        assert!(!auth.is_required());
+        assert!(auth.check(&request("/api/v1/status", None)).is_ok());
+    }
+
+    #[test]
+    fn auth_token_and_users() {
+        let bcrypt_hash = bcrypt::hash("bcrypt-pw", 4).unwrap();
+        let argon2_hash = {
+            use argon2::PasswordHasher;
+            use argon2::password_hash::SaltString;
+
+            argon2::Argon2::default().hash_password(
+                b"argon2-pw", &SaltString::from_b64("c2FsdHNhbHQ").unwrap()
+            ).unwrap().to_string()
+        };
+        let auth: HttpAuth = toml::from_str(&format!(r#"
+            token = "s3cr3t"
+            users = [
+                {{ name = "plain", password = "plain-pw" }},
+                {{ name = "bcrypt", password-hash = "{}" }},
+                {{ name = "argon2", password-hash = "{}" }},
+            ]
+        "#, bcrypt_hash, argon2_hash)).unwrap();
+        assert!(auth.is_required());
+
+        for path in ["/metrics", "/api/v1/status", "/api/v1/units/a/diffs"] {
+            // Accepted requests.
+            assert!(
+                auth.check(&request(path, Some("Bearer s3cr3t"))).is_ok()
+            );
+            for (name, password) in [
+                ("plain", "plain-pw"), ("bcrypt", "bcrypt-pw"),
+                ("argon2", "argon2-pw")
+            ] {
+                assert!(
+                    auth.check(
+                        &request(path, Some(&basic(name, password)))
+                    ).is_ok()
+                );
+            }
+
+            // Rejected requests.
+            for auth_header in [
+                None,
+                Some("Bearer s3cr3"),
+                Some("Bearer s3cr3t0"),
+                Some("s3cr3t"),
+                Some(basic("plain", "bcrypt-pw").as_str()),
+                Some(basic("bcrypt", "plain-pw").as_str()),
+                Some(basic("argon2", "argon2-PW").as_str()),
+                Some(basic("nobody", "plain-pw").as_str()),
+                Some("Basic not-base64"),
+            ] {
+                let response = auth.check(
+                    &request(path, auth_header)
+                ).unwrap_err();
+                assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            }
+        }
+    }
+
+    #[test]
+    fn bad_users() {
+        assert!(toml::from_str::<HttpAuth>(r#"
+            users = [ { name = "both", password = "a", password-hash = "b" } ]
+        "#).is_err());
+        assert!(toml::from_str::<HttpAuth>(r#"
+            users = [ { name = "none" } ]
+        "#).is_err());
+        assert!(toml::from_str::<HttpAuth>(r#"
+            users = [ { name = "md5", password-hash = "$apr1$abc$def" } ]
+        "#).is_err());
+        assert!(toml::from_str::<HttpAuth>(r#"
+            users = [ { name = "broken", password-hash = "$2y$05$short" } ]
+        "#).is_err());
+    }
+
+    #[test]
+    fn htpasswd() {
+        let hash = bcrypt::hash("htpasswd-pw", 4).unwrap();
+        let users = Htpasswd::parse(&format!(
+            "# Comment\n\nalice:{}\n", hash
+        )).unwrap();
+        assert_eq!(users.0.len(), 1);
+        let auth = HttpAuth {
+            htpasswd: Some(users),
+            .. Default::default()
+        };
+        assert!(auth.is_required());
+        assert!(
+            auth.check(
+                &request("/metrics", Some(&basic("alice", "htpasswd-pw")))
+            ).is_ok()
+        );
+        assert!(
+            auth.check(
+                &request("/metrics", Some(&basic("alice", "wrong")))
+            ).is_err()
+        );
+
+        assert!(Htpasswd::parse("alice").is_err());
+        assert!(Htpasswd::parse("alice:plain").is_err());
+        assert!(Htpasswd::parse("alice:{SHA}abcdef").is_err());
+    }
+}
@@ -23,10 +23,15 @@
 #![allow(renamed_and_removed_lints)]
 #![allow(clippy::unknown_clippy_lints)]
 
+pub mod cache;
 pub mod comms;
 pub mod config;
+pub mod eval;
+pub mod events;
 pub mod formats;
+pub mod handoff;
 pub mod http;
+pub mod log;
 pub mod manager;
 pub mod metrics;
 pub mod payload;
@@ -0,0 +1,560 @@
+//! Logging beyond the main log.
+//!
+//! Regular logging is provided by the `daemonbase` crate and configured via
+//! its logging config that is part of [`Config`](crate::config::Config).
+//!
+//! This module provides the audit log: a separate file that records every
+//! administrative action taken through RTRTR’s interfaces. Each action is
+//! written as a single line containing a JSON object with the time of the
+//! action, the action itself, the address it was requested from, the
+//! authenticated identity of the requester if any, and the state before and
+//! after the action.
+//!
+//! It also provides the access log of the HTTP server which records every
+//! request served either via the regular log or in a file of its own.
+
+use std::{fmt, io};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use chrono::{DateTime, Utc};
+use daemonbase::error::Failed;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+
+//------------ AuditConfig ---------------------------------------------------
+
+/// The configuration of the audit log.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AuditConfig {
+    /// The path to the audit log file.
+    ///
+    /// If this is `None`, admin actions are not recorded.
+    #[serde(rename = "audit-log-file")]
+    file: Option<PathBuf>,
+}
+
+impl AuditConfig {
+    /// Opens the audit log described by the config.
+    ///
+    /// New entries are always appended to an existing file.
+    pub fn open(&self) -> Result<AuditLog, Failed> {
+        let path = match self.file.as_ref() {
+            Some(path) => path,
+            None => return Ok(AuditLog::default()),
+        };
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => {
+                Ok(AuditLog { file: Some(Arc::new(Mutex::new(file))) })
+            }
+            Err(err) => {
+                error!(
+                    "Failed to open audit log file '{}': {}",
+                    path.display(), err
+                );
+                Err(Failed)
+            }
+        }
+    }
+}
+
+
+//------------ AuditLog ------------------------------------------------------
+
+/// The audit log.
+///
+/// Values of this type can be cloned cheaply and all clones write to the
+/// same file. If no audit log file is configured, entries are silently
+/// dropped.
+#[derive(Clone, Debug, Default)]
+pub struct AuditLog {
+    /// The file to append entries to.
+    file: Option<Arc<Mutex<File>>>,
+}
+
+impl AuditLog {
+    /// Records an entry in the audit log.
+    ///
+    /// Failing to write the entry is logged as an error but otherwise
+    /// ignored.
+    pub fn record(&self, entry: AuditEntry) {
+        let file = match self.file.as_ref() {
+            Some(file) => file,
+            None => return,
+        };
+        let res = Self::write_entry(&mut file.lock().unwrap(), &entry);
+        if let Err(err) = res {
+            error!("Failed to write to audit log: {}", err);
+        }
+    }
+
+    /// Writes an entry to the file.
+    fn write_entry(file: &mut File, entry: &AuditEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        file.flush()
+    }
+}
+
+
+//------------ AuditEntry ----------------------------------------------------
+
+/// A single entry of the audit log.
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditEntry {
+    /// The time the action was taken in RFC 3339 format.
+    timestamp: String,
+
+    /// A short identifier of the action.
+    action: String,
+
+    /// The address the action was requested from if available.
+    source: Option<SocketAddr>,
+
+    /// The authenticated identity of the requester if available.
+    identity: Option<String>,
+
+    /// The state before the action.
+    before: serde_json::Value,
+
+    /// The state after the action.
+    after: serde_json::Value,
+}
+
+impl AuditEntry {
+    /// Creates a new entry for the given action taken right now.
+    pub fn new(action: impl Into<String>) -> Self {
+        AuditEntry {
+            timestamp: Utc::now().to_rfc3339(),
+            action: action.into(),
+            source: None,
+            identity: None,
+            before: serde_json::Value::Null,
+            after: serde_json::Value::Null,
+        }
+    }
+
+    /// Sets the address the action was requested from.
+    pub fn source(mut self, source: Option<SocketAddr>) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Sets the authenticated identity of the requester.
+    pub fn identity(mut self, identity: Option<impl Into<String>>) -> Self {
+        self.identity = identity.map(Into::into);
+        self
+    }
+
+    /// Sets the state before and after the action.
+    pub fn state(
+        mut self,
+        before: impl Into<serde_json::Value>,
+        after: impl Into<serde_json::Value>,
+    ) -> Self {
+        self.before = before.into();
+        self.after = after.into();
+        self
+    }
+}
+
+
+
+//------------ AccessLogConfig -----------------------------------------------
+
+/// The configuration of the HTTP access log.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AccessLogConfig {
+    /// Whether to record served HTTP requests.
+    #[serde(default, rename = "http-access-log")]
+    enabled: bool,
+
+    /// The format of the access log entries.
+    #[serde(default, rename = "http-access-log-format")]
+    format: AccessLogFormat,
+
+    /// The path to the access log file.
+    ///
+    /// If this is `None`, entries are written to the regular log.
+    #[serde(rename = "http-access-log-file")]
+    file: Option<PathBuf>,
+
+    /// The names of the targets whose requests should be recorded.
+    ///
+    /// If this is `None`, requests for all targets are recorded.
+    #[serde(rename = "http-access-log-include")]
+    include: Option<Vec<String>>,
+
+    /// The names of the targets whose requests should not be recorded.
+    #[serde(default, rename = "http-access-log-exclude")]
+    exclude: Vec<String>,
+}
+
+impl AccessLogConfig {
+    /// Opens the access log described by the config.
+    ///
+    /// New entries are always appended to an existing file.
+    pub fn open(&self) -> Result<AccessLog, Failed> {
+        if !self.enabled {
+            return Ok(AccessLog::default())
+        }
+        let file = match self.file.as_ref() {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true)
+                    .open(path);
+                match file {
+                    Ok(file) => Some(Mutex::new(file)),
+                    Err(err) => {
+                        error!(
+                            "Failed to open access log file '{}': {}",
+                            path.display(), err
+                        );
+                        return Err(Failed)
+                    }
+                }
+            }
+            None => None,
+        };
+        Ok(AccessLog {
+            inner: Some(Arc::new(AccessLogInner {
+                format: self.format,
+                file,
+                include: self.include.as_ref().map(|include| {
+                    include.iter().cloned().collect()
+                }),
+                exclude: self.exclude.iter().cloned().collect(),
+            }))
+        })
+    }
+}
+
+
+//------------ AccessLogFormat -----------------------------------------------
+
+/// The format of access log entries.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// The Common Log Format.
+    Common,
+
+    /// The Combined Log Format adding referrer and user agent.
+    #[default]
+    Combined,
+
+    /// A JSON object per entry.
+    Json,
+}
+
+
+//------------ AccessLog -----------------------------------------------------
+
+/// The HTTP access log.
+///
+/// Values of this type can be cloned cheaply and all clones write to the
+/// same destination. If the access log is disabled, entries are silently
+/// dropped.
+#[derive(Clone, Debug, Default)]
+pub struct AccessLog {
+    /// The actual log if it is enabled.
+    inner: Option<Arc<AccessLogInner>>,
+}
+
+/// The data of an enabled access log.
+#[derive(Debug)]
+struct AccessLogInner {
+    /// The format of the entries.
+    format: AccessLogFormat,
+
+    /// The file to append entries to or `None` to use the regular log.
+    file: Option<Mutex<File>>,
+
+    /// The targets to record requests for or `None` for all of them.
+    include: Option<HashSet<String>>,
+
+    /// The targets to not record requests for.
+    exclude: HashSet<String>,
+}
+
+impl AccessLog {
+    /// Returns whether requests served by the given target are recorded.
+    ///
+    /// Requests not served by any target are given as `None`. They are only
+    /// recorded if no explicit list of targets to include has been given.
+    pub fn includes(&self, target: Option<&str>) -> bool {
+        let inner = match self.inner.as_ref() {
+            Some(inner) => inner,
+            None => return false,
+        };
+        match target {
+            Some(target) => {
+                !inner.exclude.contains(target)
+                && inner.include.as_ref().map(|include| {
+                    include.contains(target)
+                }).unwrap_or(true)
+            }
+            None => inner.include.is_none()
+        }
+    }
+
+    /// Records an entry in the access log.
+    ///
+    /// Failing to write the entry is logged as an error but otherwise
+    /// ignored.
+    pub fn record(&self, entry: &AccessEntry) {
+        let inner = match self.inner.as_ref() {
+            Some(inner) => inner,
+            None => return,
+        };
+        let line = entry.format(inner.format);
+        match inner.file.as_ref() {
+            Some(file) => {
+                let mut file = file.lock().unwrap();
+                let res = writeln!(file, "{}", line).and_then(|_| {
+                    file.flush()
+                });
+                if let Err(err) = res {
+                    error!("Failed to write to access log: {}", err);
+                }
+            }
+            None => info!(target: "rtrtr::access", "{}", line),
+        }
+    }
+}
+
+
+//------------ AccessEntry ---------------------------------------------------
+
+/// A single entry of the access log.
+#[derive(Clone, Debug, Serialize)]
+pub struct AccessEntry {
+    /// The time the request was served.
+    #[serde(serialize_with = "serialize_rfc3339")]
+    timestamp: DateTime<Utc>,
+
+    /// The address of the client.
+    client: SocketAddr,
+
+    /// The request method.
+    method: String,
+
+    /// The request target, i.e., path and query.
+    path: String,
+
+    /// The HTTP version of the request.
+    version: String,
+
+    /// The status code of the response.
+    status: u16,
+
+    /// The size of the response body if known in advance.
+    bytes: Option<u64>,
+
+    /// The value of the Referer header if present.
+    referer: Option<String>,
+
+    /// The value of the User-Agent header if present.
+    user_agent: Option<String>,
+
+    /// The name of the target that served the request if any.
+    target: Option<String>,
+}
+
+impl AccessEntry {
+    /// Creates a new entry for a request served right now.
+    pub fn new(
+        client: SocketAddr,
+        method: impl Into<String>,
+        path: impl Into<String>,
+        version: impl Into<String>,
+    ) -> Self {
+        AccessEntry {
+            timestamp: Utc::now(),
+            client,
+            method: method.into(),
+            path: path.into(),
+            version: version.into(),
+            status: 0,
+            bytes: None,
+            referer: None,
+            user_agent: None,
+            target: None,
+        }
+    }
+
+    /// Sets the status code and body size of the response.
+    pub fn response(mut self, status: u16, bytes: Option<u64>) -> Self {
+        self.status = status;
+        self.bytes = bytes;
+        self
+    }
+
+    /// Sets the referrer and user agent provided by the client.
+    pub fn headers(
+        mut self,
+        referer: Option<impl Into<String>>,
+        user_agent: Option<impl Into<String>>,
+    ) -> Self {
+        self.referer = referer.map(Into::into);
+        self.user_agent = user_agent.map(Into::into);
+        self
+    }
+
+    /// Sets the name of the target that served the request.
+    pub fn target(mut self, target: Option<impl Into<String>>) -> Self {
+        self.target = target.map(Into::into);
+        self
+    }
+
+    /// Formats the entry in the given format.
+    fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Common => CommonLine(self, false).to_string(),
+            AccessLogFormat::Combined => CommonLine(self, true).to_string(),
+            AccessLogFormat::Json => {
+                serde_json::to_string(self).unwrap_or_default()
+            }
+        }
+    }
+}
+
+fn serialize_rfc3339<S: serde::Serializer>(
+    time: &DateTime<Utc>, serializer: S
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.to_rfc3339())
+}
+
+
+//------------ CommonLine ----------------------------------------------------
+
+/// Helper type for displaying an entry in Common or Combined Log Format.
+///
+/// The second element states whether to use the Combined format.
+struct CommonLine<'a>(&'a AccessEntry, bool);
+
+impl fmt::Display for CommonLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let entry = self.0;
+        write!(
+            f, "{} - - [{}] \"{} {} {}\" {} ",
+            entry.client.ip(),
+            entry.timestamp.format("%d/%b/%Y:%H:%M:%S %z"),
+            Escaped(&entry.method), Escaped(&entry.path),
+            Escaped(&entry.version),
+            entry.status,
+        )?;
+        match entry.bytes {
+            Some(bytes) => write!(f, "{}", bytes)?,
+            None => f.write_str("-")?,
+        }
+        if self.1 {
+            write!(
+                f, " \"{}\" \"{}\"",
+                Escaped(entry.referer.as_deref().unwrap_or("-")),
+                Escaped(entry.user_agent.as_deref().unwrap_or("-")),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+
+//------------ Escaped -------------------------------------------------------
+
+/// Helper type for displaying client provided strings in log lines.
+///
+/// Quotes, backslashes, and control characters are escaped so that the
+/// client cannot mess with the structure of the log.
+struct Escaped<'a>(&'a str);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for ch in self.0.chars() {
+            match ch {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                ch if ch.is_control() => {
+                    write!(f, "\\x{:02x}", u32::from(ch))?
+                }
+                ch => write!(f, "{}", ch)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn entry() -> AccessEntry {
+        let mut entry = AccessEntry::new(
+            "192.0.2.1:4711".parse().unwrap(), "GET", "/json?x=\"y\"",
+            "HTTP/1.1"
+        ).response(
+            200, Some(1234)
+        ).headers(
+            None::<String>, Some("curl/8.0")
+        ).target(Some("http"));
+        entry.timestamp = Utc.with_ymd_and_hms(
+            2024, 3, 1, 12, 30, 5
+        ).unwrap();
+        entry
+    }
+
+    #[test]
+    fn format_entries() {
+        let entry = entry();
+        assert_eq!(
+            entry.format(AccessLogFormat::Common),
+            "192.0.2.1 - - [01/Mar/2024:12:30:05 +0000] \
+             \"GET /json?x=\\\"y\\\" HTTP/1.1\" 200 1234"
+        );
+        assert_eq!(
+            entry.clone().response(404, None).format(
+                AccessLogFormat::Combined
+            ),
+            "192.0.2.1 - - [01/Mar/2024:12:30:05 +0000] \
+             \"GET /json?x=\\\"y\\\" HTTP/1.1\" 404 - \
+             \"-\" \"curl/8.0\""
+        );
+        let json: serde_json::Value = serde_json::from_str(
+            &entry.format(AccessLogFormat::Json)
+        ).unwrap();
+        assert_eq!(json["client"], "192.0.2.1:4711");
+        assert_eq!(json["path"], "/json?x=\"y\"");
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["referer"], serde_json::Value::Null);
+        assert_eq!(json["target"], "http");
+    }
+
+    #[test]
+    fn include_exclude() {
+        let config: AccessLogConfig = toml::from_str(
+            "http-access-log = true\nhttp-access-log-exclude = [\"b\"]"
+        ).unwrap();
+        let log = config.open().unwrap();
+        assert!(log.includes(Some("a")));
+        assert!(!log.includes(Some("b")));
+        assert!(log.includes(None));
+
+        let config: AccessLogConfig = toml::from_str(
+            "http-access-log = true\nhttp-access-log-include = [\"a\"]"
+        ).unwrap();
+        let log = config.open().unwrap();
+        assert!(log.includes(Some("a")));
+        assert!(!log.includes(Some("b")));
+        assert!(!log.includes(None));
+
+        assert!(!AccessLog::default().includes(Some("a")));
+    }
+}
@@ -1,36 +1,166 @@
 use std::process::exit;
-use clap::{Command, crate_authors, crate_version};
-use daemonbase::error::ExitError;
+use clap::{
+    Arg, ArgAction, Args as _, Command, FromArgMatches, crate_authors,
+    crate_version
+};
 use daemonbase::logging::Logger;
-use futures_util::future::pending;
+use log::{error, info};
 use tokio::runtime;
 use rtrtr::config::Config;
+use rtrtr::eval;
 
 
-fn _main() -> Result<(), ExitError> {
-    Logger::init_logging()?;
+//------------ ExitStatus ----------------------------------------------------
+
+/// The reason for the process to exit unsuccessfully.
+///
+/// Each reason has its own exit code so that orchestration systems can tell
+/// them apart. The codes are part of the stable interface of RTRTR.
+#[derive(Clone, Copy, Debug)]
+enum ExitStatus {
+    /// Setting up the process failed for any other reason.
+    Generic = 1,
+
+    /// The configuration is invalid.
+    Config = 2,
+
+    /// A listen socket couldn’t be bound or a target failed to start.
+    Bind = 3,
+
+    /// A target failed after everything had been started.
+    Runtime = 4,
+}
+
+impl ExitStatus {
+    /// Exits the process with the status’s exit code.
+    fn exit(self) -> ! {
+        exit(self as i32)
+    }
+}
+
+
+fn _main() -> Result<(), ExitStatus> {
+    Logger::init_logging().map_err(|_| ExitStatus::Generic)?;
     let matches = Config::config_args(
         Command::new("rtrtr")
         .version(crate_version!())
         .author(crate_authors!())
         .about("collecting, processing and distributing route filtering data")
+        .arg(
+            Arg::new("check-config")
+            .long("check-config")
+            .action(ArgAction::SetTrue)
+            .help("Check the configuration and exit")
+        )
+        .subcommand(eval::Args::augment_args(
+            Command::new("eval")
+            .about("Evaluate the configuration once against static input")
+        ))
     ).get_matches();
-    let (mut manager, mut config) = Config::from_arg_matches(&matches)?;
-    Logger::from_config(&config.log)?.switch_logging(false)?;
+    let (mut manager, mut config) = Config::from_arg_matches(
+        &matches
+    ).map_err(|_| ExitStatus::Config)?;
+    if matches.get_flag("check-config") {
+        config.check().map_err(|_| ExitStatus::Config)?;
+        println!("Configuration is valid.");
+        return Ok(())
+    }
+    if let Some(matches) = matches.subcommand_matches("eval") {
+        let args = eval::Args::from_arg_matches(
+            matches
+        ).expect("bug in command line arguments parser");
+        return eval::run(args, manager, config).map_err(|_| {
+            ExitStatus::Generic
+        })
+    }
+    Logger::from_config(
+        &config.log
+    ).map_err(|_| ExitStatus::Config)?.switch_logging(
+        false
+    ).map_err(|_| ExitStatus::Generic)?;
     let runtime = runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .unwrap();
     let handle = runtime.handle();
-    config.http.run(manager.metrics(), manager.http_resources(), &runtime)?;
+    config.http.run(
+        manager.metrics(), manager.http_resources(), &runtime
+    ).map_err(|_| ExitStatus::Bind)?;
     manager.spawn(&mut config.units, &mut config.targets, handle);
-    runtime.block_on(pending())
+    let status = runtime.block_on(async {
+        let status = tokio::select! {
+            res = manager.started() => {
+                if res.is_err() {
+                    error!("Failed to start all targets. Shutting down.");
+                    Err(ExitStatus::Bind)
+                }
+                else {
+                    info!(
+                        "Startup summary: {}",
+                        manager.startup_summary(&config.http.listen_addrs())
+                    );
+                    loop {
+                        let request = tokio::select! {
+                            _ = shutdown_signal() => break Ok(()),
+                            request = manager.reload_requested() => request,
+                            _ = manager.fatal_error() => {
+                                error!(
+                                    "Fatal error in a target. Shutting down."
+                                );
+                                break Err(ExitStatus::Runtime)
+                            }
+                        };
+                        info!("Reloading configuration.");
+                        manager.reload(request, handle).await;
+                    }
+                }
+            }
+            _ = shutdown_signal() => Ok(()),
+        };
+        tokio::select! {
+            _ = manager.shutdown() => { }
+            _ = shutdown_signal() => {
+                info!("Forced shutdown.");
+            }
+        }
+        status
+    });
+    manager.write_handoff().map_err(|_| ExitStatus::Runtime)?;
+    status
+}
+
+/// Waits until the process is asked to terminate.
+///
+/// This happens via either SIGINT or, on Unix systems, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(err) => {
+                error!("Failed to listen for SIGTERM: {}", err);
+                let _ = tokio::signal::ctrl_c().await;
+                return
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => { }
+            _ = terminate.recv() => { }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+    info!("Shutting down.");
 }
 
 fn main() {
     match _main() {
         Ok(_) => exit(0),
-        Err(err) => err.exit(),
+        Err(status) => status.exit(),
     }
 }
 
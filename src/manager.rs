@@ -1,26 +1,62 @@
 //! Controlling the entire operation.
 
+#[cfg(any(
+    feature = "target-mirror", feature = "unit-json", feature = "webhooks"
+))]
 use std::{fs, io};
+use std::fmt;
 use std::cell::RefCell;
-use std::collections::HashMap;
-use std::net::IpAddr;
-use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::marker::PhantomData;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(any(
+    feature = "target-mirror", feature = "unit-json", feature = "webhooks"
+))]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::atomic::Ordering::SeqCst;
+#[cfg(any(
+    feature = "target-mirror", feature = "unit-json", feature = "webhooks"
+))]
 use clap::crate_version;
 use daemonbase::error::Failed;
-use log::error;
-use serde::Deserialize;
-use tokio::runtime;
-use crate::{http, metrics};
-use crate::comms::{Gate, GateAgent, Link};
-use crate::config::{Config, ConfigFile, Marked};
+use futures_util::future::join_all;
+use hyper::{Method, StatusCode};
+use log::{error, info, warn};
+use chrono::SecondsFormat;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde::de::DeserializeOwned;
+use tokio::{runtime, task};
+use tokio::sync::{oneshot, Notify};
+use crate::{http, metrics, payload};
+use crate::comms::{Gate, GateAgent, GateMetrics, Link, UnitHealth};
+use crate::config::{Config, ConfigDiff, ConfigFile, Marked};
+use crate::events::{Dispatcher, Event, Notifier};
+use crate::formats::output;
+use crate::cache::{Cache, UnitCache};
+use crate::handoff::{Export, Handoff};
+use crate::http::{ContentType, ResponseBuilder};
+use crate::log::{AuditEntry, AuditLog};
+use crate::metrics::{Metric, MetricType, MetricUnit};
 use crate::targets::Target;
-use crate::units::Unit;
+use crate::units::{Unit, UnitConfig};
 
 
 //------------ HttpClientConfig ----------------------------------------------
 
+/// The configuration for outgoing HTTP requests.
+///
+/// This is only used if the `target-mirror`, `unit-json`, or `webhooks`
+/// features are enabled.
 #[derive(Clone, Debug, Default, Deserialize)]
+#[cfg_attr(
+    not(any(
+        feature = "target-mirror", feature = "unit-json", feature = "webhooks"
+    )),
+    allow(dead_code)
+)]
 pub struct HttpClientConfig {
     /// The proxy servers to use for outgoing HTTP requests.
     #[cfg(feature = "socks")]
@@ -40,6 +76,77 @@ pub struct HttpClientConfig {
     local_addr: Option<IpAddr>,
 }
 
+impl HttpClientConfig {
+    /// Creates a new HTTP client builder using this configuration.
+    #[cfg(any(
+        feature = "target-mirror", feature = "unit-json", feature = "webhooks"
+    ))]
+    pub fn client_builder(&self) -> Result<reqwest::ClientBuilder, String> {
+        let mut builder = reqwest::Client::builder();
+        
+        #[cfg(feature = "socks")]
+        for proxy in &self.proxies {
+            let proxy = match reqwest::Proxy::all(proxy) {
+                Ok(proxy) => proxy,
+                Err(err) => {
+                    return Err(format!(
+                        "Invalid rrdp-proxy '{}': {}", proxy, err
+                    ));
+                }
+            };
+            builder = builder.proxy(proxy);
+        }
+
+        for path in &self.root_certs {
+            builder = builder.add_root_certificate(
+                Self::load_cert(path)?
+            );
+        }
+
+        builder = builder.user_agent(
+            match self.user_agent.as_ref() {
+                Some(agent) => agent.as_str(),
+                None => concat!("RTRTR ", crate_version!()),
+            }
+        );
+
+        if let Some(addr) = self.local_addr {
+            builder = builder.local_address(addr)
+        }
+
+        Ok(builder)
+    }
+
+    /// Loads a WebPKI trusted certificate.
+    #[cfg(any(
+        feature = "target-mirror", feature = "unit-json", feature = "webhooks"
+    ))]
+    fn load_cert(path: &Path) -> Result<reqwest::Certificate, String> {
+        let mut file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) => {
+                return Err(format!(
+                    "Cannot open rrdp-root-cert file '{}': {}'",
+                    path.display(), err
+                ));
+            }
+        };
+        let mut data = Vec::new();
+        if let Err(err) = io::Read::read_to_end(&mut file, &mut data) {
+            return Err(format!(
+                "Cannot read rrdp-root-cert file '{}': {}'",
+                path.display(), err
+            ));
+        }
+        reqwest::Certificate::from_pem(&data).map_err(|err| {
+            format!(
+                "Cannot decode rrdp-root-cert file '{}': {}'",
+                path.display(), err
+            )
+        })
+    }
+}
+
 
 //------------ Component -----------------------------------------------------
 
@@ -60,18 +167,61 @@ pub struct Component {
 
     /// A reference to the HTTP resources collection.
     http_resources: http::Resources,
+
+    /// The notifier for reporting events.
+    notifier: Notifier,
+
+    /// The health of the pipelines.
+    pipelines: Arc<Pipelines>,
+
+    /// The state handed off between processes.
+    handoff: Arc<Handoff>,
+
+    /// The cache for data sets across restarts.
+    cache: Cache,
+
+    /// The coordination of a graceful shutdown.
+    shutdown: Arc<Shutdown>,
+
+    /// The coordination of stopping only this component.
+    ///
+    /// This is used when the component is removed or replaced while
+    /// reloading the configuration.
+    stop: Arc<Shutdown>,
+
+    /// The progress of starting the targets.
+    startup: Arc<Startup>,
+
+    /// Whether the component still needs to report having started.
+    ///
+    /// This is only ever `true` for targets.
+    start_pending: AtomicBool,
 }
 
 impl Component {
     /// Creates a new component from its, well, components.
+    #[allow(clippy::too_many_arguments)]
     fn new(
         name: String,
         http_config: Arc<HttpClientConfig>,
         metrics: metrics::Collection,
         http_resources: http::Resources,
+        notifier: Notifier,
+        pipelines: Arc<Pipelines>,
+        handoff: Arc<Handoff>,
+        cache: Cache,
+        shutdown: Arc<Shutdown>,
+        stop: Arc<Shutdown>,
+        startup: Arc<Startup>,
+        is_target: bool,
     ) -> Self {
+        if is_target {
+            startup.pending.fetch_add(1, SeqCst);
+        }
         Component {
             name: name.into(), http_config, metrics, http_resources,
+            notifier, pipelines, handoff, cache, shutdown, stop, startup,
+            start_pending: AtomicBool::new(is_target),
         }
     }
 
@@ -86,73 +236,122 @@ impl Component {
     }
 
     /// Register an HTTP resources.
+    ///
+    /// The resource will be available on the HTTP server with the name
+    /// given via `server` or on the default server if that is `None`.
     pub fn register_http_resource(
-        &mut self, process: Arc<dyn http::ProcessRequest>
+        &mut self,
+        process: Arc<dyn http::ProcessRequest>,
+        server: Option<&str>,
     ) {
-        self.http_resources.register(Arc::downgrade(&process))
+        self.http_resources.register(
+            Arc::downgrade(&process), server.map(Into::into),
+            Some(self.name.clone()),
+        )
     }
 
     /// Creates a new HTTP client for the component.
+    #[cfg(any(feature = "target-mirror", feature = "unit-json"))]
     pub fn http_client(&self) -> Result<reqwest::ClientBuilder, String> {
-        let mut builder = reqwest::Client::builder();
-        
-        #[cfg(feature = "socks")]
-        for proxy in &self.http_config.proxies {
-            let proxy = match reqwest::Proxy::all(proxy) {
-                Ok(proxy) => proxy,
-                Err(err) => {
-                    return Err(format!(
-                        "Invalid rrdp-proxy '{}': {}", proxy, err
-                    ));
-                }
-            };
-            builder = builder.proxy(proxy);
+        self.http_config.client_builder()
+    }
+
+    /// Returns the notifier for reporting events.
+    pub fn notifier(&self) -> &Notifier {
+        &self.notifier
+    }
+
+    /// Reports whether a target is ready to serve its data.
+    ///
+    /// Targets should call this once all their listeners are bound. Until
+    /// then, their pipeline is considered unhealthy. Becoming ready implies
+    /// that the target has started.
+    pub fn set_ready(&self, ready: bool) {
+        self.pipelines.set_ready(&self.name, ready);
+        if ready {
+            self.set_started()
         }
+    }
 
-        for path in &self.http_config.root_certs {
-            builder = builder.add_root_certificate(
-                Self::load_cert(path)?
-            );
+    /// Reports a socket address a target is listening on.
+    ///
+    /// Targets should call this for each bound TCP listener with the
+    /// address read back from the socket, i.e., with the port actually
+    /// used if the configuration asked for port 0.
+    pub fn add_listen_addr(&self, addr: SocketAddr) {
+        self.pipelines.add_listen_addr(&self.name, addr)
+    }
+
+    /// Reports that a target has started.
+    ///
+    /// A target has started once it has set up everything it needs, in
+    /// particular bound all its listeners. Targets that call
+    /// [`set_ready`](Self::set_ready) at that point don’t need to call
+    /// this method. The process is considered running once all targets
+    /// have started.
+    pub fn set_started(&self) {
+        if self.start_pending.swap(false, SeqCst) {
+            self.startup.started()
         }
+    }
 
-        builder = builder.user_agent(
-            match self.http_config.user_agent.as_ref() {
-                Some(agent) => agent.as_str(),
-                None => concat!("RTRTR ", crate_version!()),
+    /// Takes the state handed off to the component by a previous process.
+    ///
+    /// Returns `None` if there is no such state or it can’t be parsed. The
+    /// state can only be taken once.
+    pub fn take_handoff<T: DeserializeOwned>(&self) -> Option<T> {
+        let state = self.handoff.take(&self.name)?;
+        match serde_json::from_value(state) {
+            Ok(state) => Some(state),
+            Err(err) => {
+                warn!(
+                    "Component {}: ignoring invalid handoff state: {}",
+                    self.name, err
+                );
+                None
             }
+        }
+    }
+
+    /// Registers a source of state to hand off to the next process.
+    pub fn register_handoff(&mut self, source: Arc<dyn Export>) {
+        self.handoff.register(
+            self.name.to_string(), Arc::downgrade(&source)
         );
+    }
 
-        if let Some(addr) = self.http_config.local_addr {
-            builder = builder.local_address(addr)
-        }
+    /// Returns the cache for the data set of a unit.
+    ///
+    /// Returns `None` if no state directory has been configured.
+    pub fn data_cache(&self) -> Option<UnitCache> {
+        self.cache.unit(&self.name)
+    }
 
-        Ok(builder)
+    /// Returns the path of the state file of a target in the state directory.
+    ///
+    /// Returns `None` if no state directory has been configured.
+    pub fn state_file(&self) -> Option<PathBuf> {
+        self.cache.target_state(&self.name)
     }
 
-    /// Loads a WebPKI trusted certificate.
-    fn load_cert(path: &Path) -> Result<reqwest::Certificate, String> {
-        let mut file = match fs::File::open(path) {
-            Ok(file) => file,
-            Err(err) => {
-                return Err(format!(
-                    "Cannot open rrdp-root-cert file '{}': {}'",
-                    path.display(), err
-                ));
-            }
-        };
-        let mut data = Vec::new();
-        if let Err(err) = io::Read::read_to_end(&mut file, &mut data) {
-            return Err(format!(
-                "Cannot read rrdp-root-cert file '{}': {}'",
-                path.display(), err
-            ));
+    /// Resolves once the component has been asked to shut down.
+    ///
+    /// This happens when the process shuts down or when the component is
+    /// stopped because of a configuration reload.
+    pub async fn shutdown_requested(&self) {
+        tokio::select! {
+            _ = self.shutdown.requested() => { }
+            _ = self.stop.requested() => { }
         }
-        reqwest::Certificate::from_pem(&data).map_err(|err| {
-            format!(
-                "Cannot decode rrdp-root-cert file '{}': {}'",
-                path.display(), err
-            )
-        })
+    }
+
+    /// Returns a guard delaying shutdown until it is dropped.
+    ///
+    /// Components that need to wind down gracefully should acquire the
+    /// guard when starting and drop it once they are done after shutdown
+    /// was requested.
+    pub fn drain_guard(&self) -> DrainGuard {
+        DrainGuard::new(self.shutdown.clone(), Some(self.stop.clone()))
     }
 }
 
@@ -176,16 +375,68 @@ pub struct Manager {
 
     /// The HTTP resources collection maintained by this manager.
     http_resources: http::Resources,
+
+    /// The audit log for recording admin actions.
+    audit_log: AuditLog,
+
+    /// The notifier for reporting events.
+    notifier: Notifier,
+
+    /// The event dispatcher if it hasn’t been spawned yet.
+    dispatcher: Option<Dispatcher>,
+
+    /// The health of the pipelines feeding the targets.
+    pipelines: Arc<Pipelines>,
+
+    /// The number of components in each state.
+    states: Arc<ComponentStates>,
+
+    /// The admin endpoints for the units.
+    unit_admin: Arc<UnitAdmin>,
+
+    /// The admin endpoints for checking the config and restarting.
+    config_admin: Arc<ConfigAdmin>,
+
+    /// The state handed off between processes.
+    handoff: Arc<Handoff>,
+
+    /// The cache for data sets across restarts.
+    cache: Cache,
+
+    /// The coordination of a graceful shutdown.
+    shutdown: Arc<Shutdown>,
+
+    /// The progress of starting the targets.
+    startup: Arc<Startup>,
+
+    /// The components spawned so far for the startup summary.
+    spawned: Vec<SpawnedComponent>,
+
+    /// The running components by class and name.
+    running: HashMap<(&'static str, String), RunningComponent>,
 }
 
 
 impl Manager {
     /// Creates a new manager.
     pub fn new(http_config: &HttpClientConfig) -> Self {
-        Self {
+        let res = Self {
             http_config: http_config.clone().into(),
             .. Default::default()
-        }
+        };
+        res.metrics.register(
+            "pipelines".into(),
+            Arc::downgrade(&res.pipelines) as Weak<dyn metrics::Source>
+        );
+        res.http_resources.register(
+            Arc::downgrade(&res.pipelines) as Weak<dyn http::ProcessRequest>,
+            None, None,
+        );
+        res.metrics.register(
+            "components".into(),
+            Arc::downgrade(&res.states) as Weak<dyn metrics::Source>
+        );
+        res
     }
 
     /// Loads the given config file.
@@ -203,54 +454,248 @@ impl Manager {
     pub fn load(
         file: ConfigFile
     ) -> Result<(Self, Config), Failed> {
+        let (config, gates) = match Self::parse(&file) {
+            Ok(res) => res,
+            Err(errs) => {
+                for err in errs {
+                    error!("{}", err);
+                }
+                return Err(Failed)
+            }
+        };
+
+        let mut manager = Self::new(&config.http_client);
+        manager.audit_log = config.audit.open()?;
+        let (notifier, dispatcher) = config.events.start(
+            &config.http_client
+        )?;
+        manager.notifier = notifier;
+        manager.dispatcher = dispatcher;
+        manager.handoff = config.handoff.load().into();
+        manager.cache = config.cache.open()?;
+        manager.pipelines.set_ready_units(config.ready_units.clone());
+
+        // All units with a gate are new. Parsing has made sure they all
+        // appear in the config.
+        for (name, load) in gates {
+            if let Some(gate) = load.gate {
+                manager.units.insert(name.clone(), load.agent);
+                manager.pending.insert(name.clone(), gate);
+            }
+            for user in load.users {
+                manager.pipelines.add_source(user, name.clone());
+            }
+        }
+
+        manager.unit_admin = Arc::new(UnitAdmin {
+            units: Mutex::new(manager.units.iter().map(|(name, agent)| {
+                let metrics = manager.pending.get(name).map(Gate::metrics);
+                (name.clone(), (agent.clone(), metrics))
+            }).collect()),
+            audit_log: manager.audit_log.clone(),
+        });
+        manager.http_resources.register(
+            Arc::downgrade(
+                &manager.unit_admin
+            ) as Weak<dyn http::ProcessRequest>,
+            None, None
+        );
+        manager.config_admin = Arc::new(ConfigAdmin::new(file));
+        manager.http_resources.register(
+            Arc::downgrade(
+                &manager.config_admin
+            ) as Weak<dyn http::ProcessRequest>,
+            None, None
+        );
+
+        Ok((manager, config))
+    }
+
+    /// Parses and checks the given config file.
+    ///
+    /// This performs all the checks of [`load`](Self::load) but has no
+    /// side effects. Returns the config and the units referenced while
+    /// loading it or the error messages for all problems found.
+    fn parse(
+        file: &ConfigFile
+    ) -> Result<(Config, HashMap<String, LoadUnit>), Vec<String>> {
+        Self::parse_with(file, HashMap::new())
+    }
+
+    /// Parses and checks the given config file with some existing units.
+    ///
+    /// Links to the units in `units` are connected to these units rather
+    /// than to new ones.
+    fn parse_with(
+        file: &ConfigFile, units: HashMap<String, LoadUnit>,
+    ) -> Result<(Config, HashMap<String, LoadUnit>), Vec<String>> {
         // Prepare the thread-local used to allow serde load the links in the
         // units and targets.
         GATES.with(|gates| {
-            gates.replace(Some(Default::default()))
+            gates.replace(Some(units))
         });
+        let config = Self::parse_config(file);
+        let gates = GATES.with(|gates| gates.replace(None) ).unwrap();
+        let config = config?;
+
+        // All entries in the thread-local that have a gate are new. They must
+        // appear in config’s units or we have unresolved links.
+        let mut errs = Vec::new();
+        for (name, load) in &gates {
+            if load.gate.is_some() && !config.units.units.contains_key(name) {
+                for link in &load.links {
+                    let mut link = link.clone();
+                    link.resolve_config(file);
+                    errs.push(link.mark(
+                        format!("unresolved link to unit '{}'", name)
+                    ).to_string())
+                }
+            }
+        }
+        if !errs.is_empty() {
+            return Err(errs)
+        }
+        Ok((config, gates))
+    }
 
-        // Now load the config file.
-        let config = match Config::from_toml(file.bytes(), file.dir()) {
+    /// Parses the config file and checks the relations between components.
+    fn parse_config(file: &ConfigFile) -> Result<Config, Vec<String>> {
+        let config = match Config::from_toml(
+            file.bytes(), file.dir(), file.profile()
+        ) {
             Ok(config) => config,
             Err(err) => {
-                match file.path() {
-                    Some(path) => error!("{}: {}", path.display(), err),
-                    None => error!("{}", err)
-                }
-                return Err(Failed)
+                return Err(vec![match file.path() {
+                    Some(path) => format!("{}: {}", path.display(), err),
+                    None => err.to_string()
+                }])
             }
         };
 
-        let mut manager = Self::new(&config.http_client);
+        // All HTTP servers referenced by targets must exist.
+        let mut errs = Vec::new();
+        for (name, target) in &config.targets.targets {
+            if let Some(server) = target.http_server() {
+                if !config.http.has_server(server) {
+                    errs.push(format!(
+                        "Target {}: unknown HTTP server '{}'.",
+                        name.as_inner(), server
+                    ));
+                }
+            }
+        }
+        // So must all units needed for readiness.
+        for name in config.ready_units.iter().flatten() {
+            if !config.units.units.contains_key(name) {
+                errs.push(
+                    format!("Unknown unit '{}' in 'ready-units'.", name)
+                );
+            }
+        }
+        if !errs.is_empty() {
+            return Err(errs)
+        }
 
-        // All entries in the thread-local that have a gate are new. They must
-        // appear in config’s units or we have unresolved links.
-        let gates = GATES.with(|gates| gates.replace(None) ).unwrap();
+        // Components must not get into each other’s way.
+        let errs = Self::check_conflicts(&config.units, &config.targets);
+        if !errs.is_empty() {
+            return Err(errs.into_iter().map(|mut err| {
+                err.resolve_config(file);
+                err.to_string()
+            }).collect())
+        }
+        Ok(config)
+    }
+
+    /// Checks the config for components that would conflict at runtime.
+    ///
+    /// These are units whose names only differ in case, and targets that
+    /// listen on the same socket address or serve the same path on the
+    /// same HTTP server. Returns an error marked with the position of the
+    /// offending component’s name for each conflict.
+    fn check_conflicts(
+        units: &UnitSet, targets: &TargetSet,
+    ) -> Vec<Marked<String>> {
         let mut errs = Vec::new();
-        for (name, load) in gates {
-            if let Some(gate) = load.gate {
-                if !config.units.units.contains_key(&name) {
-                    for mut link in load.links {
-                        link.resolve_config(&file);
-                        errs.push(link.mark(
-                            format!("unresolved link to unit '{}'", name)
-                        ))
-                    }
+        let mut error = |name: &Marked<String>, msg: String| {
+            errs.push(name.mark(msg));
+        };
+
+        // Process components in order of their names so that we always
+        // complain about the same one.
+        let mut units: Vec<_> = units.units.keys().collect();
+        units.sort_by(|left, right| left.as_inner().cmp(right.as_inner()));
+        let mut unit_names = HashMap::new();
+        for name in units {
+            let normalized = name.to_lowercase();
+            match unit_names.get(&normalized) {
+                Some(other) => {
+                    error(name, format!(
+                        "unit name '{}' conflicts with unit '{}'",
+                        name.as_inner(), other
+                    ));
                 }
-                else {
-                    manager.units.insert(name.clone(), load.agent);
-                    manager.pending.insert(name, gate);
+                None => {
+                    unit_names.insert(normalized, name.as_inner());
                 }
             }
         }
-        if !errs.is_empty() {
-            for err in errs {
-                error!("{}", err);
+
+        let mut targets: Vec<_> = targets.targets.iter().collect();
+        targets.sort_by(|left, right| {
+            left.0.as_inner().cmp(right.0.as_inner())
+        });
+        let mut addrs: Vec<(SocketAddr, &str)> = Vec::new();
+        let mut paths = HashMap::new();
+        for (name, target) in targets {
+            for addr in target.listen() {
+                let other = addrs.iter().find(|(other, _)| {
+                    Self::addrs_conflict(*addr, *other)
+                });
+                match other {
+                    Some((other_addr, other)) => {
+                        error(name, format!(
+                            "target '{}' listens on {} which conflicts with \
+                             {} of target '{}'",
+                            name.as_inner(), addr, other_addr, other
+                        ));
+                    }
+                    None => addrs.push((*addr, name.as_inner()))
+                }
+            }
+            if let Some(path) = target.http_path() {
+                let key = (target.http_server(), path);
+                match paths.get(&key) {
+                    Some(other) => {
+                        error(name, format!(
+                            "target '{}' uses HTTP path '{}' already used \
+                             by target '{}'",
+                            name.as_inner(), path, other
+                        ));
+                    }
+                    None => {
+                        paths.insert(key, name.as_inner());
+                    }
+                }
             }
-            return Err(Failed)
         }
+        errs
+    }
 
-        Ok((manager, config))
+    /// Returns whether two listen addresses can’t be used at the same time.
+    ///
+    /// This is the case if they use the same port and either the same
+    /// address or one of them is the unspecified address of the family.
+    /// Port 0 never conflicts since the system picks a free port for it.
+    fn addrs_conflict(left: SocketAddr, right: SocketAddr) -> bool {
+        if left.port() != right.port() || left.is_ipv4() != right.is_ipv4() {
+            return false
+        }
+        if left.port() == 0 {
+            return false
+        }
+        left.ip() == right.ip()
+            || left.ip().is_unspecified() || right.ip().is_unspecified()
     }
 
     /// Allows creating components and adding them to the manager.
@@ -319,27 +764,79 @@ impl Manager {
         targets: &mut TargetSet,
         runtime: &runtime::Handle,
     ) {
+        if let Some(dispatcher) = self.dispatcher.take() {
+            runtime.spawn(dispatcher.run());
+        }
+
         for (name, unit) in units.units.drain() {
-            let gate = match self.pending.remove(&name) {
+            let name = name.into_inner();
+            let type_name = unit.unit.type_name();
+            self.states.configured("unit", type_name);
+            let mut gate = match self.pending.remove(&name) {
                 Some(gate) => gate,
                 None => {
                     error!("Unit {} is unused and will not be started.", name);
                     continue
                 }
             };
+            self.pipelines.add_unit(&name, type_name, gate.metrics());
+            self.spawned.push(SpawnedComponent {
+                name: name.clone(), class: "unit", type_name,
+                listen: Vec::new(),
+            });
+            let stop = Arc::new(Shutdown::default());
             let controller = Component::new(
-                name, self.http_config.clone(), self.metrics.clone(),
-                self.http_resources.clone()
+                name.clone(), self.http_config.clone(), self.metrics.clone(),
+                self.http_resources.clone(), self.notifier.clone(),
+                self.pipelines.clone(), self.handoff.clone(),
+                self.cache.clone(), self.shutdown.clone(), stop.clone(),
+                self.startup.clone(), false,
+            );
+            gate.set_name(controller.name().clone());
+            gate.set_notifier(self.notifier.clone());
+            let states = self.states.clone();
+            states.started("unit", type_name);
+            let task = runtime.spawn(async move {
+                unit.run(controller, gate).await;
+                states.failed("unit", type_name);
+            });
+            self.running.insert(
+                ("unit", name), RunningComponent { type_name, stop, task }
             );
-            runtime.spawn(unit.run(controller, gate));
         }
 
         for (name, target) in targets.targets.drain() {
+            let name = name.into_inner();
+            let type_name = target.type_name();
+            self.states.configured("target", type_name);
+            self.pipelines.add_target(&name, type_name);
+            self.spawned.push(SpawnedComponent {
+                name: name.clone(), class: "target", type_name,
+                listen: target.listen().to_vec(),
+            });
+            let stop = Arc::new(Shutdown::default());
             let controller = Component::new(
-                name, self.http_config.clone(), self.metrics.clone(),
-                self.http_resources.clone()
+                name.clone(), self.http_config.clone(), self.metrics.clone(),
+                self.http_resources.clone(), self.notifier.clone(),
+                self.pipelines.clone(), self.handoff.clone(),
+                self.cache.clone(), self.shutdown.clone(), stop.clone(),
+                self.startup.clone(), true,
+            );
+            let states = self.states.clone();
+            let startup = self.startup.clone();
+            states.started("target", type_name);
+            let task = runtime.spawn(async move {
+                let name = controller.name().clone();
+                let res = target.run(controller).await;
+                states.failed("target", type_name);
+                if res.is_err() {
+                    error!("Target {}: terminated with a fatal error.", name);
+                    startup.fail();
+                }
+            });
+            self.running.insert(
+                ("target", name), RunningComponent { type_name, stop, task }
             );
-            runtime.spawn(target.run(controller));
         }
 
     }
@@ -353,25 +850,1388 @@ impl Manager {
     pub fn http_resources(&self) -> http::Resources {
         self.http_resources.clone()
     }
-}
 
+    /// Returns a new reference to the audit log.
+    pub fn audit_log(&self) -> AuditLog {
+        self.audit_log.clone()
+    }
 
-//------------ UnitSet -------------------------------------------------------
+    /// Returns the socket addresses a target is listening on.
+    ///
+    /// The list is empty until the target has bound its listeners or if
+    /// there is no target by this name.
+    pub fn listen_addrs(&self, target: &str) -> Vec<SocketAddr> {
+        self.pipelines.listen_addrs(target)
+    }
 
-/// A set of units to be started.
-#[derive(Default, Deserialize)]
-#[serde(transparent)]
-pub struct UnitSet {
-    units: HashMap<String, Unit>,
-}
+    /// Writes the state of all components to the handoff file.
+    ///
+    /// This should be called right before the process exits. It does
+    /// nothing if no handoff file has been configured.
+    pub fn write_handoff(&self) -> Result<(), Failed> {
+        self.handoff.write()
+    }
 
-impl UnitSet {
+    /// Resolves once all targets have started.
+    ///
+    /// Returns an error if any target fails before all of them have
+    /// started.
+    pub async fn started(&self) -> Result<(), Failed> {
+        self.startup.all_started().await
+    }
+
+    /// Resolves once a target has terminated with a fatal error.
+    pub async fn fatal_error(&self) {
+        self.startup.failed().await
+    }
+
+    /// Returns a single-line JSON summary of the running process.
+    ///
+    /// The summary lists the version of RTRTR, the socket addresses of the
+    /// HTTP server given via `http_listen`, and all spawned units and
+    /// targets with their types and the addresses targets listen on.
+    pub fn startup_summary(&self, http_listen: &[SocketAddr]) -> String {
+        #[derive(Serialize)]
+        #[serde(rename_all = "kebab-case")]
+        struct Summary<'a> {
+            version: &'static str,
+            http_listen: &'a [SocketAddr],
+            units: Vec<&'a SpawnedComponent>,
+            targets: Vec<&'a SpawnedComponent>,
+        }
+
+        let mut spawned: Vec<_> = self.spawned.iter().collect();
+        spawned.sort_by(|left, right| left.name.cmp(&right.name));
+        let (units, targets) = spawned.into_iter().partition(|item| {
+            item.class == "unit"
+        });
+        serde_json::to_string(&Summary {
+            version: clap::crate_version!(),
+            http_listen,
+            units,
+            targets,
+        }).unwrap_or_default()
+    }
+
+    /// Resolves with the next request to reload the configuration.
+    ///
+    /// The request should be passed to [`reload`](Self::reload).
+    pub async fn reload_requested(&self) -> ReloadRequest {
+        self.config_admin.reload_requested().await
+    }
+
+    /// Reloads the configuration in place.
+    ///
+    /// Applies the config file of the request and reports the outcome back
+    /// to the requester. Units and targets that have been removed or
+    /// changed are stopped as are all components using any of these
+    /// units, directly or via other units. Then all new components and
+    /// all stopped components still present in the new configuration are
+    /// started. All other components keep running undisturbed.
+    ///
+    /// Changes to settings other than units and targets are not applied.
+    /// They only take effect when the process is restarted.
+    pub async fn reload(
+        &mut self, request: ReloadRequest, runtime: &runtime::Handle
+    ) {
+        let ReloadRequest { file, source, reply } = request;
+        let res = self.apply_reload(file, runtime).await;
+        match res.as_ref() {
+            Ok(plan) => {
+                info!("Configuration reloaded.");
+                if plan.diff.settings_changed {
+                    warn!(
+                        "Changes to settings outside of units and targets \
+                         only take effect after a restart."
+                    );
+                }
+                self.audit_log.record(
+                    AuditEntry::new("config-reload")
+                    .source(source)
+                    .state(
+                        serde_json::Value::Null,
+                        serde_json::json!({
+                            "diff": plan.diff, "dependents": plan.dependents
+                        }),
+                    )
+                );
+                self.notifier.notify(Event::ConfigReloaded);
+            }
+            Err(errs) => {
+                for err in errs {
+                    error!("Config reload: {}", err);
+                }
+            }
+        }
+        let _ = reply.send(res);
+    }
+
+    /// Applies a new config file.
+    async fn apply_reload(
+        &mut self, file: ConfigFile, runtime: &runtime::Handle
+    ) -> Result<ReloadPlan, Vec<String>> {
+        let old = match self.config_admin.file() {
+            Some(file) => file,
+            None => return Err(vec!["no config file loaded".into()])
+        };
+        let plan = {
+            let (config, gates) = Self::parse(&file)?;
+            ReloadPlan::new(&old, &file, &config, &gates)?
+        };
+        let stop_units = plan.stopped("unit");
+        let stop_targets = plan.stopped("target");
+
+        // Parse again, this time connecting the links to the units that
+        // keep running.
+        let (mut config, gates) = Self::parse_with(
+            &file,
+            self.units.iter().filter(|(name, _)| {
+                !stop_units.contains(*name)
+            }).map(|(name, agent)| {
+                (name.clone(), agent.clone().into())
+            }).collect()
+        )?;
+
+        // Stop the targets first so they don’t see their units go away.
+        self.stop_components("target", &stop_targets).await;
+        self.stop_components("unit", &stop_units).await;
+        self.units.retain(|name, _| !stop_units.contains(name));
+
+        // All units with a gate are new.
+        for (name, load) in gates {
+            if let Some(gate) = load.gate {
+                self.units.insert(name.clone(), load.agent);
+                self.pending.insert(name.clone(), gate);
+            }
+            for user in load.users {
+                self.pipelines.add_source(user, name.clone());
+            }
+        }
+        {
+            let mut admin = self.unit_admin.units.lock().unwrap();
+            admin.retain(|name, _| !stop_units.contains(name));
+            for (name, gate) in &self.pending {
+                if let Some(agent) = self.units.get(name) {
+                    admin.insert(
+                        name.clone(), (agent.clone(), Some(gate.metrics()))
+                    );
+                }
+            }
+        }
+
+        // Only start what isn’t running. New units nobody uses are passed
+        // on, too, so that they are reported.
+        config.units.units.retain(|name, _| {
+            self.pending.contains_key(name.as_inner())
+                || plan.diff.units.added.contains(name.as_inner())
+        });
+        config.targets.targets.retain(|name, _| {
+            !self.running.contains_key(&("target", name.as_inner().clone()))
+        });
+        self.spawn(&mut config.units, &mut config.targets, runtime);
+        *self.config_admin.file.lock().unwrap() = Some(file.into());
+        Ok(plan)
+    }
+
+    /// Stops the running components of a class with the given names.
+    async fn stop_components(
+        &mut self, class: &'static str, names: &HashSet<String>
+    ) {
+        let stopping: Vec<_> = names.iter().filter_map(|name| {
+            let component = self.running.remove(&(class, name.clone()))?;
+            Some(async move {
+                let type_name = component.type_name;
+                (name, type_name, component.stop().await)
+            })
+        }).collect();
+        for (name, type_name, failed) in join_all(stopping).await {
+            self.states.stopped(class, type_name, failed);
+            self.pipelines.remove(name);
+            self.spawned.retain(|item| {
+                item.class != class || item.name != *name
+            });
+        }
+    }
+
+    /// Shuts down all components gracefully.
+    ///
+    /// Informs all components that shutdown has been requested and
+    /// resolves once all components holding a drain guard are done.
+    pub async fn shutdown(&self) {
+        self.config_admin.close();
+        self.shutdown.request();
+        self.shutdown.drained().await
+    }
+}
+
+
+//------------ Shutdown ------------------------------------------------------
+
+/// The coordination of a graceful shutdown.
+#[derive(Debug, Default)]
+struct Shutdown {
+    /// Has shutdown been requested?
+    requested: AtomicBool,
+
+    /// Notification for a shutdown request.
+    request_notify: Notify,
+
+    /// The number of components that haven’t finished draining yet.
+    draining: AtomicUsize,
+
+    /// Notification for a component having finished draining.
+    drained_notify: Notify,
+}
+
+impl Shutdown {
+    /// Requests shutdown.
+    fn request(&self) {
+        self.requested.store(true, SeqCst);
+        self.request_notify.notify_waiters();
+    }
+
+    /// Resolves once shutdown has been requested.
+    async fn requested(&self) {
+        loop {
+            // Create the future first so we don’t miss a notification.
+            let notified = self.request_notify.notified();
+            if self.requested.load(SeqCst) {
+                return
+            }
+            notified.await;
+        }
+    }
+
+    /// Resolves once all components have finished draining.
+    async fn drained(&self) {
+        loop {
+            let notified = self.drained_notify.notified();
+            if self.draining.load(SeqCst) == 0 {
+                return
+            }
+            notified.await;
+        }
+    }
+}
+
+
+//------------ Startup -------------------------------------------------------
+
+/// The progress of starting the targets.
+///
+/// Every target counts as pending until it reports having started via
+/// [`Component::set_started`]. A target terminating with an error is
+/// recorded as a failure, whether it has started or not.
+#[derive(Debug, Default)]
+struct Startup {
+    /// The number of targets that haven’t started yet.
+    pending: AtomicUsize,
+
+    /// Has any target failed?
+    failed: AtomicBool,
+
+    /// Notification for a change of the above.
+    notify: Notify,
+}
+
+impl Startup {
+    /// Records that a target has started.
+    fn started(&self) {
+        self.pending.fetch_sub(1, SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    /// Records that a target has failed.
+    fn fail(&self) {
+        self.failed.store(true, SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    /// Resolves once all targets have started or any of them has failed.
+    async fn all_started(&self) -> Result<(), Failed> {
+        loop {
+            let notified = self.notify.notified();
+            if self.failed.load(SeqCst) {
+                return Err(Failed)
+            }
+            if self.pending.load(SeqCst) == 0 {
+                return Ok(())
+            }
+            notified.await;
+        }
+    }
+
+    /// Resolves once any target has failed.
+    async fn failed(&self) {
+        loop {
+            let notified = self.notify.notified();
+            if self.failed.load(SeqCst) {
+                return
+            }
+            notified.await;
+        }
+    }
+}
+
+
+//------------ SpawnedComponent ----------------------------------------------
+
+/// A spawned component as listed in the startup summary.
+#[derive(Debug, Serialize)]
+struct SpawnedComponent {
+    /// The name of the component.
+    name: String,
+
+    /// The class of the component, i.e., `"unit"` or `"target"`.
+    #[serde(skip)]
+    class: &'static str,
+
+    /// The type name of the component.
+    #[serde(rename = "type")]
+    type_name: &'static str,
+
+    /// The socket addresses the component listens on.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    listen: Vec<SocketAddr>,
+}
+
+
+//------------ RunningComponent ----------------------------------------------
+
+/// A component that has been spawned onto the runtime.
+#[derive(Debug)]
+struct RunningComponent {
+    /// The type name of the component.
+    type_name: &'static str,
+
+    /// The coordination of stopping the component.
+    stop: Arc<Shutdown>,
+
+    /// The task running the component.
+    task: task::JoinHandle<()>,
+}
+
+impl RunningComponent {
+    /// Stops the component.
+    ///
+    /// Asks the component to shut down, waits until it has finished
+    /// draining, and then aborts its task. Returns whether the component
+    /// had already terminated, i.e., failed, before.
+    async fn stop(self) -> bool {
+        let failed = self.task.is_finished();
+        self.stop.request();
+        self.stop.drained().await;
+        self.task.abort();
+
+        // Waiting for the task makes sure everything it owns, in particular
+        // its listening sockets, is gone.
+        let _ = self.task.await;
+        failed
+    }
+}
+
+
+//------------ DrainGuard ----------------------------------------------------
+
+/// A guard delaying shutdown until a component has finished draining.
+///
+/// This type is returned by [`Component::drain_guard`].
+#[derive(Debug)]
+pub struct DrainGuard {
+    /// The shutdown coordination.
+    shutdown: Arc<Shutdown>,
+
+    /// The coordination of stopping the component if there is one.
+    stop: Option<Arc<Shutdown>>,
+}
+
+impl DrainGuard {
+    /// Creates a new guard for the given shutdown coordinations.
+    fn new(shutdown: Arc<Shutdown>, stop: Option<Arc<Shutdown>>) -> Self {
+        shutdown.draining.fetch_add(1, SeqCst);
+        if let Some(stop) = stop.as_ref() {
+            stop.draining.fetch_add(1, SeqCst);
+        }
+        DrainGuard { shutdown, stop }
+    }
+}
+
+impl Drop for DrainGuard {
+    fn drop(&mut self) {
+        for shutdown in Some(&self.shutdown).into_iter().chain(&self.stop) {
+            if shutdown.draining.fetch_sub(1, SeqCst) == 1 {
+                shutdown.drained_notify.notify_waiters();
+            }
+        }
+    }
+}
+
+
+//------------ Pipelines -----------------------------------------------------
+
+/// The health of the pipelines feeding the targets.
+///
+/// The pipeline of a target consists of the unit it is linked to and,
+/// recursively, all units these units are linked to. A pipeline is healthy
+/// if all its units are healthy, the data of all units with a maximum age is
+/// within that age, and the target has reported to be ready.
+///
+/// The health is available as the `pipeline_healthy` metric for each target
+/// and via the status code of the `/readyz/<target>` HTTP endpoint.
+///
+/// Whether the instance as a whole is ready to serve data is available via
+/// the status code of the `/readyz` HTTP endpoint. This is the case once
+/// all units feeding a target – or only those units explicitly configured –
+/// have produced at least one data set.
+///
+/// In addition, the status of all components is available as a JSON
+/// document via the `/api/v1/status` HTTP endpoint and the recent diffs of
+/// a unit via `/api/v1/units/<unit>/diffs`.
+#[derive(Debug, Default)]
+pub struct Pipelines {
+    /// The components by name.
+    components: Mutex<HashMap<String, PipelineComponent>>,
+
+    /// The units that need to have data for the instance to be ready.
+    ///
+    /// If this is `None`, all units feeding a target are used.
+    ready_units: Mutex<Option<Vec<String>>>,
+}
+
+/// A component as far as pipelines are concerned.
+#[derive(Debug, Default)]
+struct PipelineComponent {
+    /// The type name of the component.
+    ///
+    /// This is empty if the component has only been seen as a link so far.
+    type_name: &'static str,
+
+    /// The names of the units the component is linked to.
+    sources: Vec<String>,
+
+    /// The gate metrics if the component is a unit.
+    gate: Option<Arc<GateMetrics>>,
+
+    /// Whether the component is a target that has reported ready.
+    ///
+    /// This is `None` if the component isn’t a target.
+    ready: Option<bool>,
+
+    /// The socket addresses a target has bound its listeners to.
+    listen: Vec<SocketAddr>,
+}
+
+impl Pipelines {
+    const HEALTHY_METRIC: Metric = Metric::new(
+        "pipeline_healthy",
+        "whether the target and all units feeding it are healthy",
+        MetricType::Gauge, MetricUnit::Info
+    );
+
+    /// Records that the component `user` is linked to the unit `source`.
+    fn add_source(&self, user: String, source: String) {
+        let mut components = self.components.lock().unwrap();
+        let sources = &mut components.entry(user).or_default().sources;
+        if !sources.contains(&source) {
+            sources.push(source)
+        }
+    }
+
+    /// Adds a unit with the given gate metrics.
+    fn add_unit(
+        &self, name: &str, type_name: &'static str, gate: Arc<GateMetrics>
+    ) {
+        let mut components = self.components.lock().unwrap();
+        let component = components.entry(name.into()).or_default();
+        component.type_name = type_name;
+        component.gate = Some(gate);
+    }
+
+    /// Adds a target that hasn’t reported ready yet.
+    fn add_target(&self, name: &str, type_name: &'static str) {
+        let mut components = self.components.lock().unwrap();
+        let component = components.entry(name.into()).or_default();
+        component.type_name = type_name;
+        component.ready = Some(false);
+    }
+
+    /// Removes a component that has been stopped.
+    fn remove(&self, name: &str) {
+        self.components.lock().unwrap().remove(name);
+    }
+
+    /// Sets whether a target is ready.
+    fn set_ready(&self, name: &str, ready: bool) {
+        let mut components = self.components.lock().unwrap();
+        if let Some(component) = components.get_mut(name) {
+            if component.ready.is_some() {
+                component.ready = Some(ready)
+            }
+        }
+    }
+
+    /// Records a socket address a target is listening on.
+    fn add_listen_addr(&self, name: &str, addr: SocketAddr) {
+        let mut components = self.components.lock().unwrap();
+        if let Some(component) = components.get_mut(name) {
+            component.listen.push(addr)
+        }
+    }
+
+    /// Returns the socket addresses a target is listening on.
+    fn listen_addrs(&self, name: &str) -> Vec<SocketAddr> {
+        let components = self.components.lock().unwrap();
+        components.get(name).map(|component| {
+            component.listen.clone()
+        }).unwrap_or_default()
+    }
+
+    /// Sets the units that need to have data for the instance to be ready.
+    ///
+    /// If `units` is `None`, all units feeding a target are used.
+    fn set_ready_units(&self, units: Option<Vec<String>>) {
+        *self.ready_units.lock().unwrap() = units;
+    }
+
+    /// Returns whether the instance is ready to serve data.
+    ///
+    /// This is the case if all relevant units have produced at least one
+    /// data set. These are either the units given via
+    /// [`set_ready_units`](Self::set_ready_units) or all units feeding a
+    /// target.
+    pub fn is_ready(&self) -> bool {
+        let components = self.components.lock().unwrap();
+        let ready_units = self.ready_units.lock().unwrap();
+        let has_payload = |name: &str| {
+            components.get(name).and_then(|unit| {
+                unit.gate.as_ref()
+            }).map(|gate| gate.has_payload()).unwrap_or(false)
+        };
+        if let Some(units) = ready_units.as_ref() {
+            return units.iter().all(|name| has_payload(name))
+        }
+
+        let mut seen = HashSet::new();
+        let mut todo: Vec<&str> = components.values().filter(|item| {
+            item.ready.is_some()
+        }).flat_map(|target| {
+            target.sources.iter().map(String::as_str)
+        }).collect();
+        while let Some(name) = todo.pop() {
+            if !seen.insert(name) {
+                continue
+            }
+            if !has_payload(name) {
+                return false
+            }
+            if let Some(unit) = components.get(name) {
+                todo.extend(unit.sources.iter().map(String::as_str));
+            }
+        }
+        true
+    }
+
+    /// Returns the health of a target’s pipeline.
+    ///
+    /// Returns `None` if there is no target by this name.
+    pub fn target_health(&self, name: &str) -> Option<bool> {
+        let components = self.components.lock().unwrap();
+        Self::rollup(&components, name)
+    }
+
+    /// Returns the status of all components as a JSON document.
+    fn status_json(&self) -> Vec<u8> {
+        #[derive(Serialize)]
+        struct Status<'a> {
+            units: Vec<UnitStatus<'a>>,
+            targets: Vec<TargetStatus<'a>>,
+        }
+
+        #[derive(Serialize)]
+        #[serde(rename_all = "kebab-case")]
+        struct UnitStatus<'a> {
+            name: &'a str,
+            #[serde(rename = "type")]
+            type_name: &'static str,
+            health: String,
+            last_update: Option<String>,
+            payload_count: usize,
+            gate_status: String,
+            links: usize,
+            suspended_links: usize,
+            sources: &'a [String],
+        }
+
+        #[derive(Serialize)]
+        #[serde(rename_all = "kebab-case")]
+        struct TargetStatus<'a> {
+            name: &'a str,
+            #[serde(rename = "type")]
+            type_name: &'static str,
+            ready: bool,
+            pipeline_healthy: bool,
+            sources: &'a [String],
+        }
+
+        let components = self.components.lock().unwrap();
+        let mut status = Status { units: Vec::new(), targets: Vec::new() };
+        for (name, component) in components.iter() {
+            if let Some(gate) = component.gate.as_ref() {
+                status.units.push(UnitStatus {
+                    name,
+                    type_name: component.type_name,
+                    health: gate.health().to_string(),
+                    last_update: gate.updated().map(|update| {
+                        update.to_rfc3339_opts(SecondsFormat::Secs, true)
+                    }),
+                    payload_count: gate.count(),
+                    gate_status: gate.gate_status().to_string(),
+                    links: gate.links(),
+                    suspended_links: gate.suspended_links(),
+                    sources: &component.sources,
+                })
+            }
+            else if let Some(ready) = component.ready {
+                status.targets.push(TargetStatus {
+                    name,
+                    type_name: component.type_name,
+                    ready,
+                    pipeline_healthy:
+                        Self::rollup(&components, name) == Some(true),
+                    sources: &component.sources,
+                })
+            }
+        }
+        status.units.sort_by(|left, right| left.name.cmp(right.name));
+        status.targets.sort_by(|left, right| left.name.cmp(right.name));
+        serde_json::to_vec(&status).unwrap_or_default()
+    }
+
+    /// Returns the recent diffs of a unit as a JSON document.
+    ///
+    /// Returns `None` if there is no unit with the given name.
+    fn diffs_json(&self, name: &str) -> Option<Vec<u8>> {
+        #[derive(Serialize)]
+        #[serde(rename_all = "kebab-case")]
+        struct Diffs<'a> {
+            unit: &'a str,
+            history_size: usize,
+            diffs: Vec<DiffItem>,
+        }
+
+        #[derive(Serialize)]
+        struct DiffItem {
+            time: String,
+            announced: serde_json::Value,
+            withdrawn: serde_json::Value,
+        }
+
+        let gate = self.components.lock().unwrap().get(name)?.gate.clone()?;
+        let history = gate.diffs();
+        let diffs = history.to_vec().into_iter().map(|(time, diff)| {
+            DiffItem {
+                time: time.to_rfc3339_opts(SecondsFormat::Secs, true),
+                announced: Self::pack_json(diff.announced()),
+                withdrawn: Self::pack_json(diff.withdrawn()),
+            }
+        }).collect();
+        serde_json::to_vec(&Diffs {
+            unit: name,
+            history_size: history.size(),
+            diffs
+        }).ok()
+    }
+
+    /// Converts a pack into the JSON output format.
+    fn pack_json(pack: &payload::Pack) -> serde_json::Value {
+        let data: Vec<u8> = output::Format::Json.stream(
+            pack.clone().into(), Default::default(), None
+        ).flatten().collect();
+        serde_json::from_slice(&data).unwrap_or_default()
+    }
+
+    /// Determines the health of a target’s pipeline.
+    fn rollup(
+        components: &HashMap<String, PipelineComponent>, name: &str
+    ) -> Option<bool> {
+        let target = components.get(name)?;
+        if !target.ready? {
+            return Some(false)
+        }
+
+        // Walk through all units reachable from the target. Since the units
+        // can form arbitrary graphs, we need to remember where we have been.
+        let mut seen = HashSet::new();
+        let mut todo: Vec<&str> = target.sources.iter().map(|name| {
+            name.as_str()
+        }).collect();
+        while let Some(name) = todo.pop() {
+            if !seen.insert(name) {
+                continue
+            }
+            let gate = match components.get(name).and_then(|unit| {
+                unit.gate.as_ref().map(|gate| (unit, gate))
+            }) {
+                Some((unit, gate)) => {
+                    todo.extend(unit.sources.iter().map(String::as_str));
+                    gate
+                }
+                // The unit was never started.
+                None => return Some(false)
+            };
+            if gate.health() != UnitHealth::Healthy
+                || gate.within_slo() == Some(false)
+            {
+                return Some(false)
+            }
+        }
+        Some(true)
+    }
+}
+
+impl metrics::Source for Pipelines {
+    fn append(&self, _name: &str, target: &mut metrics::Target)  {
+        let components = self.components.lock().unwrap();
+        let mut targets: Vec<_> = components.iter().filter_map(|item| {
+            item.1.ready.map(|_| item.0.as_str())
+        }).collect();
+        targets.sort();
+        for name in targets {
+            target.append_simple(
+                &Self::HEALTHY_METRIC, Some(name),
+                if Self::rollup(&components, name) == Some(true) {
+                    1
+                }
+                else {
+                    0
+                }
+            );
+        }
+    }
+}
+
+impl http::ProcessRequest for Pipelines {
+    fn process_request(
+        &self, request: &http::Request
+    ) -> Option<http::Response> {
+        if request.uri().path() == "/api/v1/status" {
+            if *request.method() != Method::GET {
+                return None
+            }
+            return Some(
+                ResponseBuilder::ok()
+                .content_type(ContentType::JSON)
+                .body(self.status_json())
+            )
+        }
+        if let Some(name) = request.uri().path().strip_prefix(
+            "/api/v1/units/"
+        ).and_then(|path| path.strip_suffix("/diffs")) {
+            if *request.method() != Method::GET {
+                return None
+            }
+            return Some(match self.diffs_json(name) {
+                Some(body) => {
+                    ResponseBuilder::ok()
+                    .content_type(ContentType::JSON)
+                    .body(body)
+                }
+                None => {
+                    ResponseBuilder::not_found()
+                    .content_type(ContentType::TEXT)
+                    .body("Not Found")
+                }
+            })
+        }
+        if request.uri().path() == "/readyz" {
+            if *request.method() != Method::GET {
+                return None
+            }
+            return Some(if self.is_ready() {
+                ResponseBuilder::ok()
+                .content_type(ContentType::TEXT)
+                .body("ready")
+            }
+            else {
+                ResponseBuilder::service_unavailable()
+                .content_type(ContentType::TEXT)
+                .body("not ready")
+            })
+        }
+        let name = request.uri().path().strip_prefix("/readyz/")?;
+        if *request.method() != Method::GET {
+            return None
+        }
+        Some(match self.target_health(name) {
+            Some(true) => {
+                ResponseBuilder::ok()
+                .content_type(ContentType::TEXT)
+                .body("ready")
+            }
+            Some(false) => {
+                ResponseBuilder::service_unavailable()
+                .content_type(ContentType::TEXT)
+                .body("not ready")
+            }
+            None => {
+                ResponseBuilder::not_found()
+                .content_type(ContentType::TEXT)
+                .body("Not Found")
+            }
+        })
+    }
+}
+
+
+//------------ UnitAdmin -----------------------------------------------------
+
+/// The admin endpoints for suspending and resuming units.
+///
+/// This provides `/api/v1/units/<name>/suspend` and
+/// `/api/v1/units/<name>/resume`, both only accepting POST requests. The
+/// HTTP server only forwards these requests if they carry the admin token.
+#[derive(Debug, Default)]
+struct UnitAdmin {
+    /// The agents and gate metrics of all units.
+    ///
+    /// This is updated when the configuration is reloaded.
+    units: Mutex<HashMap<String, AdminUnit>>,
+
+    /// The audit log to record suspensions in.
+    audit_log: AuditLog,
+}
+
+/// The agent and gate metrics of a unit.
+type AdminUnit = (GateAgent, Option<Arc<GateMetrics>>);
+
+impl http::ProcessRequest for UnitAdmin {
+    fn process_request(
+        &self, request: &http::Request
+    ) -> Option<http::Response> {
+        if *request.method() != Method::POST {
+            return None
+        }
+        let path = request.uri().path().strip_prefix("/api/v1/units/")?;
+        let (name, suspend) = if let Some(name) = path.strip_suffix(
+            "/suspend"
+        ) {
+            (name, true)
+        }
+        else {
+            (path.strip_suffix("/resume")?, false)
+        };
+        let unit = self.units.lock().unwrap().get(name).cloned();
+        let (agent, metrics) = match unit {
+            Some(unit) => unit,
+            None => {
+                return Some(
+                    ResponseBuilder::not_found()
+                    .content_type(ContentType::TEXT)
+                    .body("Not Found")
+                )
+            }
+        };
+        let before = metrics.as_ref().map(|metrics| metrics.is_suspended());
+        if !agent.suspend(suspend) {
+            return Some(
+                ResponseBuilder::service_unavailable()
+                .content_type(ContentType::TEXT)
+                .body("unit busy, try again")
+            )
+        }
+        self.audit_log.record(
+            AuditEntry::new(
+                if suspend { "unit-suspend" } else { "unit-resume" }
+            )
+            .source(http::client_addr(request))
+            .state(
+                serde_json::json!({ "unit": name, "suspended": before }),
+                serde_json::json!({ "unit": name, "suspended": suspend }),
+            )
+        );
+        Some(
+            ResponseBuilder::ok()
+            .content_type(ContentType::JSON)
+            .body(
+                serde_json::json!({
+                    "unit": name, "suspended": suspend
+                }).to_string()
+            )
+        )
+    }
+}
+
+
+//------------ ConfigAdmin ---------------------------------------------------
+
+/// The admin endpoint for reloading the config file.
+///
+/// This provides `/api/v1/reload` which only accepts POST requests. The
+/// HTTP server only forwards these requests if they carry the admin token.
+/// Because they read the config file, it processes them off the async
+/// runtime.
+///
+/// The endpoint loads the config file from disk again, checks it, and
+/// responds with the changes necessary to switch to it. If the query
+/// parameter `apply` is `true`, the new config file is handed to the
+/// manager which applies it via [`Manager::reload`] and the response lists
+/// the changes actually made.
+#[derive(Debug, Default)]
+struct ConfigAdmin {
+    /// The config file currently in use.
+    file: Mutex<Option<Arc<ConfigFile>>>,
+
+    /// A reload waiting to be applied by the manager.
+    pending: Mutex<Option<ReloadRequest>>,
+
+    /// Is a reload currently being applied?
+    busy: AtomicBool,
+
+    /// Have we stopped accepting reloads because of shutdown?
+    closed: AtomicBool,
+
+    /// Notification for a reload request.
+    notify: Notify,
+}
+
+impl ConfigAdmin {
+    /// Creates the endpoint for the config file currently in use.
+    fn new(file: ConfigFile) -> Self {
+        ConfigAdmin {
+            file: Mutex::new(Some(file.into())),
+            .. Default::default()
+        }
+    }
+
+    /// Returns the config file currently in use.
+    fn file(&self) -> Option<Arc<ConfigFile>> {
+        self.file.lock().unwrap().clone()
+    }
+
+    /// Checks the config file on disk against the current one.
+    ///
+    /// Returns the new config file and the changes necessary to switch to
+    /// it if the new config is valid or the error messages otherwise. This
+    /// does blocking I/O.
+    fn check(&self) -> Result<(ConfigFile, ReloadPlan), Vec<String>> {
+        let file = match self.file() {
+            Some(file) => file,
+            None => return Err(vec!["no config file loaded".into()])
+        };
+        let new_file = file.reread().map_err(|err| {
+            vec![format!("failed to read config file: {}", err)]
+        })?;
+        let (config, gates) = Manager::parse(&new_file)?;
+        if config.check().is_err() {
+            return Err(vec![
+                "failed to load the files referenced by the config, \
+                 see the log for details".into()
+            ])
+        }
+        let plan = ReloadPlan::new(&file, &new_file, &config, &gates)?;
+        Ok((new_file, plan))
+    }
+
+    /// Hands a new config file to the manager and waits for the result.
+    ///
+    /// This blocks the current thread until the manager has applied the
+    /// file. Returns the changes made or the status code and error
+    /// messages for the response.
+    fn apply(
+        &self, file: ConfigFile, source: Option<SocketAddr>
+    ) -> Result<ReloadPlan, (StatusCode, Vec<String>)> {
+        if self.busy.swap(true, SeqCst) {
+            return Err((
+                StatusCode::CONFLICT,
+                vec!["a reload is already in progress".into()]
+            ))
+        }
+        let (reply, result) = oneshot::channel();
+        let res = {
+            let mut pending = self.pending.lock().unwrap();
+            if self.closed.load(SeqCst) {
+                None
+            }
+            else {
+                *pending = Some(ReloadRequest { file, source, reply });
+                Some(result)
+            }
+        };
+        let res = res.and_then(|result| {
+            self.notify.notify_waiters();
+            result.blocking_recv().ok()
+        });
+        self.busy.store(false, SeqCst);
+        match res {
+            Some(res) => {
+                res.map_err(|errs| (StatusCode::BAD_REQUEST, errs))
+            }
+            None => Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                vec!["shutting down".into()]
+            ))
+        }
+    }
+
+    /// Resolves with the next reload request.
+    async fn reload_requested(&self) -> ReloadRequest {
+        loop {
+            // Create the future first so we don’t miss a notification.
+            let notified = self.notify.notified();
+            let request = self.pending.lock().unwrap().take();
+            if let Some(request) = request {
+                return request
+            }
+            notified.await;
+        }
+    }
+
+    /// Stops accepting reload requests.
+    ///
+    /// A request that hasn’t been picked up yet fails.
+    fn close(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        self.closed.store(true, SeqCst);
+        pending.take();
+    }
+
+    /// Returns whether the request asks for the new config to be applied.
+    fn apply_requested(request: &http::Request) -> bool {
+        request.uri().query().map(|query| {
+            query.split('&').any(|item| item == "apply=true")
+        }).unwrap_or(false)
+    }
+
+    /// Creates a response for a reload that failed.
+    fn error_response(
+        status: StatusCode, valid: bool, errs: Vec<String>
+    ) -> http::Response {
+        ResponseBuilder::new(status)
+        .content_type(ContentType::JSON)
+        .body(
+            serde_json::json!({
+                "valid": valid, "applied": false, "errors": errs
+            }).to_string()
+        )
+    }
+}
+
+impl http::ProcessRequest for ConfigAdmin {
+    fn process_request(
+        &self, request: &http::Request
+    ) -> Option<http::Response> {
+        if *request.method() != Method::POST
+            || request.uri().path() != "/api/v1/reload"
+        {
+            return None
+        }
+        let (file, plan) = match self.check() {
+            Ok(res) => res,
+            Err(errs) => {
+                for err in &errs {
+                    warn!("Config reload: {}", err);
+                }
+                return Some(Self::error_response(
+                    StatusCode::BAD_REQUEST, false, errs
+                ))
+            }
+        };
+        let (plan, applied) = if Self::apply_requested(request) {
+            match self.apply(file, http::client_addr(request)) {
+                Ok(plan) => (plan, true),
+                Err((status, errs)) => {
+                    return Some(Self::error_response(
+                        status, status != StatusCode::BAD_REQUEST, errs
+                    ))
+                }
+            }
+        }
+        else {
+            (plan, false)
+        };
+        Some(
+            ResponseBuilder::ok()
+            .content_type(ContentType::JSON)
+            .body(
+                serde_json::json!({
+                    "valid": true, "applied": applied,
+                    "diff": plan.diff, "dependents": plan.dependents,
+                }).to_string()
+            )
+        )
+    }
+}
+
+
+//------------ ReloadRequest -------------------------------------------------
+
+/// A request to reload the configuration.
+///
+/// Values of this type are returned by [`Manager::reload_requested`] and
+/// need to be passed to [`Manager::reload`].
+#[derive(Debug)]
+pub struct ReloadRequest {
+    /// The new config file.
+    file: ConfigFile,
+
+    /// The address of the client requesting the reload.
+    source: Option<SocketAddr>,
+
+    /// The sender for the result of the reload.
+    reply: oneshot::Sender<Result<ReloadPlan, Vec<String>>>,
+}
+
+
+//------------ ReloadPlan ----------------------------------------------------
+
+/// The changes necessary to switch to a new configuration.
+#[derive(Clone, Debug, Default)]
+struct ReloadPlan {
+    /// The differences between the current and the new configuration.
+    diff: ConfigDiff,
+
+    /// The unchanged components that need to be restarted.
+    ///
+    /// These are the components that use a unit that is removed or
+    /// restarted, either directly or via other units.
+    dependents: Dependents,
+}
+
+/// The unchanged components restarted because of other components.
+#[derive(Clone, Debug, Default, Serialize)]
+struct Dependents {
+    /// The names of the units in alphabetical order.
+    units: Vec<String>,
+
+    /// The names of the targets in alphabetical order.
+    targets: Vec<String>,
+}
+
+impl ReloadPlan {
+    /// Determines the changes to switch from the `old` to the `new` file.
+    ///
+    /// The `new` file must already have been parsed into `config` with
+    /// `gates` being the units referenced while parsing.
+    fn new(
+        old: &ConfigFile, new: &ConfigFile,
+        config: &Config, gates: &HashMap<String, LoadUnit>,
+    ) -> Result<Self, Vec<String>> {
+        let old = old.expanded().map_err(|err| vec![err.to_string()])?;
+        let new = new.expanded().map_err(|err| vec![err.to_string()])?;
+        let diff = ConfigDiff::new(&old, &new);
+
+        // Components using a removed unit have changed, too, so we only
+        // need to follow the users of changed units.
+        let mut units: HashSet<&String> = HashSet::new();
+        let mut targets: HashSet<&String> = HashSet::new();
+        let mut queue: Vec<&String> = diff.units.changed.iter().collect();
+        while let Some(name) = queue.pop() {
+            let users = gates.get(name).map(|load| {
+                load.users.as_slice()
+            }).unwrap_or_default();
+            for user in users {
+                if config.units.units.contains_key(user)
+                    && !diff.units.added.contains(user)
+                    && !diff.units.changed.contains(user)
+                    && units.insert(user)
+                {
+                    queue.push(user)
+                }
+                if config.targets.targets.contains_key(user)
+                    && !diff.targets.added.contains(user)
+                    && !diff.targets.changed.contains(user)
+                {
+                    targets.insert(user);
+                }
+            }
+        }
+        let sorted = |set: HashSet<&String>| {
+            let mut res: Vec<_> = set.into_iter().cloned().collect();
+            res.sort();
+            res
+        };
+        let dependents = Dependents {
+            units: sorted(units), targets: sorted(targets)
+        };
+        Ok(ReloadPlan { diff, dependents })
+    }
+
+    /// Returns the names of the components of a class to be stopped.
+    ///
+    /// These are the removed and changed components as well as the
+    /// dependents.
+    fn stopped(&self, class: &str) -> HashSet<String> {
+        let (diff, dependents) = if class == "unit" {
+            (&self.diff.units, &self.dependents.units)
+        }
+        else {
+            (&self.diff.targets, &self.dependents.targets)
+        };
+        diff.removed.iter().chain(&diff.changed).chain(
+            dependents
+        ).cloned().collect()
+    }
+}
+
+
+//------------ ComponentStates -----------------------------------------------
+
+/// The number of components in each state.
+///
+/// Components are counted by their class, i.e., unit or target, and their
+/// type. A component is configured if it appears in the configuration,
+/// started if it has been spawned, and failed if it has terminated since.
+/// Since components are supposed to run forever, any component that
+/// terminates has failed.
+#[derive(Debug, Default)]
+pub struct ComponentStates {
+    /// The counts by class and type.
+    counts: Mutex<BTreeMap<(&'static str, &'static str), StateCounts>>,
+}
+
+/// The number of components of a class and type in each state.
+#[derive(Clone, Copy, Debug, Default)]
+struct StateCounts {
+    /// The number of configured components.
+    configured: usize,
+
+    /// The number of started components.
+    started: usize,
+
+    /// The number of started components that have terminated.
+    failed: usize,
+}
+
+impl ComponentStates {
+    const CONFIGURED_METRIC: Metric = Metric::new(
+        "components_configured",
+        "the number of components in the configuration",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const STARTED_METRIC: Metric = Metric::new(
+        "components_started",
+        "the number of components that have been started",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const FAILED_METRIC: Metric = Metric::new(
+        "components_failed",
+        "the number of started components that have terminated",
+        MetricType::Gauge, MetricUnit::Total
+    );
+
+    /// Records a configured component.
+    fn configured(&self, class: &'static str, type_name: &'static str) {
+        self.update(class, type_name, |counts| counts.configured += 1)
+    }
+
+    /// Records a started component.
+    fn started(&self, class: &'static str, type_name: &'static str) {
+        self.update(class, type_name, |counts| counts.started += 1)
+    }
+
+    /// Records a component that has terminated.
+    fn failed(&self, class: &'static str, type_name: &'static str) {
+        self.update(class, type_name, |counts| counts.failed += 1)
+    }
+
+    /// Records a started component that has been stopped and removed.
+    ///
+    /// If `failed` is `true`, the component had terminated before.
+    fn stopped(
+        &self, class: &'static str, type_name: &'static str, failed: bool
+    ) {
+        self.update(class, type_name, |counts| {
+            counts.configured = counts.configured.saturating_sub(1);
+            counts.started = counts.started.saturating_sub(1);
+            if failed {
+                counts.failed = counts.failed.saturating_sub(1);
+            }
+        })
+    }
+
+    fn update(
+        &self, class: &'static str, type_name: &'static str,
+        op: impl FnOnce(&mut StateCounts)
+    ) {
+        op(self.counts.lock().unwrap().entry((class, type_name)).or_default())
+    }
+}
+
+impl ComponentStates {
+    /// Appends the values of one state for all classes and types.
+    fn append_state(
+        counts: &BTreeMap<(&'static str, &'static str), StateCounts>,
+        metric: &Metric, target: &mut metrics::Target,
+        value: impl Fn(&StateCounts) -> usize,
+    ) {
+        target.append(metric, None, |records| {
+            for ((class, type_name), item) in counts {
+                records.label_value(
+                    &[("class", *class), ("type", *type_name)], value(item)
+                );
+            }
+        });
+    }
+}
+
+impl metrics::Source for ComponentStates {
+    fn append(&self, _name: &str, target: &mut metrics::Target)  {
+        let counts = self.counts.lock().unwrap();
+        Self::append_state(
+            &counts, &Self::CONFIGURED_METRIC, target, |c| c.configured
+        );
+        Self::append_state(
+            &counts, &Self::STARTED_METRIC, target, |c| c.started
+        );
+        Self::append_state(
+            &counts, &Self::FAILED_METRIC, target, |c| c.failed
+        );
+    }
+}
+
+
+//------------ UnitSet -------------------------------------------------------
+
+/// A set of units to be started.
+#[derive(Default, Deserialize)]
+#[serde(transparent)]
+pub struct UnitSet {
+    #[serde(deserialize_with = "deserialize_components")]
+    units: HashMap<Marked<String>, UnitConfig>,
+}
+
+impl UnitSet {
     pub fn new() -> Self {
         Default::default()
     }
 
     pub fn insert(&mut self, name: impl Into<String>, unit: Unit) {
-        self.units.insert(name.into(), unit);
+        self.units.insert(Marked::from(name.into()), unit.into());
+    }
+
+    /// Replaces the unit with the given name.
+    ///
+    /// The configuration common to all units is kept. Returns whether
+    /// there was a unit with this name.
+    pub fn replace(&mut self, name: &str, unit: Unit) -> bool {
+        match self.units.iter_mut().find(|(key, _)| key.as_str() == name) {
+            Some((_, config)) => {
+                config.unit = unit;
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Checks the parts of the configuration of all units used at run time.
+    pub fn check(&self) -> Result<(), Failed> {
+        let mut res = Ok(());
+        for (name, unit) in &self.units {
+            if unit.unit.check(name.as_inner()).is_err() {
+                res = Err(Failed)
+            }
+        }
+        res
     }
 }
 
@@ -382,7 +2242,8 @@ impl UnitSet {
 #[derive(Default, Deserialize)]
 #[serde(transparent)]
 pub struct TargetSet {
-    targets: HashMap<String, Target>,
+    #[serde(deserialize_with = "deserialize_components")]
+    targets: HashMap<Marked<String>, Target>,
 }
 
 impl TargetSet {
@@ -391,7 +2252,30 @@ impl TargetSet {
     }
 
     pub fn insert(&mut self, name: impl Into<String>, target: Target) {
-        self.targets.insert(name.into(), target);
+        self.targets.insert(Marked::from(name.into()), target);
+    }
+
+    /// Converts the set into the links of each target.
+    ///
+    /// Returns the name of each target together with the links to the
+    /// units it uses in order of preference.
+    pub fn into_links(self) -> Vec<(String, Vec<Link>)> {
+        let mut res: Vec<_> = self.targets.into_iter().map(|(name, target)| {
+            (name.into_inner(), target.into_links())
+        }).collect();
+        res.sort_by(|left, right| left.0.cmp(&right.0));
+        res
+    }
+
+    /// Checks the parts of the configuration of all targets used at run time.
+    pub fn check(&self) -> Result<(), Failed> {
+        let mut res = Ok(());
+        for (name, target) in &self.targets {
+            if target.check(name.as_inner()).is_err() {
+                res = Err(Failed)
+            }
+        }
+        res
     }
 }
 
@@ -413,6 +2297,9 @@ struct LoadUnit {
     /// This is only used for generating errors if non-existing units are
     /// referenced in the config file.
     links: Vec<Marked<()>>,
+
+    /// The names of the components linking to the unit.
+    users: Vec<String>,
 }
 
 impl Default for LoadUnit {
@@ -421,7 +2308,8 @@ impl Default for LoadUnit {
         LoadUnit {
             gate: Some(gate),
             agent,
-            links: Vec::new()
+            links: Vec::new(),
+            users: Vec::new(),
         }
     }
 }
@@ -431,7 +2319,8 @@ impl From<GateAgent> for LoadUnit {
         LoadUnit {
             gate: None,
             agent,
-            links: Vec::new()
+            links: Vec::new(),
+            users: Vec::new(),
         }
     }
 }
@@ -445,6 +2334,11 @@ thread_local!(
     }
 );
 
+thread_local!(
+    /// The name of the component currently being loaded.
+    static LOADING: RefCell<Option<String>> = const { RefCell::new(None) }
+);
+
 
 /// Loads a link with the given name.
 ///
@@ -461,7 +2355,482 @@ pub fn load_link(name: Marked<String>) -> Link {
         let name = name.into_inner();
         let unit = gates.entry(name).or_default();
         unit.links.push(mark);
+        if let Some(user) = LOADING.with(|loading| loading.borrow().clone()) {
+            unit.users.push(user)
+        }
         unit.agent.create_link()
     })
 }
 
+/// Creates a link outside of loading a configuration.
+///
+/// The link is connected to a gate nobody uses. This is only useful for
+/// testing components without running them.
+#[cfg(test)]
+pub fn dangling_link(name: &str) -> Link {
+    GATES.with(|gates| {
+        let prev = gates.replace(Some(Default::default()));
+        let res = load_link(String::from(name).into());
+        gates.replace(prev);
+        res
+    })
+}
+
+/// Deserializes a map of components.
+///
+/// While deserializing a component, its name is made available to
+/// [`load_link`] so that we learn which components are linked to which
+/// units. The names are marked with their position for error reporting.
+fn deserialize_components<'de, D, T>(
+    deserializer: D
+) -> Result<HashMap<Marked<String>, T>, D::Error>
+where D: Deserializer<'de>, T: Deserialize<'de> {
+    struct Visitor<T>(PhantomData<T>);
+
+    impl<'de, T: Deserialize<'de>> de::Visitor<'de> for Visitor<T> {
+        type Value = HashMap<Marked<String>, T>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a map of components")
+        }
+
+        fn visit_map<A: de::MapAccess<'de>>(
+            self, mut map: A
+        ) -> Result<Self::Value, A::Error> {
+            let mut res = HashMap::new();
+            while let Some(name) = map.next_key::<Marked<String>>()? {
+                LOADING.with(|loading| {
+                    *loading.borrow_mut() = Some(name.as_inner().clone())
+                });
+                let value = map.next_value();
+                LOADING.with(|loading| *loading.borrow_mut() = None);
+                res.insert(name, value?);
+            }
+            Ok(res)
+        }
+    }
+
+    deserializer.deserialize_map(Visitor(PhantomData))
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use crate::payload;
+    use crate::comms::UnitUpdate;
+
+    #[tokio::test]
+    async fn pipeline_health() {
+        let pipelines = Pipelines::default();
+        let (mut source, _) = Gate::new();
+        let (mut any, _) = Gate::new();
+        pipelines.add_unit("source", "json", source.metrics());
+        pipelines.add_unit("any", "any", any.metrics());
+        pipelines.add_source("any".into(), "source".into());
+        pipelines.add_source("any".into(), "any".into());
+        pipelines.add_source("rtr".into(), "any".into());
+        pipelines.add_target("rtr", "rtr");
+
+        assert_eq!(pipelines.target_health("other"), None);
+        assert_eq!(pipelines.target_health("any"), None);
+
+        // Not ready yet.
+        assert_eq!(pipelines.target_health("rtr"), Some(false));
+        pipelines.set_ready("rtr", true);
+        assert_eq!(pipelines.target_health("rtr"), Some(true));
+
+        // Unhealthy units anywhere up the chain spoil it.
+        source.update(UnitUpdate::Stalled).await;
+        assert_eq!(pipelines.target_health("rtr"), Some(false));
+        any.update(UnitUpdate::Stalled).await;
+        assert_eq!(pipelines.target_health("rtr"), Some(false));
+        source.update(UnitUpdate::Payload(
+            payload::Update::new(Default::default())
+        )).await;
+        assert_eq!(pipelines.target_health("rtr"), Some(false));
+        any.update(UnitUpdate::Payload(
+            payload::Update::new(Default::default())
+        )).await;
+        assert_eq!(pipelines.target_health("rtr"), Some(true));
+
+        // So does data that is too old.
+        source.metrics().set_max_age(Some(Duration::ZERO));
+        any.metrics().set_max_age(Some(Duration::from_secs(3600)));
+        std::thread::sleep(Duration::from_millis(1100));
+        assert_eq!(pipelines.target_health("rtr"), Some(false));
+
+        // The status document lists everything.
+        let status: serde_json::Value = serde_json::from_slice(
+            &pipelines.status_json()
+        ).unwrap();
+        assert_eq!(status["units"][0]["name"], "any");
+        assert_eq!(status["units"][0]["health"], "healthy");
+        assert_eq!(status["units"][0]["payload-count"], 0);
+        assert_eq!(status["units"][0]["gate-status"], "dormant");
+        assert_eq!(status["units"][0]["links"], 0);
+        assert_eq!(status["units"][0]["suspended-links"], 0);
+        assert_eq!(
+            status["units"][0]["sources"],
+            serde_json::json!(["source", "any"])
+        );
+        assert_eq!(status["units"][1]["name"], "source");
+        assert_eq!(status["units"][1]["type"], "json");
+        assert!(status["units"][1]["last-update"].is_string());
+        assert_eq!(status["targets"][0]["name"], "rtr");
+        assert_eq!(status["targets"][0]["ready"], true);
+        assert_eq!(status["targets"][0]["pipeline-healthy"], false);
+    }
+
+    #[tokio::test]
+    async fn readiness() {
+        let pipelines = Pipelines::default();
+        let (mut source, _) = Gate::new();
+        let (mut other, _) = Gate::new();
+        let (mut unused, _) = Gate::new();
+        pipelines.add_unit("source", "json", source.metrics());
+        pipelines.add_unit("other", "json", other.metrics());
+        pipelines.add_unit("unused", "json", unused.metrics());
+        pipelines.add_unit("any", "any", Gate::new().0.metrics());
+        pipelines.add_source("any".into(), "source".into());
+        pipelines.add_source("any".into(), "other".into());
+        pipelines.add_source("rtr".into(), "any".into());
+        pipelines.add_target("rtr", "rtr");
+
+        // Only units feeding a target count but all of them do.
+        let update = || UnitUpdate::Payload(
+            payload::Update::new(Default::default())
+        );
+        assert!(!pipelines.is_ready());
+        source.update(update()).await;
+        other.update(UnitUpdate::Stalled).await;
+        assert!(!pipelines.is_ready());
+        other.update(update()).await;
+        assert!(!pipelines.is_ready());
+        let mut any = Gate::new().0;
+        pipelines.add_unit("any", "any", any.metrics());
+        any.update(update()).await;
+        assert!(pipelines.is_ready());
+
+        // Stalling later doesn’t matter.
+        other.update(UnitUpdate::Stalled).await;
+        assert!(pipelines.is_ready());
+
+        // Explicitly configured units replace those feeding targets.
+        pipelines.set_ready_units(Some(vec!["unused".into()]));
+        assert!(!pipelines.is_ready());
+        unused.update(update()).await;
+        assert!(pipelines.is_ready());
+    }
+
+    #[tokio::test]
+    async fn diff_history() {
+        use crate::payload::testrig;
+
+        let pipelines = Pipelines::default();
+        let (mut gate, _) = Gate::new();
+        pipelines.add_unit("source", "json", gate.metrics());
+        gate.metrics().diffs().set_size(2);
+        for values in [[1, 2, 3], [2, 3, 4], [3, 4, 5], [4, 5, 6]] {
+            gate.update(UnitUpdate::Payload(payload::Update::new(
+                payload::Set::from(testrig::pack(values))
+            ))).await;
+        }
+        assert!(pipelines.diffs_json("other").is_none());
+        let diffs: serde_json::Value = serde_json::from_slice(
+            &pipelines.diffs_json("source").unwrap()
+        ).unwrap();
+        assert_eq!(diffs["unit"], "source");
+        assert_eq!(diffs["history-size"], 2);
+        let diffs = diffs["diffs"].as_array().unwrap();
+        assert_eq!(diffs.len(), 2);
+        for diff in diffs {
+            for action in ["announced", "withdrawn"] {
+                assert_eq!(
+                    diff[action]["roas"].as_array().unwrap().len(), 1
+                );
+            }
+        }
+
+        // Shrinking the history drops the oldest diffs.
+        gate.metrics().diffs().set_size(1);
+        assert_eq!(gate.metrics().diffs().to_vec().len(), 1);
+    }
+
+    #[test]
+    fn component_states() {
+        let states = ComponentStates::default();
+        states.configured("unit", "rtr");
+        states.configured("unit", "rtr");
+        states.configured("target", "http");
+        states.started("unit", "rtr");
+        states.started("target", "http");
+        states.failed("target", "http");
+
+        let mut target = metrics::Target::new(metrics::OutputFormat::Plain);
+        metrics::Source::append(&states, "components", &mut target);
+        let output = target.into_string();
+        for line in [
+            "components_configured class=target type=http: 1",
+            "components_configured class=unit type=rtr: 2",
+            "components_started class=unit type=rtr: 1",
+            "components_failed class=target type=http: 1",
+            "components_failed class=unit type=rtr: 0",
+        ] {
+            assert!(output.lines().any(|item| item == line), "{}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        let shutdown = Arc::new(Shutdown::default());
+        let guard = DrainGuard::new(shutdown.clone(), None);
+
+        let requested = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.requested().await }
+        });
+        let drained = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.drained().await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!requested.is_finished());
+
+        shutdown.request();
+        requested.await.unwrap();
+        shutdown.requested().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!drained.is_finished());
+
+        drop(guard);
+        drained.await.unwrap();
+        shutdown.drained().await;
+    }
+
+    #[tokio::test]
+    async fn startup() {
+        let startup = Arc::new(Startup::default());
+        startup.pending.store(2, SeqCst);
+
+        let started = tokio::spawn({
+            let startup = startup.clone();
+            async move { startup.all_started().await }
+        });
+        startup.started();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!started.is_finished());
+        startup.started();
+        assert!(started.await.unwrap().is_ok());
+
+        let failed = tokio::spawn({
+            let startup = startup.clone();
+            async move { startup.failed().await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!failed.is_finished());
+        startup.fail();
+        failed.await.unwrap();
+        assert!(startup.all_started().await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reload_requests() {
+        let path = std::env::temp_dir().join(
+            format!("rtrtr-reload-requests-{}.conf", std::process::id())
+        );
+        std::fs::write(&path, "").unwrap();
+        let file = || ConfigFile::load(&path).unwrap();
+
+        let admin = Arc::new(ConfigAdmin::default());
+        let apply = tokio::task::spawn_blocking({
+            let admin = admin.clone();
+            let file = file();
+            move || admin.apply(file, None)
+        });
+        let request = admin.reload_requested().await;
+        assert_eq!(
+            admin.apply(file(), None).unwrap_err().0, StatusCode::CONFLICT
+        );
+        request.reply.send(Ok(ReloadPlan::default())).unwrap();
+        assert!(apply.await.unwrap().is_ok());
+
+        // Once closed, requests fail right away.
+        admin.close();
+        let apply = tokio::task::spawn_blocking({
+            let admin = admin.clone();
+            let file = file();
+            move || admin.apply(file, None)
+        });
+        assert_eq!(
+            apply.await.unwrap().unwrap_err().0,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reload() {
+        fn write_config(path: &Path, units: &str, targets: &str) {
+            std::fs::write(
+                path,
+                format!(
+                    "http-listen = []\n[units]\n{}\n[targets]\n{}\n",
+                    units, targets
+                )
+            ).unwrap();
+        }
+
+        let path = std::env::temp_dir().join(
+            format!("rtrtr-reload-{}.conf", std::process::id())
+        );
+        write_config(&path, r#"
+            a = { type = "static" }
+            b = { type = "static" }
+            c = { type = "any", sources = [ "b" ], random = false }
+        "#, r#"
+            t1 = { type = "rtr", listen = [ "127.0.0.1:0" ], unit = "a" }
+            t2 = { type = "rtr", listen = [ "127.0.0.1:0" ], unit = "c" }
+            t3 = { type = "rtr", listen = [ "127.0.0.1:0" ], unit = "b" }
+        "#);
+        let (mut manager, mut config) = Manager::load(
+            ConfigFile::load(&path).unwrap()
+        ).unwrap();
+        let runtime = runtime::Handle::current();
+        manager.spawn(&mut config.units, &mut config.targets, &runtime);
+        manager.started().await.unwrap();
+        let t1 = manager.running[&("target", "t1".into())].stop.clone();
+        let t1_addrs = manager.listen_addrs("t1");
+
+        // Change b, remove t3, add d and t4.
+        write_config(&path, r#"
+            a = { type = "static" }
+            b = { type = "static", vrps = [
+                { prefix = "192.0.2.0/24", asn = 64496 }
+            ] }
+            c = { type = "any", sources = [ "b" ], random = false }
+            d = { type = "static" }
+        "#, r#"
+            t1 = { type = "rtr", listen = [ "127.0.0.1:0" ], unit = "a" }
+            t2 = { type = "rtr", listen = [ "127.0.0.1:0" ], unit = "c" }
+            t4 = { type = "rtr", listen = [ "127.0.0.1:0" ], unit = "d" }
+        "#);
+        let (file, plan) = manager.config_admin.check().unwrap();
+        assert_eq!(plan.diff.units.added, ["d"]);
+        assert_eq!(plan.diff.units.changed, ["b"]);
+        assert_eq!(plan.diff.targets.added, ["t4"]);
+        assert_eq!(plan.diff.targets.removed, ["t3"]);
+        assert_eq!(plan.dependents.units, ["c"]);
+        assert_eq!(plan.dependents.targets, ["t2"]);
+
+        let (reply, result) = oneshot::channel();
+        manager.reload(
+            ReloadRequest { file, source: None, reply }, &runtime
+        ).await;
+        assert!(result.await.unwrap().is_ok());
+        manager.started().await.unwrap();
+
+        // The new file is now the current one.
+        let (_, plan) = manager.config_admin.check().unwrap();
+        assert!(plan.diff.is_empty());
+        std::fs::remove_file(&path).unwrap();
+
+        let mut running: Vec<_> = manager.running.keys().map(|(_, name)| {
+            name.as_str()
+        }).collect();
+        running.sort();
+        assert_eq!(running, ["a", "b", "c", "d", "t1", "t2", "t4"]);
+        let mut units: Vec<_> = manager.units.keys().collect();
+        units.sort();
+        assert_eq!(units, ["a", "b", "c", "d"]);
+
+        // The unaffected target has kept running.
+        assert!(Arc::ptr_eq(
+            &t1, &manager.running[&("target", "t1".into())].stop
+        ));
+        assert_eq!(manager.listen_addrs("t1"), t1_addrs);
+        assert!(!manager.listen_addrs("t4").is_empty());
+        assert!(manager.listen_addrs("t3").is_empty());
+
+    }
+
+    #[test]
+    #[cfg(feature = "http-server")]
+    fn conflicts() {
+        GATES.with(|gates| gates.replace(Some(Default::default())));
+        let units: UnitSet = toml::from_str(r#"
+            [vrps]
+            type = "any"
+            sources = [ "a" ]
+            random = false
+
+            [VRPs]
+            type = "any"
+            sources = [ "a" ]
+            random = false
+
+            [other]
+            type = "any"
+            sources = [ "a" ]
+            random = false
+        "#).unwrap();
+        let targets: TargetSet = toml::from_str(r#"
+            [rtr-1]
+            type = "rtr"
+            listen = [ "192.0.2.1:323", "[2001:db8::1]:323" ]
+            unit = "vrps"
+
+            [rtr-2]
+            type = "rtr"
+            listen = [ "0.0.0.0:323", "[2001:db8::2]:323" ]
+            unit = "vrps"
+
+            [rtr-3]
+            type = "rtr"
+            listen = [ "192.0.2.1:8323" ]
+            unit = "vrps"
+
+            [rtr-4]
+            type = "rtr"
+            listen = [ "127.0.0.1:0" ]
+            unit = "vrps"
+
+            [rtr-5]
+            type = "rtr"
+            listen = [ "127.0.0.1:0", "0.0.0.0:0" ]
+            unit = "vrps"
+
+            [json-1]
+            type = "http"
+            path = "/json"
+            format = "json"
+            unit = "vrps"
+
+            [json-2]
+            type = "http"
+            path = "/json"
+            format = "json"
+            unit = "vrps"
+
+            [json-3]
+            type = "http"
+            path = "/json"
+            format = "json"
+            unit = "vrps"
+            server = "other"
+        "#).unwrap();
+        GATES.with(|gates| gates.replace(None));
+
+        let errs = Manager::check_conflicts(&units, &targets);
+        let errs: Vec<_> = errs.iter().map(|err| {
+            err.as_inner().as_str()
+        }).collect();
+        assert_eq!(errs.len(), 3);
+        assert!(errs[0].starts_with("unit name 'vrps'"));
+        assert!(errs[1].starts_with("target 'json-2' uses HTTP path"));
+        assert!(errs[2].starts_with("target 'rtr-2' listens on 0.0.0.0:323"));
+    }
+}
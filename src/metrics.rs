@@ -19,10 +19,18 @@
 //! data to a [`Target`]. To make that task easier, the [`Metric`] type is
 //! used to define all the properties of an individual metric. Values of this
 //! type can be created as constants.
+//!
+//! Since the plain text output can become rather large, it can be limited
+//! to a [`Selection`] of the metrics.
+//!
+//! The distribution of durations can be kept in a [`Histogram`].
 
 use std::fmt;
 use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
 use std::fmt::Write;
+use std::time::Duration;
 use arc_swap::ArcSwap;
 use clap::{crate_name, crate_version};
 
@@ -86,9 +94,25 @@ impl Collection {
     /// Produces an output of all the sources in the collection in the given
     /// format and returns it as a string.
     pub fn assemble(&self, format: OutputFormat) -> String {
+        self.assemble_selected(format, Selection::default())
+    }
+
+    /// Assembles metrics output limited to a selection.
+    ///
+    /// Produces an output of the sources in the collection chosen by
+    /// `selection` in the given format and returns it as a string.
+    pub fn assemble_selected(
+        &self, format: OutputFormat, selection: Selection,
+    ) -> String {
         let sources = self.sources.load();
-        let mut target = Target::new(format);
+        let component = selection.component.clone();
+        let mut target = Target::with_selection(format, selection);
         for item in sources.iter() {
+            if let Some(component) = component.as_deref() {
+                if item.name.as_ref() != component {
+                    continue
+                }
+            }
             if let Some(source) = item.source.upgrade() {
                 source.append(&item.name, &mut target)
             }
@@ -119,6 +143,71 @@ struct RegisteredSource {
 }
 
 
+//------------ Selection -----------------------------------------------------
+
+/// A selection of the metrics to include in the output.
+///
+/// By default, everything is included. The selection can be limited to the
+/// metrics of a single component, to a maximum number of labelled values
+/// per metric – such as the values for each client of a target –, or to
+/// only the unlabelled values for a summary.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Selection {
+    /// Only include the metrics of the component with this name.
+    pub component: Option<String>,
+
+    /// The maximum number of labelled values to include for each metric.
+    pub limit: Option<usize>,
+
+    /// Only include unlabelled values.
+    pub summary: bool,
+}
+
+impl Selection {
+    /// Creates a selection from the query string of a URI.
+    ///
+    /// The recognized parameters are `component` with the name of a
+    /// component, `limit` with the maximum number of labelled values, and
+    /// `summary` which can be given without a value or with a value of
+    /// `true` or `false`. Returns an error message if the query contains
+    /// unknown parameters or invalid values.
+    pub fn from_query(query: Option<&str>) -> Result<Self, String> {
+        let mut res = Selection::default();
+        let query = match query {
+            Some(query) => query,
+            None => return Ok(res)
+        };
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match key.as_ref() {
+                "component" => {
+                    res.component = Some(value.into_owned());
+                }
+                "limit" => {
+                    res.limit = Some(value.parse().map_err(|_| {
+                        format!("invalid limit '{}'", value)
+                    })?);
+                }
+                "summary" => {
+                    res.summary = match value.as_ref() {
+                        "" | "true" => true,
+                        "false" => false,
+                        _ => {
+                            return Err(format!(
+                                "invalid summary value '{}'", value
+                            ))
+                        }
+                    }
+                }
+                _ => {
+                    return Err(format!("unknown query parameter '{}'", key))
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+
 //------------ Source --------------------------------------------------------
 
 /// A type producing some metrics.
@@ -155,6 +244,9 @@ pub struct Target {
 
     /// The output assembled so far.
     target: String,
+
+    /// The selection of metrics to include.
+    selection: Selection,
 }
 
 impl Target {
@@ -162,6 +254,17 @@ impl Target {
     ///
     /// The target will produce output in the given format.
     pub fn new(format: OutputFormat) -> Self {
+        Self::with_selection(format, Selection::default())
+    }
+
+    /// Creates a new target limited to a selection.
+    ///
+    /// The component given in the selection is not considered by the
+    /// target itself. It is up to the caller to only append the metrics of
+    /// that component.
+    pub fn with_selection(
+        format: OutputFormat, selection: Selection
+    ) -> Self {
         let mut target = String::new();
         if matches!(format, OutputFormat::Plain) {
             target.push_str(
@@ -170,7 +273,7 @@ impl Target {
                 )
             );
         }
-        Target { format, target }
+        Target { format, target, selection }
     }
 
     /// Converts the target into a string with the assembled output.
@@ -205,7 +308,17 @@ impl Target {
             self.append_metric_name(metric, unit_name);
             writeln!(&mut self.target, " {}", metric.metric_type).unwrap();
         }
-        values(&mut Records { target: self, metric, unit_name })
+        let mut records = Records {
+            target: self, metric, unit_name, labelled: 0,
+        };
+        values(&mut records);
+        let omitted = records.omitted();
+        if omitted > 0 && matches!(self.format, OutputFormat::Plain) {
+            self.append_metric_name(metric, unit_name);
+            writeln!(
+                &mut self.target, " (omitted): {}", omitted
+            ).unwrap();
+        }
     }
 
     /// Append a single metric value to the target.
@@ -273,6 +386,9 @@ pub struct Records<'a> {
 
     /// An reference to the name of the component if any.
     unit_name: Option<&'a str>,
+
+    /// The number of labelled values appended or omitted so far.
+    labelled: usize,
 }
 
 impl Records<'_> {
@@ -309,6 +425,10 @@ impl Records<'_> {
         labels: &[(&str, &str)],
         value: impl fmt::Display
     ) {
+        self.labelled += 1;
+        if self.labelled > self.labelled_limit() {
+            return
+        }
         match self.target.format {
             OutputFormat::Prometheus => {
                 self.target.append_metric_name(self.metric, self.unit_name);
@@ -346,6 +466,176 @@ impl Records<'_> {
             }
         }
     }
+
+    /// Appends a histogram to the metrics target.
+    ///
+    /// The histogram is output as its cumulative buckets followed by the
+    /// sum and count of all observed values. The labels are added to all
+    /// these values in the same way as for
+    /// [`label_value`](Self::label_value).
+    pub fn histogram(
+        &mut self,
+        labels: &[(&str, &str)],
+        histogram: &Histogram,
+    ) {
+        let mut cumulative = 0;
+        for (bound, bucket) in histogram.bounds.iter().zip(
+            histogram.buckets.iter()
+        ) {
+            cumulative += bucket.load(Relaxed);
+            self.histogram_value(
+                "_bucket", labels, Some(&bound.to_string()), cumulative
+            );
+        }
+        let count = histogram.count();
+        self.histogram_value("_bucket", labels, Some("+Inf"), count);
+        self.histogram_value("_sum", labels, None, histogram.sum());
+        self.histogram_value("_count", labels, None, count);
+    }
+
+    /// Appends a single value of a histogram.
+    fn histogram_value(
+        &mut self,
+        suffix: &str,
+        labels: &[(&str, &str)],
+        le: Option<&str>,
+        value: impl fmt::Display,
+    ) {
+        self.target.append_metric_name(self.metric, self.unit_name);
+        match self.target.format {
+            OutputFormat::Prometheus => {
+                self.target.target.push_str(suffix);
+                let labels = self.unit_name.map(|unit_name| {
+                    ("component", unit_name)
+                }).into_iter().chain(
+                    labels.iter().copied()
+                ).chain(
+                    le.map(|le| ("le", le))
+                );
+                let mut comma = false;
+                for (name, value) in labels {
+                    self.target.target.push_str(
+                        if comma { ", " } else { "{" }
+                    );
+                    write!(&mut self.target.target,
+                        "{}=\"{}\"", name, value
+                    ).unwrap();
+                    comma = true;
+                }
+                if comma {
+                    self.target.target.push('}');
+                }
+                writeln!(&mut self.target.target, " {}", value).unwrap()
+            }
+            OutputFormat::Plain => {
+                for (name, value) in labels {
+                    write!(&mut self.target.target,
+                        " {}={}", name, value
+                    ).unwrap();
+                }
+                match le {
+                    Some(le) => {
+                        write!(&mut self.target.target,
+                            " le={}", le
+                        ).unwrap()
+                    }
+                    None => {
+                        write!(&mut self.target.target,
+                            " {}", &suffix[1..]
+                        ).unwrap()
+                    }
+                }
+                writeln!(&mut self.target.target, ": {}", value).unwrap()
+            }
+        }
+    }
+}
+
+
+impl Records<'_> {
+    /// Returns the maximum number of labelled values to append.
+    fn labelled_limit(&self) -> usize {
+        if self.target.selection.summary {
+            0
+        }
+        else {
+            self.target.selection.limit.unwrap_or(usize::MAX)
+        }
+    }
+
+    /// Returns the number of labelled values that have been omitted.
+    fn omitted(&self) -> usize {
+        self.labelled.saturating_sub(self.labelled_limit())
+    }
+}
+
+
+//------------ Histogram -----------------------------------------------------
+
+/// A histogram of observed durations.
+///
+/// The histogram counts the observed durations in buckets with fixed upper
+/// bounds given in seconds. It also keeps the number and sum of all
+/// observed durations. Values can be observed concurrently via a shared
+/// reference.
+///
+/// A histogram is appended to a metrics target via
+/// [`Records::histogram`] for a metric of type
+/// [`MetricType::Histogram`].
+#[derive(Debug)]
+pub struct Histogram {
+    /// The upper bounds of the buckets in seconds in increasing order.
+    bounds: &'static [f64],
+
+    /// The number of values in each bucket.
+    ///
+    /// Each value is only counted in the first bucket it fits. Values
+    /// larger than the last bound are only included in `count`.
+    buckets: Box<[AtomicU64]>,
+
+    /// The sum of all observed values in nanoseconds.
+    sum: AtomicU64,
+
+    /// The number of observed values.
+    count: AtomicU64,
+}
+
+impl Histogram {
+    /// Creates a new, empty histogram with the given bucket bounds.
+    ///
+    /// The bounds are given in seconds and must be in increasing order.
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// Adds an observed duration to the histogram.
+    pub fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        if let Some(idx) = self.bounds.iter().position(|&bound| {
+            secs <= bound
+        }) {
+            self.buckets[idx].fetch_add(1, Relaxed);
+        }
+        self.sum.fetch_add(
+            u64::try_from(value.as_nanos()).unwrap_or(u64::MAX), Relaxed
+        );
+        self.count.fetch_add(1, Relaxed);
+    }
+
+    /// Returns the number of observed values.
+    pub fn count(&self) -> u64 {
+        self.count.load(Relaxed)
+    }
+
+    /// Returns the sum of all observed values in seconds.
+    pub fn sum(&self) -> f64 {
+        Duration::from_nanos(self.sum.load(Relaxed)).as_secs_f64()
+    }
 }
 
 
@@ -506,3 +796,119 @@ impl fmt::Display for MetricUnit {
     }
 }
 
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const METRIC: Metric = Metric::new(
+        "clients", "the clients", MetricType::Gauge, MetricUnit::Total
+    );
+
+    fn assemble(selection: Selection) -> String {
+        let mut target = Target::with_selection(
+            OutputFormat::Plain, selection
+        );
+        target.append_simple(&METRIC, Some("rtr"), 3);
+        target.append(&METRIC, Some("rtr"), |records| {
+            records.label_value(&[("addr", "192.0.2.1")], 1);
+            records.label_value(&[("addr", "192.0.2.2")], 2);
+            records.label_value(&[("addr", "192.0.2.3")], 3);
+        });
+        target.into_string()
+    }
+
+    #[test]
+    fn selection_from_query() {
+        assert_eq!(Selection::from_query(None), Ok(Selection::default()));
+        assert_eq!(
+            Selection::from_query(Some("component=rtr&limit=10&summary")),
+            Ok(Selection {
+                component: Some("rtr".into()),
+                limit: Some(10),
+                summary: true,
+            })
+        );
+        assert!(
+            !Selection::from_query(Some("summary=false")).unwrap().summary
+        );
+        assert!(Selection::from_query(Some("limit=ten")).is_err());
+        assert!(Selection::from_query(Some("summary=maybe")).is_err());
+        assert!(Selection::from_query(Some("foo=bar")).is_err());
+    }
+
+    #[test]
+    fn selected_output() {
+        let all = assemble(Selection::default());
+        assert!(all.contains("rtr clients addr=192.0.2.3: 3\n"));
+        assert!(!all.contains("(omitted)"));
+
+        let limited = assemble(Selection {
+            limit: Some(2), .. Default::default()
+        });
+        assert!(limited.contains("rtr clients addr=192.0.2.2: 2\n"));
+        assert!(!limited.contains("192.0.2.3"));
+        assert!(limited.contains("rtr clients (omitted): 1\n"));
+
+        let summary = assemble(Selection {
+            summary: true, .. Default::default()
+        });
+        assert!(summary.contains("rtr clients: 3\n"));
+        assert!(!summary.contains("addr="));
+        assert!(summary.contains("rtr clients (omitted): 3\n"));
+    }
+
+    #[test]
+    fn histogram() {
+        const DELAY: Metric = Metric::new(
+            "delay", "the delay", MetricType::Histogram, MetricUnit::Second
+        );
+
+        let histogram = Histogram::new(&[0.01, 0.1, 1.]);
+        histogram.observe(Duration::from_millis(5));
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_millis(60));
+        histogram.observe(Duration::from_secs(2));
+        assert_eq!(histogram.count(), 4);
+        assert!((histogram.sum() - 2.115).abs() < 1e-9);
+
+        let mut target = Target::new(OutputFormat::Prometheus);
+        target.append(&DELAY, Some("rtr"), |records| {
+            records.histogram(&[], &histogram)
+        });
+        let prometheus = target.into_string();
+        assert!(
+            prometheus.contains("# TYPE rtrtr_delay_seconds histogram\n")
+        );
+        assert!(prometheus.contains(
+            "rtrtr_delay_seconds_bucket{component=\"rtr\", le=\"0.01\"} 1\n"
+        ));
+        assert!(prometheus.contains(
+            "rtrtr_delay_seconds_bucket{component=\"rtr\", le=\"0.1\"} 3\n"
+        ));
+        assert!(prometheus.contains(
+            "rtrtr_delay_seconds_bucket{component=\"rtr\", le=\"1\"} 3\n"
+        ));
+        assert!(prometheus.contains(
+            "rtrtr_delay_seconds_bucket{component=\"rtr\", le=\"+Inf\"} 4\n"
+        ));
+        assert!(prometheus.contains(
+            "rtrtr_delay_seconds_sum{component=\"rtr\"} 2.11"
+        ));
+        assert!(prometheus.contains(
+            "rtrtr_delay_seconds_count{component=\"rtr\"} 4\n"
+        ));
+
+        let mut target = Target::new(OutputFormat::Plain);
+        target.append(&DELAY, Some("rtr"), |records| {
+            records.histogram(&[], &histogram)
+        });
+        let plain = target.into_string();
+        assert!(plain.contains("rtr delay le=0.1: 3\n"));
+        assert!(plain.contains("rtr delay le=+Inf: 4\n"));
+        assert!(plain.contains("rtr delay count: 4\n"));
+    }
+}
@@ -42,20 +42,42 @@
 //! the set are announced or items to be withdrawn are not present, while
 //! the latter happily ignores such inconsistencies.
 //!
+//! Units that fetch their complete data set again and again create an
+//! entirely new pack each time even if only a few items changed, and
+//! several units fetching the same data from different sources keep the
+//! same items several times. In order to store these items only once, a set
+//! can be interned via [`intern`][Set::intern]. This splits the set into
+//! packs at boundaries determined by the items themselves and looks up each
+//! pack in a process-wide table keyed by the hash of its content. Identical
+//! stretches of items in different sets thus end up in the very same pack.
+//! The gate of each unit interns the sets of all updates.
+//!
 //! The module also provides iterators for blocks, sets, and diffs. Apart from
 //! the normal iterators there are owned operators that hold a clone of the
 //! base type yet returns references to the items. For now, these need to
 //! separate because the `Iterator` trait requires the returned items to have
 //! the same lifetime as the iterator type itself. 
-use std::slice;
+//!
+//! Packs and diffs that are kept around for a long time without being
+//! looked at often can be stored in compressed form via [`CompressedPack`]
+//! and [`CompressedDiff`].
+//!
+//! Finally, an [`Update`] is what units hand to other components. It
+//! contains a set and a [`Provenance`] recording which components the
+//! update has passed through.
+use std::{cmp, fmt, slice, thread};
 use std::borrow::Borrow;
 use std::cmp::Ordering;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::iter::Peekable;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::ops::{Deref, Range};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, Weak};
+use chrono::{DateTime, SecondsFormat, Utc};
+use rpki::resources::addr::{MaxLenPrefix, Prefix};
 use rpki::rtr::client::PayloadError;
-use rpki::rtr::payload::{Action, Payload, PayloadRef};
+use rpki::rtr::payload::{Action, Payload, PayloadRef, RouteOrigin};
 use rpki::rtr::server::{PayloadDiff, PayloadSet};
 
 
@@ -419,8 +441,22 @@ impl Set {
         self.blocks.is_empty()
     }
 
+    /// Returns whether the given value is included in the set.
+    pub fn contains(&self, payload: &Payload) -> bool {
+        // Since the blocks are ordered, only the first block whose last
+        // element isn’t smaller than the value can contain it.
+        for block in self.blocks.iter() {
+            match block.last() {
+                Some(last) if last < payload => continue,
+                Some(_) => return block.binary_search(payload).is_ok(),
+                None => continue,
+            }
+        }
+        false
+    }
+
     /// Returns an iterator over the set’s elements.
-    pub fn iter(&self) -> SetIter {
+    pub fn iter(&self) -> SetIter<'_> {
         SetIter::new(self)
     }
 
@@ -592,43 +628,118 @@ impl Set {
 
     /// Returns the diff to get from `other` to `self`.
     pub fn diff_from(&self, other: &Set) -> Diff {
-        let mut diff = DiffBuilder::empty();
-        let mut source = other.iter().peekable();
-        let mut target = self.iter().peekable();
+        let mut announced = Vec::new();
+        let mut withdrawn = Vec::new();
+        diff_items(
+            other.iter(), self.iter(), &mut announced, &mut withdrawn
+        );
+        Diff {
+            announced: Pack { items: announced.into() },
+            withdrawn: Pack { items: withdrawn.into() },
+        }
+    }
 
-        // Process items while there’s some left in both sets.
-        while let (Some(&source_item), Some(&target_item)) = (
-            source.peek(), target.peek()
-        ) {
-            match source_item.cmp(target_item) {
-                Ordering::Less => {
-                    diff.withdrawn.insert_unchecked(source_item.clone());
-                    source.next();
-                }
-                Ordering::Equal => {
-                    source.next();
-                    target.next();
-                }
-                Ordering::Greater => {
-                    diff.announced.insert_unchecked(target_item.clone());
-                    target.next();
+    /// Returns the diff to get from `other` to `self` using several threads.
+    ///
+    /// For large sets, the work is split between several threads and the
+    /// current thread blocks until they are all done. This must therefore
+    /// not be called from within an async task. Use
+    /// `tokio::task::spawn_blocking` instead. For small sets, this is the
+    /// same as [`diff_from`][Self::diff_from].
+    pub fn diff_from_parallel(&self, other: &Set) -> Diff {
+        let mut announced = Vec::new();
+        let mut withdrawn = Vec::new();
+        let threads = thread_count(cmp::max(self.len, other.len));
+        if threads < 2 {
+            diff_items(
+                other.iter(), self.iter(), &mut announced, &mut withdrawn
+            );
+        }
+        else {
+            // We split both sets at the same items so that each part of
+            // the source only needs to be compared with the same part of
+            // the target. Since the parts are in order, we can just
+            // concatenate the results.
+            let source: Vec<_> = other.iter().collect();
+            let target: Vec<_> = self.iter().collect();
+            let points = split_points(
+                if source.len() > target.len() { &source } else { &target },
+                threads
+            );
+            let source = split_items(&source, &points);
+            let target = split_items(&target, &points);
+            thread::scope(|scope| {
+                let parts: Vec<_> = source.into_iter().zip(
+                    target
+                ).map(|(source, target)| {
+                    scope.spawn(move || {
+                        let mut announced = Vec::new();
+                        let mut withdrawn = Vec::new();
+                        diff_items(
+                            source.iter().copied(), target.iter().copied(),
+                            &mut announced, &mut withdrawn
+                        );
+                        (announced, withdrawn)
+                    })
+                }).collect();
+                for part in parts {
+                    let (part_announced, part_withdrawn) = part.join().expect(
+                        "diff thread panicked"
+                    );
+                    announced.extend(part_announced);
+                    withdrawn.extend(part_withdrawn);
                 }
-            }
+            });
+        }
+        Diff {
+            announced: Pack { items: announced.into() },
+            withdrawn: Pack { items: withdrawn.into() },
         }
+    }
 
-        // Now at least one set is empty so we can just withdraw anything
-        // left in source and announce anything left in target. Only one of
-        // those will happen.
-        for item in source {
-            diff.withdrawn.insert_unchecked(item.clone());
+    /// Returns a set with the same items stored in interned packs.
+    ///
+    /// The items are split into packs of 256 items on average. An item
+    /// ends a pack if the lowest bits of its hash are all zero, so the
+    /// boundaries only depend on the items and not on their position in
+    /// the set. Each pack is then looked up in a process-wide table of
+    /// packs and replaced with an existing pack of the same content if
+    /// there is one. As a result, stretches of identical items in
+    /// different sets share their storage.
+    pub fn intern(&self) -> Set {
+        INTERNER.lock().unwrap().sweep();
+
+        let mut blocks = Vec::new();
+        let mut items = Vec::new();
+        let mut pack_hasher = Fnv1a::default();
+        for item in self.iter() {
+            let hash = Interner::item_hash(item);
+            pack_hasher.write_u64(hash);
+            items.push(item.clone());
+            if
+                hash & INTERN_BOUNDARY_MASK == 0
+                || items.len() >= INTERN_MAX_PACK_LEN
+            {
+                blocks.push(Block::from(
+                    INTERNER.lock().unwrap().intern(
+                        pack_hasher.finish(), items
+                    )
+                ));
+                items = Vec::new();
+                pack_hasher = Fnv1a::default();
+            }
+        }
+        if !items.is_empty() {
+            blocks.push(Block::from(
+                INTERNER.lock().unwrap().intern(pack_hasher.finish(), items)
+            ));
         }
-        for item in target {
-            diff.announced.insert_unchecked(item.clone());
+        Set {
+            blocks: blocks.into(),
+            len: self.len,
         }
-        diff.finalize()
     }
 
-
     /// Returns a reference of the blocks of the set.
     pub fn as_blocks(&self) -> &[Block] {
         self.blocks.as_ref()
@@ -640,6 +751,28 @@ impl Set {
             blocks: self.blocks.as_ref().into()
         }
     }
+
+    /// Returns a weak reference identifying this very set.
+    ///
+    /// The reference doesn’t keep the set’s items alive. It can only be used
+    /// to check whether a set is this set or a clone of it.
+    pub fn downgrade(&self) -> SetRef {
+        SetRef(Arc::downgrade(&self.blocks))
+    }
+
+    /// Returns a digest of the content of the set.
+    ///
+    /// The digest only depends on the items in the set and is the same
+    /// across runs of the same build. It can be used to check whether a set
+    /// is the same as one seen before but is not suitable for anything
+    /// requiring collision resistance.
+    pub fn digest(&self) -> u64 {
+        let mut hasher = Fnv1a::default();
+        for item in self.iter() {
+            item.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
 }
 
 
@@ -665,6 +798,10 @@ impl From<Pack> for Set {
 
 impl From<Block> for Set {
     fn from(block: Block) -> Self {
+        // A set never contains empty blocks or `is_empty` would be wrong.
+        if block.is_empty() {
+            return Set::default()
+        }
         Set {
             len: block.len(),
             blocks: vec!(block).into(),
@@ -696,6 +833,133 @@ impl<'a> IntoIterator for &'a Set {
 }
 
 
+//------------ Fnv1a ---------------------------------------------------------
+
+/// A hasher using the 64 bit FNV-1a algorithm.
+///
+/// Unlike the standard library’s default hasher, this one produces the same
+/// output every time.
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+
+//------------ Interner ------------------------------------------------------
+
+/// The mask for finding the boundaries of interned packs.
+///
+/// An item ends a pack if the bits of its hash selected by the mask are all
+/// zero. Interned packs thus contain 256 items on average.
+const INTERN_BOUNDARY_MASK: u64 = 0xff;
+
+/// The maximum number of items in an interned pack.
+const INTERN_MAX_PACK_LEN: usize = 4096;
+
+/// The table of all interned packs.
+static INTERNER: Mutex<Interner> = Mutex::new(Interner::new());
+
+/// A table of interned packs.
+///
+/// The table only keeps weak references to the packs, so they are dropped
+/// once they aren’t used by any set any more. However, the memory of a pack
+/// is only released once its weak reference is gone, too. Because of this,
+/// references to dropped packs are removed each time a set is interned.
+#[derive(Debug)]
+struct Interner {
+    /// The packs by the hash of their content.
+    packs: BTreeMap<u64, Vec<Weak<[Payload]>>>,
+}
+
+impl Interner {
+    /// Creates a new, empty table.
+    const fn new() -> Self {
+        Interner { packs: BTreeMap::new() }
+    }
+
+    /// Returns the hash of an item used for finding pack boundaries.
+    ///
+    /// The lowest bits of an FNV-1a hash only depend on the lowest bits of
+    /// the input bytes, so the hash is mixed using the finalizer of
+    /// MurmurHash3.
+    fn item_hash(item: &Payload) -> u64 {
+        let mut hasher = Fnv1a::default();
+        item.hash(&mut hasher);
+        let mut hash = hasher.finish();
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        hash ^ (hash >> 33)
+    }
+
+    /// Returns the interned pack for the given items.
+    ///
+    /// The `hash` must be the hash of the content of the items. It is only
+    /// used to find candidates, the items are always compared, too.
+    fn intern(&mut self, hash: u64, items: Vec<Payload>) -> Pack {
+        let candidates = self.packs.entry(hash).or_default();
+        for candidate in candidates.iter() {
+            if let Some(candidate) = candidate.upgrade() {
+                if *candidate == *items {
+                    return Pack { items: candidate }
+                }
+            }
+        }
+        let items: Arc<[Payload]> = items.into();
+        candidates.push(Arc::downgrade(&items));
+        Pack { items }
+    }
+
+    /// Removes all references to packs that have been dropped.
+    fn sweep(&mut self) {
+        self.packs.retain(|_, candidates| {
+            candidates.retain(|pack| pack.strong_count() > 0);
+            !candidates.is_empty()
+        });
+    }
+}
+
+
+//------------ SetRef --------------------------------------------------------
+
+/// A weak reference identifying a set.
+///
+/// Values of this type are created via [`Set::downgrade`]. Since sets are
+/// immutable, a set that is identified by a reference is guaranteed to
+/// have the same content as the set the reference was created from.
+#[derive(Clone, Debug)]
+pub struct SetRef(Weak<[Block]>);
+
+impl SetRef {
+    /// Returns whether `set` is the set this reference was created from.
+    pub fn is(&self, set: &Set) -> bool {
+        Weak::ptr_eq(&self.0, &Arc::downgrade(&set.blocks))
+    }
+
+    /// Returns whether both references were created from the same set.
+    pub fn ptr_eq(&self, other: &SetRef) -> bool {
+        Weak::ptr_eq(&self.0, &other.0)
+    }
+}
+
+
 //------------ SetIter -------------------------------------------------------
 
 /// An iterator over the content of a set.
@@ -788,7 +1052,7 @@ impl OwnedSetIter {
 }
 
 impl PayloadSet for OwnedSetIter {
-    fn next(&mut self) -> Option<PayloadRef> {
+    fn next(&mut self) -> Option<PayloadRef<'_>> {
         if let Some(item) =
             self.set.blocks.get(self.block)?.get_from_pack(self.item)
         {
@@ -855,7 +1119,71 @@ impl SetBuilder {
     }
 
     /// Finalizes the builder into a set.
-    pub fn finalize(mut self) -> Set {
+    pub fn finalize(self) -> Set {
+        Self::finalize_blocks(self.blocks)
+    }
+
+    /// Finalizes the builder into a set using several threads.
+    ///
+    /// For large sets, the work is split between several threads and the
+    /// current thread blocks until they are all done. This must therefore
+    /// not be called from within an async task. Use
+    /// `tokio::task::spawn_blocking` instead. For small sets, this is the
+    /// same as [`finalize`][Self::finalize].
+    pub fn finalize_parallel(self) -> Set {
+        let len = self.blocks.iter().map(|block| block.len()).sum();
+        let threads = thread_count(len);
+        let largest = match self.blocks.iter().max_by_key(|block| {
+            block.len()
+        }) {
+            Some(largest) if threads > 1 => largest,
+            _ => return Self::finalize_blocks(self.blocks)
+        };
+
+        // We split all blocks at the same items taken from the largest
+        // block. Since the resulting parts don’t overlap, each of them can
+        // be finalized separately and the resulting blocks concatenated.
+        let points: Vec<_> = (1..threads).map(|idx| {
+            &largest[idx * largest.len() / threads]
+        }).collect();
+        let mut parts = vec![Vec::new(); points.len() + 1];
+        for block in self.blocks.iter() {
+            let mut start = block.start();
+            for (idx, part) in parts.iter_mut().enumerate() {
+                let end = match points.get(idx) {
+                    Some(point) => {
+                        block.start() + block.partition_point(|item| {
+                            item < *point
+                        })
+                    }
+                    None => block.end()
+                };
+                if end > start {
+                    part.push(block.pack.block(start..end));
+                }
+                start = end;
+            }
+        }
+        let mut res = Vec::new();
+        let mut res_len = 0;
+        thread::scope(|scope| {
+            let parts: Vec<_> = parts.into_iter().map(|part| {
+                scope.spawn(move || Self::finalize_blocks(part))
+            }).collect();
+            for part in parts {
+                let part = part.join().expect("finalize thread panicked");
+                res.extend(part.blocks.iter().cloned());
+                res_len += part.len;
+            }
+        });
+        Set {
+            blocks: res.into(),
+            len: res_len
+        }
+    }
+
+    /// Finalizes a vec of blocks into a set on the current thread.
+    fn finalize_blocks(mut blocks: Vec<Block>) -> Set {
         // All blocks themselves are already sorted. But since they may not
         // be continuous, we may have to break them up and insert other
         // blocks in between.
@@ -865,7 +1193,7 @@ impl SetBuilder {
         // add them to the result. Rinse and repeat until the slice is empty.
         let mut res = Vec::new();
         let mut res_len = 0;
-        let mut src = self.blocks.as_mut_slice();
+        let mut src = blocks.as_mut_slice();
         loop {
             // First, let’s skip over all empty blocks at the beginning. We
             // can use this later and slowly drain the first block until it
@@ -948,6 +1276,85 @@ impl SetBuilder {
 }
 
 
+//------------ Parallel Processing -------------------------------------------
+
+/// The minimum number of items to be processed by a thread.
+///
+/// Sets with fewer than twice this many items are processed on the current
+/// thread only.
+const MIN_ITEMS_PER_THREAD: usize = 100_000;
+
+/// Returns the number of threads to use for processing `len` items.
+fn thread_count(len: usize) -> usize {
+    thread::available_parallelism().map(usize::from).unwrap_or(1).min(
+        len / MIN_ITEMS_PER_THREAD
+    )
+}
+
+/// Returns the items at which to split a sorted slice into `count` parts.
+fn split_points<'a>(items: &[&'a Payload], count: usize) -> Vec<&'a Payload> {
+    (1..count).map(|idx| items[idx * items.len() / count]).collect()
+}
+
+/// Splits a sorted slice into parts before each of the given items.
+///
+/// The returned vec has one more element than `points`. Parts can be
+/// empty.
+fn split_items<'a, 'b>(
+    items: &'b [&'a Payload], points: &[&Payload]
+) -> Vec<&'b [&'a Payload]> {
+    let mut res = Vec::with_capacity(points.len() + 1);
+    let mut start = 0;
+    for point in points {
+        let end = items.partition_point(|item| item < point).max(start);
+        res.push(&items[start..end]);
+        start = end;
+    }
+    res.push(&items[start..]);
+    res
+}
+
+/// Determines the difference between two sorted sequences of items.
+///
+/// Items only present in `source` are appended to `withdrawn` and items
+/// only present in `target` are appended to `announced`.
+fn diff_items<'a>(
+    source: impl Iterator<Item = &'a Payload>,
+    target: impl Iterator<Item = &'a Payload>,
+    announced: &mut Vec<Payload>,
+    withdrawn: &mut Vec<Payload>,
+) {
+    let mut source = source.peekable();
+    let mut target = target.peekable();
+
+    // Process items while there’s some left in both sequences.
+    while let (Some(&source_item), Some(&target_item)) = (
+        source.peek(), target.peek()
+    ) {
+        match source_item.cmp(target_item) {
+            Ordering::Less => {
+                withdrawn.push(source_item.clone());
+                source.next();
+            }
+            Ordering::Equal => {
+                source.next();
+                target.next();
+            }
+            Ordering::Greater => {
+                announced.push(target_item.clone());
+                target.next();
+            }
+        }
+    }
+
+    // Now at least one sequence is empty so we can just withdraw anything
+    // left in source and announce anything left in target. Only one of
+    // those will happen.
+    withdrawn.extend(source.cloned());
+    announced.extend(target.cloned());
+}
+
+
 //------------ Diff ----------------------------------------------------------
 
 /// The differences between two payload sets.
@@ -977,8 +1384,18 @@ impl Diff {
         self.announced.is_empty() && self.withdrawn.is_empty()
     }
 
+    /// Returns the announced elements of the diff.
+    pub fn announced(&self) -> &Pack {
+        &self.announced
+    }
+
+    /// Returns the withdrawn elements of the diff.
+    pub fn withdrawn(&self) -> &Pack {
+        &self.withdrawn
+    }
+
     /// Returns an iterator over the set’s elements.
-    pub fn iter(&self) -> DiffIter {
+    pub fn iter(&self) -> DiffIter<'_> {
         DiffIter::new(self)
     }
 
@@ -1126,7 +1543,7 @@ impl OwnedDiffIter {
 }
 
 impl PayloadDiff for OwnedDiffIter {
-    fn next(&mut self) -> Option<(PayloadRef, Action)> {
+    fn next(&mut self) -> Option<(PayloadRef<'_>, Action)> {
         match (self.announced.peek(), self.withdrawn.peek()
         ) {
             (Some(_), None) => {
@@ -1242,22 +1659,355 @@ impl DiffBuilder {
 }
 
 
+//------------ CompressedPack ------------------------------------------------
+
+/// A pack stored in compressed form.
+///
+/// Route origins, which make up the bulk of most data sets, are encoded
+/// into a compact sequence of octets. Each origin is stored as the
+/// difference of its address and ASN to those of the previous origin,
+/// encoded as variable-length integers. Since the items are sorted, these
+/// differences are mostly small. All other items are kept as they are.
+///
+/// The pack needs to be decompressed via [`decompress`](Self::decompress)
+/// before it can be used.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CompressedPack {
+    /// The encoded route origins.
+    origins: Box<[u8]>,
+
+    /// The number of route origins.
+    origin_count: usize,
+
+    /// All items that aren’t route origins.
+    other: Pack,
+}
+
+impl CompressedPack {
+    /// Flag in the header octet of an IPv6 origin.
+    const V6: u8 = 0x01;
+
+    /// Flag in the header octet of an origin with a max length.
+    const MAX_LEN: u8 = 0x02;
+
+    /// Compresses a pack.
+    pub fn compress(pack: &Pack) -> Self {
+        let mut origins = Vec::new();
+        let mut origin_count = 0;
+        let mut other = Vec::new();
+        let mut prev_addr = 0u128;
+        let mut prev_asn = 0u32;
+        for item in pack.iter() {
+            let origin = match item {
+                Payload::Origin(origin) => origin,
+                _ => {
+                    other.push(item.clone());
+                    continue
+                }
+            };
+            let (header, addr) = match origin.prefix.addr() {
+                IpAddr::V4(addr) => (0, u128::from(u32::from(addr))),
+                IpAddr::V6(addr) => (Self::V6, u128::from(addr)),
+            };
+            let max_len = origin.prefix.max_len();
+            origins.push(
+                if max_len.is_some() { header | Self::MAX_LEN }
+                else { header }
+            );
+            origins.push(origin.prefix.prefix_len());
+            if let Some(max_len) = max_len {
+                origins.push(max_len);
+            }
+            Self::push_varint(&mut origins, zigzag(
+                addr.wrapping_sub(prev_addr) as i128
+            ));
+            let asn = origin.asn.into_u32();
+            Self::push_varint(&mut origins, zigzag(
+                i128::from(asn) - i128::from(prev_asn)
+            ));
+            prev_addr = addr;
+            prev_asn = asn;
+            origin_count += 1;
+        }
+        CompressedPack {
+            origins: origins.into_boxed_slice(),
+            origin_count,
+            other: Pack { items: other.into_boxed_slice().into() },
+        }
+    }
+
+    /// Returns the number of items in the pack.
+    pub fn len(&self) -> usize {
+        self.origin_count + self.other.len()
+    }
+
+    /// Returns whether the pack is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of octets used by the encoded route origins.
+    pub fn encoded_len(&self) -> usize {
+        self.origins.len()
+    }
+
+    /// Decompresses the pack.
+    ///
+    /// # Panics
+    ///
+    /// The method panics if the encoded data is corrupt. Since it has been
+    /// produced by [`compress`](Self::compress), this can’t happen.
+    pub fn decompress(&self) -> Pack {
+        let mut origins = Vec::with_capacity(self.origin_count);
+        let mut data = self.origins.as_ref();
+        let mut prev_addr = 0u128;
+        let mut prev_asn = 0u32;
+        while let Some((&header, rest)) = data.split_first() {
+            let prefix_len = rest[0];
+            let (max_len, rest) = if header & Self::MAX_LEN != 0 {
+                (Some(rest[1]), &rest[2..])
+            }
+            else {
+                (None, &rest[1..])
+            };
+            let (addr, rest) = Self::take_varint(rest);
+            let (asn, rest) = Self::take_varint(rest);
+            data = rest;
+            let addr = prev_addr.wrapping_add(unzigzag(addr) as u128);
+            let asn = (i128::from(prev_asn) + unzigzag(asn)) as u32;
+            prev_addr = addr;
+            prev_asn = asn;
+            let addr = if header & Self::V6 != 0 {
+                IpAddr::V6(Ipv6Addr::from(addr))
+            }
+            else {
+                IpAddr::V4(Ipv4Addr::from(addr as u32))
+            };
+            origins.push(Payload::Origin(RouteOrigin::new(
+                MaxLenPrefix::new(
+                    Prefix::new(addr, prefix_len).expect("corrupt pack"),
+                    max_len
+                ).expect("corrupt pack"),
+                asn.into()
+            )));
+        }
+
+        // Both the origins and the other items are sorted, so we only need
+        // to merge them.
+        if self.other.is_empty() {
+            return Pack { items: origins.into_boxed_slice().into() }
+        }
+        let mut items = Vec::with_capacity(self.len());
+        let mut origins = origins.into_iter().peekable();
+        let mut other = self.other.iter().peekable();
+        loop {
+            let take_origin = match (origins.peek(), other.peek()) {
+                (Some(origin), Some(other)) => origin < *other,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            if take_origin {
+                items.extend(origins.next())
+            }
+            else {
+                items.extend(other.next().cloned())
+            }
+        }
+        Pack { items: items.into_boxed_slice().into() }
+    }
+
+    /// Appends a variable-length integer to the data.
+    fn push_varint(data: &mut Vec<u8>, mut value: u128) {
+        while value >= 0x80 {
+            data.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        data.push(value as u8);
+    }
+
+    /// Takes a variable-length integer from the beginning of the data.
+    fn take_varint(data: &[u8]) -> (u128, &[u8]) {
+        let mut res = 0u128;
+        let mut shift = 0;
+        for (idx, octet) in data.iter().enumerate() {
+            res |= u128::from(octet & 0x7f) << shift;
+            if octet & 0x80 == 0 {
+                return (res, &data[idx + 1..])
+            }
+            shift += 7;
+        }
+        panic!("corrupt pack")
+    }
+}
+
+/// Maps a signed integer to an unsigned one keeping small values small.
+fn zigzag(value: i128) -> u128 {
+    ((value << 1) ^ (value >> 127)) as u128
+}
+
+/// Reverses [`zigzag`].
+fn unzigzag(value: u128) -> i128 {
+    ((value >> 1) as i128) ^ -((value & 1) as i128)
+}
+
+
+//------------ CompressedDiff ------------------------------------------------
+
+/// A diff stored in compressed form.
+///
+/// This is a diff with both its announced and withdrawn packs kept as a
+/// [`CompressedPack`]. It needs to be decompressed via
+/// [`decompress`](Self::decompress) before it can be used.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CompressedDiff {
+    /// The announced items.
+    announced: CompressedPack,
+
+    /// The withdrawn items.
+    withdrawn: CompressedPack,
+}
+
+impl CompressedDiff {
+    /// Compresses a diff.
+    pub fn compress(diff: &Diff) -> Self {
+        CompressedDiff {
+            announced: CompressedPack::compress(&diff.announced),
+            withdrawn: CompressedPack::compress(&diff.withdrawn),
+        }
+    }
+
+    /// Returns the number of changes in the diff.
+    pub fn len(&self) -> usize {
+        self.announced.len() + self.withdrawn.len()
+    }
+
+    /// Returns whether the diff contains no changes at all.
+    pub fn is_empty(&self) -> bool {
+        self.announced.is_empty() && self.withdrawn.is_empty()
+    }
+
+    /// Returns the number of octets used by the encoded route origins.
+    pub fn encoded_len(&self) -> usize {
+        self.announced.encoded_len() + self.withdrawn.encoded_len()
+    }
+
+    /// Decompresses the diff.
+    pub fn decompress(&self) -> Diff {
+        Diff {
+            announced: self.announced.decompress(),
+            withdrawn: self.withdrawn.decompress(),
+        }
+    }
+}
+
+
 //------------ Update --------------------------------------------------------
 
 /// An update of a unit’s payload data.
-#[derive(Clone, Debug, Eq, PartialEq)]
+///
+/// Two updates are considered equal if their payload sets are equal. The
+/// provenance is only informational and ignored in the comparison.
+///
+/// A unit that knows how its new set differs from the previous one can
+/// attach this diff to the update via [`with_diff`](Self::with_diff).
+/// Components receiving the update can then get the changes via
+/// [`diff_since`](Self::diff_since) without having to compare the two sets.
+#[derive(Clone, Debug)]
 #[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct Update {
     /// The new payload set.
     set: Set,
+
+    /// The components the update has passed through.
+    provenance: Provenance,
+
+    /// The trust anchors of the items if known.
+    trust_anchors: Option<Arc<TrustAnchors>>,
+
+    /// The diff from the previous set and a reference to that set if known.
+    #[cfg_attr(feature = "arbitrary", arbitrary(default))]
+    diff: Option<Arc<(SetRef, Diff)>>,
 }
 
 impl Update {
     /// Creates a new update.
+    ///
+    /// The update starts out with an empty provenance and without
+    /// trust anchor information.
     pub fn new(
         set: Set
     ) -> Self {
-        Update { set }
+        Update {
+            set,
+            provenance: Provenance::default(),
+            trust_anchors: None,
+            diff: None,
+        }
+    }
+
+    /// Creates a new update with a set derived from this update.
+    ///
+    /// The new update keeps the provenance and the trust anchors of this
+    /// update. Since the set is different, the diff is not kept.
+    pub fn derive(&self, set: Set) -> Self {
+        Update {
+            set,
+            provenance: self.provenance.clone(),
+            trust_anchors: self.trust_anchors.clone(),
+            diff: None,
+        }
+    }
+
+    /// Attaches the diff from the previous set to the update.
+    ///
+    /// The diff must be the difference between `previous` and the set of
+    /// the update.
+    pub fn with_diff(mut self, previous: &Set, diff: Diff) -> Self {
+        self.diff = Some(Arc::new((previous.downgrade(), diff)));
+        self
+    }
+
+    /// Returns the diff to get from `previous` to the update’s set.
+    ///
+    /// If the update carries a diff from exactly this set, it is returned.
+    /// Otherwise the diff is calculated.
+    pub fn diff_since(&self, previous: &Set) -> Diff {
+        if let Some(diff) = self.diff.as_ref() {
+            if diff.0.is(previous) {
+                return diff.1.clone()
+            }
+        }
+        self.set.diff_from(previous)
+    }
+
+    /// Returns the diff carried by the update if it was made from `previous`.
+    ///
+    /// Unlike [`diff_since`][Self::diff_since], this doesn’t need the
+    /// previous set itself, only a reference to it.
+    pub fn diff_from_ref(&self, previous: &SetRef) -> Option<&Diff> {
+        self.diff.as_ref().and_then(|diff| {
+            diff.0.ptr_eq(previous).then_some(&diff.1)
+        })
+    }
+
+    /// Interns the update’s set.
+    ///
+    /// See [`Set::intern`] for details. Since the content of the set stays
+    /// the same, a diff attached to the update is kept.
+    pub fn intern(&mut self) {
+        self.set = self.set.intern();
+    }
+
+    /// Attaches trust anchor information to the update.
+    pub fn with_trust_anchors(mut self, trust_anchors: TrustAnchors) -> Self {
+        self.trust_anchors = Some(trust_anchors.into());
+        self
+    }
+
+    /// Returns the trust anchor information of the update if available.
+    pub fn trust_anchors(&self) -> Option<&TrustAnchors> {
+        self.trust_anchors.as_deref()
     }
 
     /// Returns the payload set of the update.
@@ -1265,6 +2015,11 @@ impl Update {
         &self.set
     }
 
+    /// Returns the provenance of the update.
+    pub fn provenance(&self) -> &Provenance {
+        &self.provenance
+    }
+
     /// Converts the update into the payload set.
     pub fn into_set(self) -> Set {
         self.set
@@ -1273,6 +2028,217 @@ impl Update {
     /// Applies a diff to the update.
     pub fn apply_diff_relaxed(&mut self, diff: &Diff)  {
         self.set = diff.apply_relaxed(&self.set);
+        self.diff = None;
+    }
+
+    /// Records that the update has been processed by a component right now.
+    pub fn record_step(&mut self, component: Arc<str>) {
+        self.provenance = self.provenance.prepend(component, Utc::now());
+    }
+}
+
+
+//--- PartialEq and Eq
+
+impl PartialEq for Update {
+    fn eq(&self, other: &Self) -> bool {
+        self.set == other.set
+    }
+}
+
+impl Eq for Update { }
+
+
+//------------ Provenance ----------------------------------------------------
+
+/// The path an update has taken through the components.
+///
+/// The provenance is a list of components together with the time they
+/// processed the update. The most recent step comes first. The list is
+/// limited to [`Provenance::MAX_LEN`] steps. If it grows longer, the oldest
+/// steps are dropped.
+///
+/// The `Display` implementation prints the steps separated by arrows
+/// pointing from the later to the earlier component.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Provenance {
+    /// The steps, most recent first.
+    steps: Arc<[ProvenanceStep]>,
+
+    /// Have older steps been dropped?
+    truncated: bool,
+}
+
+impl Provenance {
+    /// The maximum number of steps kept.
+    pub const MAX_LEN: usize = 16;
+
+    /// Returns a new provenance with a step added at the front.
+    fn prepend(&self, component: Arc<str>, time: DateTime<Utc>) -> Self {
+        let keep = self.steps.len().min(Self::MAX_LEN - 1);
+        let mut steps = Vec::with_capacity(keep + 1);
+        steps.push(ProvenanceStep { component, time });
+        steps.extend(self.steps[..keep].iter().cloned());
+        Provenance {
+            steps: steps.into(),
+            truncated: self.truncated || keep < self.steps.len(),
+        }
+    }
+
+    /// Returns the steps of the provenance, most recent first.
+    pub fn steps(&self) -> &[ProvenanceStep] {
+        self.steps.as_ref()
+    }
+
+    /// Returns whether older steps have been dropped.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut steps = self.steps.iter();
+        match steps.next() {
+            Some(step) => write!(f, "{}", step)?,
+            None => return f.write_str("(unknown)"),
+        }
+        for step in steps {
+            write!(f, " <- {}", step)?;
+        }
+        if self.truncated {
+            f.write_str(" <- ...")?;
+        }
+        Ok(())
+    }
+}
+
+
+//------------ ProvenanceStep ------------------------------------------------
+
+/// A single step in the provenance of an update.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct ProvenanceStep {
+    /// The name of the component.
+    component: Arc<str>,
+
+    /// The time the component processed the update.
+    time: DateTime<Utc>,
+}
+
+impl ProvenanceStep {
+    /// Returns the name of the component.
+    pub fn component(&self) -> &str {
+        &self.component
+    }
+
+    /// Returns the time the component processed the update.
+    pub fn time(&self) -> DateTime<Utc> {
+        self.time
+    }
+}
+
+impl fmt::Display for ProvenanceStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})",
+            self.component,
+            self.time.to_rfc3339_opts(SecondsFormat::Millis, true)
+        )
+    }
+}
+
+
+//------------ TrustAnchors --------------------------------------------------
+
+/// The trust anchors payload items were derived from.
+///
+/// Some sources provide the name of the trust anchor for each item. This
+/// information is kept separately from the payload set and can be attached
+/// to an update. Because it is kept when a set is derived from the update,
+/// it may contain items that aren’t part of the set any more and lack
+/// items that were added later. The trust anchor of such items is unknown.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct TrustAnchors {
+    /// The names of the trust anchors.
+    names: Vec<Arc<str>>,
+
+    /// The items and the index of their trust anchor, ordered by item.
+    items: Vec<(Payload, usize)>,
+}
+
+impl TrustAnchors {
+    /// Returns the name of the trust anchor of an item if known.
+    pub fn get(&self, payload: &Payload) -> Option<&str> {
+        let idx = self.items.binary_search_by(|item| {
+            item.0.cmp(payload)
+        }).ok()?;
+        self.names.get(self.items[idx].1).map(AsRef::as_ref)
+    }
+
+    /// Returns whether there are no items with a known trust anchor.
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Returns the number of items of a set per trust anchor.
+    ///
+    /// Items of the set with an unknown trust anchor are not counted.
+    pub fn counts(&self, set: &Set) -> Vec<(&str, usize)> {
+        let mut counts = vec![0; self.names.len()];
+        for payload in set.iter() {
+            if let Ok(idx) = self.items.binary_search_by(|item| {
+                item.0.cmp(payload)
+            }) {
+                counts[self.items[idx].1] += 1
+            }
+        }
+        self.names.iter().map(AsRef::as_ref).zip(counts).collect()
+    }
+}
+
+
+//------------ TrustAnchorsBuilder -------------------------------------------
+
+/// A builder for trust anchor information.
+#[derive(Clone, Debug, Default)]
+pub struct TrustAnchorsBuilder {
+    /// The names of the trust anchors.
+    names: Vec<Arc<str>>,
+
+    /// The items and the index of their trust anchor in any order.
+    items: Vec<(Payload, usize)>,
+}
+
+impl TrustAnchorsBuilder {
+    /// Creates a new, empty builder.
+    pub fn empty() -> Self {
+        Default::default()
+    }
+
+    /// Adds an item with the name of its trust anchor.
+    pub fn insert(&mut self, payload: Payload, name: &str) {
+        let idx = match self.names.iter().position(|item| {
+            item.as_ref() == name
+        }) {
+            Some(idx) => idx,
+            None => {
+                self.names.push(name.into());
+                self.names.len() - 1
+            }
+        };
+        self.items.push((payload, idx))
+    }
+
+    /// Converts the builder into the final trust anchor information.
+    ///
+    /// If an item was added more than once, the first trust anchor wins.
+    pub fn finalize(mut self) -> TrustAnchors {
+        self.items.sort_by(|left, right| left.0.cmp(&right.0));
+        self.items.dedup_by(|right, left| left.0 == right.0);
+        TrustAnchors { names: self.names, items: self.items }
     }
 }
 
@@ -1369,6 +2335,18 @@ pub(crate) mod testrig {
         )
     }
 
+    /// Creates a pack from the assertions of a SLURM file.
+    ///
+    /// This is the easiest way to get hold of router keys for testing.
+    pub fn slurm_pack(slurm: &[u8]) -> Pack {
+        let slurm = rpki::slurm::SlurmFile::from_reader(slurm).unwrap();
+        let mut res = PackBuilder::empty();
+        for payload in slurm.assertions.iter_payload() {
+            res.insert_unchecked(payload)
+        }
+        res.finalize()
+    }
+
 
     /// Converts a set into a vec of integers.
     pub fn set_to_vec(set: &Set) -> Vec<u32> {
@@ -1429,6 +2407,20 @@ mod test {
         );
     }
 
+    #[test]
+    fn set_contains() {
+        let set = set([
+            block([1, 2, 4], 0..3), block([], 0..0), block([5, 6, 8], 1..3)
+        ]);
+        for value in [1, 2, 4, 6, 8] {
+            assert!(set.contains(&p(value)), "{}", value);
+        }
+        for value in [0, 3, 5, 7, 9] {
+            assert!(!set.contains(&p(value)), "{}", value);
+        }
+        assert!(!Set::default().contains(&p(1)));
+    }
+
     #[test]
     fn set_iter() {
         assert_eq!(
@@ -1443,6 +2435,68 @@ mod test {
         );
     }
 
+    #[test]
+    fn set_digest() {
+        // The digest doesn’t depend on how the set is split into blocks.
+        assert_eq!(
+            set([block([1, 2, 4, 5], 0..4)]).digest(),
+            set([block([1, 2, 4], 0..3), block([4, 5], 1..2)]).digest(),
+        );
+        assert_ne!(
+            set([block([1, 2, 4, 5], 0..4)]).digest(),
+            set([block([1, 2, 4, 6], 0..4)]).digest(),
+        );
+        assert_ne!(
+            set([block([1, 2, 4, 5], 0..4)]).digest(),
+            set([block([1, 2, 4], 0..3)]).digest(),
+        );
+    }
+
+    #[test]
+    fn set_intern() {
+        fn packs(set: &Set) -> Vec<*const Payload> {
+            set.as_blocks().iter().map(|block| {
+                block.pack.items.as_ptr()
+            }).collect()
+        }
+
+        fn make_set(values: impl Iterator<Item = u32>) -> Set {
+            Set::from(Pack {
+                items: values.map(p).collect::<Vec<_>>().into()
+            })
+        }
+
+        let set = make_set(0..10_000);
+        let interned = set.intern();
+        check_set(&interned);
+        assert_eq!(interned, set);
+        assert!(interned.as_blocks().len() > 1);
+
+        // The same content built separately ends up in the same packs.
+        let again = make_set(0..10_000).intern();
+        assert_eq!(again, set);
+        assert_eq!(packs(&again), packs(&interned));
+
+        // Changing one item only changes the packs around it.
+        let changed = make_set((0..10_000).filter(|&v| v != 5_000)).intern();
+        check_set(&changed);
+        assert_eq!(changed.len(), 9_999);
+        let shared = packs(&changed).iter().filter(|ptr| {
+            packs(&interned).contains(ptr)
+        }).count();
+        assert!(shared + 2 >= interned.as_blocks().len());
+
+        // Dropped packs are removed from the table.
+        let mut dropped = packs(&interned);
+        dropped.extend(packs(&changed));
+        drop((set, interned, again, changed));
+        let mut interner = INTERNER.lock().unwrap();
+        interner.sweep();
+        assert!(interner.packs.values().flatten().all(|pack| {
+            !dropped.contains(&pack.as_ptr().cast())
+        }));
+    }
+
     #[test]
     fn set_builder() {
         let mut builder = SetBuilder::empty();
@@ -1459,6 +2513,42 @@ mod test {
         );
     }
 
+    #[test]
+    fn large_sets() {
+        // Large enough to be processed in parallel if there are multiple
+        // CPUs.
+        fn large_set(keep: impl Fn(u32) -> bool) -> Set {
+            let mut items: Vec<_> = (0..400_000).filter(|&value| {
+                keep(value)
+            }).map(p).collect();
+            items.sort_unstable();
+            Set::from(Pack { items: items.into() })
+        }
+
+        let source = large_set(|value| value % 7 != 0);
+        let target = large_set(|value| value % 5 != 0);
+
+        let diff = target.diff_from_parallel(&source);
+        assert_eq!(diff, target.diff_from(&source));
+        let mut announced = Vec::new();
+        let mut withdrawn = Vec::new();
+        diff_items(
+            source.iter(), target.iter(), &mut announced, &mut withdrawn
+        );
+        assert_eq!(diff.announced().as_slice(), announced.as_slice());
+        assert_eq!(diff.withdrawn().as_slice(), withdrawn.as_slice());
+        check_pack(diff.announced());
+        check_pack(diff.withdrawn());
+
+        let mut builder = SetBuilder::empty();
+        builder.insert_set(source.clone());
+        builder.insert_set(target.clone());
+        let merged = builder.clone().finalize_parallel();
+        check_set(&merged);
+        assert_eq!(merged, builder.finalize());
+        assert_eq!(merged, source.merge(&target));
+    }
+
     #[test]
     fn diff_iter() {
         use rpki::rtr::payload::Action::{Announce as A, Withdraw as W};
@@ -1575,6 +2665,40 @@ mod test {
         );
     }
 
+    #[test]
+    fn provenance() {
+        let mut update = update([1, 2]);
+        assert_eq!(update.provenance().to_string(), "(unknown)");
+
+        update.record_step("source".into());
+        update.record_step("merge".into());
+        let steps: Vec<_> = update.provenance().steps().iter().map(|step| {
+            step.component()
+        }).collect();
+        assert_eq!(steps, ["merge", "source"]);
+        assert!(!update.provenance().is_truncated());
+
+        // Provenance doesn’t affect equality.
+        assert_eq!(update, super::testrig::update([1, 2]));
+
+        // Derived updates keep the provenance.
+        let derived = update.derive(Set::default());
+        assert_eq!(derived.provenance().steps().len(), 2);
+
+        // Too many steps drop the oldest ones.
+        for _ in 0..Provenance::MAX_LEN {
+            update.record_step("any".into());
+        }
+        assert_eq!(update.provenance().steps().len(), Provenance::MAX_LEN);
+        assert!(update.provenance().is_truncated());
+        assert!(update.provenance().to_string().ends_with(" <- ..."));
+        assert!(
+            update.provenance().steps().iter().all(|step| {
+                step.component() == "any"
+            })
+        );
+    }
+
     #[test]
     fn owned_block_iter() {
         fn test_iter<const N: usize>(payload: [Payload; N], block: Block) {
@@ -1695,5 +2819,99 @@ mod test {
             ])
         );
     }
-}
+    #[test]
+    fn compressed_pack() {
+        let mut builder = PackBuilder::empty();
+        for item in slurm_pack(
+            include_bytes!("../test-data/router-keys.slurm.json")
+        ).iter() {
+            builder.insert_unchecked(item.clone())
+        }
+        for value in [1, 2, 7, 1000, 0xffff_ffff, 0x8000_0000] {
+            builder.insert_unchecked(p(value))
+        }
+        for (prefix, max_len, asn) in [
+            ("2001:db8::/32", Some(48), 64496),
+            ("2001:db8::/48", None, 0),
+            ("::/0", None, u32::MAX),
+            ("192.0.2.0/24", Some(24), 4_200_000_000),
+        ] {
+            let (addr, len) = prefix.split_once('/').unwrap();
+            builder.insert_unchecked(Payload::Origin(RouteOrigin::new(
+                MaxLenPrefix::new(
+                    Prefix::new(
+                        addr.parse().unwrap(), len.parse().unwrap()
+                    ).unwrap(),
+                    max_len
+                ).unwrap(),
+                asn.into()
+            )));
+        }
+        let full = builder.finalize();
 
+        let compressed = CompressedPack::compress(&full);
+        assert_eq!(compressed.len(), full.len());
+        assert_eq!(compressed.decompress(), full);
+        assert_eq!(
+            CompressedPack::compress(&Pack::default()).decompress(),
+            Pack::default()
+        );
+
+        let diff = Set::from(pack([1, 3, 4, 5])).diff_from(
+            &Set::from(pack([1, 2, 5, 6]))
+        );
+        let compressed = CompressedDiff::compress(&diff);
+        assert_eq!(compressed.len(), 4);
+        assert_eq!(compressed.decompress(), diff);
+    }
+
+    #[test]
+    fn trust_anchors() {
+        let mut builder = TrustAnchorsBuilder::empty();
+        builder.insert(p(3), "ripe");
+        builder.insert(p(1), "arin");
+        builder.insert(p(2), "ripe");
+        builder.insert(p(1), "ripe");
+        let tas = builder.finalize();
+        assert_eq!(tas.get(&p(1)), Some("arin"));
+        assert_eq!(tas.get(&p(2)), Some("ripe"));
+        assert_eq!(tas.get(&p(4)), None);
+        assert_eq!(
+            tas.counts(&Set::from(pack([1, 3, 4, 5]))),
+            [("ripe", 1), ("arin", 1)]
+        );
+
+        let update = Update::new(Set::from(pack([1, 2]))).with_trust_anchors(
+            tas
+        );
+        let update = update.derive(Set::from(pack([2])));
+        assert_eq!(
+            update.trust_anchors().and_then(|tas| tas.get(&p(2))),
+            Some("ripe")
+        );
+    }
+
+    #[test]
+    fn update_diff() {
+        let base = Set::from(pack([1, 2, 3]));
+        let other = Set::from(pack([1, 2, 3]));
+        assert!(base.downgrade().is(&base.clone()));
+        assert!(!base.downgrade().is(&other));
+
+        let new = Set::from(pack([2, 3, 4]));
+        let diff = new.diff_from(&base);
+        let update = Update::new(new.clone()).with_diff(&base, diff.clone());
+        assert_eq!(update.diff_since(&base), diff);
+        assert_eq!(update.diff_since(&other), diff);
+        assert_eq!(
+            update.diff_since(&Set::from(pack([4]))),
+            new.diff_from(&Set::from(pack([4])))
+        );
+
+        // Deriving a new update drops the diff.
+        let update = update.derive(Set::from(pack([3])));
+        assert_eq!(
+            update.diff_since(&base), Set::from(pack([3])).diff_from(&base)
+        );
+    }
+}
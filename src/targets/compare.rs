@@ -0,0 +1,316 @@
+//! A target comparing the data sets of two units.
+//!
+//! The _compare_ target keeps the current data sets of two units and
+//! determines the entries only present in one of them whenever either unit
+//! produces an update. The differences are available as a JSON document
+//! via the HTTP server and their sizes via metrics. This is helpful when
+//! migrating from one relying party software to another, e.g., by feeding
+//! the target with an _rtr_ unit for each of them.
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use arc_swap::ArcSwap;
+use chrono::{SecondsFormat, Utc};
+use daemonbase::error::ExitError;
+use hyper::Method;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use crate::{metrics, payload};
+use crate::comms::{Link, UnitUpdate};
+use crate::formats::output;
+use crate::http::{ContentType, HttpAuth, Request, ResponseBuilder};
+use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+
+
+//------------ Target --------------------------------------------------------
+
+/// A target comparing the data sets of two units.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    /// The first unit to compare.
+    a: Link,
+
+    /// The second unit to compare.
+    b: Link,
+
+    /// The path the differences are available under.
+    path: String,
+
+    /// The name of the HTTP server to use.
+    ///
+    /// If this is `None`, the default server is used.
+    server: Option<String>,
+
+    /// The authentication required for accessing the differences.
+    #[serde(default)]
+    auth: HttpAuth,
+}
+
+impl Target {
+    /// Converts the target into the links to its units.
+    pub fn into_links(self) -> Vec<Link> {
+        vec![self.a, self.b]
+    }
+
+    /// Returns the name of the HTTP server the target should use.
+    pub fn server(&self) -> Option<&str> {
+        self.server.as_deref()
+    }
+
+    /// Returns the path the target is available under.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Runs the target.
+    pub async fn run(
+        mut self, mut component: Component
+    ) -> Result<(), ExitError> {
+        let metrics = Arc::new(CompareMetrics::default());
+        component.register_metrics(metrics.clone());
+
+        let current = Arc::new(ArcSwap::from_pointee(None::<Comparison>));
+        let http_current = current.clone();
+        let path = self.path.clone();
+        let auth = self.auth.clone();
+        let processor = Arc::new(
+            move |request: &Request| {
+                if
+                    request.method() != Method::GET
+                    || request.uri().path() != path
+                {
+                    return None
+                }
+                if let Err(response) = auth.check(request) {
+                    return Some(*response)
+                }
+                let current = http_current.load();
+                Some(match current.as_ref() {
+                    Some(comparison) => {
+                        ResponseBuilder::ok()
+                        .content_type(ContentType::JSON)
+                        .body(comparison.json.clone())
+                    }
+                    None => {
+                        ResponseBuilder::service_unavailable()
+                        .content_type(ContentType::TEXT)
+                        .body("Waiting for data from both units.")
+                    }
+                })
+            }
+        );
+        component.register_http_resource(
+            processor.clone(), self.server.as_deref()
+        );
+
+        // We only become ready once we have data from both units.
+        component.set_started();
+
+        let mut set_a: Option<payload::Set> = None;
+        let mut set_b: Option<payload::Set> = None;
+        loop {
+            let (update, is_a) = tokio::select! {
+                update = self.a.query() => (update, true),
+                update = self.b.query() => (update, false),
+            };
+            let update = match update {
+                UnitUpdate::Payload(update) => update,
+                _ => continue,
+            };
+            debug!(
+                "Target {}: Got update ({} entries) for unit {} via {}",
+                component.name(), update.set().len(),
+                if is_a { "a" } else { "b" }, update.provenance()
+            );
+            if is_a {
+                set_a = Some(update.set().clone());
+            }
+            else {
+                set_b = Some(update.set().clone());
+            }
+            let (a, b) = match (set_a.as_ref(), set_b.as_ref()) {
+                (Some(a), Some(b)) => (a.clone(), b.clone()),
+                _ => continue,
+            };
+            let comparison = match tokio::task::spawn_blocking(move || {
+                Comparison::new(&a, &b)
+            }).await {
+                Ok(comparison) => comparison,
+                Err(err) => {
+                    error!(
+                        "Target {}: failed to compare data sets: {}",
+                        component.name(), err
+                    );
+                    continue
+                }
+            };
+            debug!(
+                "Target {}: {} entries only in a, {} only in b.",
+                component.name(), comparison.only_a, comparison.only_b
+            );
+            metrics.update(&comparison);
+            current.store(Arc::new(Some(comparison)));
+            component.set_ready(true);
+        }
+    }
+}
+
+
+//------------ Comparison ----------------------------------------------------
+
+/// The result of comparing two data sets.
+#[derive(Clone, Debug)]
+struct Comparison {
+    /// The number of entries only in the first data set.
+    only_a: usize,
+
+    /// The number of entries only in the second data set.
+    only_b: usize,
+
+    /// The number of entries in both data sets.
+    common: usize,
+
+    /// The JSON document with the differences.
+    json: Vec<u8>,
+}
+
+impl Comparison {
+    /// Compares the two data sets.
+    ///
+    /// For large sets, this blocks the current thread while the diff is
+    /// calculated on several threads.
+    fn new(a: &payload::Set, b: &payload::Set) -> Self {
+        #[derive(Serialize)]
+        #[serde(rename_all = "kebab-case")]
+        struct Json {
+            generated: String,
+            common: usize,
+            only_in_a: serde_json::Value,
+            only_in_b: serde_json::Value,
+        }
+
+        // The diff from a to b announces what is only in b and withdraws
+        // what is only in a.
+        let diff = b.diff_from_parallel(a);
+        let only_a = diff.withdrawn().len();
+        let only_b = diff.announced().len();
+        let common = a.len() - only_a;
+        let json = serde_json::to_vec(&Json {
+            generated: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            common,
+            only_in_a: Self::pack_json(diff.withdrawn()),
+            only_in_b: Self::pack_json(diff.announced()),
+        }).unwrap_or_default();
+        Comparison { only_a, only_b, common, json }
+    }
+
+    /// Converts a pack into the JSON output format.
+    fn pack_json(pack: &payload::Pack) -> serde_json::Value {
+        let data: Vec<u8> = output::Format::Json.stream(
+            pack.clone().into(), Default::default(), None
+        ).flatten().collect();
+        serde_json::from_slice(&data).unwrap_or_default()
+    }
+}
+
+
+//------------ CompareMetrics ------------------------------------------------
+
+/// The metrics of a compare target.
+#[derive(Debug, Default)]
+struct CompareMetrics {
+    /// The number of entries only in the first data set.
+    only_a: AtomicU64,
+
+    /// The number of entries only in the second data set.
+    only_b: AtomicU64,
+
+    /// The number of entries in both data sets.
+    common: AtomicU64,
+
+    /// The number of comparisons made.
+    comparisons: AtomicU64,
+}
+
+impl CompareMetrics {
+    const ONLY_A_METRIC: Metric = Metric::new(
+        "compare_only_a",
+        "number of entries only present in the data set of unit a",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const ONLY_B_METRIC: Metric = Metric::new(
+        "compare_only_b",
+        "number of entries only present in the data set of unit b",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const COMMON_METRIC: Metric = Metric::new(
+        "compare_common",
+        "number of entries present in both data sets",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const COMPARISONS_METRIC: Metric = Metric::new(
+        "compare_runs",
+        "number of times the data sets have been compared",
+        MetricType::Counter, MetricUnit::Total
+    );
+
+    /// Updates the metrics from a new comparison.
+    fn update(&self, comparison: &Comparison) {
+        self.only_a.store(comparison.only_a as u64, Relaxed);
+        self.only_b.store(comparison.only_b as u64, Relaxed);
+        self.common.store(comparison.common as u64, Relaxed);
+        self.comparisons.fetch_add(1, Relaxed);
+    }
+}
+
+impl metrics::Source for CompareMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::ONLY_A_METRIC, Some(unit_name), self.only_a.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::ONLY_B_METRIC, Some(unit_name), self.only_b.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::COMMON_METRIC, Some(unit_name), self.common.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::COMPARISONS_METRIC, Some(unit_name),
+            self.comparisons.load(Relaxed)
+        );
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::payload::testrig;
+
+    #[test]
+    fn comparison() {
+        let a = payload::Set::from(testrig::pack([1, 2, 3, 4]));
+        let b = payload::Set::from(testrig::pack([3, 4, 5]));
+        let comparison = Comparison::new(&a, &b);
+        assert_eq!(comparison.only_a, 2);
+        assert_eq!(comparison.only_b, 1);
+        assert_eq!(comparison.common, 2);
+
+        let json: serde_json::Value = serde_json::from_slice(
+            &comparison.json
+        ).unwrap();
+        assert_eq!(json["common"], 2);
+        assert_eq!(json["only-in-a"]["roas"].as_array().unwrap().len(), 2);
+        assert_eq!(json["only-in-b"]["roas"].as_array().unwrap().len(), 1);
+
+        let comparison = Comparison::new(&a, &a);
+        assert_eq!(comparison.only_a, 0);
+        assert_eq!(comparison.only_b, 0);
+        assert_eq!(comparison.common, 4);
+    }
+}
@@ -0,0 +1,284 @@
+//! A target writing the data set to a file.
+//!
+//! The _file_ target writes the data set of its unit to a local file in
+//! one of the output formats whenever the unit produces an update. This
+//! allows feeding tools that can only read files.
+//!
+//! The file is replaced atomically: the data is first written to a
+//! temporary file next to it which is then renamed. Readers thus always see
+//! a complete data set.
+
+use std::{fs, io};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+use daemonbase::config::ConfigPath;
+use daemonbase::error::ExitError;
+use log::{debug, error};
+use rpki::rtr::State;
+use serde::Deserialize;
+use tokio::time::{Instant, sleep_until};
+use crate::{metrics, payload};
+use crate::comms::{Link, UnitUpdate};
+use crate::formats::output;
+use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+use super::limits::{MaxPrefixLen, MaxPrefixLenMetrics};
+
+
+//------------ Target --------------------------------------------------------
+
+/// A target writing the data set to a file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    /// The unit whose data set should be written.
+    unit: Link,
+
+    /// The path of the file to write.
+    path: ConfigPath,
+
+    /// The format of the file.
+    format: output::Format,
+
+    /// The order of the items in the output.
+    #[serde(default)]
+    order: output::Order,
+
+    /// Wrap the output with a metadata object?
+    #[serde(default)]
+    metadata: bool,
+
+    /// The maximum prefix lengths of route origins to write.
+    #[serde(default)]
+    #[serde(rename = "max-prefix-length")]
+    max_prefix_len: MaxPrefixLen,
+
+    /// The number of seconds to wait for further updates before writing.
+    ///
+    /// Every new update restarts the wait so that only the last of a
+    /// sequence of quick updates is written.
+    #[serde(default)]
+    debounce: u64,
+}
+
+impl Target {
+    /// Converts the target into the links to its units.
+    pub fn into_links(self) -> Vec<Link> {
+        vec![self.unit]
+    }
+
+    /// Runs the target.
+    pub async fn run(
+        mut self, mut component: Component
+    ) -> Result<(), ExitError> {
+        let metrics = Arc::new(FileMetrics::default());
+        component.register_metrics(metrics.clone());
+        let limit_metrics = Arc::new(MaxPrefixLenMetrics::default());
+        if !self.max_prefix_len.is_unlimited() {
+            component.register_metrics(limit_metrics.clone());
+        }
+        let path = {
+            let path: &Path = self.path.as_ref();
+            path.to_path_buf()
+        };
+        let debounce = Duration::from_secs(self.debounce);
+        let mut state = State::new();
+        let mut pending: Option<(payload::Update, Instant)> = None;
+
+        // There is nothing to set up, so we have started. We only become
+        // ready once we have written the file.
+        component.set_started();
+
+        loop {
+            let deadline = pending.as_ref().map(|item| item.1);
+            let update = tokio::select! {
+                update = self.unit.query() => update,
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)),
+                    if deadline.is_some() =>
+                {
+                    if let Some((update, _)) = pending.take() {
+                        self.write(
+                            update, &path, &mut state, &component, &metrics
+                        ).await;
+                    }
+                    continue
+                }
+            };
+            if let UnitUpdate::Payload(update) = update {
+                debug!(
+                    "Target {}: Got update ({} entries) via {}",
+                    component.name(), update.set().len(),
+                    update.provenance()
+                );
+                let update = self.max_prefix_len.apply(
+                    update, &limit_metrics
+                );
+                if debounce.is_zero() {
+                    self.write(
+                        update, &path, &mut state, &component, &metrics
+                    ).await;
+                }
+                else {
+                    pending = Some((update, Instant::now() + debounce));
+                }
+            }
+        }
+    }
+
+    /// Writes an update to the file.
+    ///
+    /// Failures are logged and recorded in the metrics.
+    async fn write(
+        &self,
+        update: payload::Update,
+        path: &Path,
+        state: &mut State,
+        component: &Component,
+        metrics: &FileMetrics,
+    ) {
+        let metadata = (
+            self.metadata || self.format.has_metadata()
+        ).then(|| {
+            output::Metadata::new(
+                &update, state.serial().into()
+            ).with_session(state.session())
+        });
+        state.inc();
+        let stream = self.format.stream(
+            update.set().clone(), self.order, metadata
+        );
+        let target_path = path.to_path_buf();
+        let res = tokio::task::spawn_blocking(move || {
+            write_atomic(&target_path, stream)
+        }).await;
+        match res {
+            Ok(Ok(())) => {
+                debug!(
+                    "Target {}: wrote {} entries to {}.",
+                    component.name(), update.set().len(), path.display()
+                );
+                metrics.written.fetch_add(1, Relaxed);
+                component.set_ready(true);
+            }
+            Ok(Err(err)) => {
+                error!(
+                    "Target {}: failed to write {}: {}",
+                    component.name(), path.display(), err
+                );
+                metrics.failed.fetch_add(1, Relaxed);
+            }
+            Err(err) => {
+                error!(
+                    "Target {}: failed to write {}: {}",
+                    component.name(), path.display(), err
+                );
+                metrics.failed.fetch_add(1, Relaxed);
+            }
+        }
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Atomically replaces a file with the output of a stream.
+pub(super) fn write_atomic(
+    path: &Path, stream: impl IntoIterator<Item = Vec<u8>>
+) -> Result<(), io::Error> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let res = (|| {
+        let mut file = io::BufWriter::new(fs::File::create(&tmp_path)?);
+        for chunk in stream {
+            file.write_all(&chunk)?;
+        }
+        file.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        fs::rename(&tmp_path, path)
+    })();
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    res
+}
+
+
+//------------ FileMetrics ---------------------------------------------------
+
+/// The metrics of a file target.
+#[derive(Debug, Default)]
+struct FileMetrics {
+    /// The number of times the file has been written.
+    written: AtomicU64,
+
+    /// The number of failed attempts to write the file.
+    failed: AtomicU64,
+}
+
+impl FileMetrics {
+    const WRITTEN_METRIC: Metric = Metric::new(
+        "file_target_writes",
+        "number of times the file has been written",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const FAILED_METRIC: Metric = Metric::new(
+        "file_target_write_failures",
+        "number of failed attempts to write the file",
+        MetricType::Counter, MetricUnit::Total
+    );
+}
+
+impl metrics::Source for FileMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::WRITTEN_METRIC, Some(unit_name),
+            self.written.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::FAILED_METRIC, Some(unit_name),
+            self.failed.load(Relaxed)
+        );
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::payload::testrig;
+
+    #[test]
+    fn write_file() {
+        let dir = std::env::temp_dir().join(format!(
+            "rtrtr-file-target-{}", std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("vrps.csv");
+
+        let set = payload::Set::from(testrig::pack([1, 2]));
+        write_atomic(
+            &path,
+            output::Format::Csv.stream(set, Default::default(), None)
+        ).unwrap();
+        let data = fs::read_to_string(&path).unwrap();
+        assert_eq!(data.lines().count(), 3);
+        assert!(!dir.join("vrps.csv.tmp").exists());
+
+        write_atomic(
+            &path,
+            output::Format::Csv.stream(
+                payload::Set::default(), Default::default(), None
+            )
+        ).unwrap();
+        let data = fs::read_to_string(&path).unwrap();
+        assert_eq!(data.lines().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,22 +1,46 @@
 //! A target using the HTTP server.
+//!
+//! The target serves the data set of a unit in a given format under a
+//! given path. The types of payload included can be limited via the
+//! `include` option and further per request via the `include` query
+//! parameter.
+//!
+//! The output is produced on the fly while it is being sent in chunks of
+//! limited size. This keeps memory use low even for large data sets and
+//! many concurrent requests.
 
+use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
+use std::sync::atomic::Ordering::Relaxed;
+use std::task::{Context, Poll};
 use arc_swap::ArcSwap;
+use bytes::Bytes;
 use daemonbase::error::ExitError;
 use chrono::{DateTime, Utc};
-use futures_util::stream;
+use futures_util::stream::Stream;
 use hyper::Method;
 use hyper::header::{IF_NONE_MATCH, IF_MODIFIED_SINCE};
 use log::debug;
 use rpki::rtr::State;
 use serde::Deserialize;
-use crate::payload;
+use crate::{metrics, payload};
 use crate::comms::{Link, UnitUpdate};
 use crate::formats::output;
-use crate::http::{ContentType, Response, ResponseBuilder, Request};
+use crate::http::{
+    ContentType, HttpAuth, Response, ResponseBuilder, Request
+};
 use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
 use crate::utils::http::EtagsIter;
 use crate::utils::http::parse_http_date;
+use super::limits::{MaxPrefixLen, MaxPrefixLenMetrics};
+
+
+//------------ Configuration -------------------------------------------------
+
+/// The size of the chunks the output is sent in.
+const CHUNK_SIZE: usize = 64 * 1024;
 
 
 //------------ Target --------------------------------------------------------
@@ -27,15 +51,68 @@ pub struct Target {
     path: String,
     format: output::Format,
     unit: Link,
+
+    /// The order of the items in the output.
+    #[serde(default)]
+    order: output::Order,
+
+    /// Wrap the output with a metadata object?
+    #[serde(default)]
+    metadata: bool,
+
+    /// The types of payload to include in the output by default.
+    #[serde(default)]
+    include: output::Include,
+
+    /// The maximum prefix lengths of route origins to serve.
+    #[serde(default)]
+    #[serde(rename = "max-prefix-length")]
+    max_prefix_len: MaxPrefixLen,
+
+    /// The name of the HTTP server to use.
+    ///
+    /// If this is `None`, the default server is used.
+    server: Option<String>,
+
+    /// The authentication required for accessing the data set.
+    #[serde(default)]
+    auth: HttpAuth,
 }
 
 impl Target {
+    /// Converts the target into the links to its units.
+    pub fn into_links(self) -> Vec<Link> {
+        vec![self.unit]
+    }
+
+    /// Returns the name of the HTTP server the target should use.
+    pub fn server(&self) -> Option<&str> {
+        self.server.as_deref()
+    }
+
+    /// Returns the path the target is available under.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
     /// Runs the target.
     pub async fn run(
         self, mut component: Component
     ) -> Result<(), ExitError> {
         let source = Source::default();
         let (path, format, mut unit) = (self.path, self.format, self.unit);
+        let order = self.order;
+        let with_metadata = self.metadata || format.has_metadata();
+        let include = self.include;
+        let server = self.server;
+        let auth = self.auth;
+        let max_prefix_len = self.max_prefix_len;
+        let limit_metrics = Arc::new(MaxPrefixLenMetrics::default());
+        if !max_prefix_len.is_unlimited() {
+            component.register_metrics(limit_metrics.clone());
+        }
+        let metrics = Arc::new(HttpTargetMetrics::default());
+        component.register_metrics(metrics.clone());
 
         let http_source = source.clone();
         
@@ -47,6 +124,9 @@ impl Target {
                 {
                     return None
                 }
+                if let Err(response) = auth.check(request) {
+                    return Some(*response)
+                }
 
                 let update = http_source.data();
                 let update = match update.as_ref() {
@@ -60,6 +140,20 @@ impl Target {
                     }
                 };
 
+                let include = match output::Include::from_query(
+                    request.uri().query()
+                ) {
+                    Ok(Some(requested)) => requested,
+                    Ok(None) => include,
+                    Err(err) => {
+                        return Some(
+                            ResponseBuilder::bad_request()
+                            .content_type(ContentType::TEXT)
+                            .body(err)
+                        )
+                    }
+                };
+
                 if update.is_not_modified(request) {
                     return Some(update.not_modified())
                 }
@@ -70,14 +164,23 @@ impl Target {
                     .etag(&update.etag)
                     .last_modified(update.created)
                     .stream(
-                        stream::iter(
-                            format.stream(update.set.clone()).map(Into::into)
+                        Body::new(
+                            format.stream(
+                                include.apply(&update.set), order,
+                                update.metadata.as_ref().map(|metadata| {
+                                    metadata.restrict(include)
+                                }),
+                            ).chunks(CHUNK_SIZE),
+                            metrics.clone()
                         )
                     )
                 )
             }
         );
-        component.register_http_resource(processor.clone());
+        component.register_http_resource(
+            processor.clone(), server.as_deref()
+        );
+        component.set_ready(true);
 
         let mut state = State::new();
 
@@ -87,16 +190,116 @@ impl Target {
             );
             if let UnitUpdate::Payload(update) = unit.query().await {
                 debug!(
-                    "Target {}: Got update ({} entries)",
-                    component.name(), update.set().len()
+                    "Target {}: Got update ({} entries) via {}",
+                    component.name(), update.set().len(),
+                    update.provenance()
+                );
+                let update = max_prefix_len.apply(update, &limit_metrics);
+                source.update(
+                    SourceData::new(&update, &mut state, with_metadata)
                 );
-                source.update(SourceData::new(&update, &mut state));
             }
         }
     }
 }
 
 
+//------------ Body ----------------------------------------------------------
+
+/// The body of a response.
+///
+/// The body produces the next chunk of output only when the connection is
+/// ready to send it. It counts the bytes sent and keeps the request counted
+/// as in flight until it is dropped.
+struct Body {
+    /// The chunks of output.
+    chunks: output::Chunks,
+
+    /// The metrics of the target.
+    metrics: Arc<HttpTargetMetrics>,
+}
+
+impl Body {
+    /// Creates a new body and starts counting it as in flight.
+    fn new(chunks: output::Chunks, metrics: Arc<HttpTargetMetrics>) -> Self {
+        metrics.requests.fetch_add(1, Relaxed);
+        metrics.in_flight.fetch_add(1, Relaxed);
+        Body { chunks, metrics }
+    }
+}
+
+impl Stream for Body {
+    type Item = Bytes;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>, _cx: &mut Context
+    ) -> Poll<Option<Self::Item>> {
+        Poll::Ready(self.chunks.next().map(|chunk| {
+            self.metrics.bytes.fetch_add(
+                u64::try_from(chunk.len()).unwrap_or(u64::MAX), Relaxed
+            );
+            chunk.into()
+        }))
+    }
+}
+
+impl Drop for Body {
+    fn drop(&mut self) {
+        self.metrics.in_flight.fetch_sub(1, Relaxed);
+    }
+}
+
+
+//------------ HttpTargetMetrics ---------------------------------------------
+
+/// The metrics of an HTTP target.
+#[derive(Debug, Default)]
+struct HttpTargetMetrics {
+    /// The number of requests answered with the data set.
+    requests: AtomicU64,
+
+    /// The number of bytes of data sets sent.
+    bytes: AtomicU64,
+
+    /// The number of responses currently being sent.
+    in_flight: AtomicUsize,
+}
+
+impl HttpTargetMetrics {
+    const REQUESTS_METRIC: Metric = Metric::new(
+        "http_target_requests",
+        "number of requests answered with the data set",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const BYTES_METRIC: Metric = Metric::new(
+        "http_target_bytes_served",
+        "number of bytes of data sets served",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const IN_FLIGHT_METRIC: Metric = Metric::new(
+        "http_target_requests_in_flight",
+        "number of responses currently being sent",
+        MetricType::Gauge, MetricUnit::Total
+    );
+}
+
+impl metrics::Source for HttpTargetMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::REQUESTS_METRIC, Some(unit_name),
+            self.requests.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::BYTES_METRIC, Some(unit_name), self.bytes.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::IN_FLIGHT_METRIC, Some(unit_name),
+            self.in_flight.load(Relaxed)
+        );
+    }
+}
+
+
 //------------ Source --------------------------------------------------------
 
 /// The date source for an HTTP target.
@@ -127,16 +330,27 @@ struct SourceData {
     set: payload::Set,
     etag: String,
     created: DateTime<Utc>,
+
+    /// The metadata to wrap the output with if requested.
+    metadata: Option<output::Metadata>,
 }
 
 impl SourceData {
-    fn new(update: &payload::Update, state: &mut State) -> Self {
+    fn new(
+        update: &payload::Update, state: &mut State, with_metadata: bool
+    ) -> Self {
         let etag = format!("\"{:x}-{}\"", state.session(), state.serial());
+        let metadata = with_metadata.then(|| {
+            output::Metadata::new(
+                update, state.serial().into()
+            ).with_session(state.session())
+        });
         state.inc();
         Self {
             set: update.set().clone(),
             etag,
             created: Utc::now(),
+            metadata,
         }
     }
 
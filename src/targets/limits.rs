@@ -0,0 +1,172 @@
+//! Limits applied to payload before it is served by a target.
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use rpki::rtr::payload::Payload;
+use serde::Deserialize;
+use crate::{metrics, payload};
+use crate::metrics::{Metric, MetricType, MetricUnit};
+
+
+//------------ MaxPrefixLen --------------------------------------------------
+
+/// The maximum prefix lengths of route origins per address family.
+///
+/// Route origins whose resolved max length exceeds the limit for their
+/// address family are dropped before the data is served. Router keys and
+/// ASPA records are never affected.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaxPrefixLen {
+    /// The limit for IPv4 prefixes.
+    ipv4: Option<u8>,
+
+    /// The limit for IPv6 prefixes.
+    ipv6: Option<u8>,
+}
+
+impl MaxPrefixLen {
+    /// Returns whether no limits have been configured at all.
+    pub fn is_unlimited(&self) -> bool {
+        self.ipv4.is_none() && self.ipv6.is_none()
+    }
+
+    /// Returns whether the given payload item is within the limits.
+    fn permits(&self, payload: &Payload) -> bool {
+        let origin = match payload {
+            Payload::Origin(origin) => origin,
+            _ => return true,
+        };
+        let limit = if origin.prefix.addr().is_ipv4() {
+            self.ipv4
+        }
+        else {
+            self.ipv6
+        };
+        match limit {
+            Some(limit) => origin.prefix.resolved_max_len() <= limit,
+            None => true,
+        }
+    }
+
+    /// Applies the limits to the payload set of an update.
+    ///
+    /// The number of dropped entries is recorded in `metrics`.
+    pub fn apply(
+        &self, update: payload::Update, metrics: &MaxPrefixLenMetrics,
+    ) -> payload::Update {
+        if self.is_unlimited() {
+            return update
+        }
+        let (set, dropped_v4, dropped_v6) = self.filter(update.set());
+        metrics.ipv4.store(dropped_v4, Relaxed);
+        metrics.ipv6.store(dropped_v6, Relaxed);
+        if dropped_v4 == 0 && dropped_v6 == 0 {
+            update
+        }
+        else {
+            update.derive(set)
+        }
+    }
+
+    /// Filters a set, returning the dropped entries per address family.
+    fn filter(&self, set: &payload::Set) -> (payload::Set, usize, usize) {
+        let mut dropped_v4 = 0;
+        let mut dropped_v6 = 0;
+        let set = set.filter(|payload| {
+            if self.permits(payload) {
+                return true
+            }
+            if let Payload::Origin(origin) = payload {
+                if origin.prefix.addr().is_ipv4() {
+                    dropped_v4 += 1;
+                }
+                else {
+                    dropped_v6 += 1;
+                }
+            }
+            false
+        });
+        (set, dropped_v4, dropped_v6)
+    }
+}
+
+
+//------------ MaxPrefixLenMetrics -------------------------------------------
+
+/// The metrics for the prefix length limits of a target.
+#[derive(Debug, Default)]
+pub struct MaxPrefixLenMetrics {
+    /// The number of IPv4 route origins dropped from the current set.
+    ipv4: AtomicUsize,
+
+    /// The number of IPv6 route origins dropped from the current set.
+    ipv6: AtomicUsize,
+}
+
+impl MaxPrefixLenMetrics {
+    const DROPPED_METRIC: Metric = Metric::new(
+        "target_max_prefix_length_dropped",
+        "number of route origins dropped for exceeding the max length limit",
+        MetricType::Gauge, MetricUnit::Total
+    );
+}
+
+impl metrics::Source for MaxPrefixLenMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append(&Self::DROPPED_METRIC, Some(unit_name), |records| {
+            records.label_value(
+                &[("family", "ipv4")], self.ipv4.load(Relaxed)
+            );
+            records.label_value(
+                &[("family", "ipv6")], self.ipv6.load(Relaxed)
+            );
+        });
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rpki::resources::addr::{MaxLenPrefix, Prefix};
+
+    fn origin(prefix: &str, max_len: u8) -> Payload {
+        Payload::origin(
+            MaxLenPrefix::new(
+                prefix.parse::<Prefix>().unwrap(), Some(max_len)
+            ).unwrap(),
+            0.into()
+        )
+    }
+
+    #[test]
+    fn filter() {
+        let mut pack = payload::PackBuilder::empty();
+        pack.insert(origin("192.0.2.0/24", 24)).unwrap();
+        pack.insert(origin("198.51.100.0/22", 25)).unwrap();
+        pack.insert(origin("2001:db8::/32", 48)).unwrap();
+        pack.insert(origin("2001:db8::/32", 64)).unwrap();
+        let set = payload::Set::from(pack.finalize());
+
+        let limits: MaxPrefixLen = toml::from_str(
+            "ipv4 = 24\nipv6 = 48"
+        ).unwrap();
+        let (filtered, v4, v6) = limits.filter(&set);
+        assert_eq!((filtered.len(), v4, v6), (2, 1, 1));
+        assert!(filtered.iter().all(|item| limits.permits(item)));
+
+        let limits: MaxPrefixLen = toml::from_str("ipv6 = 32").unwrap();
+        let (filtered, v4, v6) = limits.filter(&set);
+        assert_eq!((filtered.len(), v4, v6), (2, 0, 2));
+
+        let unlimited = MaxPrefixLen::default();
+        assert!(unlimited.is_unlimited());
+        let (filtered, v4, v6) = unlimited.filter(&set);
+        assert_eq!((filtered.len(), v4, v6), (4, 0, 0));
+
+        assert!(toml::from_str::<MaxPrefixLen>("ipv5 = 24").is_err());
+    }
+}
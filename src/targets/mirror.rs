@@ -0,0 +1,474 @@
+//! A target uploading the data set to a remote HTTP server.
+//!
+//! The _mirror_ target serializes the data set of its unit in one of the
+//! output formats and uploads it to a remote HTTP endpoint via PUT or POST
+//! whenever the unit produces an update. This allows feeding systems that
+//! can neither poll RTRTR nor speak RTR.
+//!
+//! Failed uploads are retried with increasing delays. If a new update
+//! arrives while an upload is still being retried, the old data set is
+//! abandoned in favour of the new one.
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+use bytes::Bytes;
+use chrono::Utc;
+use crossbeam_utils::atomic::AtomicCell;
+use daemonbase::error::ExitError;
+use log::{debug, error, warn};
+use reqwest::{header, Method};
+use rpki::rtr::State;
+use serde::Deserialize;
+use url::Url;
+use crate::{metrics, payload};
+use crate::comms::{Link, UnitUpdate};
+use crate::config::Secret;
+use crate::formats::output;
+use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+use super::limits::{MaxPrefixLen, MaxPrefixLenMetrics};
+
+
+//------------ Configuration -------------------------------------------------
+
+/// The delay before the first retry of a failed upload.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The maximum delay between two retries of a failed upload.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+
+//------------ Target --------------------------------------------------------
+
+/// A target uploading the data set to a remote HTTP server.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    /// The unit whose data set should be uploaded.
+    unit: Link,
+
+    /// The URI to upload the data set to.
+    uri: Url,
+
+    /// The HTTP method to use for uploading.
+    #[serde(default)]
+    method: UploadMethod,
+
+    /// The format of the uploaded data.
+    format: output::Format,
+
+    /// The order of the items in the output.
+    #[serde(default)]
+    order: output::Order,
+
+    /// Wrap the output with a metadata object?
+    #[serde(default)]
+    metadata: bool,
+
+    /// The maximum prefix lengths of route origins to upload.
+    #[serde(default)]
+    #[serde(rename = "max-prefix-length")]
+    max_prefix_len: MaxPrefixLen,
+
+    /// The user name for HTTP basic authentication.
+    username: Option<String>,
+
+    /// The password for HTTP basic authentication.
+    password: Option<Secret>,
+
+    /// The token for HTTP bearer authentication.
+    #[serde(rename = "bearer-token")]
+    bearer_token: Option<Secret>,
+
+    /// How often to retry a failed upload.
+    ///
+    /// If this is `None`, uploads are retried until a new update arrives.
+    retries: Option<u32>,
+
+    /// The timeout for a single upload attempt in seconds.
+    #[serde(default = "Target::default_timeout")]
+    timeout: u64,
+}
+
+impl Target {
+    /// Converts the target into the links to its units.
+    pub fn into_links(self) -> Vec<Link> {
+        vec![self.unit]
+    }
+
+    /// The default for the `timeout` value.
+    fn default_timeout() -> u64 {
+        60
+    }
+
+    /// Runs the target.
+    pub async fn run(
+        mut self, mut component: Component
+    ) -> Result<(), ExitError> {
+        let metrics = Arc::new(MirrorMetrics::default());
+        component.register_metrics(metrics.clone());
+        let limit_metrics = Arc::new(MaxPrefixLenMetrics::default());
+        if !self.max_prefix_len.is_unlimited() {
+            component.register_metrics(limit_metrics.clone());
+        }
+        let uploader = Uploader::new(&self, &component)?;
+        let mut state = State::new();
+        let mut pending: Option<Bytes> = None;
+
+        // There is nothing to set up, so we have started. We only become
+        // ready once we have uploaded the data set.
+        component.set_started();
+
+        loop {
+            let update = match pending.take() {
+                Some(body) => {
+                    tokio::select! {
+                        update = self.unit.query() => {
+                            // Only a new data set replaces the pending one.
+                            if matches!(update, UnitUpdate::Payload(_)) {
+                                metrics.abandoned.fetch_add(1, Relaxed);
+                            }
+                            else {
+                                pending = Some(body);
+                            }
+                            update
+                        }
+                        _ = uploader.upload(
+                            body.clone(), &component, &metrics
+                        ) => {
+                            continue
+                        }
+                    }
+                }
+                None => self.unit.query().await,
+            };
+            if let UnitUpdate::Payload(update) = update {
+                debug!(
+                    "Target {}: Got update ({} entries) via {}",
+                    component.name(), update.set().len(),
+                    update.provenance()
+                );
+                let update = self.max_prefix_len.apply(
+                    update, &limit_metrics
+                );
+                pending = Some(self.serialize(update, &mut state).await);
+            }
+        }
+    }
+
+    /// Serializes an update into the body of an upload.
+    async fn serialize(
+        &self, update: payload::Update, state: &mut State,
+    ) -> Bytes {
+        let metadata = (
+            self.metadata || self.format.has_metadata()
+        ).then(|| {
+            output::Metadata::new(
+                &update, state.serial().into()
+            ).with_session(state.session())
+        });
+        state.inc();
+        let stream = self.format.stream(
+            update.into_set(), self.order, metadata
+        );
+        tokio::task::spawn_blocking(move || {
+            stream.flatten().collect::<Vec<u8>>()
+        }).await.map(Bytes::from).unwrap_or_default()
+    }
+}
+
+
+//------------ UploadMethod --------------------------------------------------
+
+/// The HTTP method used for uploading.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+enum UploadMethod {
+    #[default]
+    #[serde(rename = "PUT")]
+    Put,
+
+    #[serde(rename = "POST")]
+    Post,
+}
+
+impl From<UploadMethod> for Method {
+    fn from(method: UploadMethod) -> Self {
+        match method {
+            UploadMethod::Put => Method::PUT,
+            UploadMethod::Post => Method::POST,
+        }
+    }
+}
+
+
+//------------ Uploader ------------------------------------------------------
+
+/// Everything necessary for uploading a data set.
+struct Uploader {
+    /// The HTTP client to use.
+    client: reqwest::Client,
+
+    /// The URI to upload to.
+    uri: Url,
+
+    /// The HTTP method to use.
+    method: Method,
+
+    /// The content type of the uploaded data.
+    content_type: &'static [u8],
+
+    /// The authentication to use.
+    auth: Auth,
+
+    /// How often to retry a failed upload.
+    retries: Option<u32>,
+}
+
+impl Uploader {
+    /// Creates the uploader from the target configuration.
+    fn new(
+        target: &Target, component: &Component
+    ) -> Result<Self, ExitError> {
+        let auth = Auth::new(
+            target.username.as_ref(), target.password.as_ref(),
+            target.bearer_token.as_ref()
+        ).map_err(|err| {
+            error!("Target {}: {}.", component.name(), err);
+            ExitError::default()
+        })?;
+        let client = component.http_client().and_then(|builder| {
+            builder.timeout(
+                Duration::from_secs(target.timeout)
+            ).build().map_err(|err| err.to_string())
+        }).map_err(|err| {
+            error!(
+                "Target {}: failed to create HTTP client: {}",
+                component.name(), err
+            );
+            ExitError::default()
+        })?;
+        Ok(Uploader {
+            client,
+            uri: target.uri.clone(),
+            method: target.method.into(),
+            content_type: target.format.content_type().as_bytes(),
+            auth,
+            retries: target.retries,
+        })
+    }
+
+    /// Uploads a data set, retrying if necessary.
+    async fn upload(
+        &self, body: Bytes, component: &Component, metrics: &MirrorMetrics,
+    ) {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            let res = self.request(body.clone()).send().await.and_then(
+                |response| response.error_for_status()
+            );
+            let err = match res {
+                Ok(_) => {
+                    debug!(
+                        "Target {}: uploaded {} bytes to {}.",
+                        component.name(), body.len(), self.host()
+                    );
+                    metrics.uploads.fetch_add(1, Relaxed);
+                    metrics.last_success.store(Some(Utc::now().timestamp()));
+                    component.set_ready(true);
+                    return
+                }
+                Err(err) => err,
+            };
+            metrics.failures.fetch_add(1, Relaxed);
+            if self.retries.is_some_and(|retries| attempt >= retries) {
+                error!(
+                    "Target {}: failed to upload to {}: {}. Giving up.",
+                    component.name(), self.host(), err
+                );
+                return
+            }
+            warn!(
+                "Target {}: failed to upload to {}: {}. Retrying in {}s.",
+                component.name(), self.host(), err, backoff.as_secs()
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            attempt += 1;
+        }
+    }
+
+    /// Creates the request for uploading a data set.
+    fn request(&self, body: Bytes) -> reqwest::RequestBuilder {
+        let request = self.client.request(
+            self.method.clone(), self.uri.clone()
+        ).header(
+            header::CONTENT_TYPE, self.content_type
+        ).body(body);
+        match self.auth {
+            Auth::None => request,
+            Auth::Basic(ref username, ref password) => {
+                request.basic_auth(
+                    username, password.as_ref().map(Secret::expose)
+                )
+            }
+            Auth::Bearer(ref token) => request.bearer_auth(token.expose()),
+        }
+    }
+
+    /// Returns the host portion of the URI for logging.
+    ///
+    /// The URI may contain secrets in its path or query, so we only ever
+    /// log the host.
+    fn host(&self) -> &str {
+        self.uri.host_str().unwrap_or("<unknown>")
+    }
+}
+
+
+//------------ Auth ----------------------------------------------------------
+
+/// The authentication used for uploads.
+enum Auth {
+    /// No authentication.
+    None,
+
+    /// HTTP basic authentication with user name and optional password.
+    Basic(String, Option<Secret>),
+
+    /// HTTP bearer authentication with the given token.
+    Bearer(Secret),
+}
+
+impl Auth {
+    /// Creates the authentication from the configuration values.
+    fn new(
+        username: Option<&String>,
+        password: Option<&Secret>,
+        bearer_token: Option<&Secret>,
+    ) -> Result<Self, &'static str> {
+        match (username, password, bearer_token) {
+            (None, None, None) => Ok(Auth::None),
+            (Some(username), password, None) => {
+                Ok(Auth::Basic(username.clone(), password.cloned()))
+            }
+            (None, None, Some(token)) => Ok(Auth::Bearer(token.clone())),
+            (None, Some(_), None) => {
+                Err("'password' given without 'username'")
+            }
+            _ => {
+                Err(
+                    "only one of basic and bearer authentication \
+                     can be given"
+                )
+            }
+        }
+    }
+}
+
+
+//------------ MirrorMetrics -------------------------------------------------
+
+/// The metrics of a mirror target.
+#[derive(Debug, Default)]
+struct MirrorMetrics {
+    /// The number of successful uploads.
+    uploads: AtomicU64,
+
+    /// The number of failed upload attempts.
+    failures: AtomicU64,
+
+    /// The number of data sets abandoned in favour of a newer one.
+    abandoned: AtomicU64,
+
+    /// The Unix time of the last successful upload.
+    last_success: AtomicCell<Option<i64>>,
+}
+
+impl MirrorMetrics {
+    const UPLOADS_METRIC: Metric = Metric::new(
+        "mirror_target_uploads",
+        "number of times the data set has been uploaded",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const FAILURES_METRIC: Metric = Metric::new(
+        "mirror_target_upload_failures",
+        "number of failed attempts to upload the data set",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const ABANDONED_METRIC: Metric = Metric::new(
+        "mirror_target_abandoned",
+        "number of data sets replaced by a newer one before being uploaded",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const LAST_SUCCESS_METRIC: Metric = Metric::new(
+        "mirror_target_last_success",
+        "Unix time of the last successful upload",
+        MetricType::Gauge, MetricUnit::Second
+    );
+}
+
+impl metrics::Source for MirrorMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::UPLOADS_METRIC, Some(unit_name),
+            self.uploads.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::FAILURES_METRIC, Some(unit_name),
+            self.failures.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::ABANDONED_METRIC, Some(unit_name),
+            self.abandoned.load(Relaxed)
+        );
+        if let Some(last) = self.last_success.load() {
+            target.append_simple(
+                &Self::LAST_SUCCESS_METRIC, Some(unit_name), last
+            );
+        }
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn auth() {
+        let name = String::from("rtrtr");
+        let secret = Secret::from("secret");
+        assert!(matches!(Auth::new(None, None, None), Ok(Auth::None)));
+        assert!(matches!(
+            Auth::new(Some(&name), Some(&secret), None),
+            Ok(Auth::Basic(_, Some(_)))
+        ));
+        assert!(matches!(
+            Auth::new(Some(&name), None, None), Ok(Auth::Basic(_, None))
+        ));
+        assert!(matches!(
+            Auth::new(None, None, Some(&secret)), Ok(Auth::Bearer(_))
+        ));
+        assert!(Auth::new(None, Some(&secret), None).is_err());
+        assert!(Auth::new(Some(&name), None, Some(&secret)).is_err());
+    }
+
+    #[test]
+    fn upload_method() {
+        #[derive(Deserialize)]
+        struct Config {
+            method: UploadMethod,
+        }
+
+        let config: Config = toml::from_str("method = \"POST\"").unwrap();
+        assert_eq!(Method::from(config.method), Method::POST);
+        let config: Config = toml::from_str("method = \"PUT\"").unwrap();
+        assert_eq!(Method::from(config.method), Method::PUT);
+        assert!(toml::from_str::<Config>("method = \"PATCH\"").is_err());
+    }
+}
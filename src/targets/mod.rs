@@ -1,8 +1,8 @@
 //! The targets for RPKI data.
 //!
 //! A target is anything that produces the final output from payload data.
-//! Each target is connected to exactly one unit and constantly converts its
-//! payload set into some form of output.
+//! Each target is connected to one or more units and constantly converts
+//! their payload sets into some form of output.
 //!
 //! This module contains all the different kinds of targets currently
 //! available. It provides access to them via the enum [`Target`] that
@@ -15,14 +15,31 @@
 //------------ Sub-modules ---------------------------------------------------
 //
 // These contain all the actual unit types grouped by shared functionality.
+#[cfg(feature = "http-server")]
+mod compare;
+mod file;
+#[cfg(feature = "http-server")]
 mod http;
+mod limits;
+#[cfg(feature = "target-mirror")]
+mod mirror;
+mod nats;
+mod router;
 mod rtr;
+mod statsd;
 
 
 //------------ Target --------------------------------------------------------
 
-use daemonbase::error::ExitError;
+use std::net::SocketAddr;
+use daemonbase::error::{ExitError, Failed};
 use serde::Deserialize;
+#[cfg(not(all(
+    feature = "http-server", feature = "ssh", feature = "target-mirror",
+    feature = "tls"
+)))]
+use crate::config::Disabled;
+use crate::comms::Link;
 use crate::manager::Component;
 
 
@@ -30,15 +47,61 @@ use crate::manager::Component;
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type")]
 pub enum Target {
+    #[cfg(feature = "http-server")]
+    #[serde(rename = "compare")]
+    Compare(compare::Target),
+
+    #[cfg(not(feature = "http-server"))]
+    #[serde(rename = "compare")]
+    Compare(Disabled),
+
+    #[serde(rename = "file")]
+    File(file::Target),
+
+    #[cfg(feature = "target-mirror")]
+    #[serde(rename = "mirror")]
+    Mirror(mirror::Target),
+
+    #[cfg(not(feature = "target-mirror"))]
+    #[serde(rename = "mirror")]
+    Mirror(Disabled),
+
+    #[serde(rename = "nats")]
+    Nats(nats::Target),
+
+    #[serde(rename = "router-config")]
+    RouterConfig(router::Target),
+
     #[serde(rename = "rtr")]
     RtrTcp(rtr::Tcp),
 
+    #[cfg(feature = "ssh")]
+    #[serde(rename = "rtr-ssh")]
+    RtrSsh(rtr::Ssh),
+
+    #[cfg(not(feature = "ssh"))]
+    #[serde(rename = "rtr-ssh")]
+    RtrSsh(Disabled),
+
+    #[cfg(feature = "tls")]
     #[serde(rename = "rtr-tls")]
     RtrTls(rtr::Tls),
 
+    #[cfg(not(feature = "tls"))]
+    #[serde(rename = "rtr-tls")]
+    RtrTls(Disabled),
+
+    #[serde(rename = "statsd")]
+    Statsd(statsd::Target),
+
+    #[cfg(feature = "http-server")]
     #[serde(rename = "http")]
     Http(http::Target),
 
+    #[cfg(not(feature = "http-server"))]
+    #[serde(rename = "http")]
+    Http(Disabled),
+
     #[cfg(test)]
     #[serde(skip)]
     Test(crate::test::Target)
@@ -48,13 +111,140 @@ impl Target {
     /// Runs the target.
     pub async fn run(self, component: Component) -> Result<(), ExitError> {
         match self {
+            #[cfg(feature = "http-server")]
+            Target::Compare(target) => target.run(component).await,
+            #[cfg(not(feature = "http-server"))]
+            Target::Compare(target) => match target { },
+            Target::File(target) => target.run(component).await,
+            #[cfg(feature = "target-mirror")]
+            Target::Mirror(target) => target.run(component).await,
+            #[cfg(not(feature = "target-mirror"))]
+            Target::Mirror(target) => match target { },
+            Target::Nats(target) => target.run(component).await,
+            Target::RouterConfig(target) => target.run(component).await,
             Target::RtrTcp(target) => target.run(component).await,
+            #[cfg(feature = "ssh")]
+            Target::RtrSsh(target) => target.run(component).await,
+            #[cfg(not(feature = "ssh"))]
+            Target::RtrSsh(target) => match target { },
+            #[cfg(feature = "tls")]
             Target::RtrTls(target) => target.run(component).await,
+            #[cfg(not(feature = "tls"))]
+            Target::RtrTls(target) => match target { },
+            Target::Statsd(target) => target.run(component).await,
+            #[cfg(feature = "http-server")]
             Target::Http(target) => target.run(component).await,
+            #[cfg(not(feature = "http-server"))]
+            Target::Http(target) => match target { },
 
             #[cfg(test)]
             Target::Test(target) => target.run(component).await,
         }
     }
+
+    /// Returns the name of the target’s type as used in the configuration.
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Target::Compare(_) => "compare",
+            Target::File(_) => "file",
+            Target::Mirror(_) => "mirror",
+            Target::Nats(_) => "nats",
+            Target::RouterConfig(_) => "router-config",
+            Target::RtrTcp(_) => "rtr",
+            Target::RtrSsh(_) => "rtr-ssh",
+            Target::RtrTls(_) => "rtr-tls",
+            Target::Statsd(_) => "statsd",
+            Target::Http(_) => "http",
+
+            #[cfg(test)]
+            Target::Test(_) => "test",
+        }
+    }
+
+    /// Checks the parts of the configuration only used at run time.
+    ///
+    /// This loads all files the target will need, such as TLS certificates,
+    /// and logs any problems found.
+    pub fn check(&self, name: &str) -> Result<(), Failed> {
+        match *self {
+            Target::RtrTcp(ref target) => target.check(name),
+            #[cfg(feature = "ssh")]
+            Target::RtrSsh(ref target) => target.check(name),
+            #[cfg(feature = "tls")]
+            Target::RtrTls(ref target) => target.check(name),
+            _ => Ok(())
+        }
+    }
+
+    /// Converts the target into the links to its units.
+    ///
+    /// If the target uses several units, the links are in order of
+    /// preference.
+    pub fn into_links(self) -> Vec<Link> {
+        match self {
+            #[cfg(feature = "http-server")]
+            Target::Compare(target) => target.into_links(),
+            #[cfg(not(feature = "http-server"))]
+            Target::Compare(target) => match target { },
+            Target::File(target) => target.into_links(),
+            #[cfg(feature = "target-mirror")]
+            Target::Mirror(target) => target.into_links(),
+            #[cfg(not(feature = "target-mirror"))]
+            Target::Mirror(target) => match target { },
+            Target::Nats(target) => target.into_links(),
+            Target::RouterConfig(target) => target.into_links(),
+            Target::RtrTcp(target) => target.into_links(),
+            #[cfg(feature = "ssh")]
+            Target::RtrSsh(target) => target.into_links(),
+            #[cfg(not(feature = "ssh"))]
+            Target::RtrSsh(target) => match target { },
+            #[cfg(feature = "tls")]
+            Target::RtrTls(target) => target.into_links(),
+            #[cfg(not(feature = "tls"))]
+            Target::RtrTls(target) => match target { },
+            Target::Statsd(target) => target.into_links(),
+            #[cfg(feature = "http-server")]
+            Target::Http(target) => target.into_links(),
+            #[cfg(not(feature = "http-server"))]
+            Target::Http(target) => match target { },
+
+            #[cfg(test)]
+            Target::Test(target) => target.into_links(),
+        }
+    }
+
+    /// Returns the name of the HTTP server the target wants to use if any.
+    pub fn http_server(&self) -> Option<&str> {
+        match *self {
+            #[cfg(feature = "http-server")]
+            Target::Http(ref target) => target.server(),
+            #[cfg(feature = "http-server")]
+            Target::Compare(ref target) => target.server(),
+            _ => None,
+        }
+    }
+
+    /// Returns the HTTP path the target wants to serve if any.
+    pub fn http_path(&self) -> Option<&str> {
+        match *self {
+            #[cfg(feature = "http-server")]
+            Target::Http(ref target) => Some(target.path()),
+            #[cfg(feature = "http-server")]
+            Target::Compare(ref target) => Some(target.path()),
+            _ => None,
+        }
+    }
+
+    /// Returns the socket addresses the target wants to listen on.
+    pub fn listen(&self) -> &[SocketAddr] {
+        match *self {
+            Target::RtrTcp(ref target) => target.listen(),
+            #[cfg(feature = "ssh")]
+            Target::RtrSsh(ref target) => target.listen(),
+            #[cfg(feature = "tls")]
+            Target::RtrTls(ref target) => target.listen(),
+            _ => &[],
+        }
+    }
 }
 
@@ -0,0 +1,608 @@
+//! A target publishing the data set to a NATS message bus.
+//!
+//! The _nats_ target publishes a JSON encoded message to a NATS subject
+//! whenever its unit produces an update. Depending on its configuration,
+//! the message contains the complete data set, the changes since the
+//! previously published data set, or both in separate messages. This allows
+//! automation to react to changes without having to poll RTRTR.
+//!
+//! The target uses the minimal NATS client of [`crate::utils::nats`]. It
+//! connects when it first publishes and reconnects with increasing delays
+//! whenever the connection is lost. While idle, it answers the server’s
+//! keepalive pings. If a new update arrives while publishing is still being
+//! retried, the old data set is abandoned in favour of the new one. Since
+//! diffs are always determined against the data set that was last published
+//! successfully, subscribers still see a consistent sequence of changes.
+
+use std::{fmt, io};
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+use chrono::Utc;
+use crossbeam_utils::atomic::AtomicCell;
+use daemonbase::config::ConfigPath;
+use daemonbase::error::ExitError;
+use log::{debug, error, warn};
+use serde::Deserialize;
+use tokio::time::timeout;
+use crate::{metrics, payload};
+use crate::comms::{Link, UnitUpdate};
+use crate::config::Secret;
+use crate::formats::json::items_value;
+use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+use crate::utils::nats::{Auth, Connection, Tls};
+
+
+//------------ Configuration -------------------------------------------------
+
+/// The delay before the first retry of a failed publication.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The maximum delay between two retries of a failed publication.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+
+//------------ Target --------------------------------------------------------
+
+/// A target publishing the data set to a NATS subject.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    /// The unit whose data set should be published.
+    unit: Link,
+
+    /// The address of the NATS server as host and port.
+    server: String,
+
+    /// Whether to use TLS for the connection.
+    #[serde(default)]
+    tls: bool,
+
+    /// Paths to additional root certificates for TLS.
+    #[serde(default)]
+    cacerts: Vec<ConfigPath>,
+
+    /// The subject to publish to.
+    subject: String,
+
+    /// What to publish.
+    #[serde(default)]
+    publish: PublishMode,
+
+    /// The user name for authentication.
+    username: Option<String>,
+
+    /// The password for authentication.
+    password: Option<Secret>,
+
+    /// The token for token authentication.
+    token: Option<Secret>,
+
+    /// The timeout for connecting and publishing in seconds.
+    #[serde(default = "Target::default_timeout")]
+    timeout: u64,
+}
+
+impl Target {
+    /// Converts the target into the links to its units.
+    pub fn into_links(self) -> Vec<Link> {
+        vec![self.unit]
+    }
+
+    /// The default for the `timeout` value.
+    fn default_timeout() -> u64 {
+        10
+    }
+
+    /// Runs the target.
+    pub async fn run(
+        mut self, mut component: Component
+    ) -> Result<(), ExitError> {
+        let metrics = Arc::new(NatsMetrics::default());
+        component.register_metrics(metrics.clone());
+        let mut publisher = Publisher::new(&self, &component)?;
+        let mut pending: Option<payload::Set> = None;
+
+        // There is nothing to set up, so we have started. We only become
+        // ready once we have published the data set.
+        component.set_started();
+
+        loop {
+            let update = match pending.take() {
+                Some(set) => {
+                    tokio::select! {
+                        update = self.unit.query() => {
+                            // Only a new data set replaces the pending one.
+                            if matches!(update, UnitUpdate::Payload(_)) {
+                                metrics.abandoned.fetch_add(1, Relaxed);
+                            }
+                            else {
+                                pending = Some(set);
+                            }
+                            update
+                        }
+                        _ = publisher.publish(
+                            set.clone(), &component, &metrics
+                        ) => {
+                            continue
+                        }
+                    }
+                }
+                None => {
+                    tokio::select! {
+                        update = self.unit.query() => update,
+                        _ = publisher.idle(&component, &metrics) => {
+                            continue
+                        }
+                    }
+                }
+            };
+            if let UnitUpdate::Payload(update) = update {
+                debug!(
+                    "Target {}: Got update ({} entries) via {}",
+                    component.name(), update.set().len(),
+                    update.provenance()
+                );
+                pending = Some(update.into_set());
+            }
+        }
+    }
+}
+
+
+//------------ PublishMode ---------------------------------------------------
+
+/// What the target publishes for each update.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+enum PublishMode {
+    /// Publish the complete data set.
+    #[default]
+    #[serde(rename = "snapshot")]
+    Snapshot,
+
+    /// Publish the changes since the last published data set.
+    #[serde(rename = "diff")]
+    Diff,
+
+    /// Publish the complete data set followed by the changes.
+    #[serde(rename = "both")]
+    Both,
+}
+
+impl PublishMode {
+    /// Returns whether complete data sets are published.
+    fn snapshots(self) -> bool {
+        matches!(self, PublishMode::Snapshot | PublishMode::Both)
+    }
+
+    /// Returns whether diffs are published.
+    fn diffs(self) -> bool {
+        matches!(self, PublishMode::Diff | PublishMode::Both)
+    }
+}
+
+
+//------------ Publisher -----------------------------------------------------
+
+/// Everything necessary for publishing data sets.
+struct Publisher {
+    /// The address of the NATS server.
+    server: String,
+
+    /// The subject to publish to.
+    subject: String,
+
+    /// What to publish.
+    mode: PublishMode,
+
+    /// The authentication with the server.
+    auth: Auth,
+
+    /// The TLS configuration if TLS is used.
+    tls: Option<Tls>,
+
+    /// The timeout for connecting and publishing.
+    timeout: Duration,
+
+    /// The current connection to the server if there is one.
+    conn: Option<Connection>,
+
+    /// The data set last published successfully.
+    published: Option<payload::Set>,
+
+    /// The serial number of the next message.
+    serial: u64,
+}
+
+impl Publisher {
+    /// Creates the publisher from the target configuration.
+    fn new(
+        target: &Target, component: &Component
+    ) -> Result<Self, ExitError> {
+        let auth = Auth::new(
+            target.username.as_ref(), target.password.as_ref(),
+            target.token.as_ref(), target.tls,
+        ).map_err(|err| {
+            error!("Target {}: {}.", component.name(), err);
+            ExitError::default()
+        })?;
+        let tls = Tls::new(
+            component.name(), &target.server, target.tls, &target.cacerts
+        )?;
+        Ok(Publisher {
+            server: target.server.clone(),
+            subject: target.subject.clone(),
+            mode: target.publish,
+            auth,
+            tls,
+            timeout: Duration::from_secs(target.timeout),
+            conn: None,
+            published: None,
+            serial: 0,
+        })
+    }
+
+    /// Publishes a data set, retrying if necessary.
+    ///
+    /// The method only returns once the data set has been published or if
+    /// it can’t ever be published because it is too large.
+    async fn publish(
+        &mut self,
+        set: payload::Set,
+        component: &Component,
+        metrics: &NatsMetrics,
+    ) {
+        let messages = self.messages(&set).await;
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let err = match self.try_publish(&messages, metrics).await {
+                Ok(()) => {
+                    debug!(
+                        "Target {}: published serial {} to '{}'.",
+                        component.name(), self.serial, self.subject
+                    );
+                    metrics.last_success.store(Some(Utc::now().timestamp()));
+                    self.published = Some(set);
+                    self.serial += 1;
+                    component.set_ready(true);
+                    return
+                }
+                Err(err) => err,
+            };
+            metrics.failures.fetch_add(1, Relaxed);
+            if matches!(err, PublishError::TooLarge { .. }) {
+                error!(
+                    "Target {}: failed to publish to '{}': {}. Giving up.",
+                    component.name(), self.server, err
+                );
+                return
+            }
+            warn!(
+                "Target {}: failed to publish to '{}': {}. Retrying in {}s.",
+                component.name(), self.server, err, backoff.as_secs()
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Tries to publish the messages once.
+    ///
+    /// Connects to the server first if necessary. The connection is only
+    /// kept if publishing succeeds. This way, a publication cancelled half
+    /// way through never leaves a broken connection behind.
+    async fn try_publish(
+        &mut self, messages: &[Vec<u8>], metrics: &NatsMetrics,
+    ) -> Result<(), PublishError> {
+        let mut conn = match self.conn.take() {
+            Some(conn) => conn,
+            None => {
+                let conn = timeout(
+                    self.timeout,
+                    Connection::connect(
+                        &self.server, &self.auth, self.tls.as_ref()
+                    )
+                ).await.map_err(|_| {
+                    io::Error::new(io::ErrorKind::TimedOut, "timed out")
+                })??;
+                metrics.connects.fetch_add(1, Relaxed);
+                conn
+            }
+        };
+        if let Some(len) = messages.iter().map(Vec::len).find(|len| {
+            *len > conn.max_payload()
+        }) {
+            let max = conn.max_payload();
+            self.conn = Some(conn);
+            return Err(PublishError::TooLarge { len, max })
+        }
+        timeout(
+            self.timeout,
+            conn.publish(&self.subject, messages)
+        ).await.map_err(|_| {
+            io::Error::new(io::ErrorKind::TimedOut, "timed out")
+        })??;
+        for message in messages {
+            metrics.messages.fetch_add(1, Relaxed);
+            metrics.bytes.fetch_add(message.len() as u64, Relaxed);
+        }
+        self.conn = Some(conn);
+        Ok(())
+    }
+
+    /// Keeps the connection alive while there is nothing to publish.
+    ///
+    /// Answers the server’s pings and drops the connection if it fails.
+    /// The method only returns after the connection has been dropped.
+    async fn idle(&mut self, component: &Component, metrics: &NatsMetrics) {
+        let conn = match self.conn.as_mut() {
+            Some(conn) => conn,
+            None => return std::future::pending().await,
+        };
+        if let Err(err) = conn.keepalive().await {
+            warn!(
+                "Target {}: lost connection to '{}': {}",
+                component.name(), self.server, err
+            );
+            metrics.disconnects.fetch_add(1, Relaxed);
+            self.conn = None;
+        }
+    }
+
+    /// Creates the messages for publishing a data set.
+    async fn messages(&self, set: &payload::Set) -> Vec<Vec<u8>> {
+        let mode = self.mode;
+        let serial = self.serial;
+        let set = set.clone();
+        let published = self.published.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut res = Vec::new();
+            if mode.snapshots() {
+                res.push(Self::snapshot_message(serial, &set));
+            }
+            if mode.diffs() {
+                res.push(Self::diff_message(
+                    serial, &set, published.as_ref()
+                ));
+            }
+            res
+        }).await.unwrap_or_default()
+    }
+
+    /// Creates the message containing the complete data set.
+    fn snapshot_message(serial: u64, set: &payload::Set) -> Vec<u8> {
+        let mut value = items_value(set.iter());
+        value["type"] = "snapshot".into();
+        value["serial"] = serial.into();
+        value["generated"] = Utc::now().timestamp().into();
+        // Serializing a JSON value into a vec can’t fail.
+        serde_json::to_vec(&value).unwrap()
+    }
+
+    /// Creates the message containing the changes to the data set.
+    ///
+    /// If no data set has been published before, the diff is against the
+    /// empty set.
+    fn diff_message(
+        serial: u64, set: &payload::Set, published: Option<&payload::Set>
+    ) -> Vec<u8> {
+        let diff = match published {
+            Some(published) => set.diff_from(published),
+            None => set.diff_from(&payload::Set::default()),
+        };
+        let value = serde_json::json!({
+            "type": "diff",
+            "serial": serial,
+            "reset": published.is_none(),
+            "generated": Utc::now().timestamp(),
+            "announced": items_value(diff.announced().as_slice().iter()),
+            "withdrawn": items_value(diff.withdrawn().as_slice().iter()),
+        });
+        // Serializing a JSON value into a vec can’t fail.
+        serde_json::to_vec(&value).unwrap()
+    }
+}
+
+
+//------------ PublishError --------------------------------------------------
+
+/// An error happened while publishing.
+#[derive(Debug)]
+enum PublishError {
+    /// A message is larger than the server accepts.
+    TooLarge {
+        /// The length of the message.
+        len: usize,
+
+        /// The maximum payload size of the server.
+        max: usize,
+    },
+
+    /// Communication with the server failed.
+    Io(io::Error),
+}
+
+impl From<io::Error> for PublishError {
+    fn from(err: io::Error) -> Self {
+        PublishError::Io(err)
+    }
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PublishError::TooLarge { len, max } => {
+                write!(f,
+                    "message of {} bytes exceeds the server’s maximum \
+                     payload of {} bytes",
+                    len, max
+                )
+            }
+            PublishError::Io(ref err) => err.fmt(f),
+        }
+    }
+}
+
+
+//------------ NatsMetrics ---------------------------------------------------
+
+/// The metrics of a NATS target.
+#[derive(Debug, Default)]
+struct NatsMetrics {
+    /// The number of messages published.
+    messages: AtomicU64,
+
+    /// The number of bytes published.
+    bytes: AtomicU64,
+
+    /// The number of failed attempts to publish a data set.
+    failures: AtomicU64,
+
+    /// The number of data sets abandoned in favour of a newer one.
+    abandoned: AtomicU64,
+
+    /// The number of connections established.
+    connects: AtomicU64,
+
+    /// The number of connections lost while idle.
+    disconnects: AtomicU64,
+
+    /// The Unix time of the last successful publication.
+    last_success: AtomicCell<Option<i64>>,
+}
+
+impl NatsMetrics {
+    const MESSAGES_METRIC: Metric = Metric::new(
+        "nats_target_messages",
+        "number of messages published",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const BYTES_METRIC: Metric = Metric::new(
+        "nats_target_published",
+        "number of bytes of message payload published",
+        MetricType::Counter, MetricUnit::Byte
+    );
+    const FAILURES_METRIC: Metric = Metric::new(
+        "nats_target_failures",
+        "number of failed attempts to publish the data set",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const ABANDONED_METRIC: Metric = Metric::new(
+        "nats_target_abandoned",
+        "number of data sets replaced by a newer one before being published",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const CONNECTS_METRIC: Metric = Metric::new(
+        "nats_target_connects",
+        "number of connections established to the server",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const DISCONNECTS_METRIC: Metric = Metric::new(
+        "nats_target_disconnects",
+        "number of connections to the server lost while idle",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const LAST_SUCCESS_METRIC: Metric = Metric::new(
+        "nats_target_last_success",
+        "Unix time of the last successful publication",
+        MetricType::Gauge, MetricUnit::Second
+    );
+}
+
+impl metrics::Source for NatsMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::MESSAGES_METRIC, Some(unit_name),
+            self.messages.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::BYTES_METRIC, Some(unit_name),
+            self.bytes.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::FAILURES_METRIC, Some(unit_name),
+            self.failures.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::ABANDONED_METRIC, Some(unit_name),
+            self.abandoned.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::CONNECTS_METRIC, Some(unit_name),
+            self.connects.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::DISCONNECTS_METRIC, Some(unit_name),
+            self.disconnects.load(Relaxed)
+        );
+        if let Some(last) = self.last_success.load() {
+            target.append_simple(
+                &Self::LAST_SUCCESS_METRIC, Some(unit_name), last
+            );
+        }
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn publish_mode() {
+        #[derive(Deserialize)]
+        struct Config {
+            publish: PublishMode,
+        }
+
+        let mode = |s: &str| {
+            toml::from_str::<Config>(&format!("publish = \"{}\"", s))
+                .map(|config| config.publish)
+        };
+        assert_eq!(mode("snapshot").unwrap(), PublishMode::Snapshot);
+        assert_eq!(mode("diff").unwrap(), PublishMode::Diff);
+        assert_eq!(mode("both").unwrap(), PublishMode::Both);
+        assert!(mode("all").is_err());
+        assert!(PublishMode::Both.snapshots() && PublishMode::Both.diffs());
+        assert!(!PublishMode::Diff.snapshots());
+    }
+
+    #[test]
+    fn messages() {
+        use crate::payload::testrig::pack;
+
+        let old = payload::Set::from(pack([1, 2]));
+        let new = payload::Set::from(pack([2, 3]));
+
+        let value: serde_json::Value = serde_json::from_slice(
+            &Publisher::diff_message(4, &new, Some(&old))
+        ).unwrap();
+        assert_eq!(value["type"], "diff");
+        assert_eq!(value["serial"], 4);
+        assert_eq!(value["reset"], false);
+        assert_eq!(
+            value["announced"]["routeOrigins"].as_array().unwrap().len(), 1
+        );
+        assert_eq!(
+            value["withdrawn"]["routeOrigins"].as_array().unwrap().len(), 1
+        );
+
+        let value: serde_json::Value = serde_json::from_slice(
+            &Publisher::diff_message(0, &new, None)
+        ).unwrap();
+        assert_eq!(value["reset"], true);
+        assert_eq!(
+            value["announced"]["routeOrigins"].as_array().unwrap().len(), 2
+        );
+
+        let value: serde_json::Value = serde_json::from_slice(
+            &Publisher::snapshot_message(4, &new)
+        ).unwrap();
+        assert_eq!(value["type"], "snapshot");
+        assert_eq!(value["routeOrigins"].as_array().unwrap().len(), 2);
+    }
+}
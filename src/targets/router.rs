@@ -0,0 +1,341 @@
+//! A target writing the data set as a router configuration snippet.
+//!
+//! The _router-config_ target renders the data set of its unit in the
+//! configuration syntax of a routing daemon whenever the unit produces an
+//! update: either as OpenBGPD `roa-set` and `aspa-set` blocks or as a BIRD
+//! `roa table` statement. The snippet is written atomically in the same way
+//! as by the _file_ target. It is meant to be included into the main
+//! configuration of the daemon.
+//!
+//! After the file has been written successfully, an optional reload
+//! command is run so that the daemon picks up the new data. This allows
+//! small networks whose routers don’t speak RTR to use RPKI data.
+
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+use daemonbase::config::ConfigPath;
+use daemonbase::error::ExitError;
+use log::{debug, error};
+use serde::Deserialize;
+use tokio::process::Command;
+use crate::{metrics, payload};
+use crate::comms::{Link, UnitUpdate};
+use crate::formats::{bgpd, bird};
+use crate::formats::output::{Aspas, Origins};
+use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+use super::file::write_atomic;
+use super::limits::{MaxPrefixLen, MaxPrefixLenMetrics};
+
+
+//------------ Target --------------------------------------------------------
+
+/// A target writing a router configuration snippet.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    /// The unit whose data set should be written.
+    unit: Link,
+
+    /// The path of the file to write.
+    path: ConfigPath,
+
+    /// The configuration syntax to use.
+    dialect: Dialect,
+
+    /// The name of the ROA table for BIRD.
+    #[serde(default = "Target::default_roa_table", rename = "roa-table")]
+    roa_table: String,
+
+    /// The program to run after the file has been written.
+    #[serde(rename = "reload-command")]
+    reload_command: Option<String>,
+
+    /// The arguments to pass to the reload program.
+    #[serde(default, rename = "reload-args")]
+    reload_args: Vec<String>,
+
+    /// The number of seconds the reload program may run.
+    #[serde(
+        default = "Target::default_reload_timeout", rename = "reload-timeout"
+    )]
+    reload_timeout: u64,
+
+    /// The maximum prefix lengths of route origins to write.
+    #[serde(default)]
+    #[serde(rename = "max-prefix-length")]
+    max_prefix_len: MaxPrefixLen,
+}
+
+impl Target {
+    /// Converts the target into the links to its units.
+    pub fn into_links(self) -> Vec<Link> {
+        vec![self.unit]
+    }
+
+    /// The default for the `roa-table` value.
+    fn default_roa_table() -> String {
+        "rtrtr".into()
+    }
+
+    /// The default for the `reload-timeout` value.
+    fn default_reload_timeout() -> u64 {
+        60
+    }
+
+    /// Runs the target.
+    pub async fn run(
+        mut self, mut component: Component
+    ) -> Result<(), ExitError> {
+        if
+            self.dialect == Dialect::Bird
+            && !bird::is_valid_table_name(&self.roa_table)
+        {
+            error!(
+                "Target {}: invalid ROA table name '{}'.",
+                component.name(), self.roa_table
+            );
+            return Err(ExitError::default())
+        }
+        let metrics = Arc::new(RouterMetrics::default());
+        component.register_metrics(metrics.clone());
+        let limit_metrics = Arc::new(MaxPrefixLenMetrics::default());
+        if !self.max_prefix_len.is_unlimited() {
+            component.register_metrics(limit_metrics.clone());
+        }
+
+        // There is nothing to set up, so we have started. We only become
+        // ready once we have written the file.
+        component.set_started();
+
+        loop {
+            if let UnitUpdate::Payload(update) = self.unit.query().await {
+                debug!(
+                    "Target {}: Got update ({} entries) via {}",
+                    component.name(), update.set().len(),
+                    update.provenance()
+                );
+                let update = self.max_prefix_len.apply(
+                    update, &limit_metrics
+                );
+                if self.write(&update, &component, &metrics).await {
+                    self.reload(&component, &metrics).await;
+                }
+            }
+        }
+    }
+
+    /// Writes an update to the file.
+    ///
+    /// Returns whether writing succeeded. Failures are logged and recorded
+    /// in the metrics.
+    async fn write(
+        &self,
+        update: &payload::Update,
+        component: &Component,
+        metrics: &RouterMetrics,
+    ) -> bool {
+        let stream = self.dialect.stream(
+            update.set().clone(), &self.roa_table
+        );
+        let path: &Path = self.path.as_ref();
+        let target_path = path.to_path_buf();
+        let res = tokio::task::spawn_blocking(move || {
+            write_atomic(&target_path, stream)
+        }).await;
+        let err = match res {
+            Ok(Ok(())) => {
+                debug!(
+                    "Target {}: wrote {} entries to {}.",
+                    component.name(), update.set().len(), path.display()
+                );
+                metrics.written.fetch_add(1, Relaxed);
+                component.set_ready(true);
+                return true
+            }
+            Ok(Err(err)) => err.to_string(),
+            Err(err) => err.to_string(),
+        };
+        error!(
+            "Target {}: failed to write {}: {}",
+            component.name(), path.display(), err
+        );
+        metrics.failed.fetch_add(1, Relaxed);
+        false
+    }
+
+    /// Runs the reload command if there is one.
+    ///
+    /// Failures are logged and recorded in the metrics.
+    async fn reload(&self, component: &Component, metrics: &RouterMetrics) {
+        let command = match self.reload_command.as_ref() {
+            Some(command) => command,
+            None => return
+        };
+        let timeout = Duration::from_secs(self.reload_timeout);
+        let res = tokio::time::timeout(
+            timeout,
+            Command::new(command)
+                .args(&self.reload_args)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .output()
+        ).await;
+        let err = match res {
+            Ok(Ok(output)) if output.status.success() => {
+                debug!(
+                    "Target {}: reload command '{}' succeeded.",
+                    component.name(), command
+                );
+                metrics.reloads.fetch_add(1, Relaxed);
+                return
+            }
+            Ok(Ok(output)) => {
+                format!(
+                    "{}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )
+            }
+            Ok(Err(err)) => err.to_string(),
+            Err(_) => format!("timed out after {}s", timeout.as_secs()),
+        };
+        error!(
+            "Target {}: reload command '{}' failed: {}",
+            component.name(), command, err
+        );
+        metrics.reload_failures.fetch_add(1, Relaxed);
+    }
+}
+
+
+//------------ Dialect -------------------------------------------------------
+
+/// The configuration syntax to produce.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+enum Dialect {
+    /// OpenBGPD `roa-set` and `aspa-set` blocks.
+    #[serde(rename = "openbgpd")]
+    Openbgpd,
+
+    /// A BIRD 1.x `roa table` statement.
+    #[serde(rename = "bird")]
+    Bird,
+}
+
+impl Dialect {
+    /// Returns a stream of the data set in this dialect.
+    fn stream(
+        self, set: payload::Set, roa_table: &str
+    ) -> Box<dyn Iterator<Item = Vec<u8>> + Send> {
+        match self {
+            Dialect::Openbgpd => {
+                let aspas = Aspas::new(&set);
+                Box::new(bgpd::OutputStream::new(
+                    Origins::new(set, Default::default()), aspas
+                ))
+            }
+            Dialect::Bird => {
+                Box::new(bird::OutputStream::new(
+                    Origins::new(set, Default::default()), roa_table.into()
+                ))
+            }
+        }
+    }
+}
+
+
+//------------ RouterMetrics -------------------------------------------------
+
+/// The metrics of a router-config target.
+#[derive(Debug, Default)]
+struct RouterMetrics {
+    /// The number of times the file has been written.
+    written: AtomicU64,
+
+    /// The number of failed attempts to write the file.
+    failed: AtomicU64,
+
+    /// The number of successful runs of the reload command.
+    reloads: AtomicU64,
+
+    /// The number of failed runs of the reload command.
+    reload_failures: AtomicU64,
+}
+
+impl RouterMetrics {
+    const WRITTEN_METRIC: Metric = Metric::new(
+        "router_config_writes",
+        "number of times the configuration has been written",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const FAILED_METRIC: Metric = Metric::new(
+        "router_config_write_failures",
+        "number of failed attempts to write the configuration",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const RELOADS_METRIC: Metric = Metric::new(
+        "router_config_reloads",
+        "number of successful runs of the reload command",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const RELOAD_FAILURES_METRIC: Metric = Metric::new(
+        "router_config_reload_failures",
+        "number of failed runs of the reload command",
+        MetricType::Counter, MetricUnit::Total
+    );
+}
+
+impl metrics::Source for RouterMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::WRITTEN_METRIC, Some(unit_name),
+            self.written.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::FAILED_METRIC, Some(unit_name),
+            self.failed.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::RELOADS_METRIC, Some(unit_name),
+            self.reloads.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::RELOAD_FAILURES_METRIC, Some(unit_name),
+            self.reload_failures.load(Relaxed)
+        );
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::payload::testrig;
+
+    #[test]
+    fn dialects() {
+        let set = payload::Set::from(testrig::pack([1, 2]));
+        let output = Dialect::Openbgpd.stream(
+            set.clone(), "rtrtr"
+        ).flatten().collect::<Vec<_>>();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("roa-set {\n"));
+        assert_eq!(output.lines().count(), 4);
+
+        let output = Dialect::Bird.stream(
+            set, "rpki"
+        ).flatten().collect::<Vec<_>>();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("roa table rpki {\n"));
+        assert_eq!(output.lines().count(), 4);
+    }
+}
@@ -1,36 +1,59 @@
-/// RTR servers as a target.
+//! RTR servers as a target.
 
-use std::{cmp, io};
+use std::{cmp, fmt, fs, io};
+use std::collections::HashMap;
+use std::future::Future;
 use std::ops::Deref;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{
-    AtomicI64, AtomicU32, AtomicU64, AtomicUsize,
+    AtomicBool, AtomicI64, AtomicU32, AtomicU64, AtomicUsize,
 };
-use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::Ordering::{Relaxed, SeqCst};
 use std::net::{IpAddr, SocketAddr};
-use std::net::TcpListener as StdTcpListener;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::task::{Context, Poll};
-use std::time::Duration;
+use std::task::{ready, Context, Poll, Waker};
+use std::time::{Duration, Instant};
 use arc_swap::ArcSwap;
 use chrono::{DateTime, TimeZone, Utc};
 use daemonbase::config::ConfigPath;
-use daemonbase::error::ExitError;
+use daemonbase::error::{ExitError, Failed};
 use futures_util::{Stream, pin_mut};
-use log::{debug, error};
-use serde::Deserialize;
-use rpki::rtr::payload::Timing;
+use futures_util::future::{select_all, FutureExt};
+use log::{debug, error, info, warn};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use slab::Slab;
+use rpki::resources::addr::Prefix;
+use rpki::rtr::payload::{Action, Timing};
 use rpki::rtr::server::{NotifySender, Server, Socket, PayloadSource};
 use rpki::rtr::state::{Serial, State};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-use tokio::net::{TcpListener, TcpStream};
-use tokio_rustls::TlsAcceptor;
+use tokio::time::Sleep;
 use crate::{metrics, payload};
-use crate::comms::{Link, UnitUpdate};
+use crate::comms::{Link, UnitHealth, UnitUpdate};
+use crate::events::Event;
+use crate::handoff::{self, PayloadList};
 use crate::manager::Component;
-use crate::metrics::{Metric, MetricType, MetricUnit};
+use crate::metrics::{Histogram, Metric, MetricType, MetricUnit};
+#[cfg(unix)]
+use crate::utils::listener::bind_unix;
+use crate::utils::listener::{
+    bind, AcceptMetrics, ListenAddr, ListenAddrs, Listener, SocketOptions
+};
+#[cfg(feature = "ssh")]
+use crate::utils::ssh;
+use crate::utils::rtr::{
+    ASPA, CACHE_RESET, END_OF_DATA, ERROR_REPORT, IPV4_PREFIX, IPV6_PREFIX,
+    RESET_QUERY, ROUTER_KEY, SERIAL_NOTIFY, SERIAL_QUERY, UNSUPPORTED_VERSION,
+    Pdu, PduTracker, TimingOverride,
+};
+#[cfg(feature = "tls")]
 use crate::utils::tls;
-use crate::utils::tls::MaybeTlsTcpStream;
+use crate::utils::net::{covers, AccessList};
+use crate::utils::{tcp_ao, tcp_md5};
+use crate::utils::tls::{MaybeTlsTcpStream, TlsAcceptor};
+use super::limits::{MaxPrefixLen, MaxPrefixLenMetrics};
 
 
 //------------ Tcp -----------------------------------------------------------
@@ -38,17 +61,33 @@ use crate::utils::tls::MaybeTlsTcpStream;
 /// An RTR server atop unencrypted, plain TCP.
 #[derive(Debug, Deserialize)]
 pub struct Tcp {
-    /// The socket addresses to listen on.
-    listen: Vec<SocketAddr>,
+    /// The addresses to listen on.
+    listen: ListenAddrs,
 
     /// The unit whose data set we should serve.
-    unit: Link,
+    unit: Option<Link>,
+
+    /// The units whose data set we should serve in order of preference.
+    ///
+    /// This can be given instead of `unit`.
+    #[serde(default)]
+    units: Vec<Link>,
+
+    /// Drop the diff history when switching between units?
+    #[serde(default)]
+    #[serde(rename = "failover-reset")]
+    failover_reset: bool,
 
     /// The maximum number of deltas we should keep.
     #[serde(default = "Tcp::default_history_size")]
     #[serde(rename = "history-size")]
     history_size: usize,
 
+    /// Keep older deltas in compressed form?
+    #[serde(default)]
+    #[serde(rename = "compress-history")]
+    compress_history: bool,
+
     /// The RTR refresh interval.
     refresh: Option<u32>,
 
@@ -62,6 +101,99 @@ pub struct Tcp {
     #[serde(default)]
     #[serde(rename = "client-metrics")]
     client_metrics: bool,
+
+    /// The minimum number of seconds expected between two reset queries.
+    ///
+    /// Clients resetting more often are considered anomalous.
+    #[serde(default = "Tcp::default_min_reset_interval")]
+    #[serde(rename = "min-reset-interval")]
+    min_reset_interval: u64,
+
+    /// The minimum number of seconds between two serial notifies.
+    ///
+    /// Notifies for updates arriving more quickly are delayed and
+    /// coalesced into a single notify.
+    #[serde(default)]
+    #[serde(rename = "min-notify-interval")]
+    min_notify_interval: u64,
+
+    /// The maximum number of milliseconds to delay serial responses.
+    ///
+    /// The response to each Serial Query is delayed by a random time of up
+    /// to this value. This spreads out the queries of clients that all
+    /// react to the same serial notify.
+    #[serde(default)]
+    #[serde(rename = "serial-jitter")]
+    serial_jitter: u64,
+
+    /// The maximum prefix lengths of route origins to serve.
+    #[serde(default)]
+    #[serde(rename = "max-prefix-length")]
+    max_prefix_len: MaxPrefixLen,
+
+    /// The RTR session ID to use.
+    ///
+    /// If this is `None`, the session ID is taken from the state file or
+    /// derived from the current time.
+    #[serde(rename = "session-id")]
+    session_id: Option<u16>,
+
+    /// The path of a file to keep the RTR state in across restarts.
+    ///
+    /// If this is `None`, a file in the state directory is used if there
+    /// is one.
+    #[serde(rename = "state-file")]
+    state_file: Option<ConfigPath>,
+
+    /// Whether to keep the current data set in the state file, too.
+    #[serde(default, rename = "persist-data")]
+    persist_data: bool,
+
+    /// The number of seconds to wait for clients during shutdown.
+    ///
+    /// After this time, all remaining connections are closed.
+    #[serde(default = "Tcp::default_drain_timeout")]
+    #[serde(rename = "drain-timeout")]
+    drain_timeout: u64,
+
+    /// Classes of clients with their own timing values and metrics.
+    #[serde(default)]
+    #[serde(rename = "client-classes")]
+    client_classes: HashMap<String, ClientClassConfig>,
+
+    /// The maximum number of concurrent client connections.
+    #[serde(rename = "max-connections")]
+    max_connections: Option<usize>,
+
+    /// The maximum number of concurrent connections per client address.
+    #[serde(rename = "max-connections-per-ip")]
+    max_connections_per_ip: Option<usize>,
+
+    /// The rate of new connections accepted per client address.
+    #[serde(rename = "connection-rate")]
+    connection_rate: Option<RateConfig>,
+
+    /// The rate of serial queries answered per client address.
+    #[serde(rename = "serial-query-rate")]
+    serial_query_rate: Option<RateConfig>,
+
+    /// The prefixes clients are allowed to connect from.
+    #[serde(default)]
+    allow: Vec<Prefix>,
+
+    /// The prefixes clients are not allowed to connect from.
+    #[serde(default)]
+    deny: Vec<Prefix>,
+
+    /// The TCP-MD5 keys for clients.
+    #[serde(default)]
+    #[serde(rename = "tcp-md5-keys")]
+    tcp_md5_keys: Vec<tcp_md5::PeerKey>,
+
+    /// The TCP-AO keys for clients.
+    #[serde(default)]
+    #[serde(rename = "tcp-ao-keys")]
+    tcp_ao_keys: Vec<tcp_ao::PeerKey>,
 }
 
 impl Tcp {
@@ -70,45 +202,326 @@ impl Tcp {
         10
     }
 
+    /// The default for the `min_reset_interval` value.
+    const fn default_min_reset_interval() -> u64 {
+        60
+    }
+
+    /// The default for the `drain_timeout` value.
+    const fn default_drain_timeout() -> u64 {
+        10
+    }
+
+    /// Returns the TCP socket addresses to listen on.
+    pub fn listen(&self) -> &[SocketAddr] {
+        self.listen.tcp()
+    }
+
+    /// Converts the target into the links to its units.
+    ///
+    /// The links are in order of preference.
+    pub fn into_links(self) -> Vec<Link> {
+        self.unit.into_iter().chain(self.units).collect()
+    }
+
+    /// Checks the parts of the configuration only used at run time.
+    pub fn check(&self, name: &str) -> Result<(), Failed> {
+        if self.unit.is_some() != self.units.is_empty() {
+            error!(
+                "Target {}: exactly one of 'unit' and 'units' must be given.",
+                name
+            );
+            return Err(Failed)
+        }
+        for (option, rate) in [
+            ("connection-rate", self.connection_rate.as_ref()),
+            ("serial-query-rate", self.serial_query_rate.as_ref()),
+        ] {
+            if rate.is_some_and(|rate| !rate.is_valid()) {
+                error!(
+                    "Target {}: '{}' needs a positive rate and burst.",
+                    name, option
+                );
+                return Err(Failed)
+            }
+        }
+        for key in &self.tcp_md5_keys {
+            if key.key.expose().len() > tcp_md5::MAX_KEY_LEN {
+                error!(
+                    "Target {}: TCP-MD5 key for {} longer than {} octets.",
+                    name, key.peer, tcp_md5::MAX_KEY_LEN
+                );
+                return Err(Failed)
+            }
+        }
+        for key in &self.tcp_ao_keys {
+            if let Err(err) = key.key.check() {
+                error!("Target {}: {} for {}.", name, err, key.peer);
+                return Err(Failed)
+            }
+            if self.tcp_md5_keys.iter().any(|md5| md5.peer == key.peer) {
+                error!(
+                    "Target {}: both a TCP-MD5 and a TCP-AO key given \
+                     for {}.",
+                    name, key.peer
+                );
+                return Err(Failed)
+            }
+        }
+        Ok(())
+    }
+
     /// Runs the target.
     pub async fn run(
-        self, mut component: Component
+        mut self, mut component: Component
     ) -> Result<(), ExitError> {
+        let upstreams = self.upstreams(&component)?;
         let notify = NotifySender::new();
-        let target = Source::new(self.history_size, self.timing());
-        let metrics = Arc::new(ListenerMetrics::new(self.client_metrics));
+        let target = Arc::new(Source::new(
+            self.history_size, self.compress_history, self.timing(),
+            self.initial_state(&component)
+        ));
+        component.register_handoff(target.clone());
+        let classes = Arc::new(ClientClasses::new(&self.client_classes));
+        let metrics = Arc::new(
+            ListenerMetrics::new(self.client_metrics, classes.clone())
+        );
         component.register_metrics(metrics.clone());
+        let drain = Arc::new(Drain::default());
+        let options = self.connection_options(
+            &classes, &Arc::new(self.connection_limits())
+        );
 
-        for &addr in &self.listen {
-            RtrListener::spawn(
-                addr, None, None,
-                target.clone(), notify.clone(), metrics.clone()
+        for &addr in self.listen.tcp() {
+            let listener = RtrListener::tcp(
+                addr, None, &self.tcp_md5_keys, &self.tcp_ao_keys,
+                &options, &metrics
             )?;
+            if let ListenAddr::Tcp(addr) = listener.addr() {
+                component.add_listen_addr(*addr);
+            }
+            RtrListener::spawn(
+                component.name().clone(), listener,
+                options.clone(), target.as_ref().clone(),
+                notify.clone(), metrics.clone(), drain.clone(),
+            );
+        }
+        #[cfg(unix)]
+        for path in self.listen.unix() {
+            RtrListener::spawn(
+                component.name().clone(),
+                RtrListener::unix(path, &metrics)?,
+                options.clone(), target.as_ref().clone(),
+                notify.clone(), metrics.clone(), drain.clone(),
+            );
         }
+        component.set_ready(true);
 
-        self.run_loop(component, target, notify, metrics).await
+        self.run_loop(
+            component, upstreams, target, notify, metrics, drain
+        ).await
+    }
+
+    /// Takes the configured units out of the target.
+    ///
+    /// Logs an error and fails unless exactly one of `unit` and `units`
+    /// has been given.
+    fn upstreams(
+        &mut self, component: &Component
+    ) -> Result<Upstreams, ExitError> {
+        let links = match (self.unit.take(), self.units.is_empty()) {
+            (Some(unit), true) => vec![unit],
+            (None, false) => std::mem::take(&mut self.units),
+            _ => {
+                error!(
+                    "Target {}: exactly one of 'unit' and 'units' \
+                     must be given.",
+                    component.name()
+                );
+                return Err(ExitError::default())
+            }
+        };
+        Ok(Upstreams::new(links))
     }
 
     /// Runs the target’s main loop.
     async fn run_loop(
-        mut self,
-        component: Component,
-        target: Source,
+        self,
+        mut component: Component,
+        mut upstreams: Upstreams,
+        target: Arc<Source>,
         mut notify: NotifySender,
         metrics: Arc<ListenerMetrics>,
+        drain: Arc<Drain>,
     ) -> Result<(), ExitError> {
+        let drain_guard = component.drain_guard();
+        let limit_metrics = Arc::new(MaxPrefixLenMetrics::default());
+        if !self.max_prefix_len.is_unlimited() {
+            component.register_metrics(limit_metrics.clone());
+        }
+        let mut throttle = NotifyThrottle::new(
+            Duration::from_secs(self.min_notify_interval)
+        );
         loop {
-            let update = self.unit.query().await;
-            if let UnitUpdate::Payload(ref payload) = update {
+            let (mut update, switched) = tokio::select! {
+                res = upstreams.query(component.name()) => res,
+                _ = throttle.delayed() => {
+                    throttle.sent();
+                    notify.notify();
+                    continue
+                }
+                _ = component.shutdown_requested() => break
+            };
+            if let UnitUpdate::Payload(payload) = update {
                 debug!(
-                    "Target {}: Got update ({} entries)",
-                    component.name(), payload.set().len()
+                    "Target {}: Got update ({} entries) via {}",
+                    component.name(), payload.set().len(),
+                    payload.provenance()
+                );
+                update = UnitUpdate::Payload(
+                    self.max_prefix_len.apply(payload, &limit_metrics)
                 );
             }
             if target.update(update, &metrics) {
-                notify.notify()
+                if switched && self.failover_reset {
+                    target.drop_history();
+                }
+                if let Some(path) = self.state_path(&component) {
+                    let state = target.stored_state(self.persist_data);
+                    let name = component.name().clone();
+                    let _ = tokio::task::spawn_blocking(move || {
+                        state.store(&path, &name)
+                    }).await;
+                }
+                if throttle.request(&metrics) {
+                    throttle.sent();
+                    notify.notify();
+                }
+                component.notifier().notify(Event::TargetSerial {
+                    component: component.name().to_string(),
+                    serial: metrics.serial.load(Relaxed),
+                });
+            }
+        }
+
+        self.drain(&component, throttle, &mut notify, &metrics, &drain).await;
+        drop(drain_guard);
+
+        // Keep the data source alive so its state can be handed off.
+        futures_util::future::pending().await
+    }
+
+    /// Drains client connections after shutdown was requested.
+    ///
+    /// Sends a delayed serial notify right away and then stops accepting
+    /// new connections. Connections are closed once their client has
+    /// received the response to its last query. Whatever connections are
+    /// still open after the drain timeout are closed forcibly.
+    async fn drain(
+        &self,
+        component: &Component,
+        throttle: NotifyThrottle,
+        notify: &mut NotifySender,
+        metrics: &ListenerMetrics,
+        drain: &Drain,
+    ) {
+        if throttle.pending {
+            notify.notify();
+            // Give the connections a chance to send the notify.
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+        drain.start();
+        let deadline = Instant::now() + Duration::from_secs(
+            self.drain_timeout
+        );
+        let open = metrics.global.open();
+        if open > 0 {
+            info!(
+                "Target {}: draining {} client connections.",
+                component.name(), open
+            );
+        }
+        while metrics.global.open() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+        let open = metrics.global.open();
+        if open > 0 {
+            info!(
+                "Target {}: closing {} remaining client connections.",
+                component.name(), open
+            );
+            drain.close();
+            let deadline = Instant::now() + DRAIN_CLOSE_TIMEOUT;
+            while metrics.global.open() > 0 && Instant::now() < deadline {
+                tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+            }
+        }
+    }
+
+    /// Returns the path of the state file if there is one.
+    ///
+    /// This is the configured state file or, if there is none, the file
+    /// for the target in the state directory.
+    fn state_path(&self, component: &Component) -> Option<PathBuf> {
+        match self.state_file.as_ref() {
+            Some(path) => {
+                let path: &Path = path.as_ref();
+                Some(path.to_path_buf())
+            }
+            None => component.state_file()
+        }
+    }
+
+    /// Returns the RTR state to start out with.
+    ///
+    /// If a previous process handed off its state, the target resumes
+    /// with its data set and diffs. Otherwise, if there is a state file,
+    /// the session ID and serial number are taken from it. In both cases,
+    /// this only happens unless a different session ID is configured.
+    fn initial_state(&self, component: &Component) -> InitialState {
+        let name = component.name();
+        if let Some(state) = component.take_handoff::<HandoffState>() {
+            if self.session_id.unwrap_or(state.session) == state.session {
+                match state.decode() {
+                    Ok(initial) => {
+                        info!(
+                            "Target {}: resuming handed off session {} \
+                             at serial {}.",
+                            name, state.session, state.serial
+                        );
+                        return initial
+                    }
+                    Err(err) => {
+                        warn!(
+                            "Target {}: ignoring invalid handoff state: {}",
+                            name, err
+                        );
+                    }
+                }
+            }
+        }
+        let stored = self.state_path(component).and_then(|path| {
+            StoredState::load(&path, name)
+        });
+        if let Some(stored) = stored {
+            if self.session_id.unwrap_or(stored.session) == stored.session {
+                info!(
+                    "Target {}: resuming session {} at serial {}.",
+                    name, stored.session, stored.serial
+                );
+                return stored.decode(name)
             }
         }
+        InitialState {
+            state: match self.session_id {
+                Some(session) => {
+                    State::from_parts(session, Serial::default())
+                }
+                None => State::new(),
+            },
+            digest: None,
+            data: None,
+        }
     }
 
     /// Returns the RTR timing based on the configured values.
@@ -125,12 +538,44 @@ impl Tcp {
         }
         res
     }
+
+    /// Returns the options for client connections.
+    fn connection_options(
+        &self, classes: &Arc<ClientClasses>, limits: &Arc<ConnectionLimits>,
+    ) -> ConnectionOptions {
+        ConnectionOptions {
+            keepalive: None,
+            min_reset_interval: Duration::from_secs(self.min_reset_interval),
+            serial_jitter: Duration::from_millis(self.serial_jitter),
+            classes: classes.clone(),
+            limits: limits.clone(),
+            connection_rate: self.connection_rate.map(|rate| {
+                Arc::new(RateLimit::new(rate))
+            }),
+            serial_query_rate: self.serial_query_rate.map(|rate| {
+                Arc::new(RateLimit::new(rate))
+            }),
+            access: Arc::new(AccessList::new(&self.allow, &self.deny)),
+            #[cfg(feature = "ssh")]
+            ssh: None,
+        }
+    }
+
+    /// Returns the limits for client connections.
+    ///
+    /// The limits need to be shared by all listeners of the target.
+    fn connection_limits(&self) -> ConnectionLimits {
+        ConnectionLimits::new(
+            self.max_connections, self.max_connections_per_ip
+        )
+    }
 }
 
 
 //------------ Tls -----------------------------------------------------------
 
 /// An RTR server atop TLS.
+#[cfg(feature = "tls")]
 #[derive(Debug, Deserialize)]
 pub struct Tls {
     /// The configuration values shared with [`Tcp`].
@@ -144,29 +589,236 @@ pub struct Tls {
     key: ConfigPath,
 }
 
+#[cfg(feature = "tls")]
 impl Tls {
+    /// Returns the socket addresses to listen on.
+    pub fn listen(&self) -> &[SocketAddr] {
+        self.tcp.listen()
+    }
+
+    /// Converts the target into the links to its units.
+    pub fn into_links(self) -> Vec<Link> {
+        self.tcp.into_links()
+    }
+
+    /// Checks the parts of the configuration only used at run time.
+    ///
+    /// This also loads the server certificate and private key.
+    pub fn check(&self, name: &str) -> Result<(), Failed> {
+        self.tcp.check(name)?;
+        #[cfg(unix)]
+        if !self.tcp.listen.unix().is_empty() {
+            error!(
+                "Target {}: Unix domain sockets cannot be used with TLS.",
+                name
+            );
+            return Err(Failed)
+        }
+        tls::create_server_config(
+            name, &self.certificate, &self.key
+        ).map(|_| ()).map_err(|_| Failed)
+    }
+
     /// Runs the target.
     pub async fn run(
-        self, mut component: Component
+        mut self, mut component: Component
     ) -> Result<(), ExitError> {
+        let upstreams = self.tcp.upstreams(&component)?;
+        #[cfg(unix)]
+        if !self.tcp.listen.unix().is_empty() {
+            error!(
+                "Target {}: Unix domain sockets cannot be used with TLS.",
+                component.name()
+            );
+            return Err(ExitError::default())
+        }
         let acceptor = TlsAcceptor::from(Arc::new(
             tls::create_server_config(
                 component.name(), &self.certificate, &self.key
             )?
         ));
         let notify = NotifySender::new();
-        let target = Source::new(self.tcp.history_size, self.tcp.timing());
-        let metrics = Arc::new(ListenerMetrics::new(self.tcp.client_metrics));
+        let target = Arc::new(Source::new(
+            self.tcp.history_size, self.tcp.compress_history,
+            self.tcp.timing(), self.tcp.initial_state(&component)
+        ));
+        component.register_handoff(target.clone());
+        let classes = Arc::new(
+            ClientClasses::new(&self.tcp.client_classes)
+        );
+        let metrics = Arc::new(
+            ListenerMetrics::new(self.tcp.client_metrics, classes.clone())
+        );
         component.register_metrics(metrics.clone());
+        let drain = Arc::new(Drain::default());
+        let options = self.tcp.connection_options(
+            &classes, &Arc::new(self.tcp.connection_limits())
+        );
 
-        for &addr in &self.tcp.listen {
+        for &addr in self.tcp.listen.tcp() {
+            let listener = RtrListener::tcp(
+                addr, Some(acceptor.clone()), &self.tcp.tcp_md5_keys,
+                &self.tcp.tcp_ao_keys, &options, &metrics
+            )?;
+            if let ListenAddr::Tcp(addr) = listener.addr() {
+                component.add_listen_addr(*addr);
+            }
             RtrListener::spawn(
-                addr, Some(acceptor.clone()), None,
-                target.clone(), notify.clone(), metrics.clone(),
+                component.name().clone(), listener,
+                options.clone(), target.as_ref().clone(),
+                notify.clone(), metrics.clone(), drain.clone(),
+            );
+        }
+        component.set_ready(true);
+
+        self.tcp.run_loop(
+            component, upstreams, target, notify, metrics, drain
+        ).await
+    }
+}
+
+
+//------------ Ssh -----------------------------------------------------------
+
+/// An RTR server atop SSH.
+#[cfg(feature = "ssh")]
+#[derive(Debug, Deserialize)]
+pub struct Ssh {
+    /// The configuration values shared with [`Tcp`].
+    #[serde(flatten)]
+    tcp: Tcp,
+
+    /// The path to the private host key of the server.
+    #[serde(rename = "host-key")]
+    host_key: ConfigPath,
+
+    /// The path to a file with the public keys of authorized clients.
+    #[serde(rename = "authorized-keys")]
+    authorized_keys: ConfigPath,
+
+    /// The name of the SSH subsystem for RTR.
+    #[serde(default = "Ssh::default_subsystem")]
+    subsystem: String,
+}
+
+#[cfg(feature = "ssh")]
+impl Ssh {
+    /// The default subsystem name as defined in RFC 6810.
+    fn default_subsystem() -> String {
+        "rpki-rtr".into()
+    }
+
+    /// Returns the socket addresses to listen on.
+    pub fn listen(&self) -> &[SocketAddr] {
+        self.tcp.listen()
+    }
+
+    /// Converts the target into the links to its units.
+    pub fn into_links(self) -> Vec<Link> {
+        self.tcp.into_links()
+    }
+
+    /// Checks the parts of the configuration only used at run time.
+    ///
+    /// This also loads the host key and the authorized keys.
+    pub fn check(&self, name: &str) -> Result<(), Failed> {
+        self.tcp.check(name)?;
+        self.check_listen(name)?;
+        self.server_config(name).map(|_| ())
+    }
+
+    /// Runs the target.
+    pub async fn run(
+        mut self, mut component: Component
+    ) -> Result<(), ExitError> {
+        let upstreams = self.tcp.upstreams(&component)?;
+        self.check_listen(component.name())?;
+        let config = Arc::new(self.server_config(component.name())?);
+        let notify = NotifySender::new();
+        let target = Arc::new(Source::new(
+            self.tcp.history_size, self.tcp.compress_history,
+            self.tcp.timing(), self.tcp.initial_state(&component)
+        ));
+        component.register_handoff(target.clone());
+        let classes = Arc::new(
+            ClientClasses::new(&self.tcp.client_classes)
+        );
+        let metrics = Arc::new(
+            ListenerMetrics::new(self.tcp.client_metrics, classes.clone())
+        );
+        component.register_metrics(metrics.clone());
+        let drain = Arc::new(Drain::default());
+        let mut options = self.tcp.connection_options(
+            &classes, &Arc::new(self.tcp.connection_limits())
+        );
+        options.ssh = Some(config);
+
+        for &addr in self.tcp.listen.tcp() {
+            let listener = RtrListener::tcp(
+                addr, None, &self.tcp.tcp_md5_keys,
+                &self.tcp.tcp_ao_keys, &options, &metrics
             )?;
+            if let ListenAddr::Tcp(addr) = listener.addr() {
+                component.add_listen_addr(*addr);
+            }
+            RtrListener::spawn(
+                component.name().clone(), listener,
+                options.clone(), target.as_ref().clone(),
+                notify.clone(), metrics.clone(), drain.clone(),
+            );
+        }
+        component.set_ready(true);
+
+        self.tcp.run_loop(
+            component, upstreams, target, notify, metrics, drain
+        ).await
+    }
+
+    /// Checks that only TCP addresses are given to listen on.
+    fn check_listen(&self, name: &str) -> Result<(), Failed> {
+        #[cfg(unix)]
+        if !self.tcp.listen.unix().is_empty() {
+            error!(
+                "Target {}: Unix domain sockets cannot be used with SSH.",
+                name
+            );
+            return Err(Failed)
         }
+        Ok(())
+    }
 
-        self.tcp.run_loop(component, target, notify, metrics).await
+    /// Loads the keys and creates the SSH server configuration.
+    fn server_config(&self, name: &str) -> Result<ssh::ServerConfig, Failed> {
+        let host_key = ssh::PrivateKey::load_file(
+            &self.host_key
+        ).map_err(|err| {
+            error!(
+                "Target {}: failed to read SSH host key '{}': {}",
+                name, self.host_key.display(), err
+            );
+            Failed
+        })?;
+        let authorized_keys = ssh::PublicKey::load_file(
+            &self.authorized_keys
+        ).map_err(|err| {
+            error!(
+                "Target {}: failed to read authorized keys file '{}': {}",
+                name, self.authorized_keys.display(), err
+            );
+            Failed
+        })?;
+        if authorized_keys.is_empty() {
+            error!(
+                "Target {}: no Ed25519 keys in authorized keys file '{}'.",
+                name, self.authorized_keys.display()
+            );
+            return Err(Failed)
+        }
+        Ok(ssh::ServerConfig {
+            host_key,
+            authorized_keys,
+            subsystem: self.subsystem.clone(),
+        })
     }
 }
 
@@ -184,17 +836,74 @@ struct Source {
     /// The maximum nummber of diffs to keep.
     history_size: usize,
 
+    /// Whether to keep all but the newest diff compressed.
+    compress_history: bool,
+
     /// The RTR timing values.
     timing: Timing,
+
+    /// The digest of the data set the initial state refers to.
+    ///
+    /// If the first data set differs, the serial number is increased.
+    /// If this is `None`, the initial serial number is used as is.
+    initial_digest: Option<u64>,
 }
 
 impl Source {
     /// Creates a new source using the given history size and timing.
-    fn new(history_size: usize, timing: Timing) -> Self {
+    fn new(
+        history_size: usize, compress_history: bool, timing: Timing,
+        initial: InitialState
+    ) -> Self {
+        let (current, diffs) = match initial.data {
+            Some((current, diffs)) => {
+                let diffs = diffs.into_iter().enumerate().map(
+                    |(idx, (serial, diff))| {
+                        (
+                            serial,
+                            HistoryDiff::new(
+                                diff, compress_history && idx > 0
+                            )
+                        )
+                    }
+                ).collect();
+                (Some(current), diffs)
+            }
+            None => (None, Vec::new()),
+        };
         Source {
-            data: Default::default(),
+            data: Arc::new(ArcSwap::from_pointee(SourceData {
+                state: initial.state,
+                current,
+                diffs,
+                timing,
+            })),
             history_size,
+            compress_history,
             timing,
+            initial_digest: initial.digest,
+        }
+    }
+
+    /// Returns the state to be stored in the state file.
+    ///
+    /// The current data set is only included if `with_data` is `true`.
+    fn stored_state(&self, with_data: bool) -> StoredState {
+        let data = self.data.load();
+        StoredState {
+            session: data.state.session(),
+            serial: data.state.serial().into(),
+            digest: data.current.as_ref().map(|set| {
+                set.digest()
+            }).unwrap_or_default(),
+            current: if with_data {
+                data.current.as_ref().map(|set| {
+                    PayloadList::encode(set.iter())
+                })
+            }
+            else {
+                None
+            },
         }
     }
 
@@ -214,15 +923,21 @@ impl Source {
         let data = self.data.load();
         let new_data = match data.current.as_ref() {
             None => {
+                let mut state = data.state;
+                if let Some(digest) = self.initial_digest {
+                    if digest != payload.set().digest() {
+                        state.inc();
+                    }
+                }
                 SourceData {
-                    state: data.state,
+                    state,
                     current: Some(payload.set().clone()),
                     diffs: Vec::new(),
                     timing: self.timing,
                 }
             }
             Some(current) => {
-                let diff = payload.set().diff_from(current);
+                let diff = payload.diff_since(current);
                 if diff.is_empty() {
                     // If there is no change in data, don’t update.
                     return false
@@ -230,14 +945,19 @@ impl Source {
                 let mut diffs = Vec::with_capacity(
                     cmp::min(data.diffs.len() + 1, self.history_size)
                 );
-                diffs.push((data.state.serial(), diff.clone()));
+                diffs.push((
+                    data.state.serial(), HistoryDiff::new(diff.clone(), false)
+                ));
                 for (serial, old_diff) in &data.diffs {
                     if diffs.len() >= self.history_size {
                         break
                     }
                     diffs.push((
                         *serial,
-                        old_diff.extend(&diff).unwrap()
+                        HistoryDiff::new(
+                            old_diff.to_diff().extend(&diff).unwrap(),
+                            self.compress_history
+                        )
                     ))
                 }
                 let mut state = data.state;
@@ -261,6 +981,14 @@ impl Source {
     }
 }
 
+impl handoff::Export for Source {
+    fn export(&self) -> Option<serde_json::Value> {
+        let data = self.data.load();
+        let state = HandoffState::encode(&data)?;
+        serde_json::to_value(state).ok()
+    }
+}
+
 impl PayloadSource for Source {
     type Set = payload::OwnedSetIter;
     type Diff = payload::OwnedDiffIter;
@@ -297,37 +1025,397 @@ impl PayloadSource for Source {
     }
 }
 
+impl Source {
+    /// Drops all diffs kept for the data set.
+    ///
+    /// Clients not already at the current serial number will have to reset
+    /// their data.
+    fn drop_history(&self) {
+        let data = self.data.load();
+        self.data.store(SourceData {
+            diffs: Vec::new(),
+            .. SourceData::clone(&data)
+        }.into());
+    }
+}
 
-//------------ SourceData ----------------------------------------------------
-
-/// The RTR data set.
-#[derive(Clone, Default)]
-struct SourceData {
-    /// The current RTR state of the target.
-    state: State,
 
-    /// The current set of RTR data.
-    current: Option<payload::Set>,
+//------------ Upstreams -----------------------------------------------------
 
-    /// The diffs we currently keep.
-    ///
-    /// The diff with the largest serial is first.
-    diffs: Vec<(Serial, payload::Diff)>,
+/// The units a target gets its data from in order of preference.
+///
+/// The target serves the data of the first unit that is healthy and has
+/// produced data. If that unit becomes stalled or gone, the target
+/// switches to the next such unit. Once a more preferred unit has data
+/// again, the target switches back to it.
+struct Upstreams {
+    /// The links to the units.
+    links: Vec<Link>,
 
-    /// The timing paramters for this source.
-    timing: Timing,
+    /// The index of the unit currently used.
+    current: Option<usize>,
 }
 
-impl SourceData {
-    /// Returns the diff for the given serial if available.
-    fn get_diff(&self, serial: Serial) -> Option<payload::OwnedDiffIter> {
-        if serial == self.state.serial() {
-            Some(payload::Diff::default().into_owned_iter())
+impl Upstreams {
+    /// Creates a new value from the links in order of preference.
+    fn new(links: Vec<Link>) -> Self {
+        Upstreams { links, current: None }
+    }
+
+    /// Waits for the next update to serve.
+    ///
+    /// Returns the update and whether the target has switched to a
+    /// different unit with it.
+    ///
+    /// This is cancel safe. The queries of all links are re-created in
+    /// every round which is fine because [`Link::query`] is cancel safe:
+    /// an update is only taken from a link when its query resolves and the
+    /// link keeps the unit’s status for later rounds. Since `current` is
+    /// only changed right before returning, cancelling this future doesn’t
+    /// lose a switch either.
+    async fn query(&mut self, name: &str) -> (UnitUpdate, bool) {
+        if self.links.len() == 1 {
+            return (self.links[0].query().await, false)
+        }
+        loop {
+            let (update, idx, _) = select_all(
+                self.links.iter_mut().map(|link| link.query().boxed())
+            ).await;
+
+            let preferred = self.links.iter().position(|link| {
+                link.health() == UnitHealth::Healthy
+                    && link.payload().is_some()
+            });
+            if let Some(preferred) = preferred {
+                if self.current != Some(preferred) {
+                    if let Some(current) = self.current {
+                        info!(
+                            "Target {}: switching from unit {} to unit {} \
+                             of its units.",
+                            name, current + 1, preferred + 1
+                        );
+                    }
+                    let switched = self.current.is_some();
+                    self.current = Some(preferred);
+                    // We checked that there is a payload above.
+                    let payload = self.links[preferred].payload().cloned();
+                    if let Some(payload) = payload {
+                        return (UnitUpdate::Payload(payload), switched)
+                    }
+                }
+            }
+            if self.current == Some(idx) {
+                return (update, false)
+            }
+        }
+    }
+}
+
+
+//------------ NotifyThrottle ------------------------------------------------
+
+/// Limits the rate of serial notifies sent to clients.
+#[derive(Clone, Copy, Debug)]
+struct NotifyThrottle {
+    /// The minimum time between two notifies.
+    interval: Duration,
+
+    /// The earliest time the next notify can be sent.
+    next: Option<tokio::time::Instant>,
+
+    /// Is a delayed notify waiting to be sent?
+    pending: bool,
+}
+
+impl NotifyThrottle {
+    /// Creates a new throttle with the given minimum interval.
+    fn new(interval: Duration) -> Self {
+        NotifyThrottle { interval, next: None, pending: false }
+    }
+
+    /// Requests a notify for a new data set.
+    ///
+    /// Returns whether the notify should be sent right away. Otherwise, it
+    /// will be sent once [`delayed`][Self::delayed] resolves. If a delayed
+    /// notify is already waiting, the new notify is coalesced with it and
+    /// counted as suppressed.
+    fn request(&mut self, metrics: &ListenerMetrics) -> bool {
+        if self.pending {
+            metrics.notifies_suppressed.fetch_add(1, Relaxed);
+            return false
+        }
+        match self.next {
+            Some(next) if next > tokio::time::Instant::now() => {
+                self.pending = true;
+                false
+            }
+            _ => true
+        }
+    }
+
+    /// Resolves when a delayed notify is due.
+    ///
+    /// Never resolves if there is no delayed notify.
+    async fn delayed(&self) {
+        match self.next {
+            Some(next) if self.pending => {
+                tokio::time::sleep_until(next).await
+            }
+            _ => futures_util::future::pending().await
+        }
+    }
+
+    /// Records that a notify has been sent.
+    fn sent(&mut self) {
+        self.pending = false;
+        self.next = Some(tokio::time::Instant::now() + self.interval);
+    }
+}
+
+
+//------------ InitialState --------------------------------------------------
+
+/// The state an RTR target starts out with.
+#[derive(Clone, Debug)]
+struct InitialState {
+    /// The RTR state.
+    state: State,
+
+    /// The digest of the data set the state refers to if known.
+    digest: Option<u64>,
+
+    /// The data set and diffs to start out with if known.
+    data: Option<(payload::Set, Vec<(Serial, payload::Diff)>)>,
+}
+
+
+//------------ HandoffState --------------------------------------------------
+
+/// The state of a target handed off to a new process.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct HandoffState {
+    /// The session ID.
+    session: u16,
+
+    /// The serial number of the current data set.
+    serial: u32,
+
+    /// The current data set.
+    current: PayloadList,
+
+    /// The diffs to the current data set by their serial number.
+    diffs: Vec<HandoffDiff>,
+}
+
+/// A diff in handed off state.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct HandoffDiff {
+    /// The serial number the diff starts from.
+    serial: u32,
+
+    /// The announced items.
+    announced: PayloadList,
+
+    /// The withdrawn items.
+    withdrawn: PayloadList,
+}
+
+impl HandoffState {
+    /// Encodes the state of a target.
+    ///
+    /// Returns `None` if the target doesn’t have a data set yet.
+    fn encode(data: &SourceData) -> Option<Self> {
+        let current = data.current.as_ref()?;
+        let mut diffs = Vec::with_capacity(data.diffs.len());
+        for (serial, diff) in &data.diffs {
+            let diff = diff.to_diff();
+            let announced = diff.iter().filter_map(|(payload, action)| {
+                matches!(action, Action::Announce).then_some(payload)
+            });
+            let withdrawn = diff.iter().filter_map(|(payload, action)| {
+                matches!(action, Action::Withdraw).then_some(payload)
+            });
+            diffs.push(HandoffDiff {
+                serial: (*serial).into(),
+                announced: PayloadList::encode(announced),
+                withdrawn: PayloadList::encode(withdrawn),
+            })
+        }
+        Some(HandoffState {
+            session: data.state.session(),
+            serial: data.state.serial().into(),
+            current: PayloadList::encode(current.iter()),
+            diffs,
+        })
+    }
+
+    /// Decodes the state into the initial state of a target.
+    fn decode(&self) -> Result<InitialState, String> {
+        let current = payload::Set::from(self.current.decode()?);
+        let mut diffs = Vec::with_capacity(self.diffs.len());
+        for diff in &self.diffs {
+            let mut builder = payload::DiffBuilder::empty();
+            for item in diff.announced.decode()?.iter() {
+                builder.push(
+                    item.clone(), Action::Announce
+                ).map_err(|_| String::from("corrupt diff"))?;
+            }
+            for item in diff.withdrawn.decode()?.iter() {
+                builder.push(
+                    item.clone(), Action::Withdraw
+                ).map_err(|_| String::from("corrupt diff"))?;
+            }
+            diffs.push((Serial::from(diff.serial), builder.finalize()));
+        }
+        Ok(InitialState {
+            state: State::from_parts(
+                self.session, Serial::from(self.serial)
+            ),
+            digest: None,
+            data: Some((current, diffs)),
+        })
+    }
+}
+
+
+//------------ StoredState ---------------------------------------------------
+
+/// The RTR state of a target as kept in its state file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct StoredState {
+    /// The session ID.
+    session: u16,
+
+    /// The serial number of the current data set.
+    serial: u32,
+
+    /// The digest of the current data set.
+    digest: u64,
+
+    /// The current data set if it is kept, too.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    current: Option<PayloadList>,
+}
+
+impl StoredState {
+    /// Converts the stored state into the initial state of a target.
+    ///
+    /// If the stored state contains a data set, the target starts out
+    /// serving it. If the data set can’t be decoded, this is logged and
+    /// only the session and serial number are used.
+    fn decode(self, name: &str) -> InitialState {
+        let state = State::from_parts(
+            self.session, Serial::from(self.serial)
+        );
+        if let Some(current) = self.current {
+            match current.decode() {
+                Ok(current) => {
+                    return InitialState {
+                        state,
+                        digest: None,
+                        data: Some((current.into(), Vec::new())),
+                    }
+                }
+                Err(err) => {
+                    warn!(
+                        "Target {}: ignoring invalid data set in state \
+                         file: {}",
+                        name, err
+                    );
+                }
+            }
+        }
+        InitialState {
+            state,
+            digest: Some(self.digest),
+            data: None,
+        }
+    }
+
+    /// Loads the state from a file.
+    ///
+    /// Logs and returns `None` if the file can’t be read or parsed.
+    fn load(path: &Path, name: &str) -> Option<Self> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                info!(
+                    "Target {}: state file {} not found, starting a new \
+                     session.",
+                    name, path.display()
+                );
+                return None
+            }
+            Err(err) => {
+                warn!(
+                    "Target {}: failed to read state file {}: {}. Starting \
+                     a new session.",
+                    name, path.display(), err
+                );
+                return None
+            }
+        };
+        match serde_json::from_slice(&data) {
+            Ok(state) => Some(state),
+            Err(err) => {
+                warn!(
+                    "Target {}: failed to parse state file {}: {}. \
+                     Starting a new session.",
+                    name, path.display(), err
+                );
+                None
+            }
+        }
+    }
+
+    /// Stores the state in a file.
+    ///
+    /// The state is first written to a temporary file which then replaces
+    /// the file. Errors are logged and otherwise ignored.
+    fn store(&self, path: &Path, name: &str) {
+        let res = serde_json::to_vec(self).map_err(io::Error::from).and_then(
+            |data| handoff::write_file(path, &data)
+        );
+        if let Err(err) = res {
+            warn!(
+                "Target {}: failed to write state file {}: {}",
+                name, path.display(), err
+            );
+        }
+    }
+}
+
+
+//------------ SourceData ----------------------------------------------------
+
+/// The RTR data set.
+#[derive(Clone, Default)]
+struct SourceData {
+    /// The current RTR state of the target.
+    state: State,
+
+    /// The current set of RTR data.
+    current: Option<payload::Set>,
+
+    /// The diffs we currently keep.
+    ///
+    /// The diff with the largest serial is first.
+    diffs: Vec<(Serial, HistoryDiff)>,
+
+    /// The timing paramters for this source.
+    timing: Timing,
+}
+
+impl SourceData {
+    /// Returns the diff for the given serial if available.
+    fn get_diff(&self, serial: Serial) -> Option<payload::OwnedDiffIter> {
+        if serial == self.state.serial() {
+            Some(payload::Diff::default().into_owned_iter())
         }
         else {
             self.diffs.iter().find_map(|item| {
                 if item.0 == serial {
-                    Some(item.1.owned_iter())
+                    Some(item.1.to_diff().into_owned_iter())
                 }
                 else {
                     None
@@ -338,48 +1426,129 @@ impl SourceData {
 }
 
 
+//------------ HistoryDiff ---------------------------------------------------
+
+/// A diff kept in the history of a target.
+#[derive(Clone, Debug)]
+enum HistoryDiff {
+    /// The diff is kept as is.
+    Plain(payload::Diff),
+
+    /// The diff is kept in compressed form.
+    Compressed(payload::CompressedDiff),
+}
+
+impl HistoryDiff {
+    /// Creates a new history diff, compressing it if requested.
+    fn new(diff: payload::Diff, compress: bool) -> Self {
+        if compress {
+            HistoryDiff::Compressed(payload::CompressedDiff::compress(&diff))
+        }
+        else {
+            HistoryDiff::Plain(diff)
+        }
+    }
+
+    /// Returns the diff, decompressing it if necessary.
+    fn to_diff(&self) -> payload::Diff {
+        match *self {
+            HistoryDiff::Plain(ref diff) => diff.clone(),
+            HistoryDiff::Compressed(ref diff) => diff.decompress(),
+        }
+    }
+}
+
+
 //============ Sockets =======================================================
 
 //------------ RtrListener --------------------------------------------------
 
-/// A wrapper around an TCP listener that produces RTR streams.
+/// A wrapper around a listener that produces RTR streams.
 struct RtrListener {
-    tcp: TcpListener,
-    tls: Option<TlsAcceptor>,
-    keepalive: Option<Duration>,
+    name: Arc<str>,
+    listener: Listener,
+    options: ConnectionOptions,
     server_metrics: Arc<ListenerMetrics>,
+
+    /// The drain state shared by all listeners and connections.
+    drain: Arc<Drain>,
+
+    /// Our key for registering with the drain state.
+    drain_key: Option<usize>,
 }
 
 impl RtrListener {
-    /// Spawns the a listener socket onto the current Tokio runtime.
-    fn spawn(
+    /// Creates a listener for a TCP socket address.
+    ///
+    /// The TCP-MD5 and TCP-AO keys are set on the listening socket.
+    fn tcp(
         addr: SocketAddr,
         tls: Option<TlsAcceptor>,
-        keepalive: Option<Duration>,
-        target: Source,
-        notify: NotifySender,
-        server_metrics: Arc<ListenerMetrics>,
-    ) -> Result<(), ExitError> {
-        let listener = match StdTcpListener::bind(addr) {
-            Ok(listener) => listener,
-            Err(err) => {
-                error!("Can’t bind to {}: {}", addr, err);
+        md5_keys: &[tcp_md5::PeerKey],
+        ao_keys: &[tcp_ao::PeerKey],
+        options: &ConnectionOptions,
+        server_metrics: &ListenerMetrics,
+    ) -> Result<Listener, ExitError> {
+        let sock = bind(addr)?;
+        for key in md5_keys {
+            if let Err(err) = key.apply(&sock, addr.is_ipv6()) {
+                error!(
+                    "Fatal: failed to set TCP-MD5 key for {} on {}: {}",
+                    key.peer, addr, err
+                );
                 return Err(ExitError::default())
             }
-        };
-        if let Err(err) = listener.set_nonblocking(true) {
-            error!(
-                "Fatal: failed to set listener {} to non-blocking: {}.",
-                addr, err
-            );
-            return Err(ExitError::default());
         }
-        let listener = match TcpListener::from_std(listener) {
-            Ok(tcp) => Self { tcp, tls, keepalive, server_metrics },
-            Err(err) => {
-                error!("Fatal error listening on {}: {}", addr, err);
+        for key in ao_keys {
+            if let Err(err) = key.apply(&sock, addr.is_ipv6()) {
+                error!(
+                    "Fatal: failed to set TCP-AO key for {} on {}: {}",
+                    key.peer, addr, err
+                );
                 return Err(ExitError::default())
             }
+        }
+        Listener::new(
+            sock, addr, tls,
+            SocketOptions { keepalive: options.keepalive },
+            server_metrics.accept.clone(),
+        ).map(|listener| {
+            listener.with_access(options.access.clone())
+        }).map_err(|err| {
+            error!("Fatal error listening on {}: {}", addr, err);
+            ExitError::default()
+        })
+    }
+
+    /// Creates a listener for a Unix domain socket.
+    #[cfg(unix)]
+    fn unix(
+        path: &Path, server_metrics: &ListenerMetrics,
+    ) -> Result<Listener, ExitError> {
+        Listener::new_unix(
+            bind_unix(path)?, path.into(), server_metrics.accept.clone(),
+        ).map_err(|err| {
+            error!(
+                "Fatal error listening on unix:{}: {}", path.display(), err
+            );
+            ExitError::default()
+        })
+    }
+
+    /// Spawns a server for the listener onto the current Tokio runtime.
+    fn spawn(
+        name: Arc<str>,
+        listener: Listener,
+        options: ConnectionOptions,
+        target: Source,
+        notify: NotifySender,
+        server_metrics: Arc<ListenerMetrics>,
+        drain: Arc<Drain>,
+    ) {
+        let addr = listener.addr().clone();
+        let listener = Self {
+            name, listener, options, server_metrics, drain,
+            drain_key: None,
         };
         tokio::spawn(async move {
             let server = Server::new(listener, notify, target);
@@ -387,7 +1556,6 @@ impl RtrListener {
                 error!("Fatal error in RTR server on {}.", addr);
             }
         });
-        Ok(())
     }
 }
 
@@ -395,23 +1563,614 @@ impl Stream for RtrListener {
     type Item = Result<RtrStream, io::Error>;
 
     fn poll_next(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         ctx: &mut Context<'_>,
     ) -> Poll<Option<Self::Item>> {
-        match self.tcp.poll_accept(ctx) {
-            Poll::Ready(Ok((sock, addr))) => {
-                match RtrStream::new(
-                    sock, addr,
-                    self.tls.as_ref(),
-                    self.keepalive,
-                    &self.server_metrics
-                ) {
-                    Ok(stream) => Poll::Ready(Some(Ok(stream))),
-                    Err(_) => Poll::Pending,
+        // Ending the stream ends the server and thus stops accepting.
+        let key = self.drain.register(self.drain_key, ctx.waker());
+        self.drain_key = Some(key);
+        if self.drain.is_draining() {
+            return Poll::Ready(None)
+        }
+        loop {
+            let (sock, addr) = match self.listener.poll_accept(ctx) {
+                Poll::Ready(Ok(res)) => res,
+                Poll::Ready(Err(err)) => return Poll::Ready(Some(Err(err))),
+                Poll::Pending => return Poll::Pending,
+            };
+            let permitted = self.options.connection_rate.as_ref().map_or(
+                Ok(()), |rate| {
+                    if rate.try_acquire(addr.ip(), Instant::now()) {
+                        Ok(())
+                    }
+                    else {
+                        Err(ConnectionLimit::Rate)
+                    }
+                }
+            );
+            let slot = match permitted.and_then(|_| {
+                self.options.limits.acquire(addr.ip())
+            }) {
+                Ok(slot) => slot,
+                Err(limit) => {
+                    // Dropping the socket closes the connection.
+                    debug!(
+                        "Target {}: rejecting connection from {}: \
+                         {} reached.",
+                        self.name, addr, limit
+                    );
+                    self.server_metrics.inc_rejected(limit);
+                    continue
                 }
+            };
+            #[cfg(feature = "ssh")]
+            let sock = match self.options.ssh {
+                Some(ref config) => MaybeTlsTcpStream::duplex(ssh::accept(
+                    sock, config.clone(), self.name.clone(), addr
+                )),
+                None => sock,
+            };
+            return Poll::Ready(Some(Ok(RtrStream::new(
+                self.name.clone(), sock, addr, &self.options,
+                &self.server_metrics, self.drain.clone(), slot,
+            ))))
+        }
+    }
+}
+
+impl Drop for RtrListener {
+    fn drop(&mut self) {
+        if let Some(key) = self.drain_key {
+            self.drain.unregister(key)
+        }
+    }
+}
+
+
+//------------ Drain ---------------------------------------------------------
+
+/// The interval for checking on connections while draining.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The time to wait for connections to close after closing them forcibly.
+const DRAIN_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The state of draining client connections during shutdown.
+///
+/// The state is shared by all listeners and connections of a target. Once
+/// draining has started, listeners stop accepting connections and
+/// connections end once they are idle. Once closing, all connections end
+/// right away.
+///
+/// Listeners and connections register the waker of their task so they
+/// notice changes of the state even if their socket is idle.
+#[derive(Debug, Default)]
+struct Drain {
+    /// Have we started draining?
+    draining: AtomicBool,
+
+    /// Should all connections be closed?
+    closing: AtomicBool,
+
+    /// The wakers of all registered tasks.
+    wakers: Mutex<Slab<Waker>>,
+}
+
+impl Drain {
+    /// Registers a waker to be woken when the state changes.
+    ///
+    /// Returns the key for the registration to be passed in next time.
+    fn register(&self, key: Option<usize>, waker: &Waker) -> usize {
+        let mut wakers = self.wakers.lock().unwrap();
+        if let Some(key) = key {
+            if let Some(registered) = wakers.get_mut(key) {
+                if !registered.will_wake(waker) {
+                    *registered = waker.clone();
+                }
+                return key
             }
-            Poll::Ready(Err(err)) => Poll::Ready(Some(Err(err))),
-            Poll::Pending => Poll::Pending,
+        }
+        wakers.insert(waker.clone())
+    }
+
+    /// Removes a registration.
+    fn unregister(&self, key: usize) {
+        let mut wakers = self.wakers.lock().unwrap();
+        if wakers.contains(key) {
+            wakers.remove(key);
+        }
+    }
+
+    /// Returns whether draining has started.
+    fn is_draining(&self) -> bool {
+        self.draining.load(SeqCst)
+    }
+
+    /// Returns whether all connections should be closed.
+    fn is_closing(&self) -> bool {
+        self.closing.load(SeqCst)
+    }
+
+    /// Starts draining.
+    fn start(&self) {
+        self.draining.store(true, SeqCst);
+        self.wake_all();
+    }
+
+    /// Starts closing all connections.
+    fn close(&self) {
+        self.closing.store(true, SeqCst);
+        self.wake_all();
+    }
+
+    /// Wakes all registered tasks.
+    fn wake_all(&self) {
+        for (_, waker) in self.wakers.lock().unwrap().iter() {
+            waker.wake_by_ref()
+        }
+    }
+}
+
+
+//------------ ConnectionOptions ---------------------------------------------
+
+/// The options for client connections of an RTR target.
+#[derive(Clone, Debug)]
+struct ConnectionOptions {
+    /// The TCP keepalive duration if keepalive should be used.
+    keepalive: Option<Duration>,
+
+    /// The minimum time expected between two reset queries of a client.
+    min_reset_interval: Duration,
+
+    /// The maximum random delay before responding to a serial query.
+    serial_jitter: Duration,
+
+    /// The client classes.
+    classes: Arc<ClientClasses>,
+
+    /// The limits for the number of connections.
+    limits: Arc<ConnectionLimits>,
+
+    /// The rate limit for new connections per client address.
+    connection_rate: Option<Arc<RateLimit>>,
+
+    /// The rate limit for serial queries per client address.
+    serial_query_rate: Option<Arc<RateLimit>>,
+
+    /// The addresses clients may connect from.
+    access: Arc<AccessList>,
+
+    /// The SSH configuration if connections use SSH.
+    #[cfg(feature = "ssh")]
+    ssh: Option<Arc<ssh::ServerConfig>>,
+}
+
+
+//------------ ConnectionLimits ----------------------------------------------
+
+/// The limits for the number of concurrent client connections.
+///
+/// The limits are shared by all listeners of a target. Each accepted
+/// connection acquires a [`ConnectionSlot`] which is released again when
+/// the connection is dropped.
+#[derive(Debug)]
+struct ConnectionLimits {
+    /// The maximum number of connections overall.
+    max: Option<usize>,
+
+    /// The maximum number of connections per client address.
+    max_per_ip: Option<usize>,
+
+    /// The number of open connections overall and per client address.
+    open: Mutex<(usize, HashMap<IpAddr, usize>)>,
+}
+
+impl ConnectionLimits {
+    /// Creates new connection limits.
+    fn new(max: Option<usize>, max_per_ip: Option<usize>) -> Self {
+        ConnectionLimits {
+            max, max_per_ip,
+            open: Default::default(),
+        }
+    }
+
+    /// Acquires a slot for a new connection from the given address.
+    ///
+    /// Returns the limit that has been reached if no further connection is
+    /// permitted.
+    fn acquire(
+        self: &Arc<Self>, addr: IpAddr
+    ) -> Result<ConnectionSlot, ConnectionLimit> {
+        let mut open = self.open.lock().unwrap();
+        let (total, per_ip) = &mut *open;
+        if self.max.is_some_and(|max| *total >= max) {
+            return Err(ConnectionLimit::Total)
+        }
+        let count = per_ip.entry(addr).or_default();
+        if self.max_per_ip.is_some_and(|max| *count >= max) {
+            if *count == 0 {
+                per_ip.remove(&addr);
+            }
+            return Err(ConnectionLimit::PerIp)
+        }
+        *count += 1;
+        *total += 1;
+        Ok(ConnectionSlot { limits: self.clone(), addr })
+    }
+
+    /// Releases a slot for a connection from the given address.
+    fn release(&self, addr: IpAddr) {
+        let mut open = self.open.lock().unwrap();
+        let (total, per_ip) = &mut *open;
+        *total = total.saturating_sub(1);
+        if let Some(count) = per_ip.get_mut(&addr) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                per_ip.remove(&addr);
+            }
+        }
+    }
+}
+
+
+//------------ ConnectionSlot ------------------------------------------------
+
+/// A connection’s share of the connection limits.
+///
+/// The slot is released when the value is dropped.
+#[derive(Debug)]
+struct ConnectionSlot {
+    /// The limits the slot was acquired from.
+    limits: Arc<ConnectionLimits>,
+
+    /// The address of the client.
+    addr: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.limits.release(self.addr)
+    }
+}
+
+
+//------------ ConnectionLimit -----------------------------------------------
+
+/// The connection limit that prevented a new connection.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ConnectionLimit {
+    /// The maximum number of connections overall.
+    Total,
+
+    /// The maximum number of connections per client address.
+    PerIp,
+
+    /// The rate of new connections per client address.
+    Rate,
+}
+
+impl ConnectionLimit {
+    /// All the limits.
+    const ALL: [Self; 3] = [
+        ConnectionLimit::Total, ConnectionLimit::PerIp, ConnectionLimit::Rate
+    ];
+
+    /// Returns the name of the option for the limit.
+    fn as_str(self) -> &'static str {
+        match self {
+            ConnectionLimit::Total => "max-connections",
+            ConnectionLimit::PerIp => "max-connections-per-ip",
+            ConnectionLimit::Rate => "connection-rate",
+        }
+    }
+}
+
+impl fmt::Display for ConnectionLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+
+//------------ RateConfig ----------------------------------------------------
+
+/// The configuration of a rate limit.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateConfig {
+    /// The number of events permitted per second on average.
+    rate: f64,
+
+    /// The number of events permitted in a burst.
+    ///
+    /// If this is missing, the rate rounded up is used.
+    burst: Option<u32>,
+}
+
+impl RateConfig {
+    /// Returns whether the configuration describes a usable limit.
+    fn is_valid(self) -> bool {
+        self.rate.is_finite() && self.rate > 0. && self.burst != Some(0)
+    }
+
+    /// Returns the size of a burst.
+    fn burst(self) -> f64 {
+        match self.burst {
+            Some(burst) => burst.into(),
+            None => self.rate.ceil().max(1.),
+        }
+    }
+}
+
+
+//------------ RateLimit -----------------------------------------------------
+
+/// A token bucket rate limit per client address.
+///
+/// Each client address has its own bucket holding up to the burst size of
+/// tokens which is refilled at the configured rate. Each event takes one
+/// token out of the bucket.
+#[derive(Debug)]
+struct RateLimit {
+    /// The number of tokens added per second.
+    rate: f64,
+
+    /// The maximum number of tokens in a bucket.
+    burst: f64,
+
+    /// The buckets of the client addresses.
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+/// The number of buckets above which full buckets are removed.
+const RATE_LIMIT_PRUNE_LEN: usize = 1024;
+
+impl RateLimit {
+    /// Creates a new rate limit from its configuration.
+    fn new(config: RateConfig) -> Self {
+        RateLimit {
+            rate: config.rate,
+            burst: config.burst(),
+            buckets: Default::default(),
+        }
+    }
+
+    /// Tries to take a token for an event from the given address.
+    ///
+    /// Returns whether there was a token and the event is permitted.
+    fn try_acquire(&self, addr: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = self.bucket(&mut buckets, addr, now);
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    /// Takes a token for an event from the given address.
+    ///
+    /// If the bucket is empty, the token is taken anyway and the time
+    /// until it would have been available is returned. The event should
+    /// be delayed by this time.
+    fn reserve(&self, addr: IpAddr, now: Instant) -> Duration {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = self.bucket(&mut buckets, addr, now);
+        bucket.tokens -= 1.;
+        if bucket.tokens >= 0. {
+            Duration::ZERO
+        }
+        else {
+            Duration::from_secs_f64(-bucket.tokens / self.rate)
+        }
+    }
+
+    /// Returns the refilled bucket for an address.
+    ///
+    /// Buckets that are full anyway are dropped once there are too many of
+    /// them.
+    fn bucket<'a>(
+        &self,
+        buckets: &'a mut HashMap<IpAddr, TokenBucket>,
+        addr: IpAddr,
+        now: Instant,
+    ) -> &'a mut TokenBucket {
+        if buckets.len() > RATE_LIMIT_PRUNE_LEN {
+            buckets.retain(|_, bucket| {
+                bucket.refill(now, self.rate, self.burst);
+                bucket.tokens < self.burst
+            });
+        }
+        let bucket = buckets.entry(addr).or_insert(TokenBucket {
+            tokens: self.burst, updated: now
+        });
+        bucket.refill(now, self.rate, self.burst);
+        bucket
+    }
+}
+
+
+//------------ TokenBucket ---------------------------------------------------
+
+/// The token bucket of a single client address.
+#[derive(Clone, Copy, Debug)]
+struct TokenBucket {
+    /// The number of tokens currently in the bucket.
+    ///
+    /// This can become negative if tokens are reserved in advance.
+    tokens: f64,
+
+    /// The time the number of tokens was last updated.
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Adds the tokens accrued since the last update.
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) {
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.tokens = (
+            self.tokens + elapsed.as_secs_f64() * rate
+        ).min(burst);
+        self.updated = now;
+    }
+}
+
+
+//------------ ClientClassConfig ---------------------------------------------
+
+/// The configuration of a class of clients.
+#[derive(Clone, Debug, Deserialize)]
+struct ClientClassConfig {
+    /// The prefixes covering the addresses of clients in the class.
+    prefixes: Vec<Prefix>,
+
+    /// The RTR refresh interval for the class.
+    refresh: Option<u32>,
+
+    /// The RTR retry interval for the class.
+    retry: Option<u32>,
+
+    /// The RTR expire interval for the class.
+    expire: Option<u32>,
+}
+
+
+//------------ ClientClasses -------------------------------------------------
+
+/// The classes of clients of an RTR target.
+#[derive(Debug, Default)]
+struct ClientClasses {
+    /// The prefixes of all classes and the index of their class.
+    ///
+    /// The most specific prefixes come first.
+    prefixes: Vec<(Prefix, usize)>,
+
+    /// The classes.
+    classes: Vec<ClientClass>,
+}
+
+impl ClientClasses {
+    /// Creates the classes from their configuration.
+    fn new(config: &HashMap<String, ClientClassConfig>) -> Self {
+        let mut res = Self::default();
+        let mut config: Vec<_> = config.iter().collect();
+        config.sort_by(|left, right| left.0.cmp(right.0));
+        for (name, class) in config {
+            let idx = res.classes.len();
+            res.classes.push(ClientClass {
+                name: name.as_str().into(),
+                timing: TimingOverride::new(
+                    class.refresh, class.retry, class.expire
+                ),
+                metrics: Default::default(),
+            });
+            res.prefixes.extend(
+                class.prefixes.iter().map(|prefix| (*prefix, idx))
+            );
+        }
+        res.prefixes.sort_by_key(|item| cmp::Reverse(item.0.len()));
+        res
+    }
+
+    /// Returns the class of the given client address if there is one.
+    ///
+    /// If the address is covered by prefixes of several classes, the
+    /// class of the most specific prefix is returned.
+    fn get(&self, addr: IpAddr) -> Option<&ClientClass> {
+        let len = if addr.is_ipv4() { 32 } else { 128 };
+        self.prefixes.iter().find(|(prefix, _)| {
+            covers(prefix.addr(), prefix.len(), addr, len)
+        }).map(|(_, idx)| &self.classes[*idx])
+    }
+
+    /// Returns an iterator over all the classes.
+    fn iter(&self) -> impl Iterator<Item = &ClientClass> {
+        self.classes.iter()
+    }
+}
+
+
+//------------ ClientClass ---------------------------------------------------
+
+/// A class of clients.
+#[derive(Debug)]
+struct ClientClass {
+    /// The name of the class.
+    name: Arc<str>,
+
+    /// The timing values advertised to clients of the class.
+    timing: TimingOverride,
+
+    /// The metrics of the class.
+    metrics: Arc<MetricsData>,
+}
+
+
+//------------ Response ------------------------------------------------------
+
+/// The kind of response sent to a client.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Response {
+    /// A response to a Reset Query, i.e., the full data set.
+    Reset,
+
+    /// A response to a Serial Query, i.e., a diff.
+    Serial,
+}
+
+impl Response {
+    /// All response kinds.
+    const ALL: [Response; 2] = [Response::Reset, Response::Serial];
+
+    /// Returns the name of the response kind for use in metrics.
+    fn as_str(self) -> &'static str {
+        match self {
+            Response::Reset => "reset",
+            Response::Serial => "serial",
+        }
+    }
+}
+
+
+//------------ PayloadType ---------------------------------------------------
+
+/// The type of a payload item sent to a client.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum PayloadType {
+    Ipv4Prefix,
+    Ipv6Prefix,
+    RouterKey,
+    Aspa,
+}
+
+impl PayloadType {
+    /// All payload types.
+    const ALL: [PayloadType; 4] = [
+        PayloadType::Ipv4Prefix, PayloadType::Ipv6Prefix,
+        PayloadType::RouterKey, PayloadType::Aspa,
+    ];
+
+    /// Returns the payload type for a PDU type if it is a payload PDU.
+    fn from_pdu_type(pdu_type: u8) -> Option<Self> {
+        match pdu_type {
+            IPV4_PREFIX => Some(PayloadType::Ipv4Prefix),
+            IPV6_PREFIX => Some(PayloadType::Ipv6Prefix),
+            ROUTER_KEY => Some(PayloadType::RouterKey),
+            ASPA => Some(PayloadType::Aspa),
+            _ => None
+        }
+    }
+
+    /// Returns the name of the payload type for use in metrics.
+    fn as_str(self) -> &'static str {
+        match self {
+            PayloadType::Ipv4Prefix => "ipv4",
+            PayloadType::Ipv6Prefix => "ipv6",
+            PayloadType::RouterKey => "router-key",
+            PayloadType::Aspa => "aspa",
         }
     }
 }
@@ -423,79 +2182,317 @@ impl Stream for RtrListener {
 struct RtrStream {
     sock: MaybeTlsTcpStream,
     metrics: ClientMetrics,
+
+    /// The name of the target for logging.
+    name: Arc<str>,
+
+    /// The address of the client.
+    addr: SocketAddr,
+
+    /// The tracker for PDUs received from the client.
+    read_pdus: PduTracker,
+
+    /// The tracker for PDUs sent to the client.
+    write_pdus: PduTracker,
+
+    /// The protocol version of the first PDU sent by the client.
+    client_version: Option<u8>,
+
+    /// The protocol version of the first PDU we sent to the client.
+    server_version: Option<u8>,
+
+    /// The serial number of the last End of Data PDU sent to the client.
+    sent_serial: Option<Serial>,
+
+    /// The serial number of the last Serial Query received from the client.
+    acked_serial: Option<Serial>,
+
+    /// The time of the last Reset Query received from the client.
+    last_reset: Option<Instant>,
+
+    /// The kind of response currently being sent to the client.
+    ///
+    /// This is determined by the last query received from the client.
+    response: Option<Response>,
+
+    /// The minimum time expected between two reset queries.
+    min_reset_interval: Duration,
+
+    /// The maximum random delay before responding to a serial query.
+    serial_jitter: Duration,
+
+    /// The histogram of the delays applied to serial responses.
+    jitter_metrics: Arc<Histogram>,
+
+    /// The rate limit for serial queries.
+    serial_query_rate: Option<Arc<RateLimit>>,
+
+    /// The number of serial queries delayed because of the rate limit.
+    serial_throttled: Arc<AtomicU64>,
+
+    /// The delay to wait out before writing the next response.
+    delay: Option<Pin<Box<Sleep>>>,
+
+    /// Is the client waiting for a response or are we waiting for a query?
+    ///
+    /// While this is `true`, the connection is kept open when draining.
+    in_flight: bool,
+
+    /// The drain state of the target.
+    drain: Arc<Drain>,
+
+    /// Our key for registering with the drain state.
+    drain_key: Option<usize>,
+
+    /// The timing values to advertise if they differ from the defaults.
+    timing: Option<TimingOverride>,
+
+    /// The connection’s slot in the connection limits.
+    ///
+    /// This is only kept so the slot is released when the stream is
+    /// dropped.
+    _slot: ConnectionSlot,
 }
 
 impl RtrStream {
     /// Creates a new RTR connection stream.
-    #[allow(clippy::redundant_async_block)] // False positive
     fn new(
-        sock: TcpStream,
+        name: Arc<str>,
+        sock: MaybeTlsTcpStream,
         addr: SocketAddr,
-        tls: Option<&TlsAcceptor>,
-        keepalive: Option<Duration>,
+        options: &ConnectionOptions,
         server_metrics: &ListenerMetrics,
-    ) -> Result<Self, io::Error> {
-        if let Some(duration) = keepalive {
-            Self::set_keepalive(&sock, duration)?
-        }
-        let metrics = server_metrics.get_client(addr.ip());
+        drain: Arc<Drain>,
+        slot: ConnectionSlot,
+    ) -> Self {
+        let class = options.classes.get(addr.ip());
+        let metrics = server_metrics.get_client(addr.ip(), class);
         metrics.update(|metrics| metrics.inc_open());
-        Ok(RtrStream {
-            sock: MaybeTlsTcpStream::new(sock, tls),
-            metrics
-        })
+        RtrStream {
+            sock,
+            metrics,
+            timing: class.and_then(|class| {
+                (!class.timing.is_empty()).then_some(class.timing)
+            }),
+            name,
+            addr,
+            read_pdus: Default::default(),
+            write_pdus: Default::default(),
+            client_version: None,
+            server_version: None,
+            sent_serial: None,
+            acked_serial: None,
+            last_reset: None,
+            response: None,
+            min_reset_interval: options.min_reset_interval,
+            serial_jitter: options.serial_jitter,
+            jitter_metrics: server_metrics.serial_jitter.clone(),
+            serial_query_rate: options.serial_query_rate.clone(),
+            serial_throttled: server_metrics.serial_throttled.clone(),
+            delay: None,
+            in_flight: false,
+            drain,
+            drain_key: None,
+            _slot: slot,
+        }
     }
 
-    /// Sets the TCP keepalive if configured.
-    #[cfg(unix)]
-    fn set_keepalive(
-        sock: &TcpStream, duration: Duration
-    ) -> Result<(), io::Error>{
-        use nix::sys::socket::{setsockopt, sockopt};
+    /// Processes a PDU received from the client.
+    fn client_pdu(&mut self, pdu: Pdu) {
+        if self.client_version.is_none() {
+            self.client_version = Some(pdu.version());
+        }
+        match pdu.pdu_type() {
+            SERIAL_QUERY => {
+                self.in_flight = true;
+                self.response = Some(Response::Serial);
+                if let Some(serial) = pdu.serial() {
+                    self.serial_query(Serial::from(serial))
+                }
+                let throttle = self.throttle_serial();
+                self.arm_delay(throttle);
+            }
+            RESET_QUERY => {
+                self.in_flight = true;
+                self.response = Some(Response::Reset);
+                self.reset_query()
+            }
+            _ => { }
+        }
+    }
 
-        (|fd, duration: Duration| {
-            setsockopt(fd, sockopt::KeepAlive, &true)?;
+    /// Processes a Serial Query received from the client.
+    ///
+    /// The serial of the query acknowledges the data the client has. It
+    /// is expected to lie between the serial the client acknowledged last
+    /// and the serial we last sent it.
+    fn serial_query(&mut self, serial: Serial) {
+        if let Some(acked) = self.acked_serial {
+            if serial < acked {
+                warn!(
+                    "Target {}: client {} went back from serial {} to {}.",
+                    self.name, self.addr, u32::from(acked), u32::from(serial)
+                );
+                self.metrics.update(|metrics| metrics.inc_anomalies());
+            }
+        }
+        if let Some(sent) = self.sent_serial {
+            if serial > sent {
+                warn!(
+                    "Target {}: client {} queried for serial {} but was \
+                     only ever sent serial {}.",
+                    self.name, self.addr, u32::from(serial), u32::from(sent)
+                );
+                self.metrics.update(|metrics| metrics.inc_anomalies());
+            }
+        }
+        self.acked_serial = Some(serial);
+        self.metrics.update(|metrics| metrics.acked_serial(serial));
+    }
 
-            // The attributes are copied from the definitions in
-            // nix::sys::socket::sockopt. Let’s hope they never change.
+    /// Applies the serial query rate limit.
+    ///
+    /// Returns the time the response needs to be delayed because the
+    /// client address has exceeded its rate.
+    fn throttle_serial(&mut self) -> Duration {
+        let rate = match self.serial_query_rate.as_ref() {
+            Some(rate) => rate,
+            None => return Duration::ZERO
+        };
+        let delay = rate.reserve(self.addr.ip(), Instant::now());
+        if !delay.is_zero() {
+            debug!(
+                "Target {}: delaying serial response to {} by {}ms \
+                 because of the rate limit.",
+                self.name, self.addr, delay.as_millis()
+            );
+            self.serial_throttled.fetch_add(1, Relaxed);
+        }
+        delay
+    }
 
-            #[cfg(any(target_os = "ios", target_os = "macos"))]
-            setsockopt(
-                fd, sockopt::TcpKeepAlive,
-                &u32::try_from(duration.as_secs()).unwrap_or(u32::MAX)
-            )?;
+    /// Delays the response to a Serial Query.
+    ///
+    /// The response is delayed by the given throttle time plus a random
+    /// time if jitter is configured. Does nothing if the total is zero.
+    fn arm_delay(&mut self, throttle: Duration) {
+        let jitter = if self.serial_jitter.is_zero() {
+            Duration::ZERO
+        }
+        else {
+            let jitter = thread_rng().gen_range(
+                Duration::ZERO..=self.serial_jitter
+            );
+            self.jitter_metrics.observe(jitter);
+            jitter
+        };
+        let delay = throttle + jitter;
+        if !delay.is_zero() {
+            self.delay = Some(Box::pin(tokio::time::sleep(delay)));
+        }
+    }
 
-            #[cfg(any(
-                target_os = "android",
-                target_os = "dragonfly",
-                target_os = "freebsd",
-                target_os = "linux",
-            ))]
-            setsockopt(
-                fd, sockopt::TcpKeepIdle,
-                &u32::try_from(duration.as_secs()).unwrap_or(u32::MAX)
-            )?;
+    /// Processes a Reset Query received from the client.
+    ///
+    /// Checks the time since the last reset query on this connection and,
+    /// if per-client metrics are enabled, since the last completed reset
+    /// of the client address.
+    fn reset_query(&mut self) {
+        let now = Instant::now();
+        let since = self.last_reset.map(|last| now - last).or_else(|| {
+            let last = self.metrics.client.as_ref()?.last_reset()?;
+            (Utc::now() - last).to_std().ok()
+        });
+        self.last_reset = Some(now);
+        if let Some(since) = since {
+            if since < self.min_reset_interval {
+                warn!(
+                    "Target {}: client {} sent a reset query only {}s \
+                     after the last one.",
+                    self.name, self.addr, since.as_secs()
+                );
+                self.metrics.update(|metrics| metrics.inc_anomalies());
+            }
+        }
+    }
 
-            #[cfg(not(target_os = "openbsd"))]
-            setsockopt(
-                fd, sockopt::TcpKeepInterval,
-                &u32::try_from(duration.as_secs()).unwrap_or(u32::MAX)
-            )?;
+    /// Processes a PDU sent to the client.
+    fn server_pdu(&mut self, pdu: Pdu) {
+        if let Some(payload) = PayloadType::from_pdu_type(pdu.pdu_type()) {
+            if let Some(response) = self.response {
+                self.metrics.update(|metrics| {
+                    metrics.inc_sent_payload(response, payload)
+                });
+            }
+            return
+        }
+        match pdu.pdu_type() {
+            // After a notify, we expect the client to query.
+            SERIAL_NOTIFY => self.in_flight = true,
+            END_OF_DATA | CACHE_RESET | ERROR_REPORT => {
+                self.in_flight = false
+            }
+            _ => { }
+        }
+        if pdu.pdu_type() == END_OF_DATA {
+            if let Some(serial) = pdu.serial() {
+                self.sent_serial = Some(Serial::from(serial));
+            }
+        }
+        if pdu.pdu_type() == ERROR_REPORT
+            && pdu.session() == UNSUPPORTED_VERSION
+        {
+            info!(
+                "Target {}: refused client {} requesting unsupported \
+                 RTR version {}.",
+                self.name, self.addr,
+                self.client_version.unwrap_or(pdu.version())
+            );
+            self.metrics.update(|metrics| metrics.inc_version_refused());
+            return
+        }
+        if self.server_version.is_some() {
+            return
+        }
+        let version = pdu.version();
+        self.server_version = Some(version);
+        let downgraded = self.client_version.map(|client| {
+            client > version
+        }).unwrap_or(false);
+        if downgraded {
+            info!(
+                "Target {}: client {} downgraded from RTR version {} to {}.",
+                self.name, self.addr,
+                self.client_version.unwrap_or_default(), version
+            );
+        }
+        else {
+            info!(
+                "Target {}: client {} uses RTR version {}.",
+                self.name, self.addr, version
+            );
+        }
+        self.metrics.update(|metrics| {
+            metrics.negotiated_version(version, downgraded)
+        });
+    }
 
-            Ok(())
-        })(sock, duration).map_err(|err: nix::errno::Errno| {
-            io::Error::new(io::ErrorKind::Other, err)
-        })
+    /// Registers the task’s waker with the drain state.
+    fn register_drain(&mut self, cx: &Context) {
+        self.drain_key = Some(
+            self.drain.register(self.drain_key, cx.waker())
+        );
     }
 
-    /// Sets the TCP keepalive if configured.
+    /// Returns whether the connection should end because of draining.
     ///
-    /// This is the non-Unix version that is actually a no-op.
-    #[cfg(not(unix))]
-    fn set_keepalive(
-        _sock: &TcpStream, _duration: Duration
-    ) -> Result<(), io::Error>{
-        Ok(())
+    /// This is the case if all connections are being closed or if we are
+    /// draining and there is no exchange with the client in progress.
+    fn drained(&self) -> bool {
+        self.drain.is_closing() || (
+            self.drain.is_draining()
+            && !self.in_flight && !self.read_pdus.is_partial()
+        )
     }
 }
 
@@ -511,13 +2508,27 @@ impl AsyncRead for RtrStream {
     fn poll_read(
         mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf
     ) -> Poll<Result<(), io::Error>> {
+        self.register_drain(cx);
+        if self.drain.is_closing() {
+            // Signal end-of-file to end the connection.
+            return Poll::Ready(Ok(()))
+        }
         let len = buf.filled().len();
         let sock = &mut self.sock;
         pin_mut!(sock);
         let res = sock.poll_read(cx, buf);
+        if res.is_pending() && self.drained() {
+            return Poll::Ready(Ok(()))
+        }
         if let Poll::Ready(Ok(())) = res {
-            let len = buf.filled().len().saturating_sub(len) as u64;
-            self.metrics.update(|metrics| metrics.inc_bytes_read(len));
+            let data = buf.filled().get(len..).unwrap_or_default();
+            let this = &mut *self;
+            this.metrics.update(|metrics| {
+                metrics.inc_bytes_read(data.len() as u64)
+            });
+            let mut tracker = std::mem::take(&mut this.read_pdus);
+            tracker.feed(data, |pdu| this.client_pdu(pdu));
+            this.read_pdus = tracker;
         }
         res
     }
@@ -527,11 +2538,46 @@ impl AsyncWrite for RtrStream {
     fn poll_write(
         mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]
     ) -> Poll<Result<usize, io::Error>> {
-        let sock = &mut self.sock;
-        pin_mut!(sock);
-        let res = sock.poll_write(cx, buf);
+        self.register_drain(cx);
+        if self.drain.is_closing() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted, "shutting down"
+            )))
+        }
+        if let Some(delay) = self.delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+        let res = match self.timing {
+            Some(mut timing) => {
+                let mut data = buf.to_vec();
+                timing.apply(&mut data);
+                let sock = &mut self.sock;
+                pin_mut!(sock);
+                let res = sock.poll_write(cx, &data);
+                if let (Poll::Ready(Ok(n)), Some(timing)) = (
+                    &res, self.timing.as_mut()
+                ) {
+                    // Only advance the override by what was actually
+                    // written.
+                    timing.apply(&mut data[..*n]);
+                }
+                res
+            }
+            None => {
+                let sock = &mut self.sock;
+                pin_mut!(sock);
+                sock.poll_write(cx, buf)
+            }
+        };
         if let Poll::Ready(Ok(n)) = res {
-            self.metrics.update(|metrics| metrics.inc_bytes_written(n as u64))
+            let this = &mut *self;
+            this.metrics.update(|metrics| {
+                metrics.inc_bytes_written(n as u64)
+            });
+            let mut tracker = std::mem::take(&mut this.write_pdus);
+            tracker.feed(&buf[..n], |pdu| this.server_pdu(pdu));
+            this.write_pdus = tracker;
         }
         res
     }
@@ -555,6 +2601,9 @@ impl AsyncWrite for RtrStream {
 
 impl Drop for RtrStream {
     fn drop(&mut self) {
+        if let Some(key) = self.drain_key {
+            self.drain.unregister(key)
+        }
         self.metrics.update(|metrics| metrics.dec_open())
     }
 }
@@ -579,27 +2628,66 @@ struct ListenerMetrics {
 
     /// The number of entries in the current payload set.
     payload_size: AtomicUsize,
+
+    /// The number of serial notifies coalesced into a later one.
+    notifies_suppressed: AtomicU64,
+
+    /// The metrics of the accept loops of all listeners.
+    accept: Arc<AcceptMetrics>,
+
+    /// The number of connections rejected because of the overall limit.
+    rejected_total: AtomicU64,
+
+    /// The number of connections rejected because of the per-address limit.
+    rejected_per_ip: AtomicU64,
+
+    /// The number of connections rejected because of the rate limit.
+    rejected_rate: AtomicU64,
+
+    /// The number of serial queries delayed because of the rate limit.
+    serial_throttled: Arc<AtomicU64>,
+
+    /// The delays applied to serial responses.
+    serial_jitter: Arc<Histogram>,
+
+    /// The client classes.
+    classes: Arc<ClientClasses>,
 }
 
+/// A per-class metric and the function to get its value.
+type ClassMetric = (&'static Metric, fn(&MetricsData) -> u64);
+
 impl ListenerMetrics {
     /// Creates a new listener metrics value.
     ///
     /// If `client_metrics` is `true`, the value will keep per-client address
-    /// metrics. Otherwise it will only keep global metrics.
-    fn new(client_metrics: bool) -> Self {
+    /// metrics. Otherwise it will only keep global metrics. In addition,
+    /// metrics are kept for each of the client classes.
+    fn new(client_metrics: bool, classes: Arc<ClientClasses>) -> Self {
         Self {
             global: Default::default(),
             client: client_metrics.then(Default::default),
             serial: Default::default(),
             payload_size: Default::default(),
+            notifies_suppressed: Default::default(),
+            accept: Default::default(),
+            rejected_total: Default::default(),
+            rejected_per_ip: Default::default(),
+            rejected_rate: Default::default(),
+            serial_throttled: Default::default(),
+            serial_jitter: Arc::new(Histogram::new(Self::JITTER_BOUNDS)),
+            classes,
         }
     }
 
-    /// Returns a client metrics value for the given address.
-    fn get_client(&self, addr: IpAddr) -> ClientMetrics {
+    /// Returns a client metrics value for the given address and class.
+    fn get_client(
+        &self, addr: IpAddr, class: Option<&ClientClass>
+    ) -> ClientMetrics {
         ClientMetrics {
             global: self.global.clone(),
             client: self.client.as_ref().map(|client| client.get(addr)),
+            class: class.map(|class| class.metrics.clone()),
         }
     }
 }
@@ -638,6 +2726,25 @@ impl metrics::Source for ListenerMetrics {
                     }
                 }
             );
+            target.append(
+                &Self::CLIENT_ACKED_METRIC, Some(unit_name),
+                |records| {
+                    for (addr, metric) in &client {
+                        match metric.acked() {
+                            Some(serial) => {
+                                records.label_value(
+                                    &[("addr", addr)], serial
+                                );
+                            }
+                            None => {
+                                records.label_value(
+                                    &[("addr", addr)], "-1"
+                                );
+                            }
+                        }
+                    }
+                }
+            );
             target.append(
                 &Self::UPDATED_METRIC, Some(unit_name),
                 |records| {
@@ -732,6 +2839,59 @@ impl metrics::Source for ListenerMetrics {
                     }
                 }
             );
+            target.append(
+                &Self::CLIENT_ANOMALIES_METRIC, Some(unit_name),
+                |records| {
+                    for (addr, metric) in &client {
+                        records.label_value(
+                            &[("addr", addr)],
+                            metric.anomalies()
+                        );
+                    }
+                }
+            );
+            target.append(
+                &Self::CLIENT_SENT_PAYLOAD_METRIC, Some(unit_name),
+                |records| {
+                    for (addr, metric) in &client {
+                        for response in Response::ALL {
+                            for payload in PayloadType::ALL {
+                                records.label_value(
+                                    &[
+                                        ("addr", addr),
+                                        ("response", response.as_str()),
+                                        ("type", payload.as_str()),
+                                    ],
+                                    metric.sent_payload(response, payload)
+                                );
+                            }
+                        }
+                    }
+                }
+            );
+            target.append(
+                &Self::CLIENT_VERSION_METRIC, Some(unit_name),
+                |records| {
+                    for (addr, metric) in &client {
+                        match metric.version() {
+                            Some(version) => {
+                                records.label_value(
+                                    &[("addr", addr)], version
+                                );
+                            }
+                            None => {
+                                records.label_value(
+                                    &[("addr", addr)], "-1"
+                                );
+                            }
+                        }
+                    }
+                }
+            );
+        }
+
+        if !self.classes.classes.is_empty() {
+            self.append_classes(unit_name, target);
         }
 
         target.append_simple(
@@ -750,6 +2910,125 @@ impl metrics::Source for ListenerMetrics {
         target.append_simple(
             &Self::WRITE_METRIC, Some(unit_name), self.global.bytes_written()
         );
+        target.append(
+            &Self::VERSION_CONNECTIONS_METRIC, Some(unit_name),
+            |records| {
+                for (version, count) in
+                    self.global.version_connections.iter().enumerate()
+                {
+                    records.label_value(
+                        &[("version", &version.to_string())],
+                        count.load(Relaxed)
+                    );
+                }
+            }
+        );
+        target.append_simple(
+            &Self::VERSION_DOWNGRADES_METRIC, Some(unit_name),
+            self.global.version_downgrades.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::VERSION_REFUSED_METRIC, Some(unit_name),
+            self.global.version_refused.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::ANOMALIES_METRIC, Some(unit_name),
+            self.global.anomalies()
+        );
+        target.append(
+            &Self::SENT_PAYLOAD_METRIC, Some(unit_name),
+            |records| {
+                for response in Response::ALL {
+                    for payload in PayloadType::ALL {
+                        records.label_value(
+                            &[
+                                ("response", response.as_str()),
+                                ("type", payload.as_str()),
+                            ],
+                            self.global.sent_payload(response, payload)
+                        );
+                    }
+                }
+            }
+        );
+        target.append_simple(
+            &Self::NOTIFIES_SUPPRESSED_METRIC, Some(unit_name),
+            self.notifies_suppressed.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::ACCEPTED_METRIC, Some(unit_name), self.accept.accepted()
+        );
+        target.append_simple(
+            &Self::ACCEPT_ERRORS_METRIC, Some(unit_name), self.accept.errors()
+        );
+        target.append_simple(
+            &Self::ACCEPT_PAUSES_METRIC, Some(unit_name), self.accept.pauses()
+        );
+        target.append_simple(
+            &Self::DENIED_METRIC, Some(unit_name), self.accept.denied()
+        );
+        target.append(
+            &Self::REJECTED_METRIC, Some(unit_name),
+            |records| {
+                for limit in ConnectionLimit::ALL {
+                    records.label_value(
+                        &[("limit", limit.as_str())],
+                        self.rejected(limit).load(Relaxed)
+                    );
+                }
+            }
+        );
+        target.append_simple(
+            &Self::SERIAL_THROTTLED_METRIC, Some(unit_name),
+            self.serial_throttled.load(Relaxed)
+        );
+        if self.serial_jitter.count() > 0 {
+            target.append(
+                &Self::SERIAL_JITTER_METRIC, Some(unit_name),
+                |records| records.histogram(&[], &self.serial_jitter)
+            );
+        }
+    }
+}
+
+impl ListenerMetrics {
+    /// Returns the counter of connections rejected because of `limit`.
+    fn rejected(&self, limit: ConnectionLimit) -> &AtomicU64 {
+        match limit {
+            ConnectionLimit::Total => &self.rejected_total,
+            ConnectionLimit::PerIp => &self.rejected_per_ip,
+            ConnectionLimit::Rate => &self.rejected_rate,
+        }
+    }
+
+    /// Counts a connection rejected because of `limit`.
+    fn inc_rejected(&self, limit: ConnectionLimit) {
+        self.rejected(limit).fetch_add(1, Relaxed);
+    }
+
+    /// Appends the metrics of the client classes.
+    fn append_classes(&self, unit_name: &str, target: &mut metrics::Target) {
+        let metrics: [ClassMetric; 6] = [
+            (&Self::CLASS_OPEN_METRIC, |data| data.open() as u64),
+            (&Self::CLASS_RESET_QUERIES_METRIC, |data| {
+                data.reset_queries().into()
+            }),
+            (&Self::CLASS_SERIAL_QUERIES_METRIC, |data| {
+                data.serial_queries().into()
+            }),
+            (&Self::CLASS_READ_METRIC, MetricsData::bytes_read),
+            (&Self::CLASS_WRITE_METRIC, MetricsData::bytes_written),
+            (&Self::CLASS_ANOMALIES_METRIC, |data| data.anomalies().into()),
+        ];
+        for (metric, value) in metrics {
+            target.append(metric, Some(unit_name), |records| {
+                for class in self.classes.iter() {
+                    records.label_value(
+                        &[("class", &class.name)], value(&class.metrics)
+                    );
+                }
+            });
+        }
     }
 }
 
@@ -763,6 +3042,11 @@ impl ListenerMetrics {
         "rtr_client_serial", "last serial seen by a client address",
         MetricType::Gauge, MetricUnit::Total
     );
+    const CLIENT_ACKED_METRIC: Metric = Metric::new(
+        "rtr_client_acked_serial",
+        "last serial acknowledged by a client address",
+        MetricType::Gauge, MetricUnit::Total
+    );
     const UPDATED_METRIC: Metric = Metric::new(
         "rtr_client_last_update",
         "seconds since last update by a client address",
@@ -793,6 +3077,121 @@ impl ListenerMetrics {
         "number of bytes written to a client address",
         MetricType::Counter, MetricUnit::Byte
     );
+    const CLIENT_ANOMALIES_METRIC: Metric = Metric::new(
+        "rtr_client_anomalies",
+        "number of anomalous queries by a client address",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const CLIENT_SENT_PAYLOAD_METRIC: Metric = Metric::new(
+        "rtr_client_sent_payload",
+        "number of payload items sent in responses to a client address",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const CLIENT_VERSION_METRIC: Metric = Metric::new(
+        "rtr_client_version",
+        "RTR version last negotiated by a client address",
+        MetricType::Gauge, MetricUnit::Info
+    );
+    const CLASS_OPEN_METRIC: Metric = Metric::new(
+        "rtr_class_connections",
+        "number of open client connections by a client class",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const CLASS_RESET_QUERIES_METRIC: Metric = Metric::new(
+        "rtr_class_reset_queries",
+        "number of reset queries by a client class",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const CLASS_SERIAL_QUERIES_METRIC: Metric = Metric::new(
+        "rtr_class_serial_queries",
+        "number of serial queries by a client class",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const CLASS_READ_METRIC: Metric = Metric::new(
+        "rtr_class_read",
+        "number of bytes read from a client class",
+        MetricType::Counter, MetricUnit::Byte
+    );
+    const CLASS_WRITE_METRIC: Metric = Metric::new(
+        "rtr_class_write",
+        "number of bytes written to a client class",
+        MetricType::Counter, MetricUnit::Byte
+    );
+    const CLASS_ANOMALIES_METRIC: Metric = Metric::new(
+        "rtr_class_anomalies",
+        "number of anomalous queries by a client class",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const VERSION_CONNECTIONS_METRIC: Metric = Metric::new(
+        "rtr_version_connections",
+        "number of connections that negotiated an RTR version",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const VERSION_DOWNGRADES_METRIC: Metric = Metric::new(
+        "rtr_version_downgrades",
+        "number of connections downgraded to a lower RTR version",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const VERSION_REFUSED_METRIC: Metric = Metric::new(
+        "rtr_version_refused",
+        "number of connections refused for an unsupported RTR version",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const ANOMALIES_METRIC: Metric = Metric::new(
+        "rtr_anomalies",
+        "number of anomalous queries by RTR clients",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const SENT_PAYLOAD_METRIC: Metric = Metric::new(
+        "rtr_sent_payload",
+        "number of payload items sent in responses to RTR clients",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const NOTIFIES_SUPPRESSED_METRIC: Metric = Metric::new(
+        "rtr_notifies_suppressed",
+        "number of serial notifies coalesced into a later one",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const ACCEPTED_METRIC: Metric = Metric::new(
+        "rtr_accepted_connections",
+        "number of client connections accepted since startup",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const ACCEPT_ERRORS_METRIC: Metric = Metric::new(
+        "rtr_accept_errors",
+        "number of failed attempts to accept a client connection",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const ACCEPT_PAUSES_METRIC: Metric = Metric::new(
+        "rtr_accept_pauses",
+        "number of times accepting was paused for lack of resources",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const DENIED_METRIC: Metric = Metric::new(
+        "rtr_denied_connections",
+        "number of client connections denied because of their address",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const REJECTED_METRIC: Metric = Metric::new(
+        "rtr_rejected_connections",
+        "number of client connections rejected because of a limit",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const SERIAL_THROTTLED_METRIC: Metric = Metric::new(
+        "rtr_throttled_serial_queries",
+        "number of serial responses delayed because of the rate limit",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const SERIAL_JITTER_METRIC: Metric = Metric::new(
+        "rtr_serial_jitter",
+        "random delay applied to responses to serial queries",
+        MetricType::Histogram, MetricUnit::Second
+    );
+
+    /// The bucket bounds of the serial jitter histogram in seconds.
+    const JITTER_BOUNDS: &'static [f64] = &[
+        0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10.
+    ];
     const OPEN_METRIC: Metric = Metric::new(
         "rtr_connections",
         "number of currently open RTR client connections",
@@ -873,18 +3272,23 @@ impl PerAddrMetrics {
 struct ClientMetrics {
     global: Arc<MetricsData>,
     client: Option<Arc<MetricsData>>,
+    class: Option<Arc<MetricsData>>,
 }
 
 impl ClientMetrics {
     /// Updates the client metrics.
     ///
-    /// The method takes a closure that is run either once or twice, depending
-    /// on whether per-client address metrics are enabled.
+    /// The method takes a closure that is run once for the global metrics
+    /// and once more each for the per-client address metrics and the
+    /// client class metrics if these are present.
     fn update(&self, op: impl Fn(&MetricsData)) {
         op(&self.global);
         if let Some(client) = self.client.as_ref() {
             op(client)
         }
+        if let Some(class) = self.class.as_ref() {
+            op(class)
+        }
     }
 }
 
@@ -925,8 +3329,38 @@ pub struct MetricsData {
 
     /// The number of bytes written.
     bytes_written: AtomicU64,
+
+    /// The RTR version negotiated last.
+    ///
+    /// This is actually an option with the value of `u32::MAX` serving as
+    /// `None`.
+    version: AtomicU32,
+
+    /// The number of connections per negotiated RTR version.
+    version_connections: [AtomicU32; RTR_VERSIONS],
+
+    /// The number of connections downgraded to a lower version.
+    version_downgrades: AtomicU32,
+
+    /// The number of connections refused for an unsupported version.
+    version_refused: AtomicU32,
+
+    /// The serial number last acknowledged via a Serial Query.
+    ///
+    /// This is actually an option with the value of `u32::MAX` serving as
+    /// `None`.
+    acked_serial: AtomicU32,
+
+    /// The number of anomalous queries.
+    anomalies: AtomicU32,
+
+    /// The number of payload items sent per response kind and type.
+    sent_payload: [[AtomicU64; 4]; 2],
 }
 
+/// The number of RTR versions we keep metrics for.
+const RTR_VERSIONS: usize = 3;
+
 impl Default for MetricsData {
     fn default() -> Self {
         Self {
@@ -938,6 +3372,13 @@ impl Default for MetricsData {
             serial_queries: AtomicU32::new(0),
             bytes_read: AtomicU64::new(0),
             bytes_written: AtomicU64::new(0),
+            version: AtomicU32::new(u32::MAX),
+            version_connections: Default::default(),
+            version_downgrades: AtomicU32::new(0),
+            version_refused: AtomicU32::new(0),
+            acked_serial: AtomicU32::new(u32::MAX),
+            anomalies: AtomicU32::new(0),
+            sent_payload: Default::default(),
         }
     }
 }
@@ -1032,5 +3473,360 @@ impl MetricsData {
     fn inc_bytes_written(&self, count: u64) {
         self.bytes_written.fetch_add(count, Relaxed);
     }
+
+    /// Returns the RTR version negotiated last.
+    fn version(&self) -> Option<u32> {
+        match self.version.load(Relaxed) {
+            u32::MAX => None,
+            other => Some(other),
+        }
+    }
+
+    /// Records a newly negotiated RTR version.
+    fn negotiated_version(&self, version: u8, downgraded: bool) {
+        self.version.store(version.into(), Relaxed);
+        if let Some(count) = self.version_connections.get(
+            usize::from(version)
+        ) {
+            count.fetch_add(1, Relaxed);
+        }
+        if downgraded {
+            self.version_downgrades.fetch_add(1, Relaxed);
+        }
+    }
+
+    /// Increases the number of connections refused for their version.
+    fn inc_version_refused(&self) {
+        self.version_refused.fetch_add(1, Relaxed);
+    }
+
+    /// Returns the serial number last acknowledged by a client.
+    fn acked(&self) -> Option<u32> {
+        match self.acked_serial.load(Relaxed) {
+            u32::MAX => None,
+            other => Some(other),
+        }
+    }
+
+    /// Records a serial number acknowledged by a client.
+    fn acked_serial(&self, serial: Serial) {
+        self.acked_serial.store(serial.into(), Relaxed);
+    }
+
+    /// Returns the number of anomalous queries.
+    fn anomalies(&self) -> u32 {
+        self.anomalies.load(Relaxed)
+    }
+
+    /// Increases the number of anomalous queries.
+    fn inc_anomalies(&self) {
+        self.anomalies.fetch_add(1, Relaxed);
+    }
+
+    /// Returns the number of payload items sent of the given kinds.
+    fn sent_payload(&self, response: Response, payload: PayloadType) -> u64 {
+        self.sent_payload[response as usize][payload as usize].load(Relaxed)
+    }
+
+    /// Increases the number of payload items sent of the given kinds.
+    fn inc_sent_payload(&self, response: Response, payload: PayloadType) {
+        self.sent_payload[response as usize][payload as usize].fetch_add(
+            1, Relaxed
+        );
+    }
 }
 
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn drain() {
+        struct Flag(AtomicBool);
+
+        impl futures_util::task::ArcWake for Flag {
+            fn wake_by_ref(arc: &Arc<Self>) {
+                arc.0.store(true, SeqCst)
+            }
+        }
+
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = futures_util::task::waker(flag.clone());
+        let drain = Drain::default();
+        let key = drain.register(None, &waker);
+        assert_eq!(drain.register(Some(key), &waker), key);
+        assert!(!drain.is_draining());
+        assert!(!drain.is_closing());
+
+        drain.start();
+        assert!(drain.is_draining());
+        assert!(!drain.is_closing());
+        assert!(flag.0.swap(false, SeqCst));
+
+        drain.unregister(key);
+        drain.close();
+        assert!(drain.is_closing());
+        assert!(!flag.0.load(SeqCst));
+    }
+
+    #[test]
+    fn notify_throttle() {
+        let metrics = ListenerMetrics::new(false, Default::default());
+
+        // Without an interval, all notifies are sent right away.
+        let mut throttle = NotifyThrottle::new(Duration::ZERO);
+        assert!(throttle.request(&metrics));
+        throttle.sent();
+        assert!(throttle.request(&metrics));
+        throttle.sent();
+
+        // With an interval, later notifies are delayed and coalesced.
+        let mut throttle = NotifyThrottle::new(Duration::from_secs(3600));
+        assert!(throttle.request(&metrics));
+        throttle.sent();
+        assert!(!throttle.request(&metrics));
+        assert!(throttle.pending);
+        assert!(!throttle.request(&metrics));
+        assert!(!throttle.request(&metrics));
+        assert_eq!(metrics.notifies_suppressed.load(Relaxed), 2);
+        throttle.sent();
+        assert!(!throttle.pending);
+    }
+
+    #[test]
+    fn compressed_history() {
+        use crate::payload::testrig;
+
+        let metrics = ListenerMetrics::new(false, Default::default());
+        let initial = InitialState {
+            state: State::new(), digest: None, data: None
+        };
+        let plain = Source::new(3, false, Timing::default(), initial.clone());
+        let compressed = Source::new(3, true, Timing::default(), initial);
+        for values in [
+            [1, 2, 3], [2, 3, 4], [3, 4, 5], [4, 5, 6], [5, 6, 7]
+        ] {
+            let update = UnitUpdate::Payload(payload::Update::new(
+                payload::Set::from(testrig::pack(values))
+            ));
+            assert!(plain.update(update.clone(), &metrics));
+            assert!(compressed.update(update, &metrics));
+        }
+
+        let plain = plain.data.load();
+        let compressed = compressed.data.load();
+        assert_eq!(compressed.diffs.len(), 3);
+        assert!(matches!(compressed.diffs[0].1, HistoryDiff::Plain(_)));
+        assert!(matches!(compressed.diffs[2].1, HistoryDiff::Compressed(_)));
+        for (left, right) in plain.diffs.iter().zip(compressed.diffs.iter()) {
+            assert_eq!(left.0, right.0);
+            assert_eq!(left.1.to_diff(), right.1.to_diff());
+        }
+    }
+
+    #[test]
+    fn stored_state() {
+        use crate::payload::testrig;
+
+        let metrics = ListenerMetrics::new(false, Default::default());
+        let source = Source::new(
+            3, false, Timing::default(),
+            InitialState { state: State::new(), digest: None, data: None }
+        );
+        let set = payload::Set::from(testrig::pack([1, 2, 3]));
+        assert!(source.update(
+            UnitUpdate::Payload(payload::Update::new(set.clone())), &metrics
+        ));
+
+        // Without the data set, the target waits for the first update.
+        let stored = source.stored_state(false);
+        assert!(stored.current.is_none());
+        let initial = stored.decode("test");
+        assert_eq!(initial.digest, Some(set.digest()));
+        assert!(initial.data.is_none());
+
+        // With the data set, the target resumes serving it right away.
+        let stored: StoredState = serde_json::from_slice(
+            &serde_json::to_vec(&source.stored_state(true)).unwrap()
+        ).unwrap();
+        let initial = stored.decode("test");
+        let state = source.data.load().state;
+        assert_eq!(initial.state.session(), state.session());
+        assert_eq!(initial.state.serial(), state.serial());
+        assert!(initial.digest.is_none());
+        let (current, diffs) = initial.data.unwrap();
+        assert_eq!(current, set);
+        assert!(diffs.is_empty());
+    }
+
+    #[test]
+    fn connection_limits() {
+        let a = IpAddr::from([192, 0, 2, 1]);
+        let b = IpAddr::from([192, 0, 2, 2]);
+        let limits = Arc::new(ConnectionLimits::new(Some(3), Some(2)));
+        let a1 = limits.acquire(a).unwrap();
+        let _a2 = limits.acquire(a).unwrap();
+        assert_eq!(limits.acquire(a).unwrap_err(), ConnectionLimit::PerIp);
+        let _b1 = limits.acquire(b).unwrap();
+        assert_eq!(limits.acquire(b).unwrap_err(), ConnectionLimit::Total);
+
+        // Dropping a slot frees it up again.
+        drop(a1);
+        let _b2 = limits.acquire(b).unwrap();
+        assert_eq!(limits.acquire(a).unwrap_err(), ConnectionLimit::Total);
+
+        // No limits means no limits.
+        let limits = Arc::new(ConnectionLimits::new(None, None));
+        let slots = (0..10).map(|_| {
+            limits.acquire(a).unwrap()
+        }).collect::<Vec<_>>();
+        assert_eq!(limits.open.lock().unwrap().0, 10);
+        drop(slots);
+        assert_eq!(limits.open.lock().unwrap().0, 0);
+        assert!(limits.open.lock().unwrap().1.is_empty());
+    }
+
+    #[test]
+    fn rate_limits() {
+        let a = IpAddr::from([192, 0, 2, 1]);
+        let b = IpAddr::from([192, 0, 2, 2]);
+        let start = Instant::now();
+        let rate = RateLimit::new(RateConfig { rate: 2., burst: Some(3) });
+        assert!(rate.try_acquire(a, start));
+        assert!(rate.try_acquire(a, start));
+        assert!(rate.try_acquire(a, start));
+        assert!(!rate.try_acquire(a, start));
+        assert!(rate.try_acquire(b, start));
+
+        // Tokens are refilled at the rate but never above the burst.
+        let later = start + Duration::from_millis(500);
+        assert!(rate.try_acquire(a, later));
+        assert!(!rate.try_acquire(a, later));
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(rate.try_acquire(a, later));
+        }
+        assert!(!rate.try_acquire(a, later));
+
+        // Reserving takes tokens in advance and returns the delay.
+        let rate = RateLimit::new(RateConfig { rate: 2., burst: None });
+        assert_eq!(rate.reserve(a, start), Duration::ZERO);
+        assert_eq!(rate.reserve(a, start), Duration::ZERO);
+        assert_eq!(rate.reserve(a, start), Duration::from_millis(500));
+        assert_eq!(rate.reserve(a, start), Duration::from_secs(1));
+
+        assert!(RateConfig { rate: 0.5, burst: None }.is_valid());
+        assert_eq!(RateConfig { rate: 0.5, burst: None }.burst(), 1.);
+        assert!(!RateConfig { rate: 0., burst: None }.is_valid());
+        assert!(!RateConfig { rate: 1., burst: Some(0) }.is_valid());
+    }
+
+    #[test]
+    fn client_classes() {
+        let config: HashMap<String, ClientClassConfig> = toml::from_str(r#"
+            [wide]
+            prefixes = [ "192.0.2.0/24", "2001:db8::/32" ]
+            refresh = 60
+
+            [narrow]
+            prefixes = [ "192.0.2.128/25" ]
+            retry = 30
+        "#).unwrap();
+        let classes = ClientClasses::new(&config);
+        let name = |addr: &str| {
+            classes.get(addr.parse().unwrap()).map(|class| {
+                String::from(class.name.as_ref())
+            })
+        };
+        assert_eq!(name("192.0.2.1").as_deref(), Some("wide"));
+        assert_eq!(name("192.0.2.129").as_deref(), Some("narrow"));
+        assert_eq!(name("2001:db8::1").as_deref(), Some("wide"));
+        assert_eq!(name("198.51.100.1"), None);
+        assert_eq!(name("2001:db9::1"), None);
+    }
+
+    #[tokio::test]
+    async fn upstreams() {
+        use tokio::sync::mpsc;
+        use tokio::time::timeout;
+        use crate::comms::Gate;
+        use crate::payload::testrig;
+
+        /// Spawns a unit producing the updates sent via the channel.
+        fn unit() -> (Link, mpsc::Sender<UnitUpdate>) {
+            let (mut gate, mut agent) = Gate::new();
+            let link = agent.create_link();
+            let (tx, mut rx) = mpsc::channel(8);
+            tokio::spawn(async move {
+                let _agent = agent;
+                while let Ok(Some(update)) = gate.process_until(
+                    rx.recv()
+                ).await {
+                    gate.update(update).await;
+                }
+            });
+            (link, tx)
+        }
+
+        fn update<const N: usize>(values: [u32; N]) -> UnitUpdate {
+            UnitUpdate::Payload(payload::Update::new(
+                testrig::pack(values).into()
+            ))
+        }
+
+        fn set<const N: usize>(values: [u32; N]) -> payload::Set {
+            testrig::pack(values).into()
+        }
+
+        async fn query(
+            upstreams: &mut Upstreams
+        ) -> Option<(payload::Set, bool)> {
+            let res = timeout(
+                Duration::from_millis(100), upstreams.query("test")
+            ).await.ok()?;
+            match res {
+                (UnitUpdate::Payload(update), switched) => {
+                    Some((update.set().clone(), switched))
+                }
+                (update, _) => panic!("unexpected update {:?}", update),
+            }
+        }
+
+        let (link_a, tx_a) = unit();
+        let (link_b, tx_b) = unit();
+        let mut upstreams = Upstreams::new(vec![link_a, link_b]);
+
+        // The first unit with data is used.
+        tx_a.send(update([1, 2])).await.unwrap();
+        assert_eq!(query(&mut upstreams).await, Some((set([1, 2]), false)));
+
+        // Updates of the other unit are not served. Since the query is
+        // cancelled, this also checks that the update isn’t lost.
+        tx_b.send(update([3, 4])).await.unwrap();
+        assert_eq!(query(&mut upstreams).await, None);
+
+        // If the preferred unit stalls, the target switches to the next
+        // one and serves its last update.
+        tx_a.send(UnitUpdate::Stalled).await.unwrap();
+        assert_eq!(query(&mut upstreams).await, Some((set([3, 4]), true)));
+        tx_b.send(update([5])).await.unwrap();
+        assert_eq!(query(&mut upstreams).await, Some((set([5]), false)));
+
+        // The target switches back once the preferred unit has data again.
+        tx_a.send(update([6])).await.unwrap();
+        assert_eq!(query(&mut upstreams).await, Some((set([6]), true)));
+        tx_b.send(update([7])).await.unwrap();
+        assert_eq!(query(&mut upstreams).await, None);
+        tx_a.send(update([8])).await.unwrap();
+        assert_eq!(query(&mut upstreams).await, Some((set([8]), false)));
+
+        // The update of the other unit received while it wasn’t used is
+        // still there.
+        tx_a.send(UnitUpdate::Stalled).await.unwrap();
+        assert_eq!(query(&mut upstreams).await, Some((set([7]), true)));
+    }
+}
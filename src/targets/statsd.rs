@@ -0,0 +1,370 @@
+//! A target pushing statistics of the data set to a statsd server.
+//!
+//! The _statsd_ target doesn’t serve the data set itself. Instead, it
+//! periodically sends a few aggregate numbers describing the data set of
+//! its unit as gauges to a statsd server via UDP: the number of route
+//! origins per address family, the number of router keys and ASPA records,
+//! and, if known, the number of items per trust anchor.
+//!
+//! This is meant for setups where the metrics endpoint of RTRTR can’t be
+//! scraped directly but statistics are collected via statsd instead.
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+use daemonbase::error::ExitError;
+use log::{debug, error};
+use rpki::rtr::payload::Payload;
+use serde::Deserialize;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time::{interval, MissedTickBehavior};
+use crate::{metrics, payload};
+use crate::comms::{Link, UnitUpdate};
+use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+
+
+//------------ Configuration -------------------------------------------------
+
+/// The maximum size of a UDP datagram sent to the server.
+///
+/// This is the value commonly recommended for statsd to avoid
+/// fragmentation on Ethernet networks.
+const MAX_DATAGRAM_LEN: usize = 1432;
+
+
+//------------ Target --------------------------------------------------------
+
+/// A target pushing statistics to a statsd server.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    /// The unit whose data set should be described.
+    unit: Link,
+
+    /// The address of the statsd server as host and port.
+    server: String,
+
+    /// The prefix for the names of the gauges.
+    #[serde(default = "Target::default_prefix")]
+    prefix: String,
+
+    /// The number of seconds between pushes.
+    #[serde(default = "Target::default_interval")]
+    interval: u64,
+}
+
+impl Target {
+    /// Converts the target into the links to its units.
+    pub fn into_links(self) -> Vec<Link> {
+        vec![self.unit]
+    }
+
+    /// The default for the `prefix` value.
+    fn default_prefix() -> String {
+        "rtrtr".into()
+    }
+
+    /// The default for the `interval` value.
+    fn default_interval() -> u64 {
+        60
+    }
+
+    /// Runs the target.
+    pub async fn run(
+        mut self, mut component: Component
+    ) -> Result<(), ExitError> {
+        if self.interval == 0 {
+            error!("Target {}: interval must not be zero.", component.name());
+            return Err(ExitError::default())
+        }
+        let metrics = Arc::new(StatsdMetrics::default());
+        component.register_metrics(metrics.clone());
+        let prefix = format!(
+            "{}.{}", self.prefix, sanitize(component.name())
+        );
+        let mut stats: Option<Stats> = None;
+        let mut ticker = interval(Duration::from_secs(self.interval));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        // There is nothing to set up, so we have started. We only become
+        // ready once we have pushed the statistics.
+        component.set_started();
+
+        loop {
+            tokio::select! {
+                update = self.unit.query() => {
+                    if let UnitUpdate::Payload(update) = update {
+                        debug!(
+                            "Target {}: Got update ({} entries) via {}",
+                            component.name(), update.set().len(),
+                            update.provenance()
+                        );
+                        stats = Some(Stats::new(&update));
+                    }
+                }
+                _ = ticker.tick() => {
+                    if let Some(stats) = stats.as_ref() {
+                        self.push(
+                            stats, &prefix, &component, &metrics
+                        ).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Pushes the statistics to the server.
+    ///
+    /// Failures are logged and recorded in the metrics.
+    async fn push(
+        &self,
+        stats: &Stats,
+        prefix: &str,
+        component: &Component,
+        metrics: &StatsdMetrics,
+    ) {
+        match self.send(&stats.datagrams(prefix)).await {
+            Ok(()) => {
+                debug!(
+                    "Target {}: pushed statistics to {}.",
+                    component.name(), self.server
+                );
+                metrics.pushed.fetch_add(1, Relaxed);
+                component.set_ready(true);
+            }
+            Err(err) => {
+                error!(
+                    "Target {}: failed to push statistics to {}: {}",
+                    component.name(), self.server, err
+                );
+                metrics.failed.fetch_add(1, Relaxed);
+            }
+        }
+    }
+
+    /// Sends the datagrams to the server.
+    async fn send(&self, datagrams: &[String]) -> Result<(), io::Error> {
+        let addr = lookup_host(&self.server).await?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} doesn’t resolve to any address", self.server)
+            )
+        })?;
+        let local = if addr.is_ipv4() {
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        }
+        else {
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        };
+        let sock = UdpSocket::bind(local).await?;
+        for datagram in datagrams {
+            sock.send_to(datagram.as_bytes(), addr).await?;
+        }
+        Ok(())
+    }
+}
+
+
+//------------ Stats ---------------------------------------------------------
+
+/// The statistics of a data set.
+#[derive(Clone, Debug, Default)]
+struct Stats {
+    /// The number of IPv4 route origins.
+    ipv4_origins: usize,
+
+    /// The number of IPv6 route origins.
+    ipv6_origins: usize,
+
+    /// The number of router keys.
+    router_keys: usize,
+
+    /// The number of ASPA records.
+    aspas: usize,
+
+    /// The number of items per trust anchor if known.
+    trust_anchors: Vec<(String, usize)>,
+}
+
+impl Stats {
+    /// Determines the statistics of an update.
+    fn new(update: &payload::Update) -> Self {
+        let mut res = Stats::default();
+        for payload in update.set().iter() {
+            match payload {
+                Payload::Origin(origin) => {
+                    if origin.prefix.addr().is_ipv4() {
+                        res.ipv4_origins += 1
+                    }
+                    else {
+                        res.ipv6_origins += 1
+                    }
+                }
+                Payload::RouterKey(_) => res.router_keys += 1,
+                Payload::Aspa(_) => res.aspas += 1,
+            }
+        }
+        if let Some(trust_anchors) = update.trust_anchors() {
+            res.trust_anchors = trust_anchors.counts(
+                update.set()
+            ).into_iter().map(|(name, count)| {
+                (sanitize(name), count)
+            }).collect();
+        }
+        res
+    }
+
+    /// Returns the gauges as lines in statsd format.
+    fn lines(&self, prefix: &str) -> Vec<String> {
+        let mut res = vec![
+            format!("{}.origins.ipv4:{}|g", prefix, self.ipv4_origins),
+            format!("{}.origins.ipv6:{}|g", prefix, self.ipv6_origins),
+            format!("{}.router_keys:{}|g", prefix, self.router_keys),
+            format!("{}.aspas:{}|g", prefix, self.aspas),
+        ];
+        for (name, count) in &self.trust_anchors {
+            res.push(format!("{}.trust_anchors.{}:{}|g", prefix, name, count))
+        }
+        res
+    }
+
+    /// Returns the gauges combined into datagrams.
+    ///
+    /// Lines are separated by line feeds and each datagram is at most
+    /// [`MAX_DATAGRAM_LEN`] bytes long unless a single line is longer.
+    fn datagrams(&self, prefix: &str) -> Vec<String> {
+        let mut res = Vec::new();
+        let mut current = String::new();
+        for line in self.lines(prefix) {
+            if
+                !current.is_empty()
+                && current.len() + line.len() + 1 > MAX_DATAGRAM_LEN
+            {
+                res.push(std::mem::take(&mut current));
+            }
+            if !current.is_empty() {
+                current.push('\n');
+            }
+            current.push_str(&line);
+        }
+        if !current.is_empty() {
+            res.push(current);
+        }
+        res
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Converts a name into something usable as part of a statsd name.
+///
+/// All characters other than ASCII letters, digits, `-`, and `_` are
+/// replaced with an underscore.
+fn sanitize(name: &str) -> String {
+    name.chars().map(|ch| {
+        if ch.is_ascii_alphanumeric() || ch == '-' || ch == '_' {
+            ch
+        }
+        else {
+            '_'
+        }
+    }).collect()
+}
+
+
+//------------ StatsdMetrics -------------------------------------------------
+
+/// The metrics of a statsd target.
+#[derive(Debug, Default)]
+struct StatsdMetrics {
+    /// The number of times statistics have been pushed.
+    pushed: AtomicU64,
+
+    /// The number of failed attempts to push statistics.
+    failed: AtomicU64,
+}
+
+impl StatsdMetrics {
+    const PUSHED_METRIC: Metric = Metric::new(
+        "statsd_target_pushes",
+        "number of times statistics have been pushed",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const FAILED_METRIC: Metric = Metric::new(
+        "statsd_target_push_failures",
+        "number of failed attempts to push statistics",
+        MetricType::Counter, MetricUnit::Total
+    );
+}
+
+impl metrics::Source for StatsdMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::PUSHED_METRIC, Some(unit_name),
+            self.pushed.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::FAILED_METRIC, Some(unit_name),
+            self.failed.load(Relaxed)
+        );
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::payload::testrig;
+
+    #[test]
+    fn stats() {
+        let update = payload::Update::new(testrig::pack([1, 2, 3]).into());
+        let stats = Stats::new(&update);
+        assert_eq!(stats.ipv4_origins, 3);
+        assert_eq!(stats.ipv6_origins, 0);
+        assert!(stats.trust_anchors.is_empty());
+        assert_eq!(
+            stats.lines("rtrtr.stats"),
+            [
+                "rtrtr.stats.origins.ipv4:3|g",
+                "rtrtr.stats.origins.ipv6:0|g",
+                "rtrtr.stats.router_keys:0|g",
+                "rtrtr.stats.aspas:0|g",
+            ]
+        );
+        assert_eq!(
+            stats.datagrams("rtrtr.stats"),
+            [stats.lines("rtrtr.stats").join("\n")]
+        );
+    }
+
+    #[test]
+    fn datagrams() {
+        let stats = Stats {
+            trust_anchors: (0..100).map(|idx| {
+                (format!("ta-{}", idx), idx)
+            }).collect(),
+            ..Default::default()
+        };
+        let datagrams = stats.datagrams("rtrtr");
+        assert!(datagrams.len() > 1);
+        assert!(datagrams.iter().all(|item| item.len() <= MAX_DATAGRAM_LEN));
+        assert_eq!(
+            datagrams.iter().map(|item| item.lines().count()).sum::<usize>(),
+            104
+        );
+    }
+
+    #[test]
+    fn sanitize_names() {
+        assert_eq!(sanitize("ripe-ncc_2"), "ripe-ncc_2");
+        assert_eq!(sanitize("arin.net/ta"), "arin_net_ta");
+    }
+}
@@ -85,6 +85,10 @@ impl Target {
         )
     }
 
+    pub fn into_links(self) -> Vec<Link> {
+        vec![self.link]
+    }
+
     pub async fn run(
         mut self, _component: Component,
     ) -> Result<(), ExitError> {
@@ -136,6 +140,22 @@ impl TargetController {
             other => Err(format!("expected stalled status, got {:?}", other))
         }
     }
+
+    /// Receives the next payload update, skipping any stalled statuses.
+    ///
+    /// Use this when a unit may legitimately report being stalled while
+    /// waiting for its sources before it produces data.
+    pub async fn next_payload(&mut self) -> Result<payload::Update, String> {
+        loop {
+            match self.recv().await? {
+                UnitUpdate::Payload(payload) => return Ok(payload),
+                UnitUpdate::Stalled => { }
+                other => {
+                    return Err(format!("expected payload, got {:?}", other))
+                }
+            }
+        }
+    }
 }
 
 
@@ -173,3 +193,242 @@ async fn simple_comms() {
 }
 
 
+
+/// The path of the SLURM file with the router key exceptions.
+const ROUTER_KEY_EXCEPTIONS: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/test-data/router-keys-exceptions.slurm.json"
+);
+
+/// Returns the input and expected output of the router key tests.
+///
+/// The input contains route origins and router keys for AS64496 and
+/// AS64497. The exceptions drop the keys of AS64496 and add a key for
+/// AS64498.
+fn router_key_sets() -> (payload::Set, payload::Set) {
+    use rpki::rtr::payload::Payload;
+    use crate::payload::{Set, testrig};
+
+    let input = Set::from(testrig::slurm_pack(
+        include_bytes!("../test-data/router-keys.slurm.json")
+    ));
+    let expected = input.filter(|item| {
+        !matches!(item, Payload::RouterKey(key) if key.asn == 64496.into())
+    }).merge(&Set::from(testrig::slurm_pack(
+        include_bytes!("../test-data/router-keys-exceptions.slurm.json")
+    )));
+    assert_eq!(
+        expected.iter().filter(|item| {
+            matches!(item, Payload::RouterKey(_))
+        }).count(),
+        2
+    );
+    (input, expected)
+}
+
+/// Returns the configuration of an RTR target listening on any free port.
+fn rtr_target(unit: &str) -> targets::Target {
+    toml::from_str(&format!(r#"
+        type = "rtr"
+        listen = [ "127.0.0.1:0" ]
+        unit = "{}"
+    "#, unit)).unwrap()
+}
+
+/// Returns the configuration of an RTR unit connecting to a target.
+fn rtr_unit(remote: std::net::SocketAddr) -> units::Unit {
+    toml::from_str(&format!(r#"
+        type = "rtr"
+        remote = "{}"
+        retry = 1
+    "#, remote)).unwrap()
+}
+
+/// Waits for an RTR target to bind its listener and returns its address.
+async fn listen_addr(
+    manager: &crate::manager::Manager, target: &str
+) -> std::net::SocketAddr {
+    use std::time::Duration;
+    use tokio::time::{sleep, timeout};
+
+    timeout(Duration::from_secs(10), async {
+        loop {
+            if let Some(addr) = manager.listen_addrs(target).first() {
+                return *addr
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    }).await.expect("target didn’t start listening")
+}
+
+/// Adds an RTR unit for the given target and a test target for that unit.
+async fn check_target(
+    manager: &mut crate::manager::Manager, target: &str
+) -> TargetController {
+    let remote = listen_addr(manager, target).await;
+    manager.add_components(
+        &tokio::runtime::Handle::current(),
+        |units, targets| {
+            units.insert("check", rtr_unit(remote));
+            let (t, tc) = Target::new("check");
+            targets.insert("t", t);
+            tc
+        }
+    ).unwrap()
+}
+
+/// Waits for the test target to receive the expected data set.
+async fn recv_set(
+    t: &mut TargetController, expected: &payload::Set
+) -> Result<(), tokio::time::error::Elapsed> {
+    use std::time::Duration;
+
+    tokio::time::timeout(Duration::from_secs(60), async {
+        loop {
+            if let UnitUpdate::Payload(update) = t.recv().await.unwrap() {
+                if update.set() == expected {
+                    break
+                }
+            }
+        }
+    }).await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn router_keys_end_to_end() {
+    use tokio::runtime;
+    use crate::manager::Manager;
+
+    let (input, expected) = router_key_sets();
+
+    // The data travels from the test unit via an RTR target to an RTR unit,
+    // through a SLURM and a merge unit, and via another pair of RTR target
+    // and unit to the test target.
+    let mut manager = Manager::default();
+    let u = manager.add_components(
+        &runtime::Handle::current(),
+        |units, targets| {
+            let (u, uc) = Unit::new();
+            units.insert("source", u);
+            targets.insert("upstream", rtr_target("source"));
+            uc
+        }
+    ).unwrap();
+    let upstream = listen_addr(&manager, "upstream").await;
+    manager.add_components(
+        &runtime::Handle::current(),
+        |units, targets| {
+            units.insert("client", rtr_unit(upstream));
+            units.insert("exceptions", toml::from_str(&format!(r#"
+                type = "slurm"
+                source = "client"
+                files = [ {:?} ]
+            "#, ROUTER_KEY_EXCEPTIONS)).unwrap());
+            units.insert("merge", toml::from_str(r#"
+                type = "merge"
+                sources = [ "exceptions" ]
+            "#).unwrap());
+            targets.insert("downstream", rtr_target("merge"));
+        }
+    ).unwrap();
+    let mut t = check_target(&mut manager, "downstream").await;
+
+    u.send_payload(payload::Update::new(input)).await;
+    recv_set(&mut t, &expected).await.expect(
+        "router keys didn’t arrive intact"
+    );
+}
+
+#[cfg(feature = "unit-json")]
+#[tokio::test(flavor = "multi_thread")]
+async fn json_router_keys_end_to_end() {
+    use std::fs;
+    use tokio::runtime;
+    use crate::formats::output::Format;
+    use crate::manager::Manager;
+
+    let (input, expected) = router_key_sets();
+
+    // The source data is written as JSON and read by a JSON unit.
+    let dir = std::env::temp_dir().join(format!(
+        "rtrtr-json-router-keys-{}", std::process::id()
+    ));
+    fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("vrps.json");
+    fs::write(
+        &source,
+        Format::Json.stream(
+            input, Default::default(), None
+        ).flatten().collect::<Vec<_>>()
+    ).unwrap();
+
+    // The data travels from the JSON unit through a SLURM unit and via an
+    // RTR target and unit to the test target.
+    let mut manager = Manager::default();
+    manager.add_components(
+        &runtime::Handle::current(),
+        |units, targets| {
+            units.insert("source", toml::from_str(&format!(r#"
+                type = "json"
+                uri = "file:{}"
+                refresh = 1
+            "#, source.display())).unwrap());
+            units.insert("exceptions", toml::from_str(&format!(r#"
+                type = "slurm"
+                source = "source"
+                files = [ {:?} ]
+            "#, ROUTER_KEY_EXCEPTIONS)).unwrap());
+            targets.insert("rtr", rtr_target("exceptions"));
+        }
+    ).unwrap();
+    let mut t = check_target(&mut manager, "rtr").await;
+
+    let res = recv_set(&mut t, &expected).await;
+    let _ = fs::remove_dir_all(&dir);
+    res.expect("router keys didn’t arrive intact");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rtr_session_id() {
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::runtime;
+    use tokio::time::{sleep, timeout};
+    use crate::manager::Manager;
+    use crate::payload::testrig;
+
+    let mut manager = Manager::default();
+    let u = manager.add_components(
+        &runtime::Handle::current(),
+        |units, targets| {
+            let (u, uc) = Unit::new();
+            units.insert("source", u);
+            targets.insert("rtr", toml::from_str(r#"
+                type = "rtr"
+                listen = [ "127.0.0.1:0" ]
+                unit = "source"
+                session-id = 4711
+            "#).unwrap());
+            uc
+        }
+    ).unwrap();
+    u.send_payload(testrig::update([1, 2])).await;
+    let addr = listen_addr(&manager, "rtr").await;
+
+    // Send a Reset Query until the target has data and answers with a
+    // Cache Response carrying the configured session ID.
+    let header = timeout(Duration::from_secs(10), async {
+        loop {
+            let mut sock = TcpStream::connect(addr).await.unwrap();
+            sock.write_all(&[1, 2, 0, 0, 0, 0, 0, 8]).await.unwrap();
+            let mut header = [0u8; 8];
+            sock.read_exact(&mut header).await.unwrap();
+            if header[1] == 3 {
+                return header
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    }).await.expect("target didn’t answer with a Cache Response");
+    assert_eq!(u16::from_be_bytes([header[2], header[3]]), 4711);
+}
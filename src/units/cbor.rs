@@ -0,0 +1,268 @@
+//! Fetching data sets in CBOR format.
+//!
+//! The _cbor_ unit regularly fetches the data set of another RTRTR instance
+//! from an HTTP target using the `cbor` format. Along with each request it
+//! sends the entity tag of the last response so that the server can
+//! respond with 304 Not Modified if nothing has changed. If the server
+//! sends a data set with the same session and serial number as the current
+//! one anyway, it is not passed on either.
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+use crossbeam_utils::atomic::AtomicCell;
+use daemonbase::error::Failed;
+use log::{debug, error, warn};
+use reqwest::{StatusCode, Url};
+use reqwest::header::{ACCEPT, ETAG, HeaderValue, IF_NONE_MATCH};
+use serde::Deserialize;
+use tokio::task::spawn_blocking;
+use tokio::time::{Instant, timeout_at};
+use crate::{metrics, payload};
+use crate::comms::{Gate, GateMetrics, Terminated, UnitUpdate};
+use crate::formats::cbor::Snapshot;
+use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+
+
+//------------ Cbor ----------------------------------------------------------
+
+/// A unit that regularly fetches a CBOR encoded data set.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Cbor {
+    /// The URI of the data set.
+    uri: Url,
+
+    /// How many seconds to wait before refreshing the data.
+    refresh: u64,
+}
+
+impl Cbor {
+    pub async fn run(
+        self, mut component: Component, mut gate: Gate
+    ) -> Result<(), Terminated> {
+        let metrics = Arc::new(CborMetrics::new(&gate));
+        component.register_metrics(metrics.clone());
+        let client = self.http_client(&component)?;
+        let mut current = Current::default();
+        loop {
+            match gate.process_until(
+                self.fetch(&client, &mut current, &component, &metrics)
+            ).await? {
+                Ok(Some(set)) => {
+                    let update = payload::Update::new(set);
+                    if gate.update(UnitUpdate::Payload(update)).await {
+                        debug!(
+                            "Unit {}: successfully updated.",
+                            component.name()
+                        );
+                    }
+                }
+                Ok(None) => {
+                    debug!("Unit {}: no changes.", component.name());
+                }
+                Err(Failed) => {
+                    metrics.failures.fetch_add(1, Relaxed);
+                    if gate.update(UnitUpdate::Stalled).await {
+                        debug!(
+                            "Unit {}: marked as stalled.",
+                            component.name()
+                        );
+                    }
+                }
+            }
+            self.wait(&mut gate).await?;
+        }
+    }
+
+    fn http_client(
+        &self, component: &Component
+    ) -> Result<reqwest::Client, Terminated> {
+        let builder = component.http_client().map_err(|err| {
+            error!("Unit {}: {}", component.name(), err);
+            Terminated
+        })?;
+        let builder = builder.gzip(true).deflate(true).brotli(true);
+        builder.build().map_err(|err| {
+            error!("Unit {}: Failed to initialize HTTP client: {}.",
+                component.name(), err
+            );
+            Terminated
+        })
+    }
+
+    /// Fetches the data set.
+    ///
+    /// Returns the new data set or `None` if it hasn’t changed.
+    async fn fetch(
+        &self,
+        client: &reqwest::Client,
+        current: &mut Current,
+        component: &Component,
+        metrics: &CborMetrics,
+    ) -> Result<Option<payload::Set>, Failed> {
+        let mut request = client.get(self.uri.clone()).header(
+            ACCEPT, "application/cbor"
+        );
+        if let Some(etag) = current.etag.as_ref() {
+            request = request.header(IF_NONE_MATCH, etag.clone());
+        }
+        let response = request.send().await.map_err(|err| {
+            warn!(
+                "Unit {}: HTTP request failed: {}",
+                component.name(), err
+            );
+            Failed
+        })?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            metrics.not_modified.fetch_add(1, Relaxed);
+            return Ok(None)
+        }
+        if response.status() != StatusCode::OK {
+            warn!(
+                "Unit {}: HTTP request return status {}",
+                component.name(), response.status()
+            );
+            return Err(Failed)
+        }
+        let etag = response.headers().get(ETAG).cloned();
+        let body = response.bytes().await.map_err(|err| {
+            warn!(
+                "Unit {}: HTTP request failed: {}",
+                component.name(), err
+            );
+            Failed
+        })?;
+        metrics.bytes.fetch_add(body.len() as u64, Relaxed);
+
+        let snapshot = match spawn_blocking(move || {
+            Snapshot::from_slice(&body)
+        }).await {
+            Ok(Ok(snapshot)) => snapshot,
+            Ok(Err(err)) => {
+                warn!(
+                    "Unit {}: Failed parsing source: {}",
+                    component.name(), err
+                );
+                return Err(Failed)
+            }
+            Err(_) => {
+                warn!(
+                    "Unit {}: Failed parsing source: parser panicked.",
+                    component.name(),
+                );
+                return Err(Failed)
+            }
+        };
+        current.etag = etag;
+        metrics.serial.store(snapshot.serial());
+        let version = snapshot.session().zip(snapshot.serial());
+        if version.is_some() && version == current.version {
+            return Ok(None)
+        }
+        current.version = version;
+        Ok(Some(snapshot.into_set()))
+    }
+
+    async fn wait(&self, gate: &mut Gate) -> Result<(), Terminated> {
+        let end = Instant::now() + Duration::from_secs(self.refresh);
+        while end > Instant::now() {
+            match timeout_at(end, gate.process()).await {
+                Ok(Ok(_status)) => { }
+                Ok(Err(_)) => return Err(Terminated),
+                Err(_) => return Ok(()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+//------------ Current -------------------------------------------------------
+
+/// Information about the current data set.
+#[derive(Clone, Debug, Default)]
+struct Current {
+    /// The entity tag of the last response if it had one.
+    etag: Option<HeaderValue>,
+
+    /// The session ID and serial number of the data set if known.
+    version: Option<(u16, u32)>,
+}
+
+
+//------------ CborMetrics ---------------------------------------------------
+
+/// The metrics of a CBOR unit.
+#[derive(Debug, Default)]
+struct CborMetrics {
+    /// The number of times the server reported no changes.
+    not_modified: AtomicU64,
+
+    /// The number of times fetching the data set failed.
+    failures: AtomicU64,
+
+    /// The number of bytes received.
+    bytes: AtomicU64,
+
+    /// The serial number of the current data set.
+    serial: AtomicCell<Option<u32>>,
+
+    /// The gate metrics.
+    gate: Arc<GateMetrics>,
+}
+
+impl CborMetrics {
+    const NOT_MODIFIED_METRIC: Metric = Metric::new(
+        "cbor_not_modified",
+        "the number of times the server reported no changes",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const FAILURES_METRIC: Metric = Metric::new(
+        "cbor_failures",
+        "the number of times fetching the data set failed",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const BYTES_METRIC: Metric = Metric::new(
+        "cbor_received",
+        "the number of bytes received from the source",
+        MetricType::Counter, MetricUnit::Byte
+    );
+    const SERIAL_METRIC: Metric = Metric::new(
+        "cbor_serial",
+        "the serial number of the current data set",
+        MetricType::Gauge, MetricUnit::Info
+    );
+
+    fn new(gate: &Gate) -> Self {
+        CborMetrics {
+            gate: gate.metrics(),
+            ..Default::default()
+        }
+    }
+}
+
+impl metrics::Source for CborMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::NOT_MODIFIED_METRIC, Some(unit_name),
+            self.not_modified.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::FAILURES_METRIC, Some(unit_name),
+            self.failures.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::BYTES_METRIC, Some(unit_name), self.bytes.load(Relaxed)
+        );
+        if let Some(serial) = self.serial.load() {
+            target.append_simple(
+                &Self::SERIAL_METRIC, Some(unit_name), serial
+            );
+        }
+        self.gate.append(unit_name, target);
+    }
+}
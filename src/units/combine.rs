@@ -1,16 +1,21 @@
 //! Units that combine the updates from other units.
 
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
 use crossbeam_utils::atomic::AtomicCell;
 use futures_util::future::{select, select_all, Either, FutureExt};
-use log::debug;
+use log::{debug, error, info};
 use rand::{thread_rng, Rng};
 use serde::Deserialize;
+use tokio::time::{Instant, timeout_at};
 use crate::{metrics, payload};
 use crate::metrics::{Metric, MetricType, MetricUnit};
 use crate::comms::{
     Gate, GateMetrics, Link, Terminated, UnitHealth, UnitUpdate
 };
+use crate::events::Event;
 use crate::manager::Component;
 
 
@@ -24,9 +29,37 @@ pub struct Any {
 
     /// Whether to pick randomly from the sources.
     random: bool,
+
+    /// Whether to always try the sources in the configured order.
+    ///
+    /// If this is `true`, the source picked only depends on the state of
+    /// the sources and not on which source was used before.
+    #[serde(default)]
+    deterministic: bool,
+
+    /// The maximum difference to the current data for switching sources.
+    ///
+    /// This is the number of items that differ between the data set of
+    /// the new source and the data set currently served relative to the
+    /// size of the latter. If this is `None`, any source is acceptable.
+    #[serde(rename = "max-difference")]
+    max_difference: Option<f64>,
+
+    /// The number of seconds to wait for an acceptable source.
+    ///
+    /// If no source within the maximum difference becomes available for
+    /// this long, any source is acceptable again.
+    #[serde(default = "Any::default_max_difference_timeout")]
+    #[serde(rename = "max-difference-timeout")]
+    max_difference_timeout: u64,
 }
 
 impl Any {
+    /// The default for the `max_difference_timeout` value.
+    fn default_max_difference_timeout() -> u64 {
+        600
+    }
+
     pub async fn run(
         mut self, mut component: Component, mut gate: Gate
     ) -> Result<(), Terminated> {
@@ -34,20 +67,89 @@ impl Any {
             gate.update(UnitUpdate::Gone).await;
             return Err(Terminated)
         }
+        if self.random && self.deterministic {
+            error!(
+                "Unit {}: 'random' and 'deterministic' can’t both be set.",
+                component.name()
+            );
+            gate.update(UnitUpdate::Gone).await;
+            return Err(Terminated)
+        }
+        if let Some(max) = self.max_difference {
+            if !max.is_finite() || max < 0. {
+                error!(
+                    "Unit {}: max-difference must be a non-negative number.",
+                    component.name()
+                );
+                gate.update(UnitUpdate::Gone).await;
+                return Err(Terminated)
+            }
+        }
         let metrics = Arc::new(AnyMetrics::new(&gate));
         component.register_metrics(metrics.clone());
 
         let mut curr_idx: Option<usize> = None;
 
+        // The data set we are currently serving.
+        let mut served: Option<payload::Set> = None;
+
+        // Since when we are refusing sources that differ too much.
+        let mut refusing_since: Option<Instant> = None;
+
+        let timeout = Duration::from_secs(self.max_difference_timeout);
+
         // Outer loop picks a new source.
         loop {
-            curr_idx = match self.pick(curr_idx) {
+            let accept_any = refusing_since.map(|since| {
+                since.elapsed() >= timeout
+            }).unwrap_or(false);
+            let mut refused = false;
+            curr_idx = match self.pick(curr_idx, |idx, update| {
+                let ok = self.acceptable(
+                    component.name(), idx, update, served.as_ref(),
+                    accept_any
+                );
+                if !ok {
+                    refused = true;
+                }
+                ok
+            }) {
                 Ok(curr_idx) => curr_idx,
                 Err(_) => {
                     gate.update(UnitUpdate::Gone).await;
                     return Err(Terminated)
                 }
             };
+            if refused {
+                metrics.refused.fetch_add(1, Relaxed);
+                component.notifier().notify(Event::GuardTriggered {
+                    component: component.name().to_string(),
+                    guard: "max-difference",
+                    detail: match curr_idx {
+                        Some(idx) => format!(
+                            "switched to source {} instead", idx
+                        ),
+                        None => "no acceptable source available".into(),
+                    },
+                });
+            }
+            if curr_idx.is_some() {
+                refusing_since = None;
+            }
+            else if refused && refusing_since.is_none() {
+                refusing_since = Some(Instant::now());
+            }
+
+            // If we are waiting for an acceptable source, we need to wake
+            // up in time to accept any source.
+            let deadline = match curr_idx {
+                Some(_) => None,
+                None => {
+                    refusing_since.and_then(|since| {
+                        since.checked_add(timeout)
+                    })
+                }
+            };
             debug!(
                 "Unit {}: current index is now {:?}",
                 component.name(), curr_idx
@@ -56,6 +158,7 @@ impl Any {
             match curr_idx {
                 Some(idx) => {
                     if let Some(update) = self.sources[idx].payload() {
+                        served = Some(update.set().clone());
                         gate.update(
                             UnitUpdate::Payload(update.clone())
                         ).await;
@@ -82,7 +185,17 @@ impl Any {
                             )
                         ),
                         gate.process().boxed()
-                    ).await;
+                    );
+                    let res = match deadline {
+                        Some(deadline) => {
+                            match timeout_at(deadline, res).await {
+                                Ok(res) => res,
+                                // Time to accept any source.
+                                Err(_) => break,
+                            }
+                        }
+                        None => res.await
+                    };
 
                     match res {
                         // The select_all
@@ -99,6 +212,7 @@ impl Any {
                         // If we don’t have an active source, break out of
                         // the loop because we may now have one.
                         if Some(idx) == curr_idx {
+                            served = Some(payload.set().clone());
                             gate.update(UnitUpdate::Payload(payload)).await;
                         }
                         else if curr_idx.is_none() {
@@ -117,17 +231,67 @@ impl Any {
         }
     }
 
+    /// Returns whether the update of a source is acceptable.
+    ///
+    /// An update is acceptable if it doesn’t differ from the currently
+    /// served data set by more than the configured maximum difference or if
+    /// `accept_any` is `true`. The decision is logged.
+    fn acceptable(
+        &self,
+        name: &str,
+        idx: usize,
+        update: &payload::Update,
+        served: Option<&payload::Set>,
+        accept_any: bool,
+    ) -> bool {
+        let (max, served) = match (self.max_difference, served) {
+            (Some(max), Some(served)) => (max, served),
+            _ => return true,
+        };
+        let diff = update.diff_since(served).len();
+        let difference = diff as f64 / served.len().max(1) as f64;
+        if difference <= max {
+            return true
+        }
+        if accept_any {
+            info!(
+                "Unit {}: switching to source {} despite a difference of \
+                 {:.1}% as no closer source became available in time.",
+                name, idx, difference * 100.
+            );
+            true
+        }
+        else {
+            info!(
+                "Unit {}: not switching to source {}: difference of {:.1}% \
+                 exceeds the maximum of {:.1}%.",
+                name, idx, difference * 100., max * 100.
+            );
+            false
+        }
+    }
+
     /// Pick the next healthy source.
     ///
+    /// Only sources whose update is accepted by the `accept` closure are
+    /// considered.
+    ///
     /// This will return `None`, if no healthy source is currently available.
     /// It will error out if all sources have gone.
-    fn pick(&self, curr: Option<usize>) -> Result<Option<usize>, Terminated> {
+    fn pick(
+        &self,
+        curr: Option<usize>,
+        mut accept: impl FnMut(usize, &payload::Update) -> bool,
+    ) -> Result<Option<usize>, Terminated> {
         // Here’s what we do in case of random picking: We only pick the next
         // source at random and then loop around. That’s not truly random but
         // deterministic.
         let mut next = if self.random {
             thread_rng().gen_range(0..self.sources.len())
         }
+        else if self.deterministic {
+            0
+        }
         else if let Some(curr) = curr {
             (curr + 1) % self.sources.len()
         }
@@ -138,8 +302,10 @@ impl Any {
         for _ in 0..self.sources.len() {
             match self.sources[next].health() {
                 UnitHealth::Healthy => {
-                    if self.sources[next].payload().is_some() {
-                        return Ok(Some(next))
+                    if let Some(update) = self.sources[next].payload() {
+                        if accept(next, update) {
+                            return Ok(Some(next))
+                        }
                     }
                     only_gone = false;
                 }
@@ -165,6 +331,10 @@ impl Any {
 #[derive(Debug, Default)]
 struct AnyMetrics {
     current_index: AtomicCell<Option<usize>>,
+
+    /// The number of times a switch was refused due to the difference.
+    refused: AtomicU64,
+
     gate: Arc<GateMetrics>,
 }
 
@@ -173,12 +343,18 @@ impl AnyMetrics {
         "current_index", "the index of the currenly selected source",
         MetricType::Gauge, MetricUnit::Info
     );
+    const REFUSED_METRIC: Metric = Metric::new(
+        "any_refused_switches",
+        "the number of times switching sources was refused",
+        MetricType::Counter, MetricUnit::Total
+    );
 }
 
 impl AnyMetrics {
     fn new(gate: &Gate) -> Self {
         AnyMetrics {
             current_index: Default::default(),
+            refused: Default::default(),
             gate: gate.metrics(),
         }
     }
@@ -190,6 +366,10 @@ impl metrics::Source for AnyMetrics {
             &Self::CURRENT_INDEX_METRIC, Some(unit_name),
             self.current_index.load().map(|v| v as isize).unwrap_or(-1)
         );
+        target.append_simple(
+            &Self::REFUSED_METRIC, Some(unit_name),
+            self.refused.load(Relaxed)
+        );
         self.gate.append(unit_name, target);
     }
 }
@@ -212,11 +392,13 @@ impl Merge {
             gate.update(UnitUpdate::Gone).await;
             return Err(Terminated)
         }
-        let metrics = gate.metrics();
+        let metrics = Arc::new(
+            MergeMetrics::new(&gate, self.sources.len())
+        );
         component.register_metrics(metrics.clone());
 
         loop {
-            {
+            let trigger = {
                 let res = select(
                     select_all(
                         self.sources.iter_mut().map(|link|
@@ -226,26 +408,175 @@ impl Merge {
                     gate.process().boxed()
                 ).await;
 
-                if let Either::Right(_) = res {
-                    continue
+                match res {
+                    Either::Left(((_, idx, _), _)) => idx,
+                    Either::Right(_) => continue,
                 }
-            }
+            };
 
             let mut output = payload::Set::default();
-            for source in self.sources.iter() {
+            for (source, items) in self.sources.iter().zip(&metrics.sources) {
+                let mut contributed = 0;
                 if matches!(source.health(), UnitHealth::Healthy) {
                     if let Some(update) = source.payload() {
+                        contributed = update.set().len();
                         output = output.merge(update.set())
                     }
                 }
+                items.store(contributed, Relaxed);
             }
-            gate.update(
-                UnitUpdate::Payload(payload::Update::new(output))
-            ).await;
+
+            // The provenance is that of the source that triggered the
+            // update since that is what changed the data.
+            let update = match self.sources[trigger].payload() {
+                Some(update) => update.derive(output),
+                None => payload::Update::new(output),
+            };
+            gate.update(UnitUpdate::Payload(update)).await;
+        }
+    }
+}
+
+
+//------------ Intersect -----------------------------------------------------
+
+/// A unit producing the items present in a number of upstream units.
+#[derive(Debug, Deserialize)]
+pub struct Intersect {
+    /// The set of units whose data sets should be intersected.
+    sources: Vec<Link>,
+
+    /// The number of sources an item needs to be present in.
+    ///
+    /// If this is `None`, an item needs to be present in all sources.
+    quorum: Option<usize>,
+}
+
+impl Intersect {
+    pub async fn run(
+        mut self, mut component: Component, mut gate: Gate
+    ) -> Result<(), Terminated> {
+        let quorum = self.quorum.unwrap_or(self.sources.len());
+        if quorum == 0 || quorum > self.sources.len() {
+            error!(
+                "Unit {}: quorum must be between 1 and the number of \
+                 sources.",
+                component.name()
+            );
+            gate.update(UnitUpdate::Gone).await;
+            return Err(Terminated)
+        }
+        let metrics = Arc::new(MergeMetrics::new(&gate, self.sources.len()));
+        component.register_metrics(metrics.clone());
+
+        let mut stalled = false;
+        loop {
+            let trigger = {
+                let res = select(
+                    select_all(
+                        self.sources.iter_mut().map(|link|
+                            link.query().boxed()
+                        )
+                    ),
+                    gate.process().boxed()
+                ).await;
+
+                match res {
+                    Either::Left(((_, idx, _), _)) => idx,
+                    Either::Right(_) => continue,
+                }
+            };
+
+            let mut sets = Vec::new();
+            for (source, items) in self.sources.iter().zip(&metrics.sources) {
+                let mut contributed = 0;
+                if matches!(source.health(), UnitHealth::Healthy) {
+                    if let Some(update) = source.payload() {
+                        contributed = update.set().len();
+                        sets.push(update.set());
+                    }
+                }
+                items.store(contributed, Relaxed);
+            }
+
+            // Without enough sources, no item can reach the quorum. An
+            // empty set would be wrong, so we are stalled instead.
+            if sets.len() < quorum {
+                if !stalled {
+                    debug!(
+                        "Unit {}: only {} of {} required sources available.",
+                        component.name(), sets.len(), quorum
+                    );
+                    stalled = true;
+                    gate.update(UnitUpdate::Stalled).await;
+                }
+                continue
+            }
+            stalled = false;
+
+            let output = intersect(&sets, quorum);
+            let update = match self.sources[trigger].payload() {
+                Some(update) => update.derive(output),
+                None => payload::Update::new(output),
+            };
+            gate.update(UnitUpdate::Payload(update)).await;
+        }
+    }
+}
+
+/// Returns the items present in at least `quorum` of the given sets.
+fn intersect(sets: &[&payload::Set], quorum: usize) -> payload::Set {
+    let union = sets.iter().fold(payload::Set::default(), |union, set| {
+        union.merge(set)
+    });
+    union.filter(|item| {
+        sets.iter().filter(|set| set.contains(item)).count() >= quorum
+    })
+}
+
+
+//------------ MergeMetrics --------------------------------------------------
+
+/// The metrics of a merge or intersect unit.
+#[derive(Debug)]
+struct MergeMetrics {
+    /// The number of items contributed by each source to the last update.
+    sources: Vec<AtomicUsize>,
+
+    /// The gate metrics.
+    gate: Arc<GateMetrics>,
+}
+
+impl MergeMetrics {
+    const SOURCE_ITEMS_METRIC: Metric = Metric::new(
+        "merge_source_items",
+        "the number of items contributed by a source to the last update",
+        MetricType::Gauge, MetricUnit::Total
+    );
+}
+
+impl MergeMetrics {
+    fn new(gate: &Gate, sources: usize) -> Self {
+        MergeMetrics {
+            sources: (0..sources).map(|_| AtomicUsize::new(0)).collect(),
+            gate: gate.metrics(),
         }
     }
 }
 
+impl metrics::Source for MergeMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append(&Self::SOURCE_ITEMS_METRIC, Some(unit_name), |records| {
+            for (index, items) in self.sources.iter().enumerate() {
+                records.label_value(
+                    &[("source", &index.to_string())], items.load(Relaxed)
+                );
+            }
+        });
+        self.gate.append(unit_name, target);
+    }
+}
+
 
 //============ Tests =========================================================
 
@@ -274,7 +605,10 @@ mod test {
 
                 units.insert("any", units::Unit::Any(Any {
                     sources: vec!["u1".into(), "u2".into(), "u3".into()],
-                    random: false
+                    random: false,
+                    deterministic: false,
+                    max_difference: None,
+                    max_difference_timeout: 600,
                 }));
 
                 let (t, tc) = test::Target::new("any");
@@ -317,5 +651,164 @@ mod test {
         u3.send_payload(testrig::update([3])).await;
         assert_eq!(t.recv_payload().await.unwrap(), testrig::update([3]));
     }
-}
 
+    #[tokio::test]
+    async fn max_difference() {
+        let mut manager = Manager::default();
+
+        let (u1, u2, u3, mut t) = manager.add_components(
+            &runtime::Handle::current(),
+            |units, targets| {
+                let (u, u1c) = test::Unit::new();
+                units.insert("u1", u);
+                let (u, u2c) = test::Unit::new();
+                units.insert("u2", u);
+                let (u, u3c) = test::Unit::new();
+                units.insert("u3", u);
+
+                units.insert("any", units::Unit::Any(Any {
+                    sources: vec!["u1".into(), "u2".into(), "u3".into()],
+                    random: false,
+                    deterministic: false,
+                    max_difference: Some(0.5),
+                    max_difference_timeout: 3600,
+                }));
+
+                let (t, tc) = test::Target::new("any");
+                targets.insert("t", t);
+
+                (u1c, u2c, u3c, tc)
+            }
+        ).unwrap();
+
+        // The unit may report being stalled before any source has data.
+        u1.send_payload(testrig::update([1, 2, 3, 4])).await;
+        assert_eq!(
+            t.next_payload().await.unwrap(), testrig::update([1, 2, 3, 4])
+        );
+        u2.send_payload(testrig::update([10, 11, 12, 13])).await;
+        u3.send_payload(testrig::update([1, 2, 3, 5])).await;
+        t.recv_nothing().unwrap();
+
+        // Stalling the first one skips the second one as it differs too
+        // much and picks the third one. If the unit sees the stall before
+        // the third one’s data, it is stalled until that data arrives.
+        u1.send_stalled().await;
+        assert_eq!(
+            t.next_payload().await.unwrap(), testrig::update([1, 2, 3, 5])
+        );
+
+        // Stalling the third one leaves only the second which is refused.
+        u3.send_stalled().await;
+        t.recv_stalled().await.unwrap();
+
+        // The first one coming back is close enough again.
+        u1.send_payload(testrig::update([1, 2, 3, 4])).await;
+        assert_eq!(
+            t.recv_payload().await.unwrap(), testrig::update([1, 2, 3, 4])
+        );
+    }
+
+    #[tokio::test]
+    async fn intersect_sources() {
+        let mut manager = Manager::default();
+
+        let (u1, u2, u3, mut t) = manager.add_components(
+            &runtime::Handle::current(),
+            |units, targets| {
+                let (u, u1c) = test::Unit::new();
+                units.insert("u1", u);
+                let (u, u2c) = test::Unit::new();
+                units.insert("u2", u);
+                let (u, u3c) = test::Unit::new();
+                units.insert("u3", u);
+
+                units.insert("intersect", units::Unit::Intersect(Intersect {
+                    sources: vec!["u1".into(), "u2".into(), "u3".into()],
+                    quorum: Some(2),
+                }));
+
+                let (t, tc) = test::Target::new("intersect");
+                targets.insert("t", t);
+
+                (u1c, u2c, u3c, tc)
+            }
+        ).unwrap();
+
+        // A single source isn’t enough for the quorum.
+        u1.send_payload(testrig::update([1, 2, 3])).await;
+        t.recv_stalled().await.unwrap();
+
+        u2.send_payload(testrig::update([2, 3, 4])).await;
+        assert_eq!(
+            t.recv_payload().await.unwrap(), testrig::update([2, 3])
+        );
+
+        u3.send_payload(testrig::update([1, 5])).await;
+        assert_eq!(
+            t.recv_payload().await.unwrap(), testrig::update([1, 2, 3])
+        );
+
+        // Stalled sources don’t count.
+        u1.send_stalled().await;
+        assert_eq!(t.recv_payload().await.unwrap(), testrig::update([]));
+        u2.send_stalled().await;
+        t.recv_stalled().await.unwrap();
+    }
+
+    #[test]
+    fn intersect_sets() {
+        let sets = [
+            testrig::set([testrig::block([1, 2, 3, 4], 0..4)]),
+            testrig::set([testrig::block([2, 4, 6], 0..3)]),
+            testrig::set([testrig::block([4, 5, 6], 0..3)]),
+        ];
+        let sets: Vec<_> = sets.iter().collect();
+        assert_eq!(testrig::set_to_vec(&intersect(&sets, 3)), [4]);
+        assert_eq!(testrig::set_to_vec(&intersect(&sets, 2)), [2, 4, 6]);
+        assert_eq!(
+            testrig::set_to_vec(&intersect(&sets, 1)), [1, 2, 3, 4, 5, 6]
+        );
+    }
+
+    #[tokio::test]
+    async fn merge_sources() {
+        let mut manager = Manager::default();
+
+        let (u1, u2, mut t) = manager.add_components(
+            &runtime::Handle::current(),
+            |units, targets| {
+                let (u, u1c) = test::Unit::new();
+                units.insert("u1", u);
+                let (u, u2c) = test::Unit::new();
+                units.insert("u2", u);
+
+                units.insert("merge", units::Unit::Merge(Merge {
+                    sources: vec!["u1".into(), "u2".into()],
+                }));
+
+                let (t, tc) = test::Target::new("merge");
+                targets.insert("t", t);
+
+                (u1c, u2c, tc)
+            }
+        ).unwrap();
+
+        u1.send_payload(testrig::update([1, 2])).await;
+        assert_eq!(
+            t.recv_payload().await.unwrap(), testrig::update([1, 2])
+        );
+
+        // Any change to a source results in the union of all sources.
+        u2.send_payload(testrig::update([2, 3])).await;
+        assert_eq!(
+            t.recv_payload().await.unwrap(), testrig::update([1, 2, 3])
+        );
+
+        // Stalled sources don’t contribute.
+        u1.send_stalled().await;
+        assert_eq!(
+            t.recv_payload().await.unwrap(), testrig::update([2, 3])
+        );
+    }
+}
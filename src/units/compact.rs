@@ -0,0 +1,327 @@
+//! Removing redundant VRPs.
+//!
+//! A VRP is redundant if there is another VRP for the same AS number whose
+//! prefix covers the VRP’s prefix and whose max length is at least that of
+//! the VRP. Every route matched by the redundant VRP is also matched by the
+//! other VRP and every route covered by it is also covered by the other
+//! one. Removing the redundant VRP therefore doesn’t change the outcome of
+//! route origin validation for any route.
+//!
+//! The _compact_ unit removes all redundant VRPs from the data set of its
+//! source. All other payload is passed through unchanged.
+
+use std::cmp;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use log::debug;
+use rpki::rtr::payload::Payload;
+use serde::Deserialize;
+use crate::{metrics, payload};
+use crate::comms::{Gate, GateMetrics, Link, Terminated, UnitUpdate};
+use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+use crate::utils::net::covers;
+
+
+//------------ Compact -------------------------------------------------------
+
+/// A unit removing redundant VRPs.
+#[derive(Debug, Deserialize)]
+pub struct Compact {
+    /// The source to read data from.
+    source: Link,
+}
+
+impl Compact {
+    pub async fn run(
+        mut self, mut component: Component, mut gate: Gate
+    ) -> Result<(), Terminated> {
+        let metrics = Arc::new(CompactMetrics::new(&gate));
+        component.register_metrics(metrics.clone());
+
+        loop {
+            let update = tokio::select! {
+                update = self.source.query() => update,
+                res = gate.process() => {
+                    res?;
+                    continue
+                }
+            };
+            match update {
+                UnitUpdate::Payload(update) => {
+                    let set = compact(update.set());
+                    let removed = update.set().len() - set.len();
+                    debug!(
+                        "Unit {}: removed {} redundant VRPs.",
+                        component.name(), removed
+                    );
+                    metrics.removed.store(removed, Relaxed);
+                    gate.update(
+                        UnitUpdate::Payload(update.derive(set))
+                    ).await;
+                }
+                UnitUpdate::Stalled => {
+                    gate.update(UnitUpdate::Stalled).await;
+                }
+                UnitUpdate::Gone => {
+                    gate.update(UnitUpdate::Gone).await;
+                    return Ok(())
+                }
+            }
+        }
+    }
+}
+
+
+//------------ compact -------------------------------------------------------
+
+/// Returns a copy of the set with all redundant VRPs removed.
+fn compact(set: &payload::Set) -> payload::Set {
+    // Sorting by AS number first and then by prefix means that all the
+    // prefixes covering a prefix appear before it. For equal prefixes, we
+    // want the largest max length first so that it is the one being kept.
+    let mut origins = set.iter().filter_map(|payload| {
+        match payload {
+            Payload::Origin(origin) => Some(*origin),
+            _ => None,
+        }
+    }).collect::<Vec<_>>();
+    origins.sort_unstable_by_key(|origin| {
+        (
+            origin.asn, origin.prefix.addr(), origin.prefix.prefix_len(),
+            cmp::Reverse(origin.prefix.resolved_max_len()),
+        )
+    });
+
+    // The stack contains the chain of retained prefixes covering the
+    // current prefix, each with the largest max length of itself and all
+    // the prefixes before it on the stack. A redundant VRP never needs to
+    // go onto the stack: whatever it covers is also covered by the VRP
+    // that made it redundant.
+    let mut redundant = Vec::new();
+    let mut stack: Vec<(IpAddr, u8, u8)> = Vec::new();
+    let mut asn = None;
+    for origin in origins {
+        if asn != Some(origin.asn) {
+            stack.clear();
+            asn = Some(origin.asn);
+        }
+        let addr = origin.prefix.addr();
+        let len = origin.prefix.prefix_len();
+        let max_len = origin.prefix.resolved_max_len();
+        while let Some(&(top_addr, top_len, _)) = stack.last() {
+            if covers(top_addr, top_len, addr, len) {
+                break
+            }
+            stack.pop();
+        }
+        match stack.last() {
+            Some(&(_, _, covering)) if covering >= max_len => {
+                redundant.push(origin)
+            }
+            Some(&(_, _, covering)) => {
+                stack.push((addr, len, covering.max(max_len)))
+            }
+            None => stack.push((addr, len, max_len))
+        }
+    }
+
+    if redundant.is_empty() {
+        return set.clone()
+    }
+    redundant.sort_unstable();
+    set.filter(|payload| {
+        match payload {
+            Payload::Origin(origin) => {
+                redundant.binary_search(origin).is_err()
+            }
+            _ => true
+        }
+    })
+}
+
+
+//------------ CompactMetrics ------------------------------------------------
+
+/// The metrics of a compact unit.
+#[derive(Debug, Default)]
+struct CompactMetrics {
+    /// The number of VRPs removed from the last update.
+    removed: AtomicUsize,
+
+    /// The gate metrics.
+    gate: Arc<GateMetrics>,
+}
+
+impl CompactMetrics {
+    const REMOVED_METRIC: Metric = Metric::new(
+        "compact_removed",
+        "the number of redundant VRPs removed from the last update",
+        MetricType::Gauge, MetricUnit::Total
+    );
+}
+
+impl CompactMetrics {
+    fn new(gate: &Gate) -> Self {
+        CompactMetrics {
+            gate: gate.metrics(),
+            ..Default::default()
+        }
+    }
+}
+
+impl metrics::Source for CompactMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::REMOVED_METRIC, Some(unit_name),
+            self.removed.load(Relaxed)
+        );
+        self.gate.append(unit_name, target);
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+    use rand::Rng;
+    use rpki::resources::addr::{MaxLenPrefix, Prefix};
+    use rpki::rtr::payload::RouteOrigin;
+
+    fn vrp(prefix: &str, max_len: u8, asn: u32) -> RouteOrigin {
+        let (addr, len) = prefix.split_once('/').unwrap();
+        RouteOrigin::new(
+            MaxLenPrefix::new(
+                Prefix::new(
+                    IpAddr::from_str(addr).unwrap(), len.parse().unwrap()
+                ).unwrap(),
+                Some(max_len)
+            ).unwrap(),
+            asn.into()
+        )
+    }
+
+    fn make_set(vrps: &[RouteOrigin]) -> payload::Set {
+        let mut res = payload::PackBuilder::empty();
+        for vrp in vrps {
+            res.insert_unchecked(Payload::Origin(*vrp))
+        }
+        res.finalize().into()
+    }
+
+    fn check(input: &[RouteOrigin], expected: &[RouteOrigin]) {
+        assert_eq!(compact(&make_set(input)), make_set(expected));
+    }
+
+    #[test]
+    fn compact_rules() {
+        // A covered VRP is removed if the max length suffices.
+        check(
+            &[vrp("10.0.0.0/16", 24, 1), vrp("10.0.1.0/24", 24, 1)],
+            &[vrp("10.0.0.0/16", 24, 1)],
+        );
+
+        // It is kept if the max length is too short.
+        check(
+            &[vrp("10.0.0.0/16", 23, 1), vrp("10.0.1.0/24", 24, 1)],
+            &[vrp("10.0.0.0/16", 23, 1), vrp("10.0.1.0/24", 24, 1)],
+        );
+
+        // It is kept if the AS number differs.
+        check(
+            &[vrp("10.0.0.0/16", 24, 1), vrp("10.0.1.0/24", 24, 2)],
+            &[vrp("10.0.0.0/16", 24, 1), vrp("10.0.1.0/24", 24, 2)],
+        );
+
+        // Of two VRPs for the same prefix, the one with the larger max
+        // length wins.
+        check(
+            &[vrp("10.0.0.0/16", 20, 1), vrp("10.0.0.0/16", 24, 1)],
+            &[vrp("10.0.0.0/16", 24, 1)],
+        );
+
+        // Neighbouring prefixes don’t cover each other.
+        check(
+            &[vrp("10.0.0.0/16", 24, 1), vrp("10.1.0.0/16", 24, 1)],
+            &[vrp("10.0.0.0/16", 24, 1), vrp("10.1.0.0/16", 24, 1)],
+        );
+
+        // A VRP can be made redundant by a less specific that isn’t
+        // the immediately covering one.
+        check(
+            &[
+                vrp("10.0.0.0/8", 24, 1), vrp("10.0.0.0/16", 16, 1),
+                vrp("10.0.1.0/24", 24, 1),
+            ],
+            &[vrp("10.0.0.0/8", 24, 1)],
+        );
+
+        // Chains where only the innermost one is redundant.
+        check(
+            &[
+                vrp("10.0.0.0/8", 8, 1), vrp("10.0.0.0/16", 24, 1),
+                vrp("10.0.1.0/24", 24, 1), vrp("10.0.16.0/20", 20, 1),
+                vrp("10.1.0.0/24", 24, 1),
+            ],
+            &[
+                vrp("10.0.0.0/8", 8, 1), vrp("10.0.0.0/16", 24, 1),
+                vrp("10.1.0.0/24", 24, 1),
+            ],
+        );
+
+        // Address families are kept apart.
+        check(
+            &[vrp("0.0.0.0/0", 32, 1), vrp("2001:db8::/32", 48, 1)],
+            &[vrp("0.0.0.0/0", 32, 1), vrp("2001:db8::/32", 48, 1)],
+        );
+        check(
+            &[vrp("::/0", 128, 1), vrp("2001:db8::/32", 48, 1)],
+            &[vrp("::/0", 128, 1)],
+        );
+    }
+
+    #[test]
+    fn compact_random() {
+        // Compares against a straightforward quadratic implementation.
+        fn is_redundant(vrp: &RouteOrigin, all: &[RouteOrigin]) -> bool {
+            all.iter().any(|other| {
+                other != vrp
+                && other.asn == vrp.asn
+                && covers(
+                    other.prefix.addr(), other.prefix.prefix_len(),
+                    vrp.prefix.addr(), vrp.prefix.prefix_len(),
+                )
+                && other.prefix.resolved_max_len()
+                    >= vrp.prefix.resolved_max_len()
+            })
+        }
+
+        let mut rng = rand_pcg::Pcg32::new(
+            0xcafef00dd15ea5e5, 0xa02bdbf7bb3c0a7
+        );
+        for _ in 0..100 {
+            let mut vrps = Vec::new();
+            for _ in 0..50 {
+                let len = rng.gen_range(8..=14);
+                let addr = (10u32 << 24) | (rng.gen::<u32>() & 0x00fc_0000);
+                let addr = addr & (u32::MAX << (32 - len));
+                vrps.push(vrp(
+                    &format!("{}/{}", std::net::Ipv4Addr::from(addr), len),
+                    rng.gen_range(len..=16),
+                    rng.gen_range(1..=3),
+                ));
+            }
+            vrps.sort_unstable();
+            vrps.dedup();
+            let expected = vrps.iter().filter(|vrp| {
+                !is_redundant(vrp, &vrps)
+            }).copied().collect::<Vec<_>>();
+            check(&vrps, &expected);
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! Smoothing out frequent updates.
+//!
+//! Some upstream sources produce bursts of updates, for instance when a
+//! validator flaps between two states. Every update is passed on to all RTR
+//! clients, so a flappy upstream can cause a considerable amount of load
+//! further down the line.
+//!
+//! The _delay_ unit holds back each update of its source for a hold-down
+//! time. If another update arrives during that time, it replaces the held
+//! update and the hold-down time starts over. This way, a burst of updates
+//! is collapsed into a single one. In order to not delay updates forever
+//! for a source that never calms down, the time an update is held back in
+//! total can be limited.
+
+use std::cmp;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+use futures_util::future::pending;
+use log::debug;
+use serde::Deserialize;
+use tokio::time::{sleep_until, Instant};
+use crate::{metrics, payload};
+use crate::comms::{Gate, GateMetrics, Link, Terminated, UnitUpdate};
+use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+
+
+//------------ Delay ---------------------------------------------------------
+
+/// A unit delaying and collapsing updates.
+#[derive(Debug, Deserialize)]
+pub struct Delay {
+    /// The source to read data from.
+    source: Link,
+
+    /// The hold-down time for updates in seconds.
+    delay: u64,
+
+    /// The maximum time an update is held back in seconds.
+    ///
+    /// If this is `None`, updates are held back until the source has been
+    /// quiet for the hold-down time.
+    #[serde(rename = "max-delay")]
+    max_delay: Option<u64>,
+
+    /// Whether to delay the first update, too.
+    #[serde(rename = "delay-initial", default)]
+    delay_initial: bool,
+}
+
+impl Delay {
+    pub async fn run(
+        mut self, mut component: Component, mut gate: Gate
+    ) -> Result<(), Terminated> {
+        let metrics = Arc::new(DelayMetrics::new(&gate));
+        component.register_metrics(metrics.clone());
+
+        let delay = Duration::from_secs(self.delay);
+        let max_delay = self.max_delay.map(Duration::from_secs);
+
+        // Whether the next update is the first one to be passed on.
+        let mut initial = !self.delay_initial;
+
+        // The update currently held back.
+        let mut held: Option<Held> = None;
+
+        loop {
+            let deadline = held.as_ref().map(|held| {
+                held.deadline(delay, max_delay)
+            });
+            let update = tokio::select! {
+                update = self.source.query() => update,
+                res = gate.process() => {
+                    res?;
+                    continue
+                }
+                _ = async {
+                    match deadline {
+                        Some(deadline) => sleep_until(deadline).await,
+                        None => pending().await,
+                    }
+                } => {
+                    if let Some(held) = held.take() {
+                        debug!(
+                            "Unit {}: passing on update after collapsing {} \
+                             updates.",
+                            component.name(), held.count
+                        );
+                        gate.update(UnitUpdate::Payload(held.update)).await;
+                    }
+                    continue
+                }
+            };
+            match update {
+                UnitUpdate::Payload(update) => {
+                    if initial {
+                        initial = false;
+                        gate.update(UnitUpdate::Payload(update)).await;
+                        continue
+                    }
+                    match held.as_mut() {
+                        Some(held) => {
+                            metrics.collapsed.fetch_add(1, Relaxed);
+                            held.replace(update);
+                        }
+                        None => held = Some(Held::new(update)),
+                    }
+                }
+                UnitUpdate::Stalled => {
+                    gate.update(UnitUpdate::Stalled).await;
+                }
+                UnitUpdate::Gone => {
+                    gate.update(UnitUpdate::Gone).await;
+                    return Ok(())
+                }
+            }
+        }
+    }
+}
+
+
+//------------ Held ----------------------------------------------------------
+
+/// An update being held back.
+#[derive(Debug)]
+struct Held {
+    /// The update.
+    update: payload::Update,
+
+    /// The number of updates collapsed into this one.
+    count: usize,
+
+    /// The time the first of the collapsed updates arrived.
+    first: Instant,
+
+    /// The time the last of the collapsed updates arrived.
+    last: Instant,
+}
+
+impl Held {
+    /// Creates a new held update that has just arrived.
+    fn new(update: payload::Update) -> Self {
+        let now = Instant::now();
+        Held { update, count: 1, first: now, last: now }
+    }
+
+    /// Replaces the held update with a newer one that has just arrived.
+    fn replace(&mut self, update: payload::Update) {
+        self.update = update;
+        self.count += 1;
+        self.last = Instant::now();
+    }
+
+    /// Returns the time the update should be passed on.
+    fn deadline(
+        &self, delay: Duration, max_delay: Option<Duration>
+    ) -> Instant {
+        let deadline = self.last + delay;
+        match max_delay {
+            Some(max_delay) => cmp::min(deadline, self.first + max_delay),
+            None => deadline
+        }
+    }
+}
+
+
+//------------ DelayMetrics --------------------------------------------------
+
+/// The metrics of a delay unit.
+#[derive(Debug, Default)]
+struct DelayMetrics {
+    /// The number of updates replaced by a later update.
+    collapsed: AtomicU64,
+
+    /// The gate metrics.
+    gate: Arc<GateMetrics>,
+}
+
+impl DelayMetrics {
+    const COLLAPSED_METRIC: Metric = Metric::new(
+        "delay_collapsed",
+        "the number of updates replaced by a later update",
+        MetricType::Counter, MetricUnit::Total
+    );
+}
+
+impl DelayMetrics {
+    fn new(gate: &Gate) -> Self {
+        DelayMetrics {
+            gate: gate.metrics(),
+            ..Default::default()
+        }
+    }
+}
+
+impl metrics::Source for DelayMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::COLLAPSED_METRIC, Some(unit_name),
+            self.collapsed.load(Relaxed)
+        );
+        self.gate.append(unit_name, target);
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::payload::testrig;
+
+    #[test]
+    fn deadline() {
+        let update = payload::Update::new(testrig::pack([1]).into());
+        let mut held = Held::new(update.clone());
+        let first = held.first;
+        assert_eq!(
+            held.deadline(Duration::from_secs(10), None),
+            first + Duration::from_secs(10)
+        );
+
+        held.replace(update);
+        assert_eq!(held.count, 2);
+        assert!(held.last >= first);
+        held.last = first + Duration::from_secs(50);
+        assert_eq!(
+            held.deadline(Duration::from_secs(10), None),
+            first + Duration::from_secs(60)
+        );
+        assert_eq!(
+            held.deadline(
+                Duration::from_secs(10), Some(Duration::from_secs(30))
+            ),
+            first + Duration::from_secs(30)
+        );
+        assert_eq!(
+            held.deadline(
+                Duration::from_secs(10), Some(Duration::from_secs(300))
+            ),
+            first + Duration::from_secs(60)
+        );
+    }
+}
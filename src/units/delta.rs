@@ -0,0 +1,401 @@
+//! Incrementally updated JSON clients.
+//!
+//! The _json-delta_ unit follows the data set of a validator that provides
+//! changes as JSON deltas, such as the `/json-delta` endpoint of Routinator.
+//! The first request fetches the complete data set. Later requests include
+//! the session and serial number of the last data set received and only
+//! transfer the changes since then. If the server cannot provide a delta
+//! for that version, it returns the complete data set again.
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+use crossbeam_utils::atomic::AtomicCell;
+use daemonbase::error::Failed;
+use log::{debug, error, warn};
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+use tokio::task::spawn_blocking;
+use tokio::time::{Instant, timeout_at};
+use crate::{metrics, payload};
+use crate::comms::{Gate, GateMetrics, Terminated, UnitUpdate};
+use crate::formats::json::Delta;
+use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+
+
+//------------ JsonDelta -----------------------------------------------------
+
+/// A unit that follows a data set via JSON deltas.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JsonDelta {
+    /// The URI of the delta endpoint.
+    uri: Url,
+
+    /// How many seconds to wait before asking for changes.
+    refresh: u64,
+}
+
+impl JsonDelta {
+    pub async fn run(
+        self, mut component: Component, mut gate: Gate
+    ) -> Result<(), Terminated> {
+        let metrics = Arc::new(DeltaMetrics::new(&gate));
+        component.register_metrics(metrics.clone());
+        let client = self.http_client(&component)?;
+        let mut current = None;
+        loop {
+            match gate.process_until(
+                self.fetch(&client, &mut current, &component, &metrics)
+            ).await? {
+                Ok(true) => {
+                    let update = payload::Update::new(
+                        current.as_ref().map(|version| {
+                            version.set.clone()
+                        }).unwrap_or_default()
+                    );
+                    if gate.update(UnitUpdate::Payload(update)).await {
+                        debug!(
+                            "Unit {}: successfully updated.",
+                            component.name()
+                        );
+                    }
+                }
+                Ok(false) => {
+                    debug!("Unit {}: no changes.", component.name());
+                }
+                Err(Failed) => {
+                    metrics.failures.fetch_add(1, Relaxed);
+                    if gate.update(UnitUpdate::Stalled).await {
+                        debug!(
+                            "Unit {}: marked as stalled.",
+                            component.name()
+                        );
+                    }
+                }
+            }
+            self.wait(&mut gate).await?;
+        }
+    }
+
+    fn http_client(
+        &self, component: &Component
+    ) -> Result<reqwest::Client, Terminated> {
+        let builder = component.http_client().map_err(|err| {
+            error!("Unit {}: {}", component.name(), err);
+            Terminated
+        })?;
+        let builder = builder.gzip(true).deflate(true).brotli(true);
+        builder.build().map_err(|err| {
+            error!("Unit {}: Failed to initialize HTTP client: {}.",
+                component.name(), err
+            );
+            Terminated
+        })
+    }
+
+    /// Fetches the changes since the current version and applies them.
+    ///
+    /// Returns whether the data set has changed. If the changes cannot be
+    /// applied, the current version is dropped so that the complete data
+    /// set is requested next time.
+    async fn fetch(
+        &self,
+        client: &reqwest::Client,
+        current: &mut Option<Version>,
+        component: &Component,
+        metrics: &DeltaMetrics,
+    ) -> Result<bool, Failed> {
+        let mut uri = self.uri.clone();
+        if let Some(current) = current.as_ref() {
+            uri.query_pairs_mut()
+                .append_pair("session", &current.session.to_string())
+                .append_pair("serial", &current.serial.to_string());
+        }
+        let response = client.get(uri).send().await.map_err(|err| {
+            warn!(
+                "Unit {}: HTTP request failed: {}",
+                component.name(), err
+            );
+            Failed
+        })?;
+        if response.status() != StatusCode::OK {
+            warn!(
+                "Unit {}: HTTP request return status {}",
+                component.name(), response.status()
+            );
+            return Err(Failed)
+        }
+        let body = response.bytes().await.map_err(|err| {
+            warn!(
+                "Unit {}: HTTP request failed: {}",
+                component.name(), err
+            );
+            Failed
+        })?;
+        metrics.bytes.fetch_add(body.len() as u64, Relaxed);
+
+        let delta = match spawn_blocking(move || {
+            serde_json::from_slice::<Delta>(&body)
+        }).await {
+            Ok(Ok(delta)) => delta,
+            Ok(Err(err)) => {
+                warn!(
+                    "Unit {}: Failed parsing source: {}",
+                    component.name(), err
+                );
+                return Err(Failed)
+            }
+            Err(_) => {
+                warn!(
+                    "Unit {}: Failed parsing source: JSON parser panicked.",
+                    component.name(),
+                );
+                return Err(Failed)
+            }
+        };
+        let reset = delta.is_reset();
+        match Version::apply(current.as_ref(), delta) {
+            Ok(res) => {
+                if reset {
+                    metrics.resets.fetch_add(1, Relaxed);
+                }
+                else {
+                    metrics.deltas.fetch_add(1, Relaxed);
+                }
+                match res {
+                    Some(res) => {
+                        metrics.serial.store(Some(res.serial));
+                        *current = Some(res);
+                        Ok(true)
+                    }
+                    None => Ok(false)
+                }
+            }
+            Err(err) => {
+                warn!(
+                    "Unit {}: {}. Requesting complete data set next time.",
+                    component.name(), err
+                );
+                *current = None;
+                Err(Failed)
+            }
+        }
+    }
+
+    async fn wait(&self, gate: &mut Gate) -> Result<(), Terminated> {
+        let end = Instant::now() + Duration::from_secs(self.refresh);
+        while end > Instant::now() {
+            match timeout_at(end, gate.process()).await {
+                Ok(Ok(_status)) => { }
+                Ok(Err(_)) => return Err(Terminated),
+                Err(_) => return Ok(()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+//------------ Version -------------------------------------------------------
+
+/// A version of the data set.
+#[derive(Clone, Debug)]
+struct Version {
+    /// The session of the data set.
+    session: u64,
+
+    /// The serial number of the data set.
+    serial: u64,
+
+    /// The data set itself.
+    set: payload::Set,
+}
+
+impl Version {
+    /// Applies a delta to the current version.
+    ///
+    /// Returns the new version or `None` if the delta doesn’t change
+    /// anything. Returns an error message if the delta cannot be applied.
+    fn apply(
+        current: Option<&Version>, delta: Delta,
+    ) -> Result<Option<Self>, String> {
+        let session = delta.session();
+        let serial = delta.serial();
+        if delta.is_reset() {
+            return Ok(Some(Version {
+                session, serial, set: delta.into_set()
+            }))
+        }
+        let current = match current {
+            Some(current) if current.session == session => current,
+            _ => {
+                return Err(format!(
+                    "received delta for unknown session {}", session
+                ))
+            }
+        };
+        if current.serial == serial {
+            return Ok(None)
+        }
+        let set = delta.into_diff().and_then(|diff| {
+            diff.apply(&current.set)
+        }).map_err(|_| {
+            format!(
+                "delta to serial {} doesn’t apply to serial {}",
+                serial, current.serial
+            )
+        })?;
+        Ok(Some(Version { session, serial, set }))
+    }
+}
+
+
+//------------ DeltaMetrics --------------------------------------------------
+
+/// The metrics of a JSON delta unit.
+#[derive(Debug, Default)]
+struct DeltaMetrics {
+    /// The number of times the complete data set was received.
+    resets: AtomicU64,
+
+    /// The number of times a delta was received.
+    deltas: AtomicU64,
+
+    /// The number of times fetching or applying changes failed.
+    failures: AtomicU64,
+
+    /// The number of bytes received.
+    bytes: AtomicU64,
+
+    /// The serial number of the current data set.
+    serial: AtomicCell<Option<u64>>,
+
+    /// The gate metrics.
+    gate: Arc<GateMetrics>,
+}
+
+impl DeltaMetrics {
+    const RESETS_METRIC: Metric = Metric::new(
+        "json_delta_resets",
+        "the number of times the complete data set was received",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const DELTAS_METRIC: Metric = Metric::new(
+        "json_delta_deltas",
+        "the number of times changes to the data set were received",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const FAILURES_METRIC: Metric = Metric::new(
+        "json_delta_failures",
+        "the number of times fetching or applying changes failed",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const BYTES_METRIC: Metric = Metric::new(
+        "json_delta_received",
+        "the number of bytes received from the source",
+        MetricType::Counter, MetricUnit::Byte
+    );
+    const SERIAL_METRIC: Metric = Metric::new(
+        "json_delta_serial",
+        "the serial number of the current data set",
+        MetricType::Gauge, MetricUnit::Info
+    );
+
+    fn new(gate: &Gate) -> Self {
+        DeltaMetrics {
+            gate: gate.metrics(),
+            ..Default::default()
+        }
+    }
+}
+
+impl metrics::Source for DeltaMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::RESETS_METRIC, Some(unit_name), self.resets.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::DELTAS_METRIC, Some(unit_name), self.deltas.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::FAILURES_METRIC, Some(unit_name),
+            self.failures.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::BYTES_METRIC, Some(unit_name), self.bytes.load(Relaxed)
+        );
+        if let Some(serial) = self.serial.load() {
+            target.append_simple(
+                &Self::SERIAL_METRIC, Some(unit_name), serial
+            );
+        }
+        self.gate.append(unit_name, target);
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn delta(json: &str) -> Delta {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn apply() {
+        // A delta without a current version fails.
+        assert!(Version::apply(None, delta(
+            r#"{"session": 1, "serial": 2, "announced": []}"#
+        )).is_err());
+
+        let first = Version::apply(None, delta(r#"{
+            "reset": true, "session": 1, "serial": 2,
+            "announced": [
+                { "asn": "AS64496", "prefix": "192.0.2.0/24",
+                  "maxLength": 24 }
+            ]
+        }"#)).unwrap().unwrap();
+        assert_eq!(first.set.len(), 1);
+
+        // Same serial means no change.
+        assert!(Version::apply(Some(&first), delta(
+            r#"{"session": 1, "serial": 2}"#
+        )).unwrap().is_none());
+
+        let second = Version::apply(Some(&first), delta(r#"{
+            "session": 1, "serial": 3,
+            "announced": [
+                { "asn": "AS64497", "prefix": "198.51.100.0/24",
+                  "maxLength": 24 }
+            ]
+        }"#)).unwrap().unwrap();
+        assert_eq!(second.serial, 3);
+        assert_eq!(second.set.len(), 2);
+
+        // Withdrawing something we don’t have fails.
+        assert!(Version::apply(Some(&second), delta(r#"{
+            "session": 1, "serial": 4,
+            "withdrawn": [
+                { "asn": "AS64498", "prefix": "203.0.113.0/24",
+                  "maxLength": 24 }
+            ]
+        }"#)).is_err());
+
+        // A different session fails unless it is a reset.
+        assert!(Version::apply(Some(&second), delta(
+            r#"{"session": 2, "serial": 4}"#
+        )).is_err());
+        let third = Version::apply(Some(&second), delta(
+            r#"{"reset": true, "session": 2, "serial": 0}"#
+        )).unwrap().unwrap();
+        assert_eq!(third.session, 2);
+        assert!(third.set.is_empty());
+    }
+}
@@ -0,0 +1,358 @@
+//! Transforming data sets with an external program.
+//!
+//! The _exec_ unit runs a configured program for every update of its
+//! source. The data set is written to the program’s standard input in the
+//! JSON format also produced by the HTTP target. The program is expected to
+//! write a data set in the same format to its standard output and exit
+//! successfully. The route origins of this output replace those of the
+//! source data set. Router keys and ASPA records are passed on unchanged.
+//!
+//! If the program fails, takes too long, or produces output that can’t be
+//! parsed, the unit logs an error and keeps its previous data set.
+
+use std::io;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::{Duration, Instant};
+use log::{debug, error, warn};
+use rpki::rtr::payload::Payload;
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use crate::{metrics, payload};
+use crate::comms::{Gate, GateMetrics, Link, Terminated, UnitUpdate};
+use crate::formats::json::Set as JsonSet;
+use crate::formats::output;
+use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+
+
+//------------ Exec ----------------------------------------------------------
+
+/// A unit transforming data sets via an external program.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Exec {
+    /// The source to read data from.
+    source: Link,
+
+    /// The program to run.
+    command: String,
+
+    /// The arguments to pass to the program.
+    #[serde(default)]
+    args: Vec<String>,
+
+    /// The number of seconds the program may run before it is killed.
+    #[serde(default = "Exec::default_timeout")]
+    timeout: u64,
+}
+
+impl Exec {
+    /// The default for the timeout.
+    fn default_timeout() -> u64 {
+        60
+    }
+
+    pub async fn run(
+        mut self, mut component: Component, mut gate: Gate
+    ) -> Result<(), Terminated> {
+        let metrics = Arc::new(ExecMetrics::new(&gate));
+        component.register_metrics(metrics.clone());
+        let timeout = Duration::from_secs(self.timeout);
+
+        loop {
+            let update = tokio::select! {
+                update = self.source.query() => update,
+                res = gate.process() => {
+                    res?;
+                    continue
+                }
+            };
+            match update {
+                UnitUpdate::Payload(update) => {
+                    let start = Instant::now();
+                    let res = gate.process_until(
+                        self.transform(update.set(), timeout)
+                    ).await?;
+                    metrics.runs.fetch_add(1, Relaxed);
+                    metrics.duration.store(
+                        start.elapsed().as_millis().try_into()
+                            .unwrap_or(u64::MAX),
+                        Relaxed
+                    );
+                    match res {
+                        Ok((set, stderr)) => {
+                            for line in stderr.lines() {
+                                debug!(
+                                    "Unit {}: {}: {}",
+                                    component.name(), self.command, line
+                                );
+                            }
+                            debug!(
+                                "Unit {}: {} turned {} items into {}.",
+                                component.name(), self.command,
+                                update.set().len(), set.len()
+                            );
+                            metrics.items.store(set.len(), Relaxed);
+                            gate.update(
+                                UnitUpdate::Payload(update.derive(set))
+                            ).await;
+                        }
+                        Err((err, stderr)) => {
+                            for line in stderr.lines() {
+                                warn!(
+                                    "Unit {}: {}: {}",
+                                    component.name(), self.command, line
+                                );
+                            }
+                            error!(
+                                "Unit {}: running {} failed: {}. \
+                                 Keeping previous data set.",
+                                component.name(), self.command, err
+                            );
+                            metrics.failures.fetch_add(1, Relaxed);
+                        }
+                    }
+                }
+                UnitUpdate::Stalled => {
+                    gate.update(UnitUpdate::Stalled).await;
+                }
+                UnitUpdate::Gone => {
+                    gate.update(UnitUpdate::Gone).await;
+                    return Ok(())
+                }
+            }
+        }
+    }
+
+    /// Runs the program for a data set.
+    ///
+    /// Returns the transformed data set or an error message. Either way,
+    /// whatever the program wrote to its standard error is returned, too.
+    async fn transform(
+        &self, set: &payload::Set, timeout: Duration,
+    ) -> Result<(payload::Set, String), (String, String)> {
+        let mut stderr = Vec::new();
+        let res = tokio::time::timeout(
+            timeout, self.run_program(set, &mut stderr)
+        ).await;
+        let stderr = String::from_utf8_lossy(&stderr).into_owned();
+        let stdout = match res {
+            Ok(Ok(stdout)) => stdout,
+            Ok(Err(err)) => return Err((err, stderr)),
+            Err(_) => {
+                return Err((
+                    format!("timed out after {}s", timeout.as_secs()),
+                    stderr
+                ))
+            }
+        };
+        let output = match serde_json::from_slice::<JsonSet>(&stdout) {
+            Ok(output) => output,
+            Err(err) => {
+                return Err((format!("invalid output: {}", err), stderr))
+            }
+        };
+
+        // Replace the route origins with the program’s output.
+        let mut res = set.filter(|payload| {
+            !matches!(payload, Payload::Origin(_))
+        }).to_builder();
+        res.insert_set(output.into_payload().filter(|payload| {
+            matches!(payload, Payload::Origin(_))
+        }));
+        Ok((res.finalize(), stderr))
+    }
+
+    /// Runs the program and returns its standard output.
+    ///
+    /// The standard error is collected into `stderr` so that it is
+    /// available even if the program is killed because of the timeout.
+    async fn run_program(
+        &self, set: &payload::Set, stderr: &mut Vec<u8>,
+    ) -> Result<Vec<u8>, String> {
+        let mut child = Command::new(&self.command)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|err| err.to_string())?;
+
+        let mut child_stdin = child.stdin.take().ok_or("no stdin")?;
+        let mut child_stdout = child.stdout.take().ok_or("no stdout")?;
+        let mut child_stderr = child.stderr.take().ok_or("no stderr")?;
+        let input = output::Format::Json.stream(
+            set.clone(), Default::default(), None
+        );
+        let write = async move {
+            for chunk in input {
+                child_stdin.write_all(&chunk).await?;
+            }
+            // Dropping stdin closes it.
+            Ok::<_, io::Error>(())
+        };
+        let mut stdout = Vec::new();
+        let (write, read_out, read_err) = tokio::join!(
+            write,
+            child_stdout.read_to_end(&mut stdout),
+            child_stderr.read_to_end(stderr),
+        );
+        match write {
+            // The program may well decide not to read all its input.
+            Err(err) if err.kind() != io::ErrorKind::BrokenPipe => {
+                return Err(format!("failed to write input: {}", err))
+            }
+            _ => { }
+        }
+        read_out.map_err(|err| format!("failed to read output: {}", err))?;
+        read_err.map_err(|err| format!("failed to read output: {}", err))?;
+        let status = child.wait().await.map_err(|err| err.to_string())?;
+        if !status.success() {
+            return Err(format!("program exited with {}", status))
+        }
+        Ok(stdout)
+    }
+}
+
+
+//------------ ExecMetrics ---------------------------------------------------
+
+/// The metrics of an exec unit.
+#[derive(Debug, Default)]
+struct ExecMetrics {
+    /// The number of times the program was run.
+    runs: AtomicU64,
+
+    /// The number of times the program failed.
+    failures: AtomicU64,
+
+    /// The duration of the last run in milliseconds.
+    duration: AtomicU64,
+
+    /// The number of items in the last successfully transformed set.
+    items: AtomicUsize,
+
+    /// The gate metrics.
+    gate: Arc<GateMetrics>,
+}
+
+impl ExecMetrics {
+    const RUNS_METRIC: Metric = Metric::new(
+        "exec_runs", "the number of times the program was run",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const FAILURES_METRIC: Metric = Metric::new(
+        "exec_failures", "the number of times the program failed",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const DURATION_METRIC: Metric = Metric::new(
+        "exec_duration", "the duration of the last run of the program",
+        MetricType::Gauge, MetricUnit::Second
+    );
+    const ITEMS_METRIC: Metric = Metric::new(
+        "exec_items",
+        "the number of items in the last successfully transformed set",
+        MetricType::Gauge, MetricUnit::Total
+    );
+
+    fn new(gate: &Gate) -> Self {
+        ExecMetrics {
+            gate: gate.metrics(),
+            ..Default::default()
+        }
+    }
+}
+
+impl metrics::Source for ExecMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::RUNS_METRIC, Some(unit_name), self.runs.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::FAILURES_METRIC, Some(unit_name),
+            self.failures.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::DURATION_METRIC, Some(unit_name),
+            self.duration.load(Relaxed) as f64 / 1000.
+        );
+        target.append_simple(
+            &Self::ITEMS_METRIC, Some(unit_name), self.items.load(Relaxed)
+        );
+        self.gate.append(unit_name, target);
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use crate::payload::testrig;
+
+    fn exec(command: &str, args: &[&str]) -> Exec {
+        Exec {
+            source: crate::manager::dangling_link("source"),
+            command: command.into(),
+            args: args.iter().map(|arg| String::from(*arg)).collect(),
+            timeout: Exec::default_timeout(),
+        }
+    }
+
+    #[tokio::test]
+    async fn transform_identity() {
+        let set = payload::Set::from(testrig::pack([1, 2, 3]));
+        let (res, _) = exec("cat", &[]).transform(
+            &set, Duration::from_secs(10)
+        ).await.unwrap();
+        assert_eq!(res, set);
+    }
+
+    #[tokio::test]
+    async fn transform_replace() {
+        let set = payload::Set::from(testrig::slurm_pack(
+            include_bytes!("../../test-data/router-keys.slurm.json")
+        ));
+        let (res, _) = exec(
+            "sh", &[
+                "-c",
+                "cat > /dev/null; echo '{ \"roas\": [ { \
+                 \"asn\": \"AS64496\", \"prefix\": \"192.0.2.0/24\", \
+                 \"maxLength\": 24 } ] }'"
+            ]
+        ).transform(&set, Duration::from_secs(10)).await.unwrap();
+        assert_eq!(res.len(), 3);
+        assert_eq!(
+            res.iter().filter(|item| {
+                matches!(item, Payload::RouterKey(_))
+            }).count(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn transform_failures() {
+        let set = payload::Set::from(testrig::pack([1, 2, 3]));
+        let (err, stderr) = exec(
+            "sh", &["-c", "echo broken >&2; exit 1"]
+        ).transform(&set, Duration::from_secs(10)).await.unwrap_err();
+        assert!(err.contains("exited"));
+        assert_eq!(stderr, "broken\n");
+
+        let (err, _) = exec("sh", &["-c", "echo garbage"]).transform(
+            &set, Duration::from_secs(10)
+        ).await.unwrap_err();
+        assert!(err.starts_with("invalid output"));
+
+        let (err, _) = exec("sleep", &["10"]).transform(
+            &set, Duration::from_millis(100)
+        ).await.unwrap_err();
+        assert!(err.starts_with("timed out"));
+    }
+}
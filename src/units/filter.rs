@@ -0,0 +1,571 @@
+//! Filtering payload by static rules.
+//!
+//! The _filter_ unit applies a list of rules to the data set of its source.
+//! Each rule consists of a number of conditions and an action. The first
+//! rule whose conditions all match an item decides whether the item is kept
+//! or dropped. Items not matched by any rule are treated according to the
+//! unit’s default action.
+//!
+//! This provides a simpler alternative to SLURM files for static policies
+//! such as dropping all VRPs for a certain address range or AS number.
+//!
+//! If the source provides the trust anchors of its items, rules can also
+//! match by trust anchor name. This allows, for instance, to temporarily
+//! exclude a trust anchor during an incident.
+//!
+//! Rules can be limited to certain types of payload. ASPA records can
+//! further be matched by their providers. Together with the ASPA filters
+//! and assertions of SLURM version 2 files, this allows overriding ASPA
+//! data locally.
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use log::debug;
+use rpki::resources::addr::Prefix;
+use rpki::resources::asn::Asn;
+use rpki::rtr::payload::Payload;
+use serde::Deserialize;
+use crate::{metrics, payload};
+use crate::comms::{Gate, GateMetrics, Link, Terminated, UnitUpdate};
+use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+use crate::utils::net::covers;
+
+
+//------------ Filter --------------------------------------------------------
+
+/// A unit filtering payload by static rules.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Filter {
+    /// The source to read data from.
+    source: Link,
+
+    /// The rules to apply in order.
+    #[serde(default)]
+    rules: Vec<Rule>,
+
+    /// What to do with items not matched by any rule.
+    #[serde(default)]
+    default: Action,
+}
+
+impl Filter {
+    pub async fn run(
+        mut self, mut component: Component, mut gate: Gate
+    ) -> Result<(), Terminated> {
+        let metrics = Arc::new(FilterMetrics::new(&gate));
+        component.register_metrics(metrics.clone());
+
+        loop {
+            let update = tokio::select! {
+                update = self.source.query() => update,
+                res = gate.process() => {
+                    res?;
+                    continue
+                }
+            };
+            match update {
+                UnitUpdate::Payload(update) => {
+                    let set = self.filter(
+                        update.set(), update.trust_anchors()
+                    );
+                    let removed = update.set().len() - set.len();
+                    debug!(
+                        "Unit {}: filter removed {} items.",
+                        component.name(), removed
+                    );
+                    metrics.removed.store(removed, Relaxed);
+                    gate.update(
+                        UnitUpdate::Payload(update.derive(set))
+                    ).await;
+                }
+                UnitUpdate::Stalled => {
+                    gate.update(UnitUpdate::Stalled).await;
+                }
+                UnitUpdate::Gone => {
+                    gate.update(UnitUpdate::Gone).await;
+                    return Ok(())
+                }
+            }
+        }
+    }
+
+    /// Returns a copy of the set with only the items to be kept.
+    fn filter(
+        &self, set: &payload::Set, tas: Option<&payload::TrustAnchors>
+    ) -> payload::Set {
+        filter_set(&self.rules, self.default, set, tas)
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Returns whether an item should be kept.
+///
+/// The first rule matching the item decides. If there is none, the
+/// default action is used.
+fn keeps(
+    rules: &[Rule], default: Action, payload: &Payload, ta: Option<&str>
+) -> bool {
+    let action = rules.iter().find(|rule| {
+        rule.matches(payload, ta)
+    }).map(|rule| rule.action).unwrap_or(default);
+    action == Action::Keep
+}
+
+/// Returns a copy of the set with only the items to be kept.
+fn filter_set(
+    rules: &[Rule], default: Action, set: &payload::Set,
+    tas: Option<&payload::TrustAnchors>,
+) -> payload::Set {
+    set.filter(|payload| {
+        keeps(
+            rules, default, payload,
+            tas.and_then(|tas| tas.get(payload))
+        )
+    })
+}
+
+
+//------------ Action --------------------------------------------------------
+
+/// What to do with a matching item.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    /// Keep the item.
+    #[default]
+    Keep,
+
+    /// Drop the item.
+    Drop,
+}
+
+
+//------------ Rule ----------------------------------------------------------
+
+/// A single filter rule.
+///
+/// A rule matches an item if all of its conditions match. A rule without
+/// any conditions matches all items. The family, prefix, and max length
+/// conditions only ever match route origins while the providers condition
+/// only ever matches ASPA records. The trust anchor condition only matches
+/// items whose trust anchor is known.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    /// What to do with matching items.
+    action: Action,
+
+    /// The types of matching items.
+    types: Option<Vec<ItemType>>,
+
+    /// The address family of matching route origins.
+    family: Option<Family>,
+
+    /// Prefixes covering the prefix of matching route origins.
+    prefixes: Option<Vec<Prefix>>,
+
+    /// AS numbers of matching items.
+    ///
+    /// This is the origin AS of route origins, the AS of router keys, and
+    /// the customer AS of ASPA records.
+    asns: Option<Vec<RuleAsn>>,
+
+    /// Provider AS numbers of matching ASPA records.
+    ///
+    /// An ASPA record matches if any of its providers is listed.
+    providers: Option<Vec<RuleAsn>>,
+
+    /// The range of the max length of matching route origins.
+    #[serde(rename = "max-length")]
+    max_len: Option<MaxLenRange>,
+
+    /// The names of the trust anchors of matching items.
+    #[serde(rename = "trust-anchors")]
+    trust_anchors: Option<Vec<String>>,
+}
+
+impl Rule {
+    /// Returns whether the rule matches the given item.
+    ///
+    /// The trust anchor of the item is given via `ta` if it is known.
+    fn matches(&self, payload: &Payload, ta: Option<&str>) -> bool {
+        if let Some(types) = self.types.as_ref() {
+            if !types.contains(&ItemType::of(payload)) {
+                return false
+            }
+        }
+        if let Some(names) = self.trust_anchors.as_ref() {
+            match ta {
+                Some(ta) if names.iter().any(|name| name == ta) => { }
+                _ => return false
+            }
+        }
+        if let Some(asns) = self.asns.as_ref() {
+            let asn = match payload {
+                Payload::Origin(origin) => origin.asn,
+                Payload::RouterKey(key) => key.asn,
+                Payload::Aspa(aspa) => aspa.customer,
+            };
+            if !asns.iter().any(|item| item.0 == asn) {
+                return false
+            }
+        }
+        if let Some(providers) = self.providers.as_ref() {
+            let aspa = match payload {
+                Payload::Aspa(aspa) => aspa,
+                _ => return false,
+            };
+            if !aspa.providers.iter().any(|asn| {
+                providers.iter().any(|item| item.0 == asn)
+            }) {
+                return false
+            }
+        }
+        if !self.has_origin_conditions() {
+            return true
+        }
+        let origin = match payload {
+            Payload::Origin(origin) => origin,
+            _ => return false,
+        };
+        let addr = origin.prefix.addr();
+        let len = origin.prefix.prefix_len();
+        if let Some(family) = self.family {
+            if family.is_ipv4() != addr.is_ipv4() {
+                return false
+            }
+        }
+        if let Some(prefixes) = self.prefixes.as_ref() {
+            if !prefixes.iter().any(|prefix| {
+                covers(prefix.addr(), prefix.len(), addr, len)
+            }) {
+                return false
+            }
+        }
+        if let Some(range) = self.max_len {
+            if !range.contains(origin.prefix.resolved_max_len()) {
+                return false
+            }
+        }
+        true
+    }
+
+    /// Returns whether the rule has conditions for route origins only.
+    fn has_origin_conditions(&self) -> bool {
+        self.family.is_some() || self.prefixes.is_some()
+            || self.max_len.is_some()
+    }
+}
+
+
+//------------ ItemType ------------------------------------------------------
+
+/// The type of a payload item.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum ItemType {
+    Origin,
+    RouterKey,
+    Aspa,
+}
+
+impl ItemType {
+    fn of(payload: &Payload) -> Self {
+        match payload {
+            Payload::Origin(_) => ItemType::Origin,
+            Payload::RouterKey(_) => ItemType::RouterKey,
+            Payload::Aspa(_) => ItemType::Aspa,
+        }
+    }
+}
+
+
+//------------ Family --------------------------------------------------------
+
+/// An address family.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Family {
+    Ipv4,
+    Ipv6,
+}
+
+impl Family {
+    fn is_ipv4(self) -> bool {
+        matches!(self, Family::Ipv4)
+    }
+}
+
+
+//------------ RuleAsn -------------------------------------------------------
+
+/// An AS number in a rule.
+///
+/// This can be given either as an integer or as a string with or without
+/// the `AS` prefix.
+#[derive(Clone, Copy, Debug, Deserialize)]
+struct RuleAsn(
+    #[serde(deserialize_with = "Asn::deserialize_from_any")]
+    Asn
+);
+
+
+//------------ MaxLenRange ---------------------------------------------------
+
+/// A range of max lengths.
+///
+/// Both limits are inclusive and optional.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaxLenRange {
+    /// The smallest matching max length.
+    min: Option<u8>,
+
+    /// The largest matching max length.
+    max: Option<u8>,
+}
+
+impl MaxLenRange {
+    /// Returns whether the range contains the given max length.
+    fn contains(self, max_len: u8) -> bool {
+        self.min.map(|min| max_len >= min).unwrap_or(true)
+            && self.max.map(|max| max_len <= max).unwrap_or(true)
+    }
+}
+
+
+//------------ FilterMetrics -------------------------------------------------
+
+/// The metrics of a filter unit.
+#[derive(Debug, Default)]
+struct FilterMetrics {
+    /// The number of items removed from the last update.
+    removed: AtomicUsize,
+
+    /// The gate metrics.
+    gate: Arc<GateMetrics>,
+}
+
+impl FilterMetrics {
+    const REMOVED_METRIC: Metric = Metric::new(
+        "filter_removed",
+        "the number of items removed from the last update",
+        MetricType::Gauge, MetricUnit::Total
+    );
+}
+
+impl FilterMetrics {
+    fn new(gate: &Gate) -> Self {
+        FilterMetrics {
+            gate: gate.metrics(),
+            ..Default::default()
+        }
+    }
+}
+
+impl metrics::Source for FilterMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::REMOVED_METRIC, Some(unit_name),
+            self.removed.load(Relaxed)
+        );
+        self.gate.append(unit_name, target);
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rpki::resources::addr::MaxLenPrefix;
+
+    fn vrp(prefix: &str, max_len: u8, asn: u32) -> Payload {
+        Payload::origin(
+            MaxLenPrefix::new(
+                prefix.parse::<Prefix>().unwrap(), Some(max_len)
+            ).unwrap(),
+            asn.into()
+        )
+    }
+
+    /// The filter options without the source link.
+    #[derive(Deserialize)]
+    struct Policy {
+        #[serde(default)]
+        rules: Vec<Rule>,
+
+        #[serde(default)]
+        default: Action,
+    }
+
+    impl Policy {
+        fn keeps(&self, payload: &Payload) -> bool {
+            keeps(&self.rules, self.default, payload, None)
+        }
+
+        fn keeps_ta(&self, payload: &Payload, ta: &str) -> bool {
+            keeps(&self.rules, self.default, payload, Some(ta))
+        }
+
+        fn filter(&self, set: &payload::Set) -> payload::Set {
+            filter_set(&self.rules, self.default, set, None)
+        }
+    }
+
+    fn filter(rules: &str) -> Policy {
+        toml::from_str(rules).unwrap()
+    }
+
+    #[test]
+    fn rules() {
+        let filter = filter(r#"
+            [[rules]]
+            action = "keep"
+            asns = [ 64496 ]
+
+            [[rules]]
+            action = "drop"
+            prefixes = [ "10.0.0.0/8", "2001:db8::/32" ]
+
+            [[rules]]
+            action = "drop"
+            family = "ipv4"
+            max-length = { min = 25 }
+
+            [[rules]]
+            action = "drop"
+            asns = [ "AS64497" ]
+        "#);
+
+        // The first rule keeps everything for AS64496.
+        assert!(filter.keeps(&vrp("10.1.0.0/16", 16, 64496)));
+        assert!(filter.keeps(&vrp("192.0.2.0/24", 32, 64496)));
+
+        // The second rule drops covered prefixes.
+        assert!(!filter.keeps(&vrp("10.1.0.0/16", 16, 64511)));
+        assert!(!filter.keeps(&vrp("2001:db8:1::/48", 48, 64511)));
+        assert!(filter.keeps(&vrp("11.0.0.0/8", 8, 64511)));
+
+        // The third rule drops long IPv4 max lengths.
+        assert!(!filter.keeps(&vrp("192.0.2.0/24", 25, 64511)));
+        assert!(filter.keeps(&vrp("192.0.2.0/24", 24, 64511)));
+        assert!(filter.keeps(&vrp("2001:db9::/32", 64, 64511)));
+
+        // The fourth rule drops by ASN.
+        assert!(!filter.keeps(&vrp("192.0.2.0/24", 24, 64497)));
+
+        let set = {
+            let mut res = payload::PackBuilder::empty();
+            res.insert(vrp("10.1.0.0/16", 16, 64496)).unwrap();
+            res.insert(vrp("10.1.0.0/16", 16, 64511)).unwrap();
+            res.insert(vrp("192.0.2.0/24", 24, 64511)).unwrap();
+            payload::Set::from(res.finalize())
+        };
+        assert_eq!(filter.filter(&set).len(), 2);
+    }
+
+    #[test]
+    fn default_action() {
+        let filter = filter(r#"
+            default = "drop"
+
+            [[rules]]
+            action = "keep"
+            family = "ipv6"
+        "#);
+        assert!(filter.keeps(&vrp("2001:db8::/32", 48, 64496)));
+        assert!(!filter.keeps(&vrp("192.0.2.0/24", 24, 64496)));
+    }
+
+    #[test]
+    fn trust_anchors() {
+        let filter = filter(r#"
+            [[rules]]
+            action = "drop"
+            trust-anchors = [ "apnic", "lacnic" ]
+        "#);
+        assert!(!filter.keeps_ta(&vrp("192.0.2.0/24", 24, 64496), "apnic"));
+        assert!(filter.keeps_ta(&vrp("192.0.2.0/24", 24, 64496), "ripe"));
+        assert!(filter.keeps(&vrp("192.0.2.0/24", 24, 64496)));
+
+        let mut tas = payload::TrustAnchorsBuilder::empty();
+        tas.insert(vrp("10.1.0.0/16", 16, 64496), "ripe");
+        tas.insert(vrp("192.0.2.0/24", 24, 64496), "lacnic");
+        let tas = tas.finalize();
+        let set = {
+            let mut res = payload::PackBuilder::empty();
+            res.insert(vrp("10.1.0.0/16", 16, 64496)).unwrap();
+            res.insert(vrp("192.0.2.0/24", 24, 64496)).unwrap();
+            res.insert(vrp("198.51.100.0/24", 24, 64496)).unwrap();
+            payload::Set::from(res.finalize())
+        };
+        assert_eq!(
+            filter_set(&filter.rules, filter.default, &set, Some(&tas)).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn aspa_rules() {
+        use rpki::rtr::payload::Aspa;
+        use rpki::rtr::pdu::ProviderAsns;
+
+        fn aspa(customer: u32, providers: &[u32]) -> Payload {
+            Payload::Aspa(Aspa::new(
+                customer.into(),
+                ProviderAsns::try_from_iter(
+                    providers.iter().copied().map(Asn::from)
+                ).unwrap()
+            ))
+        }
+
+        let filter = filter(r#"
+            [[rules]]
+            action = "drop"
+            providers = [ "AS64510" ]
+
+            [[rules]]
+            action = "keep"
+            types = [ "aspa" ]
+            asns = [ 64496 ]
+
+            [[rules]]
+            action = "drop"
+            types = [ "aspa", "router-key" ]
+        "#);
+
+        // The first rule drops ASPA records listing the provider.
+        assert!(!filter.keeps(&aspa(64496, &[64500, 64510])));
+
+        // The second rule keeps ASPA records of the customer.
+        assert!(filter.keeps(&aspa(64496, &[64500])));
+
+        // The third rule drops all other ASPA records but no origins.
+        assert!(!filter.keeps(&aspa(64497, &[64500])));
+        assert!(filter.keeps(&vrp("192.0.2.0/24", 24, 64510)));
+
+        assert!(
+            toml::from_str::<Rule>(
+                "action = \"drop\"\ntypes = [ \"vrp\" ]"
+            ).is_err()
+        );
+    }
+
+    #[test]
+    fn bad_rules() {
+        assert!(toml::from_str::<MaxLenRange>("minimum = 24").is_err());
+        assert!(toml::from_str::<Rule>("family = \"ipv4\"").is_err());
+        assert!(
+            toml::from_str::<Rule>(
+                "action = \"drop\"\nfamily = \"ipv5\""
+            ).is_err()
+        );
+    }
+}
@@ -0,0 +1,402 @@
+//! Protecting against sudden large changes.
+//!
+//! Upstream validators occasionally produce data sets that differ wildly
+//! from their previous ones, for instance if they lost access to a
+//! repository or a trust anchor. Passing such a data set on to routers can
+//! do more harm than serving slightly outdated data.
+//!
+//! The _guard_ unit compares each update of its source with the data set it
+//! currently serves. If the update changes more items than allowed, either
+//! as an absolute number or relative to the size of the served data set,
+//! the unit keeps serving the old data set and raises an alarm. If a hold
+//! timeout is configured, a refused update is accepted once it has been
+//! held for that long.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+use futures_util::future::pending;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use tokio::time::{sleep_until, Instant};
+use crate::{metrics, payload};
+use crate::comms::{Gate, GateMetrics, Link, Terminated, UnitUpdate};
+use crate::events::Event;
+use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+
+
+//------------ Guard ---------------------------------------------------------
+
+/// A unit refusing updates that change too much.
+#[derive(Debug, Deserialize)]
+pub struct Guard {
+    /// The source to read data from.
+    source: Link,
+
+    /// The limits for changes.
+    #[serde(flatten)]
+    limits: Limits,
+
+    /// The number of seconds to hold a refused update before accepting it.
+    ///
+    /// If this is `None`, refused updates are never accepted.
+    #[serde(rename = "hold-timeout")]
+    hold_timeout: Option<u64>,
+}
+
+impl Guard {
+    pub async fn run(
+        mut self, mut component: Component, mut gate: Gate
+    ) -> Result<(), Terminated> {
+        let metrics = Arc::new(GuardMetrics::new(&gate));
+        component.register_metrics(metrics.clone());
+
+        // The data set we are currently serving.
+        let mut served: Option<payload::Set> = None;
+
+        // The update we are holding back and since when we do so.
+        let mut held: Option<(payload::Update, Instant)> = None;
+
+        loop {
+            let timeout = held.as_ref().and_then(|(_, since)| {
+                self.hold_timeout.map(|timeout| {
+                    *since + Duration::from_secs(timeout)
+                })
+            });
+            let update = tokio::select! {
+                update = self.source.query() => update,
+                res = gate.process() => {
+                    res?;
+                    continue
+                }
+                _ = async {
+                    match timeout {
+                        Some(timeout) => sleep_until(timeout).await,
+                        None => pending().await,
+                    }
+                } => {
+                    // We only get here if there is a held update.
+                    if let Some((update, _)) = held.take() {
+                        info!(
+                            "Unit {}: accepting held update after the hold \
+                             timeout expired.",
+                            component.name()
+                        );
+                        metrics.holding.store(false, Relaxed);
+                        served = Some(update.set().clone());
+                        gate.update(UnitUpdate::Payload(update)).await;
+                    }
+                    continue
+                }
+            };
+            match update {
+                UnitUpdate::Payload(update) => {
+                    let change = served.as_ref().map(|served| {
+                        Change::new(&update, served)
+                    });
+                    if let Some(err) = change.and_then(|change| {
+                        self.limits.violation(&change)
+                    }) {
+                        warn!(
+                            "Unit {}: holding back update: {}",
+                            component.name(), err
+                        );
+                        metrics.refused.fetch_add(1, Relaxed);
+                        metrics.holding.store(true, Relaxed);
+                        component.notifier().notify(Event::GuardTriggered {
+                            component: component.name().to_string(),
+                            guard: "change-limit",
+                            detail: err,
+                        });
+                        let since = held.take().map(|(_, since)| {
+                            since
+                        }).unwrap_or_else(Instant::now);
+                        held = Some((update, since));
+                        continue
+                    }
+                    if held.take().is_some() {
+                        info!(
+                            "Unit {}: update within limits, releasing hold.",
+                            component.name()
+                        );
+                        metrics.holding.store(false, Relaxed);
+                    }
+                    else {
+                        debug!(
+                            "Unit {}: update within limits.",
+                            component.name()
+                        );
+                    }
+                    served = Some(update.set().clone());
+                    gate.update(UnitUpdate::Payload(update)).await;
+                }
+                UnitUpdate::Stalled => {
+                    gate.update(UnitUpdate::Stalled).await;
+                }
+                UnitUpdate::Gone => {
+                    gate.update(UnitUpdate::Gone).await;
+                    return Ok(())
+                }
+            }
+        }
+    }
+}
+
+
+//------------ Limits --------------------------------------------------------
+
+/// The limits for the changes of an update.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+struct Limits {
+    /// The maximum number of items added or removed.
+    #[serde(rename = "max-changes")]
+    max_changes: Option<usize>,
+
+    /// The maximum number of items added or removed relative to the set.
+    #[serde(rename = "max-change-ratio")]
+    max_change_ratio: Option<Ratio>,
+
+    /// The maximum number of items removed.
+    #[serde(rename = "max-withdrawals")]
+    max_withdrawals: Option<usize>,
+
+    /// The maximum number of items removed relative to the set.
+    #[serde(rename = "max-withdrawal-ratio")]
+    max_withdrawal_ratio: Option<Ratio>,
+}
+
+impl Limits {
+    /// Returns a description of the first limit violated by a change.
+    ///
+    /// Returns `None` if the change is within all limits.
+    fn violation(&self, change: &Change) -> Option<String> {
+        if let Some(max) = self.max_changes {
+            if change.changes() > max {
+                return Some(format!(
+                    "{} changed items exceed the maximum of {}",
+                    change.changes(), max
+                ))
+            }
+        }
+        if let Some(max) = self.max_withdrawals {
+            if change.withdrawn > max {
+                return Some(format!(
+                    "{} withdrawn items exceed the maximum of {}",
+                    change.withdrawn, max
+                ))
+            }
+        }
+        if let Some(Ratio(max)) = self.max_change_ratio {
+            let ratio = change.ratio(change.changes());
+            if ratio > max {
+                return Some(format!(
+                    "changes of {:.1}% exceed the maximum of {:.1}%",
+                    ratio * 100., max * 100.
+                ))
+            }
+        }
+        if let Some(Ratio(max)) = self.max_withdrawal_ratio {
+            let ratio = change.ratio(change.withdrawn);
+            if ratio > max {
+                return Some(format!(
+                    "withdrawals of {:.1}% exceed the maximum of {:.1}%",
+                    ratio * 100., max * 100.
+                ))
+            }
+        }
+        None
+    }
+}
+
+
+//------------ Ratio ---------------------------------------------------------
+
+/// A limit relative to the size of the served data set.
+///
+/// A ratio must be a finite, non-negative number. This is checked when
+/// the configuration is loaded.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "f64")]
+struct Ratio(f64);
+
+impl TryFrom<f64> for Ratio {
+    type Error = String;
+
+    fn try_from(ratio: f64) -> Result<Self, Self::Error> {
+        if !ratio.is_finite() || ratio < 0. {
+            return Err(format!(
+                "invalid ratio {}, expected a non-negative number", ratio
+            ))
+        }
+        Ok(Ratio(ratio))
+    }
+}
+
+
+//------------ Change --------------------------------------------------------
+
+/// The size of the change an update would make to the served data set.
+#[derive(Clone, Copy, Debug)]
+struct Change {
+    /// The number of items in the served data set.
+    served: usize,
+
+    /// The number of items added.
+    announced: usize,
+
+    /// The number of items removed.
+    withdrawn: usize,
+}
+
+impl Change {
+    /// Determines the change made by an update to the served set.
+    fn new(update: &payload::Update, served: &payload::Set) -> Self {
+        let diff = update.diff_since(served);
+        Change {
+            served: served.len(),
+            announced: diff.announced().len(),
+            withdrawn: diff.withdrawn().len(),
+        }
+    }
+
+    /// Returns the total number of changed items.
+    fn changes(&self) -> usize {
+        self.announced + self.withdrawn
+    }
+
+    /// Returns a number of items relative to the served data set.
+    fn ratio(&self, count: usize) -> f64 {
+        count as f64 / self.served.max(1) as f64
+    }
+}
+
+
+//------------ GuardMetrics --------------------------------------------------
+
+/// The metrics of a guard unit.
+#[derive(Debug, Default)]
+struct GuardMetrics {
+    /// The number of updates refused.
+    refused: AtomicU64,
+
+    /// Whether an update is currently being held back.
+    holding: AtomicBool,
+
+    /// The gate metrics.
+    gate: Arc<GateMetrics>,
+}
+
+impl GuardMetrics {
+    const REFUSED_METRIC: Metric = Metric::new(
+        "guard_refused",
+        "the number of updates refused for changing too much",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const HOLDING_METRIC: Metric = Metric::new(
+        "guard_holding",
+        "whether an update is currently being held back",
+        MetricType::Gauge, MetricUnit::Info
+    );
+}
+
+impl GuardMetrics {
+    fn new(gate: &Gate) -> Self {
+        GuardMetrics {
+            gate: gate.metrics(),
+            ..Default::default()
+        }
+    }
+}
+
+impl metrics::Source for GuardMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::REFUSED_METRIC, Some(unit_name),
+            self.refused.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::HOLDING_METRIC, Some(unit_name),
+            u8::from(self.holding.load(Relaxed))
+        );
+        self.gate.append(unit_name, target);
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn change(served: usize, announced: usize, withdrawn: usize) -> Change {
+        Change { served, announced, withdrawn }
+    }
+
+    #[test]
+    fn limits() {
+        let limits = Limits::default();
+        assert!(limits.violation(&change(10, 100, 10)).is_none());
+
+        let limits = Limits {
+            max_changes: Some(10), .. Default::default()
+        };
+        assert!(limits.violation(&change(100, 5, 5)).is_none());
+        assert!(limits.violation(&change(100, 6, 5)).is_some());
+
+        let limits = Limits {
+            max_withdrawals: Some(10), .. Default::default()
+        };
+        assert!(limits.violation(&change(100, 50, 10)).is_none());
+        assert!(limits.violation(&change(100, 0, 11)).is_some());
+
+        let limits = Limits {
+            max_change_ratio: Some(Ratio(0.2)), .. Default::default()
+        };
+        assert!(limits.violation(&change(100, 10, 10)).is_none());
+        assert!(limits.violation(&change(100, 11, 10)).is_some());
+
+        let limits = Limits {
+            max_withdrawal_ratio: Some(Ratio(0.2)), .. Default::default()
+        };
+        assert!(limits.violation(&change(100, 100, 20)).is_none());
+        assert!(limits.violation(&change(100, 0, 21)).is_some());
+        assert!(limits.violation(&change(0, 1, 0)).is_none());
+    }
+
+    #[test]
+    fn ratios() {
+        let limits: Limits = toml::from_str(r#"
+            max-change-ratio = 0.2
+            max-withdrawal-ratio = 1
+        "#).unwrap();
+        assert_eq!(limits.max_change_ratio, Some(Ratio(0.2)));
+        assert_eq!(limits.max_withdrawal_ratio, Some(Ratio(1.)));
+
+        // Invalid ratios are rejected when loading the configuration.
+        for ratio in ["-1.0", "-0.1", "nan", "inf"] {
+            assert!(toml::from_str::<Limits>(&format!(
+                "max-change-ratio = {}", ratio
+            )).is_err());
+            assert!(toml::from_str::<Limits>(&format!(
+                "max-withdrawal-ratio = {}", ratio
+            )).is_err());
+        }
+    }
+
+    #[test]
+    fn changes() {
+        use crate::payload::testrig;
+
+        let served = payload::Set::from(testrig::pack([1, 2, 3, 4]));
+        let update = payload::Update::new(testrig::pack([3, 4, 5]).into());
+        let change = Change::new(&update, &served);
+        assert_eq!(change.served, 4);
+        assert_eq!(change.announced, 1);
+        assert_eq!(change.withdrawn, 2);
+        assert_eq!(change.changes(), 3);
+        assert_eq!(change.ratio(2), 0.5);
+    }
+}
@@ -0,0 +1,198 @@
+//! Data sets given directly in the configuration.
+//!
+//! The _static_ unit produces a fixed data set of VRPs and ASPA records
+//! listed in its configuration. It publishes this data set once when
+//! started and never changes it. This is useful for lab setups, for beacon
+//! prefixes used in health checks, and as a known source in tests without
+//! having to maintain a separate SLURM file.
+
+use rpki::resources::addr::{MaxLenPrefix, Prefix};
+use rpki::resources::asn::Asn;
+use rpki::rtr::payload::{Aspa, Payload, RouteOrigin};
+use rpki::rtr::pdu::ProviderAsns;
+use serde::Deserialize;
+use crate::payload;
+use crate::comms::{Gate, Terminated, UnitUpdate};
+use crate::manager::Component;
+
+
+//------------ Static --------------------------------------------------------
+
+/// A unit providing a data set given in the configuration.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Static {
+    /// The VRPs of the data set.
+    #[serde(default)]
+    vrps: Vec<StaticVrp>,
+
+    /// The ASPA records of the data set.
+    #[serde(default)]
+    aspas: Vec<StaticAspa>,
+}
+
+impl Static {
+    pub async fn run(
+        self, mut component: Component, mut gate: Gate
+    ) -> Result<(), Terminated> {
+        let metrics = gate.metrics();
+        component.register_metrics(metrics.clone());
+        gate.update(
+            UnitUpdate::Payload(payload::Update::new(self.into_set()))
+        ).await;
+        loop {
+            gate.process().await?;
+        }
+    }
+
+    /// Converts the configured items into a data set.
+    ///
+    /// Items listed more than once are only included once.
+    fn into_set(self) -> payload::Set {
+        let mut res = payload::PackBuilder::empty();
+        for item in self.vrps {
+            res.insert_unchecked(Payload::Origin(item.0));
+        }
+        for item in self.aspas {
+            res.insert_unchecked(Payload::Aspa(item.0));
+        }
+        res.finalize().into()
+    }
+}
+
+
+//------------ StaticVrp -----------------------------------------------------
+
+/// A VRP given in the configuration.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "VrpConfig")]
+struct StaticVrp(RouteOrigin);
+
+impl TryFrom<VrpConfig> for StaticVrp {
+    type Error = String;
+
+    fn try_from(config: VrpConfig) -> Result<Self, Self::Error> {
+        let prefix = MaxLenPrefix::new(
+            config.prefix, config.max_length
+        ).map_err(|_| {
+            format!(
+                "invalid max-length {} for prefix {}",
+                config.max_length.unwrap_or_default(), config.prefix
+            )
+        })?;
+        Ok(StaticVrp(RouteOrigin::new(prefix, config.asn)))
+    }
+}
+
+/// The configuration of a VRP.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VrpConfig {
+    /// The prefix.
+    prefix: Prefix,
+
+    /// The origin AS number.
+    #[serde(deserialize_with = "Asn::deserialize_from_any")]
+    asn: Asn,
+
+    /// The maximum prefix length.
+    ///
+    /// If this is missing, the length of the prefix is used.
+    #[serde(rename = "max-length")]
+    max_length: Option<u8>,
+}
+
+
+//------------ StaticAspa ----------------------------------------------------
+
+/// An ASPA record given in the configuration.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "AspaConfig")]
+struct StaticAspa(Aspa);
+
+impl TryFrom<AspaConfig> for StaticAspa {
+    type Error = String;
+
+    fn try_from(config: AspaConfig) -> Result<Self, Self::Error> {
+        let mut providers = config.providers.into_iter().map(|item| {
+            item.0
+        }).collect::<Vec<_>>();
+        providers.sort();
+        providers.dedup();
+        let providers = ProviderAsns::try_from_iter(
+            providers
+        ).map_err(|_| String::from("too many ASPA providers"))?;
+        Ok(StaticAspa(Aspa::new(config.customer, providers)))
+    }
+}
+
+/// The configuration of an ASPA record.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AspaConfig {
+    /// The customer AS number.
+    #[serde(deserialize_with = "Asn::deserialize_from_any")]
+    customer: Asn,
+
+    /// The provider AS numbers.
+    providers: Vec<AsnConfig>,
+}
+
+/// An AS number given as a number or a string.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(transparent)]
+struct AsnConfig(
+    #[serde(deserialize_with = "Asn::deserialize_from_any")]
+    Asn
+);
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn into_set() {
+        let unit: Static = toml::from_str(r#"
+            [[vrps]]
+            prefix = "192.0.2.0/24"
+            asn = 64496
+
+            [[vrps]]
+            prefix = "192.0.2.0/24"
+            asn = 64496
+
+            [[vrps]]
+            prefix = "2001:db8::/32"
+            asn = "AS64497"
+            max-length = 48
+
+            [[aspas]]
+            customer = 64496
+            providers = [ 64498, "AS64497", 64498 ]
+        "#).unwrap();
+        let set = unit.into_set();
+        assert_eq!(set.len(), 3);
+        let aspa = set.iter().find_map(|item| match item {
+            Payload::Aspa(aspa) => Some(aspa.clone()),
+            _ => None
+        }).unwrap();
+        assert_eq!(aspa.customer, Asn::from(64496));
+        assert_eq!(
+            aspa.providers.iter().collect::<Vec<_>>(),
+            [Asn::from(64497), Asn::from(64498)]
+        );
+
+        assert!(toml::from_str::<Static>(r#"
+            [[vrps]]
+            prefix = "192.0.2.0/24"
+            asn = 64496
+            max-length = 16
+        "#).is_err());
+        assert!(toml::from_str::<Static>(r#"
+            vrps = [ { prefix = "192.0.2.0/24", origin = 64496 } ]
+        "#).is_err());
+    }
+}
@@ -1,25 +1,40 @@
 //! JSON clients.
+//!
+//! Despite its name, the _json_ unit also understands the OpenBGPD
+//! configuration format written by rpki-client. Which format a source is
+//! in can be given explicitly or is detected from its first character.
 
-use std::{cmp, fs, io};
+use std::{cmp, fmt, fs, io};
+use std::collections::{BTreeMap, VecDeque};
 use std::fs::metadata;
+use std::io::Read;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
 use std::time::{Duration, SystemTime};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, SecondsFormat, Utc};
 use bytes::{Buf, Bytes, BytesMut};
+use crossbeam_utils::atomic::AtomicCell;
 use daemonbase::config::ConfigPath;
 use daemonbase::error::Failed;
 use log::{debug, error, warn};
 use reqwest::{header, tls};
-use reqwest::{StatusCode, Url};
+use reqwest::{Method, StatusCode, Url};
+use ring::digest;
 use serde::Deserialize;
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 use tokio::task::spawn_blocking;
 use tokio::time::{Instant, timeout_at};
-use crate::payload;
-use crate::comms::{Gate, Terminated, UnitUpdate};
-use crate::formats::json::Set as JsonSet;
+use crate::{metrics, payload};
+use crate::cache::UnitCache;
+use crate::comms::{Gate, GateMetrics, Terminated, UnitUpdate};
+use crate::formats::bgpd::Set as BgpdSet;
+use crate::formats::json::{Metadata, Set as JsonSet};
 use crate::manager::Component;
+use crate::metrics::{Histogram, Metric, MetricType, MetricUnit};
+use crate::utils::breaker::{DEGRADED_AFTER, FileBreaker};
 use crate::utils::http::{format_http_date, parse_http_date};
 
 
@@ -37,38 +52,190 @@ pub struct Json {
     /// Path to a file with a client certificate and private key.
     #[serde(default, deserialize_with = "deserialize_identity")]
     identity: Option<ConfigPath>,
+
+    /// The expected SHA-256 digest of the source.
+    sha256: Option<Sha256>,
+
+    /// The URI of a companion file containing the SHA-256 digest.
+    #[serde(rename = "sha256-uri")]
+    sha256_uri: Option<SourceUri>,
+
+    /// The format of the source.
+    #[serde(default)]
+    format: InputFormat,
+
+    /// Whether to check the content type and the schema strictly.
+    #[serde(default)]
+    strict: bool,
+
+    /// Whether to keep the trust anchors of the VRPs.
+    #[serde(default, rename = "trust-anchors")]
+    trust_anchors: bool,
+
+    /// The HTTP method to use for requests to the source.
+    #[serde(default)]
+    method: HttpMethod,
+
+    /// The body to send with requests to the source.
+    body: Option<Template>,
+
+    /// Additional query parameters for requests to the source.
+    #[serde(default)]
+    query: BTreeMap<String, Template>,
+
+    /// Additional headers for requests to the source.
+    #[serde(default)]
+    headers: BTreeMap<String, Template>,
 }
 
 impl Json {
     pub async fn run(
         self, mut component: Component, mut gate: Gate
     ) -> Result<(), Terminated> {
-        component.register_metrics(gate.metrics());
+        let metrics = Arc::new(JsonMetrics::new(&gate));
+        component.register_metrics(metrics.clone());
         let mut source = self.create_source(&component)?;
+        let checksum = self.create_checksum(&source, &component)?;
+        let cache = component.data_cache();
+        if let Some(cache) = cache.as_ref() {
+            cache.restore(component.name(), &mut gate).await;
+        }
         loop {
-            self.step(&mut source, &component, &mut gate).await?;
+            self.step(
+                &mut source, checksum.as_ref(), cache.as_ref(), &component,
+                &mut gate, &metrics
+            ).await?;
             self.wait(&mut gate).await?;
         }
     }
 
     fn create_source(
         &self, component: &Component
-    ) -> Result<Source, Terminated> {
+    ) -> Result<Source<'_>, Terminated> {
         match self.uri {
             SourceUri::Http(ref url) => {
                 Ok(Source::Http {
                     url,
+                    request: self.request_template(component)?,
                     client: self.http_client(component)?,
                     last_modified: None,
                     etag: None,
                 })
             }
             SourceUri::File(ref path) => {
-                Ok(Source::File { path, last_modified: None })
+                if self.has_request_options() {
+                    error!(
+                        "Unit {}: request options can only be used with \
+                         HTTP sources.",
+                        component.name()
+                    );
+                    return Err(Terminated)
+                }
+                Ok(Source::File {
+                    path,
+                    last_modified: None,
+                    breaker: FileBreaker::new(
+                        Duration::from_secs(self.refresh)
+                    ),
+                })
             }
         }
     }
 
+    /// Returns whether any of the HTTP request options have been given.
+    fn has_request_options(&self) -> bool {
+        self.method.0 != Method::GET || self.body.is_some()
+            || !self.query.is_empty() || !self.headers.is_empty()
+    }
+
+    /// Creates the template for HTTP requests to the source.
+    fn request_template(
+        &self, component: &Component
+    ) -> Result<RequestTemplate<'_>, Terminated> {
+        let headers = self.headers.iter().map(|(name, value)| {
+            let name = header::HeaderName::from_str(name).map_err(|_| {
+                error!(
+                    "Unit {}: invalid header name '{}'.",
+                    component.name(), name
+                );
+                Terminated
+            })?;
+            // Compression is negotiated by the client itself and responses
+            // wouldn’t be decompressed if the header was overridden.
+            if name == header::ACCEPT_ENCODING {
+                error!(
+                    "Unit {}: the Accept-Encoding header cannot be set.",
+                    component.name()
+                );
+                return Err(Terminated)
+            }
+            Ok((name, value))
+        }).collect::<Result<_, _>>()?;
+        Ok(RequestTemplate {
+            method: self.method.0.clone(),
+            body: self.body.as_ref(),
+            query: &self.query,
+            headers,
+        })
+    }
+
+    fn create_checksum<'a>(
+        &'a self, source: &Source, component: &Component
+    ) -> Result<Option<Checksum<'a>>, Terminated> {
+        match (self.sha256, self.sha256_uri.as_ref()) {
+            (None, None) => Ok(None),
+            (Some(sha256), None) => Ok(Some(Checksum::Inline(sha256))),
+            (None, Some(SourceUri::File(path))) => {
+                Ok(Some(Checksum::File(path)))
+            }
+            (None, Some(SourceUri::Http(url))) => {
+                // Re-use the source’s client if there is one.
+                let client = match source {
+                    Source::Http { client, .. } => client.clone(),
+                    Source::File { .. } => self.http_client(component)?,
+                };
+                Ok(Some(Checksum::Http { url, client }))
+            }
+            (Some(_), Some(_)) => {
+                error!(
+                    "Unit {}: only one of 'sha256' and 'sha256-uri' \
+                     can be given.",
+                    component.name()
+                );
+                Err(Terminated)
+            }
+        }
+    }
+
+    /// Checks the parts of the configuration only used at run time.
+    ///
+    /// This loads the client identity if one is configured.
+    pub fn check(&self, unit_name: &str) -> Result<(), Failed> {
+        self.load_identity(unit_name).map(|_| ()).map_err(|_| Failed)
+    }
+
+    /// Loads the client identity if one is configured.
+    fn load_identity(
+        &self, unit_name: &str
+    ) -> Result<Option<tls::Identity>, Terminated> {
+        let identity = match self.identity.as_ref() {
+            Some(identity) => identity,
+            None => return Ok(None)
+        };
+        let data = fs::read(identity).map_err(|err| {
+            error!("Unit {}: cannot read identity file {}: {}",
+                unit_name, identity.display(), err
+            );
+            Terminated
+        })?;
+        tls::Identity::from_pem(&data).map(Some).map_err(|err| {
+            error!("Unit {}: cannot parse identity file {}: {}",
+                unit_name, identity.display(), err
+            );
+            Terminated
+        })
+    }
+
     fn http_client(
         &self, component: &Component
     ) -> Result<reqwest::Client, Terminated> {
@@ -76,24 +243,17 @@ impl Json {
             error!("Unit {}: {}", component.name(), err);
             Terminated
         })?;
-        if let Some(identity) = self.identity.as_ref() {
-            let data = fs::read(identity).map_err(|err| {
-                error!("Unit {}: cannot read identity file {}: {}",
-                    component.name(), identity.display(), err
-                );
-                Terminated
-            })?;
-            let identity = tls::Identity::from_pem(&data).map_err(|err| {
-                error!("Unit {}: cannot parse identity file {}: {}",
-                    component.name(), identity.display(), err
-                );
-                Terminated
-            })?;
+        if let Some(identity) = self.load_identity(component.name())? {
             builder = builder.identity(identity);
             debug!("Unit {}: successfully loaded client certificate.",
                 component.name()
             );
         }
+
+        // Ask for compressed responses. They are decompressed transparently
+        // before being parsed or checked against the digest.
+        builder = builder.gzip(true).deflate(true).brotli(true);
+
         builder.build().map_err(|err| {
             error!("Unit {}: Failed to initialize HTTP client: {}.",
                 component.name(), err
@@ -105,12 +265,32 @@ impl Json {
     async fn step(
         &self,
         source: &mut Source<'_>,
+        checksum: Option<&Checksum<'_>>,
+        cache: Option<&UnitCache>,
         component: &Component,
-        gate: &mut Gate
+        gate: &mut Gate,
+        metrics: &JsonMetrics,
     ) -> Result<(), Terminated> {
-        match gate.process_until(self.fetch_json(source, component)).await? {
+        if !source.permits() {
+            debug!(
+                "Unit {}: source keeps failing, skipping this attempt.",
+                component.name()
+            );
+            return Ok(())
+        }
+        let start = Instant::now();
+        let res = gate.process_until(
+            self.fetch_json(source, checksum, component, metrics)
+        ).await?;
+        metrics.fetch_duration.observe(start.elapsed());
+        match res {
             Ok(Some(res)) => {
+                source.record_success(metrics);
+                let set = res.set().clone();
                 if gate.update(UnitUpdate::Payload(res)).await {
+                    if let Some(cache) = cache {
+                        cache.store(&set);
+                    }
                     debug!(
                         "Unit {}: successfully updated.",
                         component.name()
@@ -128,6 +308,7 @@ impl Json {
                 // to do, really.
             }
             Err(Failed) => {
+                source.record_failure(component, metrics);
                 if gate.update(UnitUpdate::Stalled).await {
                     debug!(
                         "Unit {}: marked as stalled.",
@@ -140,28 +321,118 @@ impl Json {
     }
 
     async fn fetch_json(
-        &self, source: &mut Source<'_>, component: &Component
+        &self,
+        source: &mut Source<'_>,
+        checksum: Option<&Checksum<'_>>,
+        component: &Component,
+        metrics: &JsonMetrics,
     ) -> Result<Option<payload::Update>, Failed> {
-        let reader = match SourceReader::open(source, component).await? {
+        // The content type is only checked for JSON.
+        let strict = self.strict && self.format != InputFormat::RoaSet;
+        let mut reader = match SourceReader::open(
+            source, strict, component
+        ).await? {
             Some(reader) => reader,
             None => {
                 debug!("Unit {}: Source not modified.", component.name());
                 return Ok(None)
             }
         };
+        let expected = match checksum {
+            Some(checksum) => Some(checksum.fetch(component).await?),
+            None => None,
+        };
+        let format = self.format;
         match spawn_blocking(move || {
-            serde_json::from_reader::<_, JsonSet>(reader)
+            // If we have a checksum, we need to read all the data first
+            // and check it before parsing.
+            match expected {
+                Some(expected) => {
+                    let mut data = Vec::new();
+                    reader.read_to_end(&mut data).map_err(|err| {
+                        (err.to_string(), String::new())
+                    })?;
+                    let actual = Sha256::digest(&data);
+                    if actual != expected {
+                        return Ok(Err((expected, actual)))
+                    }
+                    let first = data.iter().copied().find(|ch| {
+                        !ch.is_ascii_whitespace()
+                    });
+                    if format.is_json(first) {
+                        serde_json::from_slice::<JsonSet>(&data).map(|set| {
+                            Ok(SourceSet::Json(set))
+                        }).map_err(|err| {
+                            let snippet = snippet_at(
+                                &data, err.line(), err.column()
+                            );
+                            (err.to_string(), snippet)
+                        })
+                    }
+                    else {
+                        SourceSet::roa_set(&data)
+                    }
+                }
+                None => {
+                    let first = reader.peek().map_err(|err| {
+                        (err.to_string(), String::new())
+                    })?;
+                    if format.is_json(first) {
+                        serde_json::from_reader::<_, JsonSet>(
+                            &mut reader
+                        ).map(|set| Ok(SourceSet::Json(set))).map_err(|err| {
+                            (err.to_string(), reader.snippet())
+                        })
+                    }
+                    else {
+                        let mut data = Vec::new();
+                        reader.read_to_end(&mut data).map_err(|err| {
+                            (err.to_string(), String::new())
+                        })?;
+                        SourceSet::roa_set(&data)
+                    }
+                }
+            }
         }).await {
-            Ok(Ok(res)) => {
+            Ok(Ok(Ok(SourceSet::Json(res)))) => {
+                self.process_json(res, component, metrics).map(Some)
+            }
+            Ok(Ok(Ok(SourceSet::RoaSet(res)))) => {
+                // The format has neither metadata nor trust anchors.
+                metrics.upstream_count.store(None);
+                metrics.upstream_generated.store(None);
+                metrics.upstream_serial.store(None);
+                metrics.trust_anchors.lock().unwrap().clear();
                 Ok(Some(payload::Update::new(res.into_payload())))
             }
-            Ok(Err(err)) => {
-                // Joining succeded but JSON parsing didn’t.
-                warn!(
-                    "Unit {}: Failed parsing source: {}",
-                    component.name(),
-                    err
+            Ok(Ok(Err((expected, actual)))) => {
+                error!(
+                    "Unit {}: checksum mismatch for source: \
+                     expected SHA-256 {}, got {}.",
+                    component.name(), expected, actual
                 );
+                metrics.checksum_mismatches.fetch_add(1, Relaxed);
+
+                // Make sure we fetch the data again next time.
+                source.forget_modified();
+                Err(Failed)
+            }
+            Ok(Err((err, snippet))) => {
+                // Joining succeded but parsing didn’t.
+                if snippet.is_empty() {
+                    warn!(
+                        "Unit {}: Failed parsing source: {}",
+                        component.name(),
+                        err
+                    );
+                }
+                else {
+                    warn!(
+                        "Unit {}: Failed parsing source: {} after {:?}",
+                        component.name(),
+                        err, snippet
+                    );
+                }
                 Err(Failed)
             }
             Err(err) => {
@@ -173,7 +444,7 @@ impl Json {
                 // broken.
                 if err.is_panic() {
                     warn!(
-                        "Unit {}: Failed parsing source: JSON parser panicked.",
+                        "Unit {}: Failed parsing source: parser panicked.",
                         component.name(),
                     );
                 }
@@ -189,6 +460,70 @@ impl Json {
         }
     }
 
+    /// Processes a JSON formatted data set.
+    ///
+    /// Checks the schema in strict mode and converts the data set into an
+    /// update.
+    fn process_json(
+        &self, res: JsonSet, component: &Component, metrics: &JsonMetrics
+    ) -> Result<payload::Update, Failed> {
+        if self.strict {
+            if let Err(err) = res.check_schema() {
+                warn!(
+                    "Unit {}: source violates strict schema: {}.",
+                    component.name(), err
+                );
+                return Err(Failed)
+            }
+        }
+        Self::check_metadata(&res, component, metrics);
+        let trust_anchors = if self.trust_anchors {
+            res.trust_anchors()
+        }
+        else {
+            None
+        };
+        let update = payload::Update::new(res.into_payload());
+        match trust_anchors {
+            Some(trust_anchors) => {
+                *metrics.trust_anchors.lock().unwrap() =
+                    trust_anchors.counts(update.set()).into_iter()
+                    .map(|(name, count)| (name.into(), count))
+                    .collect();
+                Ok(update.with_trust_anchors(trust_anchors))
+            }
+            None => {
+                metrics.trust_anchors.lock().unwrap().clear();
+                Ok(update)
+            }
+        }
+    }
+
+    /// Processes the metadata of a data set.
+    ///
+    /// Updates the metrics and warns if the reported number of VRPs differs
+    /// from the number actually contained in the data set.
+    fn check_metadata(
+        set: &JsonSet, component: &Component, metrics: &JsonMetrics
+    ) {
+        let metadata = set.metadata();
+        let count = metadata.and_then(Metadata::count);
+        metrics.upstream_count.store(count);
+        metrics.upstream_generated.store(
+            metadata.and_then(Metadata::generated)
+        );
+        metrics.upstream_serial.store(metadata.and_then(Metadata::serial));
+        if let Some(count) = count {
+            if count != set.len() as u64 {
+                warn!(
+                    "Unit {}: source reports {} VRPs but contains {}.",
+                    component.name(), count, set.len()
+                );
+                metrics.count_mismatches.fetch_add(1, Relaxed);
+            }
+        }
+    }
+
     async fn wait(&self, gate: &mut Gate) -> Result<(), Terminated> {
         let end = Instant::now() + Duration::from_secs(self.refresh);
         while end > Instant::now() {
@@ -204,6 +539,65 @@ impl Json {
 }
 
 
+//------------ InputFormat ---------------------------------------------------
+
+/// The format of the source.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+enum InputFormat {
+    /// Detect the format from the first character.
+    #[default]
+    #[serde(rename = "auto")]
+    Auto,
+
+    /// One of the JSON flavours.
+    #[serde(rename = "json")]
+    Json,
+
+    /// The OpenBGPD configuration format.
+    #[serde(rename = "roa-set")]
+    RoaSet,
+}
+
+impl InputFormat {
+    /// Returns whether the source is to be parsed as JSON.
+    ///
+    /// The `first` argument is the first character of the source that
+    /// isn’t white space. A JSON source always starts with an object.
+    fn is_json(self, first: Option<u8>) -> bool {
+        match self {
+            InputFormat::Auto => first == Some(b'{'),
+            InputFormat::Json => true,
+            InputFormat::RoaSet => false,
+        }
+    }
+}
+
+
+//------------ SourceSet -----------------------------------------------------
+
+/// A data set parsed from the source.
+enum SourceSet {
+    /// A JSON formatted data set.
+    Json(JsonSet),
+
+    /// An OpenBGPD formatted data set.
+    RoaSet(BgpdSet),
+}
+
+impl SourceSet {
+    /// Parses an OpenBGPD formatted data set.
+    ///
+    /// The error is returned in the same form as JSON parse errors.
+    fn roa_set(
+        data: &[u8]
+    ) -> Result<Result<Self, (Sha256, Sha256)>, (String, String)> {
+        BgpdSet::from_slice(data).map(|set| {
+            Ok(SourceSet::RoaSet(set))
+        }).map_err(|err| (err.to_string(), String::new()))
+    }
+}
+
+
 //------------ SourceUri -----------------------------------------------------
 
 /// The URI of the unit’s source.
@@ -235,6 +629,7 @@ impl TryFrom<String> for SourceUri {
 enum Source<'a> {
     Http {
         url: &'a Url,
+        request: RequestTemplate<'a>,
         client: reqwest::Client,
         last_modified: Option<DateTime<Utc>>,
         etag: Option<Bytes>,
@@ -242,6 +637,516 @@ enum Source<'a> {
     File {
         path: &'a ConfigPath,
         last_modified: Option<SystemTime>,
+        breaker: FileBreaker,
+    }
+}
+
+
+impl Source<'_> {
+    /// Forgets what we know about the last modification of the source.
+    ///
+    /// This will cause the next fetch to happen unconditionally.
+    fn forget_modified(&mut self) {
+        match self {
+            Source::Http { last_modified, etag, .. } => {
+                *last_modified = None;
+                *etag = None;
+            }
+            Source::File { last_modified, .. } => {
+                *last_modified = None;
+            }
+        }
+    }
+
+    /// Returns whether the source should be fetched now.
+    ///
+    /// This is always the case for HTTP sources. Local files that keep
+    /// failing are retried less often until they change.
+    fn permits(&self) -> bool {
+        match self {
+            Source::Http { .. } => true,
+            Source::File { path, breaker, .. } => breaker.permits(path),
+        }
+    }
+
+    /// Records that the source was fetched successfully.
+    fn record_success(&mut self, metrics: &JsonMetrics) {
+        if let Source::File { breaker, .. } = self {
+            breaker.success();
+            metrics.file_failures.store(Some(0));
+        }
+    }
+
+    /// Records that fetching the source failed.
+    fn record_failure(
+        &mut self, component: &Component, metrics: &JsonMetrics
+    ) {
+        if let Source::File { path, breaker, .. } = self {
+            if breaker.failure(*path) && breaker.is_degraded() {
+                warn!(
+                    "Unit {}: file {} failed {} times in a row. Retrying \
+                     less often until it changes.",
+                    component.name(), path.display(), breaker.failures()
+                );
+            }
+            metrics.file_failures.store(Some(breaker.failures()));
+        }
+    }
+}
+
+
+//------------ RequestTemplate -----------------------------------------------
+
+/// The configured properties of HTTP requests to the source.
+#[derive(Clone, Debug)]
+struct RequestTemplate<'a> {
+    /// The request method.
+    method: Method,
+
+    /// The request body if there is one.
+    body: Option<&'a Template>,
+
+    /// Additional query parameters.
+    query: &'a BTreeMap<String, Template>,
+
+    /// Additional headers.
+    headers: Vec<(header::HeaderName, &'a Template)>,
+}
+
+impl RequestTemplate<'_> {
+    /// Creates a request builder for the given time.
+    fn build(
+        &self, url: &Url, client: &reqwest::Client, now: DateTime<Utc>
+    ) -> reqwest::RequestBuilder {
+        let mut url = url.clone();
+        if !self.query.is_empty() {
+            let mut pairs = url.query_pairs_mut();
+            for (name, value) in self.query {
+                pairs.append_pair(name, &value.expand(now));
+            }
+        }
+        let mut request = client.request(self.method.clone(), url);
+        for (name, value) in &self.headers {
+            request = request.header(name.clone(), value.expand(now));
+        }
+        if let Some(body) = self.body {
+            if !self.headers.iter().any(|(name, _)| {
+                *name == header::CONTENT_TYPE
+            }) {
+                request = request.header(
+                    header::CONTENT_TYPE, "application/json"
+                );
+            }
+            request = request.body(body.expand(now));
+        }
+        request
+    }
+
+    /// Returns whether conditional requests can be used.
+    ///
+    /// This is only the case for plain GET requests.
+    fn is_conditional(&self) -> bool {
+        self.method == Method::GET
+    }
+}
+
+
+//------------ HttpMethod ----------------------------------------------------
+
+/// The HTTP method for requests to the source.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+struct HttpMethod(Method);
+
+impl Default for HttpMethod {
+    fn default() -> Self {
+        HttpMethod(Method::GET)
+    }
+}
+
+impl TryFrom<String> for HttpMethod {
+    type Error = String;
+
+    fn try_from(src: String) -> Result<Self, Self::Error> {
+        Method::from_bytes(src.to_ascii_uppercase().as_bytes()).map(
+            HttpMethod
+        ).map_err(|_| format!("invalid HTTP method '{}'", src))
+    }
+}
+
+
+//------------ Template ------------------------------------------------------
+
+/// A string with placeholders for values determined at request time.
+///
+/// The placeholder `${timestamp}` is replaced with the current time as
+/// seconds since the Unix epoch and `${datetime}` with the current time in
+/// RFC 3339 format. A literal `$` can be given as `$$`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+struct Template(String);
+
+impl Template {
+    /// Returns the template with all placeholders replaced.
+    fn expand(&self, now: DateTime<Utc>) -> String {
+        // The template has been checked when it was created.
+        Self::render(&self.0, now).unwrap_or_default()
+    }
+
+    /// Renders a template string, returning an error if it is invalid.
+    fn render(src: &str, now: DateTime<Utc>) -> Result<String, String> {
+        let mut res = String::with_capacity(src.len());
+        let mut rest = src;
+        while let Some(pos) = rest.find('$') {
+            res.push_str(&rest[..pos]);
+            rest = &rest[pos + 1..];
+            if let Some(tail) = rest.strip_prefix('$') {
+                res.push('$');
+                rest = tail;
+                continue
+            }
+            let tail = match rest.strip_prefix('{') {
+                Some(tail) => tail,
+                None => {
+                    res.push('$');
+                    continue
+                }
+            };
+            let (name, tail) = tail.split_once('}').ok_or_else(|| {
+                format!("unclosed placeholder in '{}'", src)
+            })?;
+            match name {
+                "timestamp" => res.push_str(&now.timestamp().to_string()),
+                "datetime" => {
+                    res.push_str(
+                        &now.to_rfc3339_opts(SecondsFormat::Secs, true)
+                    )
+                }
+                _ => {
+                    return Err(format!(
+                        "unknown placeholder '${{{}}}' in '{}'", name, src
+                    ))
+                }
+            }
+            rest = tail;
+        }
+        res.push_str(rest);
+        Ok(res)
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = String;
+
+    fn try_from(src: String) -> Result<Self, Self::Error> {
+        Self::render(&src, Utc::now())?;
+        Ok(Template(src))
+    }
+}
+
+
+//------------ Checksum ------------------------------------------------------
+
+/// Where to get the expected checksum of the source from.
+#[derive(Clone, Debug)]
+enum Checksum<'a> {
+    /// The checksum was given in the config.
+    Inline(Sha256),
+
+    /// The checksum is in a companion file available via HTTP.
+    Http {
+        url: &'a Url,
+        client: reqwest::Client,
+    },
+
+    /// The checksum is in a local companion file.
+    File(&'a ConfigPath),
+}
+
+impl Checksum<'_> {
+    /// Fetches the expected checksum.
+    async fn fetch(&self, component: &Component) -> Result<Sha256, Failed> {
+        let content = match self {
+            Checksum::Inline(sha256) => return Ok(*sha256),
+            Checksum::Http { url, client } => {
+                Self::fetch_http(url, client, component).await?
+            }
+            Checksum::File(path) => {
+                tokio::fs::read_to_string(path).await.map_err(|err| {
+                    warn!(
+                        "Unit {}: Failed to read checksum file {}: {}.",
+                        component.name(), path.display(), err
+                    );
+                    Failed
+                })?
+            }
+        };
+        Sha256::from_companion(&content).ok_or_else(|| {
+            warn!(
+                "Unit {}: Failed to parse checksum file.",
+                component.name()
+            );
+            Failed
+        })
+    }
+
+    /// Fetches the content of a companion file via HTTP.
+    async fn fetch_http(
+        url: &Url, client: &reqwest::Client, component: &Component
+    ) -> Result<String, Failed> {
+        let response = client.get(url.clone()).send().await.map_err(|err| {
+            warn!(
+                "Unit {}: HTTP request for checksum failed: {}",
+                component.name(), err
+            );
+            Failed
+        })?;
+        if response.status() != StatusCode::OK {
+            warn!(
+                "Unit {}: HTTP request for checksum return status {}",
+                component.name(), response.status()
+            );
+            return Err(Failed)
+        }
+        response.text().await.map_err(|err| {
+            warn!(
+                "Unit {}: Failed to read checksum response: {}",
+                component.name(), err
+            );
+            Failed
+        })
+    }
+}
+
+
+//------------ Sha256 --------------------------------------------------------
+
+/// A SHA-256 digest.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(try_from = "String")]
+struct Sha256([u8; 32]);
+
+impl Sha256 {
+    /// Calculates the digest of the given data.
+    fn digest(data: &[u8]) -> Self {
+        let mut res = [0u8; 32];
+        res.copy_from_slice(
+            digest::digest(&digest::SHA256, data).as_ref()
+        );
+        Sha256(res)
+    }
+
+    /// Parses the content of a companion checksum file.
+    ///
+    /// This accepts the output of the `sha256sum` command, i.e., the
+    /// digest is the first word of the file and everything after it is
+    /// ignored.
+    fn from_companion(content: &str) -> Option<Self> {
+        Self::from_str(content.split_whitespace().next()?).ok()
+    }
+}
+
+impl FromStr for Sha256 {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERR: &str = "expected 64 hex digits";
+
+        let s = s.as_bytes();
+        if s.len() != 64 {
+            return Err(ERR)
+        }
+        let mut res = [0u8; 32];
+        for (target, pair) in res.iter_mut().zip(s.chunks(2)) {
+            let hi = (pair[0] as char).to_digit(16).ok_or(ERR)?;
+            let lo = (pair[1] as char).to_digit(16).ok_or(ERR)?;
+            *target = ((hi << 4) | lo) as u8;
+        }
+        Ok(Sha256(res))
+    }
+}
+
+impl TryFrom<String> for Sha256 {
+    type Error = &'static str;
+
+    fn try_from(src: String) -> Result<Self, Self::Error> {
+        Self::from_str(&src)
+    }
+}
+
+impl fmt::Display for Sha256 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for ch in &self.0 {
+            write!(f, "{:02x}", ch)?;
+        }
+        Ok(())
+    }
+}
+
+
+//------------ JsonMetrics ---------------------------------------------------
+
+/// The metrics of a JSON unit.
+#[derive(Debug)]
+struct JsonMetrics {
+    /// The number of times the source didn’t match its checksum.
+    checksum_mismatches: AtomicU64,
+
+    /// The number of VRPs reported in the metadata of the last data set.
+    upstream_count: AtomicCell<Option<u64>>,
+
+    /// The generation time reported in the metadata of the last data set.
+    upstream_generated: AtomicCell<Option<DateTime<Utc>>>,
+
+    /// The serial number reported in the metadata of the last data set.
+    upstream_serial: AtomicCell<Option<u32>>,
+
+    /// The number of times the reported number of VRPs was wrong.
+    count_mismatches: AtomicU64,
+
+    /// The number of consecutive failures of a local file source.
+    ///
+    /// This is `None` if the source isn’t a local file or hasn’t been read
+    /// yet.
+    file_failures: AtomicCell<Option<u32>>,
+
+    /// The number of VRPs per trust anchor in the last data set.
+    ///
+    /// This is only available if trust anchors are kept.
+    trust_anchors: Mutex<Vec<(String, usize)>>,
+
+    /// The durations of attempts to fetch the source.
+    fetch_duration: Histogram,
+
+    /// The gate metrics.
+    gate: Arc<GateMetrics>,
+}
+
+impl JsonMetrics {
+    const CHECKSUM_MISMATCHES_METRIC: Metric = Metric::new(
+        "json_checksum_mismatches",
+        "the number of times the source didn’t match its checksum",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const UPSTREAM_COUNT_METRIC: Metric = Metric::new(
+        "json_upstream_vrps",
+        "the number of VRPs reported in the source’s metadata",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const UPSTREAM_GENERATED_METRIC: Metric = Metric::new(
+        "json_upstream_generated",
+        "the generation time reported in the source’s metadata",
+        MetricType::Text, MetricUnit::Info
+    );
+    const UPSTREAM_SERIAL_METRIC: Metric = Metric::new(
+        "json_upstream_serial",
+        "the serial number reported in the source’s metadata",
+        MetricType::Gauge, MetricUnit::Info
+    );
+    const COUNT_MISMATCHES_METRIC: Metric = Metric::new(
+        "json_count_mismatches",
+        "the number of times the source contained a different number of \
+         VRPs than reported in its metadata",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const FILE_FAILURES_METRIC: Metric = Metric::new(
+        "json_file_failures",
+        "the number of consecutive failures to read a local source file",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const TRUST_ANCHOR_METRIC: Metric = Metric::new(
+        "json_trust_anchor_vrps",
+        "the number of VRPs per trust anchor in the last data set",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const FILE_DEGRADED_METRIC: Metric = Metric::new(
+        "json_file_degraded",
+        "whether a local source file keeps failing and is retried less often",
+        MetricType::Gauge, MetricUnit::Info
+    );
+    const FETCH_DURATION_METRIC: Metric = Metric::new(
+        "json_fetch_duration",
+        "the time it took to fetch and parse the source",
+        MetricType::Histogram, MetricUnit::Second
+    );
+
+    /// The bucket bounds of the fetch duration histogram in seconds.
+    const FETCH_DURATION_BOUNDS: &'static [f64] = &[
+        0.01, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10., 30., 60.
+    ];
+}
+
+impl JsonMetrics {
+    fn new(gate: &Gate) -> Self {
+        JsonMetrics {
+            checksum_mismatches: Default::default(),
+            upstream_count: Default::default(),
+            upstream_generated: Default::default(),
+            upstream_serial: Default::default(),
+            count_mismatches: Default::default(),
+            file_failures: Default::default(),
+            trust_anchors: Default::default(),
+            fetch_duration: Histogram::new(Self::FETCH_DURATION_BOUNDS),
+            gate: gate.metrics(),
+        }
+    }
+}
+
+impl metrics::Source for JsonMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::CHECKSUM_MISMATCHES_METRIC, Some(unit_name),
+            self.checksum_mismatches.load(Relaxed)
+        );
+        if let Some(count) = self.upstream_count.load() {
+            target.append_simple(
+                &Self::UPSTREAM_COUNT_METRIC, Some(unit_name), count
+            );
+        }
+        if let Some(generated) = self.upstream_generated.load() {
+            target.append_simple(
+                &Self::UPSTREAM_GENERATED_METRIC, Some(unit_name), generated
+            );
+        }
+        if let Some(serial) = self.upstream_serial.load() {
+            target.append_simple(
+                &Self::UPSTREAM_SERIAL_METRIC, Some(unit_name), serial
+            );
+        }
+        target.append_simple(
+            &Self::COUNT_MISMATCHES_METRIC, Some(unit_name),
+            self.count_mismatches.load(Relaxed)
+        );
+        if let Some(failures) = self.file_failures.load() {
+            target.append_simple(
+                &Self::FILE_FAILURES_METRIC, Some(unit_name), failures
+            );
+            target.append_simple(
+                &Self::FILE_DEGRADED_METRIC, Some(unit_name),
+                u8::from(failures >= DEGRADED_AFTER)
+            );
+        }
+        {
+            let trust_anchors = self.trust_anchors.lock().unwrap();
+            if !trust_anchors.is_empty() {
+                target.append(
+                    &Self::TRUST_ANCHOR_METRIC, Some(unit_name), |records| {
+                        for (name, count) in trust_anchors.iter() {
+                            records.label_value(
+                                &[("ta", name.as_str())], count
+                            );
+                        }
+                    }
+                );
+            }
+        }
+        if self.fetch_duration.count() > 0 {
+            target.append(
+                &Self::FETCH_DURATION_METRIC, Some(unit_name),
+                |records| records.histogram(&[], &self.fetch_duration)
+            );
+        }
+        self.gate.append(unit_name, target);
     }
 }
 
@@ -252,6 +1157,9 @@ struct SourceReader {
     reader: Reader,
     chunk: Bytes,
     rt: tokio::runtime::Handle,
+
+    /// The last bytes handed out for showing them with parse errors.
+    tail: VecDeque<u8>,
 }
 
 enum Reader {
@@ -262,40 +1170,48 @@ enum Reader {
 impl SourceReader {
     async fn open(
         source: &mut Source<'_>, 
+        strict: bool,
         component: &Component,
     ) -> Result<Option<Self>, Failed> {
         match source {
             Source::Http {
-                url, ref client, ref mut etag, ref mut last_modified
+                url, ref request, ref client, ref mut etag,
+                ref mut last_modified
             } => {
                 Self::open_http(
-                    url, client, last_modified, etag, component
+                    url, request, client, last_modified, etag, strict,
+                    component
                 ).await
             }
-            Source::File { path, ref mut last_modified } => {
+            Source::File { path, ref mut last_modified, .. } => {
                 Self::open_file(path, last_modified, component).await
             }
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn open_http(
         uri: &Url, 
+        template: &RequestTemplate<'_>,
         client: &reqwest::Client,
         last_modified: &mut Option<DateTime<Utc>>,
         etag: &mut Option<Bytes>,
+        strict: bool,
         component: &Component,
     ) -> Result<Option<Self>, Failed> {
         // Create and send the request.
-        let mut request = client.get(uri.clone());
-        if let Some(etag) = etag.as_ref() {
-            request = request.header(
-                header::IF_NONE_MATCH, etag.as_ref()
-            );
-        }
-        if let Some(ts) = last_modified {
-            request = request.header(
-                header::IF_MODIFIED_SINCE, format_http_date(*ts)
-            );
+        let mut request = template.build(uri, client, Utc::now());
+        if template.is_conditional() {
+            if let Some(etag) = etag.as_ref() {
+                request = request.header(
+                    header::IF_NONE_MATCH, etag.as_ref()
+                );
+            }
+            if let Some(ts) = last_modified {
+                request = request.header(
+                    header::IF_MODIFIED_SINCE, format_http_date(*ts)
+                );
+            }
         }
         let response = request.send().await.map_err(|err| {
             warn!(
@@ -317,6 +1233,32 @@ impl SourceReader {
             return Err(Failed)
         }
 
+        // In strict mode, we only accept JSON.
+        if strict {
+            let content_type = response.headers().get(
+                header::CONTENT_TYPE
+            ).map(|value| String::from_utf8_lossy(value.as_bytes()));
+            match content_type {
+                Some(value) if is_json_content_type(&value) => { }
+                Some(value) => {
+                    warn!(
+                        "Unit {}: unexpected content type '{}' in HTTP \
+                         response, expected application/json.",
+                        component.name(), value
+                    );
+                    return Err(Failed)
+                }
+                None => {
+                    warn!(
+                        "Unit {}: missing content type in HTTP response, \
+                         expected application/json.",
+                        component.name()
+                    );
+                    return Err(Failed)
+                }
+            }
+        }
+
         // Update Etag and Last-Modified.
         *etag = Self::parse_etag(&response);
         *last_modified = Self::parse_last_modified(&response);
@@ -363,10 +1305,39 @@ impl SourceReader {
         SourceReader {
             reader,
             chunk: Bytes::new(),
-            rt: tokio::runtime::Handle::current()
+            rt: tokio::runtime::Handle::current(),
+            tail: VecDeque::with_capacity(SNIPPET_LEN),
         }
     }
 
+    /// Returns the first byte that isn’t white space without consuming it.
+    ///
+    /// Leading white space is dropped. Returns `None` if there is no more
+    /// data.
+    fn peek(&mut self) -> Result<Option<u8>, io::Error> {
+        loop {
+            if !self.prepare_chunk()? {
+                return Ok(None)
+            }
+            match self.chunk.iter().position(|ch| {
+                !ch.is_ascii_whitespace()
+            }) {
+                Some(pos) => {
+                    self.chunk.advance(pos);
+                    return Ok(Some(self.chunk[0]))
+                }
+                None => self.chunk.clear(),
+            }
+        }
+    }
+
+    /// Returns the last bytes read for use in error messages.
+    fn snippet(&self) -> String {
+        String::from_utf8_lossy(
+            &self.tail.iter().copied().collect::<Vec<_>>()
+        ).into_owned()
+    }
+
     fn prepare_chunk(&mut self) -> Result<bool, io::Error> {
         if !self.chunk.is_empty() {
             return Ok(true)
@@ -456,6 +1427,12 @@ impl io::Read for SourceReader {
         let len = cmp::min(self.chunk.len(), buf.len());
         buf[..len].copy_from_slice(&self.chunk[..len]);
         self.chunk.advance(len);
+        for &ch in &buf[len.saturating_sub(SNIPPET_LEN)..len] {
+            if self.tail.len() == SNIPPET_LEN {
+                self.tail.pop_front();
+            }
+            self.tail.push_back(ch);
+        }
         Ok(len)
     }
 }
@@ -463,9 +1440,160 @@ impl io::Read for SourceReader {
 
 //------------ Helper Functions ------------------------------------------------
 
+/// The maximum number of bytes of content shown with parse errors.
+const SNIPPET_LEN: usize = 64;
+
+/// Returns the content leading up to the given position.
+///
+/// The position is given as the line and column reported by a JSON parse
+/// error. At most [`SNIPPET_LEN`] bytes are returned.
+fn snippet_at(data: &[u8], line: usize, column: usize) -> String {
+    let line_start = if line <= 1 {
+        0
+    }
+    else {
+        data.iter().enumerate().filter(|(_, ch)| **ch == b'\n').nth(
+            line - 2
+        ).map(|(idx, _)| idx + 1).unwrap_or(data.len())
+    };
+    let end = cmp::min(line_start.saturating_add(column), data.len());
+    let start = end.saturating_sub(SNIPPET_LEN);
+    String::from_utf8_lossy(&data[start..end]).into_owned()
+}
+
+/// Returns whether a Content-Type header value describes JSON.
+fn is_json_content_type(value: &str) -> bool {
+    let media_type = value.split(';').next().unwrap_or("").trim();
+    media_type.eq_ignore_ascii_case("application/json")
+        || media_type.to_ascii_lowercase().ends_with("+json")
+}
+
 fn deserialize_identity<'de, D: serde::Deserializer<'de>>(
     deserializer: D
 ) -> Result<Option<ConfigPath>, D::Error> {
     ConfigPath::deserialize(deserializer).map(Some)
 }
 
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sha256() {
+        let digest = Sha256::digest(b"abc");
+        assert_eq!(
+            digest.to_string(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            Sha256::from_companion(
+                "BA7816BF8F01CFEA414140DE5DAE2223\
+                 B00361A396177A9CB410FF61F20015AD  vrps.json\n"
+            ),
+            Some(digest)
+        );
+        assert_eq!(Sha256::from_companion(""), None);
+        assert!(Sha256::from_str("ba7816bf").is_err());
+        assert!(Sha256::from_str(
+            "xa7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        ).is_err());
+    }
+
+    #[test]
+    fn content_type() {
+        assert!(is_json_content_type("application/json"));
+        assert!(is_json_content_type("Application/JSON; charset=utf-8"));
+        assert!(is_json_content_type("application/vnd.example+json"));
+        assert!(!is_json_content_type("text/html"));
+        assert!(!is_json_content_type(""));
+    }
+
+    #[test]
+    fn templates() {
+        let now = DateTime::parse_from_rfc3339(
+            "2024-01-02T03:04:05Z"
+        ).unwrap().with_timezone(&Utc);
+        let expand = |src: &str| {
+            Template::try_from(String::from(src)).unwrap().expand(now)
+        };
+        assert_eq!(expand("plain"), "plain");
+        assert_eq!(expand("since=${timestamp}"), "since=1704164645");
+        assert_eq!(
+            expand("{\"at\": \"${datetime}\"}"),
+            "{\"at\": \"2024-01-02T03:04:05Z\"}"
+        );
+        assert_eq!(expand("$${timestamp} costs $5"), "${timestamp} costs $5");
+        assert!(Template::try_from(String::from("${now}")).is_err());
+        assert!(Template::try_from(String::from("${timestamp")).is_err());
+    }
+
+    #[test]
+    fn input_format() {
+        let json: Json = toml::from_str(r#"
+            uri = "file:/var/db/rpki-client/openbgpd"
+            refresh = 60
+        "#).unwrap();
+        assert_eq!(json.format, InputFormat::Auto);
+        assert!(json.format.is_json(Some(b'{')));
+        assert!(!json.format.is_json(Some(b'r')));
+        assert!(!json.format.is_json(None));
+
+        let json: Json = toml::from_str(r#"
+            uri = "file:/var/db/rpki-client/openbgpd"
+            refresh = 60
+            format = "roa-set"
+        "#).unwrap();
+        assert!(!json.format.is_json(Some(b'{')));
+
+        assert!(toml::from_str::<Json>(r#"
+            uri = "file:/var/db/rpki-client/openbgpd"
+            refresh = 60
+            format = "bird"
+        "#).is_err());
+    }
+
+    #[test]
+    fn request_options() {
+        let json: Json = toml::from_str(r#"
+            uri = "https://rp.example.net/vrps"
+            refresh = 60
+            method = "post"
+            body = '{ "since": ${timestamp} }'
+            query = { format = "json" }
+            headers = { x-request-time = "${datetime}" }
+        "#).unwrap();
+        assert_eq!(json.method.0, Method::POST);
+        assert!(json.has_request_options());
+
+        let json: Json = toml::from_str(r#"
+            uri = "https://rp.example.net/vrps"
+            refresh = 60
+        "#).unwrap();
+        assert_eq!(json.method.0, Method::GET);
+        assert!(!json.has_request_options());
+
+        assert!(toml::from_str::<Json>(r#"
+            uri = "https://rp.example.net/vrps"
+            refresh = 60
+            method = "G E T"
+        "#).is_err());
+    }
+
+    #[test]
+    fn snippets() {
+        let data = b"{\n  \"roas\": [\n    { \"asn\": \"AS64496\", oops }\n";
+        let err = serde_json::from_slice::<JsonSet>(data).unwrap_err();
+        assert_eq!(
+            snippet_at(data, err.line(), err.column()),
+            "{\n  \"roas\": [\n    { \"asn\": \"AS64496\", o"
+        );
+
+        let long = [b'x'; 100];
+        assert_eq!(snippet_at(&long, 1, 80), "x".repeat(SNIPPET_LEN));
+        assert_eq!(snippet_at(&long, 3, 1), "x".repeat(SNIPPET_LEN));
+    }
+}
+
@@ -18,15 +18,37 @@
 //------------ Sub-modules ---------------------------------------------------
 //
 // These contain all the actual unit types grouped by shared functionality.
+#[cfg(feature = "unit-json")]
+mod cbor;
 mod combine;
+mod compact;
+mod delay;
+#[cfg(feature = "unit-json")]
+mod delta;
+mod exec;
+mod filter;
+mod guard;
+mod inline;
+#[cfg(feature = "unit-json")]
 mod json;
+mod nats;
+mod replay;
 mod rtr;
 mod slurm;
+#[cfg(feature = "http-server")]
+mod webhook;
 
 //------------ Unit ----------------------------------------------------------
 
+use std::time::Duration;
+use daemonbase::error::Failed;
 use serde::Deserialize;
 use crate::comms::Gate;
+#[cfg(not(all(
+    feature = "http-server", feature = "ssh", feature = "tls",
+    feature = "unit-json"
+)))]
+use crate::config::Disabled;
 use crate::manager::Component;
 
 /// The fundamental entity for data processing.
@@ -36,21 +58,93 @@ pub enum Unit {
     #[serde(rename = "any")]
     Any(combine::Any),
 
+    #[cfg(feature = "unit-json")]
+    #[serde(rename = "cbor")]
+    Cbor(cbor::Cbor),
+
+    #[cfg(not(feature = "unit-json"))]
+    #[serde(rename = "cbor")]
+    Cbor(Disabled),
+
+    #[serde(rename = "compact")]
+    Compact(compact::Compact),
+
+    #[serde(rename = "delay")]
+    Delay(delay::Delay),
+
+    #[serde(rename = "exec")]
+    Exec(exec::Exec),
+
+    #[serde(rename = "filter")]
+    Filter(filter::Filter),
+
+    #[serde(rename = "guard")]
+    Guard(guard::Guard),
+
     #[serde(rename = "rtr")]
     RtrTcp(rtr::Tcp),
 
+    #[cfg(feature = "ssh")]
+    #[serde(rename = "rtr-ssh")]
+    RtrSsh(rtr::Ssh),
+
+    #[cfg(not(feature = "ssh"))]
+    #[serde(rename = "rtr-ssh")]
+    RtrSsh(Disabled),
+
+    #[cfg(feature = "tls")]
     #[serde(rename = "rtr-tls")]
     RtrTls(rtr::Tls),
 
+    #[cfg(not(feature = "tls"))]
+    #[serde(rename = "rtr-tls")]
+    RtrTls(Disabled),
+
+    #[serde(rename = "intersect")]
+    Intersect(combine::Intersect),
+
+    #[cfg(feature = "unit-json")]
     #[serde(rename = "json")]
     Json(json::Json),
 
+    #[cfg(not(feature = "unit-json"))]
+    #[serde(rename = "json")]
+    Json(Disabled),
+
+    #[cfg(feature = "unit-json")]
+    #[serde(rename = "json-delta")]
+    JsonDelta(delta::JsonDelta),
+
+    #[cfg(not(feature = "unit-json"))]
+    #[serde(rename = "json-delta")]
+    JsonDelta(Disabled),
+
     #[serde(rename = "merge")]
     Merge(combine::Merge),
 
+    #[serde(rename = "nats")]
+    Nats(nats::Nats),
+
+    #[serde(rename = "replay")]
+    Replay(replay::Replay),
+
     #[serde(rename = "slurm")]
     Slurm(slurm::LocalExceptions),
 
+    #[serde(rename = "static")]
+    Static(inline::Static),
+
+    #[cfg(feature = "http-server")]
+    #[serde(rename = "webhook")]
+    Webhook(webhook::Webhook),
+
+    #[cfg(not(feature = "http-server"))]
+    #[serde(rename = "webhook")]
+    Webhook(Disabled),
+
+    #[serde(skip)]
+    Input(crate::eval::Input),
+
     #[cfg(test)]
     #[serde(skip)]
     Test(crate::test::Unit),
@@ -62,15 +156,151 @@ impl Unit {
     )  {
         let _ = match self {
             Unit::Any(unit) => unit.run(component, gate).await,
+            #[cfg(feature = "unit-json")]
+            Unit::Cbor(unit) => unit.run(component, gate).await,
+            #[cfg(not(feature = "unit-json"))]
+            Unit::Cbor(unit) => match unit { },
+            Unit::Compact(unit) => unit.run(component, gate).await,
+            Unit::Delay(unit) => unit.run(component, gate).await,
+            Unit::Exec(unit) => unit.run(component, gate).await,
+            Unit::Filter(unit) => unit.run(component, gate).await,
+            Unit::Guard(unit) => unit.run(component, gate).await,
             Unit::RtrTcp(unit) => unit.run(component, gate).await,
+            #[cfg(feature = "ssh")]
+            Unit::RtrSsh(unit) => unit.run(component, gate).await,
+            #[cfg(not(feature = "ssh"))]
+            Unit::RtrSsh(unit) => match unit { },
+            #[cfg(feature = "tls")]
             Unit::RtrTls(unit) => unit.run(component, gate).await,
+            #[cfg(not(feature = "tls"))]
+            Unit::RtrTls(unit) => match unit { },
+            Unit::Intersect(unit) => unit.run(component, gate).await,
+            #[cfg(feature = "unit-json")]
             Unit::Json(unit) => unit.run(component, gate).await,
+            #[cfg(not(feature = "unit-json"))]
+            Unit::Json(unit) => match unit { },
+            #[cfg(feature = "unit-json")]
+            Unit::JsonDelta(unit) => unit.run(component, gate).await,
+            #[cfg(not(feature = "unit-json"))]
+            Unit::JsonDelta(unit) => match unit { },
             Unit::Merge(unit) => unit.run(component, gate).await,
+            Unit::Nats(unit) => unit.run(component, gate).await,
+            Unit::Replay(unit) => unit.run(component, gate).await,
             Unit::Slurm(unit) => unit.run(component, gate).await,
+            Unit::Static(unit) => unit.run(component, gate).await,
+            #[cfg(feature = "http-server")]
+            Unit::Webhook(unit) => unit.run(component, gate).await,
+            #[cfg(not(feature = "http-server"))]
+            Unit::Webhook(unit) => match unit { },
+            Unit::Input(unit) => unit.run(component, gate).await,
 
             #[cfg(test)]
             Unit::Test(unit) => unit.run(component, gate).await,
         };
     }
+
+    /// Returns the name of the unit’s type as used in the configuration.
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Unit::Any(_) => "any",
+            Unit::Cbor(_) => "cbor",
+            Unit::Compact(_) => "compact",
+            Unit::Delay(_) => "delay",
+            Unit::Exec(_) => "exec",
+            Unit::Filter(_) => "filter",
+            Unit::Guard(_) => "guard",
+            Unit::RtrTcp(_) => "rtr",
+            Unit::RtrSsh(_) => "rtr-ssh",
+            Unit::RtrTls(_) => "rtr-tls",
+            Unit::Intersect(_) => "intersect",
+            Unit::Json(_) => "json",
+            Unit::JsonDelta(_) => "json-delta",
+            Unit::Merge(_) => "merge",
+            Unit::Nats(_) => "nats",
+            Unit::Replay(_) => "replay",
+            Unit::Slurm(_) => "slurm",
+            Unit::Static(_) => "static",
+            Unit::Webhook(_) => "webhook",
+            Unit::Input(_) => "eval-input",
+
+            #[cfg(test)]
+            Unit::Test(_) => "test",
+        }
+    }
+
+    /// Checks the parts of the configuration only used at run time.
+    ///
+    /// This loads all files the unit will need, such as TLS certificates
+    /// or SLURM files, and logs any problems found.
+    pub fn check(&self, name: &str) -> Result<(), Failed> {
+        match *self {
+            Unit::RtrTcp(ref unit) => unit.check(name),
+            #[cfg(feature = "ssh")]
+            Unit::RtrSsh(ref unit) => unit.check(name),
+            #[cfg(feature = "tls")]
+            Unit::RtrTls(ref unit) => unit.check(name),
+            #[cfg(feature = "unit-json")]
+            Unit::Json(ref unit) => unit.check(name),
+            Unit::Slurm(ref unit) => unit.check(name),
+            _ => Ok(())
+        }
+    }
+}
+
+
+
+//------------ UnitConfig ----------------------------------------------------
+
+/// A unit together with the configuration common to all units.
+#[derive(Debug, Deserialize)]
+pub struct UnitConfig {
+    /// The unit itself.
+    #[serde(flatten)]
+    pub unit: Unit,
+
+    /// The maximum age of the unit’s data in seconds.
+    ///
+    /// If the unit hasn’t produced an update for longer than this, its
+    /// data is considered to be outside of its service level objective.
+    #[serde(rename = "max-age")]
+    max_age: Option<u64>,
+
+    /// The number of seconds the unit may be stalled before its data expires.
+    ///
+    /// Once expired, the unit’s data is replaced with an empty set until
+    /// the unit becomes healthy again.
+    expire: Option<u64>,
+
+    /// The number of recent diffs of the unit’s data to keep.
+    ///
+    /// The diffs are available via the HTTP API for inspection.
+    #[serde(default, rename = "diff-history")]
+    diff_history: usize,
+}
+
+impl UnitConfig {
+    /// Returns the maximum age of the unit’s data if configured.
+    pub fn max_age(&self) -> Option<Duration> {
+        self.max_age.map(Duration::from_secs)
+    }
+
+    /// Returns how long the unit may be stalled if configured.
+    pub fn expire(&self) -> Option<Duration> {
+        self.expire.map(Duration::from_secs)
+    }
+
+    /// Runs the unit.
+    pub async fn run(self, component: Component, mut gate: Gate) {
+        gate.set_expire(self.expire());
+        gate.metrics().set_max_age(self.max_age());
+        gate.metrics().diffs().set_size(self.diff_history);
+        self.unit.run(component, gate).await
+    }
+}
+
+impl From<Unit> for UnitConfig {
+    fn from(unit: Unit) -> Self {
+        UnitConfig { unit, max_age: None, expire: None, diff_history: 0 }
+    }
 }
 
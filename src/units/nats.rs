@@ -0,0 +1,618 @@
+//! A unit subscribing to data sets published via NATS.
+//!
+//! The _nats_ unit subscribes to a subject of a NATS server and follows the
+//! data set published there in the format produced by the _nats_ target.
+//! This allows chaining RTRTR instances over a message broker.
+//!
+//! Messages of type `"snapshot"` contain the complete data set and always
+//! replace the current data set. Messages of type `"diff"` contain the
+//! changes since the previous message and are applied to the current data
+//! set if their serial number follows directly on the last one. A diff
+//! marked as a reset contains the complete data set instead. If messages
+//! were missed, the unit goes stalled until the next snapshot or reset.
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+use bytes::Bytes;
+use crossbeam_utils::atomic::AtomicCell;
+use daemonbase::config::ConfigPath;
+use log::{debug, error, warn};
+use rpki::rtr::payload::Action;
+use serde::Deserialize;
+use tokio::task::spawn_blocking;
+use tokio::time::{Instant, timeout, timeout_at};
+use crate::{metrics, payload};
+use crate::comms::{Gate, GateMetrics, Terminated, UnitUpdate};
+use crate::config::Secret;
+use crate::formats::json::Items;
+use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+use crate::utils::nats::{Auth, Connection, Tls};
+
+
+//------------ Nats ----------------------------------------------------------
+
+/// A unit following a data set published to a NATS subject.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Nats {
+    /// The address of the NATS server as host and port.
+    server: String,
+
+    /// Whether to use TLS for the connection.
+    #[serde(default)]
+    tls: bool,
+
+    /// Paths to additional root certificates for TLS.
+    #[serde(default)]
+    cacerts: Vec<ConfigPath>,
+
+    /// The subject to subscribe to.
+    subject: String,
+
+    /// The user name for authentication.
+    username: Option<String>,
+
+    /// The password for authentication.
+    password: Option<Secret>,
+
+    /// The token for token authentication.
+    token: Option<Secret>,
+
+    /// The timeout for connecting in seconds.
+    #[serde(default = "Nats::default_timeout")]
+    timeout: u64,
+
+    /// How long to wait before connecting again in seconds.
+    #[serde(default = "Nats::default_retry")]
+    retry: u64,
+}
+
+impl Nats {
+    /// The default for the `timeout` value.
+    fn default_timeout() -> u64 {
+        10
+    }
+
+    /// The default for the `retry` value.
+    fn default_retry() -> u64 {
+        10
+    }
+
+    /// Runs the unit.
+    pub async fn run(
+        self, mut component: Component, mut gate: Gate
+    ) -> Result<(), Terminated> {
+        let metrics = Arc::new(NatsMetrics::new(&gate));
+        component.register_metrics(metrics.clone());
+        let auth = Auth::new(
+            self.username.as_ref(), self.password.as_ref(),
+            self.token.as_ref(), self.tls,
+        ).map_err(|err| {
+            error!("Unit {}: {}.", component.name(), err);
+            Terminated
+        })?;
+        let tls = Tls::new(
+            component.name(), &self.server, self.tls, &self.cacerts
+        ).map_err(|_| Terminated)?;
+        let mut state = State::default();
+        loop {
+            let connect = self.connect(&auth, tls.as_ref());
+            match gate.process_until(connect).await? {
+                Ok(conn) => {
+                    metrics.connects.fetch_add(1, Relaxed);
+                    debug!(
+                        "Unit {}: subscribed to '{}' at '{}'.",
+                        component.name(), self.subject, self.server
+                    );
+                    let err = self.follow(
+                        conn, &mut state, &mut gate, &component, &metrics
+                    ).await?;
+                    warn!(
+                        "Unit {}: lost connection to '{}': {}",
+                        component.name(), self.server, err
+                    );
+                }
+                Err(err) => {
+                    warn!(
+                        "Unit {}: failed to subscribe at '{}': {}",
+                        component.name(), self.server, err
+                    );
+                }
+            }
+            gate.update(UnitUpdate::Stalled).await;
+            self.retry_wait(&mut gate).await?;
+        }
+    }
+
+    /// Connects to the server and subscribes to the subject.
+    async fn connect(
+        &self, auth: &Auth, tls: Option<&Tls>
+    ) -> Result<Connection, io::Error> {
+        timeout(Duration::from_secs(self.timeout), async {
+            let mut conn = Connection::connect(
+                &self.server, auth, tls
+            ).await?;
+            conn.subscribe(&self.subject).await?;
+            Ok::<_, io::Error>(conn)
+        }).await.map_err(|_| {
+            io::Error::new(io::ErrorKind::TimedOut, "timed out")
+        })?
+    }
+
+    /// Processes messages until the connection fails.
+    ///
+    /// Returns the error that ended the connection.
+    async fn follow(
+        &self,
+        mut conn: Connection,
+        state: &mut State,
+        gate: &mut Gate,
+        component: &Component,
+        metrics: &NatsMetrics,
+    ) -> Result<io::Error, Terminated> {
+        loop {
+            let data = match gate.process_until(conn.next_message()).await? {
+                Ok(data) => data,
+                Err(err) => return Ok(err),
+            };
+            metrics.messages.fetch_add(1, Relaxed);
+            metrics.bytes.fetch_add(data.len() as u64, Relaxed);
+            let res = match Self::parse(data).await {
+                Ok(message) => {
+                    if message.aspa_count() > 0 {
+                        warn!(
+                            "Unit {}: ignoring {} ASPA records.",
+                            component.name(), message.aspa_count()
+                        );
+                    }
+                    if message.is_diff() {
+                        metrics.diffs.fetch_add(1, Relaxed);
+                    }
+                    else {
+                        metrics.snapshots.fetch_add(1, Relaxed);
+                    }
+                    state.apply(message)
+                }
+                Err(err) => Err(err),
+            };
+            match res {
+                Ok(true) => {
+                    metrics.serial.store(state.serial);
+                    let update = payload::Update::new(state.set.clone());
+                    if gate.update(UnitUpdate::Payload(update)).await {
+                        debug!(
+                            "Unit {}: successfully updated.",
+                            component.name()
+                        );
+                    }
+                }
+                Ok(false) => {
+                    debug!("Unit {}: no changes.", component.name());
+                }
+                Err(err) => {
+                    warn!(
+                        "Unit {}: {}. Waiting for the complete data set.",
+                        component.name(), err
+                    );
+                    metrics.failures.fetch_add(1, Relaxed);
+                    if gate.update(UnitUpdate::Stalled).await {
+                        debug!(
+                            "Unit {}: marked as stalled.",
+                            component.name()
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Parses a message.
+    async fn parse(data: Bytes) -> Result<Message, String> {
+        match spawn_blocking(move || {
+            serde_json::from_slice::<Message>(&data)
+        }).await {
+            Ok(Ok(message)) => Ok(message),
+            Ok(Err(err)) => Err(format!("failed to parse message: {}", err)),
+            Err(_) => {
+                Err("failed to parse message: JSON parser panicked".into())
+            }
+        }
+    }
+
+    /// Waits until we should connect again.
+    async fn retry_wait(&self, gate: &mut Gate) -> Result<(), Terminated> {
+        let end = Instant::now() + Duration::from_secs(self.retry);
+        while end > Instant::now() {
+            match timeout_at(end, gate.process()).await {
+                Ok(Ok(_status)) => { }
+                Ok(Err(_)) => return Err(Terminated),
+                Err(_) => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+}
+
+
+//------------ Message -------------------------------------------------------
+
+/// A message published by the _nats_ target.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum Message {
+    /// The complete data set.
+    #[serde(rename = "snapshot")]
+    Snapshot {
+        /// The serial number of the message.
+        serial: u64,
+
+        /// The content of the data set.
+        #[serde(flatten)]
+        items: Items,
+    },
+
+    /// The changes since the previous message.
+    #[serde(rename = "diff")]
+    Diff {
+        /// The serial number of the message.
+        serial: u64,
+
+        /// Whether the diff contains the complete data set.
+        #[serde(default)]
+        reset: bool,
+
+        /// The items that have been added.
+        #[serde(default)]
+        announced: Items,
+
+        /// The items that have been removed.
+        #[serde(default)]
+        withdrawn: Items,
+    },
+}
+
+impl Message {
+    /// Returns whether the message is a diff.
+    fn is_diff(&self) -> bool {
+        matches!(self, Message::Diff { .. })
+    }
+
+    /// Returns the number of ASPA records that will be ignored.
+    fn aspa_count(&self) -> usize {
+        match self {
+            Message::Snapshot { items, .. } => items.aspa_count(),
+            Message::Diff { announced, withdrawn, .. } => {
+                announced.aspa_count() + withdrawn.aspa_count()
+            }
+        }
+    }
+}
+
+
+//------------ State ---------------------------------------------------------
+
+/// The current state of the data set.
+#[derive(Debug, Default)]
+struct State {
+    /// The serial number of the last message applied.
+    ///
+    /// This is `None` if we haven’t received a complete data set yet or if
+    /// messages have been missed since.
+    serial: Option<u64>,
+
+    /// The current data set.
+    set: payload::Set,
+}
+
+impl State {
+    /// Applies a message to the state.
+    ///
+    /// Returns whether the data set has to be updated. Returns an error
+    /// message if the message cannot be applied. In this case, only a
+    /// complete data set will be accepted next.
+    fn apply(&mut self, message: Message) -> Result<bool, String> {
+        match message {
+            Message::Snapshot { serial, items } => {
+                self.set = Self::items_set(items);
+                self.serial = Some(serial);
+                Ok(true)
+            }
+            Message::Diff { serial, reset: true, announced, .. } => {
+                self.set = Self::items_set(announced);
+                self.serial = Some(serial);
+                Ok(true)
+            }
+            Message::Diff { serial, announced, withdrawn, .. } => {
+                let current = match self.serial {
+                    Some(current) => current,
+                    None => {
+                        return Err(format!(
+                            "cannot apply diff to serial {} without \
+                             complete data set",
+                            serial
+                        ))
+                    }
+                };
+                if serial <= current {
+                    // A snapshot with the same serial has been applied
+                    // already or this is an old message.
+                    return Ok(false)
+                }
+                if serial != current + 1 {
+                    self.serial = None;
+                    return Err(format!(
+                        "missed messages between serial {} and {}",
+                        current, serial
+                    ))
+                }
+                let set = Self::items_diff(
+                    announced, withdrawn
+                ).and_then(|diff| {
+                    diff.apply(&self.set).map_err(|_| {
+                        format!(
+                            "diff to serial {} doesn’t apply to serial {}",
+                            serial, current
+                        )
+                    })
+                });
+                match set {
+                    Ok(set) => {
+                        self.set = set;
+                        self.serial = Some(serial);
+                        Ok(true)
+                    }
+                    Err(err) => {
+                        self.serial = None;
+                        Err(err)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Converts items into a data set.
+    fn items_set(items: Items) -> payload::Set {
+        let mut res = payload::PackBuilder::empty();
+        for item in items.into_payload() {
+            let _ = res.insert(item);
+        }
+        res.finalize().into()
+    }
+
+    /// Converts announced and withdrawn items into a diff.
+    fn items_diff(
+        announced: Items, withdrawn: Items
+    ) -> Result<payload::Diff, String> {
+        let mut res = payload::DiffBuilder::empty();
+        for (items, action) in [
+            (announced, Action::Announce), (withdrawn, Action::Withdraw)
+        ] {
+            for item in items.into_payload() {
+                res.push(item, action).map_err(|_| {
+                    String::from("diff contains duplicate items")
+                })?;
+            }
+        }
+        Ok(res.finalize())
+    }
+}
+
+
+//------------ NatsMetrics ---------------------------------------------------
+
+/// The metrics of a NATS unit.
+#[derive(Debug, Default)]
+struct NatsMetrics {
+    /// The number of messages received.
+    messages: AtomicU64,
+
+    /// The number of bytes received.
+    bytes: AtomicU64,
+
+    /// The number of complete data sets received.
+    snapshots: AtomicU64,
+
+    /// The number of diffs received.
+    diffs: AtomicU64,
+
+    /// The number of messages that couldn’t be parsed or applied.
+    failures: AtomicU64,
+
+    /// The number of connections established.
+    connects: AtomicU64,
+
+    /// The serial number of the last message applied.
+    serial: AtomicCell<Option<u64>>,
+
+    /// The gate metrics.
+    gate: Arc<GateMetrics>,
+}
+
+impl NatsMetrics {
+    const MESSAGES_METRIC: Metric = Metric::new(
+        "nats_unit_messages",
+        "the number of messages received",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const BYTES_METRIC: Metric = Metric::new(
+        "nats_unit_received",
+        "the number of bytes of message payload received",
+        MetricType::Counter, MetricUnit::Byte
+    );
+    const SNAPSHOTS_METRIC: Metric = Metric::new(
+        "nats_unit_snapshots",
+        "the number of times the complete data set was received",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const DIFFS_METRIC: Metric = Metric::new(
+        "nats_unit_diffs",
+        "the number of times changes to the data set were received",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const FAILURES_METRIC: Metric = Metric::new(
+        "nats_unit_failures",
+        "the number of messages that could not be parsed or applied",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const CONNECTS_METRIC: Metric = Metric::new(
+        "nats_unit_connects",
+        "the number of connections established to the server",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const SERIAL_METRIC: Metric = Metric::new(
+        "nats_unit_serial",
+        "the serial number of the last message applied",
+        MetricType::Gauge, MetricUnit::Info
+    );
+
+    fn new(gate: &Gate) -> Self {
+        NatsMetrics {
+            gate: gate.metrics(),
+            ..Default::default()
+        }
+    }
+}
+
+impl metrics::Source for NatsMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::MESSAGES_METRIC, Some(unit_name),
+            self.messages.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::BYTES_METRIC, Some(unit_name), self.bytes.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::SNAPSHOTS_METRIC, Some(unit_name),
+            self.snapshots.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::DIFFS_METRIC, Some(unit_name), self.diffs.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::FAILURES_METRIC, Some(unit_name),
+            self.failures.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::CONNECTS_METRIC, Some(unit_name),
+            self.connects.load(Relaxed)
+        );
+        if let Some(serial) = self.serial.load() {
+            target.append_simple(
+                &Self::SERIAL_METRIC, Some(unit_name), serial
+            );
+        }
+        self.gate.append(unit_name, target);
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn apply(state: &mut State, json: &str) -> Result<bool, String> {
+        state.apply(serde_json::from_str(json).unwrap())
+    }
+
+    #[test]
+    fn apply_messages() {
+        let mut state = State::default();
+
+        // A diff without a complete data set fails.
+        assert!(apply(&mut state,
+            r#"{"type": "diff", "serial": 1, "announced": {}}"#
+        ).is_err());
+
+        assert!(apply(&mut state, r#"{
+            "type": "snapshot", "serial": 1, "generated": 0,
+            "routeOrigins": [
+                { "asn": "AS64496", "prefix": "192.0.2.0/24",
+                  "maxLength": 24 }
+            ],
+            "routerKeys": [], "aspas": []
+        }"#).unwrap());
+        assert_eq!(state.serial, Some(1));
+        assert_eq!(state.set.len(), 1);
+
+        // A diff with the same serial as the snapshot is ignored.
+        assert!(!apply(&mut state, r#"{
+            "type": "diff", "serial": 1, "reset": false,
+            "announced": {
+                "routeOrigins": [
+                    { "asn": "AS64496", "prefix": "192.0.2.0/24",
+                      "maxLength": 24 }
+                ]
+            }
+        }"#).unwrap());
+
+        assert!(apply(&mut state, r#"{
+            "type": "diff", "serial": 2, "reset": false,
+            "announced": {
+                "routeOrigins": [
+                    { "asn": "AS64497", "prefix": "198.51.100.0/24",
+                      "maxLength": 24 }
+                ]
+            },
+            "withdrawn": {
+                "routeOrigins": [
+                    { "asn": "AS64496", "prefix": "192.0.2.0/24",
+                      "maxLength": 24 }
+                ]
+            }
+        }"#).unwrap());
+        assert_eq!(state.serial, Some(2));
+        assert_eq!(state.set.len(), 1);
+
+        // Withdrawing something we don’t have fails.
+        assert!(apply(&mut state, r#"{
+            "type": "diff", "serial": 3,
+            "withdrawn": {
+                "routeOrigins": [
+                    { "asn": "AS64498", "prefix": "203.0.113.0/24",
+                      "maxLength": 24 }
+                ]
+            }
+        }"#).is_err());
+        assert_eq!(state.serial, None);
+
+        // A reset diff resynchronizes.
+        assert!(apply(&mut state, r#"{
+            "type": "diff", "serial": 0, "reset": true,
+            "announced": {
+                "routeOrigins": [
+                    { "asn": "AS64498", "prefix": "203.0.113.0/24",
+                      "maxLength": 24 }
+                ]
+            },
+            "withdrawn": {}
+        }"#).unwrap());
+        assert_eq!(state.serial, Some(0));
+        assert_eq!(state.set.len(), 1);
+
+        // A gap fails.
+        assert!(apply(&mut state,
+            r#"{"type": "diff", "serial": 2, "announced": {}}"#
+        ).is_err());
+        assert_eq!(state.serial, None);
+    }
+
+    #[test]
+    fn parse_messages() {
+        assert!(serde_json::from_str::<Message>(
+            r#"{"type": "delta", "serial": 1}"#
+        ).is_err());
+        let message = serde_json::from_str::<Message>(r#"{
+            "type": "snapshot", "serial": 1,
+            "aspas": [ { "customer": "AS64496", "providers": [] } ]
+        }"#).unwrap();
+        assert!(!message.is_diff());
+        assert_eq!(message.aspa_count(), 1);
+    }
+}
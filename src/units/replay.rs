@@ -0,0 +1,330 @@
+//! Replaying recorded updates.
+//!
+//! The _replay_ unit reads a journal of previously recorded data sets and
+//! re-emits them with their original relative timing or, optionally,
+//! accelerated. This is useful for reproducing incidents in a lab.
+//!
+//! The journal is a file with one JSON object per line. Each object has a
+//! member `"time"` with the time the data set was recorded in RFC 3339
+//! format and a member `"roas"` with the list of VRPs in the same format
+//! used by the [json unit][crate::formats::json]. Empty lines are ignored.
+//! The entries need to be in chronological order.
+
+use std::{fs, io};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use daemonbase::config::ConfigPath;
+use log::{debug, error, info};
+use serde::Deserialize;
+use tokio::task::spawn_blocking;
+use tokio::time::{Instant, sleep_until};
+use crate::{metrics, payload};
+use crate::comms::{Gate, GateMetrics, Terminated, UnitUpdate};
+use crate::formats::json::Set as JsonSet;
+use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+
+
+//------------ Replay --------------------------------------------------------
+
+/// A unit replaying recorded data sets from a journal.
+#[derive(Debug, Deserialize)]
+pub struct Replay {
+    /// The path to the journal file.
+    path: ConfigPath,
+
+    /// How many times faster than real time the journal is replayed.
+    #[serde(default = "Replay::default_speed")]
+    speed: f64,
+
+    /// Whether to start over once the end of the journal is reached.
+    #[serde(default, rename = "loop")]
+    repeat: bool,
+}
+
+impl Replay {
+    /// The default replay speed.
+    fn default_speed() -> f64 {
+        1.
+    }
+
+    pub async fn run(
+        self, mut component: Component, mut gate: Gate
+    ) -> Result<(), Terminated> {
+        if !self.speed.is_finite() || self.speed <= 0. {
+            error!(
+                "Unit {}: speed must be a positive number.",
+                component.name()
+            );
+            gate.update(UnitUpdate::Gone).await;
+            return Err(Terminated)
+        }
+
+        let metrics = Arc::new(ReplayMetrics::new(&gate));
+        component.register_metrics(metrics.clone());
+
+        let path = PathBuf::from(self.path.clone());
+        let journal = match spawn_blocking(move || {
+            Journal::load(&path)
+        }).await {
+            Ok(Ok(journal)) if !journal.entries.is_empty() => journal,
+            Ok(Ok(_)) => {
+                error!(
+                    "Unit {}: journal {} is empty.",
+                    component.name(), self.path.display()
+                );
+                gate.update(UnitUpdate::Gone).await;
+                return Err(Terminated)
+            }
+            Ok(Err(err)) => {
+                error!(
+                    "Unit {}: failed to load journal {}: {}",
+                    component.name(), self.path.display(), err
+                );
+                gate.update(UnitUpdate::Gone).await;
+                return Err(Terminated)
+            }
+            Err(_) => {
+                error!(
+                    "Unit {}: failed to load journal {}.",
+                    component.name(), self.path.display()
+                );
+                gate.update(UnitUpdate::Gone).await;
+                return Err(Terminated)
+            }
+        };
+        metrics.entries.store(journal.entries.len(), Relaxed);
+        info!(
+            "Unit {}: replaying {} updates from {}.",
+            component.name(), journal.entries.len(), self.path.display()
+        );
+
+        loop {
+            let start = Instant::now();
+            for (idx, entry) in journal.entries.iter().enumerate() {
+                match self.scale(entry.offset).and_then(|offset| {
+                    start.checked_add(offset)
+                }) {
+                    Some(deadline) => {
+                        gate.process_until(sleep_until(deadline)).await?;
+                    }
+                    None => {
+                        // The entry is too far in the future to ever be
+                        // reached. So we just wait until the end.
+                        loop {
+                            gate.process().await?;
+                        }
+                    }
+                }
+                metrics.position.store(idx + 1, Relaxed);
+                debug!(
+                    "Unit {}: replaying entry {} recorded at {}.",
+                    component.name(), idx + 1, entry.time
+                );
+                let update = payload::Update::new(entry.set.clone());
+                gate.update(UnitUpdate::Payload(update)).await;
+            }
+            metrics.rounds.fetch_add(1, Relaxed);
+            if !self.repeat {
+                break
+            }
+        }
+
+        info!("Unit {}: replay complete.", component.name());
+
+        // Keep serving the last data set.
+        loop {
+            gate.process().await?;
+        }
+    }
+
+    /// Scales an offset in the journal by the replay speed.
+    fn scale(&self, offset: Duration) -> Option<Duration> {
+        Duration::try_from_secs_f64(
+            offset.as_secs_f64() / self.speed
+        ).ok()
+    }
+}
+
+
+//------------ Journal -------------------------------------------------------
+
+/// The content of a journal file.
+#[derive(Debug, Default)]
+struct Journal {
+    /// The entries in chronological order.
+    entries: Vec<JournalEntry>,
+}
+
+impl Journal {
+    /// Loads the journal from a file.
+    fn load(path: &Path) -> Result<Self, io::Error> {
+        Self::from_reader(io::BufReader::new(fs::File::open(path)?))
+    }
+
+    /// Reads the journal from a reader.
+    fn from_reader(reader: impl BufRead) -> Result<Self, io::Error> {
+        let mut entries = Vec::new();
+        let mut first: Option<DateTime<Utc>> = None;
+        let mut last: Option<DateTime<Utc>> = None;
+        for (idx, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue
+            }
+            let line = serde_json::from_str::<JournalLine>(
+                &line
+            ).map_err(|err| Self::line_error(idx, err))?;
+            let time = DateTime::parse_from_rfc3339(
+                &line.time
+            ).map_err(|err| Self::line_error(idx, err))?.with_timezone(&Utc);
+            if last.map(|last| time < last).unwrap_or(false) {
+                return Err(Self::line_error(idx, "entry out of order"))
+            }
+            last = Some(time);
+            let first = *first.get_or_insert(time);
+            entries.push(JournalEntry {
+                time,
+                offset: (time - first).to_std().unwrap_or_default(),
+                set: line.set.into_payload(),
+            });
+        }
+        Ok(Journal { entries })
+    }
+
+    /// Creates an error for the given zero-based line index.
+    fn line_error(idx: usize, err: impl std::fmt::Display) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("line {}: {}", idx + 1, err)
+        )
+    }
+}
+
+
+//------------ JournalEntry --------------------------------------------------
+
+/// A single recorded data set.
+#[derive(Debug)]
+struct JournalEntry {
+    /// The time the data set was recorded.
+    time: DateTime<Utc>,
+
+    /// The offset from the first entry in the journal.
+    offset: Duration,
+
+    /// The recorded data set.
+    set: payload::Set,
+}
+
+
+//------------ JournalLine ---------------------------------------------------
+
+/// A line of the journal file as it appears in the file.
+#[derive(Deserialize)]
+struct JournalLine {
+    /// The time the data set was recorded.
+    time: String,
+
+    /// The data set.
+    #[serde(flatten)]
+    set: JsonSet,
+}
+
+
+//------------ ReplayMetrics -------------------------------------------------
+
+/// The metrics of a replay unit.
+#[derive(Debug, Default)]
+struct ReplayMetrics {
+    /// The number of entries in the journal.
+    entries: AtomicUsize,
+
+    /// The number of entries replayed in the current round.
+    position: AtomicUsize,
+
+    /// The number of completed rounds.
+    rounds: AtomicU64,
+
+    /// The gate metrics.
+    gate: Arc<GateMetrics>,
+}
+
+impl ReplayMetrics {
+    const ENTRIES_METRIC: Metric = Metric::new(
+        "replay_entries", "the number of entries in the journal",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const POSITION_METRIC: Metric = Metric::new(
+        "replay_position",
+        "the number of entries replayed in the current round",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const ROUNDS_METRIC: Metric = Metric::new(
+        "replay_rounds", "the number of times the journal was fully replayed",
+        MetricType::Counter, MetricUnit::Total
+    );
+}
+
+impl ReplayMetrics {
+    fn new(gate: &Gate) -> Self {
+        ReplayMetrics {
+            gate: gate.metrics(),
+            ..Default::default()
+        }
+    }
+}
+
+impl metrics::Source for ReplayMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::ENTRIES_METRIC, Some(unit_name),
+            self.entries.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::POSITION_METRIC, Some(unit_name),
+            self.position.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::ROUNDS_METRIC, Some(unit_name),
+            self.rounds.load(Relaxed)
+        );
+        self.gate.append(unit_name, target);
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_journal() {
+        let journal = Journal::from_reader(io::Cursor::new(
+            "{\"time\": \"2024-06-01T12:00:00Z\", \"roas\": [\
+                {\"prefix\": \"192.0.2.0/24\", \"asn\": \"AS64496\", \
+                 \"maxLength\": 24}\
+             ]}\n\
+             \n\
+             {\"time\": \"2024-06-01T12:00:30Z\", \"roas\": []}\n"
+        )).unwrap();
+        assert_eq!(journal.entries.len(), 2);
+        assert_eq!(journal.entries[0].offset, Duration::ZERO);
+        assert_eq!(journal.entries[0].set.len(), 1);
+        assert_eq!(journal.entries[1].offset, Duration::from_secs(30));
+        assert!(journal.entries[1].set.is_empty());
+
+        assert!(Journal::from_reader(io::Cursor::new(
+            "{\"time\": \"2024-06-01T12:00:30Z\", \"roas\": []}\n\
+             {\"time\": \"2024-06-01T12:00:00Z\", \"roas\": []}\n"
+        )).is_err());
+    }
+}
+
@@ -1,20 +1,26 @@
 //! RTR client units.
 //!
-//! There are two units in this module that act as an RTR client but use
-//! different transport protocols: [`Tcp`] uses plain, unencrypted TCP while
-//! [`Tls`] uses TLS.
+//! There are three units in this module that act as an RTR client but use
+//! different transport protocols: [`Tcp`] uses plain, unencrypted TCP,
+//! [`Tls`] uses TLS, and [`Ssh`] uses SSH. The TLS unit is only available
+//! if the `tls` feature is enabled and the SSH unit if the `ssh` feature is
+//! enabled.
 
 use std::io;
+use std::collections::BTreeMap;
+#[cfg(feature = "tls")]
 use std::fs::File;
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic;
 use std::sync::atomic::{AtomicI64, AtomicU32, AtomicU64};
 use std::task::{Context, Poll};
 use std::time::Duration;
 use chrono::{TimeZone, Utc};
+#[cfg(any(feature = "ssh", feature = "tls"))]
 use daemonbase::config::ConfigPath;
+use daemonbase::error::Failed;
 use futures_util::pin_mut;
 use futures_util::future::{select, Either};
 use log::{debug, error, warn};
@@ -24,21 +30,41 @@ use rpki::rtr::payload::{Action, Payload, Timing};
 use rpki::rtr::state::State;
 use serde::Deserialize;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+#[cfg(feature = "ssh")]
+use tokio::io::DuplexStream;
 use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
 use tokio::time::{timeout_at, Instant};
+#[cfg(feature = "tls")]
 use tokio_rustls::TlsConnector;
+#[cfg(feature = "tls")]
 use tokio_rustls::client::TlsStream;
+#[cfg(feature = "tls")]
 use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+#[cfg(feature = "tls")]
 use tokio_rustls::rustls::pki_types::ServerName;
 use crate::metrics;
 use crate::comms::{Gate, GateMetrics, GateStatus, Terminated, UnitUpdate};
+use crate::config::Secret;
 use crate::manager::Component;
-use crate::metrics::{Metric, MetricType, MetricUnit};
+use crate::metrics::{Histogram, Metric, MetricType, MetricUnit};
 use crate::payload;
+use crate::utils::rtr::{
+    CACHE_RESET, ERROR_REPORT, MAX_VERSION, PduTracker, TimingOverride,
+    VersionClamp,
+};
+#[cfg(feature = "ssh")]
+use crate::utils::ssh;
+use crate::utils::{tcp_ao, tcp_md5};
+use crate::utils::tls::MaybeTlsTcpStream;
 
 //------------ Tcp -----------------------------------------------------------
 
 /// An RTR client using an unencrypted plain TCP socket.
+///
+/// On Unix systems, the client can also use a Unix domain socket if the
+/// remote address is given as a path prefixed with `unix:`.
 #[derive(Debug, Deserialize)]
 pub struct Tcp {
     /// The remote address to connect to.
@@ -47,6 +73,26 @@ pub struct Tcp {
     /// How long to wait before connecting again if the connection is closed.
     #[serde(default = "Tcp::default_retry")]
     retry: u64,
+
+    /// The lowest RTR protocol version to accept from the server.
+    #[serde(rename = "min-version")]
+    min_version: Option<u8>,
+
+    /// The highest RTR protocol version to use.
+    #[serde(rename = "max-version")]
+    max_version: Option<u8>,
+
+    /// The overrides for the timing advertised by the server.
+    #[serde(flatten)]
+    timing: TimingConfig,
+
+    /// The TCP-MD5 key for the connection with the server.
+    #[serde(rename = "tcp-md5-key")]
+    tcp_md5_key: Option<Secret>,
+
+    /// The TCP-AO key for the connection with the server.
+    #[serde(rename = "tcp-ao-key")]
+    tcp_ao_key: Option<tcp_ao::Key>,
 }
 
 impl Tcp {
@@ -62,23 +108,60 @@ impl Tcp {
     pub async fn run(
         self, component: Component, gate: Gate
     ) -> Result<(), Terminated> {
+        check_tcp_keys(
+            component.name(), self.tcp_md5_key.as_ref(),
+            self.tcp_ao_key.as_ref()
+        )?;
+        let versions = Versions::new(
+            self.min_version, self.max_version, component.name()
+        )?;
         let metrics = Arc::new(RtrMetrics::new(&gate));
         RtrClient::run(
-            component, gate, self.retry, metrics.clone(),
+            component, gate, self.retry, versions,
+            self.timing.timing_override(), metrics.clone(),
             || async {
                 Ok(RtrTcpStream {
-                    sock: TcpStream::connect(&self.remote).await?,
+                    sock: Self::connect(
+                        &self.remote, self.tcp_md5_key.as_ref(),
+                        self.tcp_ao_key.as_ref()
+                    ).await?,
                     metrics: metrics.clone()
                 })
             }
         ).await
     }
+
+    /// Checks the parts of the configuration only used at run time.
+    pub fn check(&self, unit_name: &str) -> Result<(), Failed> {
+        check_tcp_keys(
+            unit_name, self.tcp_md5_key.as_ref(), self.tcp_ao_key.as_ref()
+        ).map_err(|_| Failed)
+    }
+
+    /// Connects to the remote address.
+    ///
+    /// If a TCP-MD5 or TCP-AO key is given, it is used for TCP connections
+    /// and ignored for Unix domain sockets.
+    async fn connect(
+        remote: &str, md5_key: Option<&Secret>, ao_key: Option<&tcp_ao::Key>
+    ) -> Result<MaybeTlsTcpStream, io::Error> {
+        #[cfg(unix)]
+        if let Some(path) = remote.strip_prefix("unix:") {
+            return UnixStream::connect(path).await.map(
+                MaybeTlsTcpStream::unix
+            )
+        }
+        connect_tcp(remote, md5_key, ao_key).await.map(|sock| {
+            MaybeTlsTcpStream::new(sock, None)
+        })
+    }
 }
 
 
 //------------ Tls -----------------------------------------------------------
 
 /// An RTR client using a TLS encrypted TCP socket.
+#[cfg(feature = "tls")]
 #[derive(Debug, Deserialize)]
 pub struct Tls {
     /// The remote address to connect to.
@@ -93,9 +176,30 @@ pub struct Tls {
     /// The files should contain one or more PEM-encoded certificates.
     #[serde(default)]
     cacerts: Vec<ConfigPath>,
+
+    /// The lowest RTR protocol version to accept from the server.
+    #[serde(rename = "min-version")]
+    min_version: Option<u8>,
+
+    /// The highest RTR protocol version to use.
+    #[serde(rename = "max-version")]
+    max_version: Option<u8>,
+
+    /// The overrides for the timing advertised by the server.
+    #[serde(flatten)]
+    timing: TimingConfig,
+
+    /// The TCP-MD5 key for the connection with the server.
+    #[serde(rename = "tcp-md5-key")]
+    tcp_md5_key: Option<Secret>,
+
+    /// The TCP-AO key for the connection with the server.
+    #[serde(rename = "tcp-ao-key")]
+    tcp_ao_key: Option<tcp_ao::Key>,
 }
 
 /// Run-time information of the TLS unit.
+#[cfg(feature = "tls")]
 struct TlsState {
     /// The unit configuration.
     tls: Tls,
@@ -110,6 +214,7 @@ struct TlsState {
     metrics: Arc<RtrMetrics>,
 }
 
+#[cfg(feature = "tls")]
 impl Tls {
     /// Runs the unit.
     ///
@@ -118,21 +223,41 @@ impl Tls {
     pub async fn run(
         self, component: Component, gate: Gate
     ) -> Result<(), Terminated> {
+        check_tcp_keys(
+            component.name(), self.tcp_md5_key.as_ref(),
+            self.tcp_ao_key.as_ref()
+        )?;
         let domain = self.get_domain_name(component.name())?;
         let connector = self.build_connector(component.name())?;
+        let versions = Versions::new(
+            self.min_version, self.max_version, component.name()
+        )?;
         let retry = self.retry;
+        let timing = self.timing.timing_override();
         let metrics = Arc::new(RtrMetrics::new(&gate));
         let state = Arc::new(TlsState {
             tls: self, domain, connector, metrics: metrics.clone(), 
         });
         RtrClient::run(
-            component, gate, retry, metrics,
+            component, gate, retry, versions, timing, metrics,
             move || {
                 Self::connect(state.clone())
             }
         ).await
     }
 
+    /// Checks the parts of the configuration only used at run time.
+    ///
+    /// This checks the keys and server name and loads the root
+    /// certificates.
+    pub fn check(&self, unit_name: &str) -> Result<(), Failed> {
+        check_tcp_keys(
+            unit_name, self.tcp_md5_key.as_ref(), self.tcp_ao_key.as_ref()
+        ).map_err(|_| Failed)?;
+        self.get_domain_name(unit_name).map_err(|_| Failed)?;
+        self.build_connector(unit_name).map(|_| ()).map_err(|_| Failed)
+    }
+
     /// Converts the server address into the name for certificate validation.
     fn get_domain_name(
         &self, unit_name: &str
@@ -208,11 +333,14 @@ impl Tls {
     async fn connect(
         state: Arc<TlsState>
     ) -> Result<TlsStream<RtrTcpStream>, io::Error> {
-        let stream = TcpStream::connect(&state.tls.remote).await?;
+        let stream = connect_tcp(
+            &state.tls.remote, state.tls.tcp_md5_key.as_ref(),
+            state.tls.tcp_ao_key.as_ref()
+        ).await?;
         state.connector.connect(
             state.domain.clone(),
             RtrTcpStream {
-                sock: stream,
+                sock: MaybeTlsTcpStream::new(stream, None),
                 metrics: state.metrics.clone(),
             }
         ).await
@@ -220,6 +348,213 @@ impl Tls {
 }
 
 
+//------------ Ssh -----------------------------------------------------------
+
+/// An RTR client using an SSH connection.
+///
+/// The unit connects to the server via TCP, runs the SSH protocol itself,
+/// and asks the server to start the RTR subsystem. The server’s host key
+/// must be one of the keys in the `host-keys` file and the unit
+/// authenticates with the Ed25519 key given via `identity`.
+#[cfg(feature = "ssh")]
+#[derive(Debug, Deserialize)]
+pub struct Ssh {
+    /// The remote address to connect to.
+    remote: String,
+
+    /// The user to log in as.
+    user: String,
+
+    /// The path to the private key to authenticate with.
+    identity: ConfigPath,
+
+    /// The path to a file with the acceptable host keys of the server.
+    #[serde(rename = "host-keys")]
+    host_keys: ConfigPath,
+
+    /// The name of the SSH subsystem for RTR.
+    #[serde(default = "Ssh::default_subsystem")]
+    subsystem: String,
+
+    /// How long to wait before connecting again if the connection is closed.
+    #[serde(default = "Tcp::default_retry")]
+    retry: u64,
+
+    /// The lowest RTR protocol version to accept from the server.
+    #[serde(rename = "min-version")]
+    min_version: Option<u8>,
+
+    /// The highest RTR protocol version to use.
+    #[serde(rename = "max-version")]
+    max_version: Option<u8>,
+
+    /// The overrides for the timing advertised by the server.
+    #[serde(flatten)]
+    timing: TimingConfig,
+
+    /// The TCP-MD5 key for the connection with the server.
+    #[serde(rename = "tcp-md5-key")]
+    tcp_md5_key: Option<Secret>,
+
+    /// The TCP-AO key for the connection with the server.
+    #[serde(rename = "tcp-ao-key")]
+    tcp_ao_key: Option<tcp_ao::Key>,
+}
+
+/// Run-time information of the SSH unit.
+#[cfg(feature = "ssh")]
+struct SshState {
+    /// The unit configuration.
+    ssh: Ssh,
+
+    /// The SSH configuration for connecting to the server.
+    config: Arc<ssh::ClientConfig>,
+
+    /// The unit’s metrics.
+    metrics: Arc<RtrMetrics>,
+}
+
+#[cfg(feature = "ssh")]
+impl Ssh {
+    /// The default subsystem name as defined in RFC 6810.
+    fn default_subsystem() -> String {
+        "rpki-rtr".into()
+    }
+
+    /// Runs the unit.
+    ///
+    /// This method will only ever return if the RTR client encounters a fatal
+    /// error.
+    pub async fn run(
+        self, component: Component, gate: Gate
+    ) -> Result<(), Terminated> {
+        check_tcp_keys(
+            component.name(), self.tcp_md5_key.as_ref(),
+            self.tcp_ao_key.as_ref()
+        )?;
+        let config = Arc::new(self.client_config(component.name())?);
+        let versions = Versions::new(
+            self.min_version, self.max_version, component.name()
+        )?;
+        let retry = self.retry;
+        let timing = self.timing.timing_override();
+        let metrics = Arc::new(RtrMetrics::new(&gate));
+        let state = Arc::new(SshState {
+            ssh: self, config, metrics: metrics.clone(),
+        });
+        RtrClient::run(
+            component, gate, retry, versions, timing, metrics,
+            move || {
+                Self::connect(state.clone())
+            }
+        ).await
+    }
+
+    /// Checks the parts of the configuration only used at run time.
+    ///
+    /// This checks the TCP keys and loads the SSH keys.
+    pub fn check(&self, unit_name: &str) -> Result<(), Failed> {
+        check_tcp_keys(
+            unit_name, self.tcp_md5_key.as_ref(), self.tcp_ao_key.as_ref()
+        ).map_err(|_| Failed)?;
+        self.client_config(unit_name).map(|_| ()).map_err(|_| Failed)
+    }
+
+    /// Loads the keys and prepares the SSH configuration.
+    fn client_config(
+        &self, unit_name: &str
+    ) -> Result<ssh::ClientConfig, Terminated> {
+        let key = ssh::PrivateKey::load_file(
+            &self.identity
+        ).map_err(|err| {
+            error!(
+                "Unit {}: failed to read SSH identity '{}': {}",
+                unit_name, self.identity.display(), err
+            );
+            Terminated
+        })?;
+        let host_keys = ssh::PublicKey::load_file(
+            &self.host_keys
+        ).map_err(|err| {
+            error!(
+                "Unit {}: failed to read host keys file '{}': {}",
+                unit_name, self.host_keys.display(), err
+            );
+            Terminated
+        })?;
+        if host_keys.is_empty() {
+            error!(
+                "Unit {}: no Ed25519 keys in host keys file '{}'.",
+                unit_name, self.host_keys.display()
+            );
+            return Err(Terminated)
+        }
+        Ok(ssh::ClientConfig {
+            user: self.user.clone(),
+            key,
+            host_keys,
+            subsystem: self.subsystem.clone(),
+        })
+    }
+
+    /// Connects to the server.
+    async fn connect(
+        state: Arc<SshState>
+    ) -> Result<DuplexStream, io::Error> {
+        let stream = connect_tcp(
+            &state.ssh.remote, state.ssh.tcp_md5_key.as_ref(),
+            state.ssh.tcp_ao_key.as_ref()
+        ).await?;
+        ssh::connect(
+            RtrTcpStream {
+                sock: MaybeTlsTcpStream::new(stream, None),
+                metrics: state.metrics.clone(),
+            },
+            state.config.clone()
+        ).await
+    }
+}
+
+
+//------------ connect_tcp ---------------------------------------------------
+
+/// Connects to a remote TCP address, optionally using a TCP-MD5 or TCP-AO
+/// key.
+async fn connect_tcp(
+    remote: &str, md5_key: Option<&Secret>, ao_key: Option<&tcp_ao::Key>
+) -> Result<TcpStream, io::Error> {
+    match (md5_key, ao_key) {
+        (_, Some(key)) => tcp_ao::connect(remote, key).await,
+        (Some(key), None) => tcp_md5::connect(remote, key).await,
+        (None, None) => TcpStream::connect(remote).await,
+    }
+}
+
+/// Checks that the keys for a TCP connection can be used.
+///
+/// Logs an error and fails if both a TCP-MD5 and a TCP-AO key are given or
+/// the TCP-AO key is too long.
+fn check_tcp_keys(
+    unit_name: &str, md5_key: Option<&Secret>, ao_key: Option<&tcp_ao::Key>
+) -> Result<(), Terminated> {
+    if let Some(key) = ao_key {
+        if md5_key.is_some() {
+            error!(
+                "Unit {}: only one of 'tcp-md5-key' and 'tcp-ao-key' can \
+                 be given.",
+                unit_name
+            );
+            return Err(Terminated)
+        }
+        if let Err(err) = key.check() {
+            error!("Unit {}: {}.", unit_name, err);
+            return Err(Terminated)
+        }
+    }
+    Ok(())
+}
+
+
 //------------ RtrClient -----------------------------------------------------
 
 /// The transport-agnostic parts of a running RTR client.
@@ -231,6 +566,12 @@ struct RtrClient<Connect> {
     /// How long to wait before connecting again if the connection is closed.
     retry: u64,
 
+    /// The RTR protocol versions to use.
+    versions: Versions,
+
+    /// The overrides for the timing advertised by the server.
+    timing: TimingOverride,
+
     /// Our gate status.
     status: GateStatus,
 
@@ -240,10 +581,15 @@ struct RtrClient<Connect> {
 
 impl<Connect> RtrClient<Connect> {
     /// Creates a new client from the connect closure and retry timeout.
-    fn new(connect: Connect, retry: u64, metrics: Arc<RtrMetrics>) -> Self {
+    fn new(
+        connect: Connect, retry: u64, versions: Versions,
+        timing: TimingOverride, metrics: Arc<RtrMetrics>
+    ) -> Self {
         RtrClient {
             connect,
             retry,
+            versions,
+            timing,
             status: Default::default(),
             metrics,
         }
@@ -264,12 +610,18 @@ where
         mut component: Component,
         mut gate: Gate,
         retry: u64,
+        versions: Versions,
+        timing: TimingOverride,
         metrics: Arc<RtrMetrics>,
         connect: Connect,
     ) -> Result<(), Terminated> {
         let mut target = Target::new(component.name().clone());
         component.register_metrics(metrics.clone());
-        let mut this = Self::new(connect, retry, metrics);
+        let cache = component.data_cache();
+        if let Some(cache) = cache.as_ref() {
+            cache.restore(component.name(), &mut gate).await;
+        }
+        let mut this = Self::new(connect, retry, versions, timing, metrics);
         loop {
             debug!("Unit {}: Connecting ...", target.name);
             let mut client = match this.connect(target, &mut gate).await {
@@ -316,6 +668,9 @@ where
                 };
                 if let Some(update) = update {
                     client.target_mut().current = update.set().clone();
+                    if let Some(cache) = cache.as_ref() {
+                        cache.store(update.set());
+                    }
                     gate.update(UnitUpdate::Payload(update)).await;
                 }
             }
@@ -333,7 +688,7 @@ where
     /// later retry.
     async fn connect(
         &mut self, target: Target, gate: &mut Gate,
-    ) -> Result<Client<Socket, Target>, Target> {
+    ) -> Result<Client<VersionedStream<Socket>, Target>, Target> {
         let sock = {
             let connect = (self.connect)();
             pin_mut!(connect);
@@ -365,6 +720,10 @@ where
             }
         };
 
+        let sock = VersionedStream::new(
+            sock, target.name.clone(), self.versions, self.timing,
+            self.metrics.clone()
+        );
         let state = target.state;
         Ok(Client::new(sock, target, state))
     }
@@ -386,10 +745,17 @@ where
     /// via `None`.
     #[allow(clippy::needless_pass_by_ref_mut)] // false positive
     async fn update(
-        &mut self, client: &mut Client<Socket, Target>, gate: &mut Gate
+        &mut self,
+        client: &mut Client<VersionedStream<Socket>, Target>,
+        gate: &mut Gate
     ) -> Result<Result<Option<payload::Update>, io::Error>, Terminated> {
+        let metrics = self.metrics.clone();
+        let name = client.target().name.clone();
         let update_fut = async {
             let update = client.update().await?;
+            if let Some(started) = client.target_mut().started.take() {
+                metrics.update_duration.observe(started.elapsed());
+            }
             let state = client.state();
             if update.is_definitely_empty() {
                 return Ok((state, None))
@@ -419,10 +785,23 @@ where
                     let res = match res {
                         Ok((state, res)) => {
                             if let Some(state) = state {
-                                self.metrics.session.store(
+                                let session = self.metrics.session.swap(
                                     state.session().into(),
                                     atomic::Ordering::Relaxed
                                 );
+                                if session != u32::MAX
+                                    && session != u32::from(state.session())
+                                {
+                                    warn!(
+                                        "Unit {}: server changed session ID \
+                                         from {} to {}.",
+                                        name, session,
+                                        state.session()
+                                    );
+                                    self.metrics.session_changes.fetch_add(
+                                        1, atomic::Ordering::Relaxed
+                                    );
+                                }
                                 self.metrics.serial.store(
                                     state.serial().into(),
                                     atomic::Ordering::Relaxed
@@ -475,6 +854,9 @@ struct Target {
 
     /// The component name.
     name: Arc<str>,
+
+    /// The time the server started sending the current update.
+    started: Option<Instant>,
 }
 
 impl Target {
@@ -483,7 +865,8 @@ impl Target {
         Target {
             current: Default::default(),
             state: None,
-            name
+            name,
+            started: None,
         }
     }
 }
@@ -493,6 +876,7 @@ impl PayloadTarget for Target {
 
     fn start(&mut self, reset: bool) -> Self::Update {
         debug!("Unit {}: starting update (reset={})", self.name, reset);
+        self.started = Some(Instant::now());
         if reset {
             TargetUpdate::Reset(payload::PackBuilder::empty())
         }
@@ -551,8 +935,8 @@ impl TargetUpdate {
             }
             TargetUpdate::Serial { set, diff } => {
                 let diff = diff.finalize();
-                let set = diff.apply(&set)?;
-                Ok(payload::Update::new(set))
+                let new_set = diff.apply(&set)?;
+                Ok(payload::Update::new(new_set).with_diff(&set, diff))
             }
         }
     }
@@ -584,7 +968,7 @@ impl PayloadUpdate for TargetUpdate {
 //------------ RtrMetrics ----------------------------------------------------
 
 /// The metrics for an RTR client.
-#[derive(Debug, Default)]
+#[derive(Debug)]
 struct RtrMetrics {
     /// The gate metrics.
     gate: Arc<GateMetrics>,
@@ -612,6 +996,27 @@ struct RtrMetrics {
 
     /// The number of bytes written.
     bytes_written: AtomicU64,
+
+    /// The RTR protocol version used by the server on the current connection.
+    ///
+    /// This is actually an `Option<u8>` with the value of `u32::MAX`
+    /// serving as `None`.
+    version: AtomicU32,
+
+    /// The durations of receiving updates from the server.
+    ///
+    /// This is measured from the start of the server’s response until the
+    /// update is complete.
+    update_duration: Histogram,
+
+    /// The number of Cache Reset PDUs received from the server.
+    cache_resets: AtomicU64,
+
+    /// The number of Error Report PDUs received by error code.
+    error_reports: Mutex<BTreeMap<u16, u64>>,
+
+    /// The number of times the server’s session ID has changed.
+    session_changes: AtomicU64,
 }
 
 impl RtrMetrics {
@@ -623,6 +1028,11 @@ impl RtrMetrics {
             updated: i64::MIN.into(),
             bytes_read: 0.into(),
             bytes_written: 0.into(),
+            version: u32::MAX.into(),
+            update_duration: Histogram::new(Self::UPDATE_DURATION_BOUNDS),
+            cache_resets: 0.into(),
+            error_reports: Default::default(),
+            session_changes: 0.into(),
         }
     }
 
@@ -633,6 +1043,10 @@ impl RtrMetrics {
     fn inc_bytes_written(&self, count: u64) {
         self.bytes_written.fetch_add(count, atomic::Ordering::Relaxed);
     }
+
+    fn inc_error_reports(&self, code: u16) {
+        *self.error_reports.lock().unwrap().entry(code).or_default() += 1;
+    }
 }
 
 impl RtrMetrics {
@@ -661,6 +1075,34 @@ impl RtrMetrics {
         "bytes_written", "the number of bytes written",
         MetricType::Counter, MetricUnit::Total,
     );
+    const VERSION_METRIC: Metric = Metric::new(
+        "rtr_version", "the RTR protocol version used with the server",
+        MetricType::Gauge, MetricUnit::Info,
+    );
+    const UPDATE_DURATION_METRIC: Metric = Metric::new(
+        "rtr_update_duration",
+        "the time it took to receive an update from the server",
+        MetricType::Histogram, MetricUnit::Second,
+    );
+    const CACHE_RESETS_METRIC: Metric = Metric::new(
+        "rtr_cache_resets", "the number of Cache Reset PDUs received",
+        MetricType::Counter, MetricUnit::Total,
+    );
+    const ERROR_REPORTS_METRIC: Metric = Metric::new(
+        "rtr_error_reports",
+        "the number of Error Report PDUs received by error code",
+        MetricType::Counter, MetricUnit::Total,
+    );
+    const SESSION_CHANGES_METRIC: Metric = Metric::new(
+        "rtr_session_changes",
+        "the number of times the server changed its session ID",
+        MetricType::Counter, MetricUnit::Total,
+    );
+
+    /// The bucket bounds of the update duration histogram in seconds.
+    const UPDATE_DURATION_BOUNDS: &'static [f64] = &[
+        0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10., 30.
+    ];
 
     const ISO_DATE: &'static [chrono::format::Item<'static>] = &[
         chrono::format::Item::Numeric(
@@ -731,6 +1173,273 @@ impl metrics::Source for RtrMetrics {
             &Self::BYTES_WRITTEN_METRIC, Some(unit_name),
             self.bytes_written.load(atomic::Ordering::Relaxed)
         );
+
+        let version = self.version.load(atomic::Ordering::Relaxed);
+        if version != u32::MAX {
+            target.append_simple(
+                &Self::VERSION_METRIC, Some(unit_name), version
+            );
+        }
+
+        if self.update_duration.count() > 0 {
+            target.append(
+                &Self::UPDATE_DURATION_METRIC, Some(unit_name),
+                |records| records.histogram(&[], &self.update_duration)
+            );
+        }
+
+        target.append_simple(
+            &Self::CACHE_RESETS_METRIC, Some(unit_name),
+            self.cache_resets.load(atomic::Ordering::Relaxed)
+        );
+        {
+            let error_reports = self.error_reports.lock().unwrap();
+            if !error_reports.is_empty() {
+                target.append(
+                    &Self::ERROR_REPORTS_METRIC, Some(unit_name), |records| {
+                        for (code, count) in error_reports.iter() {
+                            records.label_value(
+                                &[("code", code.to_string().as_str())],
+                                count
+                            );
+                        }
+                    }
+                );
+            }
+        }
+        target.append_simple(
+            &Self::SESSION_CHANGES_METRIC, Some(unit_name),
+            self.session_changes.load(atomic::Ordering::Relaxed)
+        );
+    }
+}
+
+
+//------------ Versions ------------------------------------------------------
+
+/// The range of RTR protocol versions to use.
+#[derive(Clone, Copy, Debug)]
+struct Versions {
+    /// The lowest version to accept from the server.
+    min: u8,
+
+    /// The highest version to use.
+    max: u8,
+}
+
+impl Versions {
+    /// Creates the range from the configured values.
+    ///
+    /// Logs an error and fails if the values are invalid.
+    fn new(
+        min: Option<u8>, max: Option<u8>, unit_name: &str
+    ) -> Result<Self, Terminated> {
+        let res = Versions {
+            min: min.unwrap_or(0),
+            max: max.unwrap_or(MAX_VERSION),
+        };
+        if res.max > MAX_VERSION {
+            error!(
+                "Unit {}: unsupported RTR version {} in max-version.",
+                unit_name, res.max
+            );
+            return Err(Terminated)
+        }
+        if res.min > res.max {
+            error!(
+                "Unit {}: min-version {} is greater than max-version {}.",
+                unit_name, res.min, res.max
+            );
+            return Err(Terminated)
+        }
+        Ok(res)
+    }
+}
+
+
+//------------ TimingConfig --------------------------------------------------
+
+/// The configuration for overriding the timing advertised by the server.
+///
+/// The server advertises the refresh, retry, and expire intervals in its
+/// End of Data PDUs starting with protocol version 1. The intervals can
+/// be replaced with fixed values or limited to maximum values.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+struct TimingConfig {
+    /// The refresh interval to use instead of the server’s.
+    refresh: Option<u32>,
+
+    /// The retry interval to use instead of the server’s.
+    #[serde(rename = "retry-interval")]
+    retry: Option<u32>,
+
+    /// The expire interval to use instead of the server’s.
+    expire: Option<u32>,
+
+    /// The maximum refresh interval.
+    #[serde(rename = "max-refresh")]
+    max_refresh: Option<u32>,
+
+    /// The maximum retry interval.
+    #[serde(rename = "max-retry-interval")]
+    max_retry: Option<u32>,
+
+    /// The maximum expire interval.
+    #[serde(rename = "max-expire")]
+    max_expire: Option<u32>,
+}
+
+impl TimingConfig {
+    /// Returns the override to apply to the End of Data PDUs received.
+    fn timing_override(&self) -> TimingOverride {
+        TimingOverride::new(
+            self.refresh, self.retry, self.expire
+        ).with_max(
+            self.max_refresh, self.max_retry, self.max_expire
+        )
+    }
+}
+
+
+//------------ VersionedStream -----------------------------------------------
+
+pin_project! {
+    /// A wrapper around a socket enforcing the RTR protocol versions.
+    ///
+    /// The version of all PDUs sent to the server is limited to the
+    /// maximum version. This way, the server will never respond with a
+    /// higher version. If the server responds with a version below the
+    /// minimum, reading fails.
+    ///
+    /// The wrapper also keeps the version, Cache Reset, and Error Report
+    /// metrics, logs the errors reported by the server, and applies the
+    /// timing overrides to the End of Data PDUs received.
+    struct VersionedStream<Sock> {
+        #[pin] sock: Sock,
+
+        name: Arc<str>,
+
+        versions: Versions,
+
+        timing: TimingOverride,
+
+        clamp: VersionClamp,
+
+        read_pdus: PduTracker,
+
+        metrics: Arc<RtrMetrics>,
+    }
+}
+
+impl<Sock> VersionedStream<Sock> {
+    /// Creates a new wrapper around a freshly connected socket.
+    fn new(
+        sock: Sock, name: Arc<str>, versions: Versions,
+        timing: TimingOverride, metrics: Arc<RtrMetrics>
+    ) -> Self {
+        metrics.version.store(u32::MAX, atomic::Ordering::Relaxed);
+        VersionedStream {
+            sock,
+            name,
+            versions,
+            timing,
+            clamp: VersionClamp::new(versions.max),
+            read_pdus: Default::default(),
+            metrics,
+        }
+    }
+}
+
+impl<Sock: AsyncRead> AsyncRead for VersionedStream<Sock> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>
+    ) -> Poll<Result<(), io::Error>> {
+        let this = self.project();
+        let len = buf.filled().len();
+        let res = this.sock.poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            if !this.timing.is_empty() {
+                if let Some(data) = buf.filled_mut().get_mut(len..) {
+                    this.timing.apply(data)
+                }
+            }
+            let mut too_low = None;
+            let data = buf.filled().get(len..).unwrap_or_default();
+            this.read_pdus.feed(data, |pdu| {
+                this.metrics.version.store(
+                    pdu.version().into(), atomic::Ordering::Relaxed
+                );
+                if pdu.version() < this.versions.min {
+                    too_low = Some(pdu.version())
+                }
+                match pdu.pdu_type() {
+                    CACHE_RESET => {
+                        debug!(
+                            "Unit {}: server sent Cache Reset.", this.name
+                        );
+                        this.metrics.cache_resets.fetch_add(
+                            1, atomic::Ordering::Relaxed
+                        );
+                    }
+                    ERROR_REPORT => {
+                        warn!(
+                            "Unit {}: server reported error {}: {}",
+                            this.name, pdu.session(),
+                            pdu.error_text().as_deref().unwrap_or(
+                                "no error text"
+                            )
+                        );
+                        this.metrics.inc_error_reports(pdu.session());
+                    }
+                    _ => { }
+                }
+            });
+            if let Some(version) = too_low {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "server uses RTR version {} below min-version {}",
+                        version, this.versions.min
+                    )
+                )))
+            }
+        }
+        res
+    }
+}
+
+impl<Sock: AsyncWrite> AsyncWrite for VersionedStream<Sock> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8]
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.project();
+        let mut data = buf.to_vec();
+        let mut clamp = *this.clamp;
+        clamp.apply(&mut data);
+        let res = this.sock.poll_write(cx, &data);
+        if let Poll::Ready(Ok(n)) = res {
+            // Only advance the clamp by what was actually written.
+            this.clamp.apply(&mut data[..n]);
+        }
+        res
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>
+    ) -> Poll<Result<(), io::Error>> {
+        self.project().sock.poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>
+    ) -> Poll<Result<(), io::Error>> {
+        self.project().sock.poll_shutdown(cx)
     }
 }
 
@@ -738,9 +1447,9 @@ impl metrics::Source for RtrMetrics {
 //------------ RtrTcpStream --------------------------------------------------
 
 pin_project! {
-    /// A wrapper around a TCP socket producing metrics.
+    /// A wrapper around a TCP or Unix socket producing metrics.
     struct RtrTcpStream {
-        #[pin] sock: TcpStream,
+        #[pin] sock: MaybeTlsTcpStream,
 
         metrics: Arc<RtrMetrics>,
     }
@@ -791,3 +1500,36 @@ impl AsyncWrite for RtrTcpStream {
     }
 }
 
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(feature = "ssh")]
+    #[test]
+    fn ssh_config() {
+        let unit: Ssh = toml::from_str(r#"
+            remote = "rtr.example.net:22"
+            user = "rtr"
+            identity = "/etc/rtrtr/id_ed25519"
+            host-keys = "/etc/rtrtr/host_keys"
+        "#).unwrap();
+        assert_eq!(unit.remote, "rtr.example.net:22");
+        assert_eq!(unit.user, "rtr");
+        assert_eq!(unit.subsystem, "rpki-rtr");
+        assert_eq!(unit.retry, 60);
+
+        // The key files are only read by the check.
+        assert!(unit.check("ssh-test").is_err());
+
+        // The user is required.
+        assert!(toml::from_str::<Ssh>(r#"
+            remote = "rtr.example.net:22"
+            identity = "/etc/rtrtr/id_ed25519"
+            host-keys = "/etc/rtrtr/host_keys"
+        "#).is_err());
+    }
+}
@@ -1,24 +1,50 @@
 //! Local Exceptions.
+//!
+//! In addition to the standard SLURM format of RFC 8416, the prefix and
+//! BGPsec assertions of a file can contain the members `"notBefore"` and
+//! `"notAfter"` with a time in RFC 3339 format. Such scheduled assertions
+//! are only added while the current time is between those two times. The
+//! unit produces a new update whenever a scheduled assertion becomes active
+//! or expires.
+//!
+//! Files can be of SLURM version 1 or version 2. The latter adds
+//! `"aspaFilters"` and `"aspaAssertions"`. An ASPA filter drops the ASPA
+//! record of the given customer ASN. An ASPA assertion replaces any ASPA
+//! record for its customer ASN in the data set. All files are checked for
+//! structural problems before they are used and all problems found are
+//! reported together.
+//!
+//! The files are checked for changes of their modification time or size
+//! every few seconds and re-applied to the current data set when they have
+//! changed.
 
-use std::{io, fs, thread};
+use std::{fmt, io, fs, mem, thread};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize};
+use std::sync::atomic::Ordering::Relaxed;
 use std::time::{Duration, SystemTime};
 use arc_swap::ArcSwap;
+use chrono::{DateTime, SecondsFormat, Utc};
 use daemonbase::config::ConfigPath;
-use log::{debug, error};
+use daemonbase::error::Failed;
+use log::{debug, error, info, warn};
+use rpki::rtr::payload::Payload;
 use rpki::slurm::{SlurmFile, ValidationOutputFilters};
 use serde::Deserialize;
 use tokio::sync::Notify;
-use crate::payload;
-use crate::comms::{Gate, Link, Terminated, UnitUpdate};
+use crate::{metrics, payload};
+use crate::comms::{Gate, GateMetrics, Link, Terminated, UnitUpdate};
+use crate::formats::slurm::{validate, AspaRules, ValidationErrors, Version};
 use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+use crate::utils::breaker::FileBreaker;
 
 
 //------------ Configuration -------------------------------------------------
 
-/// How long should the update thread sleep before checking files again?
-const UPDATE_SLEEP: Duration = Duration::from_secs(2);
+/// How long to wait before retrying a degraded file for the first time?
+const FAILURE_BACKOFF: Duration = Duration::from_secs(10);
 
 
 //------------ LocalExceptions -----------------------------------------------
@@ -31,16 +57,58 @@ pub struct LocalExceptions {
 
     /// A list of paths to the SLURM files.
     files: Vec<ConfigPath>,
+
+    /// How many seconds to wait before checking the files for changes.
+    #[serde(default = "LocalExceptions::default_refresh")]
+    refresh: u64,
+
+    /// The SLURM version all files must have.
+    ///
+    /// If this is missing, the version given in each file is used.
+    #[serde(default)]
+    version: Option<Version>,
 }
 
 impl LocalExceptions {
+    /// The default for the refresh interval.
+    fn default_refresh() -> u64 {
+        2
+    }
+
+    /// Checks the parts of the configuration only used at run time.
+    ///
+    /// This loads all SLURM files and logs any problems found.
+    pub fn check(&self, unit_name: &str) -> Result<(), Failed> {
+        let mut res = Ok(());
+        for path in &self.files {
+            let path: &Path = path.as_ref();
+            let content = fs::read(path).map_err(LoadError::from).and_then(
+                |data| Content::from_slice(&data, self.version)
+            );
+            if let Err(err) = content {
+                error!(
+                    "Unit {}: failed to load SLURM file {}: {}",
+                    unit_name, path.display(), err
+                );
+                res = Err(Failed)
+            }
+        }
+        res
+    }
+
     pub async fn run(
         mut self, mut component: Component, mut gate: Gate
     ) -> Result<(), Terminated> {
-        component.register_metrics(gate.metrics());
         let files = ExceptionSet::new(
-            self.files.into_iter().map(Into::into).collect()
+            component.name().clone(),
+            self.files.into_iter().map(Into::into).collect(),
+            Duration::from_secs(self.refresh.max(1)),
+            self.version,
         );
+        component.register_metrics(Arc::new(SlurmMetrics {
+            files: files.data.clone(),
+            gate: gate.metrics(),
+        }));
 
         // Whether we are ready to submit an update to our gate.
         //
@@ -67,6 +135,8 @@ impl LocalExceptions {
                     ready = true;
                 }
 
+                _ = files.scheduled_change() => { }
+
                 _ = gate.process() => {
                     continue
                 }
@@ -96,13 +166,23 @@ struct ExceptionSet {
 }
 
 impl ExceptionSet {
-    fn new(paths: Vec<PathBuf>) -> Self {
+    fn new(
+        unit: Arc<str>, paths: Vec<PathBuf>, refresh: Duration,
+        version: Option<Version>,
+    ) -> Self {
         // Doing things in this order avoids the need for type annotations.
         let res = ExceptionSet {
             data: Arc::new(
                 ExceptionSetData {
+                    unit,
                     files: paths.iter().map(|_| Default::default()).collect(),
+                    stats: paths.iter().map(|_| Default::default()).collect(),
+                    load_stats:
+                        paths.iter().map(|_| Default::default()).collect(),
+                    summary: Default::default(),
                     paths,
+                    refresh,
+                    version,
                     notify: Notify::new(),
                 }
             ),
@@ -120,42 +200,99 @@ impl ExceptionSet {
 
     fn apply(&self, unit: &str, update: &payload::Update) -> payload::Update {
         let mut set = update.set().clone();
+        let mut removed = 0;
+        let mut asserted = 0;
 
-        for (path, file) in
+        let now = Utc::now();
+        for ((path, file), stats) in
             self.data.paths.iter().zip(self.data.files.iter())
+                .zip(self.data.stats.iter())
         {
-            set = file.load().apply(unit, path, set);
-            
+            set = file.load().apply(unit, path, stats, set, now);
+            removed += stats.removed.load(Relaxed);
+            asserted += stats.added.load(Relaxed);
         }
 
-        payload::Update::new(set)
+        let kept = update.set().len().saturating_sub(removed);
+        self.data.summary.kept.store(kept, Relaxed);
+        self.data.summary.removed.store(removed, Relaxed);
+        self.data.summary.asserted.store(asserted, Relaxed);
+        info!(
+            "Unit {}: SLURM filtering: {} kept, {} removed, {} asserted.",
+            unit, kept, removed, asserted
+        );
+
+        update.derive(set)
     }
 
     async fn notified(&self) {
         self.data.notify.notified().await
     }
+
+    /// Resolves when the next scheduled assertion becomes active or expires.
+    ///
+    /// Never resolves if there are no upcoming changes.
+    async fn scheduled_change(&self) {
+        let now = Utc::now();
+        match self.data.next_change(now) {
+            Some(next) => {
+                tokio::time::sleep(
+                    (next - now).to_std().unwrap_or_default()
+                ).await
+            }
+            None => futures_util::future::pending().await
+        }
+    }
 }
 
 
 //------------ ExceptionSetData ---------------------------------------------
 
 struct ExceptionSetData {
+    /// The name of the unit for logging.
+    unit: Arc<str>,
+
     /// The paths to the various files.
     paths: Vec<PathBuf>,
 
+    /// How long to wait before checking the files again.
+    refresh: Duration,
+
+    /// The SLURM version required for the files, if any.
+    version: Option<Version>,
+
     /// The content of the various files.
     ///
     /// This lives behind an `ArcSwap` so we can cheaply swap out the content
     /// if a file updates.
     files: Vec<ArcSwap<Content>>,
 
+    /// The statistics of applying the various files.
+    stats: Vec<ApplyStats>,
+
+    /// The statistics of loading the various files.
+    load_stats: Vec<LoadStats>,
+
+    /// The overall effect of the last application of all files.
+    summary: ApplySummary,
+
     /// A notifier for when the set has changed.
     notify: Notify,
 }
 
 impl ExceptionSetData {
+    /// Returns the time of the next change of scheduled assertions.
+    fn next_change(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.files.iter().filter_map(|file| {
+            file.load().next_change(now)
+        }).min()
+    }
+
     fn update_thread(self: Arc<Self>, alive: Weak<()>) {
-        let mut modified = vec![None::<SystemTime>; self.paths.len()];
+        let mut modified = vec![None::<FileStamp>; self.paths.len()];
+        let mut breakers = vec![
+            FileBreaker::new(FAILURE_BACKOFF); self.paths.len()
+        ];
 
         loop {
             if alive.upgrade().is_none() {
@@ -165,83 +302,297 @@ impl ExceptionSetData {
 
             let mut updated = false;
 
-            for (path, (modified, content)) in
+            for ((path, (modified, content)), (breaker, load_stats)) in
                 self.paths.iter().zip(
                     modified.iter_mut().zip(self.files.iter())
-                )
+                ).zip(breakers.iter_mut().zip(self.load_stats.iter()))
             {
-                match Self::update_file(path, modified, content) {
-                    Ok(true) => updated = true,
+                if !breaker.permits(path) {
+                    continue
+                }
+                let mut invalid = load_stats.invalid.load(Relaxed);
+                match self.update_file(path, modified, content) {
+                    Ok(true) => {
+                        updated = true;
+                        invalid = 0;
+                        breaker.success();
+                    }
                     Ok(false) => { }
                     Err(err) => {
-                        error!(
-                            "Failed to read SLURM file {}: {}",
-                            path.display(), err
-                        );
+                        invalid = err.invalid_count();
+                        if breaker.failure(path) {
+                            error!(
+                                "Unit {}: failed to read SLURM file {}: {}",
+                                self.unit, path.display(), err
+                            );
+                            if let LoadError::Invalid(errs) = &err {
+                                for err in errs.iter() {
+                                    debug!(
+                                        "Unit {}: SLURM file {}: {}",
+                                        self.unit, path.display(), err
+                                    );
+                                }
+                            }
+                            if breaker.is_degraded() {
+                                warn!(
+                                    "Unit {}: SLURM file {} failed {} times \
+                                     in a row. Retrying less often until \
+                                     it changes.",
+                                    self.unit, path.display(),
+                                    breaker.failures()
+                                );
+                            }
+                        }
+                        else {
+                            debug!(
+                                "Unit {}: failed to read SLURM file {}: {}",
+                                self.unit, path.display(), err
+                            );
+                        }
                     }
                 }
+                load_stats.update(breaker);
+                load_stats.invalid.store(invalid, Relaxed);
             }
 
             if updated {
                 self.notify.notify_one();
             }
 
-            thread::sleep(UPDATE_SLEEP);
+            thread::sleep(self.refresh);
         }
     }
 
     /// Updates the given file if it changed.
     ///
+    /// A file is considered changed if its modification time or size
+    /// differs from when it was last read. In particular, a file replaced
+    /// by one with an older modification time is loaded, too.
+    ///
     /// Returns `Ok(true)` if the file was updated or `Ok(false)` if not.
     fn update_file(
+        &self,
         path: &Path,
-        old_modified: &mut Option<SystemTime>,
+        old_stamp: &mut Option<FileStamp>,
         content: &ArcSwap<Content>
-    ) -> Result<bool, io::Error> {
-        let new_modified = fs::metadata(path)?.modified()?;
-        if let Some(old_modified) = old_modified.as_ref() {
-            if new_modified <= *old_modified {
-                return Ok(false)
-            }
+    ) -> Result<bool, LoadError> {
+        let metadata = fs::metadata(path)?;
+        let new_modified = metadata.modified()?;
+        let new_stamp = (new_modified, metadata.len());
+        if *old_stamp == Some(new_stamp) {
+            return Ok(false)
         }
 
-        let slurm = fs::File::open(path).and_then(|file| {
-            SlurmFile::from_reader(
-                io::BufReader::new(file)
-            ).map_err(Into::into)
-        });
+        let slurm = fs::read(path).map_err(LoadError::from).and_then(
+            |data| Content::from_slice(&data, self.version)
+        );
 
-        *old_modified = Some(new_modified);
+        *old_stamp = Some(new_stamp);
 
-        let slurm = slurm?;
-        content.store(Arc::new(slurm.into()));
-        debug!("Updated Slurm file {}", path.display());
+        let mut slurm = slurm?;
+        slurm.size = metadata.len();
+        slurm.modified = Some(new_modified.into());
+        info!(
+            "Unit {}: loaded SLURM file {} (version {}, {} bytes, \
+             modified {}): {} filters, {} assertions.",
+            self.unit, path.display(), slurm.version.number(), slurm.size,
+            DateTime::<Utc>::from(new_modified).to_rfc3339_opts(
+                SecondsFormat::Secs, true
+            ),
+            slurm.filter_count(), slurm.assertions.len(),
+        );
+        if !slurm.scheduled.is_empty() {
+            info!(
+                "Unit {}: SLURM file {} contains {} scheduled assertions, \
+                 next change at {}.",
+                self.unit, path.display(), slurm.scheduled.len(),
+                slurm.next_change(Utc::now()).map(|next| {
+                    next.to_rfc3339_opts(SecondsFormat::Secs, true)
+                }).unwrap_or_else(|| "never".into())
+            );
+        }
+        if slurm.filter_count() == 0 && slurm.assertions.is_empty()
+            && slurm.scheduled.is_empty()
+        {
+            warn!(
+                "Unit {}: SLURM file {} contains no rules.",
+                self.unit, path.display()
+            );
+        }
+        content.store(Arc::new(slurm));
         Ok(true)
     }
 }
 
 
+//------------ FileStamp -----------------------------------------------------
+
+/// The modification time and size of a file when it was last read.
+type FileStamp = (SystemTime, u64);
+
+
+//------------ LoadError -----------------------------------------------------
+
+/// An error happened while loading a SLURM file.
+#[derive(Debug)]
+enum LoadError {
+    /// The file could not be read or parsed.
+    Io(io::Error),
+
+    /// The file has structural problems.
+    Invalid(ValidationErrors),
+}
+
+impl LoadError {
+    /// Returns the number of structural problems found.
+    fn invalid_count(&self) -> usize {
+        match self {
+            LoadError::Io(_) => 0,
+            LoadError::Invalid(errs) => errs.len(),
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> Self {
+        LoadError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for LoadError {
+    fn from(err: serde_json::Error) -> Self {
+        LoadError::Io(err.into())
+    }
+}
+
+impl From<ValidationErrors> for LoadError {
+    fn from(err: ValidationErrors) -> Self {
+        LoadError::Invalid(err)
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(err) => err.fmt(f),
+            LoadError::Invalid(errs) => {
+                write!(f, "invalid SLURM file: {}", errs)
+            }
+        }
+    }
+}
+
+
 //------------ Content -------------------------------------------------------
 
 /// The content of a SLURM file in slightly pre-processed form.
 #[derive(Default)]
 struct Content {
+    /// The SLURM version of the file.
+    version: Version,
+
     filters: ValidationOutputFilters,
     assertions: payload::Pack,
+
+    /// The ASPA filters and assertions of a version 2 file.
+    ///
+    /// The assertions are also part of `assertions`.
+    aspa: AspaRules,
+
+    /// The assertions only to be added during a certain time.
+    scheduled: Vec<Scheduled>,
+
+    /// The size of the file in bytes when it was loaded.
+    size: u64,
+
+    /// The modification time of the file when it was loaded.
+    ///
+    /// This is `None` if the file hasn’t been loaded yet.
+    modified: Option<DateTime<Utc>>,
 }
 
 impl Content {
+    /// Parses the content of a SLURM file with scheduled assertions.
+    ///
+    /// If `version` is given, the file must be of this version.
+    fn from_slice(
+        data: &[u8], version: Option<Version>
+    ) -> Result<Self, LoadError> {
+        let mut slurm: serde_json::Value = serde_json::from_slice(data)?;
+        let file_version = validate(&slurm, version)?;
+        let scheduled = Scheduled::take_all(&mut slurm)?;
+        let aspa = AspaRules::take(&mut slurm).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidData, err)
+        })?;
+        let mut res = Content::from(
+            SlurmFile::from_reader(
+                serde_json::to_vec(&slurm)?.as_slice()
+            )?
+        );
+        if !aspa.assertions.is_empty() {
+            let mut assertions = payload::PackBuilder::empty();
+            for payload in res.assertions.iter() {
+                assertions.insert_unchecked(payload.clone())
+            }
+            for item in &aspa.assertions {
+                assertions.insert_unchecked(Payload::Aspa(item.clone()))
+            }
+            res.assertions = assertions.finalize();
+        }
+        res.version = file_version;
+        res.aspa = aspa;
+        res.scheduled = scheduled;
+        Ok(res)
+    }
+
+    /// Returns the number of filters in the file.
+    fn filter_count(&self) -> usize {
+        self.filters.prefix.len() + self.filters.bgpsec.len()
+        + self.aspa.filters.len()
+    }
+
+    /// Returns whether the filters of the file drop the given payload.
+    ///
+    /// `ValidationOutputFilters::drop_payload` only considers the prefix
+    /// filters, so we need to check the BGPsec filters ourselves.
+    fn drops(&self, payload: &Payload) -> bool {
+        self.filters.drop_payload(payload)
+        || self.filters.bgpsec.iter().any(|filter| {
+            filter.drop_payload(payload)
+        })
+    }
+
+    /// Returns the time of the next change of scheduled assertions.
+    fn next_change(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.scheduled.iter().filter_map(|item| {
+            item.next_change(now)
+        }).min()
+    }
+
     fn apply(
-        &self, unit: &str, path: &Path, set: payload::Set
+        &self, unit: &str, path: &Path, stats: &ApplyStats,
+        set: payload::Set, now: DateTime<Utc>,
     ) -> payload::Set {
         // First filters, then assertions.
         let filtered = set.filter(|payload| {
-            !self.filters.drop_payload(payload)
+            if let Payload::Aspa(aspa) = payload {
+                if self.aspa.replaces(aspa.customer) {
+                    return false
+                }
+            }
+            !self.drops(payload)
         });
         let filtered_len = filtered.len();
         let mut builder = filtered.to_builder();
         builder.insert_pack(self.assertions.clone());
+        for item in &self.scheduled {
+            if item.is_active(now) {
+                builder.insert_pack(item.assertions.clone());
+            }
+        }
         let res = builder.finalize();
+        stats.added.store(res.len() - filtered_len, Relaxed);
+        stats.removed.store(set.len() - filtered_len, Relaxed);
         debug!(
             "Unit {}: file {}: added {}, removed {}.",
             unit, path.display(),
@@ -260,9 +611,391 @@ impl From<SlurmFile> for Content {
         }
         let assertions = assertions.finalize();
         Content {
+            version: Version::V1,
             filters: slurm.filters,
-            assertions
+            assertions,
+            aspa: AspaRules::default(),
+            scheduled: Vec::new(),
+            size: 0,
+            modified: None,
+        }
+    }
+}
+
+
+//------------ Scheduled -----------------------------------------------------
+
+/// An assertion only to be added during a certain time.
+#[derive(Clone, Debug)]
+struct Scheduled {
+    /// The payload of the assertion.
+    assertions: payload::Pack,
+
+    /// The time from which on the assertion is added.
+    not_before: Option<DateTime<Utc>>,
+
+    /// The time from which on the assertion isn’t added any more.
+    not_after: Option<DateTime<Utc>>,
+}
+
+impl Scheduled {
+    /// Removes all scheduled assertions from a SLURM file.
+    ///
+    /// Scheduled assertions are prefix and BGPsec assertions with a
+    /// `"notBefore"` or `"notAfter"` member. The remaining file is a
+    /// regular SLURM file.
+    fn take_all(
+        slurm: &mut serde_json::Value
+    ) -> Result<Vec<Self>, io::Error> {
+        let mut res = Vec::new();
+        let assertions = match slurm.get_mut("locallyAddedAssertions") {
+            Some(assertions) => assertions,
+            None => return Ok(res)
+        };
+        for key in ["prefixAssertions", "bgpsecAssertions"] {
+            let list = match assertions.get_mut(key).and_then(|list| {
+                list.as_array_mut()
+            }) {
+                Some(list) => list,
+                None => continue,
+            };
+            let (scheduled, plain): (Vec<_>, Vec<_>) = mem::take(
+                list
+            ).into_iter().partition(|item| {
+                item.get("notBefore").is_some()
+                || item.get("notAfter").is_some()
+            });
+            *list = plain;
+            for item in scheduled {
+                res.push(Self::from_json(key, item)?);
+            }
+        }
+        Ok(res)
+    }
+
+    /// Creates a scheduled assertion from its JSON representation.
+    fn from_json(
+        key: &str, mut item: serde_json::Value
+    ) -> Result<Self, io::Error> {
+        let not_before = Self::take_time(&mut item, "notBefore")?;
+        let not_after = Self::take_time(&mut item, "notAfter")?;
+        if let (Some(not_before), Some(not_after)) = (not_before, not_after) {
+            if not_before >= not_after {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "notBefore must be earlier than notAfter"
+                ))
+            }
+        }
+
+        // Let the SLURM parser deal with the actual assertion.
+        let mut slurm = serde_json::json!({
+            "slurmVersion": 1,
+            "validationOutputFilters": {
+                "prefixFilters": [],
+                "bgpsecFilters": [],
+            },
+            "locallyAddedAssertions": {
+                "prefixAssertions": [],
+                "bgpsecAssertions": [],
+            },
+        });
+        slurm["locallyAddedAssertions"][key] = serde_json::json!([item]);
+        let slurm = SlurmFile::from_reader(
+            serde_json::to_vec(&slurm)?.as_slice()
+        )?;
+        let mut assertions = payload::PackBuilder::empty();
+        for payload in slurm.assertions.iter_payload() {
+            assertions.insert_unchecked(payload)
+        }
+        Ok(Scheduled {
+            assertions: assertions.finalize(),
+            not_before, not_after,
+        })
+    }
+
+    /// Removes and parses a time member of an assertion.
+    fn take_time(
+        item: &mut serde_json::Value, key: &str
+    ) -> Result<Option<DateTime<Utc>>, io::Error> {
+        let value = match item.as_object_mut().and_then(|item| {
+            item.remove(key)
+        }) {
+            Some(value) => value,
+            None => return Ok(None)
+        };
+        value.as_str().and_then(|value| {
+            DateTime::parse_from_rfc3339(value).ok()
+        }).map(|value| Some(value.with_timezone(&Utc))).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid time in {} member", key)
+            )
+        })
+    }
+
+    /// Returns whether the assertion is to be added at the given time.
+    fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.not_before.map(|time| now >= time).unwrap_or(true)
+        && self.not_after.map(|time| now < time).unwrap_or(true)
+    }
+
+    /// Returns the time of the next change after the given time.
+    fn next_change(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        [self.not_before, self.not_after].into_iter().flatten().filter(
+            |time| *time > now
+        ).min()
+    }
+}
+
+
+//------------ ApplyStats ----------------------------------------------------
+
+/// The effect the last application of a SLURM file had.
+#[derive(Debug, Default)]
+struct ApplyStats {
+    /// The number of items added by assertions.
+    added: AtomicUsize,
+
+    /// The number of items removed by filters.
+    removed: AtomicUsize,
+}
+
+
+//------------ ApplySummary --------------------------------------------------
+
+/// The overall effect the last application of all SLURM files had.
+#[derive(Debug, Default)]
+struct ApplySummary {
+    /// The number of items of the source data set that were kept.
+    kept: AtomicUsize,
+
+    /// The number of items removed by filters.
+    removed: AtomicUsize,
+
+    /// The number of items added by assertions.
+    asserted: AtomicUsize,
+}
+
+
+//------------ LoadStats -----------------------------------------------------
+
+/// The state of loading a SLURM file.
+#[derive(Debug, Default)]
+struct LoadStats {
+    /// The number of consecutive failures to load the file.
+    failures: AtomicU32,
+
+    /// Is the file currently degraded?
+    degraded: AtomicBool,
+
+    /// The number of structural problems found when last loading the file.
+    invalid: AtomicUsize,
+}
+
+impl LoadStats {
+    /// Updates the stats from the file’s circuit breaker.
+    fn update(&self, breaker: &FileBreaker) {
+        self.failures.store(breaker.failures(), Relaxed);
+        self.degraded.store(breaker.is_degraded(), Relaxed);
+    }
+}
+
+
+//------------ SlurmMetrics --------------------------------------------------
+
+/// The metrics of a local exceptions unit.
+struct SlurmMetrics {
+    /// The exception files.
+    files: Arc<ExceptionSetData>,
+
+    /// The gate metrics.
+    gate: Arc<GateMetrics>,
+}
+
+impl SlurmMetrics {
+    const FILTERS_METRIC: Metric = Metric::new(
+        "slurm_file_filters", "the number of filters in a SLURM file",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const ASSERTIONS_METRIC: Metric = Metric::new(
+        "slurm_file_assertions", "the number of assertions in a SLURM file",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const SCHEDULED_METRIC: Metric = Metric::new(
+        "slurm_file_scheduled",
+        "the number of scheduled assertions in a SLURM file",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const NEXT_CHANGE_METRIC: Metric = Metric::new(
+        "slurm_next_change",
+        "the time the next scheduled assertion becomes active or expires",
+        MetricType::Text, MetricUnit::Info
+    );
+    const REMOVED_METRIC: Metric = Metric::new(
+        "slurm_file_removed",
+        "the number of items removed by a SLURM file in the last update",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const ADDED_METRIC: Metric = Metric::new(
+        "slurm_file_added",
+        "the number of items added by a SLURM file in the last update",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const SIZE_METRIC: Metric = Metric::new(
+        "slurm_file_size", "the size of a SLURM file when it was loaded",
+        MetricType::Gauge, MetricUnit::Byte
+    );
+    const MODIFIED_METRIC: Metric = Metric::new(
+        "slurm_file_modified",
+        "the modification time of a SLURM file when it was loaded",
+        MetricType::Text, MetricUnit::Info
+    );
+    const KEPT_METRIC: Metric = Metric::new(
+        "slurm_kept",
+        "the number of items of the source kept in the last update",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const REMOVED_TOTAL_METRIC: Metric = Metric::new(
+        "slurm_removed",
+        "the number of items removed by all SLURM files in the last update",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const ASSERTED_METRIC: Metric = Metric::new(
+        "slurm_asserted",
+        "the number of items added by all SLURM files in the last update",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const FAILURES_METRIC: Metric = Metric::new(
+        "slurm_file_failures",
+        "the number of consecutive failures to load a SLURM file",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const DEGRADED_METRIC: Metric = Metric::new(
+        "slurm_file_degraded",
+        "whether a SLURM file keeps failing and is retried less often",
+        MetricType::Gauge, MetricUnit::Info
+    );
+    const INVALID_METRIC: Metric = Metric::new(
+        "slurm_file_invalid",
+        "the number of problems found when last loading a SLURM file",
+        MetricType::Gauge, MetricUnit::Total
+    );
+}
+
+impl metrics::Source for SlurmMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        let files = self.files.paths.iter().zip(
+            self.files.files.iter().zip(self.files.stats.iter())
+        ).map(|(path, (content, stats))| {
+            (path.display().to_string(), content.load_full(), stats)
+        }).collect::<Vec<_>>();
+
+        target.append(&Self::FILTERS_METRIC, Some(unit_name), |records| {
+            for (path, content, _) in &files {
+                records.label_value(
+                    &[("file", path)], content.filter_count()
+                );
+            }
+        });
+        target.append(&Self::ASSERTIONS_METRIC, Some(unit_name), |records| {
+            for (path, content, _) in &files {
+                records.label_value(
+                    &[("file", path)], content.assertions.len()
+                );
+            }
+        });
+        target.append(&Self::SCHEDULED_METRIC, Some(unit_name), |records| {
+            for (path, content, _) in &files {
+                records.label_value(
+                    &[("file", path)], content.scheduled.len()
+                );
+            }
+        });
+        target.append(&Self::REMOVED_METRIC, Some(unit_name), |records| {
+            for (path, _, stats) in &files {
+                records.label_value(
+                    &[("file", path)], stats.removed.load(Relaxed)
+                );
+            }
+        });
+        target.append(&Self::ADDED_METRIC, Some(unit_name), |records| {
+            for (path, _, stats) in &files {
+                records.label_value(
+                    &[("file", path)], stats.added.load(Relaxed)
+                );
+            }
+        });
+        target.append(&Self::SIZE_METRIC, Some(unit_name), |records| {
+            for (path, content, _) in &files {
+                records.label_value(&[("file", path)], content.size);
+            }
+        });
+        target.append(&Self::MODIFIED_METRIC, Some(unit_name), |records| {
+            for (path, content, _) in &files {
+                match content.modified {
+                    Some(modified) => {
+                        records.label_value(&[("file", path)], modified);
+                    }
+                    None => {
+                        records.label_value(&[("file", path)], "N/A");
+                    }
+                }
+            }
+        });
+        match self.files.next_change(Utc::now()) {
+            Some(next) => {
+                target.append_simple(
+                    &Self::NEXT_CHANGE_METRIC, Some(unit_name), next
+                );
+            }
+            None => {
+                target.append_simple(
+                    &Self::NEXT_CHANGE_METRIC, Some(unit_name), "N/A"
+                );
+            }
         }
+        let summary = &self.files.summary;
+        target.append_simple(
+            &Self::KEPT_METRIC, Some(unit_name), summary.kept.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::REMOVED_TOTAL_METRIC, Some(unit_name),
+            summary.removed.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::ASSERTED_METRIC, Some(unit_name),
+            summary.asserted.load(Relaxed)
+        );
+        target.append(&Self::FAILURES_METRIC, Some(unit_name), |records| {
+            for ((path, _, _), stats) in
+                files.iter().zip(&self.files.load_stats)
+            {
+                records.label_value(
+                    &[("file", path)], stats.failures.load(Relaxed)
+                );
+            }
+        });
+        target.append(&Self::DEGRADED_METRIC, Some(unit_name), |records| {
+            for ((path, _, _), stats) in
+                files.iter().zip(&self.files.load_stats)
+            {
+                records.label_value(
+                    &[("file", path)],
+                    u8::from(stats.degraded.load(Relaxed))
+                );
+            }
+        });
+        target.append(&Self::INVALID_METRIC, Some(unit_name), |records| {
+            for ((path, _, _), stats) in
+                files.iter().zip(&self.files.load_stats)
+            {
+                records.label_value(
+                    &[("file", path)], stats.invalid.load(Relaxed)
+                );
+            }
+        });
+        self.gate.append(unit_name, target);
     }
 }
 
@@ -274,7 +1007,6 @@ mod test {
     use super::*;
     use crate::payload::testrig;
     use rpki::slurm::PrefixFilter;
-    use rpki::rtr::payload::Payload;
 
     #[test]
     fn apply_content() {
@@ -322,12 +1054,260 @@ mod test {
                         _ => None
                     }
                 }).collect(),
-                bgpsec: Vec::new()
+                bgpsec: Vec::new(),
+                aspa: None,
             },
-            assertions: p3
+            assertions: p3,
+            aspa: Default::default(),
+            scheduled: Vec::new(),
+            version: Version::V1,
+            size: 0,
+            modified: None,
         };
 
-        assert_eq!(content.apply("none", Path::new("/"), input), output);
+        let stats = ApplyStats::default();
+        assert_eq!(
+            content.apply(
+                "none", Path::new("/"), &stats, input, Utc::now()
+            ),
+            output
+        );
+        assert_eq!(stats.added.load(Relaxed), 15);
+        assert_eq!(stats.removed.load(Relaxed), 10);
     }
-}
 
+    #[test]
+    fn scheduled_assertions() {
+        let content = Content::from_slice(br#"{
+            "slurmVersion": 1,
+            "validationOutputFilters": {
+                "prefixFilters": [],
+                "bgpsecFilters": []
+            },
+            "locallyAddedAssertions": {
+                "prefixAssertions": [
+                    { "asn": 64496, "prefix": "198.51.100.0/24" },
+                    {
+                        "asn": 64497, "prefix": "203.0.113.0/24",
+                        "notBefore": "2024-03-01T00:00:00Z"
+                    },
+                    {
+                        "asn": 64498, "prefix": "192.0.2.0/24",
+                        "notBefore": "2024-02-01T00:00:00Z",
+                        "notAfter": "2024-04-01T00:00:00Z"
+                    }
+                ],
+                "bgpsecAssertions": []
+            }
+        }"#, None).unwrap();
+        assert_eq!(content.assertions.len(), 1);
+        assert_eq!(content.scheduled.len(), 2);
+
+        let time = |s: &str| {
+            DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+        };
+        let apply = |now: &str| {
+            content.apply(
+                "none", Path::new("/"), &ApplyStats::default(),
+                payload::Set::default(), time(now)
+            ).len()
+        };
+        assert_eq!(apply("2024-01-01T00:00:00Z"), 1);
+        assert_eq!(apply("2024-02-01T00:00:00Z"), 2);
+        assert_eq!(apply("2024-03-01T00:00:00Z"), 3);
+        assert_eq!(apply("2024-04-01T00:00:00Z"), 2);
+
+        assert_eq!(
+            content.next_change(time("2024-01-01T00:00:00Z")),
+            Some(time("2024-02-01T00:00:00Z"))
+        );
+        assert_eq!(
+            content.next_change(time("2024-02-01T00:00:00Z")),
+            Some(time("2024-03-01T00:00:00Z"))
+        );
+        assert_eq!(
+            content.next_change(time("2024-03-15T00:00:00Z")),
+            Some(time("2024-04-01T00:00:00Z"))
+        );
+        assert_eq!(content.next_change(time("2024-04-01T00:00:00Z")), None);
+    }
+
+    #[test]
+    fn scheduled_assertions_invalid() {
+        assert!(Content::from_slice(br#"{
+            "slurmVersion": 1,
+            "validationOutputFilters": {
+                "prefixFilters": [],
+                "bgpsecFilters": []
+            },
+            "locallyAddedAssertions": {
+                "prefixAssertions": [
+                    {
+                        "asn": 64498, "prefix": "192.0.2.0/24",
+                        "notBefore": "2024-04-01T00:00:00Z",
+                        "notAfter": "2024-02-01T00:00:00Z"
+                    }
+                ],
+                "bgpsecAssertions": []
+            }
+        }"#, None).is_err());
+        assert!(Content::from_slice(br#"{
+            "slurmVersion": 1,
+            "validationOutputFilters": {
+                "prefixFilters": [],
+                "bgpsecFilters": []
+            },
+            "locallyAddedAssertions": {
+                "prefixAssertions": [
+                    {
+                        "asn": 64498, "prefix": "192.0.2.0/24",
+                        "notAfter": "next tuesday"
+                    }
+                ],
+                "bgpsecAssertions": []
+            }
+        }"#, None).is_err());
+    }
+
+    #[test]
+    fn aspa_rules() {
+        use rpki::resources::asn::Asn;
+        use rpki::rtr::payload::Aspa;
+        use rpki::rtr::pdu::ProviderAsns;
+
+        fn aspa(customer: u32, providers: &[u32]) -> Payload {
+            Payload::Aspa(Aspa::new(
+                customer.into(),
+                ProviderAsns::try_from_iter(
+                    providers.iter().copied().map(Asn::from)
+                ).unwrap()
+            ))
+        }
+
+        let content = Content::from_slice(br#"{
+            "slurmVersion": 2,
+            "validationOutputFilters": {
+                "prefixFilters": [],
+                "bgpsecFilters": [],
+                "aspaFilters": [
+                    { "customerAsid": 64496 }
+                ]
+            },
+            "locallyAddedAssertions": {
+                "prefixAssertions": [],
+                "bgpsecAssertions": [],
+                "aspaAssertions": [
+                    { "customerAsid": 64497, "providerSet": [64511, 64510] }
+                ]
+            }
+        }"#, None).unwrap();
+        assert_eq!(content.version, Version::V2);
+        assert_eq!(content.filter_count(), 1);
+        assert_eq!(content.assertions.len(), 1);
+
+        let mut input = payload::PackBuilder::empty();
+        input.insert_unchecked(aspa(64496, &[64500]));
+        input.insert_unchecked(aspa(64497, &[64500]));
+        input.insert_unchecked(aspa(64498, &[64500]));
+        input.insert_unchecked(testrig::p(1));
+        let input = payload::Set::from(input.finalize());
+
+        let mut output = payload::PackBuilder::empty();
+        output.insert_unchecked(aspa(64497, &[64510, 64511]));
+        output.insert_unchecked(aspa(64498, &[64500]));
+        output.insert_unchecked(testrig::p(1));
+        let output = payload::Set::from(output.finalize());
+
+        let stats = ApplyStats::default();
+        assert_eq!(
+            content.apply(
+                "none", Path::new("/"), &stats, input, Utc::now()
+            ),
+            output
+        );
+        assert_eq!(stats.removed.load(Relaxed), 2);
+    }
+
+    #[test]
+    fn invalid_content() {
+        let err = Content::from_slice(br#"{
+            "slurmVersion": 1,
+            "validationOutputFilters": {
+                "prefixFilters": [ { "comment": "nothing" } ],
+                "bgpsecFilters": [],
+                "aspaFilters": []
+            },
+            "locallyAddedAssertions": {
+                "prefixAssertions": [
+                    { "asn": "AS64496", "prefix": "192.0.2.0/24" }
+                ],
+                "bgpsecAssertions": []
+            }
+        }"#, None).err().unwrap();
+        assert_eq!(err.invalid_count(), 3);
+
+        // A valid version 1 file is rejected if version 2 is required.
+        let err = Content::from_slice(br#"{
+            "slurmVersion": 1,
+            "validationOutputFilters": {
+                "prefixFilters": [],
+                "bgpsecFilters": []
+            },
+            "locallyAddedAssertions": {
+                "prefixAssertions": [],
+                "bgpsecAssertions": []
+            }
+        }"#, Some(Version::V2)).err().unwrap();
+        assert!(err.invalid_count() > 0);
+    }
+
+    #[test]
+    fn update_file() {
+        let dir = std::env::temp_dir().join(format!(
+            "rtrtr-slurm-unit-{}", std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("exceptions.json");
+        let data = ExceptionSetData {
+            unit: "slurm".into(),
+            paths: vec![path.clone()],
+            refresh: Duration::from_secs(1),
+            version: None,
+            files: vec![Default::default()],
+            stats: vec![Default::default()],
+            load_stats: vec![Default::default()],
+            summary: Default::default(),
+            notify: Notify::new(),
+        };
+        let file = &data.files[0];
+        let mut stamp = None;
+
+        fs::write(&path, include_bytes!(
+            "../../test-data/router-keys-exceptions.slurm.json"
+        )).unwrap();
+        assert!(data.update_file(&path, &mut stamp, file).unwrap());
+        assert!(!data.update_file(&path, &mut stamp, file).unwrap());
+        assert_eq!(file.load().filter_count(), 1);
+
+        // Pretend the file was replaced by one with an older modification
+        // time by storing a newer one in the stamp.
+        fs::write(&path, br#"{
+            "slurmVersion": 1,
+            "validationOutputFilters": {
+                "prefixFilters": [],
+                "bgpsecFilters": []
+            },
+            "locallyAddedAssertions": {
+                "prefixAssertions": [],
+                "bgpsecAssertions": []
+            }
+        }"#).unwrap();
+        stamp = stamp.map(|(modified, size)| {
+            (modified + Duration::from_secs(3600), size)
+        });
+        assert!(data.update_file(&path, &mut stamp, file).unwrap());
+        assert_eq!(file.load().filter_count(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,303 @@
+//! Receiving data sets pushed via HTTP.
+//!
+//! The _webhook_ unit registers a path with the HTTP server. Clients can
+//! POST a data set in the JSON format produced by validators to that path,
+//! which the unit then uses as its new data set. Requests have to carry a
+//! configured bearer token.
+//!
+//! This allows internal provisioning systems to push their data into RTRTR
+//! without the need for a separate web server for RTRTR to fetch from.
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use hyper::Method;
+use log::{debug, error, info};
+use serde::Deserialize;
+use tokio::sync::watch;
+use crate::{http, metrics, payload};
+use crate::cache::UnitCache;
+use crate::comms::{Gate, GateMetrics, Terminated, UnitUpdate};
+use crate::config::Secret;
+use crate::formats::json;
+use crate::http::{ContentType, ResponseBuilder};
+use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+
+
+//------------ Webhook -------------------------------------------------------
+
+/// A unit receiving data sets via HTTP POST requests.
+#[derive(Debug, Deserialize)]
+pub struct Webhook {
+    /// The path to accept data sets at.
+    path: String,
+
+    /// The bearer token requests must carry.
+    token: Secret,
+
+    /// The name of the HTTP server to use.
+    ///
+    /// If this is `None`, the default server is used.
+    server: Option<String>,
+}
+
+impl Webhook {
+    pub async fn run(
+        self, mut component: Component, mut gate: Gate
+    ) -> Result<(), Terminated> {
+        if !self.path.starts_with('/') || self.path.starts_with("/api/") {
+            error!(
+                "Unit {}: path must start with '/' and must not be below \
+                 '/api/'.",
+                component.name()
+            );
+            gate.update(UnitUpdate::Gone).await;
+            return Err(Terminated)
+        }
+
+        let metrics = Arc::new(WebhookMetrics::new(&gate));
+        component.register_metrics(metrics.clone());
+
+        let cache = component.data_cache();
+        if let Some(cache) = cache.as_ref() {
+            cache.restore(component.name(), &mut gate).await;
+        }
+
+        let (tx, mut rx) = watch::channel(None);
+        let receiver = Arc::new(Receiver {
+            name: component.name().clone(),
+            path: self.path,
+            token: self.token,
+            tx,
+            metrics: metrics.clone(),
+        });
+        component.register_http_resource(
+            receiver.clone(), self.server.as_deref()
+        );
+
+        loop {
+            tokio::select! {
+                res = rx.changed() => {
+                    // We hold on to the sender, so this can’t fail.
+                    if res.is_err() {
+                        return Err(Terminated)
+                    }
+                    let update = rx.borrow_and_update().clone();
+                    if let Some(update) = update {
+                        Self::update(update, cache.as_ref(), &mut gate).await;
+                    }
+                }
+                res = gate.process() => {
+                    res?;
+                }
+            }
+        }
+    }
+
+    /// Passes on an update received via HTTP.
+    async fn update(
+        update: payload::Update,
+        cache: Option<&UnitCache>,
+        gate: &mut Gate,
+    ) {
+        let set = update.set().clone();
+        if gate.update(UnitUpdate::Payload(update)).await {
+            if let Some(cache) = cache {
+                cache.store(&set);
+            }
+        }
+    }
+}
+
+
+//------------ Receiver ------------------------------------------------------
+
+/// The HTTP resource receiving the data sets.
+#[derive(Debug)]
+struct Receiver {
+    /// The name of the unit.
+    name: Arc<str>,
+
+    /// The path to accept data sets at.
+    path: String,
+
+    /// The bearer token requests must carry.
+    token: Secret,
+
+    /// The sender for passing received updates to the unit.
+    tx: watch::Sender<Option<payload::Update>>,
+
+    /// The metrics of the unit.
+    metrics: Arc<WebhookMetrics>,
+}
+
+impl Receiver {
+    /// Parses the body of a request into an update.
+    fn parse(body: &[u8]) -> Result<payload::Update, String> {
+        let set: json::Set = serde_json::from_slice(
+            body
+        ).map_err(|err| err.to_string())?;
+        set.check_schema()?;
+        let trust_anchors = set.trust_anchors();
+        let update = payload::Update::new(set.into_payload());
+        Ok(match trust_anchors {
+            Some(trust_anchors) => update.with_trust_anchors(trust_anchors),
+            None => update
+        })
+    }
+}
+
+impl http::ProcessRequest for Receiver {
+    fn process_request(
+        &self, request: &http::Request
+    ) -> Option<http::Response> {
+        if
+            *request.method() != Method::POST
+            || request.uri().path() != self.path
+        {
+            return None
+        }
+        if !http::has_bearer_token(request, &self.token) {
+            self.metrics.unauthorized.fetch_add(1, Relaxed);
+            return Some(
+                ResponseBuilder::unauthorized()
+                .header("WWW-Authenticate", "Bearer")
+                .content_type(ContentType::TEXT)
+                .body("Unauthorized")
+            )
+        }
+        let body = http::request_body(request).map(|body| {
+            body.as_ref()
+        }).unwrap_or_default();
+        let update = match Self::parse(body) {
+            Ok(update) => update,
+            Err(err) => {
+                debug!("Unit {}: rejected data set: {}", self.name, err);
+                self.metrics.rejected.fetch_add(1, Relaxed);
+                return Some(
+                    ResponseBuilder::bad_request()
+                    .content_type(ContentType::TEXT)
+                    .body(err)
+                )
+            }
+        };
+        let len = update.set().len();
+        info!(
+            "Unit {}: received data set with {} items from {}.",
+            self.name, len,
+            http::client_addr(request).map(|addr| {
+                addr.to_string()
+            }).unwrap_or_else(|| "unknown client".into())
+        );
+        self.metrics.accepted.fetch_add(1, Relaxed);
+        self.tx.send_replace(Some(update));
+        Some(
+            ResponseBuilder::ok()
+            .content_type(ContentType::JSON)
+            .body(serde_json::json!({ "items": len }).to_string())
+        )
+    }
+}
+
+
+//------------ WebhookMetrics ------------------------------------------------
+
+/// The metrics of a webhook unit.
+#[derive(Debug, Default)]
+struct WebhookMetrics {
+    /// The number of data sets accepted.
+    accepted: AtomicU64,
+
+    /// The number of data sets rejected as invalid.
+    rejected: AtomicU64,
+
+    /// The number of requests rejected for lacking the token.
+    unauthorized: AtomicU64,
+
+    /// The gate metrics.
+    gate: Arc<GateMetrics>,
+}
+
+impl WebhookMetrics {
+    const ACCEPTED_METRIC: Metric = Metric::new(
+        "webhook_accepted",
+        "the number of data sets accepted",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const REJECTED_METRIC: Metric = Metric::new(
+        "webhook_rejected",
+        "the number of data sets rejected as invalid",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const UNAUTHORIZED_METRIC: Metric = Metric::new(
+        "webhook_unauthorized",
+        "the number of requests rejected for lacking the token",
+        MetricType::Counter, MetricUnit::Total
+    );
+}
+
+impl WebhookMetrics {
+    fn new(gate: &Gate) -> Self {
+        WebhookMetrics {
+            gate: gate.metrics(),
+            ..Default::default()
+        }
+    }
+}
+
+impl metrics::Source for WebhookMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::ACCEPTED_METRIC, Some(unit_name),
+            self.accepted.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::REJECTED_METRIC, Some(unit_name),
+            self.rejected.load(Relaxed)
+        );
+        target.append_simple(
+            &Self::UNAUTHORIZED_METRIC, Some(unit_name),
+            self.unauthorized.load(Relaxed)
+        );
+        self.gate.append(unit_name, target);
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let update = Receiver::parse(br#"{
+            "roas": [
+                {
+                    "asn": "AS64496", "prefix": "192.0.2.0/24",
+                    "maxLength": 24, "ta": "test"
+                },
+                {
+                    "asn": "AS64497", "prefix": "2001:db8::/32",
+                    "maxLength": 48
+                }
+            ]
+        }"#).unwrap();
+        assert_eq!(update.set().len(), 2);
+        assert!(update.trust_anchors().is_some());
+
+        assert!(Receiver::parse(b"").is_err());
+        assert!(Receiver::parse(br#"{ "vrps": [] }"#).is_err());
+        assert!(Receiver::parse(br#"{
+            "metadata": { "counts": 2 },
+            "roas": [
+                {
+                    "asn": "AS64496", "prefix": "192.0.2.0/24",
+                    "maxLength": 24
+                }
+            ]
+        }"#).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+//! A circuit breaker for local files.
+//!
+//! Units that regularly read local files would otherwise retry a file that
+//! keeps failing to be read or parsed on every cycle and log an error each
+//! time. The [`FileBreaker`] keeps track of consecutive failures. Once
+//! there have been [`DEGRADED_AFTER`] of them, the file is considered
+//! degraded and further attempts are delayed with increasing backoff.
+//! Any change of the file’s modification time permits an immediate attempt
+//! so that fixing the file takes effect right away.
+
+use std::cmp;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+
+
+//------------ Configuration -------------------------------------------------
+
+/// The number of consecutive failures after which a file is degraded.
+pub const DEGRADED_AFTER: u32 = 3;
+
+/// The maximum delay between two attempts for a degraded file.
+const MAX_BACKOFF: Duration = Duration::from_secs(900);
+
+
+//------------ FileBreaker ---------------------------------------------------
+
+/// A circuit breaker for repeatedly failing reads of a local file.
+#[derive(Clone, Debug)]
+pub struct FileBreaker {
+    /// The delay after the first failure once degraded.
+    min_backoff: Duration,
+
+    /// The number of consecutive failures.
+    failures: u32,
+
+    /// The earliest time for the next attempt if degraded.
+    retry_at: Option<Instant>,
+
+    /// The modification time of the file at the last failure.
+    failed_modified: Option<SystemTime>,
+}
+
+impl FileBreaker {
+    /// Creates a new, closed breaker.
+    ///
+    /// Once degraded, attempts will be delayed by `min_backoff` first and
+    /// then by doubling amounts of time.
+    pub fn new(min_backoff: Duration) -> Self {
+        FileBreaker {
+            min_backoff,
+            failures: 0,
+            retry_at: None,
+            failed_modified: None,
+        }
+    }
+
+    /// Returns the number of consecutive failures.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Returns whether the file is currently considered degraded.
+    pub fn is_degraded(&self) -> bool {
+        self.failures >= DEGRADED_AFTER
+    }
+
+    /// Returns whether an attempt to read the file at `path` should be made.
+    pub fn permits(&self, path: impl AsRef<Path>) -> bool {
+        self.permits_at(modified(path.as_ref()), Instant::now())
+    }
+
+    /// Records a successful attempt, closing the breaker.
+    pub fn success(&mut self) {
+        self.failures = 0;
+        self.retry_at = None;
+        self.failed_modified = None;
+    }
+
+    /// Records a failed attempt to read the file at `path`.
+    ///
+    /// Returns whether the failure should be reported prominently. This is
+    /// the case unless the file already was degraded before.
+    pub fn failure(&mut self, path: impl AsRef<Path>) -> bool {
+        self.failure_at(modified(path.as_ref()), Instant::now())
+    }
+
+    /// Returns the delay until the next attempt if the file is degraded.
+    pub fn backoff(&self) -> Option<Duration> {
+        if !self.is_degraded() {
+            return None
+        }
+        let exp = cmp::min(self.failures - DEGRADED_AFTER, 16);
+        Some(cmp::min(
+            self.min_backoff.saturating_mul(1 << exp),
+            cmp::max(MAX_BACKOFF, self.min_backoff)
+        ))
+    }
+
+    fn permits_at(
+        &self, modified: Option<SystemTime>, now: Instant
+    ) -> bool {
+        if modified.is_some() && modified != self.failed_modified {
+            return true
+        }
+        match self.retry_at {
+            Some(retry_at) => now >= retry_at,
+            None => true,
+        }
+    }
+
+    fn failure_at(
+        &mut self, modified: Option<SystemTime>, now: Instant
+    ) -> bool {
+        let report = !self.is_degraded();
+        self.failures = self.failures.saturating_add(1);
+        self.failed_modified = modified;
+        self.retry_at = self.backoff().map(|backoff| now + backoff);
+        report
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Returns the modification time of a file if it can be determined.
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let start = Instant::now();
+        let secs = |secs| start + Duration::from_secs(secs);
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let mut breaker = FileBreaker::new(Duration::from_secs(10));
+        assert!(breaker.permits_at(Some(mtime), start));
+
+        // Failures before the threshold don’t delay attempts.
+        assert!(breaker.failure_at(Some(mtime), start));
+        assert!(breaker.failure_at(Some(mtime), start));
+        assert!(!breaker.is_degraded());
+        assert!(breaker.permits_at(Some(mtime), start));
+
+        // Reaching the threshold is still reported but trips the breaker.
+        assert!(breaker.failure_at(Some(mtime), start));
+        assert!(breaker.is_degraded());
+        assert_eq!(breaker.backoff(), Some(Duration::from_secs(10)));
+        assert!(!breaker.permits_at(Some(mtime), secs(9)));
+        assert!(breaker.permits_at(Some(mtime), secs(10)));
+
+        // Further failures aren’t reported and double the backoff.
+        assert!(!breaker.failure_at(Some(mtime), secs(10)));
+        assert_eq!(breaker.backoff(), Some(Duration::from_secs(20)));
+        assert!(!breaker.permits_at(Some(mtime), secs(29)));
+        assert!(breaker.permits_at(Some(mtime), secs(30)));
+
+        // A changed file is tried right away.
+        let new_mtime = mtime + Duration::from_secs(1);
+        assert!(breaker.permits_at(Some(new_mtime), secs(11)));
+
+        // The backoff is capped.
+        for _ in 0..100 {
+            breaker.failure_at(None, start);
+        }
+        assert_eq!(breaker.backoff(), Some(MAX_BACKOFF));
+
+        breaker.success();
+        assert_eq!(breaker.failures(), 0);
+        assert!(!breaker.is_degraded());
+        assert!(breaker.permits_at(None, start));
+    }
+}
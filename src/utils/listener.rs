@@ -0,0 +1,811 @@
+//! Listening sockets.
+//!
+//! Both the HTTP server and the RTR target listen for incoming connections.
+//! This module provides the parts of doing so that are shared between them:
+//! Binding the sockets, accepting connections, applying socket options to
+//! them, wrapping them into TLS if necessary, and keeping metrics on the
+//! accept loop.
+//!
+//! Listeners can restrict the client addresses they accept connections from
+//! via an [`AccessList`]. Connections from other addresses are closed right
+//! away.
+//!
+//! Binding happens synchronously via [`bind`] so that it can be done before
+//! privileges are dropped and before the runtime is started. The bound
+//! socket is later turned into a [`Listener`] from within the runtime.
+//!
+//! On Unix systems, listeners can also use Unix domain sockets bound via
+//! [`bind_unix`]. Which kind of socket to use is configured through
+//! [`ListenAddrs`].
+//!
+//! Errors while accepting connections don’t necessarily mean that the
+//! listener is broken. Errors relating to a single connection are simply
+//! skipped. If the process runs out of file descriptors or memory, the
+//! listener pauses for a while and then tries again with an increasing
+//! backoff. Only all other errors are returned to the caller.
+
+use std::{cmp, fmt, io};
+use std::future::{Future, poll_fn};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::net::TcpListener as StdTcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener as StdUnixListener;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use daemonbase::config::ConfigPath;
+use daemonbase::error::ExitError;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Deserializer};
+use serde::de::IntoDeserializer;
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::time::{Sleep, sleep};
+use crate::utils::net::AccessList;
+use crate::utils::tls::{MaybeTlsTcpStream, TlsAcceptor};
+
+
+//------------ bind ----------------------------------------------------------
+
+/// Binds a listening socket to the given address.
+///
+/// The socket is set to non-blocking mode so it can later be used with a
+/// [`Listener`]. Errors are logged and result in a fatal error.
+pub fn bind(addr: SocketAddr) -> Result<StdTcpListener, ExitError> {
+    let listener = match StdTcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Fatal: error listening on {}: {}", addr, err);
+            return Err(ExitError::default())
+        }
+    };
+    if let Err(err) = listener.set_nonblocking(true) {
+        error!(
+            "Fatal: failed to set listener {} to non-blocking: {}.",
+            addr, err
+        );
+        return Err(ExitError::default())
+    }
+    Ok(listener)
+}
+
+
+/// Binds a listening Unix domain socket to the given path.
+///
+/// If there already is a socket at the path, it is assumed to be left over
+/// from an earlier run and removed. The socket is set to non-blocking mode
+/// so it can later be used with a [`Listener`]. Errors are logged and
+/// result in a fatal error.
+#[cfg(unix)]
+pub fn bind_unix(path: &Path) -> Result<StdUnixListener, ExitError> {
+    use std::fs;
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            if let Err(err) = fs::remove_file(path) {
+                error!(
+                    "Fatal: failed to remove stale socket {}: {}",
+                    path.display(), err
+                );
+                return Err(ExitError::default())
+            }
+        }
+    }
+    let listener = match StdUnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!(
+                "Fatal: error listening on unix:{}: {}", path.display(), err
+            );
+            return Err(ExitError::default())
+        }
+    };
+    if let Err(err) = listener.set_nonblocking(true) {
+        error!(
+            "Fatal: failed to set listener unix:{} to non-blocking: {}.",
+            path.display(), err
+        );
+        return Err(ExitError::default())
+    }
+    Ok(listener)
+}
+
+
+//------------ ListenAddrs ---------------------------------------------------
+
+/// The addresses a server should listen on.
+///
+/// In the configuration, the addresses are given as a list of strings. Each
+/// string is either a socket address for a TCP listener or, on Unix
+/// systems, the path of a Unix domain socket prefixed with `unix:`.
+/// Relative paths are relative to the directory of the config file.
+#[derive(Clone, Debug, Default)]
+pub struct ListenAddrs {
+    /// The socket addresses of the TCP listeners.
+    tcp: Vec<SocketAddr>,
+
+    /// The paths of the Unix domain socket listeners.
+    #[cfg(unix)]
+    unix: Vec<ConfigPath>,
+}
+
+impl ListenAddrs {
+    /// Returns the socket addresses of the TCP listeners.
+    pub fn tcp(&self) -> &[SocketAddr] {
+        &self.tcp
+    }
+
+    /// Returns the paths of the Unix domain socket listeners.
+    #[cfg(unix)]
+    pub fn unix(&self) -> &[ConfigPath] {
+        &self.unix
+    }
+
+    /// Adds the address given in the configuration.
+    fn push<E: serde::de::Error>(&mut self, addr: String) -> Result<(), E> {
+        if let Some(path) = addr.strip_prefix("unix:") {
+            let path = ConfigPath::deserialize(
+                IntoDeserializer::<E>::into_deserializer(path.to_string())
+            )?;
+            return self.push_unix(path)
+        }
+        self.tcp.push(addr.parse().map_err(|_| {
+            E::custom(format!("invalid listen address '{}'", addr))
+        })?);
+        Ok(())
+    }
+
+    /// Adds the path of a Unix domain socket.
+    #[cfg(unix)]
+    fn push_unix<E: serde::de::Error>(
+        &mut self, path: ConfigPath
+    ) -> Result<(), E> {
+        self.unix.push(path);
+        Ok(())
+    }
+
+    /// Adds the path of a Unix domain socket.
+    ///
+    /// This is the non-Unix version that always fails.
+    #[cfg(not(unix))]
+    fn push_unix<E: serde::de::Error>(
+        &mut self, _path: ConfigPath
+    ) -> Result<(), E> {
+        Err(E::custom("Unix domain sockets are not supported on this system"))
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenAddrs {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D
+    ) -> Result<Self, D::Error> {
+        let mut res = ListenAddrs::default();
+        for addr in Vec::<String>::deserialize(deserializer)? {
+            res.push(addr)?;
+        }
+        Ok(res)
+    }
+}
+
+
+//------------ ListenAddr ----------------------------------------------------
+
+/// The client address given for connections via Unix domain sockets.
+///
+/// Since these connections don’t have an IP address, they are treated as
+/// coming from the IPv6 loopback address with port 0.
+pub const UNIX_PEER_ADDR: SocketAddr = SocketAddr::new(
+    IpAddr::V6(Ipv6Addr::LOCALHOST), 0
+);
+
+/// The address of a single listener.
+#[derive(Clone, Debug)]
+pub enum ListenAddr {
+    /// A TCP listener bound to the given socket address.
+    Tcp(SocketAddr),
+
+    /// A Unix domain socket listener bound to the given path.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ListenAddr::Tcp(addr) => addr.fmt(f),
+            #[cfg(unix)]
+            ListenAddr::Unix(ref path) => {
+                write!(f, "unix:{}", path.display())
+            }
+        }
+    }
+}
+
+
+//------------ Listener ------------------------------------------------------
+
+/// A listening socket producing accepted connections.
+pub struct Listener {
+    /// The address the listener is bound to.
+    addr: ListenAddr,
+
+    /// The actual socket.
+    sock: ListenSocket,
+
+    /// The TLS acceptor if connections should use TLS.
+    tls: Option<TlsAcceptor>,
+
+    /// The socket options to apply to accepted connections.
+    options: SocketOptions,
+
+    /// The addresses to accept connections from.
+    access: Arc<AccessList>,
+
+    /// The metrics for the accept loop.
+    metrics: Arc<AcceptMetrics>,
+
+    /// The timer while accepting is paused after running out of resources.
+    backoff: Option<Pin<Box<Sleep>>>,
+
+    /// The duration of the next pause.
+    next_backoff: Duration,
+}
+
+impl Listener {
+    /// The duration of the first pause after running out of resources.
+    const MIN_BACKOFF: Duration = Duration::from_millis(10);
+
+    /// The maximum duration of a pause after running out of resources.
+    const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+    /// Creates a new listener from a bound socket.
+    ///
+    /// The address the listener is bound to is read back from the socket,
+    /// so that it contains the actual port if `addr` asked for port 0.
+    /// `addr` is only used if the socket can’t provide its address.
+    ///
+    /// This needs to be called from within a Tokio runtime.
+    pub fn new(
+        sock: StdTcpListener,
+        addr: SocketAddr,
+        tls: Option<TlsAcceptor>,
+        options: SocketOptions,
+        metrics: Arc<AcceptMetrics>,
+    ) -> Result<Self, io::Error> {
+        Ok(Listener {
+            addr: ListenAddr::Tcp(sock.local_addr().unwrap_or(addr)),
+            sock: ListenSocket::Tcp(TcpListener::from_std(sock)?),
+            tls, options,
+            access: Default::default(),
+            metrics,
+            backoff: None,
+            next_backoff: Self::MIN_BACKOFF,
+        })
+    }
+
+    /// Creates a new listener from a bound Unix domain socket.
+    ///
+    /// Connections accepted by the listener never use TLS. Their client
+    /// address is given as [`UNIX_PEER_ADDR`].
+    ///
+    /// This needs to be called from within a Tokio runtime.
+    #[cfg(unix)]
+    pub fn new_unix(
+        sock: StdUnixListener,
+        path: PathBuf,
+        metrics: Arc<AcceptMetrics>,
+    ) -> Result<Self, io::Error> {
+        Ok(Listener {
+            addr: ListenAddr::Unix(path),
+            sock: ListenSocket::Unix(UnixListener::from_std(sock)?),
+            tls: None,
+            options: Default::default(),
+            access: Default::default(),
+            metrics,
+            backoff: None,
+            next_backoff: Self::MIN_BACKOFF,
+        })
+    }
+
+    /// Restricts the addresses connections are accepted from.
+    ///
+    /// Connections from addresses not permitted by `access` are closed
+    /// right away and counted as denied. Connections via Unix domain
+    /// sockets are not affected.
+    pub fn with_access(mut self, access: Arc<AccessList>) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// Returns the address the listener is bound to.
+    pub fn addr(&self) -> &ListenAddr {
+        &self.addr
+    }
+
+    /// Polls for the next accepted connection.
+    ///
+    /// Connections from addresses not permitted by the access list are
+    /// closed right away and counted as denied. Connections the socket
+    /// options cannot be applied to are closed right away and counted as
+    /// accept errors. Errors from the listening
+    /// socket itself are counted and logged. If they are transient, the
+    /// listener carries on, possibly after a pause. Only other errors are
+    /// returned.
+    pub fn poll_accept(
+        &mut self, cx: &mut Context
+    ) -> Poll<Result<(MaybeTlsTcpStream, SocketAddr), io::Error>> {
+        loop {
+            if let Some(backoff) = self.backoff.as_mut() {
+                ready!(backoff.as_mut().poll(cx));
+                self.backoff = None;
+            }
+            let res = match self.sock {
+                ListenSocket::Tcp(ref tcp) => match tcp.poll_accept(cx) {
+                    Poll::Ready(Ok((sock, addr))) => {
+                        if !self.access.permits(addr.ip()) {
+                            self.metrics.denied.fetch_add(1, Relaxed);
+                            debug!(
+                                "Denied connection from {} on {}.",
+                                addr, self.addr
+                            );
+                            continue
+                        }
+                        if let Err(err) = self.options.apply(&sock) {
+                            self.metrics.errors.fetch_add(1, Relaxed);
+                            debug!(
+                                "Failed to set socket options for \
+                                 connection from {} on {}: {}",
+                                addr, self.addr, err
+                            );
+                            continue
+                        }
+                        Ok((
+                            MaybeTlsTcpStream::new(sock, self.tls.as_ref()),
+                            addr
+                        ))
+                    }
+                    Poll::Ready(Err(err)) => Err(err),
+                    Poll::Pending => return Poll::Pending
+                },
+                #[cfg(unix)]
+                ListenSocket::Unix(ref unix) => match unix.poll_accept(cx) {
+                    Poll::Ready(Ok((sock, _))) => {
+                        Ok((MaybeTlsTcpStream::unix(sock), UNIX_PEER_ADDR))
+                    }
+                    Poll::Ready(Err(err)) => Err(err),
+                    Poll::Pending => return Poll::Pending
+                },
+            };
+            match res {
+                Ok(res) => {
+                    if self.next_backoff != Self::MIN_BACKOFF {
+                        info!(
+                            "Resumed accepting connections on {}.",
+                            self.addr
+                        );
+                        self.next_backoff = Self::MIN_BACKOFF;
+                    }
+                    self.metrics.accepted.fetch_add(1, Relaxed);
+                    return Poll::Ready(Ok(res))
+                }
+                Err(err) => {
+                    self.metrics.errors.fetch_add(1, Relaxed);
+                    match AcceptError::classify(&err) {
+                        AcceptError::Connection => {
+                            debug!(
+                                "Error accepting connection on {}: {}",
+                                self.addr, err
+                            );
+                        }
+                        AcceptError::Resources => {
+                            self.pause(err);
+                        }
+                        AcceptError::Fatal => {
+                            error!(
+                                "Error accepting connection on {}: {}",
+                                self.addr, err
+                            );
+                            return Poll::Ready(Err(err))
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Accepts the next connection.
+    pub async fn accept(
+        &mut self
+    ) -> Result<(MaybeTlsTcpStream, SocketAddr), io::Error> {
+        poll_fn(|cx| self.poll_accept(cx)).await
+    }
+
+    /// Pauses accepting after running out of resources.
+    ///
+    /// Only the first pause in a row is logged as a warning so that the
+    /// log isn’t flooded while the condition persists.
+    fn pause(&mut self, err: io::Error) {
+        if self.next_backoff == Self::MIN_BACKOFF {
+            warn!(
+                "Error accepting connection on {}: {}. \
+                 Pausing accepting connections.",
+                self.addr, err
+            );
+        }
+        else {
+            debug!(
+                "Error accepting connection on {}: {}. \
+                 Pausing for {}ms.",
+                self.addr, err, self.next_backoff.as_millis()
+            );
+        }
+        self.metrics.pauses.fetch_add(1, Relaxed);
+        self.backoff = Some(Box::pin(sleep(self.next_backoff)));
+        self.next_backoff = cmp::min(
+            self.next_backoff * 2, Self::MAX_BACKOFF
+        );
+    }
+}
+
+
+//------------ ListenSocket --------------------------------------------------
+
+/// The socket of a listener.
+enum ListenSocket {
+    /// A TCP socket.
+    Tcp(TcpListener),
+
+    /// A Unix domain socket.
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+
+//------------ AcceptError ---------------------------------------------------
+
+/// The kind of an error returned by the listening socket.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum AcceptError {
+    /// The error relates to a single connection only.
+    Connection,
+
+    /// The process or system has run out of resources.
+    Resources,
+
+    /// The listener can’t be used any more.
+    Fatal,
+}
+
+impl AcceptError {
+    /// Determines the kind of an error.
+    fn classify(err: &io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock => return AcceptError::Connection,
+            io::ErrorKind::OutOfMemory => return AcceptError::Resources,
+            _ => { }
+        }
+        Self::classify_os(err.raw_os_error())
+    }
+
+    /// Determines the kind of an error from the OS error code.
+    #[cfg(unix)]
+    fn classify_os(code: Option<i32>) -> Self {
+        use nix::libc;
+
+        match code {
+            Some(
+                libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM
+            ) => AcceptError::Resources,
+            Some(libc::ECONNABORTED | libc::EPROTO | libc::EPERM) => {
+                AcceptError::Connection
+            }
+            _ => AcceptError::Fatal,
+        }
+    }
+
+    /// Determines the kind of an error from the OS error code.
+    ///
+    /// This is the non-Unix version that only knows the error kinds.
+    #[cfg(not(unix))]
+    fn classify_os(_code: Option<i32>) -> Self {
+        AcceptError::Fatal
+    }
+}
+
+
+//------------ SocketOptions -------------------------------------------------
+
+/// Options applied to all accepted connections.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SocketOptions {
+    /// The TCP keepalive duration if keepalive should be used.
+    pub keepalive: Option<Duration>,
+}
+
+impl SocketOptions {
+    /// Applies the options to a socket.
+    fn apply(&self, sock: &TcpStream) -> Result<(), io::Error> {
+        if let Some(duration) = self.keepalive {
+            Self::set_keepalive(sock, duration)?
+        }
+        Ok(())
+    }
+
+    /// Sets the TCP keepalive.
+    #[cfg(unix)]
+    fn set_keepalive(
+        sock: &TcpStream, duration: Duration
+    ) -> Result<(), io::Error>{
+        use nix::sys::socket::{setsockopt, sockopt};
+
+        (|fd, duration: Duration| {
+            setsockopt(fd, sockopt::KeepAlive, &true)?;
+
+            // The attributes are copied from the definitions in
+            // nix::sys::socket::sockopt. Let’s hope they never change.
+
+            #[cfg(any(target_os = "ios", target_os = "macos"))]
+            setsockopt(
+                fd, sockopt::TcpKeepAlive,
+                &u32::try_from(duration.as_secs()).unwrap_or(u32::MAX)
+            )?;
+
+            #[cfg(any(
+                target_os = "android",
+                target_os = "dragonfly",
+                target_os = "freebsd",
+                target_os = "linux",
+            ))]
+            setsockopt(
+                fd, sockopt::TcpKeepIdle,
+                &u32::try_from(duration.as_secs()).unwrap_or(u32::MAX)
+            )?;
+
+            #[cfg(not(target_os = "openbsd"))]
+            setsockopt(
+                fd, sockopt::TcpKeepInterval,
+                &u32::try_from(duration.as_secs()).unwrap_or(u32::MAX)
+            )?;
+
+            Ok(())
+        })(sock, duration).map_err(|err: nix::errno::Errno| {
+            io::Error::new(io::ErrorKind::Other, err)
+        })
+    }
+
+    /// Sets the TCP keepalive.
+    ///
+    /// This is the non-Unix version that is actually a no-op.
+    #[cfg(not(unix))]
+    fn set_keepalive(
+        _sock: &TcpStream, _duration: Duration
+    ) -> Result<(), io::Error>{
+        Ok(())
+    }
+}
+
+
+//------------ AcceptMetrics -------------------------------------------------
+
+/// Metrics for the accept loop of one or more listeners.
+///
+/// The metrics are not output directly. Rather, the owner of the listeners
+/// includes them in its own metrics under appropriate names.
+#[derive(Debug, Default)]
+pub struct AcceptMetrics {
+    /// The number of connections accepted.
+    accepted: AtomicU64,
+
+    /// The number of failed attempts to accept a connection.
+    errors: AtomicU64,
+
+    /// The number of times accepting was paused due to lack of resources.
+    pauses: AtomicU64,
+
+    /// The number of connections denied because of the access list.
+    denied: AtomicU64,
+}
+
+impl AcceptMetrics {
+    /// Returns the number of connections accepted.
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Relaxed)
+    }
+
+    /// Returns the number of failed attempts to accept a connection.
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Relaxed)
+    }
+
+    /// Returns the number of times accepting was paused.
+    pub fn pauses(&self) -> u64 {
+        self.pauses.load(Relaxed)
+    }
+
+    /// Returns the number of connections denied by the access list.
+    pub fn denied(&self) -> u64 {
+        self.denied.load(Relaxed)
+    }
+}
+
+
+
+
+//============ Tests =========================================================
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use nix::libc;
+    use nix::sys::resource::{getrlimit, setrlimit, Resource};
+
+    #[test]
+    fn classify() {
+        assert_eq!(
+            AcceptError::classify(
+                &io::Error::from_raw_os_error(libc::EMFILE)
+            ),
+            AcceptError::Resources
+        );
+        assert_eq!(
+            AcceptError::classify(
+                &io::Error::from_raw_os_error(libc::ENFILE)
+            ),
+            AcceptError::Resources
+        );
+        assert_eq!(
+            AcceptError::classify(
+                &io::Error::from_raw_os_error(libc::ECONNABORTED)
+            ),
+            AcceptError::Connection
+        );
+        assert_eq!(
+            AcceptError::classify(
+                &io::Error::from_raw_os_error(libc::EBADF)
+            ),
+            AcceptError::Fatal
+        );
+    }
+
+    /// Runs `fd_exhaustion_child` in a separate process.
+    ///
+    /// Lowering the file descriptor limit affects the whole process and
+    /// thus all other tests running at the same time.
+    #[test]
+    fn fd_exhaustion() {
+        let status = std::process::Command::new(
+            std::env::current_exe().unwrap()
+        ).args([
+            "utils::listener::test::fd_exhaustion_child",
+            "--exact", "--ignored", "--test-threads=1",
+        ]).env("RTRTR_TEST_FD_EXHAUSTION", "1").status().unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    #[ignore = "run by fd_exhaustion in its own process"]
+    fn fd_exhaustion_child() {
+        if std::env::var_os("RTRTR_TEST_FD_EXHAUSTION").is_none() {
+            return
+        }
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all().build().unwrap();
+        runtime.block_on(async {
+            let sock = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            let addr = sock.local_addr().unwrap();
+            let metrics = Arc::new(AcceptMetrics::default());
+            let mut listener = Listener::new(
+                sock, addr, None, SocketOptions::default(), metrics.clone()
+            ).unwrap();
+
+            // Connect while we still can. The connection waits in the
+            // listen queue until it is accepted.
+            let _client = std::net::TcpStream::connect(addr).unwrap();
+
+            // Use up all file descriptors.
+            let (soft, hard) = getrlimit(Resource::RLIMIT_NOFILE).unwrap();
+            setrlimit(Resource::RLIMIT_NOFILE, soft.min(64), hard).unwrap();
+            let mut files = Vec::new();
+            while let Ok(file) = std::fs::File::open("/dev/null") {
+                files.push(file)
+            }
+
+            // Accepting now fails and the listener pauses.
+            assert!(
+                tokio::time::timeout(
+                    Duration::from_millis(200), listener.accept()
+                ).await.is_err()
+            );
+            assert!(metrics.errors() > 0);
+            assert!(metrics.pauses() > 0);
+            assert_eq!(metrics.accepted(), 0);
+
+            // Once descriptors are available again, the listener recovers.
+            drop(files);
+            assert!(matches!(
+                tokio::time::timeout(
+                    Duration::from_secs(5), listener.accept()
+                ).await,
+                Ok(Ok(_))
+            ));
+            assert_eq!(metrics.accepted(), 1);
+        })
+    }
+
+    #[test]
+    fn listen_addrs() {
+        #[derive(Deserialize)]
+        struct Config {
+            listen: ListenAddrs,
+        }
+
+        let config: Config = toml::from_str(
+            r#"listen = [ "127.0.0.1:323", "unix:/run/rtrtr.sock" ]"#
+        ).unwrap();
+        assert_eq!(
+            config.listen.tcp(), ["127.0.0.1:323".parse().unwrap()]
+        );
+        assert_eq!(config.listen.unix().len(), 1);
+        let path: &Path = &config.listen.unix()[0];
+        assert_eq!(path, Path::new("/run/rtrtr.sock"));
+        assert!(
+            toml::from_str::<Config>(r#"listen = [ "localhost" ]"#).is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn unix_listener() {
+        let path = std::env::temp_dir().join(
+            format!("rtrtr-test-listener-{}.sock", std::process::id())
+        );
+        let metrics = Arc::new(AcceptMetrics::default());
+        let mut listener = Listener::new_unix(
+            bind_unix(&path).unwrap(), path.clone(), metrics.clone()
+        ).unwrap();
+        assert_eq!(
+            listener.addr().to_string(), format!("unix:{}", path.display())
+        );
+        let _client = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (_, addr) = listener.accept().await.unwrap();
+        assert_eq!(addr, UNIX_PEER_ADDR);
+        assert_eq!(metrics.accepted(), 1);
+
+        // Binding again replaces the stale socket.
+        drop(listener);
+        assert!(bind_unix(&path).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn denied_connections() {
+        use std::str::FromStr;
+        use rpki::resources::addr::Prefix;
+
+        let sock = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = sock.local_addr().unwrap();
+        let metrics = Arc::new(AcceptMetrics::default());
+        let mut listener = Listener::new(
+            sock, addr, None, SocketOptions::default(), metrics.clone()
+        ).unwrap().with_access(Arc::new(AccessList::new(
+            &[], &[Prefix::from_str("127.0.0.0/8").unwrap()]
+        )));
+        let _client = TcpStream::connect(addr).await.unwrap();
+        assert!(
+            tokio::time::timeout(
+                Duration::from_millis(100), listener.accept()
+            ).await.is_err()
+        );
+        assert_eq!(metrics.denied(), 1);
+        assert_eq!(metrics.accepted(), 0);
+    }
+}
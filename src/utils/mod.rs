@@ -1,2 +1,11 @@
+pub mod breaker;
 pub mod http;
+pub mod listener;
+pub mod nats;
+pub mod net;
+#[cfg(feature = "ssh")]
+pub mod ssh;
+pub mod tcp_ao;
+pub mod tcp_md5;
 pub mod tls;
+pub mod rtr;
@@ -0,0 +1,741 @@
+//! A minimal client for the NATS messaging protocol.
+//!
+//! Both the _nats_ target and the _nats_ unit talk to a NATS server. This
+//! module provides the small subset of the client protocol they need:
+//! connecting and authenticating, publishing messages, subscribing to a
+//! subject and receiving its messages, and answering the server’s
+//! keepalive pings. Connections can be upgraded to TLS as described by the
+//! protocol if the `tls` feature is enabled. Message headers are not
+//! supported.
+
+use std::{fmt, io};
+use std::pin::Pin;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use bytes::{Buf, Bytes, BytesMut};
+use daemonbase::config::ConfigPath;
+use daemonbase::error::ExitError;
+use log::error;
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsConnector;
+#[cfg(feature = "tls")]
+use tokio_rustls::client::TlsStream;
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::pki_types::ServerName;
+use crate::config::Secret;
+#[cfg(feature = "tls")]
+use crate::utils::tls::create_client_config;
+
+
+//------------ Configuration -------------------------------------------------
+
+/// The maximum payload size assumed if the server doesn’t tell us.
+const DEFAULT_MAX_PAYLOAD: usize = 1024 * 1024;
+
+
+//------------ Auth ----------------------------------------------------------
+
+/// The authentication used when connecting to a server.
+#[derive(Clone, Debug, Default)]
+pub struct Auth {
+    /// The user name and optional password.
+    user: Option<(String, Option<Secret>)>,
+
+    /// The authentication token.
+    token: Option<Secret>,
+}
+
+impl Auth {
+    /// Creates the authentication from the configuration values.
+    ///
+    /// Since a password or token would be sent in the clear otherwise,
+    /// they can only be given if the connection uses TLS as indicated by
+    /// `tls`.
+    pub fn new(
+        username: Option<&String>,
+        password: Option<&Secret>,
+        token: Option<&Secret>,
+        tls: bool,
+    ) -> Result<Self, &'static str> {
+        match (username, password, token) {
+            (None, Some(_), _) => {
+                Err("'password' given without 'username'")
+            }
+            (Some(_), _, Some(_)) => {
+                Err(
+                    "only one of user and token authentication \
+                     can be given"
+                )
+            }
+            (_, Some(_), _) | (_, _, Some(_)) if !tls => {
+                Err(
+                    "'password' and 'token' can only be used with 'tls' \
+                     to avoid sending them in the clear"
+                )
+            }
+            (username, password, token) => {
+                Ok(Auth {
+                    user: username.map(|username| {
+                        (username.clone(), password.cloned())
+                    }),
+                    token: token.cloned(),
+                })
+            }
+        }
+    }
+}
+
+
+//------------ Tls -----------------------------------------------------------
+
+/// The TLS configuration for connecting to a server.
+#[cfg(feature = "tls")]
+#[derive(Clone)]
+pub struct Tls {
+    /// The connector with the TLS client configuration.
+    connector: TlsConnector,
+
+    /// The name of the server used for checking its certificate.
+    domain: ServerName<'static>,
+}
+
+/// A placeholder for the TLS configuration if TLS support is disabled.
+///
+/// Values of this type cannot exist.
+#[cfg(not(feature = "tls"))]
+#[derive(Clone)]
+pub enum Tls { }
+
+impl Tls {
+    /// Creates the TLS configuration if TLS is enabled.
+    ///
+    /// The server’s certificate is checked against the usual web PKI
+    /// roots and the certificates from the files in `cacerts`. The host
+    /// name to check the certificate for is taken from `server`. The
+    /// component using the connection is given via `component` for logging.
+    #[cfg(feature = "tls")]
+    pub fn new(
+        component: &str, server: &str, tls: bool, cacerts: &[ConfigPath]
+    ) -> Result<Option<Self>, ExitError> {
+        if !tls {
+            return Ok(None)
+        }
+        let host = match server.rsplit_once(':') {
+            Some((host, _)) => host,
+            None => server,
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let domain = ServerName::try_from(host.to_string()).map_err(|_| {
+            error!(
+                "Invalid server name '{}' for '{}' TLS connection.",
+                host, component
+            );
+            ExitError::default()
+        })?;
+        Ok(Some(Tls {
+            connector: TlsConnector::from(Arc::new(
+                create_client_config(component, cacerts)?
+            )),
+            domain,
+        }))
+    }
+
+    /// Creates the TLS configuration if TLS is enabled.
+    ///
+    /// Since TLS support is disabled, this fails if TLS is enabled.
+    #[cfg(not(feature = "tls"))]
+    pub fn new(
+        component: &str, _server: &str, tls: bool, _cacerts: &[ConfigPath]
+    ) -> Result<Option<Self>, ExitError> {
+        if tls {
+            error!(
+                "Cannot use TLS for '{}': TLS support is not enabled.",
+                component
+            );
+            return Err(ExitError::default())
+        }
+        Ok(None)
+    }
+}
+
+impl fmt::Debug for Tls {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tls").finish()
+    }
+}
+
+
+//------------ Connection ----------------------------------------------------
+
+/// A connection to a NATS server.
+///
+/// All data to be sent goes through an output buffer. Since this buffer
+/// keeps track of what has been written already, writing is cancel safe.
+pub struct Connection {
+    /// The socket.
+    sock: Stream,
+
+    /// Data read from the socket but not yet processed.
+    buf: BytesMut,
+
+    /// Data to be written to the socket.
+    out: BytesMut,
+
+    /// The maximum payload size accepted by the server.
+    max_payload: usize,
+}
+
+impl Connection {
+    /// Connects to a server and authenticates.
+    ///
+    /// The server is given as host and port separated by a colon. If `tls`
+    /// is given, the connection is upgraded to TLS after the server has
+    /// sent its information.
+    pub async fn connect(
+        server: &str, auth: &Auth, tls: Option<&Tls>,
+    ) -> Result<Self, io::Error> {
+        let mut sock = TcpStream::connect(server).await?;
+        let mut buf = BytesMut::new();
+        let mut max_payload = DEFAULT_MAX_PAYLOAD;
+        match read_op(&mut sock, &mut buf, max_payload).await? {
+            ServerOp::Info(info) => {
+                if info.tls_required && tls.is_none() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "server requires TLS but 'tls' is not enabled"
+                    ))
+                }
+                if let Some(max) = info.max_payload {
+                    max_payload = max;
+                }
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData, "expected INFO from server"
+                ))
+            }
+        }
+        let sock = match tls {
+            #[cfg(feature = "tls")]
+            Some(tls) => {
+                Stream::Tls(Box::new(
+                    tls.connector.connect(tls.domain.clone(), sock).await?
+                ))
+            }
+            #[cfg(not(feature = "tls"))]
+            Some(tls) => match *tls { },
+            None => Stream::Plain(sock),
+        };
+        let mut res = Connection {
+            sock, buf, out: BytesMut::new(), max_payload,
+        };
+        let mut connect = serde_json::json!({
+            "verbose": false,
+            "pedantic": false,
+            "tls_required": tls.is_some(),
+            "name": "rtrtr",
+            "lang": "rust",
+            "version": clap::crate_version!(),
+        });
+        if let Some((username, password)) = auth.user.as_ref() {
+            connect["user"] = username.as_str().into();
+            if let Some(password) = password.as_ref() {
+                connect["pass"] = password.expose().into();
+            }
+        }
+        if let Some(token) = auth.token.as_ref() {
+            connect["auth_token"] = token.expose().into();
+        }
+        res.out.extend_from_slice(b"CONNECT ");
+        // Serializing a JSON value into a vec can’t fail.
+        res.out.extend_from_slice(&serde_json::to_vec(&connect).unwrap());
+        res.out.extend_from_slice(b"\r\nPING\r\n");
+        res.wait_pong().await?;
+        Ok(res)
+    }
+
+    /// Returns the maximum payload size accepted by the server.
+    pub fn max_payload(&self) -> usize {
+        self.max_payload
+    }
+
+    /// Publishes messages to a subject.
+    ///
+    /// Waits until the server has confirmed the messages. The caller needs
+    /// to make sure that the messages don’t exceed the maximum payload.
+    pub async fn publish(
+        &mut self, subject: &str, messages: &[Vec<u8>]
+    ) -> Result<(), io::Error> {
+        for message in messages {
+            self.out.extend_from_slice(
+                format!("PUB {} {}\r\n", subject, message.len()).as_bytes()
+            );
+            self.out.extend_from_slice(message);
+            self.out.extend_from_slice(b"\r\n");
+        }
+        self.out.extend_from_slice(b"PING\r\n");
+        self.wait_pong().await
+    }
+
+    /// Subscribes to a subject.
+    ///
+    /// Waits until the server has confirmed the subscription. Messages
+    /// for the subscription can then be received via
+    /// [`next_message`](Self::next_message).
+    pub async fn subscribe(
+        &mut self, subject: &str
+    ) -> Result<(), io::Error> {
+        self.out.extend_from_slice(
+            format!("SUB {} 1\r\nPING\r\n", subject).as_bytes()
+        );
+        self.wait_pong().await
+    }
+
+    /// Returns the payload of the next message received.
+    ///
+    /// Answers the server’s pings while waiting. This is cancel safe: the
+    /// answer to a ping is kept in the output buffer until it has been
+    /// written completely.
+    pub async fn next_message(&mut self) -> Result<Bytes, io::Error> {
+        loop {
+            if let ServerOp::Msg(payload) = self.process_op().await? {
+                return Ok(payload)
+            }
+        }
+    }
+
+    /// Processes operations from the server until the connection fails.
+    ///
+    /// Messages received are dropped.
+    pub async fn keepalive(&mut self) -> Result<(), io::Error> {
+        loop {
+            self.process_op().await?;
+        }
+    }
+
+    /// Waits for a PONG from the server.
+    async fn wait_pong(&mut self) -> Result<(), io::Error> {
+        loop {
+            if let ServerOp::Pong = self.process_op().await? {
+                return Ok(())
+            }
+        }
+    }
+
+    /// Writes all pending output and then processes a single operation.
+    ///
+    /// Pings are answered and errors are turned into an error. All
+    /// operations are returned for further processing. Since the answer to
+    /// a ping is only queued, it is written before the next operation is
+    /// read.
+    async fn process_op(&mut self) -> Result<ServerOp, io::Error> {
+        self.flush().await?;
+        let op = read_op(
+            &mut self.sock, &mut self.buf, self.max_payload
+        ).await?;
+        match op {
+            ServerOp::Ping => {
+                self.out.extend_from_slice(b"PONG\r\n");
+            }
+            ServerOp::Err(ref msg) => {
+                return Err(io::Error::new(io::ErrorKind::Other, msg.clone()))
+            }
+            ServerOp::Info(ref info) => {
+                if let Some(max) = info.max_payload {
+                    self.max_payload = max;
+                }
+            }
+            _ => { }
+        }
+        Ok(op)
+    }
+
+    /// Writes all data in the output buffer to the socket.
+    ///
+    /// This is cancel safe: data is removed from the buffer as soon as it
+    /// has been written.
+    async fn flush(&mut self) -> Result<(), io::Error> {
+        while self.out.has_remaining() {
+            self.sock.write_buf(&mut self.out).await?;
+        }
+        self.sock.flush().await
+    }
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("max_payload", &self.max_payload)
+            .finish()
+    }
+}
+
+
+//------------ read_op -------------------------------------------------------
+
+/// Reads the next operation from the server.
+///
+/// Messages with a payload larger than `max_payload` are rejected. This is
+/// cancel safe: partially received data is kept in the buffer.
+async fn read_op(
+    sock: &mut (impl AsyncRead + Unpin),
+    buf: &mut BytesMut,
+    max_payload: usize,
+) -> Result<ServerOp, io::Error> {
+    loop {
+        if let Some(op) = ServerOp::parse(buf, max_payload)? {
+            return Ok(op)
+        }
+        if sock.read_buf(buf).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof, "connection closed"
+            ))
+        }
+    }
+}
+
+
+//------------ Stream --------------------------------------------------------
+
+/// The socket of a connection.
+enum Stream {
+    /// A plain TCP socket.
+    Plain(TcpStream),
+
+    /// A TCP socket upgraded to TLS.
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>
+    ) -> Poll<Result<(), io::Error>> {
+        match self.get_mut() {
+            Stream::Plain(sock) => Pin::new(sock).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(sock) => Pin::new(sock).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8]
+    ) -> Poll<Result<usize, io::Error>> {
+        match self.get_mut() {
+            Stream::Plain(sock) => Pin::new(sock).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(sock) => Pin::new(sock).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>
+    ) -> Poll<Result<(), io::Error>> {
+        match self.get_mut() {
+            Stream::Plain(sock) => Pin::new(sock).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            Stream::Tls(sock) => Pin::new(sock).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>
+    ) -> Poll<Result<(), io::Error>> {
+        match self.get_mut() {
+            Stream::Plain(sock) => Pin::new(sock).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            Stream::Tls(sock) => Pin::new(sock).poll_shutdown(cx),
+        }
+    }
+}
+
+
+//------------ ServerOp ------------------------------------------------------
+
+/// An operation received from a NATS server.
+#[derive(Debug)]
+enum ServerOp {
+    /// Information about the server.
+    Info(ServerInfo),
+
+    /// A message for a subscription with its payload.
+    Msg(Bytes),
+
+    /// A keepalive request we need to answer.
+    Ping,
+
+    /// The answer to our keepalive request.
+    Pong,
+
+    /// An error with the error message.
+    Err(String),
+
+    /// Anything else which we ignore.
+    Other,
+}
+
+impl ServerOp {
+    /// The maximum length of a line we are willing to buffer.
+    const MAX_LINE: usize = 64 * 1024;
+
+    /// Parses an operation from the beginning of the buffer.
+    ///
+    /// Returns `Ok(None)` if the buffer doesn’t contain a complete
+    /// operation yet. In this case, the buffer is left untouched.
+    /// Otherwise, the operation is removed from the buffer.
+    ///
+    /// A message with a payload larger than `max_payload` is an error. This
+    /// is checked before waiting for the payload, so it is never buffered.
+    fn parse(
+        buf: &mut BytesMut, max_payload: usize
+    ) -> Result<Option<Self>, io::Error> {
+        let end = match buf.windows(2).position(|window| window == b"\r\n") {
+            Some(end) => end,
+            None => {
+                if buf.len() > Self::MAX_LINE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData, "overlong line"
+                    ))
+                }
+                return Ok(None)
+            }
+        };
+        let line = String::from_utf8_lossy(&buf[..end]).into_owned();
+        let (op, arg) = match line.split_once(|ch: char| {
+            ch.is_ascii_whitespace()
+        }) {
+            Some((op, arg)) => (op, arg.trim()),
+            None => (line.as_str(), ""),
+        };
+        let op = match op.to_ascii_uppercase().as_str() {
+            "MSG" => {
+                // MSG <subject> <sid> [reply-to] <#bytes>
+                let len = arg.split_ascii_whitespace().last().and_then(|len| {
+                    len.parse::<usize>().ok()
+                }).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "invalid MSG")
+                })?;
+                if len > max_payload {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "MSG payload of {} bytes exceeds maximum of {}",
+                            len, max_payload
+                        )
+                    ))
+                }
+                if buf.len() < end + 2 + len + 2 {
+                    return Ok(None)
+                }
+                buf.advance(end + 2);
+                let payload = buf.split_to(len).freeze();
+                if !buf.starts_with(b"\r\n") {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData, "invalid MSG payload"
+                    ))
+                }
+                buf.advance(2);
+                return Ok(Some(ServerOp::Msg(payload)))
+            }
+            "INFO" => {
+                ServerOp::Info(serde_json::from_str(arg).map_err(|err| {
+                    io::Error::new(io::ErrorKind::InvalidData, err)
+                })?)
+            }
+            "PING" => ServerOp::Ping,
+            "PONG" => ServerOp::Pong,
+            "-ERR" => ServerOp::Err(arg.trim_matches('\'').into()),
+            _ => ServerOp::Other,
+        };
+        buf.advance(end + 2);
+        Ok(Some(op))
+    }
+}
+
+
+//------------ ServerInfo ----------------------------------------------------
+
+/// The parts of the server information we are interested in.
+#[derive(Debug, Default, Deserialize)]
+struct ServerInfo {
+    /// The maximum payload size accepted by the server.
+    max_payload: Option<usize>,
+
+    /// Whether the server requires TLS.
+    #[serde(default)]
+    tls_required: bool,
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(data: &[u8]) -> (Option<ServerOp>, BytesMut) {
+        let mut buf = BytesMut::from(data);
+        (ServerOp::parse(&mut buf, DEFAULT_MAX_PAYLOAD).unwrap(), buf)
+    }
+
+    #[test]
+    fn parse_server_op() {
+        let (op, buf) = parse(
+            b"INFO {\"max_payload\":1024,\"server_id\":\"x\"}\r\nPING\r\n"
+        );
+        match op {
+            Some(ServerOp::Info(info)) => {
+                assert_eq!(info.max_payload, Some(1024));
+                assert!(!info.tls_required);
+            }
+            _ => panic!("expected INFO")
+        }
+        assert_eq!(buf.as_ref(), b"PING\r\n");
+
+        assert!(matches!(parse(b"PING\r\n").0, Some(ServerOp::Ping)));
+        assert!(matches!(parse(b"pong\r\n").0, Some(ServerOp::Pong)));
+        assert!(matches!(parse(b"+OK\r\n").0, Some(ServerOp::Other)));
+        match parse(b"-ERR 'Authorization Violation'\r\n").0 {
+            Some(ServerOp::Err(msg)) => {
+                assert_eq!(msg, "Authorization Violation")
+            }
+            _ => panic!("expected -ERR")
+        }
+
+        // Incomplete lines stay in the buffer.
+        let (op, buf) = parse(b"PI");
+        assert!(op.is_none());
+        assert_eq!(buf.as_ref(), b"PI");
+    }
+
+    #[test]
+    fn parse_msg() {
+        let (op, buf) = parse(b"MSG rpki.vrps 1 5\r\nhello\r\nPING\r\n");
+        match op {
+            Some(ServerOp::Msg(payload)) => {
+                assert_eq!(payload.as_ref(), b"hello")
+            }
+            _ => panic!("expected MSG")
+        }
+        assert_eq!(buf.as_ref(), b"PING\r\n");
+
+        // With reply subject and a payload containing CRLF.
+        let (op, _) = parse(b"MSG rpki.vrps 1 inbox 4\r\na\r\nb\r\n");
+        match op {
+            Some(ServerOp::Msg(payload)) => {
+                assert_eq!(payload.as_ref(), b"a\r\nb")
+            }
+            _ => panic!("expected MSG")
+        }
+
+        // An incomplete payload leaves everything in the buffer.
+        let (op, buf) = parse(b"MSG rpki.vrps 1 5\r\nhel");
+        assert!(op.is_none());
+        assert_eq!(buf.as_ref(), b"MSG rpki.vrps 1 5\r\nhel");
+
+        assert!(ServerOp::parse(
+            &mut BytesMut::from(&b"MSG rpki.vrps 1 many\r\n"[..]),
+            DEFAULT_MAX_PAYLOAD
+        ).is_err());
+
+        // The payload must be followed by CRLF.
+        assert!(ServerOp::parse(
+            &mut BytesMut::from(&b"MSG rpki.vrps 1 5\r\nhello!!\r\n"[..]),
+            DEFAULT_MAX_PAYLOAD
+        ).is_err());
+    }
+
+    #[test]
+    fn parse_msg_max_payload() {
+        // A payload that is too large is rejected before it arrives.
+        let mut buf = BytesMut::from(&b"MSG rpki.vrps 1 17\r\n"[..]);
+        assert!(ServerOp::parse(&mut buf, 16).is_err());
+
+        let mut buf = BytesMut::from(&b"MSG rpki.vrps 1 16\r\n"[..]);
+        assert!(ServerOp::parse(&mut buf, 16).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn connection() {
+        use std::time::Duration;
+        use tokio::io::{AsyncBufReadExt, BufReader};
+        use tokio::net::TcpListener;
+        use tokio::sync::oneshot;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            let mut sock = BufReader::new(sock);
+            sock.get_mut().write_all(
+                b"INFO {\"max_payload\":16}\r\n"
+            ).await.unwrap();
+            let mut line = String::new();
+            for expected in ["CONNECT {", "PING"] {
+                line.clear();
+                sock.read_line(&mut line).await.unwrap();
+                assert!(line.starts_with(expected), "{}", line);
+            }
+            sock.get_mut().write_all(b"PONG\r\n").await.unwrap();
+            for expected in ["SUB rpki.vrps 1", "PING"] {
+                line.clear();
+                sock.read_line(&mut line).await.unwrap();
+                assert!(line.starts_with(expected), "{}", line);
+            }
+            sock.get_mut().write_all(b"PONG\r\n").await.unwrap();
+
+            // Our ping is answered even if the client stops waiting.
+            sock.get_mut().write_all(b"PING\r\n").await.unwrap();
+            line.clear();
+            sock.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "PONG\r\n");
+            rx.await.unwrap();
+            sock.get_mut().write_all(
+                b"MSG rpki.vrps 1 5\r\nhello\r\nMSG rpki.vrps 1 17\r\n"
+            ).await.unwrap();
+        });
+
+        let auth = Auth::new(None, None, None, false).unwrap();
+        let mut conn = Connection::connect(&addr, &auth, None).await.unwrap();
+        assert_eq!(conn.max_payload(), 16);
+        conn.subscribe("rpki.vrps").await.unwrap();
+        assert!(
+            tokio::time::timeout(
+                Duration::from_millis(100), conn.next_message()
+            ).await.is_err()
+        );
+        tx.send(()).unwrap();
+        assert_eq!(conn.next_message().await.unwrap().as_ref(), b"hello");
+        assert!(conn.next_message().await.is_err());
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn auth() {
+        let name = String::from("rtrtr");
+        let secret = Secret::from("secret");
+        assert!(Auth::new(None, None, None, false).is_ok());
+        assert!(Auth::new(Some(&name), None, None, false).is_ok());
+        assert!(Auth::new(Some(&name), Some(&secret), None, true).is_ok());
+        assert!(Auth::new(None, None, Some(&secret), true).is_ok());
+        assert!(Auth::new(None, Some(&secret), None, true).is_err());
+        assert!(Auth::new(Some(&name), None, Some(&secret), true).is_err());
+
+        // Secrets are never sent without TLS.
+        assert!(Auth::new(Some(&name), Some(&secret), None, false).is_err());
+        assert!(Auth::new(None, None, Some(&secret), false).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+//! Utilities for IP addresses and prefixes.
+
+use std::cmp;
+use std::net::IpAddr;
+use rpki::resources::addr::Prefix;
+
+
+//------------ Functions -----------------------------------------------------
+
+/// Returns whether the first prefix covers the second prefix.
+///
+/// Prefixes of different address families never cover each other. A
+/// prefix covers itself.
+pub fn covers(
+    outer: IpAddr, outer_len: u8, inner: IpAddr, inner_len: u8
+) -> bool {
+    if outer_len > inner_len {
+        return false
+    }
+
+    // Move IPv4 addresses to the top bits so the lengths work the same.
+    let (outer, inner) = match (outer, inner) {
+        (IpAddr::V4(outer), IpAddr::V4(inner)) => {
+            (
+                u128::from(u32::from(outer)) << 96,
+                u128::from(u32::from(inner)) << 96,
+            )
+        }
+        (IpAddr::V6(outer), IpAddr::V6(inner)) => {
+            (u128::from(outer), u128::from(inner))
+        }
+        _ => return false
+    };
+    (outer ^ inner).checked_shr(
+        128 - u32::from(outer_len)
+    ).unwrap_or(0) == 0
+}
+
+
+//------------ AccessList ----------------------------------------------------
+
+/// A list of prefixes clients are allowed or denied to connect from.
+///
+/// Whether an address is permitted is decided by the most specific prefix
+/// covering it. If a prefix is both allowed and denied, it is denied. An
+/// address not covered by any prefix is only permitted if there are no
+/// allowed prefixes at all. The empty list thus permits everything.
+#[derive(Clone, Debug, Default)]
+pub struct AccessList {
+    /// The prefixes and whether they are allowed.
+    ///
+    /// The most specific prefixes come first and denied prefixes come
+    /// before allowed prefixes of the same length.
+    rules: Vec<(Prefix, bool)>,
+
+    /// Whether there are any allowed prefixes.
+    has_allow: bool,
+}
+
+impl AccessList {
+    /// Creates a new access list from the allowed and denied prefixes.
+    pub fn new(allow: &[Prefix], deny: &[Prefix]) -> Self {
+        let mut rules: Vec<_> = allow.iter().map(|prefix| {
+            (*prefix, true)
+        }).chain(deny.iter().map(|prefix| (*prefix, false))).collect();
+        rules.sort_by_key(|(prefix, allowed)| {
+            (cmp::Reverse(prefix.len()), *allowed)
+        });
+        AccessList { rules, has_allow: !allow.is_empty() }
+    }
+
+    /// Returns whether the list doesn’t restrict access at all.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns whether clients from the given address are permitted.
+    pub fn permits(&self, addr: IpAddr) -> bool {
+        let len = if addr.is_ipv4() { 32 } else { 128 };
+        match self.rules.iter().find(|(prefix, _)| {
+            covers(prefix.addr(), prefix.len(), addr, len)
+        }) {
+            Some((_, allowed)) => *allowed,
+            None => !self.has_allow,
+        }
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn covers_prefixes() {
+        let addr = |s| IpAddr::from_str(s).unwrap();
+        assert!(covers(addr("10.0.0.0"), 8, addr("10.1.0.0"), 16));
+        assert!(covers(addr("10.0.0.0"), 8, addr("10.0.0.0"), 8));
+        assert!(!covers(addr("10.1.0.0"), 16, addr("10.0.0.0"), 8));
+        assert!(!covers(addr("10.0.0.0"), 16, addr("10.1.0.0"), 16));
+        assert!(covers(addr("0.0.0.0"), 0, addr("192.0.2.0"), 24));
+        assert!(!covers(addr("0.0.0.0"), 0, addr("2001:db8::"), 32));
+        assert!(covers(addr("::"), 0, addr("2001:db8::"), 32));
+        assert!(covers(addr("2001:db8::"), 32, addr("2001:db8:1::"), 48));
+        assert!(!covers(addr("2001:db8::"), 33, addr("2001:db8:8000::"), 48));
+    }
+
+    #[test]
+    fn access_list() {
+        let addr = |s| IpAddr::from_str(s).unwrap();
+        let prefix = |s| Prefix::from_str(s).unwrap();
+
+        let empty = AccessList::default();
+        assert!(empty.is_empty());
+        assert!(empty.permits(addr("192.0.2.1")));
+
+        let deny = AccessList::new(&[], &[prefix("192.0.2.0/24")]);
+        assert!(!deny.permits(addr("192.0.2.1")));
+        assert!(deny.permits(addr("198.51.100.1")));
+        assert!(deny.permits(addr("2001:db8::1")));
+
+        let list = AccessList::new(
+            &[prefix("10.0.0.0/8"), prefix("10.1.2.0/24")],
+            &[prefix("10.1.0.0/16"), prefix("10.1.2.0/24")],
+        );
+        assert!(list.permits(addr("10.0.0.1")));
+        assert!(!list.permits(addr("10.1.0.1")));
+        assert!(!list.permits(addr("10.1.2.1")));
+        assert!(!list.permits(addr("192.0.2.1")));
+        assert!(!list.permits(addr("2001:db8::1")));
+    }
+}
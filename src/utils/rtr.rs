@@ -0,0 +1,510 @@
+//! Utilities for looking at RTR traffic.
+//!
+//! The actual RTR protocol is implemented by the _rpki_ crate. Sometimes,
+//! however, we want to know a little more about what is going on than that
+//! implementation tells us. The [`PduTracker`] allows following the PDUs
+//! in the byte stream of an RTR connection.
+
+//------------ Constants -----------------------------------------------------
+
+/// The PDU type of a Serial Notify PDU.
+pub const SERIAL_NOTIFY: u8 = 0;
+
+/// The PDU type of a Serial Query PDU.
+pub const SERIAL_QUERY: u8 = 1;
+
+/// The PDU type of a Reset Query PDU.
+pub const RESET_QUERY: u8 = 2;
+
+/// The PDU type of a Cache Response PDU.
+pub const CACHE_RESPONSE: u8 = 3;
+
+/// The PDU type of an IPv4 Prefix PDU.
+pub const IPV4_PREFIX: u8 = 4;
+
+/// The PDU type of an IPv6 Prefix PDU.
+pub const IPV6_PREFIX: u8 = 6;
+
+/// The PDU type of an End of Data PDU.
+pub const END_OF_DATA: u8 = 7;
+
+/// The PDU type of a Cache Reset PDU.
+pub const CACHE_RESET: u8 = 8;
+
+/// The PDU type of a Router Key PDU.
+pub const ROUTER_KEY: u8 = 9;
+
+/// The PDU type of an Error Report PDU.
+pub const ERROR_REPORT: u8 = 10;
+
+/// The PDU type of an ASPA PDU.
+pub const ASPA: u8 = 11;
+
+/// The error code for “Unsupported Protocol Version.”
+pub const UNSUPPORTED_VERSION: u16 = 4;
+
+/// The highest protocol version we know of.
+pub const MAX_VERSION: u8 = 2;
+
+/// The length of a PDU header.
+const HEADER_LEN: usize = 8;
+
+/// The number of octets captured from the start of each PDU.
+///
+/// This is large enough to hold a complete version 1 End of Data PDU.
+const CAPTURE_LEN: usize = 24;
+
+/// The number of octets captured from the start of Error Report PDUs.
+///
+/// Error Report PDUs are captured in full up to this length so that the
+/// error text can be extracted.
+const ERROR_CAPTURE_LEN: usize = 4096;
+
+
+//------------ PduTracker ----------------------------------------------------
+
+/// Follows the PDUs in one direction of an RTR connection.
+///
+/// Feed all the data read or written via [`feed`](Self::feed). The tracker
+/// will call the provided closure for every PDU once it has been seen in
+/// full.
+#[derive(Clone, Debug, Default)]
+pub struct PduTracker {
+    /// The first octets of the current PDU.
+    capture: Vec<u8>,
+
+    /// The number of octets of the current PDU still to come.
+    ///
+    /// This is only valid once the header has been captured.
+    remaining: usize,
+
+    /// Has the stream become unparseable?
+    broken: bool,
+}
+
+impl PduTracker {
+    /// Feeds data to the tracker.
+    ///
+    /// Calls `op` for each PDU completed by this data.
+    pub fn feed(&mut self, mut data: &[u8], mut op: impl FnMut(Pdu)) {
+        while !data.is_empty() && !self.broken {
+            if self.capture.len() < HEADER_LEN {
+                let len = (HEADER_LEN - self.capture.len()).min(data.len());
+                self.capture.extend_from_slice(&data[..len]);
+                data = &data[len..];
+                if self.capture.len() < HEADER_LEN {
+                    return
+                }
+                let pdu_len = u32::from_be_bytes(
+                    self.capture[4..8].try_into().unwrap()
+                ) as usize;
+                if pdu_len < HEADER_LEN {
+                    self.broken = true;
+                    return
+                }
+                self.remaining = pdu_len - HEADER_LEN;
+            }
+            else {
+                let len = self.remaining.min(data.len());
+                let capture_len = len.min(
+                    self.capture_len().saturating_sub(self.capture.len())
+                );
+                self.capture.extend_from_slice(&data[..capture_len]);
+                self.remaining -= len;
+                data = &data[len..];
+            }
+            if self.capture.len() >= HEADER_LEN && self.remaining == 0 {
+                op(Pdu { data: &self.capture });
+                self.capture.clear();
+            }
+        }
+    }
+
+    /// Returns whether the tracker has seen only part of a PDU.
+    pub fn is_partial(&self) -> bool {
+        !self.capture.is_empty() && !self.broken
+    }
+
+    /// Returns the number of octets to capture of the current PDU.
+    ///
+    /// This must only be called once the header has been captured.
+    fn capture_len(&self) -> usize {
+        if self.capture[1] == ERROR_REPORT {
+            ERROR_CAPTURE_LEN
+        }
+        else {
+            CAPTURE_LEN
+        }
+    }
+}
+
+
+//------------ VersionClamp --------------------------------------------------
+
+/// Limits the protocol version of the PDUs in one direction of a connection.
+///
+/// Pass all data to be written through [`apply`](Self::apply). It replaces
+/// the version of every PDU that is higher than the maximum with the
+/// maximum.
+#[derive(Clone, Copy, Debug)]
+pub struct VersionClamp {
+    /// The maximum protocol version.
+    max: u8,
+
+    /// The position of the next octet within the current PDU.
+    pos: usize,
+
+    /// The length of the current PDU as far as it is known.
+    len: usize,
+}
+
+impl VersionClamp {
+    /// Creates a new clamp for the given maximum version.
+    pub fn new(max: u8) -> Self {
+        VersionClamp { max, pos: 0, len: 0 }
+    }
+
+    /// Applies the clamp to the given data.
+    ///
+    /// The data must directly follow whatever data was applied before.
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for octet in data {
+            if self.pos == 0 {
+                *octet = (*octet).min(self.max);
+            }
+            else if self.pos >= 4 && self.pos < HEADER_LEN {
+                self.len = (self.len << 8) | usize::from(*octet);
+            }
+            self.pos += 1;
+            if self.pos >= HEADER_LEN && self.pos >= self.len {
+                self.pos = 0;
+                self.len = 0;
+            }
+        }
+    }
+}
+
+
+//------------ TimingOverride ------------------------------------------------
+
+/// Overrides the timing values in End of Data PDUs.
+///
+/// Pass all data to be written or read through [`apply`](Self::apply). It
+/// replaces the refresh, retry, and expire intervals of all End of Data
+/// PDUs of version 1 or later with those values that have been provided.
+/// Intervals without a replacement value are limited to the maximum values
+/// if those have been provided.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TimingOverride {
+    /// The refresh, retry, and expire intervals to use if any.
+    timing: [Option<u32>; 3],
+
+    /// The maximum refresh, retry, and expire intervals if any.
+    max: [Option<u32>; 3],
+
+    /// Does the current interval exceed its maximum?
+    ///
+    /// This is `None` while the octets seen so far equal those of the
+    /// maximum.
+    exceeds: Option<bool>,
+
+    /// The protocol version of the current PDU.
+    version: u8,
+
+    /// The type of the current PDU.
+    pdu_type: u8,
+
+    /// The position of the next octet within the current PDU.
+    pos: usize,
+
+    /// The length of the current PDU as far as it is known.
+    len: usize,
+}
+
+impl TimingOverride {
+    /// Creates a new value overriding the given intervals.
+    pub fn new(
+        refresh: Option<u32>, retry: Option<u32>, expire: Option<u32>
+    ) -> Self {
+        TimingOverride {
+            timing: [refresh, retry, expire],
+            .. Default::default()
+        }
+    }
+
+    /// Adds maximum values for the intervals.
+    pub fn with_max(
+        mut self,
+        refresh: Option<u32>, retry: Option<u32>, expire: Option<u32>
+    ) -> Self {
+        self.max = [refresh, retry, expire];
+        self
+    }
+
+    /// Returns whether the value doesn’t override anything.
+    pub fn is_empty(&self) -> bool {
+        self.timing.iter().chain(self.max.iter()).all(Option::is_none)
+    }
+
+    /// Applies the override to the given data.
+    ///
+    /// The data must directly follow whatever data was applied before.
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for octet in data {
+            match self.pos {
+                0 => self.version = *octet,
+                1 => self.pdu_type = *octet,
+                4..=7 => self.len = (self.len << 8) | usize::from(*octet),
+                12..=23 if self.pdu_type == END_OF_DATA
+                    && self.version > 0
+                => {
+                    let idx = self.pos - 12;
+                    let (field, idx) = (idx / 4, idx % 4);
+                    if idx == 0 {
+                        self.exceeds = None;
+                    }
+                    if let Some(value) = self.timing[field] {
+                        *octet = value.to_be_bytes()[idx];
+                    }
+                    else if let Some(max) = self.max[field] {
+                        // The intervals are big-endian, so the first
+                        // differing octet decides.
+                        let max = max.to_be_bytes()[idx];
+                        if self.exceeds.is_none() && *octet != max {
+                            self.exceeds = Some(*octet > max)
+                        }
+                        if self.exceeds == Some(true) {
+                            *octet = max
+                        }
+                    }
+                }
+                _ => { }
+            }
+            self.pos += 1;
+            if self.pos >= HEADER_LEN && self.pos >= self.len {
+                self.pos = 0;
+                self.len = 0;
+            }
+        }
+    }
+}
+
+
+//------------ Pdu -----------------------------------------------------------
+
+/// The beginning of a PDU seen by a tracker.
+#[derive(Clone, Copy, Debug)]
+pub struct Pdu<'a> {
+    /// The captured octets of the PDU.
+    ///
+    /// This is at least the full header.
+    data: &'a [u8],
+}
+
+impl Pdu<'_> {
+    /// Returns the protocol version of the PDU.
+    pub fn version(&self) -> u8 {
+        self.data[0]
+    }
+
+    /// Returns the PDU type.
+    pub fn pdu_type(&self) -> u8 {
+        self.data[1]
+    }
+
+    /// Returns the session ID or error code field of the header.
+    pub fn session(&self) -> u16 {
+        u16::from_be_bytes([self.data[2], self.data[3]])
+    }
+
+    /// Returns the error code of an Error Report PDU.
+    pub fn error_code(&self) -> Option<u16> {
+        (self.pdu_type() == ERROR_REPORT).then(|| self.session())
+    }
+
+    /// Returns the error text of an Error Report PDU.
+    ///
+    /// Returns `None` if the PDU isn’t an Error Report, doesn’t contain
+    /// an error text, or is too long to have been captured in full.
+    pub fn error_text(&self) -> Option<String> {
+        if self.pdu_type() != ERROR_REPORT {
+            return None
+        }
+        let start = Self::field_len(self.data, HEADER_LEN)?.checked_add(
+            HEADER_LEN + 4
+        )?;
+        let text_len = Self::field_len(self.data, start)?;
+        let text = self.data.get(start + 4..)?.get(..text_len)?;
+        if text.is_empty() {
+            return None
+        }
+        Some(String::from_utf8_lossy(text).into_owned())
+    }
+
+    /// Returns the value of the length field starting at `pos`.
+    fn field_len(data: &[u8], pos: usize) -> Option<usize> {
+        data.get(pos..pos + 4).map(|data| {
+            u32::from_be_bytes(data.try_into().unwrap()) as usize
+        })
+    }
+
+    /// Returns the serial number of a Serial Query or End of Data PDU.
+    pub fn serial(&self) -> Option<u32> {
+        if !matches!(self.pdu_type(), SERIAL_QUERY | END_OF_DATA) {
+            return None
+        }
+        self.data.get(8..12).map(|data| {
+            u32::from_be_bytes(data.try_into().unwrap())
+        })
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn track_pdus() {
+        let mut data = Vec::new();
+        // Serial Query, version 1, session 12, serial 42.
+        data.extend_from_slice(&[1, 1, 0, 12, 0, 0, 0, 12, 0, 0, 0, 42]);
+        // Reset Query, version 2.
+        data.extend_from_slice(&[2, 2, 0, 0, 0, 0, 0, 8]);
+        // Error Report, version 2, unsupported version, 20 octets.
+        data.extend_from_slice(&[2, 10, 0, 4, 0, 0, 0, 20]);
+        data.extend_from_slice(&[0; 12]);
+
+        for chunk_size in [1, 3, 7, 100] {
+            let mut tracker = PduTracker::default();
+            let mut seen = Vec::new();
+            for chunk in data.chunks(chunk_size) {
+                tracker.feed(chunk, |pdu| {
+                    seen.push((
+                        pdu.version(), pdu.pdu_type(), pdu.session(),
+                        pdu.serial()
+                    ))
+                })
+            }
+            assert_eq!(
+                seen,
+                [
+                    (1, SERIAL_QUERY, 12, Some(42)),
+                    (2, RESET_QUERY, 0, None),
+                    (2, ERROR_REPORT, UNSUPPORTED_VERSION, None),
+                ]
+            );
+            assert!(!tracker.is_partial());
+        }
+
+        let mut tracker = PduTracker::default();
+        tracker.feed(&data[..10], |_| { });
+        assert!(tracker.is_partial());
+    }
+
+    #[test]
+    fn error_text() {
+        let mut data = Vec::new();
+        // Error Report, version 1, no data available, 8 octet encapsulated
+        // PDU, 7 octet error text.
+        data.extend_from_slice(&[1, 10, 0, 2, 0, 0, 0, 31]);
+        data.extend_from_slice(&[0, 0, 0, 8, 1, 2, 0, 0, 0, 0, 0, 8]);
+        data.extend_from_slice(&[0, 0, 0, 7]);
+        data.extend_from_slice(b"no data");
+        // Cache Reset, version 1.
+        data.extend_from_slice(&[1, 8, 0, 0, 0, 0, 0, 8]);
+
+        for chunk_size in [1, 3, 7, 100] {
+            let mut tracker = PduTracker::default();
+            let mut seen = Vec::new();
+            for chunk in data.chunks(chunk_size) {
+                tracker.feed(chunk, |pdu| {
+                    seen.push((
+                        pdu.pdu_type(), pdu.error_code(), pdu.error_text()
+                    ))
+                })
+            }
+            assert_eq!(
+                seen,
+                [
+                    (ERROR_REPORT, Some(2), Some("no data".into())),
+                    (CACHE_RESET, None, None),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn clamp_versions() {
+        let mut data = Vec::new();
+        // Reset Query, version 2.
+        data.extend_from_slice(&[2, 2, 0, 0, 0, 0, 0, 8]);
+        // Serial Query, version 2, session 12, serial 42.
+        data.extend_from_slice(&[2, 1, 0, 12, 0, 0, 0, 12, 0, 0, 0, 42]);
+        // Reset Query, version 0.
+        data.extend_from_slice(&[0, 2, 0, 0, 0, 0, 0, 8]);
+
+        for chunk_size in [1, 3, 7, 100] {
+            let mut clamp = VersionClamp::new(1);
+            let mut data = data.clone();
+            for chunk in data.chunks_mut(chunk_size) {
+                clamp.apply(chunk)
+            }
+            assert_eq!(data[0], 1);
+            assert_eq!(data[8], 1);
+            assert_eq!(data[11], 12);
+            assert_eq!(data[19], 42);
+            assert_eq!(data[20], 0);
+        }
+    }
+
+    #[test]
+    fn override_timing() {
+        let mut data = Vec::new();
+        // End of Data, version 0, serial 42.
+        data.extend_from_slice(&[0, 7, 0, 12, 0, 0, 0, 12, 0, 0, 0, 42]);
+        // End of Data, version 1, serial 42, refresh 1, retry 2, expire 3.
+        data.extend_from_slice(&[1, 7, 0, 12, 0, 0, 0, 24, 0, 0, 0, 42]);
+        data.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]);
+
+        for chunk_size in [1, 3, 7, 100] {
+            let mut timing = TimingOverride::new(Some(600), None, Some(7200));
+            let mut data = data.clone();
+            for chunk in data.chunks_mut(chunk_size) {
+                timing.apply(chunk)
+            }
+            assert_eq!(&data[..12], &[0, 7, 0, 12, 0, 0, 0, 12, 0, 0, 0, 42]);
+            assert_eq!(&data[20..24], &[0, 0, 0, 42]);
+            assert_eq!(&data[24..28], &600u32.to_be_bytes());
+            assert_eq!(&data[28..32], &[0, 0, 0, 2]);
+            assert_eq!(&data[32..36], &7200u32.to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn cap_timing() {
+        let mut data = Vec::new();
+        // End of Data, version 1, serial 42, refresh 3600, retry 600,
+        // expire 7200.
+        data.extend_from_slice(&[1, 7, 0, 12, 0, 0, 0, 24, 0, 0, 0, 42]);
+        data.extend_from_slice(&3600u32.to_be_bytes());
+        data.extend_from_slice(&600u32.to_be_bytes());
+        data.extend_from_slice(&7200u32.to_be_bytes());
+
+        for chunk_size in [1, 3, 7, 100] {
+            let mut timing = TimingOverride::new(
+                None, Some(30), None
+            ).with_max(Some(300), Some(10), Some(7300));
+            let mut data = data.clone();
+            for chunk in data.chunks_mut(chunk_size) {
+                timing.apply(chunk)
+            }
+            assert_eq!(&data[12..16], &300u32.to_be_bytes());
+            assert_eq!(&data[16..20], &30u32.to_be_bytes());
+            assert_eq!(&data[20..24], &7200u32.to_be_bytes());
+        }
+    }
+}
+
@@ -0,0 +1,381 @@
+//! Protecting TCP connections with the TCP Authentication Option.
+//!
+//! The TCP Authentication Option (TCP-AO) defined in [RFC 5925] replaces
+//! the TCP MD5 Signature Option. As with TCP-MD5, the kernel signs and
+//! checks all segments of a connection using a key configured per peer
+//! address. In addition, each key, called a master key tuple, has a send
+//! ID and a receive ID identifying it in segments and uses one of the
+//! algorithms of [RFC 5926].
+//!
+//! For outgoing connections, the key has to be set on the socket before
+//! connecting. For incoming connections, the keys for all peers have to be
+//! set on the listening socket.
+//!
+//! TCP-AO is currently only supported on Linux 6.7 or later.
+//!
+//! [RFC 5925]: https://tools.ietf.org/html/rfc5925
+//! [RFC 5926]: https://tools.ietf.org/html/rfc5926
+
+use std::io;
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use serde::Deserialize;
+use tokio::net::TcpStream;
+use crate::config::Secret;
+#[cfg(unix)]
+use super::tcp_md5::connect_with;
+
+
+//------------ Constants -----------------------------------------------------
+
+/// The maximum length of a key in octets.
+pub const MAX_KEY_LEN: usize = 80;
+
+
+//------------ Algorithm -----------------------------------------------------
+
+/// The MAC algorithm used by a key.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+pub enum Algorithm {
+    /// HMAC-SHA-1-96.
+    #[default]
+    #[serde(rename = "hmac-sha-1-96")]
+    HmacSha1,
+
+    /// AES-128-CMAC-96.
+    #[serde(rename = "aes-128-cmac-96")]
+    AesCmac,
+}
+
+impl Algorithm {
+    /// Returns the name of the algorithm used by the kernel.
+    pub fn kernel_name(self) -> &'static str {
+        match self {
+            Algorithm::HmacSha1 => "hmac(sha1)",
+            Algorithm::AesCmac => "cmac(aes128)",
+        }
+    }
+}
+
+
+//------------ Key -----------------------------------------------------------
+
+/// A TCP-AO master key tuple without the peer address.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Key {
+    /// The key.
+    pub key: Secret,
+
+    /// The key ID used in outgoing segments.
+    #[serde(rename = "send-id")]
+    pub send_id: u8,
+
+    /// The key ID expected in incoming segments.
+    #[serde(rename = "recv-id")]
+    pub recv_id: u8,
+
+    /// The MAC algorithm.
+    #[serde(default)]
+    pub algorithm: Algorithm,
+}
+
+impl Key {
+    /// Returns an error message if the key is too long.
+    pub fn check(&self) -> Result<(), String> {
+        if self.key.expose().len() > MAX_KEY_LEN {
+            Err(format!("TCP-AO key longer than {} octets", MAX_KEY_LEN))
+        }
+        else {
+            Ok(())
+        }
+    }
+}
+
+
+//------------ PeerKey -------------------------------------------------------
+
+/// The TCP-AO key for the connections with a peer.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PeerKey {
+    /// The address of the peer.
+    pub peer: IpAddr,
+
+    /// The key.
+    #[serde(flatten)]
+    pub key: Key,
+}
+
+impl PeerKey {
+    /// Sets the key on a listening socket.
+    ///
+    /// If the socket is an IPv6 socket, IPv4 peers are given as
+    /// IPv4-mapped addresses so that the key applies to connections
+    /// accepted on a dual-stack socket.
+    #[cfg(unix)]
+    pub fn apply(
+        &self, sock: &impl AsRawFd, ipv6: bool
+    ) -> Result<(), io::Error> {
+        let peer = match self.peer {
+            IpAddr::V4(addr) if ipv6 => IpAddr::V6(addr.to_ipv6_mapped()),
+            peer => peer,
+        };
+        set_key(sock, peer, &self.key)
+    }
+
+    /// Sets the key on a listening socket.
+    ///
+    /// This is the non-Unix version that always fails.
+    #[cfg(not(unix))]
+    pub fn apply<T>(&self, _sock: &T, _ipv6: bool) -> Result<(), io::Error> {
+        Err(unsupported())
+    }
+}
+
+
+//------------ connect -------------------------------------------------------
+
+/// Connects to a remote address protecting the connection with a key.
+///
+/// The remote address is given as a string with host and port. All
+/// addresses the host resolves to are tried in turn.
+#[cfg(unix)]
+pub async fn connect(
+    remote: &str, key: &Key
+) -> Result<TcpStream, io::Error> {
+    connect_with(remote, |sock, peer| set_key(sock, peer, key)).await
+}
+
+/// Connects to a remote address protecting the connection with a key.
+///
+/// This is the non-Unix version that always fails.
+#[cfg(not(unix))]
+pub async fn connect(
+    _remote: &str, _key: &Key
+) -> Result<TcpStream, io::Error> {
+    Err(unsupported())
+}
+
+
+//------------ set_key -------------------------------------------------------
+
+/// Adds the key for connections with a peer to a socket.
+#[cfg(target_os = "linux")]
+pub fn set_key(
+    sock: &impl AsRawFd, peer: IpAddr, key: &Key
+) -> Result<(), io::Error> {
+    use std::mem;
+    use nix::libc;
+    use super::tcp_md5::set_sockaddr;
+
+    /// The `TCP_AO_ADD_KEY` socket option from `linux/tcp.h`.
+    const TCP_AO_ADD_KEY: libc::c_int = 38;
+
+    key.check().map_err(|err| {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    })?;
+
+    // SAFETY: All fields are plain integers or arrays thereof, so all
+    // zeros is a valid value.
+    let mut add: TcpAoAdd = unsafe { mem::zeroed() };
+    set_sockaddr(&mut add.addr, peer);
+    let alg = key.algorithm.kernel_name().as_bytes();
+    add.alg_name[..alg.len()].copy_from_slice(alg);
+
+    // The kernel wants the prefix length of the peer address. IPv4-mapped
+    // addresses are treated as IPv4 addresses.
+    add.prefix = match peer {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(addr) if addr.to_ipv4_mapped().is_some() => 32,
+        IpAddr::V6(_) => 128,
+    };
+    add.sndid = key.send_id;
+    add.rcvid = key.recv_id;
+    let secret = key.key.expose().as_bytes();
+    add.keylen = secret.len() as u8;
+    add.key[..secret.len()].copy_from_slice(secret);
+
+    // SAFETY: We pass a pointer to and the size of a properly initialized
+    // value of the type expected by the kernel.
+    let res = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(), libc::IPPROTO_TCP, TCP_AO_ADD_KEY,
+            &add as *const _ as *const libc::c_void,
+            mem::size_of::<TcpAoAdd>() as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(())
+}
+
+/// Adds the key for connections with a peer to a socket.
+///
+/// This is the version for systems other than Linux that always fails.
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn set_key(
+    _sock: &impl AsRawFd, _peer: IpAddr, _key: &Key
+) -> Result<(), io::Error> {
+    Err(unsupported())
+}
+
+/// Returns the error for systems that don’t support TCP-AO.
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP-AO is only supported on Linux"
+    )
+}
+
+
+//------------ TcpAoAdd ------------------------------------------------------
+
+/// The `struct tcp_ao_add` from `linux/tcp.h`.
+///
+/// The fields are only ever read by the kernel. The MAC length and key
+/// flags are left at zero which selects the defaults.
+#[cfg(target_os = "linux")]
+#[allow(dead_code)]
+#[repr(C, align(8))]
+struct TcpAoAdd {
+    addr: nix::libc::sockaddr_storage,
+    alg_name: [u8; 64],
+    ifindex: i32,
+
+    /// The `set_current` and `set_rnext` bit fields.
+    flags: u32,
+    reserved2: u16,
+    prefix: u8,
+    sndid: u8,
+    rcvid: u8,
+    maclen: u8,
+    keyflags: u8,
+    keylen: u8,
+    key: [u8; MAX_KEY_LEN],
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpSocket;
+    use tokio::time::timeout;
+
+    fn key(secret: &str) -> Key {
+        Key {
+            key: Secret::from(secret),
+            send_id: 1,
+            recv_id: 1,
+            algorithm: Algorithm::default(),
+        }
+    }
+
+    /// Returns whether the kernel supports TCP-AO.
+    fn supported() -> bool {
+        let sock = TcpSocket::new_v4().unwrap();
+        match set_key(&sock, "127.0.0.1".parse().unwrap(), &key("secret")) {
+            Ok(()) => true,
+            Err(err) => {
+                assert_eq!(err.raw_os_error(), Some(nix::libc::ENOPROTOOPT));
+                false
+            }
+        }
+    }
+
+    #[test]
+    fn layout() {
+        // This is the size of the kernel’s struct on all architectures.
+        assert_eq!(std::mem::size_of::<TcpAoAdd>(), 288);
+    }
+
+    #[test]
+    fn config() {
+        let key: PeerKey = toml::from_str(r#"
+            peer = "192.0.2.1"
+            key = "secret"
+            send-id = 3
+            recv-id = 4
+        "#).unwrap();
+        assert_eq!(key.peer, "192.0.2.1".parse::<IpAddr>().unwrap());
+        assert_eq!(key.key.key.expose(), "secret");
+        assert_eq!(key.key.send_id, 3);
+        assert_eq!(key.key.recv_id, 4);
+        assert_eq!(key.key.algorithm, Algorithm::HmacSha1);
+
+        let key: Key = toml::from_str(r#"
+            key = "secret"
+            send-id = 0
+            recv-id = 0
+            algorithm = "aes-128-cmac-96"
+        "#).unwrap();
+        assert_eq!(key.algorithm, Algorithm::AesCmac);
+
+        assert!(toml::from_str::<Key>(r#"
+            key = "secret"
+            send-id = 256
+            recv-id = 0
+        "#).is_err());
+        assert!(toml::from_str::<Key>(r#"
+            key = "secret"
+            send-id = 0
+            recv-id = 0
+            algorithm = "md5"
+        "#).is_err());
+    }
+
+    #[test]
+    fn set_key_too_long() {
+        let sock = TcpSocket::new_v4().unwrap();
+        assert_eq!(
+            set_key(
+                &sock, "127.0.0.1".parse().unwrap(),
+                &key(&"x".repeat(MAX_KEY_LEN + 1))
+            ).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_loopback() {
+        if !supported() {
+            eprintln!("kernel doesn’t support TCP-AO, skipping test");
+            return
+        }
+        let listener = TcpSocket::new_v4().unwrap();
+        PeerKey {
+            peer: "127.0.0.1".parse().unwrap(),
+            key: key("secret"),
+        }.apply(&listener, false).unwrap();
+        listener.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = listener.listen(4).unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        // A connection signed with the right key gets through.
+        let right = key("secret");
+        let (client, server) = tokio::join!(
+            connect(&addr, &right),
+            listener.accept()
+        );
+        let mut client = client.unwrap();
+        let (mut server, _) = server.unwrap();
+        client.write_all(b"rtr").await.unwrap();
+        let mut buf = [0u8; 3];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"rtr");
+
+        // The kernel drops segments signed with a different key.
+        let wrong = key("wrong");
+        assert!(
+            timeout(
+                Duration::from_millis(500),
+                connect(&addr, &wrong)
+            ).await.is_err()
+        );
+    }
+}
@@ -0,0 +1,292 @@
+//! Protecting TCP connections with MD5 signatures.
+//!
+//! Some routers only support RTR over TCP connections protected by the TCP
+//! MD5 Signature Option defined in [RFC 2385]. The kernel signs and checks
+//! all segments of such connections with a key configured per peer
+//! address. For outgoing connections, the key has to be set on the socket
+//! before connecting. For incoming connections, the keys for all peers
+//! have to be set on the listening socket.
+//!
+//! TCP-MD5 is currently only supported on Linux. Its successor, the TCP
+//! Authentication Option, lives in the [`tcp_ao`][super::tcp_ao] module.
+//!
+//! [RFC 2385]: https://tools.ietf.org/html/rfc2385
+
+use std::io;
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use serde::Deserialize;
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::{lookup_host, TcpSocket};
+use crate::config::Secret;
+
+
+//------------ Constants -----------------------------------------------------
+
+/// The maximum length of a key in octets.
+pub const MAX_KEY_LEN: usize = 80;
+
+
+//------------ PeerKey -------------------------------------------------------
+
+/// The TCP-MD5 key for the connections with a peer.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PeerKey {
+    /// The address of the peer.
+    pub peer: IpAddr,
+
+    /// The key.
+    pub key: Secret,
+}
+
+impl PeerKey {
+    /// Sets the key on a listening socket.
+    ///
+    /// If the socket is an IPv6 socket, IPv4 peers are given as
+    /// IPv4-mapped addresses so that the key applies to connections
+    /// accepted on a dual-stack socket.
+    #[cfg(unix)]
+    pub fn apply(
+        &self, sock: &impl AsRawFd, ipv6: bool
+    ) -> Result<(), io::Error> {
+        let peer = match self.peer {
+            IpAddr::V4(addr) if ipv6 => IpAddr::V6(addr.to_ipv6_mapped()),
+            peer => peer,
+        };
+        set_key(sock, peer, self.key.expose().as_bytes())
+    }
+
+    /// Sets the key on a listening socket.
+    ///
+    /// This is the non-Unix version that always fails.
+    #[cfg(not(unix))]
+    pub fn apply<T>(&self, _sock: &T, _ipv6: bool) -> Result<(), io::Error> {
+        Err(unsupported())
+    }
+}
+
+
+//------------ connect -------------------------------------------------------
+
+/// Connects to a remote address protecting the connection with a key.
+///
+/// The remote address is given as a string with host and port. All
+/// addresses the host resolves to are tried in turn.
+#[cfg(unix)]
+pub async fn connect(
+    remote: &str, key: &Secret
+) -> Result<TcpStream, io::Error> {
+    connect_with(remote, |sock, peer| {
+        set_key(sock, peer, key.expose().as_bytes())
+    }).await
+}
+
+/// Connects to a remote address after preparing each socket.
+///
+/// The `prepare` closure is called with every socket before connecting it
+/// and the address of the peer it will be connected to.
+#[cfg(unix)]
+pub(super) async fn connect_with(
+    remote: &str,
+    prepare: impl Fn(&TcpSocket, IpAddr) -> Result<(), io::Error>,
+) -> Result<TcpStream, io::Error> {
+    let mut err = None;
+    for addr in lookup_host(remote).await? {
+        let sock = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        }
+        else {
+            TcpSocket::new_v6()?
+        };
+        prepare(&sock, addr.ip())?;
+        match sock.connect(addr).await {
+            Ok(sock) => return Ok(sock),
+            Err(last) => err = Some(last),
+        }
+    }
+    Err(err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} doesn’t resolve to any address", remote)
+        )
+    }))
+}
+
+/// Connects to a remote address protecting the connection with a key.
+///
+/// This is the non-Unix version that always fails.
+#[cfg(not(unix))]
+pub async fn connect(
+    _remote: &str, _key: &Secret
+) -> Result<TcpStream, io::Error> {
+    Err(unsupported())
+}
+
+
+//------------ set_key -------------------------------------------------------
+
+/// Sets the key for connections with a peer on a socket.
+#[cfg(target_os = "linux")]
+pub fn set_key(
+    sock: &impl AsRawFd, peer: IpAddr, key: &[u8]
+) -> Result<(), io::Error> {
+    use std::mem;
+    use nix::libc;
+
+    /// The `struct tcp_md5sig` from `linux/tcp.h`.
+    ///
+    /// The fields are only ever read by the kernel.
+    #[allow(dead_code)]
+    #[repr(C)]
+    struct TcpMd5Sig {
+        addr: libc::sockaddr_storage,
+        flags: u8,
+        prefixlen: u8,
+        keylen: u16,
+        ifindex: libc::c_int,
+        key: [u8; MAX_KEY_LEN],
+    }
+
+    if key.len() > MAX_KEY_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("TCP-MD5 key longer than {} octets", MAX_KEY_LEN)
+        ))
+    }
+
+    // SAFETY: All fields are plain integers or arrays thereof, so all
+    // zeros is a valid value.
+    let mut sig: TcpMd5Sig = unsafe { mem::zeroed() };
+    set_sockaddr(&mut sig.addr, peer);
+    sig.keylen = key.len() as u16;
+    sig.key[..key.len()].copy_from_slice(key);
+
+    // SAFETY: We pass a pointer to and the size of a properly initialized
+    // value of the type expected by the kernel.
+    let res = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_MD5SIG,
+            &sig as *const _ as *const libc::c_void,
+            mem::size_of::<TcpMd5Sig>() as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(())
+}
+
+/// Sets a socket address storage to the given address with port zero.
+#[cfg(target_os = "linux")]
+pub(super) fn set_sockaddr(
+    storage: &mut nix::libc::sockaddr_storage, addr: IpAddr
+) {
+    use nix::libc;
+
+    match addr {
+        IpAddr::V4(addr) => {
+            let sin = storage as *mut _ as *mut libc::sockaddr_in;
+            // SAFETY: sockaddr_storage is large enough and suitably
+            // aligned for any socket address type.
+            unsafe {
+                (*sin).sin_family = libc::AF_INET as libc::sa_family_t;
+                (*sin).sin_addr.s_addr = u32::from_ne_bytes(addr.octets());
+            }
+        }
+        IpAddr::V6(addr) => {
+            let sin6 = storage as *mut _ as *mut libc::sockaddr_in6;
+            // SAFETY: see above.
+            unsafe {
+                (*sin6).sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                (*sin6).sin6_addr.s6_addr = addr.octets();
+            }
+        }
+    }
+}
+
+/// Sets the key for connections with a peer on a socket.
+///
+/// This is the version for systems other than Linux that always fails.
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn set_key(
+    _sock: &impl AsRawFd, _peer: IpAddr, _key: &[u8]
+) -> Result<(), io::Error> {
+    Err(unsupported())
+}
+
+/// Returns the error for systems that don’t support TCP-MD5.
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP-MD5 is only supported on Linux"
+    )
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::timeout;
+
+    #[test]
+    fn set_key_loopback() {
+        let sock = TcpSocket::new_v4().unwrap();
+        set_key(&sock, "127.0.0.1".parse().unwrap(), b"secret").unwrap();
+        assert_eq!(
+            set_key(
+                &sock, "127.0.0.1".parse().unwrap(), &[0; MAX_KEY_LEN + 1]
+            ).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        // IPv4 peers are mapped on IPv6 sockets. Skip if there is no IPv6.
+        if let Ok(sock) = TcpSocket::new_v6() {
+            let key = PeerKey {
+                peer: "127.0.0.1".parse().unwrap(),
+                key: Secret::from("secret"),
+            };
+            key.apply(&sock, true).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_loopback() {
+        let listener = TcpSocket::new_v4().unwrap();
+        PeerKey {
+            peer: "127.0.0.1".parse().unwrap(),
+            key: Secret::from("secret"),
+        }.apply(&listener, false).unwrap();
+        listener.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = listener.listen(4).unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        // A connection signed with the right key gets through.
+        let secret = Secret::from("secret");
+        let (client, server) = tokio::join!(
+            connect(&addr, &secret),
+            listener.accept()
+        );
+        let mut client = client.unwrap();
+        let (mut server, _) = server.unwrap();
+        client.write_all(b"rtr").await.unwrap();
+        let mut buf = [0u8; 3];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"rtr");
+
+        // The kernel drops segments signed with a different key.
+        let wrong = Secret::from("wrong");
+        assert!(
+            timeout(
+                Duration::from_millis(500),
+                connect(&addr, &wrong)
+            ).await.is_err()
+        );
+    }
+}
@@ -1,22 +1,52 @@
 //! Utility functions for dealing with TLS.
+//!
+//! If the `tls` feature is not enabled, this module still provides
+//! [`MaybeTlsTcpStream`] and [`TlsAcceptor`] but the latter can never be
+//! created, so streams will always be plain TCP or Unix socket streams.
 
 use std::io;
+#[cfg(feature = "tls")]
 use std::fs::File;
+#[cfg(feature = "tls")]
 use std::path::Path;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+#[cfg(feature = "tls")]
 use daemonbase::error::ExitError;
+#[cfg(feature = "tls")]
 use log::error;
-use futures_util::{pin_mut, ready, TryFuture};
-use futures_util::future::Either;
+use futures_util::pin_mut;
+#[cfg(feature = "tls")]
+use futures_util::{ready, TryFuture};
+#[cfg(feature = "tls")]
 use pin_project_lite::pin_project;
+#[cfg(feature = "ssh")]
+use tokio::io::DuplexStream;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpStream;
-use tokio_rustls::{Accept, TlsAcceptor};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+#[cfg(feature = "tls")]
+use tokio_rustls::Accept;
+#[cfg(feature = "tls")]
 use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+#[cfg(feature = "tls")]
 use tokio_rustls::server::TlsStream;
 
-pub use tokio_rustls::rustls::ServerConfig;
+#[cfg(feature = "tls")]
+pub use tokio_rustls::TlsAcceptor;
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+
+
+//------------ TlsAcceptor ---------------------------------------------------
+
+/// A placeholder for the TLS acceptor if TLS support is disabled.
+///
+/// Values of this type cannot exist.
+#[cfg(not(feature = "tls"))]
+#[derive(Clone, Debug)]
+pub enum TlsAcceptor { }
 
 
 //------------ create_server_config -----------------------------------------
@@ -25,6 +55,7 @@ pub use tokio_rustls::rustls::ServerConfig;
 ///
 /// The service this config is for should be given through `service`. This is
 /// used for logging.
+#[cfg(feature = "tls")]
 pub fn create_server_config(
     service: &str, key_path: &Path, cert_path: &Path
 ) -> Result<ServerConfig, ExitError> {
@@ -41,7 +72,45 @@ pub fn create_server_config(
         })
 }
 
+
+//------------ create_client_config -----------------------------------------
+
+/// Creates a TLS client config.
+///
+/// The config trusts the usual web PKI roots as well as all certificates
+/// found in the PEM files given via `cacerts`. The service this config is
+/// for should be given through `service`. This is used for logging.
+#[cfg(feature = "tls")]
+pub fn create_client_config(
+    service: &str, cacerts: &[impl AsRef<Path>]
+) -> Result<ClientConfig, ExitError> {
+    let mut root_certs = RootCertStore {
+        roots: Vec::from(webpki_roots::TLS_SERVER_ROOTS)
+    };
+    for path in cacerts {
+        let path = path.as_ref();
+        for cert in read_certs(path)? {
+            root_certs.add(cert).map_err(|err| {
+                error!(
+                    "Failed to add '{}' TLS certificate from file '{}': {}.",
+                    service, path.display(), err
+                );
+                ExitError::default()
+            })?;
+        }
+    }
+    Ok(
+        ClientConfig::builder()
+            .with_root_certificates(root_certs)
+            .with_no_client_auth()
+    )
+}
+
+
+//------------ Helpers -------------------------------------------------------
+
 /// Reads the certificates from the given PEM file.
+#[cfg(feature = "tls")]
 fn read_certs(
     cert_path: &Path
 ) -> Result<Vec<CertificateDer<'static>>, ExitError> {
@@ -71,6 +140,7 @@ fn read_certs(
 ///
 /// Errors out if opening or reading the file fails or if there isn’t exactly
 /// one private key in the file.
+#[cfg(feature = "tls")]
 fn read_key(key_path: &Path) -> Result<PrivateKeyDer<'static>, ExitError> {
     use rustls_pemfile::Item::*;
 
@@ -128,6 +198,7 @@ fn read_key(key_path: &Path) -> Result<PrivateKeyDer<'static>, ExitError> {
 
 //------------ TlsTcpStream --------------------------------------------------
 
+#[cfg(feature = "tls")]
 pin_project! {
     /// A TLS stream that behaves like a regular TCP stream.
     ///
@@ -151,6 +222,7 @@ pin_project! {
     }
 }
 
+#[cfg(feature = "tls")]
 impl TlsTcpStream {
     fn new(sock: TcpStream, tls: &TlsAcceptor) -> Self {
         Self::Accept { fut: tls.accept(sock) }
@@ -178,6 +250,7 @@ impl TlsTcpStream {
     }
 }
 
+#[cfg(feature = "tls")]
 impl AsyncRead for TlsTcpStream {
     fn poll_read(
         self: Pin<&mut Self>,
@@ -198,6 +271,7 @@ impl AsyncRead for TlsTcpStream {
     }
 }
 
+#[cfg(feature = "tls")]
 impl AsyncWrite for TlsTcpStream {
     fn poll_write(
         self: Pin<&mut Self>,
@@ -253,11 +327,85 @@ impl AsyncWrite for TlsTcpStream {
 }
 
 
+//------------ TlsTcpStream without TLS --------------------------------------
+
+/// A placeholder for a TLS stream if TLS support is disabled.
+///
+/// Values of this type cannot exist.
+#[cfg(not(feature = "tls"))]
+enum TlsTcpStream { }
+
+#[cfg(not(feature = "tls"))]
+impl TlsTcpStream {
+    fn new(_sock: TcpStream, tls: &TlsAcceptor) -> Self {
+        match *tls { }
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+impl AsyncRead for TlsTcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut ReadBuf<'_>
+    ) -> Poll<Result<(), io::Error>> {
+        match *self { }
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+impl AsyncWrite for TlsTcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &[u8]
+    ) -> Poll<Result<usize, io::Error>> {
+        match *self { }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>
+    ) -> Poll<Result<(), io::Error>> {
+        match *self { }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>
+    ) -> Poll<Result<(), io::Error>> {
+        match *self { }
+    }
+}
+
+
 //------------ MaybeTlsTcpStream ---------------------------------------------
 
 /// A TCP stream that may or may not use TLS.
+///
+/// On Unix systems, the stream can also be a plain Unix domain socket
+/// stream.
 pub struct MaybeTlsTcpStream {
-    sock: Either<TcpStream, TlsTcpStream>,
+    sock: Sock,
+}
+
+/// The actual socket of a [`MaybeTlsTcpStream`].
+enum Sock {
+    /// A plain TCP stream.
+    Tcp(TcpStream),
+
+    /// A TLS stream atop TCP.
+    ///
+    /// The stream is boxed since it is a lot larger than the others.
+    Tls(Box<TlsTcpStream>),
+
+    /// A Unix domain socket stream.
+    #[cfg(unix)]
+    Unix(UnixStream),
+
+    /// A pipe to a task running SSH.
+    #[cfg(feature = "ssh")]
+    Duplex(DuplexStream),
 }
 
 impl MaybeTlsTcpStream {
@@ -268,11 +416,25 @@ impl MaybeTlsTcpStream {
     pub fn new(sock: TcpStream, tls: Option<&TlsAcceptor>) -> Self {
         MaybeTlsTcpStream {
             sock: match tls {
-                Some(tls) => Either::Right(TlsTcpStream::new(sock, tls)),
-                None => Either::Left(sock)
+                Some(tls) => {
+                    Sock::Tls(Box::new(TlsTcpStream::new(sock, tls)))
+                }
+                None => Sock::Tcp(sock)
             }
         }
     }
+
+    /// Creates a new stream from a Unix domain socket stream.
+    #[cfg(unix)]
+    pub fn unix(sock: UnixStream) -> Self {
+        MaybeTlsTcpStream { sock: Sock::Unix(sock) }
+    }
+
+    /// Creates a new stream from a pipe to a task running SSH.
+    #[cfg(feature = "ssh")]
+    pub fn duplex(sock: DuplexStream) -> Self {
+        MaybeTlsTcpStream { sock: Sock::Duplex(sock) }
+    }
 }
 
 impl AsyncRead for MaybeTlsTcpStream {
@@ -280,11 +442,21 @@ impl AsyncRead for MaybeTlsTcpStream {
         mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf
     ) -> Poll<Result<(), io::Error>> {
         match self.sock {
-            Either::Left(ref mut sock) => {
+            Sock::Tcp(ref mut sock) => {
+                pin_mut!(sock);
+                sock.poll_read(cx, buf)
+            }
+            Sock::Tls(ref mut sock) => {
+                pin_mut!(sock);
+                sock.poll_read(cx, buf)
+            }
+            #[cfg(unix)]
+            Sock::Unix(ref mut sock) => {
                 pin_mut!(sock);
                 sock.poll_read(cx, buf)
             }
-            Either::Right(ref mut sock) => {
+            #[cfg(feature = "ssh")]
+            Sock::Duplex(ref mut sock) => {
                 pin_mut!(sock);
                 sock.poll_read(cx, buf)
             }
@@ -298,11 +470,21 @@ impl AsyncWrite for MaybeTlsTcpStream {
         mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]
     ) -> Poll<Result<usize, io::Error>> {
         match self.sock {
-            Either::Left(ref mut sock) => {
+            Sock::Tcp(ref mut sock) => {
+                pin_mut!(sock);
+                sock.poll_write(cx, buf)
+            }
+            Sock::Tls(ref mut sock) => {
                 pin_mut!(sock);
                 sock.poll_write(cx, buf)
             }
-            Either::Right(ref mut sock) => {
+            #[cfg(unix)]
+            Sock::Unix(ref mut sock) => {
+                pin_mut!(sock);
+                sock.poll_write(cx, buf)
+            }
+            #[cfg(feature = "ssh")]
+            Sock::Duplex(ref mut sock) => {
                 pin_mut!(sock);
                 sock.poll_write(cx, buf)
             }
@@ -313,11 +495,21 @@ impl AsyncWrite for MaybeTlsTcpStream {
         mut self: Pin<&mut Self>, cx: &mut Context
     ) -> Poll<Result<(), io::Error>> {
         match self.sock {
-            Either::Left(ref mut sock) => {
+            Sock::Tcp(ref mut sock) => {
+                pin_mut!(sock);
+                sock.poll_flush(cx)
+            }
+            Sock::Tls(ref mut sock) => {
                 pin_mut!(sock);
                 sock.poll_flush(cx)
             }
-            Either::Right(ref mut sock) => {
+            #[cfg(unix)]
+            Sock::Unix(ref mut sock) => {
+                pin_mut!(sock);
+                sock.poll_flush(cx)
+            }
+            #[cfg(feature = "ssh")]
+            Sock::Duplex(ref mut sock) => {
                 pin_mut!(sock);
                 sock.poll_flush(cx)
             }
@@ -328,15 +520,24 @@ impl AsyncWrite for MaybeTlsTcpStream {
         mut self: Pin<&mut Self>, cx: &mut Context
     ) -> Poll<Result<(), io::Error>> {
         match self.sock {
-            Either::Left(ref mut sock) => {
+            Sock::Tcp(ref mut sock) => {
+                pin_mut!(sock);
+                sock.poll_shutdown(cx)
+            }
+            Sock::Tls(ref mut sock) => {
                 pin_mut!(sock);
                 sock.poll_shutdown(cx)
             }
-            Either::Right(ref mut sock) => {
+            #[cfg(unix)]
+            Sock::Unix(ref mut sock) => {
+                pin_mut!(sock);
+                sock.poll_shutdown(cx)
+            }
+            #[cfg(feature = "ssh")]
+            Sock::Duplex(ref mut sock) => {
                 pin_mut!(sock);
                 sock.poll_shutdown(cx)
             }
         }
     }
 }
-